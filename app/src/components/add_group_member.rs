@@ -4,7 +4,6 @@ use crate::{
 };
 use anyhow::{Error, Result};
 use graphql_client::GraphQLQuery;
-use std::collections::HashSet;
 use yew::prelude::*;
 
 #[derive(GraphQLQuery)]
@@ -30,7 +29,7 @@ pub type User = list_user_names::ListUserNamesUsers;
 
 pub struct AddGroupMemberComponent {
     common: CommonComponentParts<Self>,
-    /// The list of existing users, initially not loaded.
+    /// The list of the users that are not members of the group, initially not loaded.
     user_list: Option<Vec<User>>,
     /// The currently selected user.
     selected_user: Option<User>,
@@ -46,7 +45,8 @@ pub enum Msg {
 #[derive(yew::Properties, Clone, PartialEq)]
 pub struct Props {
     pub group_id: i64,
-    pub users: Vec<User>,
+    /// Used to refresh the list of candidates when the members change.
+    pub member_count: i64,
     pub on_user_added_to_group: Callback<User>,
     pub on_error: Callback<Error>,
 }
@@ -68,6 +68,10 @@ impl CommonComponent<AddGroupMemberComponent> for AddGroupMemberComponent {
                     .expect("Could not get selected user")
                     .clone();
                 // Remove the user from the dropdown.
+                if let Some(user_list) = self.user_list.as_mut() {
+                    user_list.retain(|u| u.id != user.id);
+                }
+                self.selected_user = None;
                 self.common.on_user_added_to_group.emit(user);
             }
             Msg::SelectionChanged(option_props) => {
@@ -88,9 +92,24 @@ impl CommonComponent<AddGroupMemberComponent> for AddGroupMemberComponent {
 }
 
 impl AddGroupMemberComponent {
+    /// Fetches the users that are not members of the group, without listing all the members.
     fn get_user_list(&mut self) {
+        let filter = |member_of_id, not| list_user_names::RequestFilter {
+            any: None,
+            all: None,
+            not,
+            eq: None,
+            member_of: None,
+            member_of_id,
+        };
+        let not_member = filter(
+            None,
+            Some(Box::new(filter(Some(self.common.group_id), None))),
+        );
         self.common.call_graphql::<ListUserNames, _>(
-            list_user_names::Variables { filters: None },
+            list_user_names::Variables {
+                filters: Some(not_member),
+            },
             Msg::UserListResponse,
             "Error trying to fetch user list",
         );
@@ -111,15 +130,6 @@ impl AddGroupMemberComponent {
         );
         Ok(true)
     }
-
-    fn get_selectable_user_list(&self, user_list: &[User]) -> Vec<User> {
-        let user_groups = self.common.users.iter().collect::<HashSet<_>>();
-        user_list
-            .iter()
-            .filter(|u| !user_groups.contains(u))
-            .map(Clone::clone)
-            .collect()
-    }
 }
 
 impl Component for AddGroupMemberComponent {
@@ -145,12 +155,16 @@ impl Component for AddGroupMemberComponent {
     }
 
     fn change(&mut self, props: Self::Properties) -> ShouldRender {
-        self.common.change(props)
+        let members_changed = props.member_count != self.common.member_count;
+        let should_render = self.common.change(props);
+        if members_changed {
+            self.get_user_list();
+        }
+        should_render
     }
 
     fn view(&self) -> Html {
         if let Some(user_list) = &self.user_list {
-            let to_add_user_list = self.get_selectable_user_list(user_list);
             #[allow(unused_braces)]
             let make_select_option = |user: User| {
                 html_nested! {
@@ -162,8 +176,9 @@ impl Component for AddGroupMemberComponent {
               <div class="col-sm-3">
                 <Select on_selection_change=self.common.callback(Msg::SelectionChanged)>
                   {
-                    to_add_user_list
-                        .into_iter()
+                    user_list
+                        .iter()
+                        .cloned()
                         .map(make_select_option)
                         .collect::<Vec<_>>()
                   }
@@ -1,12 +1,14 @@
 use crate::{
     components::{
         change_password::ChangePasswordForm,
+        connector_deliveries::ConnectorDeliveries,
         create_group::CreateGroupForm,
         create_user::CreateUserForm,
         group_details::GroupDetails,
         group_table::GroupTable,
         login::LoginForm,
         logout::LogoutButton,
+        reset_password_step2::ResetPasswordStep2Form,
         router::{AppRoute, Link, NavButton},
         user_details::UserDetails,
         user_table::UserTable,
@@ -81,7 +83,7 @@ impl Component for App {
                 self.redirect_to = None;
             }
         }
-        if self.user_info.is_none() {
+        if self.user_info.is_none() && !Self::is_reset_password_route() {
             self.route_dispatcher
                 .send(RouteRequest::ReplaceRoute(Route::new_no_state("/login")));
         }
@@ -106,6 +108,9 @@ impl Component for App {
                             AppRoute::Login => html! {
                                 <LoginForm on_logged_in=link.callback(Msg::Login)/>
                             },
+                            AppRoute::ResetPasswordStep2(token) => html! {
+                                <ResetPasswordStep2Form token=token.clone() />
+                            },
                             AppRoute::CreateUser => html! {
                                 <CreateUserForm/>
                             },
@@ -127,6 +132,9 @@ impl Component for App {
                             AppRoute::GroupDetails(group_id) => html! {
                                 <GroupDetails group_id=group_id />
                             },
+                            AppRoute::ConnectorDeliveries => html! {
+                                <ConnectorDeliveries />
+                            },
                             AppRoute::UserDetails(username) => html! {
                                 <UserDetails username=username.clone() is_admin=is_admin />
                             },
@@ -155,8 +163,16 @@ impl App {
         }
     }
 
+    fn is_reset_password_route() -> bool {
+        RouteService::<()>::new()
+            .get_path()
+            .starts_with("/reset-password/")
+    }
+
     fn apply_initial_redirections(&mut self) {
         match &self.user_info {
+            // The password reset links are opened without being logged in.
+            None if Self::is_reset_password_route() => {}
             None => {
                 self.route_dispatcher
                     .send(RouteRequest::ReplaceRoute(Route::new_no_state("/login")));
@@ -207,6 +223,13 @@ impl App {
                           {"Groups"}
                         </Link>
                       </li>
+                      <li>
+                        <Link
+                          classes="nav-link px-2 link-dark h4"
+                          route=AppRoute::ConnectorDeliveries>
+                          {"Connectors"}
+                        </Link>
+                      </li>
                     </>
                   } } else { html!{} } }
                 </ul>
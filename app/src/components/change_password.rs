@@ -73,6 +73,7 @@ pub struct Props {
 pub enum Msg {
     FormUpdate,
     Submit,
+    PasswordStrengthResponse(Result<password::PasswordStrength>),
     AuthenticationStartResponse(Result<Box<login::ServerLoginStartResponse>>),
     SubmitNewPassword,
     RegistrationStartResponse(Result<Box<registration::ServerRegistrationStartResponse>>),
@@ -87,6 +88,26 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
                 if !self.form.validate() {
                     bail!("Check the form for errors");
                 }
+                // The server can't check the passwords registered with OPAQUE: check it first.
+                let req = password::PasswordStrengthRequest {
+                    password: self.form.model().password,
+                    user_id: Some(self.common.username.clone()),
+                };
+                self.common.call_backend(
+                    HostService::check_password_strength,
+                    req,
+                    Msg::PasswordStrengthResponse,
+                )?;
+                Ok(true)
+            }
+            Msg::PasswordStrengthResponse(res) => {
+                let strength = res?;
+                if !strength.violations.is_empty() {
+                    bail!(
+                        "The password doesn't follow the password policy, it needs: {}",
+                        strength.violations.join(", ")
+                    );
+                }
                 if self.common.is_admin {
                     self.handle_msg(Msg::SubmitNewPassword)
                 } else {
@@ -0,0 +1,134 @@
+use crate::infra::common_component::{CommonComponent, CommonComponentParts};
+use anyhow::Result;
+use graphql_client::GraphQLQuery;
+use yew::prelude::*;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../schema.graphql",
+    query_path = "queries/get_connector_deliveries.graphql",
+    response_derives = "Debug,Clone,PartialEq",
+    custom_scalars_module = "crate::infra::graphql"
+)]
+pub struct GetConnectorDeliveries;
+
+use get_connector_deliveries::ResponseData;
+
+pub type Delivery = get_connector_deliveries::GetConnectorDeliveriesConnectorDeliveries;
+
+/// The dashboard of the last deliveries of changes to the connectors.
+pub struct ConnectorDeliveries {
+    common: CommonComponentParts<Self>,
+    deliveries: Option<Vec<Delivery>>,
+}
+
+pub enum Msg {
+    /// The "Refresh" button was clicked.
+    Refresh,
+    ListDeliveriesResponse(Result<ResponseData>),
+}
+
+impl CommonComponent<ConnectorDeliveries> for ConnectorDeliveries {
+    fn handle_msg(&mut self, msg: <Self as Component>::Message) -> Result<bool> {
+        match msg {
+            Msg::Refresh => self.fetch_deliveries(),
+            Msg::ListDeliveriesResponse(deliveries) => {
+                self.common.cancel_task();
+                self.deliveries = Some(deliveries?.connector_deliveries);
+            }
+        }
+        Ok(true)
+    }
+
+    fn mut_common(&mut self) -> &mut CommonComponentParts<Self> {
+        &mut self.common
+    }
+}
+
+impl ConnectorDeliveries {
+    fn fetch_deliveries(&mut self) {
+        self.common.call_graphql::<GetConnectorDeliveries, _>(
+            get_connector_deliveries::Variables {},
+            Msg::ListDeliveriesResponse,
+            "Error trying to fetch the connector deliveries",
+        );
+    }
+
+    fn view_deliveries(&self) -> Html {
+        let make_row = |delivery: &Delivery| {
+            html! {
+              <tr>
+                <td>{delivery.time.naive_local()}</td>
+                <td>{&delivery.connector}</td>
+                <td>{&delivery.event}</td>
+                {match &delivery.error {
+                  None => html! {<td class="text-success">{"Delivered"}</td>},
+                  Some(e) => html! {<td class="text-danger">{e}</td>},
+                }}
+              </tr>
+            }
+        };
+        match &self.deliveries {
+            None => html! {{"Loading..."}},
+            Some(deliveries) if deliveries.is_empty() => html! {{"No deliveries yet"}},
+            Some(deliveries) => html! {
+              <div class="table-responsive">
+                <table class="table table-striped">
+                  <thead>
+                    <tr>
+                      <th>{"Time"}</th>
+                      <th>{"Connector"}</th>
+                      <th>{"Event"}</th>
+                      <th>{"Status"}</th>
+                    </tr>
+                  </thead>
+                  <tbody>
+                    {deliveries.iter().map(make_row).collect::<Vec<_>>()}
+                  </tbody>
+                </table>
+              </div>
+            },
+        }
+    }
+}
+
+impl Component for ConnectorDeliveries {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let mut table = ConnectorDeliveries {
+            common: CommonComponentParts::<Self>::create(props, link),
+            deliveries: None,
+        };
+        table.fetch_deliveries();
+        table
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        CommonComponentParts::<Self>::update(self, msg)
+    }
+
+    fn change(&mut self, _: Self::Properties) -> ShouldRender {
+        false
+    }
+
+    fn view(&self) -> Html {
+        html! {
+          <div>
+            <h3>{"Connector deliveries"}</h3>
+            {self.view_deliveries()}
+            <button
+              class="btn btn-primary"
+              disabled=self.common.is_task_running()
+              onclick=self.common.callback(|_| Msg::Refresh)>
+              {"Refresh"}
+            </button>
+            {match &self.common.error {
+              None => html! {},
+              Some(e) => html! {<div>{"Error: "}{e.to_string()}</div>},
+            }}
+          </div>
+        }
+    }
+}
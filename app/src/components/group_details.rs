@@ -23,11 +23,16 @@ pub type Group = get_group_details::GetGroupDetailsGroup;
 pub type User = get_group_details::GetGroupDetailsGroupUsers;
 pub type AddGroupMemberUser = add_group_member::User;
 
+/// The number of members shown per page.
+const PAGE_SIZE: i64 = 50;
+
 pub struct GroupDetails {
     common: CommonComponentParts<Self>,
     /// The group info. If none, the error is in `error`. If `error` is None, then we haven't
     /// received the server response yet.
     group: Option<Group>,
+    /// The index of the first member shown.
+    offset: i64,
 }
 
 /// State machine describing the possible transitions of the component state.
@@ -38,6 +43,8 @@ pub enum Msg {
     OnError(Error),
     OnUserAddedToGroup(AddGroupMemberUser),
     OnUserRemovedFromGroup((String, i64)),
+    /// Show the members starting at this offset.
+    ChangePage(i64),
 }
 
 #[derive(yew::Properties, Clone, PartialEq)]
@@ -50,6 +57,8 @@ impl GroupDetails {
         self.common.call_graphql::<GetGroupDetails, _>(
             get_group_details::Variables {
                 id: self.common.group_id,
+                offset: self.offset,
+                limit: PAGE_SIZE,
             },
             Msg::GroupDetailsResponse,
             "Error trying to fetch group details",
@@ -93,7 +102,7 @@ impl GroupDetails {
         html! {
           <>
             <h3>{g.display_name.to_string()}</h3>
-            <h5 class="fw-bold">{"Members"}</h5>
+            <h5 class="fw-bold">{format!("Members ({})", g.member_count)}</h5>
             <div class="table-responsive">
               <table class="table table-striped">
                 <thead>
@@ -117,23 +126,40 @@ impl GroupDetails {
                 </tbody>
               </table>
             </div>
+            {self.view_pagination(g)}
           </>
         }
     }
 
+    fn view_pagination(&self, g: &Group) -> Html {
+        if g.member_count <= PAGE_SIZE {
+            return html! {};
+        }
+        let last = (self.offset + PAGE_SIZE).min(g.member_count);
+        html! {
+          <div class="d-flex align-items-center mb-3">
+            <button
+              class="btn btn-secondary"
+              disabled=self.offset == 0
+              onclick=self.common.callback(|_| Msg::ChangePage(-PAGE_SIZE))>
+              {"Previous"}
+            </button>
+            <span class="mx-3">{format!("{}-{} of {}", self.offset + 1, last, g.member_count)}</span>
+            <button
+              class="btn btn-secondary"
+              disabled=last >= g.member_count
+              onclick=self.common.callback(|_| Msg::ChangePage(PAGE_SIZE))>
+              {"Next"}
+            </button>
+          </div>
+        }
+    }
+
     fn view_add_user_button(&self, g: &Group) -> Html {
-        let users: Vec<_> = g
-            .users
-            .iter()
-            .map(|u| AddGroupMemberUser {
-                id: u.id.clone(),
-                display_name: u.display_name.clone(),
-            })
-            .collect();
         html! {
             <AddGroupMemberComponent
                 group_id=g.id
-                users=users
+                member_count=g.member_count
                 on_error=self.common.callback(Msg::OnError)
                 on_user_added_to_group=self.common.callback(Msg::OnUserAddedToGroup)/>
         }
@@ -151,18 +177,19 @@ impl CommonComponent<GroupDetails> for GroupDetails {
                 }
             },
             Msg::OnError(e) => return Err(e),
-            Msg::OnUserAddedToGroup(user) => {
-                self.group.as_mut().unwrap().users.push(User {
-                    id: user.id,
-                    display_name: user.display_name,
-                });
+            // Only one page of members is loaded: reload it to get the new count and order.
+            Msg::OnUserAddedToGroup(_) => self.get_group_details(),
+            Msg::OnUserRemovedFromGroup(_) => {
+                let member_count = self.group.as_ref().map_or(0, |g| g.member_count - 1);
+                // Go back a page if the last member of the page was removed.
+                if self.offset >= member_count {
+                    self.offset = (self.offset - PAGE_SIZE).max(0);
+                }
+                self.get_group_details();
             }
-            Msg::OnUserRemovedFromGroup((user_id, _)) => {
-                self.group
-                    .as_mut()
-                    .unwrap()
-                    .users
-                    .retain(|u| u.id != user_id);
+            Msg::ChangePage(delta) => {
+                self.offset = (self.offset + delta).max(0);
+                self.get_group_details();
             }
         }
         Ok(true)
@@ -181,6 +208,7 @@ impl Component for GroupDetails {
         let mut table = Self {
             common: CommonComponentParts::<Self>::create(props, link),
             group: None,
+            offset: 0,
         };
         table.get_group_details();
         table
@@ -98,6 +98,7 @@ impl GroupTable {
                     <thead>
                       <tr>
                         <th>{"Groups"}</th>
+                        <th>{"Members"}</th>
                         <th>{"Delete"}</th>
                       </tr>
                     </thead>
@@ -122,6 +123,7 @@ impl GroupTable {
                   {&group.display_name}
                 </Link>
               </td>
+              <td>{group.member_count}</td>
               <td>
                 <DeleteGroup
                   group=group.clone()
@@ -12,6 +12,8 @@ use yew_form_derive::Model;
 pub struct LoginForm {
     common: CommonComponentParts<Self>,
     form: Form<FormModel>,
+    /// The name of the OIDC provider to log in with, if the server has one.
+    oidc_provider: Option<String>,
 }
 
 /// The fields of the form, with the constraints.
@@ -38,6 +40,7 @@ pub enum Msg {
         ),
     ),
     AuthenticationFinishResponse(Result<(String, bool)>),
+    OidcProviderResponse(Result<Option<String>>),
 }
 
 impl CommonComponent<LoginForm> for LoginForm {
@@ -96,6 +99,11 @@ impl CommonComponent<LoginForm> for LoginForm {
                     .emit(user_info.context("Could not log in")?);
                 Ok(true)
             }
+            Msg::OidcProviderResponse(provider) => {
+                self.common.cancel_task();
+                self.oidc_provider = provider?;
+                Ok(true)
+            }
         }
     }
 
@@ -109,10 +117,19 @@ impl Component for LoginForm {
     type Properties = Props;
 
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
-        LoginForm {
+        let mut login_form = LoginForm {
             common: CommonComponentParts::<Self>::create(props, link),
             form: Form::<FormModel>::new(FormModel::default()),
+            oidc_provider: None,
+        };
+        if let Err(e) = login_form.common.call_backend(
+            HostService::get_oidc_provider,
+            (),
+            Msg::OidcProviderResponse,
+        ) {
+            ConsoleService::error(&e.to_string());
         }
+        login_form
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
@@ -169,6 +186,13 @@ impl Component for LoginForm {
                     {"Login"}
                   </button>
                 </div>
+                { if let Some(provider) = &self.oidc_provider { html! {
+                  <div class="form-group">
+                    <a class="btn btn-secondary" href="/auth/oidc/login">
+                      {"Log in with "}{provider}
+                    </a>
+                  </div>
+                } } else { html! {} } }
                 <div class="form-group">
                 { if let Some(e) = &self.common.error {
                     html! { e.to_string() }
@@ -2,6 +2,7 @@ pub mod add_group_member;
 pub mod add_user_to_group;
 pub mod app;
 pub mod change_password;
+pub mod connector_deliveries;
 pub mod create_group;
 pub mod create_user;
 pub mod delete_group;
@@ -11,8 +12,11 @@ pub mod group_table;
 pub mod login;
 pub mod logout;
 pub mod remove_user_from_group;
+pub mod reset_password_step2;
 pub mod router;
 pub mod select;
 pub mod user_details;
 pub mod user_details_form;
+pub mod user_hosts_form;
+pub mod user_lock;
 pub mod user_table;
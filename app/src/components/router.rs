@@ -7,6 +7,8 @@ use yew_router::{
 pub enum AppRoute {
     #[to = "/login"]
     Login,
+    #[to = "/reset-password/step2/{token}"]
+    ResetPasswordStep2(String),
     #[to = "/users/create"]
     CreateUser,
     #[to = "/users"]
@@ -21,6 +23,8 @@ pub enum AppRoute {
     ListGroups,
     #[to = "/group/{group_id}"]
     GroupDetails(i64),
+    #[to = "/connectors"]
+    ConnectorDeliveries,
     #[to = "/"]
     Index,
 }
@@ -4,6 +4,8 @@ use crate::{
         remove_user_from_group::RemoveUserFromGroupComponent,
         router::{AppRoute, Link, NavButton},
         user_details_form::UserDetailsForm,
+        user_hosts_form::UserHostsForm,
+        user_lock::UserLockForm,
     },
     infra::common_component::{CommonComponent, CommonComponentParts},
 };
@@ -22,6 +24,7 @@ pub struct GetUserDetails;
 
 pub type User = get_user_details::GetUserDetailsUser;
 pub type Group = get_user_details::GetUserDetailsUserGroups;
+pub type UserLock = get_user_details::GetUserDetailsUserLock;
 
 pub struct UserDetails {
     common: CommonComponentParts<Self>,
@@ -99,6 +102,28 @@ impl UserDetails {
         }
     }
 
+    fn view_password_expiry(&self, u: &User) -> Html {
+        match (&u.password_expires_at, u.password_expires_soon) {
+            (Some(expires_at), true) => {
+                let message = if *expires_at <= chrono::Utc::now() {
+                    format!(
+                        "The password expired on {}, please change it.",
+                        expires_at.date().naive_local()
+                    )
+                } else {
+                    format!(
+                        "The password expires on {}, change it before then.",
+                        expires_at.date().naive_local()
+                    )
+                };
+                html! {
+                  <div class="alert alert-warning">{message}</div>
+                }
+            }
+            _ => html! {},
+        }
+    }
+
     fn view_group_memberships(&self, u: &User) -> Html {
         let make_group_row = |group: &Group| {
             let display_name = group.display_name.clone();
@@ -197,6 +222,7 @@ impl Component for UserDetails {
                 html! {
                   <>
                     <h3>{u.id.to_string()}</h3>
+                    {self.view_password_expiry(u)}
                     <UserDetailsForm
                       user=u.clone()
                       on_error=self.common.callback(Msg::OnError)/>
@@ -207,6 +233,16 @@ impl Component for UserDetails {
                           {"Change password"}
                       </NavButton>
                     </div>
+                    <UserHostsForm
+                      username=u.id.clone()
+                      hosts=u.hosts.clone()
+                      is_admin=self.common.is_admin
+                      on_error=self.common.callback(Msg::OnError)/>
+                    <UserLockForm
+                      username=u.id.clone()
+                      lock=u.lock.clone()
+                      is_admin=self.common.is_admin
+                      on_error=self.common.callback(Msg::OnError)/>
                     {self.view_group_memberships(u)}
                     {self.view_add_group_button(u)}
                     {self.view_messages(error)}
@@ -268,6 +268,10 @@ impl UserDetailsForm {
                     last_name: model.last_name,
                     creation_date: self.common.user.creation_date,
                     groups: self.common.user.groups.clone(),
+                    hosts: self.common.user.hosts.clone(),
+                    password_expires_at: self.common.user.password_expires_at,
+                    password_expires_soon: self.common.user.password_expires_soon,
+                    lock: self.common.user.lock.clone(),
                 };
                 self.just_updated = true;
             }
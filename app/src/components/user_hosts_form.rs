@@ -0,0 +1,156 @@
+use crate::infra::common_component::{CommonComponent, CommonComponentParts};
+use anyhow::{Error, Result};
+use graphql_client::GraphQLQuery;
+use yew::prelude::*;
+
+/// The GraphQL query sent to the server to replace the hosts of a user.
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../schema.graphql",
+    query_path = "queries/set_user_hosts.graphql",
+    response_derives = "Debug",
+    variables_derives = "Clone",
+    custom_scalars_module = "crate::infra::graphql"
+)]
+pub struct SetUserHosts;
+
+/// A [yew::Component] to display the hosts a user can log into, editable by admins.
+pub struct UserHostsForm {
+    common: CommonComponentParts<Self>,
+    /// The comma-separated list of hosts being edited.
+    hosts_input: String,
+    /// True if we just successfully updated the hosts, to display a success message.
+    just_updated: bool,
+}
+
+pub enum Msg {
+    /// The hosts input changed.
+    HostsChanged(String),
+    /// The "Save hosts" button was clicked.
+    SubmitClicked,
+    /// We got the response from the server about our update message.
+    HostsUpdated(Result<set_user_hosts::ResponseData>),
+}
+
+#[derive(yew::Properties, Clone, PartialEq)]
+pub struct Props {
+    pub username: String,
+    /// The hosts currently allowed for the user.
+    pub hosts: Vec<String>,
+    pub is_admin: bool,
+    /// Callback to report errors (e.g. server error).
+    pub on_error: Callback<Error>,
+}
+
+fn parse_hosts(input: &str) -> Vec<String> {
+    input
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|h| !h.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl CommonComponent<UserHostsForm> for UserHostsForm {
+    fn handle_msg(&mut self, msg: <Self as Component>::Message) -> Result<bool> {
+        match msg {
+            Msg::HostsChanged(value) => self.hosts_input = value,
+            Msg::SubmitClicked => self.submit_hosts(),
+            Msg::HostsUpdated(response) => {
+                response?;
+                self.common.cancel_task();
+                self.just_updated = true;
+            }
+        }
+        Ok(true)
+    }
+
+    fn mut_common(&mut self) -> &mut CommonComponentParts<Self> {
+        &mut self.common
+    }
+}
+
+impl UserHostsForm {
+    fn submit_hosts(&mut self) {
+        self.common.call_graphql::<SetUserHosts, _>(
+            set_user_hosts::Variables {
+                user: self.common.username.clone(),
+                hosts: parse_hosts(&self.hosts_input),
+            },
+            Msg::HostsUpdated,
+            "Error trying to update the user hosts",
+        );
+    }
+}
+
+impl Component for UserHostsForm {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let hosts_input = props.hosts.join(", ");
+        Self {
+            common: CommonComponentParts::<Self>::create(props, link),
+            hosts_input,
+            just_updated: false,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        self.just_updated = false;
+        CommonComponentParts::<Self>::update_and_report_error(
+            self,
+            msg,
+            self.common.on_error.clone(),
+        )
+    }
+
+    fn change(&mut self, _: Self::Properties) -> ShouldRender {
+        false
+    }
+
+    fn view(&self) -> Html {
+        html! {
+          <>
+            <h5 class="row m-3 fw-bold">{"Allowed hosts"}</h5>
+            {if self.common.is_admin { html! {
+              <form class="form">
+                <div class="form-group row mb-3">
+                  <label for="hosts"
+                    class="form-label col-4 col-form-label">
+                    {"Hosts (comma-separated): "}
+                  </label>
+                  <div class="col-8">
+                    <input
+                      id="hosts"
+                      class="form-control"
+                      placeholder="No host restriction"
+                      value=self.hosts_input.clone()
+                      oninput=self.common.callback(|e: InputData| Msg::HostsChanged(e.value)) />
+                  </div>
+                </div>
+                <div class="form-group row justify-content-center">
+                  <button
+                    type="submit"
+                    class="btn btn-primary col-auto col-form-label"
+                    disabled=self.common.is_task_running()
+                    onclick=self.common.callback(|e: MouseEvent| {e.prevent_default(); Msg::SubmitClicked})>
+                    {"Save hosts"}
+                  </button>
+                </div>
+                <div hidden=!self.just_updated>
+                  <span>{"Hosts successfully updated!"}</span>
+                </div>
+              </form>
+            } } else { html! {
+              <div class="row m-3">
+                {if self.common.hosts.is_empty() {
+                  "No host restriction".to_string()
+                } else {
+                  self.common.hosts.join(", ")
+                }}
+              </div>
+            } } }
+          </>
+        }
+    }
+}
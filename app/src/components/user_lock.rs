@@ -0,0 +1,192 @@
+use crate::{
+    components::user_details::UserLock,
+    infra::common_component::{CommonComponent, CommonComponentParts},
+};
+use anyhow::{Error, Result};
+use graphql_client::GraphQLQuery;
+use yew::prelude::*;
+
+/// The GraphQL query sent to the server to lock a user.
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../schema.graphql",
+    query_path = "queries/lock_user.graphql",
+    response_derives = "Debug",
+    variables_derives = "Clone",
+    custom_scalars_module = "crate::infra::graphql"
+)]
+pub struct LockUser;
+
+/// The GraphQL query sent to the server to unlock a user.
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "../schema.graphql",
+    query_path = "queries/unlock_user.graphql",
+    response_derives = "Debug",
+    variables_derives = "Clone",
+    custom_scalars_module = "crate::infra::graphql"
+)]
+pub struct UnlockUser;
+
+/// A [yew::Component] to display whether a user is locked, with buttons for the admins to lock
+/// or unlock them.
+pub struct UserLockForm {
+    common: CommonComponentParts<Self>,
+    /// The reason of the current lock, if the user is locked.
+    locked_reason: Option<String>,
+    /// When the user was locked, if known: a lock made from this page only has a reason.
+    locked_at: Option<crate::infra::graphql::DateTimeUtc>,
+    /// The reason being typed for a new lock.
+    reason_input: String,
+}
+
+pub enum Msg {
+    /// The reason input changed.
+    ReasonChanged(String),
+    LockClicked,
+    UnlockClicked,
+    UserLocked(Result<lock_user::ResponseData>),
+    UserUnlocked(Result<unlock_user::ResponseData>),
+}
+
+#[derive(yew::Properties, Clone, PartialEq)]
+pub struct Props {
+    pub username: String,
+    pub lock: Option<UserLock>,
+    pub is_admin: bool,
+    /// Callback to report errors (e.g. server error).
+    pub on_error: Callback<Error>,
+}
+
+impl CommonComponent<UserLockForm> for UserLockForm {
+    fn handle_msg(&mut self, msg: <Self as Component>::Message) -> Result<bool> {
+        match msg {
+            Msg::ReasonChanged(value) => self.reason_input = value,
+            Msg::LockClicked => self.common.call_graphql::<LockUser, _>(
+                lock_user::Variables {
+                    user: self.common.username.clone(),
+                    reason: self.reason_input.trim().to_string(),
+                },
+                Msg::UserLocked,
+                "Error trying to lock the user",
+            ),
+            Msg::UnlockClicked => self.common.call_graphql::<UnlockUser, _>(
+                unlock_user::Variables {
+                    user: self.common.username.clone(),
+                },
+                Msg::UserUnlocked,
+                "Error trying to unlock the user",
+            ),
+            Msg::UserLocked(response) => {
+                response?;
+                self.common.cancel_task();
+                self.locked_reason =
+                    Some(std::mem::take(&mut self.reason_input).trim().to_string());
+                self.locked_at = None;
+            }
+            Msg::UserUnlocked(response) => {
+                response?;
+                self.common.cancel_task();
+                self.locked_reason = None;
+                self.locked_at = None;
+            }
+        }
+        Ok(true)
+    }
+
+    fn mut_common(&mut self) -> &mut CommonComponentParts<Self> {
+        &mut self.common
+    }
+}
+
+impl UserLockForm {
+    fn view_status(&self) -> Html {
+        match &self.locked_reason {
+            None => html! { <span>{"Active"}</span> },
+            Some(reason) => {
+                let since = self
+                    .locked_at
+                    .map(|t| format!(" since {}", t.date().naive_local()))
+                    .unwrap_or_default();
+                html! {
+                  <span class="text-danger">
+                    {"Locked"}{since}
+                    {if reason.is_empty() { String::new() } else { format!(": {}", reason) }}
+                  </span>
+                }
+            }
+        }
+    }
+}
+
+impl Component for UserLockForm {
+    type Message = Msg;
+    type Properties = Props;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let locked_reason = props.lock.as_ref().map(|l| l.reason.clone());
+        let locked_at = props.lock.as_ref().map(|l| l.locked_at);
+        Self {
+            common: CommonComponentParts::<Self>::create(props, link),
+            locked_reason,
+            locked_at,
+            reason_input: String::new(),
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        CommonComponentParts::<Self>::update_and_report_error(
+            self,
+            msg,
+            self.common.on_error.clone(),
+        )
+    }
+
+    fn change(&mut self, _: Self::Properties) -> ShouldRender {
+        false
+    }
+
+    fn view(&self) -> Html {
+        html! {
+          <>
+            <h5 class="row m-3 fw-bold">{"Account status"}</h5>
+            <div class="row m-3">{self.view_status()}</div>
+            {if !self.common.is_admin { html! {} } else if self.locked_reason.is_some() { html! {
+              <div class="form-group row justify-content-center">
+                <button
+                  class="btn btn-primary col-auto col-form-label"
+                  disabled=self.common.is_task_running()
+                  onclick=self.common.callback(|_| Msg::UnlockClicked)>
+                  {"Unlock"}
+                </button>
+              </div>
+            } } else { html! {
+              <form class="form">
+                <div class="form-group row mb-3">
+                  <label for="lock_reason"
+                    class="form-label col-4 col-form-label">
+                    {"Reason: "}
+                  </label>
+                  <div class="col-8">
+                    <input
+                      id="lock_reason"
+                      class="form-control"
+                      value=self.reason_input.clone()
+                      oninput=self.common.callback(|e: InputData| Msg::ReasonChanged(e.value)) />
+                  </div>
+                </div>
+                <div class="form-group row justify-content-center">
+                  <button
+                    type="submit"
+                    class="btn btn-danger col-auto col-form-label"
+                    disabled=self.common.is_task_running()
+                    onclick=self.common.callback(|e: MouseEvent| {e.prevent_default(); Msg::LockClicked})>
+                    {"Lock"}
+                  </button>
+                </div>
+              </form>
+            } } }
+          </>
+        }
+    }
+}
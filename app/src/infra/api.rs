@@ -1,7 +1,7 @@
 use super::cookies::set_cookie;
 use anyhow::{anyhow, Context, Result};
 use graphql_client::GraphQLQuery;
-use lldap_auth::{login, registration, JWTClaims};
+use lldap_auth::{login, password, registration, JWTClaims};
 
 use yew::callback::Callback;
 use yew::format::Json;
@@ -223,6 +223,47 @@ impl HostService {
         )
     }
 
+    /// Evaluates the password against the password policy of the server, before registering it.
+    pub fn check_password_strength(
+        request: password::PasswordStrengthRequest,
+        callback: Callback<Result<password::PasswordStrength>>,
+    ) -> Result<FetchTask> {
+        call_server_json_with_error_message(
+            "/auth/password/strength",
+            &request,
+            callback,
+            "Could not check the password: ",
+        )
+    }
+
+    /// Starts the registration of a new password with the token of a password reset link. The
+    /// registration is then finished with [`HostService::register_finish`].
+    pub fn reset_password_step2(
+        (token, request): (String, registration::ClientRegistrationStartRequest),
+        callback: Callback<Result<Box<registration::ServerRegistrationStartResponse>>>,
+    ) -> Result<FetchTask> {
+        call_server_json_with_error_message(
+            &format!("/auth/reset/step2/{}", token),
+            &request,
+            callback,
+            "Could not validate the reset token: ",
+        )
+    }
+
+    /// The name of the OIDC provider to log in with, if there is one.
+    // The `_request` parameter is to make it the same shape as the other functions.
+    pub fn get_oidc_provider(
+        _request: (),
+        callback: Callback<Result<Option<String>>>,
+    ) -> Result<FetchTask> {
+        call_server_json_with_error_message(
+            "/auth/oidc",
+            yew::format::Nothing,
+            callback,
+            "Could not get the OIDC provider: ",
+        )
+    }
+
     // The `_request` parameter is to make it the same shape as the other functions.
     pub fn logout(_request: (), callback: Callback<Result<()>>) -> Result<FetchTask> {
         call_server_empty_response_with_error_message(
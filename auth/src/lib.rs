@@ -67,10 +67,35 @@ pub mod registration {
     }
 }
 
+/// The messages of the `/auth/password/strength` endpoint, to check the new passwords against the
+/// password policy before registering them.
+pub mod password {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct PasswordStrengthRequest {
+        pub password: String,
+        /// The user the password is for, which the password shouldn't be based on.
+        #[serde(default)]
+        pub user_id: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+    pub struct PasswordStrength {
+        /// The zxcvbn score, from 0 (too guessable) to 4 (very unguessable).
+        pub score: u8,
+        /// The rules of the policy the password doesn't follow.
+        pub violations: Vec<String>,
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct JWTClaims {
     pub exp: DateTime<Utc>,
     pub iat: DateTime<Utc>,
     pub user: String,
     pub groups: HashSet<String>,
+    /// The admin the token was issued to, when impersonating the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
 }
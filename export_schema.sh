@@ -2,4 +2,4 @@
 
 cd $(dirname $(readlink -f "$0"))
 
-cargo run -- export_graphql_schema -o schema.graphql
+cargo run -- export-graphql-schema -o schema.graphql
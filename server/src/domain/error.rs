@@ -1,3 +1,4 @@
+use super::validation::InvalidField;
 use thiserror::Error;
 
 #[allow(clippy::enum_variant_names)]
@@ -17,6 +18,26 @@ pub enum DomainError {
     Base64DecodeError(#[from] base64::DecodeError),
     #[error("Internal error: `{0}`")]
     InternalError(String),
+    #[error("Timed out during `{0}`")]
+    TimeoutError(String),
+    #[error("Invalid input: `{0}`")]
+    InvalidInput(String),
+    /// The fields of a request rejected by the [`validation`](super::validation).
+    #[error("Invalid fields: `{}`", display_fields(.0))]
+    InvalidFields(Vec<InvalidField>),
+    /// A user or a group with the same ID, email or name already exists.
+    #[error("Conflict: `{0}`")]
+    Conflict(String),
+    #[error("Permission denied: `{0}`")]
+    PermissionDenied(String),
+}
+
+fn display_fields(fields: &[InvalidField]) -> String {
+    fields
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub type Result<T> = std::result::Result<T, DomainError>;
@@ -1,9 +1,11 @@
 use super::error::*;
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+/// A user, as stored in the database.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 pub struct User {
     pub user_id: String,
@@ -13,6 +15,10 @@ pub struct User {
     pub last_name: String,
     // pub avatar: ?,
     pub creation_date: chrono::DateTime<chrono::Utc>,
+    /// Generated when the user is created, and never changed: exposed as the `entryUUID`.
+    pub uuid: String,
+    /// Allocated when the user is created, after the highest one: exposed as the `uidNumber`.
+    pub uid_number: i32,
 }
 
 impl Default for User {
@@ -25,35 +31,344 @@ impl Default for User {
             first_name: String::new(),
             last_name: String::new(),
             creation_date: chrono::Utc.timestamp(0, 0),
+            uuid: String::new(),
+            uid_number: 0,
+        }
+    }
+}
+
+/// The suspension of a user by an admin, see [`BackendHandler::lock_user`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct UserLock {
+    pub locked_at: chrono::DateTime<chrono::Utc>,
+    pub reason: String,
+}
+
+/// A user in the recycle bin, see [`BackendHandler::delete_user`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct DeletedUser {
+    pub user_id: String,
+    pub email: String,
+    pub display_name: String,
+    pub deleted_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The way a failed authentication was attempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthProtocol {
+    /// An LDAP simple bind.
+    Ldap,
+    /// A login to the web interface or the HTTP API.
+    Web,
+}
+
+/// Why an authentication failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthFailureReason {
+    /// The LDAP bind DN isn't the DN of a user.
+    InvalidDn,
+    /// Unknown user, no password or wrong password.
+    InvalidCredentials,
+    /// The password was right, but the user is locked.
+    UserLocked,
+}
+
+impl AuthProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthProtocol::Ldap => "ldap",
+            AuthProtocol::Web => "web",
         }
     }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ldap" => Some(AuthProtocol::Ldap),
+            "web" => Some(AuthProtocol::Web),
+            _ => None,
+        }
+    }
+}
+
+impl AuthFailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthFailureReason::InvalidDn => "invalid_dn",
+            AuthFailureReason::InvalidCredentials => "invalid_credentials",
+            AuthFailureReason::UserLocked => "user_locked",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "invalid_dn" => Some(AuthFailureReason::InvalidDn),
+            "invalid_credentials" => Some(AuthFailureReason::InvalidCredentials),
+            "user_locked" => Some(AuthFailureReason::UserLocked),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the audit log: a change to the directory or an authentication attempt, see
+/// [`BackendHandler::record_audit_event`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEvent {
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// The user who made the change or tried to authenticate, if known.
+    pub actor: Option<String>,
+    pub source_ip: Option<std::net::IpAddr>,
+    /// The operation, e.g. "create_user" or "bind".
+    pub action: String,
+    /// What the operation applied to, e.g. the user ID or the group ID.
+    pub target: String,
+    pub success: bool,
+}
+
+/// A failed LDAP bind or web login, see [`BackendHandler::record_auth_failure`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct AuthFailure {
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// The user the client tried to authenticate as, if it's a valid user ID. The user doesn't
+    /// have to exist.
+    pub user_id: Option<String>,
+    pub source_ip: Option<std::net::IpAddr>,
+    pub protocol: AuthProtocol,
+    pub reason: AuthFailureReason,
+}
+
+/// The format of the logs, e.g. `protocol=ldap user=bob ip=10.0.0.1 reason=invalid_credentials`,
+/// for tools like fail2ban. A missing user or IP is written `-`.
+impl std::fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "protocol={} user={} ip={} reason={}",
+            self.protocol.as_str(),
+            self.user_id.as_deref().unwrap_or("-"),
+            self.source_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.reason.as_str()
+        )
+    }
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+/// A group, with the IDs of its members.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct Group {
     pub id: GroupId,
     pub display_name: String,
     pub users: Vec<String>,
 }
 
+/// The details of a group besides its name and members, see
+/// [`BackendHandler::get_group_metadata`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
+pub struct GroupMetadata {
+    pub description: Option<String>,
+    pub creation_date: chrono::DateTime<chrono::Utc>,
+    /// Generated when the group is created, and never changed: exposed as the `entryUUID`.
+    pub uuid: String,
+}
+
+impl Default for GroupMetadata {
+    fn default() -> Self {
+        use chrono::TimeZone;
+        GroupMetadata {
+            description: None,
+            creation_date: chrono::Utc.timestamp(0, 0),
+            uuid: String::new(),
+        }
+    }
+}
+
+/// The addresses of a group, for the mail servers to resolve them to its members.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GroupMail {
+    pub email: Option<String>,
+    /// The other addresses of the group, sorted.
+    pub aliases: Vec<String>,
+}
+
+impl GroupMail {
+    /// Trims the addresses, and drops the empty ones and the duplicates.
+    pub fn normalize(self) -> Self {
+        let email = self
+            .email
+            .map(|email| email.trim().to_string())
+            .filter(|email| !email.is_empty());
+        let aliases = self
+            .aliases
+            .iter()
+            .map(|alias| alias.trim())
+            .filter(|alias| !alias.is_empty() && Some(*alias) != email.as_deref())
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        Self { email, aliases }
+    }
+
+    /// All the addresses, the main one first.
+    pub fn addresses(&self) -> impl Iterator<Item = &String> {
+        self.email.iter().chain(self.aliases.iter())
+    }
+}
+
+/// A group with the number of its members, see [`BackendHandler::list_groups_with_member_count`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct GroupSummary {
+    pub id: GroupId,
+    pub display_name: String,
+    pub member_count: usize,
+}
+
+/// A page of the members of a group, see [`BackendHandler::get_group_members`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GroupMembersPage {
+    /// The members in the page, sorted by user ID.
+    pub users: Vec<User>,
+    /// The number of members of the group, across all the pages.
+    pub total: usize,
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct BindRequest {
     pub name: String,
     pub password: String,
 }
 
+/// The user fields that [`RequestFilter::Equality`] can test. Any other field matches no user.
+pub const USER_FILTER_FIELDS: &[&str] = &[
+    "user_id",
+    "email",
+    "display_name",
+    "first_name",
+    "last_name",
+    "avatar",
+    "creation_date",
+    "uuid",
+    "uid_number",
+];
+
+/// The parts of a substring match: the value starts with `initial`, then contains the `any` parts
+/// in order, and ends with `final_`. E.g. `jo*n*` is `initial: "jo", any: ["n"]`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SubstringFilter {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
+}
+
+impl SubstringFilter {
+    /// Whether the value matches, ignoring the case.
+    pub fn matches(&self, value: &str) -> bool {
+        let mut value = value.to_lowercase();
+        if let Some(initial) = &self.initial {
+            match value.strip_prefix(&initial.to_lowercase()) {
+                Some(rest) => value = rest.to_string(),
+                None => return false,
+            }
+        }
+        if let Some(final_) = &self.final_ {
+            match value.strip_suffix(&final_.to_lowercase()) {
+                Some(rest) => value = rest.to_string(),
+                None => return false,
+            }
+        }
+        let mut rest = value.as_str();
+        for part in &self.any {
+            match rest.find(&part.to_lowercase()) {
+                Some(index) => rest = &rest[index + part.to_lowercase().len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// A boolean expression used to select users, see [`BackendHandler::list_users`].
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub enum RequestFilter {
     And(Vec<RequestFilter>),
     Or(Vec<RequestFilter>),
     Not(Box<RequestFilter>),
+    /// A field (one of [`USER_FILTER_FIELDS`]) and its value.
     Equality(String, String),
-    // Check if a user belongs to a group identified by name.
+    /// A field (one of [`USER_FILTER_FIELDS`]) and the substrings of its value.
+    Substring(String, SubstringFilter),
+    /// A custom attribute (see the `ldap_schema` configuration) and one of its values.
+    AttributeEquality(String, String),
+    // Check if a user belongs to a group identified by name, directly or through nested groups.
     MemberOf(String),
-    // Same, by id.
+    // Check if a user is a direct member of a group identified by id.
     MemberOfId(GroupId),
 }
 
+/// A boolean expression used to select groups, see [`BackendHandler::list_groups`].
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub enum GroupRequestFilter {
+    And(Vec<GroupRequestFilter>),
+    Or(Vec<GroupRequestFilter>),
+    Not(Box<GroupRequestFilter>),
+    /// The group with this name, ignoring the case.
+    DisplayName(String),
+    /// The groups whose name contains the substrings, ignoring the case.
+    DisplayNameSubstring(SubstringFilter),
+    GroupId(GroupId),
+    /// The groups of the user, directly or through nested groups, as in
+    /// [`BackendHandler::get_user_groups`].
+    Member(String),
+    /// The groups with this address or alias, ignoring the case.
+    Mail(String),
+}
+
+/// The order of the users produced by [`BackendHandler::list_users_stream`]. Ties are broken by
+/// user ID, so that the same users always come in the same order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserOrder {
+    UserId,
+    Email,
+    DisplayName,
+    CreationDate,
+}
+
+impl Default for UserOrder {
+    fn default() -> Self {
+        UserOrder::UserId
+    }
+}
+
+impl UserOrder {
+    /// Sorts users that are already sorted by user ID.
+    pub fn sort(self, users: &mut [User]) {
+        // The sort is stable, so the ties stay sorted by user ID.
+        match self {
+            UserOrder::UserId => (),
+            UserOrder::Email => users.sort_by(|a, b| a.email.cmp(&b.email)),
+            UserOrder::DisplayName => users.sort_by(|a, b| a.display_name.cmp(&b.display_name)),
+            UserOrder::CreationDate => users.sort_by_key(|u| u.creation_date),
+        }
+    }
+}
+
+/// The direction of the order of [`BackendHandler::list_users_page`]: descending is the exact
+/// reverse of ascending, ties included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+impl Default for SortDirection {
+    fn default() -> Self {
+        SortDirection::Ascending
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CreateUserRequest {
     // Same fields as User, but no creation_date, and with password.
@@ -72,40 +387,375 @@ pub struct UpdateUserRequest {
     pub display_name: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    /// The image, already validated.
+    pub avatar: Option<Vec<u8>>,
+}
+
+/// The memberships changed by [`BackendHandler::set_group_members`], sorted by user ID.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MembershipChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl MembershipChanges {
+    /// The changes turning the `current` members into the `wanted` ones.
+    pub fn new(current: &[String], wanted: &[String]) -> Self {
+        use std::collections::BTreeSet;
+        let current = current.iter().collect::<BTreeSet<_>>();
+        let wanted = wanted.iter().collect::<BTreeSet<_>>();
+        MembershipChanges {
+            added: wanted.difference(&current).map(|u| u.to_string()).collect(),
+            removed: current.difference(&wanted).map(|u| u.to_string()).collect(),
+        }
+    }
+}
+
+/// The groups nested in other groups, see [`BackendHandler::add_group_to_group`].
+///
+/// The members of a group are members of all the groups it is nested in, directly or not.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct GroupNesting {
+    /// The groups each group is directly nested in.
+    parents: HashMap<GroupId, BTreeSet<GroupId>>,
+}
+
+impl GroupNesting {
+    /// Builds the nesting from `(member_group, parent_group)` pairs.
+    pub fn new(pairs: impl IntoIterator<Item = (GroupId, GroupId)>) -> Self {
+        let mut parents = HashMap::<_, BTreeSet<_>>::new();
+        for (member, parent) in pairs {
+            parents.entry(member).or_default().insert(parent);
+        }
+        Self { parents }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    /// Whether `member` is directly nested in `parent`.
+    pub fn contains(&self, member: GroupId, parent: GroupId) -> bool {
+        self.parents
+            .get(&member)
+            .map(|parents| parents.contains(&parent))
+            .unwrap_or(false)
+    }
+
+    /// The groups and all the groups they are nested in, directly or not. A cycle doesn't make
+    /// it loop: each group is visited once.
+    pub fn with_ancestors(&self, groups: impl IntoIterator<Item = GroupId>) -> HashSet<GroupId> {
+        let mut visited = HashSet::new();
+        let mut to_visit = groups.into_iter().collect::<Vec<_>>();
+        while let Some(group) = to_visit.pop() {
+            if visited.insert(group) {
+                if let Some(parents) = self.parents.get(&group) {
+                    to_visit.extend(parents.iter().copied());
+                }
+            }
+        }
+        visited
+    }
+
+    /// The group and all the groups nested in it, directly or not.
+    pub fn with_descendants(&self, group: GroupId) -> HashSet<GroupId> {
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![group];
+        while let Some(group) = to_visit.pop() {
+            if visited.insert(group) {
+                to_visit.extend(
+                    self.parents
+                        .iter()
+                        .filter(|(_, parents)| parents.contains(&group))
+                        .map(|(member, _)| *member),
+                );
+            }
+        }
+        visited
+    }
+
+    /// Whether nesting `member` in `parent` would make a group nested in itself.
+    pub fn would_create_cycle(&self, member: GroupId, parent: GroupId) -> bool {
+        self.with_ancestors(std::iter::once(parent))
+            .contains(&member)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct UpdateGroupRequest {
     pub group_id: GroupId,
     pub display_name: Option<String>,
+    /// An empty description removes it.
+    pub description: Option<String>,
 }
 
+/// Checks clear-text credentials, for the protocols that need them (LDAP bind).
 #[async_trait]
 pub trait LoginHandler: Clone + Send {
+    /// Returns an error unless the password is correct for the user.
     async fn bind(&self, request: BindRequest) -> Result<()>;
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct GroupId(pub i32);
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::FromRow)]
 pub struct GroupIdAndName(pub GroupId, pub String);
 
+/// The user store: all the operations on users, groups and memberships.
+///
+/// This is the main extension point to embed lldap: the LDAP and GraphQL servers are generic over
+/// it.
 #[async_trait]
 pub trait BackendHandler: Clone + Send {
+    /// Lists the users matching the filter (all of them if `None`), sorted by user ID.
     async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>>;
-    async fn list_groups(&self) -> Result<Vec<Group>>;
+    /// Same as `list_users`, but produces the users as they are read, for large results, in the
+    /// given order.
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        stream::once(self.list_users(filters))
+            .map_ok(move |mut users| {
+                order.sort(&mut users);
+                stream::iter(users.into_iter().map(Ok))
+            })
+            .try_flatten()
+            .boxed()
+    }
+    /// Same as `list_users`, but returns at most `limit` users in the given order, skipping the
+    /// first `offset` ones, for the clients that page through the results.
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        let mut users = self.list_users(filters).await?;
+        order.sort(&mut users);
+        if direction == SortDirection::Descending {
+            users.reverse();
+        }
+        Ok(users.into_iter().skip(offset).take(limit).collect())
+    }
+    /// The number of users matching the filter, e.g. to count the pages of `list_users_page`.
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        Ok(self.list_users(filters).await?.len())
+    }
+    /// Lists the groups matching the filter (all of them without one) with their direct members,
+    /// sorted by name.
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+    /// Same as `list_groups`, but returns at most `limit` groups, skipping the first `offset` ones.
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        Ok(self
+            .list_groups(filters)
+            .await?
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+    /// Same as `list_groups`, but only counts the members instead of listing them.
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        Ok(self
+            .list_groups(None)
+            .await?
+            .into_iter()
+            .map(|group| GroupSummary {
+                id: group.id,
+                display_name: group.display_name,
+                member_count: group.users.len(),
+            })
+            .collect())
+    }
     async fn get_user_details(&self, user_id: &str) -> Result<User>;
+    /// The avatar of the user, as stored by `update_user`, or `None` if they don't have one.
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>>;
+    /// Returns the user with this email, compared case-insensitively. If several users share it,
+    /// the first one by user ID.
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let email = email.to_lowercase();
+        Ok(self
+            .list_users(None)
+            .await?
+            .into_iter()
+            .find(|user| user.email.to_lowercase() == email))
+    }
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+    /// The groups the group is nested in, directly or not, without the group itself.
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>>;
+    /// Returns at most `limit` members of the group, skipping the first `offset` ones, so that
+    /// groups with many members can be listed a page at a time.
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        let users = self
+            .list_users(Some(RequestFilter::MemberOfId(group_id)))
+            .await?;
+        Ok(GroupMembersPage {
+            total: users.len(),
+            users: users.into_iter().skip(offset).take(limit).collect(),
+        })
+    }
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+    /// Creates the users, in a single transaction where supported, and returns the outcome of
+    /// each request in order: a request failing, e.g. because the user ID is taken, doesn't
+    /// prevent the others from being created.
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(self.create_user(request).await);
+        }
+        Ok(results)
+    }
+    /// Updates the fields that are set in the request, leaving the others untouched.
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
+    /// Moves the user to the recycle bin, where it is invisible (including to the searches and the
+    /// binds) until restored with `restore_user`, or purged after the retention.
     async fn delete_user(&self, user_id: &str) -> Result<()>;
+    /// Takes the user out of the recycle bin, with its groups, attributes and password.
+    async fn restore_user(&self, user_id: &str) -> Result<()>;
+    /// The users in the recycle bin, most recently deleted first.
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>>;
+    /// Changes the ID of the user, along with the memberships, passwords and sessions referencing
+    /// it. The UUID stays the same. Fails if the new ID is taken.
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()>;
+    /// Creates a group, returning its newly allocated ID.
     async fn create_group(&self, group_name: &str) -> Result<GroupId>;
     async fn delete_group(&self, group_id: GroupId) -> Result<()>;
     async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
+    /// Adds all the users to the group. By default they are added one by one, and a failure (e.g.
+    /// one is already a member) leaves the previous ones added: the SQL backend overrides it to add
+    /// all of them or none.
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        for user_id in user_ids {
+            self.add_user_to_group(user_id, group_id).await?;
+        }
+        Ok(())
+    }
+    /// Removes the users from the group, ignoring the ones that aren't members.
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let members = self
+            .list_users(Some(RequestFilter::MemberOfId(group_id)))
+            .await?;
+        for user_id in user_ids {
+            if members.iter().any(|u| &u.user_id == user_id) {
+                self.remove_user_from_group(user_id, group_id).await?;
+            }
+        }
+        Ok(())
+    }
+    /// Makes `user_ids` the exact list of members of the group, only adding and removing the
+    /// memberships that differ.
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        let current = self
+            .list_users(Some(RequestFilter::MemberOfId(group_id)))
+            .await?
+            .into_iter()
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
+        let changes = MembershipChanges::new(&current, user_ids);
+        self.add_users_to_group(group_id, &changes.added).await?;
+        self.remove_users_from_group(group_id, &changes.removed)
+            .await?;
+        Ok(changes)
+    }
+    /// Nests a group in another one: the members of `member_group_id` become members of
+    /// `parent_group_id` too. Fails if it would make a group nested in itself.
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()>;
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()>;
+    /// Returns the groups the user is a member of, directly or through nested groups.
     async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>>;
+    /// Same as `get_user_groups` for several users at once, with an entry for each of them.
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        let mut groups = HashMap::new();
+        for user_id in user_ids {
+            groups.insert(user_id.clone(), self.get_user_groups(user_id).await?);
+        }
+        Ok(groups)
+    }
+    /// Returns the hosts the user is allowed to log into, sorted, for host-based access control
+    /// (e.g. sssd's `ldap_access_order = host`).
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>>;
+    /// Replaces the list of hosts the user is allowed to log into.
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()>;
+    /// Returns the description, creation date and UUID of the group.
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata>;
+    /// Returns the email address and the aliases of the group.
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail>;
+    /// Replaces the email address and the aliases of the group, see [`GroupMail::normalize`].
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()>;
+    /// Returns the values of the custom attributes of the user (see the `ldap_schema`
+    /// configuration), sorted, by attribute name.
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>>;
+    /// Replaces the values of a custom attribute of the user. Without values, the user no longer
+    /// has the attribute.
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()>;
+    /// Prevents the user from logging in (with LDAP, the web login or a refresh token) until
+    /// `unlock_user` is called. Locking a locked user replaces the reason.
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()>;
+    async fn unlock_user(&self, user_id: &str) -> Result<()>;
+    /// Returns the lock of the user, if they are locked.
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>>;
+    /// Returns when the password of each user that has one was last set. The passwords set before
+    /// these dates were recorded count as set when the user was created.
+    async fn list_password_changes(&self)
+        -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>>;
+    /// Same as `list_password_changes` for a single user: `None` if they don't have a password.
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        Ok(self.list_password_changes().await?.remove(user_id))
+    }
+    /// Records a failed authentication, for the admins to investigate. A `user_id` that isn't a
+    /// valid user ID is not kept.
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()>;
+    /// Returns the failed authentications, most recent first, optionally only the ones of a user.
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>>;
+    /// Adds an entry to the audit log.
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()>;
+    /// Returns the entries of the audit log, most recent first.
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>>;
 }
 
 #[cfg(test)]
@@ -117,18 +767,74 @@ mockall::mock! {
     #[async_trait]
     impl BackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>>;
-        async fn list_groups(&self) -> Result<Vec<Group>>;
+        async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
         async fn get_user_details(&self, user_id: &str) -> Result<User>;
+        async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+        async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_group_members(
+            &self,
+            group_id: GroupId,
+            offset: usize,
+            limit: usize,
+        ) -> Result<GroupMembersPage>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &str) -> Result<()>;
+        async fn restore_user(&self, user_id: &str) -> Result<()>;
+        async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>>;
+        async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
         async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_users_groups(
+            &self,
+            user_ids: &[String],
+        ) -> Result<HashMap<String, HashSet<GroupIdAndName>>>;
         async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
+        async fn add_group_to_group(
+            &self,
+            member_group_id: GroupId,
+            parent_group_id: GroupId,
+        ) -> Result<()>;
+        async fn remove_group_from_group(
+            &self,
+            member_group_id: GroupId,
+            parent_group_id: GroupId,
+        ) -> Result<()>;
+        async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>>;
+        async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()>;
+        async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata>;
+        async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail>;
+        async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()>;
+        async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>>;
+        async fn set_user_attribute(
+            &self,
+            user_id: &str,
+            name: &str,
+            values: Vec<String>,
+        ) -> Result<()>;
+        async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()>;
+        async fn unlock_user(&self, user_id: &str) -> Result<()>;
+        async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>>;
+        async fn list_password_changes(
+            &self,
+        ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>>;
+        async fn get_password_change(
+            &self,
+            user_id: &str,
+        ) -> Result<Option<chrono::DateTime<chrono::Utc>>>;
+        async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()>;
+        async fn list_auth_failures(
+            &self,
+            user_id: Option<&str>,
+            offset: usize,
+            limit: usize,
+        ) -> Result<Vec<AuthFailure>>;
+        async fn record_audit_event(&self, event: AuditEvent) -> Result<()>;
+        async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>>;
     }
     #[async_trait]
     impl LoginHandler for TestBackendHandler {
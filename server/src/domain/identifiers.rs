@@ -0,0 +1,180 @@
+//! Normalization and validation of the user identifiers and names, so that two accounts can't
+//! look identical while being different strings, and so that the user IDs don't need escaping in
+//! the LDAP DNs. The user IDs and the emails are also folded to lowercase, as LDAP matches them
+//! ignoring the case.
+
+use super::error::{DomainError, Result};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// The characters accepted in user IDs, on top of [`USER_ID_PUNCTUATION`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserIdPolicy {
+    /// ASCII letters and digits.
+    Ascii,
+    /// Letters and digits of any script.
+    Unicode,
+    /// Letters and digits of any script, but a single one per user ID, so that a user ID can't
+    /// mimic another with lookalike letters ("pаypal" with a Cyrillic "а"). Latin can be mixed
+    /// with the CJK scripts, which are often written together.
+    SingleScript,
+}
+
+/// The scripts told apart by [`UserIdPolicy::SingleScript`], from the Unicode blocks of the
+/// letters. The letters of the other blocks all count as one script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Cjk,
+    Hangul,
+    Other,
+}
+
+/// The script of a letter, or `None` for the digits and punctuation, shared by all the scripts.
+fn get_script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    Some(match c as u32 {
+        0x0000..=0x024F | 0x1E00..=0x1EFF | 0x2C60..=0x2C7F | 0xA720..=0xA7FF | 0xFF21..=0xFF5A => {
+            Script::Latin
+        }
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x0400..=0x052F | 0x1C80..=0x1C8F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+        0x0530..=0x058F => Script::Armenian,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0600..=0x06FF | 0x0750..=0x077F | 0x08A0..=0x08FF => Script::Arabic,
+        0x3040..=0x30FF
+        | 0x31F0..=0x31FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xF900..=0xFAFF
+        | 0x20000..=0x3FFFF => Script::Cjk,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+        _ => Script::Other,
+    })
+}
+
+/// Whether the letters of the user ID are from a single script, or from Latin and the CJK
+/// scripts, as in the "highly restrictive" level of Unicode's UTS #39.
+fn is_single_script(user_id: &str) -> bool {
+    let mut scripts = user_id.chars().filter_map(get_script).collect::<Vec<_>>();
+    scripts.sort_by_key(|s| *s as u8);
+    scripts.dedup();
+    match scripts.as_slice() {
+        [] | [_] => true,
+        [Script::Latin, rest @ ..] => rest
+            .iter()
+            .all(|s| matches!(s, Script::Cjk | Script::Hangul)),
+        [Script::Cjk, Script::Hangul] => true,
+        _ => false,
+    }
+}
+
+pub const USER_ID_PUNCTUATION: &[char] = &['.', '_', '-', '@'];
+
+/// A new random UUID for a user, in the usual hyphenated form. It's stored with the user, so it
+/// doesn't depend on the user ID.
+pub fn generate_uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The lowercase NFC form of a user ID or an email, that they are stored and looked up with.
+pub fn fold_case(value: &str) -> String {
+    value.nfc().collect::<String>().to_lowercase()
+}
+
+/// Returns the folded form of the user ID, or an error if it contains a character not allowed by
+/// the policy.
+pub fn normalize_user_id(user_id: &str, policy: UserIdPolicy) -> Result<String> {
+    let user_id = fold_case(user_id);
+    if user_id.is_empty() {
+        return Err(DomainError::InvalidInput("empty user ID".to_string()));
+    }
+    let is_allowed = |c: &char| {
+        USER_ID_PUNCTUATION.contains(c)
+            || match policy {
+                UserIdPolicy::Ascii => c.is_ascii_alphanumeric(),
+                UserIdPolicy::Unicode | UserIdPolicy::SingleScript => c.is_alphanumeric(),
+            }
+    };
+    if let Some(c) = user_id.chars().find(|c| !is_allowed(c)) {
+        return Err(DomainError::InvalidInput(format!(
+            "character {:?} is not allowed in user IDs",
+            c
+        )));
+    }
+    if policy == UserIdPolicy::SingleScript && !is_single_script(&user_id) {
+        return Err(DomainError::InvalidInput(
+            "user IDs can't mix letters from several scripts".to_string(),
+        ));
+    }
+    Ok(user_id)
+}
+
+/// Returns the NFC form of the name, or an error if it contains control characters.
+pub fn normalize_name(name: &str) -> Result<String> {
+    let name = name.nfc().collect::<String>();
+    match name.chars().find(|c| c.is_control()) {
+        Some(c) => Err(DomainError::InvalidInput(format!(
+            "character {:?} is not allowed in names",
+            c
+        ))),
+        None => Ok(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_user_id() {
+        // "é" as "e" followed by a combining acute accent.
+        assert_eq!(
+            normalize_user_id("jose\u{301}", UserIdPolicy::Unicode).unwrap(),
+            "jos\u{e9}"
+        );
+        assert_eq!(
+            normalize_user_id("John.Doe@example", UserIdPolicy::Ascii).unwrap(),
+            "john.doe@example"
+        );
+        assert!(normalize_user_id("jos\u{e9}", UserIdPolicy::Ascii).is_err());
+        assert!(normalize_user_id("john,ou=admins", UserIdPolicy::Unicode).is_err());
+        assert!(normalize_user_id("john doe", UserIdPolicy::Unicode).is_err());
+        assert!(normalize_user_id("", UserIdPolicy::Unicode).is_err());
+    }
+
+    #[test]
+    fn test_normalize_user_id_single_script() {
+        let normalize = |user_id| normalize_user_id(user_id, UserIdPolicy::SingleScript);
+        assert_eq!(normalize("Jos\u{e9}.2").unwrap(), "jos\u{e9}.2");
+        assert_eq!(
+            normalize("\u{414}\u{438}\u{43c}\u{430}").unwrap(),
+            "\u{434}\u{438}\u{43c}\u{430}"
+        );
+        normalize("tanaka\u{592a}\u{90ce}").unwrap();
+        // "paypal" with a Cyrillic "а".
+        assert!(normalize("p\u{430}ypal").is_err());
+        assert!(normalize("\u{3b1}lpha").is_err());
+        assert!(normalize_user_id("p\u{430}ypal", UserIdPolicy::Unicode).is_ok());
+    }
+
+    #[test]
+    fn test_fold_case() {
+        assert_eq!(fold_case("Bob@Example.com"), "bob@example.com");
+        assert_eq!(fold_case("JOSE\u{301}"), "jos\u{e9}");
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("Jose\u{301} Doe").unwrap(), "Jos\u{e9} Doe");
+        assert!(normalize_name("John\u{0}Doe").is_err());
+    }
+}
@@ -1,6 +1,17 @@
+//! The domain layer: users, groups, memberships and passwords, independent of the protocols used
+//! to access them.
+//!
+//! [`handler`] defines the interface to the user store, [`sql_backend_handler`] and
+//! [`sql_opaque_handler`] implement it on top of a SQL database whose schema is created by
+//! [`sql_tables::init_table`].
+
 pub mod error;
 pub mod handler;
+pub mod identifiers;
 pub mod opaque_handler;
 pub mod sql_backend_handler;
 pub mod sql_opaque_handler;
 pub mod sql_tables;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_backend_handler;
+pub mod validation;
@@ -18,6 +18,9 @@ pub trait OpaqueHandler: Clone + Send {
         &self,
         request: registration::ClientRegistrationFinishRequest,
     ) -> Result<()>;
+    /// Whether the password is the current one of the user or one of the previous ones kept in
+    /// the history, see the `history_size` of the password policy.
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool>;
 }
 
 #[cfg(test)]
@@ -41,5 +44,6 @@ mockall::mock! {
             &self,
             request: registration::ClientRegistrationFinishRequest
         ) -> Result<()>;
+        async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool>;
     }
 }
@@ -1,10 +1,46 @@
-use super::{error::*, handler::*, sql_tables::*};
+use super::{
+    error::*,
+    handler::*,
+    identifiers::{fold_case, generate_uuid, normalize_name, normalize_user_id},
+    sql_tables::*,
+    validation::{validate_create_user, validate_update_user, validate_user_id},
+};
 use crate::infra::configuration::Configuration;
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Order, Query, SimpleExpr};
-use sqlx::Row;
-use std::collections::HashSet;
+use sea_query::{Alias, Expr, Func, Iden, Order, Query, SimpleExpr, Value};
+use sqlx::{any::AnyArguments, Arguments, Row};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use tracing::instrument;
+
+pub type Transaction = sqlx::Transaction<'static, sqlx::Any>;
+
+/// The columns set when creating a user.
+fn get_new_user_columns() -> Vec<Users> {
+    vec![
+        Users::UserId,
+        Users::Email,
+        Users::DisplayName,
+        Users::FirstName,
+        Users::LastName,
+        Users::CreationDate,
+        Users::Uuid,
+        Users::UidNumber,
+    ]
+}
+
+/// Changes to the directory applied at once by [`SqlBackendHandler::apply_changes`], e.g. for an
+/// import. They are expected to be consistent with the database: the created users don't exist
+/// yet, and the group members are not members already.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DirectoryChanges {
+    pub created_users: Vec<CreateUserRequest>,
+    pub updated_users: Vec<UpdateUserRequest>,
+    /// The users to add to each group, by group name. The groups that don't exist are created.
+    pub group_members: Vec<(String, Vec<String>)>,
+}
 
 #[derive(Debug, Clone)]
 pub struct SqlBackendHandler {
@@ -16,51 +52,489 @@ impl SqlBackendHandler {
     pub fn new(config: Configuration, sql_pool: Pool) -> Self {
         SqlBackendHandler { config, sql_pool }
     }
+
+    pub(crate) fn backend(&self) -> DbBackend {
+        DbBackend::of(&self.sql_pool)
+    }
+
+    /// Runs the operations in a single transaction, committed if they succeed. The operations
+    /// take the transaction and give it back with their result: if they fail, it is dropped
+    /// without being committed, which rolls it back.
+    pub async fn with_transaction<T, F, Fut>(&self, operations: F) -> Result<T>
+    where
+        F: FnOnce(Transaction) -> Fut,
+        Fut: Future<Output = Result<(Transaction, T)>>,
+    {
+        let transaction = self.sql_pool.begin().await?;
+        let (transaction, result) = operations(transaction).await?;
+        transaction.commit().await?;
+        Ok(result)
+    }
+
+    /// The normalized user ID, and the values of the [`get_new_user_columns`] for the new user.
+    fn get_new_user_values(
+        &self,
+        request: &CreateUserRequest,
+        uid_number: i32,
+    ) -> Result<(String, Vec<Value>)> {
+        validate_create_user(request, self.config.user_id_policy)?;
+        let user_id = normalize_user_id(&request.user_id, self.config.user_id_policy)?;
+        let name = |name: &Option<String>| normalize_name(name.as_deref().unwrap_or_default());
+        let values = vec![
+            user_id.clone().into(),
+            fold_case(&request.email).into(),
+            name(&request.display_name)?.into(),
+            name(&request.first_name)?.into(),
+            name(&request.last_name)?.into(),
+            chrono::Utc::now().naive_utc().into(),
+            generate_uuid().into(),
+            uid_number.into(),
+        ];
+        Ok((user_id, values))
+    }
+
+    /// Inserts the new users, given with their index in the results and their `uidNumber`, with
+    /// a single query. The query runs in a savepoint: when it fails, the transaction goes on as if
+    /// it had not run.
+    async fn insert_new_users(
+        &self,
+        transaction: &mut Transaction,
+        users: &[(usize, &CreateUserRequest, i32)],
+    ) -> Result<()> {
+        let mut query = Query::insert();
+        query
+            .into_table(Users::Table)
+            .columns(get_new_user_columns());
+        for &(_, request, uid_number) in users {
+            query.values_panic(self.get_new_user_values(request, uid_number)?.1);
+        }
+        let (query, values) = query.build_db_query(self.backend());
+        let mut savepoint = sqlx::Connection::begin(&mut **transaction).await?;
+        match sqlx::query_with(&query, values)
+            .execute(&mut savepoint)
+            .await
+        {
+            Ok(_) => savepoint.commit().await?,
+            Err(error) => {
+                savepoint.rollback().await?;
+                return Err(map_conflict(|| {
+                    "some of the user IDs or emails are already used".to_string()
+                })(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `uidNumber` of the next user: the one after the highest, and at least the configured
+    /// first one. The numbers are unique: an insertion racing with another one for the same
+    /// number conflicts, and is retried by [`retry_on_conflict`], or allocated again by
+    /// `bulk_create_users`.
+    async fn get_next_uid_number(&self, transaction: &mut Transaction) -> Result<i32> {
+        let (query, values) = Query::select()
+            .column(Users::UidNumber)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UidNumber).is_not_null())
+            .order_by(Users::UidNumber, Order::Desc)
+            .limit(1)
+            .build_db_query(self.backend());
+        let highest = sqlx::query_with(&query, values)
+            .map(|row: DbRow| row.get::<i32, _>(&*Users::UidNumber.to_string()))
+            .fetch_optional(&mut *transaction)
+            .await?;
+        let first = self.config.posix.first_uid_number;
+        Ok(highest.map_or(first, |highest| std::cmp::max(highest + 1, first)))
+    }
+
+    /// The query updating the fields that are set in the request, if any.
+    fn get_user_update_query(
+        &self,
+        request: UpdateUserRequest,
+    ) -> Result<Option<(String, AnyArguments<'static>)>> {
+        let mut values = Vec::new();
+        if let Some(email) = request.email {
+            values.push((Users::Email, fold_case(&email).into()));
+        }
+        if let Some(display_name) = request.display_name {
+            values.push((Users::DisplayName, normalize_name(&display_name)?.into()));
+        }
+        if let Some(first_name) = request.first_name {
+            values.push((Users::FirstName, normalize_name(&first_name)?.into()));
+        }
+        if let Some(last_name) = request.last_name {
+            values.push((Users::LastName, normalize_name(&last_name)?.into()));
+        }
+        if let Some(avatar) = request.avatar {
+            values.push((Users::Avatar, avatar.into()));
+        }
+        if values.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            Query::update()
+                .table(Users::Table)
+                .values(values)
+                .and_where(Expr::col(Users::UserId).eq(fold_case(&request.user_id)))
+                .build_db_query(self.backend()),
+        ))
+    }
+
+    /// Applies all the changes in a single transaction: if one of them fails, none is applied.
+    pub async fn apply_changes(&self, changes: DirectoryChanges) -> Result<()> {
+        let backend = self.backend();
+        let DirectoryChanges {
+            created_users,
+            updated_users,
+            group_members,
+        } = changes;
+        let (created_users, updated_users, group_members) =
+            (&created_users, &updated_users, &group_members);
+        retry_on_conflict(|| {
+            self.with_transaction(|mut transaction| async move {
+                let mut update_queries = Vec::new();
+                for request in updated_users {
+                    update_queries.extend(self.get_user_update_query(request.clone())?);
+                }
+                if !created_users.is_empty() {
+                    let mut query = Query::insert();
+                    query
+                        .into_table(Users::Table)
+                        .columns(get_new_user_columns());
+                    let first_uid_number = self.get_next_uid_number(&mut transaction).await?;
+                    for (uid_number, request) in (first_uid_number..).zip(created_users) {
+                        query.values_panic(self.get_new_user_values(request, uid_number)?.1);
+                    }
+                    let (query, values) = query.build_db_query(backend);
+                    sqlx::query_with(&query, values)
+                        .execute(&mut transaction)
+                        .await
+                        .map_err(map_conflict(|| {
+                            "some of the users are already created".to_string()
+                        }))?;
+                }
+                for (query, values) in update_queries {
+                    sqlx::query_with(&query, values)
+                        .execute(&mut transaction)
+                        .await?;
+                }
+                // The group names are matched ignoring the case.
+                let mut group_ids = get_all_groups(&mut transaction, backend)
+                    .await?
+                    .into_iter()
+                    .map(|GroupIdAndName(id, name)| (fold_case(&name), id))
+                    .collect::<HashMap<_, _>>();
+                for (group_name, user_ids) in group_members {
+                    let group_id = match group_ids.get(&fold_case(group_name)) {
+                        Some(group_id) => *group_id,
+                        None => {
+                            let group_id =
+                                insert_group(&mut transaction, backend, group_name).await?;
+                            group_ids.insert(fold_case(group_name), group_id);
+                            group_id
+                        }
+                    };
+                    if user_ids.is_empty() {
+                        continue;
+                    }
+                    let mut query = Query::insert();
+                    query
+                        .into_table(Memberships::Table)
+                        .columns(vec![Memberships::UserId, Memberships::GroupId]);
+                    for user_id in user_ids {
+                        query.values_panic(vec![fold_case(user_id).into(), group_id.into()]);
+                    }
+                    let (query, values) = query.build_db_query(backend);
+                    sqlx::query_with(&query, values)
+                        .execute(&mut transaction)
+                        .await?;
+                }
+                Ok((transaction, ()))
+            })
+        })
+        .await
+    }
+
+    /// Fails if another group has the same name, ignoring the case.
+    async fn check_group_name_is_available(
+        &self,
+        group_name: &str,
+        group_id: Option<GroupId>,
+    ) -> Result<()> {
+        let folded_name = fold_case(group_name);
+        match get_all_groups(&self.sql_pool, self.backend())
+            .await?
+            .into_iter()
+            .find(|g| Some(g.0) != group_id && fold_case(&g.1) == folded_name)
+        {
+            Some(group) => Err(DomainError::Conflict(format!(
+                "group name {} is already used by the group {}",
+                group_name, group.1
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the names of the groups with their IDs, as the names are compared ignoring the
+    /// case, and the members with the IDs of their groups, which include the nested ones.
+    async fn resolve_group_filter(&self, filter: GroupRequestFilter) -> Result<GroupRequestFilter> {
+        fn collect_names(
+            filter: &GroupRequestFilter,
+            members: &mut Vec<String>,
+            has_name: &mut bool,
+        ) {
+            match filter {
+                GroupRequestFilter::And(fs) | GroupRequestFilter::Or(fs) => {
+                    fs.iter().for_each(|f| collect_names(f, members, has_name))
+                }
+                GroupRequestFilter::Not(f) => collect_names(f, members, has_name),
+                GroupRequestFilter::DisplayName(_) => *has_name = true,
+                GroupRequestFilter::Member(user) => members.push(user.clone()),
+                _ => (),
+            }
+        }
+        fn resolve(
+            filter: GroupRequestFilter,
+            group_ids: &HashMap<String, GroupId>,
+            user_groups: &HashMap<String, Vec<GroupId>>,
+        ) -> GroupRequestFilter {
+            use GroupRequestFilter::*;
+            match filter {
+                And(fs) => And(fs
+                    .into_iter()
+                    .map(|f| resolve(f, group_ids, user_groups))
+                    .collect()),
+                Or(fs) => Or(fs
+                    .into_iter()
+                    .map(|f| resolve(f, group_ids, user_groups))
+                    .collect()),
+                Not(f) => Not(Box::new(resolve(*f, group_ids, user_groups))),
+                DisplayName(name) => match group_ids.get(&fold_case(&name)) {
+                    Some(group_id) => GroupId(*group_id),
+                    None => Or(Vec::new()),
+                },
+                Member(user) => match user_groups.get(&user) {
+                    Some(groups) => Or(groups.iter().copied().map(GroupId).collect()),
+                    None => Member(user),
+                },
+                f => f,
+            }
+        }
+        let mut members = Vec::new();
+        let mut has_name = false;
+        collect_names(&filter, &mut members, &mut has_name);
+        let group_ids = if has_name {
+            get_all_groups(&self.sql_pool, self.backend())
+                .await?
+                .into_iter()
+                .map(|GroupIdAndName(id, name)| (fold_case(&name), id))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let mut user_groups = HashMap::new();
+        for user in members {
+            let mut groups = self
+                .get_user_groups(&user)
+                .await?
+                .into_iter()
+                .map(|g| g.0)
+                .collect::<Vec<_>>();
+            groups.sort();
+            user_groups.insert(user, groups);
+        }
+        Ok(resolve(filter, &group_ids, &user_groups))
+    }
+
+    /// With `unique_emails`, fails if another user already has this email. This only gives a
+    /// clearer error: a concurrent creation can take the email after the check. The uniqueness is
+    /// enforced by the `users_lower_email_unique` index on the folded emails, created by
+    /// [`set_unique_email_index`], whose violations are turned into conflicts by [`map_conflict`].
+    async fn check_email_is_available(&self, email: &str, user_id: &str) -> Result<()> {
+        if !self.config.unique_emails {
+            return Ok(());
+        }
+        match self.get_user_by_email(email).await? {
+            Some(user) if user.user_id != fold_case(user_id) => Err(DomainError::Conflict(
+                format!("email {} is already used by another user", email),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The password change dates of the users with a password, or only of `user_id`.
+    async fn get_password_changes(
+        &self,
+        user_id: Option<&str>,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        let mut query_builder = Query::select()
+            .column((Users::Table, Users::UserId))
+            .expr_as(
+                Expr::cust(&format!(
+                    "COALESCE({}, {})",
+                    self.backend()
+                        .quote_column(&PasswordChanges::Table, &PasswordChanges::ChangedAt),
+                    self.backend()
+                        .quote_column(&Users::Table, &Users::CreationDate),
+                )),
+                Alias::new("changed_at"),
+            )
+            .from(Users::Table)
+            .left_join(
+                PasswordChanges::Table,
+                Expr::tbl(Users::Table, Users::UserId)
+                    .equals(PasswordChanges::Table, PasswordChanges::UserId),
+            )
+            .and_where(Expr::tbl(Users::Table, Users::PasswordHash).is_not_null())
+            .and_where(Expr::tbl(Users::Table, Users::DeletedAt).is_null())
+            .to_owned();
+        if let Some(user_id) = user_id {
+            query_builder.and_where(Expr::tbl(Users::Table, Users::UserId).eq(fold_case(user_id)));
+        }
+        let (query, values) = query_builder.build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.get(&*Users::UserId.to_string()), row.get("changed_at")))
+            .collect())
+    }
 }
 
 struct RequiresGroup(bool);
 
+/// The column of a field in [`USER_FILTER_FIELDS`]. The field names never make it to the query
+/// as they are: an unknown field matches no user.
+fn get_user_column(field: &str) -> Option<Users> {
+    Some(match field {
+        "user_id" => Users::UserId,
+        "email" => Users::Email,
+        "display_name" => Users::DisplayName,
+        "first_name" => Users::FirstName,
+        "last_name" => Users::LastName,
+        "avatar" => Users::Avatar,
+        "creation_date" => Users::CreationDate,
+        "uuid" => Users::Uuid,
+        "uid_number" => Users::UidNumber,
+        _ => return None,
+    })
+}
+
+/// The escape character of the `LIKE` patterns. Not a backslash, that MySQL would read as an escape
+/// in the `ESCAPE` clause itself.
+const LIKE_ESCAPE: char = '!';
+
+/// The pattern of the SQL `LIKE` operator matching the substrings, with [`LIKE_ESCAPE`] escaping
+/// the wildcards they contain.
+fn get_like_pattern(substrings: &SubstringFilter) -> String {
+    let escape = |part: &str| {
+        let mut escaped = String::new();
+        for c in part.chars() {
+            if c == LIKE_ESCAPE || c == '%' || c == '_' {
+                escaped.push(LIKE_ESCAPE);
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    let mut pattern = substrings
+        .initial
+        .as_deref()
+        .map(escape)
+        .unwrap_or_default();
+    for part in &substrings.any {
+        pattern.push('%');
+        pattern.push_str(&escape(part.as_str()));
+    }
+    pattern.push('%');
+    if let Some(final_) = &substrings.final_ {
+        pattern.push_str(&escape(final_.as_str()));
+    }
+    pattern
+}
+
+/// The condition that the column contains the substrings. The match ignores the case, as the LDAP
+/// substring matches do: SQLite's LIKE and MySQL's default collation already do.
+fn get_like_expr(
+    table: &dyn Iden,
+    column: &dyn Iden,
+    substrings: &SubstringFilter,
+    backend: DbBackend,
+) -> SimpleExpr {
+    let operator = match backend {
+        DbBackend::Sqlite | DbBackend::Mysql => "LIKE",
+        DbBackend::Postgres => "ILIKE",
+    };
+    Expr::cust_with_values(
+        &format!(
+            "{} {} ? ESCAPE '{}'",
+            backend.quote_column(table, column),
+            operator,
+            LIKE_ESCAPE
+        ),
+        vec![get_like_pattern(substrings)],
+    )
+}
+
 // Returns the condition for the SQL query, and whether it requires joining with the groups table.
-fn get_filter_expr(filter: RequestFilter) -> (RequiresGroup, SimpleExpr) {
+fn get_filter_expr(filter: RequestFilter, backend: DbBackend) -> (RequiresGroup, SimpleExpr) {
     use RequestFilter::*;
     fn get_repeated_filter(
         fs: Vec<RequestFilter>,
         field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
+        backend: DbBackend,
     ) -> (RequiresGroup, SimpleExpr) {
         let mut requires_group = false;
         let mut it = fs.into_iter();
         let first_expr = match it.next() {
             None => return (RequiresGroup(false), Expr::value(true)),
             Some(f) => {
-                let (group, filter) = get_filter_expr(f);
+                let (group, filter) = get_filter_expr(f, backend);
                 requires_group |= group.0;
                 filter
             }
         };
         let filter = it.fold(first_expr, |e, f| {
-            let (group, filters) = get_filter_expr(f);
+            let (group, filters) = get_filter_expr(f, backend);
             requires_group |= group.0;
             field(e, filters)
         });
         (RequiresGroup(requires_group), filter)
     }
     match filter {
-        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
-        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
+        And(fs) => get_repeated_filter(fs, &SimpleExpr::and, backend),
+        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or, backend),
         Not(f) => {
-            let (requires_group, filters) = get_filter_expr(*f);
+            let (requires_group, filters) = get_filter_expr(*f, backend);
             (requires_group, Expr::not(Expr::expr(filters)))
         }
         Equality(s1, s2) => (
             RequiresGroup(false),
-            if s1 == Users::DisplayName.to_string() {
-                Expr::col((Users::Table, Users::DisplayName)).eq(s2)
-            } else if s1 == Users::UserId.to_string() {
-                Expr::col((Users::Table, Users::UserId)).eq(s2)
-            } else {
-                Expr::expr(Expr::cust(&s1)).eq(s2)
+            match get_user_column(&s1) {
+                // Stored folded, see `fold_case`.
+                Some(column @ Users::UserId) | Some(column @ Users::Email) => {
+                    Expr::col((Users::Table, column)).eq(fold_case(&s2))
+                }
+                Some(column) => Expr::col((Users::Table, column)).eq(s2),
+                None => Expr::value(false),
+            },
+        ),
+        Substring(field, substrings) => (
+            RequiresGroup(false),
+            match get_user_column(&field) {
+                Some(column) => get_like_expr(&Users::Table, &column, &substrings, backend),
+                None => Expr::value(false),
             },
         ),
+        AttributeEquality(name, value) => (
+            RequiresGroup(false),
+            Expr::col((Users::Table, Users::UserId)).in_subquery(
+                Query::select()
+                    .column(UserAttributes::UserId)
+                    .from(UserAttributes::Table)
+                    .and_where(Expr::col(UserAttributes::AttributeName).eq(name))
+                    .and_where(Expr::col(UserAttributes::Value).eq(value))
+                    .to_owned(),
+            ),
+        ),
         MemberOf(group) => (
             RequiresGroup(true),
             Expr::col((Groups::Table, Groups::DisplayName)).eq(group),
@@ -72,50 +546,409 @@ fn get_filter_expr(filter: RequestFilter) -> (RequiresGroup, SimpleExpr) {
     }
 }
 
-#[async_trait]
-impl BackendHandler for SqlBackendHandler {
-    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
-        let query = {
-            let mut query_builder = Query::select()
-                .column((Users::Table, Users::UserId))
-                .column(Users::Email)
-                .column((Users::Table, Users::DisplayName))
-                .column(Users::FirstName)
-                .column(Users::LastName)
-                .column(Users::Avatar)
-                .column(Users::CreationDate)
-                .from(Users::Table)
-                .order_by((Users::Table, Users::UserId), Order::Asc)
-                .to_owned();
-            if let Some(filter) = filters {
-                if filter == RequestFilter::Not(Box::new(RequestFilter::And(Vec::new()))) {
-                    return Ok(Vec::new());
-                }
-                if filter != RequestFilter::And(Vec::new())
-                    && filter != RequestFilter::Or(Vec::new())
-                {
-                    let (RequiresGroup(requires_group), condition) = get_filter_expr(filter);
-                    query_builder.and_where(condition);
-                    if requires_group {
-                        query_builder
-                            .left_join(
-                                Memberships::Table,
-                                Expr::tbl(Users::Table, Users::UserId)
-                                    .equals(Memberships::Table, Memberships::UserId),
-                            )
-                            .left_join(
-                                Groups::Table,
-                                Expr::tbl(Memberships::Table, Memberships::GroupId)
-                                    .equals(Groups::Table, Groups::GroupId),
-                            );
-                    }
+/// The condition on the groups table for the filter, once resolved by
+/// [`SqlBackendHandler::resolve_group_filter`].
+fn get_group_filter_expr(filter: GroupRequestFilter, backend: DbBackend) -> SimpleExpr {
+    use GroupRequestFilter::*;
+    // An empty `And` matches all the groups, an empty `Or` none.
+    let get_repeated_filter = |fs: Vec<GroupRequestFilter>, is_and: bool| {
+        fs.into_iter()
+            .map(|f| get_group_filter_expr(f, backend))
+            .reduce(|a, b| if is_and { a.and(b) } else { a.or(b) })
+            .unwrap_or_else(|| Expr::value(is_and))
+    };
+    match filter {
+        And(fs) => get_repeated_filter(fs, true),
+        Or(fs) => get_repeated_filter(fs, false),
+        Not(f) => Expr::not(Expr::expr(get_group_filter_expr(*f, backend))),
+        DisplayName(name) => Expr::col((Groups::Table, Groups::DisplayName)).eq(name),
+        DisplayNameSubstring(substrings) => {
+            get_like_expr(&Groups::Table, &Groups::DisplayName, &substrings, backend)
+        }
+        GroupId(group_id) => Expr::col((Groups::Table, Groups::GroupId)).eq(group_id),
+        Member(user_id) => Expr::col((Groups::Table, Groups::GroupId)).in_subquery(
+            Query::select()
+                .column(Memberships::GroupId)
+                .from(Memberships::Table)
+                .and_where(Expr::col(Memberships::UserId).eq(fold_case(&user_id)))
+                .to_owned(),
+        ),
+        Mail(address) => Expr::col((Groups::Table, Groups::GroupId)).in_subquery(
+            Query::select()
+                .column(GroupMailAddresses::GroupId)
+                .from(GroupMailAddresses::Table)
+                .and_where(Expr::cust_with_values(
+                    &format!(
+                        "LOWER({}) = ?",
+                        backend
+                            .quote_column(&GroupMailAddresses::Table, &GroupMailAddresses::Address)
+                    ),
+                    vec![address.to_lowercase()],
+                ))
+                .to_owned(),
+        ),
+    }
+}
+
+/// Inserts the group and returns its ID.
+async fn insert_group<'e, E>(executor: E, backend: DbBackend, group_name: &str) -> Result<GroupId>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Any>,
+{
+    let (query, values) = Query::insert()
+        .into_table(Groups::Table)
+        .columns(vec![
+            Groups::DisplayName,
+            Groups::CreationDate,
+            Groups::Uuid,
+        ])
+        .values_panic(vec![
+            group_name.into(),
+            chrono::Utc::now().naive_utc().into(),
+            generate_uuid().into(),
+        ])
+        .build_db_query(backend);
+    // The ID comes with the result of the insertion itself, so it can't be the one of a group
+    // created concurrently.
+    let already_exists = || format!("group {} already exists", group_name);
+    match backend {
+        DbBackend::Sqlite | DbBackend::Mysql => {
+            let result = sqlx::query_with(&query, values)
+                .execute(executor)
+                .await
+                .map_err(map_conflict(already_exists))?;
+            Ok(GroupId(result.last_insert_id().unwrap_or_default() as i32))
+        }
+        // PostgreSQL doesn't report the inserted ID, it has to be returned by the query.
+        DbBackend::Postgres => {
+            let query = format!(r#"{} RETURNING "{}""#, query, Groups::GroupId.to_string());
+            let row = sqlx::query_with(&query, values)
+                .fetch_one(executor)
+                .await
+                .map_err(map_conflict(already_exists))?;
+            Ok(GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())))
+        }
+    }
+}
+
+/// Turns the violations of the unique constraints into a [`DomainError::Conflict`] with the
+/// message, and the other errors into [`DomainError::DatabaseError`].
+fn map_conflict(message: impl FnOnce() -> String) -> impl FnOnce(sqlx::Error) -> DomainError {
+    move |error| {
+        if is_unique_violation(&error) {
+            DomainError::Conflict(message())
+        } else {
+            error.into()
+        }
+    }
+}
+
+/// The users inserted by a single query by [`SqlBackendHandler::bulk_create_users`], to stay
+/// under the limit on the parameters of a query: 999 for the SQLite versions before 3.32.
+const USER_INSERT_CHUNK_SIZE: usize = 100;
+
+/// The attempts at inserting new users: their `uidNumber` is allocated in the transaction, but a
+/// concurrent one can take it before the insertion.
+const UID_NUMBER_ATTEMPTS: usize = 3;
+
+/// Runs the insertion of new users again when it conflicts, in case the conflict is on the
+/// `uidNumber` allocated to them. The conflicts on the user IDs or emails persist, and are
+/// returned by the last attempt.
+async fn retry_on_conflict<T, F, Fut>(operations: F) -> Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operations().await {
+            Err(DomainError::Conflict(_)) if attempt < UID_NUMBER_ATTEMPTS => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Reads the groups nested in other groups.
+async fn get_group_nesting<'e, E>(executor: E, backend: DbBackend) -> Result<GroupNesting>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Any>,
+{
+    let (query, values) = Query::select()
+        .column(GroupMemberships::MemberGroupId)
+        .column(GroupMemberships::ParentGroupId)
+        .from(GroupMemberships::Table)
+        .build_db_query(backend);
+    Ok(GroupNesting::new(
+        sqlx::query_with(&query, values)
+            .map(|row: DbRow| {
+                (
+                    row.get::<GroupId, _>(&*GroupMemberships::MemberGroupId.to_string()),
+                    row.get::<GroupId, _>(&*GroupMemberships::ParentGroupId.to_string()),
+                )
+            })
+            .fetch_all(executor)
+            .await?,
+    ))
+}
+
+async fn get_all_groups<'e, E>(executor: E, backend: DbBackend) -> Result<Vec<GroupIdAndName>>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Any>,
+{
+    let (query, values) = Query::select()
+        .column(Groups::GroupId)
+        .column(Groups::DisplayName)
+        .from(Groups::Table)
+        .build_db_query(backend);
+    Ok(sqlx::query_as_with::<_, GroupIdAndName, _>(&query, values)
+        .fetch_all(executor)
+        .await?)
+}
+
+/// Adds the groups that the groups are nested in, directly or not.
+fn add_parent_groups(
+    groups: &mut HashSet<GroupIdAndName>,
+    nesting: &GroupNesting,
+    all_groups: &[GroupIdAndName],
+) {
+    let parents = nesting.with_ancestors(groups.iter().map(|g| g.0));
+    groups.extend(
+        all_groups
+            .iter()
+            .filter(|g| parents.contains(&g.0))
+            .cloned(),
+    );
+}
+
+/// Replaces the `MemberOf` filters with the memberships of the group or of any group nested in
+/// it, since the SQL query only matches the direct memberships. The group names are matched
+/// ignoring the case.
+async fn resolve_nested_groups(
+    sql_pool: &Pool,
+    backend: DbBackend,
+    filters: Option<RequestFilter>,
+) -> Result<Option<RequestFilter>> {
+    fn has_member_of(filter: &RequestFilter) -> bool {
+        match filter {
+            RequestFilter::And(fs) | RequestFilter::Or(fs) => fs.iter().any(has_member_of),
+            RequestFilter::Not(f) => has_member_of(f),
+            RequestFilter::MemberOf(_) => true,
+            _ => false,
+        }
+    }
+    fn resolve(
+        filter: RequestFilter,
+        nesting: &GroupNesting,
+        group_ids: &HashMap<String, GroupId>,
+    ) -> RequestFilter {
+        use RequestFilter::*;
+        match filter {
+            And(fs) => And(fs
+                .into_iter()
+                .map(|f| resolve(f, nesting, group_ids))
+                .collect()),
+            Or(fs) => Or(fs
+                .into_iter()
+                .map(|f| resolve(f, nesting, group_ids))
+                .collect()),
+            Not(f) => Not(Box::new(resolve(*f, nesting, group_ids))),
+            MemberOf(name) => match group_ids.get(&fold_case(&name)) {
+                Some(group_id) => {
+                    let mut groups = nesting
+                        .with_descendants(*group_id)
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    groups.sort();
+                    Or(groups.into_iter().map(MemberOfId).collect())
                 }
+                None => MemberOf(name),
+            },
+            f => f,
+        }
+    }
+    let filter = match filters {
+        Some(filter) if has_member_of(&filter) => filter,
+        filters => return Ok(filters),
+    };
+    let nesting = get_group_nesting(sql_pool, backend).await?;
+    let group_ids = get_all_groups(sql_pool, backend)
+        .await?
+        .into_iter()
+        .map(|GroupIdAndName(id, name)| (fold_case(&name), id))
+        .collect();
+    Ok(Some(resolve(filter, &nesting, &group_ids)))
+}
+
+/// Excludes the memberships of the users in the recycle bin, which are kept for a restore.
+fn is_active_membership() -> SimpleExpr {
+    Expr::col((Memberships::Table, Memberships::UserId)).not_in_subquery(
+        Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::DeletedAt).is_not_null())
+            .to_owned(),
+    )
+}
+
+/// The queries deleting the user for good, with the rows referencing it: the tables of databases
+/// created by older versions could be missing the foreign keys that cascade the deletion.
+fn get_purge_user_queries(
+    user_id: &str,
+    backend: DbBackend,
+) -> Vec<(String, AnyArguments<'static>)> {
+    vec![
+        Query::delete()
+            .from_table(Memberships::Table)
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(UserHosts::Table)
+            .and_where(Expr::col(UserHosts::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(UserAttributes::Table)
+            .and_where(Expr::col(UserAttributes::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(LockedUsers::Table)
+            .and_where(Expr::col(LockedUsers::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(PasswordChanges::Table)
+            .and_where(Expr::col(PasswordChanges::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(PasswordHistory::Table)
+            .and_where(Expr::col(PasswordHistory::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(ApiTokens::Table)
+            .and_where(Expr::col(ApiTokens::UserId).eq(user_id))
+            .build_db_query(backend),
+        Query::delete()
+            .from_table(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .build_db_query(backend),
+    ]
+}
+
+/// Deletes for good the users moved to the recycle bin before the date, returning their IDs.
+pub async fn purge_deleted_users(
+    sql_pool: &Pool,
+    deleted_before: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<String>> {
+    let backend = DbBackend::of(sql_pool);
+    let (query, values) = Query::select()
+        .column(Users::UserId)
+        .from(Users::Table)
+        .and_where(Expr::col(Users::DeletedAt).lt(deleted_before.naive_utc()))
+        .build_db_query(backend);
+    let user_ids = sqlx::query_with(&query, values)
+        .map(|row: DbRow| row.get::<String, _>(&*Users::UserId.to_string()))
+        .fetch_all(sql_pool)
+        .await?;
+    for user_id in &user_ids {
+        let mut transaction = sql_pool.begin().await?;
+        for (query, values) in get_purge_user_queries(user_id, backend) {
+            sqlx::query_with(&query, values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+    }
+    Ok(user_ids)
+}
+
+/// Number of users read in advance by `list_users_stream`.
+const USER_STREAM_BUFFER: usize = 64;
+
+/// The query listing the users matching the filter in the given order, or `None` if the filter
+/// can't match anyone. With a page, only returns `limit` users after skipping `offset` ones.
+fn get_list_users_query(
+    filters: Option<RequestFilter>,
+    order: UserOrder,
+    direction: SortDirection,
+    page: Option<(usize, usize)>,
+    backend: DbBackend,
+) -> Option<(String, AnyArguments<'static>)> {
+    let mut query_builder = Query::select()
+        .column((Users::Table, Users::UserId))
+        .column(Users::Email)
+        .column((Users::Table, Users::DisplayName))
+        .column(Users::FirstName)
+        .column(Users::LastName)
+        .column(Users::Avatar)
+        .column(Users::CreationDate)
+        .column(Users::Uuid)
+        .column(Users::UidNumber)
+        .from(Users::Table)
+        .and_where(Expr::col((Users::Table, Users::DeletedAt)).is_null())
+        .to_owned();
+    // Descending is the exact reverse, so the ties are also in descending user ID order.
+    let sql_order = || match direction {
+        SortDirection::Ascending => Order::Asc,
+        SortDirection::Descending => Order::Desc,
+    };
+    match order {
+        UserOrder::UserId => (),
+        UserOrder::Email => {
+            query_builder.order_by((Users::Table, Users::Email), sql_order());
+        }
+        UserOrder::DisplayName => {
+            query_builder.order_by((Users::Table, Users::DisplayName), sql_order());
+        }
+        UserOrder::CreationDate => {
+            query_builder.order_by((Users::Table, Users::CreationDate), sql_order());
+        }
+    }
+    query_builder.order_by((Users::Table, Users::UserId), sql_order());
+    if let Some((offset, limit)) = page {
+        query_builder.limit(limit as u64).offset(offset as u64);
+    }
+    if let Some(filter) = filters {
+        if filter == RequestFilter::Not(Box::new(RequestFilter::And(Vec::new()))) {
+            return None;
+        }
+        if filter != RequestFilter::And(Vec::new()) && filter != RequestFilter::Or(Vec::new()) {
+            let (RequiresGroup(requires_group), condition) = get_filter_expr(filter, backend);
+            query_builder.and_where(condition);
+            if requires_group {
+                // A user can be a member of several of the matching groups.
+                query_builder
+                    .distinct()
+                    .left_join(
+                        Memberships::Table,
+                        Expr::tbl(Users::Table, Users::UserId)
+                            .equals(Memberships::Table, Memberships::UserId),
+                    )
+                    .left_join(
+                        Groups::Table,
+                        Expr::tbl(Memberships::Table, Memberships::GroupId)
+                            .equals(Groups::Table, Groups::GroupId),
+                    );
             }
+        }
+    }
+    Some(query_builder.build_db_query(backend))
+}
 
-            query_builder.to_string(DbQueryBuilder {})
+#[async_trait]
+impl BackendHandler for SqlBackendHandler {
+    #[instrument(level = "debug", skip(self, filters))]
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        let filters = resolve_nested_groups(&self.sql_pool, self.backend(), filters).await?;
+        let (query, values) = match get_list_users_query(
+            filters,
+            UserOrder::UserId,
+            SortDirection::Ascending,
+            None,
+            self.backend(),
+        ) {
+            Some(query) => query,
+            None => return Ok(Vec::new()),
         };
 
-        let results = sqlx::query_as::<_, User>(&query)
+        let results = sqlx::query_as_with::<_, User, _>(&query, values)
             .fetch(&self.sql_pool)
             .collect::<Vec<sqlx::Result<User>>>()
             .await;
@@ -123,8 +956,94 @@ impl BackendHandler for SqlBackendHandler {
         Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
     }
 
-    async fn list_groups(&self) -> Result<Vec<Group>> {
-        let query: String = Query::select()
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        // The row stream borrows the query, so it's read in a task that owns it. The channel is
+        // bounded to stop reading the rows when the client is slower than the database.
+        let (sender, receiver) = tokio::sync::mpsc::channel(USER_STREAM_BUFFER);
+        let sql_pool = self.sql_pool.clone();
+        let backend = self.backend();
+        tokio::spawn(async move {
+            let filters = match resolve_nested_groups(&sql_pool, backend, filters).await {
+                Ok(filters) => filters,
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            };
+            let (query, values) =
+                match get_list_users_query(filters, order, SortDirection::Ascending, None, backend)
+                {
+                    Some(query) => query,
+                    None => return,
+                };
+            let mut rows = sqlx::query_as_with::<_, User, _>(&query, values).fetch(&sql_pool);
+            while let Some(row) = rows.next().await {
+                if sender.send(row.map_err(DomainError::from)).await.is_err() {
+                    // The receiver is gone, e.g. the client disconnected.
+                    break;
+                }
+            }
+        });
+        tokio_stream::wrappers::ReceiverStream::new(receiver).boxed()
+    }
+
+    #[instrument(level = "debug", skip(self, filters, order))]
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        let filters = resolve_nested_groups(&self.sql_pool, self.backend(), filters).await?;
+        let (query, values) = match get_list_users_query(
+            filters,
+            order,
+            direction,
+            Some((offset, limit)),
+            self.backend(),
+        ) {
+            Some(query) => query,
+            None => return Ok(Vec::new()),
+        };
+        Ok(sqlx::query_as_with::<_, User, _>(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    /// Counts the rows of the `list_users` query, which already removes the duplicates of the
+    /// users matching through several groups.
+    #[instrument(level = "debug", skip(self, filters))]
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        let filters = resolve_nested_groups(&self.sql_pool, self.backend(), filters).await?;
+        let (query, values) = match get_list_users_query(
+            filters,
+            UserOrder::UserId,
+            SortDirection::Ascending,
+            None,
+            self.backend(),
+        ) {
+            Some(query) => query,
+            None => return Ok(0),
+        };
+        let query = format!(
+            "SELECT COUNT(*) AS count FROM ({}) AS matching_users",
+            query
+        );
+        let row = sqlx::query_with(&query, values)
+            .fetch_one(&self.sql_pool)
+            .await?;
+        Ok(row.get::<i64, _>("count") as usize)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        let mut query_builder = Query::select()
             .column((Groups::Table, Groups::GroupId))
             .column(Groups::DisplayName)
             .column(Memberships::UserId)
@@ -132,18 +1051,24 @@ impl BackendHandler for SqlBackendHandler {
             .left_join(
                 Memberships::Table,
                 Expr::tbl(Groups::Table, Groups::GroupId)
-                    .equals(Memberships::Table, Memberships::GroupId),
+                    .equals(Memberships::Table, Memberships::GroupId)
+                    .and(is_active_membership()),
             )
             .order_by(Groups::DisplayName, Order::Asc)
             .order_by(Memberships::UserId, Order::Asc)
-            .to_string(DbQueryBuilder {});
+            .to_owned();
+        if let Some(filter) = filters {
+            let filter = self.resolve_group_filter(filter).await?;
+            query_builder.and_where(get_group_filter_expr(filter, self.backend()));
+        }
+        let (query, values) = query_builder.build_db_query(self.backend());
 
         // For group_by.
         use itertools::Itertools;
         let mut groups = Vec::new();
         // The rows are returned sorted by display_name, equivalent to group_id. We group them by
         // this key which gives us one element (`rows`) per group.
-        for ((group_id, display_name), rows) in &sqlx::query(&query)
+        for ((group_id, display_name), rows) in &sqlx::query_with(&query, values)
             .fetch_all(&self.sql_pool)
             .await?
             .into_iter()
@@ -168,8 +1093,102 @@ impl BackendHandler for SqlBackendHandler {
         Ok(groups)
     }
 
+    #[instrument(level = "debug", skip(self))]
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        let mut groups_query_builder = Query::select()
+            .column(Groups::GroupId)
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .order_by(Groups::DisplayName, Order::Asc)
+            .limit(limit as u64)
+            .offset(offset as u64)
+            .to_owned();
+        if let Some(filter) = filters {
+            let filter = self.resolve_group_filter(filter).await?;
+            groups_query_builder.and_where(get_group_filter_expr(filter, self.backend()));
+        }
+        let (groups_query, groups_values) = groups_query_builder.build_db_query(self.backend());
+        let mut groups = sqlx::query_as_with::<_, GroupIdAndName, _>(&groups_query, groups_values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|GroupIdAndName(id, display_name)| Group {
+                id,
+                display_name,
+                users: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        if groups.is_empty() {
+            return Ok(groups);
+        }
+        let (members_query, members_values) = Query::select()
+            .column(Memberships::GroupId)
+            .column(Memberships::UserId)
+            .from(Memberships::Table)
+            .and_where(Expr::col(Memberships::GroupId).is_in(groups.iter().map(|g| g.id)))
+            .and_where(is_active_membership())
+            .order_by(Memberships::UserId, Order::Asc)
+            .build_db_query(self.backend());
+        let mut members = HashMap::<GroupId, Vec<String>>::new();
+        for row in sqlx::query_with(&members_query, members_values)
+            .fetch_all(&self.sql_pool)
+            .await?
+        {
+            members
+                .entry(GroupId(
+                    row.get::<i32, _>(&*Memberships::GroupId.to_string()),
+                ))
+                .or_default()
+                .push(row.get::<String, _>(&*Memberships::UserId.to_string()));
+        }
+        for group in &mut groups {
+            group.users = members.remove(&group.id).unwrap_or_default();
+        }
+        Ok(groups)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        let (query, values) = Query::select()
+            .column((Groups::Table, Groups::GroupId))
+            .column(Groups::DisplayName)
+            .expr_as(
+                Func::count(Expr::tbl(Memberships::Table, Memberships::UserId)),
+                Alias::new("member_count"),
+            )
+            .from(Groups::Table)
+            .left_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId)
+                    .and(is_active_membership()),
+            )
+            .group_by_columns(vec![
+                (Groups::Table, Groups::GroupId),
+                (Groups::Table, Groups::DisplayName),
+            ])
+            .order_by(Groups::DisplayName, Order::Asc)
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|row| GroupSummary {
+                id: GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())),
+                display_name: row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                member_count: row.get::<i64, _>("member_count") as usize,
+            })
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip(self))]
     async fn get_user_details(&self, user_id: &str) -> Result<User> {
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(Users::UserId)
             .column(Users::Email)
             .column(Users::DisplayName)
@@ -177,36 +1196,246 @@ impl BackendHandler for SqlBackendHandler {
             .column(Users::LastName)
             .column(Users::Avatar)
             .column(Users::CreationDate)
+            .column(Users::Uuid)
+            .column(Users::UidNumber)
             .from(Users::Table)
-            .and_where(Expr::col(Users::UserId).eq(user_id))
-            .to_string(DbQueryBuilder {});
+            .and_where(Expr::col(Users::UserId).eq(fold_case(user_id)))
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .build_db_query(self.backend());
 
-        Ok(sqlx::query_as::<_, User>(&query)
+        Ok(sqlx::query_as_with::<_, User, _>(&query, values)
             .fetch_one(&self.sql_pool)
             .await?)
     }
 
-    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
-        let query = Query::select()
-            .column(Groups::GroupId)
-            .column(Groups::DisplayName)
-            .from(Groups::Table)
-            .and_where(Expr::col(Groups::GroupId).eq(group_id))
-            .to_string(DbQueryBuilder {});
-
-        Ok(sqlx::query_as::<_, GroupIdAndName>(&query)
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        let (query, values) = Query::select()
+            .column(Users::Avatar)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(fold_case(user_id)))
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .map(|row: DbRow| row.get::<Option<Vec<u8>>, _>(&*Users::Avatar.to_string()))
             .fetch_one(&self.sql_pool)
             .await?)
     }
 
-    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
-        if user == self.config.ldap_user_dn {
-            let mut groups = HashSet::new();
-            groups.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
-            return Ok(groups);
-        }
-        let query: String = Query::select()
-            .column((Groups::Table, Groups::GroupId))
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        // The emails are stored folded, LOWER only lets the query use the unique index on it.
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::Email)
+            .column(Users::DisplayName)
+            .column(Users::FirstName)
+            .column(Users::LastName)
+            .column(Users::Avatar)
+            .column(Users::CreationDate)
+            .column(Users::Uuid)
+            .column(Users::UidNumber)
+            .from(Users::Table)
+            .and_where(
+                Expr::expr(Expr::cust(&format!("LOWER({})", Users::Email.to_string())))
+                    .eq(fold_case(email)),
+            )
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .order_by(Users::UserId, Order::Asc)
+            .limit(1)
+            .build_db_query(self.backend());
+
+        Ok(sqlx::query_as_with::<_, User, _>(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        let (query, values) = Query::select()
+            .column(Groups::GroupId)
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build_db_query(self.backend());
+
+        Ok(sqlx::query_as_with::<_, GroupIdAndName, _>(&query, values)
+            .fetch_one(&self.sql_pool)
+            .await?)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        let mut parents = get_group_nesting(&self.sql_pool, self.backend())
+            .await?
+            .with_ancestors(std::iter::once(group_id));
+        parents.remove(&group_id);
+        if parents.is_empty() {
+            return Ok(HashSet::new());
+        }
+        Ok(get_all_groups(&self.sql_pool, self.backend())
+            .await?
+            .into_iter()
+            .filter(|group| parents.contains(&group.0))
+            .collect())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        let (query, values) = Query::select()
+            .column(Groups::Description)
+            .column(Groups::CreationDate)
+            .column(Groups::Uuid)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .build_db_query(self.backend());
+        Ok(sqlx::query_as_with::<_, GroupMetadata, _>(&query, values)
+            .fetch_one(&self.sql_pool)
+            .await?)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        let (count_query, count_values) = Query::select()
+            .expr_as(
+                Func::count(Expr::col(Memberships::UserId)),
+                Alias::new("total"),
+            )
+            .from(Memberships::Table)
+            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+            .and_where(is_active_membership())
+            .build_db_query(self.backend());
+        let total = sqlx::query_with(&count_query, count_values)
+            .fetch_one(&self.sql_pool)
+            .await?
+            .get::<i64, _>("total") as usize;
+        let (query, values) = Query::select()
+            .column((Users::Table, Users::UserId))
+            .column(Users::Email)
+            .column(Users::DisplayName)
+            .column(Users::FirstName)
+            .column(Users::LastName)
+            .column(Users::Avatar)
+            .column(Users::CreationDate)
+            .column(Users::Uuid)
+            .column(Users::UidNumber)
+            .from(Users::Table)
+            .inner_join(
+                Memberships::Table,
+                Expr::tbl(Users::Table, Users::UserId)
+                    .equals(Memberships::Table, Memberships::UserId),
+            )
+            .and_where(Expr::col((Memberships::Table, Memberships::GroupId)).eq(group_id))
+            .and_where(Expr::col((Users::Table, Users::DeletedAt)).is_null())
+            .order_by((Users::Table, Users::UserId), Order::Asc)
+            .limit(limit as u64)
+            .offset(offset as u64)
+            .build_db_query(self.backend());
+        let users = sqlx::query_as_with::<_, User, _>(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?;
+        Ok(GroupMembersPage { users, total })
+    }
+
+    #[instrument(level = "debug", skip(self, user_ids))]
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let mut query = Query::insert();
+        query
+            .into_table(Memberships::Table)
+            .columns(vec![Memberships::UserId, Memberships::GroupId]);
+        for user_id in user_ids {
+            query.values_panic(vec![fold_case(user_id).into(), group_id.into()]);
+        }
+        // A single statement: either all the rows are inserted, or none.
+        let (query, values) = query.build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, user_ids))]
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let (query, values) = Query::delete()
+            .from_table(Memberships::Table)
+            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+            .and_where(Expr::col(Memberships::UserId).is_in(user_ids.iter().map(|u| fold_case(u))))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, user_ids))]
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let (query, values) = Query::select()
+            .column(Memberships::UserId)
+            .from(Memberships::Table)
+            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+            .build_db_query(self.backend());
+        let current = sqlx::query_with(&query, values)
+            .map(|row: DbRow| row.get::<String, _>(&*Memberships::UserId.to_string()))
+            .fetch_all(&mut transaction)
+            .await?;
+        let user_ids = user_ids.iter().map(|u| fold_case(u)).collect::<Vec<_>>();
+        let changes = MembershipChanges::new(&current, &user_ids);
+        if !changes.added.is_empty() {
+            let mut query = Query::insert();
+            query
+                .into_table(Memberships::Table)
+                .columns(vec![Memberships::UserId, Memberships::GroupId]);
+            for user_id in &changes.added {
+                query.values_panic(vec![user_id.into(), group_id.into()]);
+            }
+            let (query, values) = query.build_db_query(self.backend());
+            sqlx::query_with(&query, values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        if !changes.removed.is_empty() {
+            let (query, values) = Query::delete()
+                .from_table(Memberships::Table)
+                .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+                .and_where(
+                    Expr::col(Memberships::UserId)
+                        .is_in(changes.removed.iter().map(String::as_str)),
+                )
+                .build_db_query(self.backend());
+            sqlx::query_with(&query, values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(changes)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        let user = fold_case(user);
+        if user == self.config.ldap_user_dn {
+            let mut groups = HashSet::new();
+            groups.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
+            return Ok(groups);
+        }
+        let (query, values) = Query::select()
+            .column((Groups::Table, Groups::GroupId))
             .column(Groups::DisplayName)
             .from(Groups::Table)
             .inner_join(
@@ -215,9 +1444,9 @@ impl BackendHandler for SqlBackendHandler {
                     .equals(Memberships::Table, Memberships::GroupId),
             )
             .and_where(Expr::col(Memberships::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
+            .build_db_query(self.backend());
 
-        sqlx::query(&query)
+        let mut groups = sqlx::query_with(&query, values)
             // Extract the group id from the row.
             .map(|row: DbRow| {
                 GroupIdAndName(
@@ -234,207 +1463,1022 @@ impl BackendHandler for SqlBackendHandler {
             // into a HashSet.
             .collect::<sqlx::Result<HashSet<_>>>()
             // Map the sqlx::Error into a DomainError.
-            .map_err(DomainError::DatabaseError)
+            .map_err(DomainError::DatabaseError)?;
+        let nesting = get_group_nesting(&self.sql_pool, self.backend()).await?;
+        if !nesting.is_empty() {
+            let all_groups = get_all_groups(&self.sql_pool, self.backend()).await?;
+            add_parent_groups(&mut groups, &nesting, &all_groups);
+        }
+        Ok(groups)
     }
 
+    #[instrument(level = "debug", skip(self, request), fields(user_id = %request.user_id))]
     async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
-        let columns = vec![
-            Users::UserId,
-            Users::Email,
-            Users::DisplayName,
-            Users::FirstName,
-            Users::LastName,
-            Users::CreationDate,
-        ];
-        let values = vec![
-            request.user_id.clone().into(),
-            request.email.into(),
-            request.display_name.unwrap_or_default().into(),
-            request.first_name.unwrap_or_default().into(),
-            request.last_name.unwrap_or_default().into(),
-            chrono::Utc::now().naive_utc().into(),
-        ];
-        let query = Query::insert()
-            .into_table(Users::Table)
-            .columns(columns)
-            .values_panic(values)
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        Ok(())
+        // Checks the request before allocating the uidNumber.
+        let (user_id, _) = self.get_new_user_values(&request, 0)?;
+        self.check_email_is_available(&request.email, &user_id)
+            .await?;
+        let (request, user_id) = (&request, &user_id);
+        retry_on_conflict(|| {
+            self.with_transaction(|mut transaction| async move {
+                let uid_number = self.get_next_uid_number(&mut transaction).await?;
+                let (_, values) = self.get_new_user_values(request, uid_number)?;
+                let (query, values) = Query::insert()
+                    .into_table(Users::Table)
+                    .columns(get_new_user_columns())
+                    .values_panic(values)
+                    .build_db_query(self.backend());
+                sqlx::query_with(&query, values)
+                    .execute(&mut transaction)
+                    .await
+                    .map_err(map_conflict(|| {
+                        format!(
+                            "user ID {} or email {} is already used",
+                            user_id, request.email
+                        )
+                    }))?;
+                Ok((transaction, ()))
+            })
+        })
+        .await
+    }
+
+    /// The requests are checked against the existing users and each other before inserting the
+    /// valid ones, by chunks of [`USER_INSERT_CHUNK_SIZE`]. A chunk conflicting with a concurrent
+    /// insertion is inserted again one user at a time, to report the conflicts for each user.
+    #[instrument(level = "debug", skip(self, requests))]
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        let requests = &requests;
+        self.with_transaction(|mut transaction| async move {
+            let (query, values) = Query::select()
+                .column(Users::UserId)
+                .column(Users::Email)
+                .from(Users::Table)
+                .build_db_query(self.backend());
+            let (mut user_ids, mut emails): (HashSet<String>, HashSet<String>) =
+                sqlx::query_with(&query, values)
+                    .map(|row: DbRow| {
+                        (
+                            row.get::<String, _>(&*Users::UserId.to_string()),
+                            fold_case(&row.get::<String, _>(&*Users::Email.to_string())),
+                        )
+                    })
+                    .fetch_all(&mut transaction)
+                    .await?
+                    .into_iter()
+                    .unzip();
+            let mut uid_number = self.get_next_uid_number(&mut transaction).await?;
+            let mut results = Vec::with_capacity(requests.len());
+            let mut new_users = Vec::new();
+            for request in requests {
+                let user_id = match self.get_new_user_values(request, uid_number) {
+                    Ok((user_id, _)) => user_id,
+                    Err(e) => {
+                        results.push(Err(e));
+                        continue;
+                    }
+                };
+                let email = fold_case(&request.email);
+                if user_ids.contains(&user_id) {
+                    results.push(Err(DomainError::Conflict(format!(
+                        "user ID {} is already used",
+                        user_id
+                    ))));
+                } else if self.config.unique_emails && emails.contains(&email) {
+                    results.push(Err(DomainError::Conflict(format!(
+                        "email {} is already used by another user",
+                        request.email
+                    ))));
+                } else {
+                    user_ids.insert(user_id);
+                    emails.insert(email);
+                    new_users.push((results.len(), request, uid_number));
+                    uid_number += 1;
+                    results.push(Ok(()));
+                }
+            }
+            for chunk in new_users.chunks(USER_INSERT_CHUNK_SIZE) {
+                match self.insert_new_users(&mut transaction, chunk).await {
+                    Err(DomainError::Conflict(_)) => {
+                        // The new uidNumbers are allocated again, in case the conflict is on them.
+                        for &(index, request, _) in chunk {
+                            let uid_number = self.get_next_uid_number(&mut transaction).await?;
+                            match self
+                                .insert_new_users(&mut transaction, &[(index, request, uid_number)])
+                                .await
+                            {
+                                Err(DomainError::Conflict(_)) => {
+                                    results[index] = Err(DomainError::Conflict(format!(
+                                        "user ID {} or email {} is already used",
+                                        request.user_id, request.email
+                                    )))
+                                }
+                                result => result?,
+                            }
+                        }
+                    }
+                    result => result?,
+                }
+            }
+            Ok((transaction, results))
+        })
+        .await
     }
 
+    #[instrument(level = "debug", skip(self, request), fields(user_id = %request.user_id))]
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
-        let mut values = Vec::new();
-        if let Some(email) = request.email {
-            values.push((Users::Email, email.into()));
+        validate_update_user(&request)?;
+        if let Some(email) = &request.email {
+            self.check_email_is_available(email, &request.user_id)
+                .await?;
         }
-        if let Some(display_name) = request.display_name {
-            values.push((Users::DisplayName, display_name.into()));
-        }
-        if let Some(first_name) = request.first_name {
-            values.push((Users::FirstName, first_name.into()));
-        }
-        if let Some(last_name) = request.last_name {
-            values.push((Users::LastName, last_name.into()));
-        }
-        if values.is_empty() {
-            return Ok(());
+        let email = request.email.clone().unwrap_or_default();
+        if let Some((query, values)) = self.get_user_update_query(request)? {
+            sqlx::query_with(&query, values)
+                .execute(&self.sql_pool)
+                .await
+                .map_err(map_conflict(|| {
+                    format!("email {} is already used by another user", email)
+                }))?;
         }
-        let query = Query::update()
-            .table(Users::Table)
-            .values(values)
-            .and_where(Expr::col(Users::UserId).eq(request.user_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
         Ok(())
     }
 
+    #[instrument(level = "debug", skip(self, request), fields(group_id = ?request.group_id))]
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
         let mut values = Vec::new();
-        if let Some(display_name) = request.display_name {
-            values.push((Groups::DisplayName, display_name.into()));
+        if let Some(display_name) = &request.display_name {
+            self.check_group_name_is_available(display_name, Some(request.group_id))
+                .await?;
+            values.push((Groups::DisplayName, display_name.as_str().into()));
+        }
+        if let Some(description) = &request.description {
+            let description = description.trim();
+            values.push((
+                Groups::Description,
+                if description.is_empty() {
+                    Value::Null
+                } else {
+                    description.into()
+                },
+            ));
         }
         if values.is_empty() {
             return Ok(());
         }
-        let query = Query::update()
+        let (query, values) = Query::update()
             .table(Groups::Table)
             .values(values)
             .and_where(Expr::col(Groups::GroupId).eq(request.group_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await
+            .map_err(map_conflict(|| {
+                format!(
+                    "group name {} is already used",
+                    request.display_name.unwrap_or_default()
+                )
+            }))?;
         Ok(())
     }
 
+    /// Only sets `deleted_at`, keeping the rows referencing the user for a restore, unless the
+    /// retention is 0: see [`purge_deleted_users`].
+    #[instrument(level = "debug", skip(self))]
     async fn delete_user(&self, user_id: &str) -> Result<()> {
-        let delete_query = Query::delete()
-            .from_table(Users::Table)
+        let user_id = fold_case(user_id);
+        let backend = self.backend();
+        if self.config.deleted_user_retention_days == 0 {
+            let delete_queries = get_purge_user_queries(&user_id, backend);
+            return self
+                .with_transaction(|mut transaction| async move {
+                    for (query, values) in delete_queries {
+                        sqlx::query_with(&query, values)
+                            .execute(&mut transaction)
+                            .await?;
+                    }
+                    Ok((transaction, ()))
+                })
+                .await;
+        }
+        let (query, values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(
+                Users::DeletedAt,
+                chrono::Utc::now().naive_utc().into(),
+            )])
             .and_where(Expr::col(Users::UserId).eq(user_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&delete_query).execute(&self.sql_pool).await?;
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .build_db_query(backend);
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        let user_id = fold_case(user_id);
+        let (query, _) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::DeletedAt, Value::Null)])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Users::DeletedAt).is_not_null())
+            .build_db_query(self.backend());
+        // Bound by hand, in the order of the statement: the untyped NULL is bound as a text,
+        // which Postgres refuses in a timestamp column.
+        let mut values = AnyArguments::default();
+        values.add(None::<chrono::NaiveDateTime>);
+        values.add(user_id);
+        let result = sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(DomainError::DatabaseError(sqlx::Error::RowNotFound));
+        }
         Ok(())
     }
 
+    #[instrument(level = "debug", skip(self))]
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .column(Users::Email)
+            .column(Users::DisplayName)
+            .column(Users::DeletedAt)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::DeletedAt).is_not_null())
+            .order_by(Users::DeletedAt, Order::Desc)
+            .order_by(Users::UserId, Order::Asc)
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|row| DeletedUser {
+                user_id: row.get(&*Users::UserId.to_string()),
+                email: row.get(&*Users::Email.to_string()),
+                display_name: row.get(&*Users::DisplayName.to_string()),
+                deleted_at: row.get(&*Users::DeletedAt.to_string()),
+            })
+            .collect())
+    }
+
+    /// The foreign keys cascade the new ID to the sessions and the password resets. The domain
+    /// tables are also updated explicitly, like for the deletion.
+    #[instrument(level = "debug", skip(self))]
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        let user_id = fold_case(user_id);
+        validate_user_id(new_user_id, self.config.user_id_policy)?;
+        let new_user_id = normalize_user_id(new_user_id, self.config.user_id_policy)?;
+        if user_id == self.config.ldap_user_dn || new_user_id == self.config.ldap_user_dn {
+            return Err(DomainError::InvalidInput(
+                "the admin user can't be renamed".to_string(),
+            ));
+        }
+        let backend = self.backend();
+        let (existing_query, existing_values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(new_user_id.as_str()))
+            .build_db_query(backend);
+        // The password file stays bound to the ID it was registered with.
+        let (keep_password_identifier_query, keep_password_identifier_values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::PasswordIdentifier, user_id.as_str().into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(Users::PasswordIdentifier).is_null())
+            .build_db_query(backend);
+        let (rename_user_query, rename_user_values) = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::UserId, new_user_id.as_str().into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id.as_str()))
+            .build_db_query(backend);
+        let update_queries = vec![
+            Query::update()
+                .table(Memberships::Table)
+                .values(vec![(Memberships::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(Memberships::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(UserHosts::Table)
+                .values(vec![(UserHosts::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(UserHosts::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(UserAttributes::Table)
+                .values(vec![(UserAttributes::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(UserAttributes::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(LockedUsers::Table)
+                .values(vec![(LockedUsers::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(LockedUsers::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(PasswordChanges::Table)
+                .values(vec![(PasswordChanges::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(PasswordChanges::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(PasswordHistory::Table)
+                .values(vec![(PasswordHistory::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(PasswordHistory::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+            Query::update()
+                .table(ApiTokens::Table)
+                .values(vec![(ApiTokens::UserId, new_user_id.as_str().into())])
+                .and_where(Expr::col(ApiTokens::UserId).eq(user_id.as_str()))
+                .build_db_query(backend),
+        ];
+        self.with_transaction(|mut transaction| async move {
+            if sqlx::query_with(&existing_query, existing_values)
+                .fetch_optional(&mut transaction)
+                .await?
+                .is_some()
+            {
+                return Err(DomainError::Conflict(format!(
+                    "user ID {} is already used",
+                    new_user_id
+                )));
+            }
+            sqlx::query_with(
+                &keep_password_identifier_query,
+                keep_password_identifier_values,
+            )
+            .execute(&mut transaction)
+            .await?;
+            if sqlx::query_with(&rename_user_query, rename_user_values)
+                .execute(&mut transaction)
+                .await
+                .map_err(map_conflict(|| {
+                    format!("user ID {} is already used", new_user_id)
+                }))?
+                .rows_affected()
+                == 0
+            {
+                return Err(DomainError::DatabaseError(sqlx::Error::RowNotFound));
+            }
+            for (query, values) in update_queries {
+                sqlx::query_with(&query, values)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+            Ok((transaction, ()))
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
     async fn create_group(&self, group_name: &str) -> Result<GroupId> {
-        let query = Query::insert()
-            .into_table(Groups::Table)
-            .columns(vec![Groups::DisplayName])
-            .values_panic(vec![group_name.into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        let query = Query::select()
-            .column(Groups::GroupId)
-            .from(Groups::Table)
-            .and_where(Expr::col(Groups::DisplayName).eq(group_name))
-            .to_string(DbQueryBuilder {});
-        let row = sqlx::query(&query).fetch_one(&self.sql_pool).await?;
-        Ok(GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())))
+        // The check only gives a clearer error: the unique key on the names rejects a concurrent
+        // creation with the same name, but not with another case.
+        self.check_group_name_is_available(group_name, None).await?;
+        insert_group(&self.sql_pool, self.backend(), group_name).await
     }
 
+    /// Like for the users, the rows referencing the group are deleted explicitly.
+    #[instrument(level = "debug", skip(self))]
     async fn delete_group(&self, group_id: GroupId) -> Result<()> {
-        let delete_query = Query::delete()
-            .from_table(Groups::Table)
-            .and_where(Expr::col(Groups::GroupId).eq(group_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&delete_query).execute(&self.sql_pool).await?;
-        Ok(())
+        let backend = self.backend();
+        let delete_queries = vec![
+            Query::delete()
+                .from_table(Memberships::Table)
+                .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+                .build_db_query(backend),
+            Query::delete()
+                .from_table(GroupMemberships::Table)
+                .and_where(
+                    Expr::col(GroupMemberships::MemberGroupId)
+                        .eq(group_id)
+                        .or(Expr::col(GroupMemberships::ParentGroupId).eq(group_id)),
+                )
+                .build_db_query(backend),
+            Query::delete()
+                .from_table(GroupMailAddresses::Table)
+                .and_where(Expr::col(GroupMailAddresses::GroupId).eq(group_id))
+                .build_db_query(backend),
+            Query::delete()
+                .from_table(Groups::Table)
+                .and_where(Expr::col(Groups::GroupId).eq(group_id))
+                .build_db_query(backend),
+        ];
+        self.with_transaction(|mut transaction| async move {
+            for (query, values) in delete_queries {
+                sqlx::query_with(&query, values)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+            Ok((transaction, ()))
+        })
+        .await
     }
 
+    #[instrument(level = "debug", skip(self))]
     async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
-        let query = Query::insert()
+        let (query, values) = Query::insert()
             .into_table(Memberships::Table)
             .columns(vec![Memberships::UserId, Memberships::GroupId])
-            .values_panic(vec![user_id.into(), group_id.into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .values_panic(vec![fold_case(user_id).into(), group_id.into()])
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(())
     }
 
+    #[instrument(level = "debug", skip(self))]
     async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
-        let query = Query::delete()
+        let (query, values) = Query::delete()
             .from_table(Memberships::Table)
             .and_where(Expr::col(Memberships::GroupId).eq(group_id))
-            .and_where(Expr::col(Memberships::UserId).eq(user_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .and_where(Expr::col(Memberships::UserId).eq(fold_case(user_id)))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::sql_tables::init_table;
-    use crate::infra::configuration::ConfigurationBuilder;
-    use lldap_auth::{opaque, registration};
 
-    fn get_default_config() -> Configuration {
-        ConfigurationBuilder::default()
-            .verbose(true)
-            .build()
-            .unwrap()
+    #[instrument(level = "debug", skip(self))]
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let mut transaction = self.sql_pool.begin().await?;
+        let nesting = get_group_nesting(&mut transaction, self.backend()).await?;
+        if nesting.would_create_cycle(member_group_id, parent_group_id) {
+            return Err(DomainError::InvalidInput(format!(
+                "group {} is already a parent of group {}",
+                member_group_id.0, parent_group_id.0
+            )));
+        }
+        if nesting.contains(member_group_id, parent_group_id) {
+            return Ok(());
+        }
+        let (query, values) = Query::insert()
+            .into_table(GroupMemberships::Table)
+            .columns(vec![
+                GroupMemberships::MemberGroupId,
+                GroupMemberships::ParentGroupId,
+            ])
+            .values_panic(vec![member_group_id.into(), parent_group_id.into()])
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
     }
 
-    async fn get_in_memory_db() -> Pool {
-        PoolOptions::new().connect("sqlite::memory:").await.unwrap()
+    #[instrument(level = "debug", skip(self))]
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let (query, values) = Query::delete()
+            .from_table(GroupMemberships::Table)
+            .and_where(Expr::col(GroupMemberships::MemberGroupId).eq(member_group_id))
+            .and_where(Expr::col(GroupMemberships::ParentGroupId).eq(parent_group_id))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
     }
 
-    async fn get_initialized_db() -> Pool {
-        let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
-        sql_pool
+    #[instrument(level = "debug", skip(self, user_ids))]
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        let user_ids = user_ids.iter().map(|u| fold_case(u)).collect::<Vec<_>>();
+        let mut groups = user_ids
+            .iter()
+            .map(|u| (u.clone(), HashSet::new()))
+            .collect::<HashMap<_, _>>();
+        if user_ids.is_empty() {
+            return Ok(groups);
+        }
+        let (query, values) = Query::select()
+            .column(Memberships::UserId)
+            .column((Groups::Table, Groups::GroupId))
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .inner_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId),
+            )
+            .and_where(Expr::col(Memberships::UserId).is_in(user_ids.iter().map(String::as_str)))
+            .build_db_query(self.backend());
+        for row in sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+        {
+            let user_id = row.get::<String, _>(&*Memberships::UserId.to_string());
+            groups.entry(user_id).or_default().insert(GroupIdAndName(
+                row.get::<GroupId, _>(&*Groups::GroupId.to_string()),
+                row.get::<String, _>(&*Groups::DisplayName.to_string()),
+            ));
+        }
+        let nesting = get_group_nesting(&self.sql_pool, self.backend()).await?;
+        if !nesting.is_empty() {
+            let all_groups = get_all_groups(&self.sql_pool, self.backend()).await?;
+            for user_groups in groups.values_mut() {
+                add_parent_groups(user_groups, &nesting, &all_groups);
+            }
+        }
+        // Same as in `get_user_groups`.
+        if let Some(admin_groups) = groups.get_mut(&self.config.ldap_user_dn) {
+            *admin_groups =
+                std::iter::once(GroupIdAndName(GroupId(1), "lldap_admin".to_string())).collect();
+        }
+        Ok(groups)
     }
 
-    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
-        use crate::domain::opaque_handler::OpaqueHandler;
-        insert_user_no_password(handler, name).await;
-        let mut rng = rand::rngs::OsRng;
-        let client_registration_start =
-            opaque::client::registration::start_registration(pass, &mut rng).unwrap();
-        let response = handler
-            .registration_start(registration::ClientRegistrationStartRequest {
-                username: name.to_string(),
-                registration_start_request: client_registration_start.message,
-            })
-            .await
-            .unwrap();
-        let registration_upload = opaque::client::registration::finish_registration(
-            client_registration_start.state,
-            response.registration_response,
-            &mut rng,
-        )
-        .unwrap();
-        handler
-            .registration_finish(registration::ClientRegistrationFinishRequest {
-                server_data: response.server_data,
-                registration_upload: registration_upload.message,
-            })
-            .await
-            .unwrap();
-    }
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        let (query, values) = Query::select()
+            .column(UserHosts::Host)
+            .from(UserHosts::Table)
+            .and_where(Expr::col(UserHosts::UserId).eq(fold_case(user_id)))
+            .order_by(UserHosts::Host, Order::Asc)
+            .build_db_query(self.backend());
 
-    async fn insert_user_no_password(handler: &SqlBackendHandler, name: &str) {
-        handler
-            .create_user(CreateUserRequest {
-                user_id: name.to_string(),
-                email: "bob@bob.bob".to_string(),
-                ..Default::default()
-            })
-            .await
-            .unwrap();
+        Ok(sqlx::query_with(&query, values)
+            .map(|row: DbRow| row.get::<String, _>(&*UserHosts::Host.to_string()))
+            .fetch_all(&self.sql_pool)
+            .await?)
     }
 
-    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> GroupId {
-        handler.create_group(name).await.unwrap()
+    #[instrument(level = "debug", skip(self, hosts))]
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        let user_id = fold_case(user_id);
+        let mut transaction = self.sql_pool.begin().await?;
+        let (delete_query, delete_values) = Query::delete()
+            .from_table(UserHosts::Table)
+            .and_where(Expr::col(UserHosts::UserId).eq(user_id.as_str()))
+            .build_db_query(self.backend());
+        sqlx::query_with(&delete_query, delete_values)
+            .execute(&mut transaction)
+            .await?;
+        let hosts = hosts.into_iter().collect::<std::collections::BTreeSet<_>>();
+        if !hosts.is_empty() {
+            let mut insert_query = Query::insert();
+            insert_query
+                .into_table(UserHosts::Table)
+                .columns(vec![UserHosts::UserId, UserHosts::Host]);
+            for host in hosts {
+                insert_query.values_panic(vec![user_id.as_str().into(), host.into()]);
+            }
+            let (insert_query, insert_values) = insert_query.build_db_query(self.backend());
+            sqlx::query_with(&insert_query, insert_values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
     }
 
-    async fn insert_membership(handler: &SqlBackendHandler, group_id: GroupId, user_id: &str) {
-        handler.add_user_to_group(user_id, group_id).await.unwrap();
+    #[instrument(level = "debug", skip(self))]
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        let (query, values) = Query::select()
+            .column(GroupMailAddresses::Address)
+            .column(GroupMailAddresses::IsAlias)
+            .from(GroupMailAddresses::Table)
+            .and_where(Expr::col(GroupMailAddresses::GroupId).eq(group_id))
+            .order_by(GroupMailAddresses::Address, Order::Asc)
+            .build_db_query(self.backend());
+        let mut mail = GroupMail::default();
+        for row in sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+        {
+            let address = row.get::<String, _>(&*GroupMailAddresses::Address.to_string());
+            if row.get::<bool, _>(&*GroupMailAddresses::IsAlias.to_string()) {
+                mail.aliases.push(address);
+            } else {
+                mail.email = Some(address);
+            }
+        }
+        Ok(mail)
     }
 
-    #[tokio::test]
-    async fn test_bind_admin() {
-        let sql_pool = get_in_memory_db().await;
+    #[instrument(level = "debug", skip(self, mail))]
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        let mail = mail.normalize();
+        let mut transaction = self.sql_pool.begin().await?;
+        let (delete_query, delete_values) = Query::delete()
+            .from_table(GroupMailAddresses::Table)
+            .and_where(Expr::col(GroupMailAddresses::GroupId).eq(group_id))
+            .build_db_query(self.backend());
+        sqlx::query_with(&delete_query, delete_values)
+            .execute(&mut transaction)
+            .await?;
+        if mail.addresses().next().is_some() {
+            let mut insert_query = Query::insert();
+            insert_query
+                .into_table(GroupMailAddresses::Table)
+                .columns(vec![
+                    GroupMailAddresses::GroupId,
+                    GroupMailAddresses::Address,
+                    GroupMailAddresses::IsAlias,
+                ]);
+            for address in &mail.email {
+                insert_query.values_panic(vec![
+                    group_id.into(),
+                    address.clone().into(),
+                    false.into(),
+                ]);
+            }
+            for alias in &mail.aliases {
+                insert_query.values_panic(vec![group_id.into(), alias.clone().into(), true.into()]);
+            }
+            let (insert_query, insert_values) = insert_query.build_db_query(self.backend());
+            sqlx::query_with(&insert_query, insert_values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        let (query, values) = Query::select()
+            .column(UserAttributes::AttributeName)
+            .column(UserAttributes::Value)
+            .from(UserAttributes::Table)
+            .and_where(Expr::col(UserAttributes::UserId).eq(fold_case(user_id)))
+            .order_by(UserAttributes::AttributeName, Order::Asc)
+            .order_by(UserAttributes::Value, Order::Asc)
+            .build_db_query(self.backend());
+        let mut attributes = HashMap::<_, Vec<_>>::new();
+        for row in sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+        {
+            attributes
+                .entry(row.get::<String, _>(&*UserAttributes::AttributeName.to_string()))
+                .or_default()
+                .push(row.get::<String, _>(&*UserAttributes::Value.to_string()));
+        }
+        Ok(attributes)
+    }
+
+    #[instrument(level = "debug", skip(self, values))]
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        let attribute = self.config.ldap_schema.get_attribute(name).ok_or_else(|| {
+            DomainError::InvalidInput(format!("{} is not a custom attribute", name))
+        })?;
+        let values = values
+            .into_iter()
+            .collect::<std::collections::BTreeSet<_>>();
+        if attribute.single_value && values.len() > 1 {
+            return Err(DomainError::InvalidInput(format!(
+                "{} can only have one value",
+                attribute.name
+            )));
+        }
+        let user_id = fold_case(user_id);
+        let mut transaction = self.sql_pool.begin().await?;
+        let (delete_query, delete_values) = Query::delete()
+            .from_table(UserAttributes::Table)
+            .and_where(Expr::col(UserAttributes::UserId).eq(user_id.as_str()))
+            .and_where(Expr::col(UserAttributes::AttributeName).eq(attribute.name.as_str()))
+            .build_db_query(self.backend());
+        sqlx::query_with(&delete_query, delete_values)
+            .execute(&mut transaction)
+            .await?;
+        if !values.is_empty() {
+            let mut insert_query = Query::insert();
+            insert_query.into_table(UserAttributes::Table).columns(vec![
+                UserAttributes::UserId,
+                UserAttributes::AttributeName,
+                UserAttributes::Value,
+            ]);
+            for value in values {
+                insert_query.values_panic(vec![
+                    user_id.as_str().into(),
+                    attribute.name.as_str().into(),
+                    value.into(),
+                ]);
+            }
+            let (insert_query, insert_values) = insert_query.build_db_query(self.backend());
+            sqlx::query_with(&insert_query, insert_values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        let user_id = fold_case(user_id);
+        let mut transaction = self.sql_pool.begin().await?;
+        let (delete_query, delete_values) = Query::delete()
+            .from_table(LockedUsers::Table)
+            .and_where(Expr::col(LockedUsers::UserId).eq(user_id.as_str()))
+            .build_db_query(self.backend());
+        sqlx::query_with(&delete_query, delete_values)
+            .execute(&mut transaction)
+            .await?;
+        let (insert_query, insert_values) = Query::insert()
+            .into_table(LockedUsers::Table)
+            .columns(vec![
+                LockedUsers::UserId,
+                LockedUsers::LockedAt,
+                LockedUsers::Reason,
+            ])
+            .values_panic(vec![
+                user_id.into(),
+                chrono::Utc::now().naive_utc().into(),
+                reason.into(),
+            ])
+            .build_db_query(self.backend());
+        sqlx::query_with(&insert_query, insert_values)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        let (query, values) = Query::delete()
+            .from_table(LockedUsers::Table)
+            .and_where(Expr::col(LockedUsers::UserId).eq(fold_case(user_id)))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        let (query, values) = Query::select()
+            .column(LockedUsers::LockedAt)
+            .column(LockedUsers::Reason)
+            .from(LockedUsers::Table)
+            .and_where(Expr::col(LockedUsers::UserId).eq(fold_case(user_id)))
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row| UserLock {
+                locked_at: row.get(&*LockedUsers::LockedAt.to_string()),
+                reason: row.get(&*LockedUsers::Reason.to_string()),
+            }))
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.get_password_changes(None).await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        Ok(self
+            .get_password_changes(Some(user_id))
+            .await?
+            .remove(user_id))
+    }
+
+    #[instrument(level = "debug", skip(self, failure))]
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        let mut columns = vec![
+            AuthFailures::Time,
+            AuthFailures::Protocol,
+            AuthFailures::Reason,
+        ];
+        let mut values = vec![
+            failure.time.naive_utc().into(),
+            failure.protocol.as_str().into(),
+            failure.reason.as_str().into(),
+        ];
+        if let Some(user_id) = failure
+            .user_id
+            .and_then(|u| normalize_user_id(&u, self.config.user_id_policy).ok())
+        {
+            columns.push(AuthFailures::UserId);
+            values.push(user_id.into());
+        }
+        if let Some(source_ip) = failure.source_ip {
+            columns.push(AuthFailures::SourceIp);
+            values.push(source_ip.to_string().into());
+        }
+        let (query, values) = Query::insert()
+            .into_table(AuthFailures::Table)
+            .columns(columns)
+            .values_panic(values)
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        let mut query_builder = Query::select()
+            .column(AuthFailures::Time)
+            .column(AuthFailures::UserId)
+            .column(AuthFailures::SourceIp)
+            .column(AuthFailures::Protocol)
+            .column(AuthFailures::Reason)
+            .from(AuthFailures::Table)
+            .order_by(AuthFailures::AuthFailureId, Order::Desc)
+            .limit(limit as u64)
+            .offset(offset as u64)
+            .to_owned();
+        if let Some(user_id) = user_id {
+            query_builder.and_where(Expr::col(AuthFailures::UserId).eq(fold_case(user_id)));
+        }
+        let (query, values) = query_builder.build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let protocol = row.get::<String, _>(&*AuthFailures::Protocol.to_string());
+                let reason = row.get::<String, _>(&*AuthFailures::Reason.to_string());
+                Ok(AuthFailure {
+                    time: row.get(&*AuthFailures::Time.to_string()),
+                    user_id: row.get(&*AuthFailures::UserId.to_string()),
+                    source_ip: row
+                        .get::<Option<String>, _>(&*AuthFailures::SourceIp.to_string())
+                        .and_then(|ip| ip.parse().ok()),
+                    protocol: AuthProtocol::parse(&protocol).ok_or_else(|| {
+                        DomainError::InternalError(format!("Unknown protocol {}", protocol))
+                    })?,
+                    reason: AuthFailureReason::parse(&reason).ok_or_else(|| {
+                        DomainError::InternalError(format!("Unknown reason {}", reason))
+                    })?,
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(level = "debug", skip(self, event))]
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        let mut columns = vec![
+            AuditLog::Time,
+            AuditLog::Action,
+            AuditLog::Target,
+            AuditLog::Success,
+        ];
+        let mut values = vec![
+            event.time.naive_utc().into(),
+            event.action.into(),
+            event.target.into(),
+            event.success.into(),
+        ];
+        if let Some(actor) = event.actor {
+            columns.push(AuditLog::Actor);
+            values.push(actor.into());
+        }
+        if let Some(source_ip) = event.source_ip {
+            columns.push(AuditLog::SourceIp);
+            values.push(source_ip.to_string().into());
+        }
+        let (query, values) = Query::insert()
+            .into_table(AuditLog::Table)
+            .columns(columns)
+            .values_panic(values)
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        let (query, values) = Query::select()
+            .column(AuditLog::Time)
+            .column(AuditLog::Actor)
+            .column(AuditLog::SourceIp)
+            .column(AuditLog::Action)
+            .column(AuditLog::Target)
+            .column(AuditLog::Success)
+            .from(AuditLog::Table)
+            .order_by(AuditLog::AuditLogId, Order::Desc)
+            .limit(limit as u64)
+            .offset(offset as u64)
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(|row| AuditEvent {
+                time: row.get(&*AuditLog::Time.to_string()),
+                actor: row.get(&*AuditLog::Actor.to_string()),
+                source_ip: row
+                    .get::<Option<String>, _>(&*AuditLog::SourceIp.to_string())
+                    .and_then(|ip| ip.parse().ok()),
+                action: row.get(&*AuditLog::Action.to_string()),
+                target: row.get(&*AuditLog::Target.to_string()),
+                success: row.get(&*AuditLog::Success.to_string()),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::init_table;
+    use crate::infra::configuration::ConfigurationBuilder;
+    use lldap_auth::{opaque, registration};
+
+    fn get_default_config() -> Configuration {
+        ConfigurationBuilder::default()
+            .verbose(true)
+            .build()
+            .unwrap()
+    }
+
+    async fn get_in_memory_db() -> Pool {
+        PoolOptions::new().connect("sqlite::memory:").await.unwrap()
+    }
+
+    async fn get_initialized_db() -> Pool {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
+        use crate::domain::opaque_handler::OpaqueHandler;
+        insert_user_no_password(handler, name).await;
+        let mut rng = rand::rngs::OsRng;
+        let client_registration_start =
+            opaque::client::registration::start_registration(pass, &mut rng).unwrap();
+        let response = handler
+            .registration_start(registration::ClientRegistrationStartRequest {
+                username: name.to_string(),
+                registration_start_request: client_registration_start.message,
+            })
+            .await
+            .unwrap();
+        let registration_upload = opaque::client::registration::finish_registration(
+            client_registration_start.state,
+            response.registration_response,
+            &mut rng,
+        )
+        .unwrap();
+        handler
+            .registration_finish(registration::ClientRegistrationFinishRequest {
+                server_data: response.server_data,
+                registration_upload: registration_upload.message,
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn insert_user_no_password(handler: &SqlBackendHandler, name: &str) {
+        handler
+            .create_user(CreateUserRequest {
+                user_id: name.to_string(),
+                email: format!("{}@bob.bob", name),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> GroupId {
+        handler.create_group(name).await.unwrap()
+    }
+
+    async fn insert_membership(handler: &SqlBackendHandler, group_id: GroupId, user_id: &str) {
+        handler.add_user_to_group(user_id, group_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_admin() {
+        let sql_pool = get_in_memory_db().await;
         let config = ConfigurationBuilder::default()
             .ldap_user_dn("admin".to_string())
             .ldap_user_pass("test".to_string())
@@ -496,6 +2540,181 @@ mod tests {
             .unwrap_err();
     }
 
+    #[tokio::test]
+    async fn test_list_users_stream() {
+        use futures::TryStreamExt;
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let users = handler
+            .list_users_stream(None, UserOrder::UserId)
+            .map_ok(|u| u.user_id)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(users, vec!["bob", "john", "patrick"]);
+        let users = handler
+            .list_users_stream(
+                Some(RequestFilter::Not(Box::new(RequestFilter::And(vec![])))),
+                UserOrder::UserId,
+            )
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert!(users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_users_unknown_field() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let users = handler
+            .list_users(Some(RequestFilter::Or(vec![
+                RequestFilter::Equality("1 = 1 OR user_id".to_string(), "x".to_string()),
+                RequestFilter::Equality("password_hash".to_string(), "x".to_string()),
+            ])))
+            .await
+            .unwrap();
+        assert!(users.is_empty());
+        let users = handler
+            .list_users(Some(RequestFilter::Equality(
+                "email".to_string(),
+                "bob@bob.bob".to_string(),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_users_stream_order() {
+        use futures::TryStreamExt;
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for (user_id, display_name) in &[("bob", "Zed"), ("jim", "Al"), ("al", "Zed")] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.to_string(),
+                    email: format!("{}@example.com", user_id),
+                    display_name: Some(display_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let users = handler
+            .list_users_stream(None, UserOrder::DisplayName)
+            .map_ok(|u| u.user_id)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(users, vec!["jim", "al", "bob"]);
+        // Same result with the default implementation.
+        let mut users = handler.list_users(None).await.unwrap();
+        UserOrder::DisplayName.sort(&mut users);
+        assert_eq!(
+            users.into_iter().map(|u| u.user_id).collect::<Vec<_>>(),
+            vec!["jim", "al", "bob"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_page() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for (user_id, display_name) in
+            &[("bob", "Zed"), ("jim", "Al"), ("al", "Zed"), ("john", "Jo")]
+        {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.to_string(),
+                    email: format!("{}@example.com", user_id),
+                    display_name: Some(display_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let get_page = |filters, order, direction, offset| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .list_users_page(filters, order, direction, offset, 2)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            get_page(None, UserOrder::UserId, SortDirection::Ascending, 0).await,
+            vec!["al", "bob"]
+        );
+        assert_eq!(
+            get_page(None, UserOrder::UserId, SortDirection::Ascending, 2).await,
+            vec!["jim", "john"]
+        );
+        assert!(
+            get_page(None, UserOrder::UserId, SortDirection::Ascending, 4)
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            get_page(None, UserOrder::DisplayName, SortDirection::Ascending, 1).await,
+            vec!["john", "al"]
+        );
+        assert_eq!(
+            get_page(
+                Some(RequestFilter::Equality(
+                    "display_name".to_string(),
+                    "Zed".to_string()
+                )),
+                UserOrder::UserId,
+                SortDirection::Ascending,
+                1
+            )
+            .await,
+            vec!["bob"]
+        );
+        // The exact reverse, ties included.
+        assert_eq!(
+            get_page(None, UserOrder::DisplayName, SortDirection::Descending, 0).await,
+            vec!["bob", "al"]
+        );
+        assert_eq!(
+            get_page(None, UserOrder::Email, SortDirection::Descending, 1).await,
+            vec!["jim", "bob"]
+        );
+        assert_eq!(handler.count_users(None).await.unwrap(), 4);
+        assert_eq!(
+            handler
+                .count_users(Some(RequestFilter::Equality(
+                    "display_name".to_string(),
+                    "Zed".to_string()
+                )))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            handler
+                .count_users(Some(RequestFilter::Not(Box::new(RequestFilter::And(
+                    vec![]
+                )))))
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
     #[tokio::test]
     async fn test_list_users() {
         let sql_pool = get_initialized_db().await;
@@ -512,7 +2731,7 @@ mod tests {
                 .into_iter()
                 .map(|u| u.user_id)
                 .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "bob", "patrick"]);
+            assert_eq!(users, vec!["bob", "john", "patrick"]);
         }
         {
             let users = handler
@@ -538,7 +2757,7 @@ mod tests {
                 .into_iter()
                 .map(|u| u.user_id)
                 .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "bob"]);
+            assert_eq!(users, vec!["bob", "john"]);
         }
         {
             let users = handler
@@ -551,7 +2770,92 @@ mod tests {
                 .into_iter()
                 .map(|u| u.user_id)
                 .collect::<Vec<_>>();
-            assert_eq!(users, vec!["John", "patrick"]);
+            assert_eq!(users, vec!["john", "patrick"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_users_substring() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for (user_id, display_name) in &[
+            ("john", "John Doe"),
+            ("jo_doe", "Jo_ Doe"),
+            ("joan", "Joan 100%"),
+            ("bob", "Bob Jones"),
+        ] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.to_string(),
+                    email: format!("{}@example.com", user_id),
+                    display_name: Some(display_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let all_users = handler.list_users(None).await.unwrap();
+        let substring =
+            |initial: Option<&str>, any: &[&str], final_: Option<&str>| SubstringFilter {
+                initial: initial.map(str::to_string),
+                any: any.iter().map(|s| s.to_string()).collect(),
+                final_: final_.map(str::to_string),
+            };
+        for (field, substrings, expected) in vec![
+            (
+                "display_name",
+                substring(Some("jo"), &[], None),
+                vec!["jo_doe", "joan", "john"],
+            ),
+            (
+                "display_name",
+                substring(None, &["o"], Some("E")),
+                vec!["jo_doe", "john"],
+            ),
+            (
+                "display_name",
+                substring(Some("j"), &["_"], None),
+                vec!["jo_doe"],
+            ),
+            ("display_name", substring(None, &["0%"], None), vec!["joan"]),
+            (
+                "display_name",
+                substring(None, &["' OR '1' = '1"], None),
+                vec![],
+            ),
+            (
+                "user_id",
+                substring(Some("jo"), &["n"], None),
+                vec!["joan", "john"],
+            ),
+            (
+                "email",
+                substring(None, &[], Some("@example.com")),
+                vec!["bob", "jo_doe", "joan", "john"],
+            ),
+            ("password", substring(Some("a"), &[], None), vec![]),
+        ] {
+            let users = handler
+                .list_users(Some(RequestFilter::Substring(
+                    field.to_string(),
+                    substrings.clone(),
+                )))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>();
+            assert_eq!(users, expected, "{} {:?}", field, substrings);
+            // The in-memory match agrees with the SQL one.
+            if field == "display_name" {
+                let matching = all_users
+                    .iter()
+                    .filter(|u| substrings.matches(&u.display_name))
+                    .map(|u| u.user_id.clone())
+                    .collect::<Vec<_>>();
+                assert_eq!(matching, expected);
+            }
         }
     }
 
@@ -571,7 +2875,7 @@ mod tests {
         insert_membership(&handler, group_2, "patrick").await;
         insert_membership(&handler, group_2, "John").await;
         assert_eq!(
-            handler.list_groups().await.unwrap(),
+            handler.list_groups(None).await.unwrap(),
             vec![
                 Group {
                     id: group_1,
@@ -586,41 +2890,998 @@ mod tests {
                 Group {
                     id: group_2,
                     display_name: "Worst Group".to_string(),
-                    users: vec!["John".to_string(), "patrick".to_string()]
+                    users: vec!["john".to_string(), "patrick".to_string()]
                 },
             ]
         );
     }
 
     #[tokio::test]
-    async fn test_get_user_details() {
+    async fn test_list_groups_filter() {
         let sql_pool = get_initialized_db().await;
         let config = get_default_config();
         let handler = SqlBackendHandler::new(config, sql_pool);
         insert_user(&handler, "bob", "bob00").await;
-        {
-            let user = handler.get_user_details("bob").await.unwrap();
-            assert_eq!(user.user_id, "bob".to_string());
-        }
-        {
-            handler.get_user_details("John").await.unwrap_err();
-        }
-    }
-    #[tokio::test]
-    async fn test_get_user_groups() {
-        let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
         insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        let group_1 = insert_group(&handler, "Group1").await;
-        let group_2 = insert_group(&handler, "Group2").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        let group_3 = insert_group(&handler, "Empty Group").await;
         insert_membership(&handler, group_1, "bob").await;
-        insert_membership(&handler, group_1, "patrick").await;
         insert_membership(&handler, group_2, "patrick").await;
-        let mut bob_groups = HashSet::new();
-        bob_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
+        handler.add_group_to_group(group_1, group_3).await.unwrap();
+        handler
+            .set_group_mail(
+                group_2,
+                GroupMail {
+                    email: Some("worst@example.com".to_string()),
+                    aliases: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        let get_groups = |filter| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .list_groups(Some(filter))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|g| g.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            get_groups(GroupRequestFilter::DisplayName("best GROUP".to_string())).await,
+            vec![group_1]
+        );
+        assert!(
+            get_groups(GroupRequestFilter::DisplayName("Other".to_string()))
+                .await
+                .is_empty()
+        );
+        // Through the nested group.
+        assert_eq!(
+            get_groups(GroupRequestFilter::Member("Bob".to_string())).await,
+            vec![group_1, group_3]
+        );
+        assert_eq!(
+            get_groups(GroupRequestFilter::And(vec![
+                GroupRequestFilter::DisplayNameSubstring(SubstringFilter {
+                    any: vec!["GROUP".to_string()],
+                    ..Default::default()
+                }),
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::GroupId(group_1))),
+            ]))
+            .await,
+            vec![group_3, group_2]
+        );
+        assert_eq!(
+            get_groups(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Mail("WORST@example.com".to_string()),
+                GroupRequestFilter::Member("nobody".to_string()),
+            ]))
+            .await,
+            vec![group_2]
+        );
+        assert_eq!(
+            handler
+                .list_groups_page(Some(GroupRequestFilter::Member("bob".to_string())), 1, 1)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.id)
+                .collect::<Vec<_>>(),
+            vec![group_3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_groups_page() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        let group_3 = insert_group(&handler, "Empty Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        assert_eq!(
+            handler.list_groups_page(None, 0, 2).await.unwrap(),
+            vec![
+                Group {
+                    id: group_1,
+                    display_name: "Best Group".to_string(),
+                    users: vec!["bob".to_string(), "patrick".to_string()]
+                },
+                Group {
+                    id: group_3,
+                    display_name: "Empty Group".to_string(),
+                    users: vec![]
+                },
+            ]
+        );
+        assert_eq!(
+            handler.list_groups_page(None, 2, 2).await.unwrap(),
+            vec![Group {
+                id: group_2,
+                display_name: "Worst Group".to_string(),
+                users: vec!["patrick".to_string()]
+            }]
+        );
+        assert!(handler
+            .list_groups_page(None, 3, 2)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_groups_with_member_count() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Empty Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        assert_eq!(
+            handler.list_groups_with_member_count().await.unwrap(),
+            vec![
+                GroupSummary {
+                    id: group_1,
+                    display_name: "Best Group".to_string(),
+                    member_count: 2,
+                },
+                GroupSummary {
+                    id: group_2,
+                    display_name: "Empty Group".to_string(),
+                    member_count: 0,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_group_members() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "john", "pass").await;
+        insert_user(&handler, "nobody", "pass").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Empty Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_1, "john").await;
+        let member_ids = |page: GroupMembersPage| {
+            page.users
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>()
+        };
+        let page = handler.get_group_members(group_1, 0, 2).await.unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(member_ids(page), vec!["bob", "john"]);
+        let page = handler.get_group_members(group_1, 2, 2).await.unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(member_ids(page), vec!["patrick"]);
+        let page = handler.get_group_members(group_1, 4, 2).await.unwrap();
+        assert_eq!(page.total, 3);
+        assert!(page.users.is_empty());
+        assert_eq!(
+            handler.get_group_members(group_2, 0, 10).await.unwrap(),
+            GroupMembersPage::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_user_normalizes() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "jose\u{301}".to_string(),
+                email: "jose@example.com".to_string(),
+                display_name: Some("Jose\u{301}".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let user = handler.get_user_details("jos\u{e9}").await.unwrap();
+        assert_eq!(user.display_name, "Jos\u{e9}");
+        assert!(matches!(
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: "john,ou=admins".to_string(),
+                    email: "john@example.com".to_string(),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::InvalidFields(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_duplicates() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let create_user = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                ..Default::default()
+            })
+        };
+        assert!(matches!(
+            create_user("bob", "bob2@bob.bob").await,
+            Err(DomainError::Conflict(_))
+        ));
+        // Even without `unique_emails`, the database rejects them.
+        assert!(matches!(
+            create_user("jim", "bob@bob.bob").await,
+            Err(DomainError::Conflict(_))
+        ));
+        create_user("jim", "jim@bob.bob").await.unwrap();
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: "jim".to_string(),
+                    email: Some("bob@bob.bob".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_uuid() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let uuid = handler.get_user_details("bob").await.unwrap().uuid;
+        assert_eq!(uuid.len(), 36);
+        assert_ne!(
+            handler.get_user_details("patrick").await.unwrap().uuid,
+            uuid
+        );
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: "bob".to_string(),
+                display_name: Some("Bob".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(handler.get_user_details("bob").await.unwrap().uuid, uuid);
+        let users = handler
+            .list_users(Some(RequestFilter::Equality(
+                "uuid".to_string(),
+                uuid.clone(),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user_id, "bob");
+    }
+
+    #[tokio::test]
+    async fn test_user_uid_number() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.posix.first_uid_number = 2000;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let request = |user_id: &str| CreateUserRequest {
+            user_id: user_id.to_string(),
+            email: format!("{}@example.com", user_id),
+            ..Default::default()
+        };
+        // The invalid requests don't use a number.
+        handler
+            .bulk_create_users(vec![request("jim"), request("a,b"), request("jane")])
+            .await
+            .unwrap();
+        handler.create_user(request("tom")).await.unwrap();
+        let uid_numbers = handler
+            .list_users(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.user_id, u.uid_number))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            uid_numbers,
+            vec![
+                ("bob".to_string(), 2000),
+                ("jane".to_string(), 2003),
+                ("jim".to_string(), 2002),
+                ("patrick".to_string(), 2001),
+                ("tom".to_string(), 2004),
+            ]
+        );
+        let users = handler
+            .list_users(Some(RequestFilter::Equality(
+                "uid_number".to_string(),
+                "2001".to_string(),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user_id, "patrick");
+    }
+
+    #[tokio::test]
+    async fn test_rename_user() {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .ldap_user_dn("admin".to_string())
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let group = insert_group(&handler, "family").await;
+        insert_membership(&handler, group, "bob").await;
+        handler
+            .set_user_hosts("bob", vec!["*.example.com".to_string()])
+            .await
+            .unwrap();
+        let uuid = handler.get_user_details("bob").await.unwrap().uuid;
+
+        handler.rename_user("bob", "robert").await.unwrap();
+        assert!(handler.get_user_details("bob").await.is_err());
+        assert_eq!(handler.get_user_details("robert").await.unwrap().uuid, uuid);
+        assert_eq!(
+            handler
+                .get_user_groups("robert")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.1)
+                .collect::<Vec<_>>(),
+            vec!["family"]
+        );
+        assert_eq!(
+            handler.get_user_hosts("robert").await.unwrap(),
+            vec!["*.example.com"]
+        );
+        handler
+            .bind(BindRequest {
+                name: "robert".to_string(),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            handler.rename_user("robert", "patrick").await,
+            Err(DomainError::Conflict(_))
+        ));
+        assert!(matches!(
+            handler.rename_user("admin", "root").await,
+            Err(DomainError::InvalidInput(_))
+        ));
+        assert!(handler.rename_user("bob", "bobby").await.is_err());
+        assert!(handler.get_user_details("robert").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_create_users() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.unique_emails = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@example.com".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let request = |user_id: &str, email: &str| CreateUserRequest {
+            user_id: user_id.to_string(),
+            email: email.to_string(),
+            ..Default::default()
+        };
+        let results = handler
+            .bulk_create_users(vec![
+                request("bob", "bob2@example.com"),
+                request("jim", "BOB@example.com"),
+                request("john,ou=admins", "john@example.com"),
+                request("patrick", "patrick@example.com"),
+                request("patrick", "patrick2@example.com"),
+                request("tom", "patrick@example.com"),
+                request("jane", "jane@example.com"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            results.iter().map(Result::is_ok).collect::<Vec<_>>(),
+            vec![false, false, false, true, false, false, true]
+        );
+        assert!(results
+            .iter()
+            .filter_map(|r| r.as_ref().err())
+            .all(|e| matches!(e, DomainError::InvalidFields(_) | DomainError::Conflict(_))));
+        let users = handler
+            .list_users(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.user_id)
+            .collect::<Vec<_>>();
+        assert_eq!(users, vec!["bob", "jane", "patrick"]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_create_users_chunks() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let count = 2 * USER_INSERT_CHUNK_SIZE + 1;
+        let mut requests = (0..count)
+            .map(|i| CreateUserRequest {
+                user_id: format!("user{}", i),
+                email: format!("user{}@example.com", i),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        requests.push(CreateUserRequest {
+            user_id: "user0".to_string(),
+            email: "other@example.com".to_string(),
+            ..Default::default()
+        });
+        let results = handler.bulk_create_users(requests).await.unwrap();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), count);
+        assert!(matches!(results[count], Err(DomainError::Conflict(_))));
+        let users = handler.list_users(None).await.unwrap();
+        assert_eq!(users.len(), count);
+        let mut uid_numbers = users.iter().map(|u| u.uid_number).collect::<Vec<_>>();
+        uid_numbers.sort_unstable();
+        uid_numbers.dedup();
+        assert_eq!(uid_numbers.len(), count);
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let existing = insert_group(&handler, "existing").await;
+        handler
+            .apply_changes(DirectoryChanges {
+                created_users: vec![CreateUserRequest {
+                    user_id: "jim".to_string(),
+                    email: "jim@example.com".to_string(),
+                    ..Default::default()
+                }],
+                updated_users: vec![UpdateUserRequest {
+                    user_id: "bob".to_string(),
+                    display_name: Some("Bob".to_string()),
+                    ..Default::default()
+                }],
+                group_members: vec![
+                    ("existing".to_string(), vec!["jim".to_string()]),
+                    (
+                        "new".to_string(),
+                        vec!["bob".to_string(), "jim".to_string()],
+                    ),
+                ],
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_details("bob").await.unwrap().display_name,
+            "Bob"
+        );
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.id == existing, g.display_name.as_str(), g.users.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (true, "existing", vec!["jim".to_string()]),
+                (false, "new", vec!["bob".to_string(), "jim".to_string()]),
+            ]
+        );
+
+        // The update is invalid, so the user isn't created either.
+        handler
+            .apply_changes(DirectoryChanges {
+                created_users: vec![CreateUserRequest {
+                    user_id: "tom".to_string(),
+                    email: "tom@example.com".to_string(),
+                    ..Default::default()
+                }],
+                updated_users: vec![UpdateUserRequest {
+                    user_id: "bob".to_string(),
+                    display_name: Some("Bob\n".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(handler.get_user_details("tom").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_avatar() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        assert_eq!(handler.get_user_avatar("bob").await.unwrap(), None);
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: "bob".to_string(),
+                avatar: Some(vec![0xFF, 0xD8, 0xFF, 0x00]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_avatar("bob").await.unwrap(),
+            Some(vec![0xFF, 0xD8, 0xFF, 0x00])
+        );
+        handler.get_user_avatar("john").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_unique_emails() {
+        let sql_pool = get_initialized_db().await;
+        let mut config = get_default_config();
+        config.unique_emails = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let create_user = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                ..Default::default()
+            })
+        };
+        create_user("bob", "bob@example.com").await.unwrap();
+        create_user("jim", "jim@example.com").await.unwrap();
+        assert!(matches!(
+            create_user("bobby", "Bob@Example.com").await,
+            Err(DomainError::Conflict(_))
+        ));
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: "jim".to_string(),
+                    email: Some("BOB@example.com".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::Conflict(_))
+        ));
+        // Changing the case of one's own email is fine.
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: "bob".to_string(),
+                email: Some("Bob@example.com".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler
+                .get_user_by_email("BOB@EXAMPLE.COM")
+                .await
+                .unwrap()
+                .map(|u| u.user_id),
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            handler.get_user_by_email("al@example.com").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unique_email_index() {
+        let sql_pool = get_initialized_db().await;
+        set_unique_email_index(&sql_pool, true).await.unwrap();
+        let mut config = get_default_config();
+        config.unique_emails = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let create_user = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                ..Default::default()
+            })
+        };
+        create_user("bob", "Élodie@example.com").await.unwrap();
+        assert!(matches!(
+            create_user("jim", "éLODIE@EXAMPLE.COM").await,
+            Err(DomainError::Conflict(_))
+        ));
+        // The deleted users are not seen by the check, but they keep their email in the index,
+        // like a user created concurrently.
+        handler.delete_user("bob").await.unwrap();
+        assert_eq!(
+            handler
+                .get_user_by_email("élodie@example.com")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            create_user("jim", "ÉLODIE@example.com").await,
+            Err(DomainError::Conflict(_))
+        ));
+        create_user("jim", "jim@example.com").await.unwrap();
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: "jim".to_string(),
+                    email: Some("élodie@example.com".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_group_concurrently() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let names = (0..10).map(|i| format!("group{}", i)).collect::<Vec<_>>();
+        let ids = futures::future::try_join_all(names.iter().map(|n| handler.create_group(n)))
+            .await
+            .unwrap();
+        for (name, id) in names.iter().zip(ids) {
+            assert_eq!(&handler.get_group_details(id).await.unwrap().1, name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_group_with_name_of_renamed_group() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let renamed = insert_group(&handler, "admins").await;
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id: renamed,
+                display_name: Some("old admins".to_string()),
+                description: None,
+            })
+            .await
+            .unwrap();
+        let created = insert_group(&handler, "admins").await;
+        assert_ne!(created, renamed);
+        assert_eq!(
+            handler.get_group_details(created).await.unwrap().1,
+            "admins"
+        );
+        assert_eq!(
+            handler.get_group_details(renamed).await.unwrap().1,
+            "old admins"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_matching() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "Bob".to_string(),
+                email: "Bob@Example.com".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let group_id = insert_group(&handler, "Admins").await;
+        insert_membership(&handler, group_id, "BOB").await;
+        let users = handler
+            .list_users(Some(RequestFilter::And(vec![
+                RequestFilter::Equality("user_id".to_string(), "bOb".to_string()),
+                RequestFilter::Equality("email".to_string(), "BOB@example.COM".to_string()),
+                RequestFilter::MemberOf("admins".to_string()),
+            ])))
+            .await
+            .unwrap();
+        assert_eq!(
+            users
+                .into_iter()
+                .map(|u| (u.user_id, u.email))
+                .collect::<Vec<_>>(),
+            vec![("bob".to_string(), "bob@example.com".to_string())]
+        );
+        assert!(matches!(
+            handler.create_group("ADMINS").await,
+            Err(DomainError::Conflict(_))
+        ));
+        let other_group = insert_group(&handler, "users").await;
+        assert!(matches!(
+            handler
+                .update_group(UpdateGroupRequest {
+                    group_id: other_group,
+                    display_name: Some("admins".to_string()),
+                    description: None,
+                })
+                .await,
+            Err(DomainError::Conflict(_))
+        ));
+        // Changing the case of its own name is allowed.
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id,
+                display_name: Some("ADMINS".to_string()),
+                description: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_entry_points_fold_the_case() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        let group_id = insert_group(&handler, "Admins").await;
+        insert_membership(&handler, group_id, "bob").await;
+
+        handler
+            .set_user_hosts("BOB", vec!["web.example.com".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_hosts("Bob").await.unwrap(),
+            vec!["web.example.com"]
+        );
+        assert_eq!(handler.get_user_avatar("BOB").await.unwrap(), None);
+        let groups = handler
+            .get_users_groups(&["BOB".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            groups["bob"],
+            std::iter::once(GroupIdAndName(group_id, "Admins".to_string())).collect()
+        );
+        handler.lock_user("BOB", "left").await.unwrap();
+        assert!(handler.get_user_lock("bob").await.unwrap().is_some());
+        handler.unlock_user("Bob").await.unwrap();
+        assert!(handler.get_user_lock("bob").await.unwrap().is_none());
+        handler.delete_user("BOB").await.unwrap();
+        handler.get_user_details("bob").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_nested_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let admins = insert_group(&handler, "Admins").await;
+        let staff = insert_group(&handler, "Staff").await;
+        let everyone = insert_group(&handler, "Everyone").await;
+        insert_membership(&handler, admins, "bob").await;
+        insert_membership(&handler, staff, "patrick").await;
+        handler.add_group_to_group(admins, staff).await.unwrap();
+        handler.add_group_to_group(staff, everyone).await.unwrap();
+        // Already nested.
+        handler.add_group_to_group(staff, everyone).await.unwrap();
+        // Cycles are rejected.
+        handler
+            .add_group_to_group(everyone, admins)
+            .await
+            .unwrap_err();
+        handler.add_group_to_group(staff, staff).await.unwrap_err();
+
+        let group_names = |groups: HashSet<GroupIdAndName>| {
+            let mut names = groups.into_iter().map(|g| g.1).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            group_names(handler.get_user_groups("bob").await.unwrap()),
+            vec!["Admins", "Everyone", "Staff"]
+        );
+        assert_eq!(
+            group_names(handler.get_user_groups("patrick").await.unwrap()),
+            vec!["Everyone", "Staff"]
+        );
+        let users_groups = handler
+            .get_users_groups(&["bob".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            group_names(users_groups["bob"].clone()),
+            vec!["Admins", "Everyone", "Staff"]
+        );
+
+        let member_of = |group: &str| {
+            let handler = handler.clone();
+            let filter = RequestFilter::MemberOf(group.to_string());
+            async move {
+                handler
+                    .list_users(Some(filter))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(member_of("Everyone").await, vec!["bob", "patrick"]);
+        assert_eq!(member_of("Staff").await, vec!["bob", "patrick"]);
+        assert_eq!(member_of("Admins").await, vec!["bob"]);
+        // The direct memberships are unchanged.
+        assert_eq!(
+            handler
+                .list_users(Some(RequestFilter::MemberOfId(everyone)))
+                .await
+                .unwrap(),
+            vec![]
+        );
+
+        handler
+            .remove_group_from_group(admins, staff)
+            .await
+            .unwrap();
+        assert_eq!(member_of("Everyone").await, vec!["patrick"]);
+        assert_eq!(
+            group_names(handler.get_user_groups("bob").await.unwrap()),
+            vec!["Admins"]
+        );
+        // Deleting a group removes its nesting.
+        handler.delete_group(staff).await.unwrap();
+        assert_eq!(member_of("Everyone").await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_users_in_bulk() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for user in &["bob", "jim", "tom"] {
+            insert_user_no_password(&handler, user).await;
+        }
+        let group = insert_group(&handler, "team").await;
+        let members = || async { handler.list_groups(None).await.unwrap()[0].users.clone() };
+        handler
+            .add_users_to_group(group, &["bob".to_string(), "jim".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(members().await, vec!["bob", "jim"]);
+        // "bob" is already a member, so "tom" isn't added either.
+        handler
+            .add_users_to_group(group, &["tom".to_string(), "bob".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(members().await, vec!["bob", "jim"]);
+        handler
+            .remove_users_from_group(group, &["bob".to_string(), "tom".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(members().await, vec!["jim"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_group_members() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for user in &["bob", "jim", "tom"] {
+            insert_user_no_password(&handler, user).await;
+        }
+        let group = insert_group(&handler, "team").await;
+        insert_membership(&handler, group, "bob").await;
+        insert_membership(&handler, group, "jim").await;
+        let changes = handler
+            .set_group_members(group, &["tom".to_string(), "jim".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            changes,
+            MembershipChanges {
+                added: vec!["tom".to_string()],
+                removed: vec!["bob".to_string()],
+            }
+        );
+        assert_eq!(
+            handler.list_groups(None).await.unwrap()[0].users,
+            vec!["jim", "tom"]
+        );
+        // Applying the same list again changes nothing.
+        assert_eq!(
+            handler
+                .set_group_members(group, &["jim".to_string(), "tom".to_string()])
+                .await
+                .unwrap(),
+            MembershipChanges::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_users_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for user in &["bob", "jim", "tom"] {
+            insert_user_no_password(&handler, user).await;
+        }
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_2, "bob").await;
+        insert_membership(&handler, group_2, "jim").await;
+        let groups = handler
+            .get_users_groups(&["bob".to_string(), "tom".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups["bob"],
+            vec![
+                GroupIdAndName(group_1, "Group1".to_string()),
+                GroupIdAndName(group_2, "Group2".to_string())
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert!(groups["tom"].is_empty());
+        for user in &["bob", "tom"] {
+            assert_eq!(groups[*user], handler.get_user_groups(user).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_user_details() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        {
+            let user = handler.get_user_details("bob").await.unwrap();
+            assert_eq!(user.user_id, "bob".to_string());
+        }
+        {
+            handler.get_user_details("John").await.unwrap_err();
+        }
+    }
+    #[tokio::test]
+    async fn test_get_user_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        let mut bob_groups = HashSet::new();
+        bob_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
         let mut patrick_groups = HashSet::new();
         patrick_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
         patrick_groups.insert(GroupIdAndName(group_2, "Group2".to_string()));
@@ -642,11 +3903,11 @@ mod tests {
         let handler = SqlBackendHandler::new(config, sql_pool.clone());
 
         insert_user(&handler, "val", "s3np4i").await;
-        insert_user(&handler, "Hector", "Be$t").await;
-        insert_user(&handler, "Jennz", "boupBoup").await;
+        insert_user(&handler, "hector", "Be$t").await;
+        insert_user(&handler, "jennz", "boupBoup").await;
 
         // Remove a user
-        let _request_result = handler.delete_user("Jennz").await.unwrap();
+        let _request_result = handler.delete_user("jennz").await.unwrap();
 
         let users = handler
             .list_users(None)
@@ -656,12 +3917,12 @@ mod tests {
             .map(|u| u.user_id)
             .collect::<Vec<_>>();
 
-        assert_eq!(users, vec!["Hector", "val"]);
+        assert_eq!(users, vec!["hector", "val"]);
 
         // Insert new user and remove two
-        insert_user(&handler, "NewBoi", "Joni").await;
-        let _request_result = handler.delete_user("Hector").await.unwrap();
-        let _request_result = handler.delete_user("NewBoi").await.unwrap();
+        insert_user(&handler, "newboi", "Joni").await;
+        let _request_result = handler.delete_user("hector").await.unwrap();
+        let _request_result = handler.delete_user("newboi").await.unwrap();
 
         let users = handler
             .list_users(None)
@@ -673,4 +3934,572 @@ mod tests {
 
         assert_eq!(users, vec!["val"]);
     }
+
+    #[tokio::test]
+    async fn test_recycle_bin() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        let group_id = insert_group(&handler, "family").await;
+        insert_membership(&handler, group_id, "bob").await;
+
+        handler.delete_user("bob").await.unwrap();
+        assert_eq!(
+            handler
+                .list_users(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>(),
+            vec!["patrick"]
+        );
+        handler.get_user_details("bob").await.unwrap_err();
+        assert!(handler.list_groups(None).await.unwrap()[0].users.is_empty());
+        assert_eq!(
+            handler.list_groups_with_member_count().await.unwrap()[0].member_count,
+            0
+        );
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap_err();
+        let deleted_users = handler.list_deleted_users().await.unwrap();
+        assert_eq!(deleted_users.len(), 1);
+        assert_eq!(deleted_users[0].user_id, "bob");
+
+        // The user comes back with their groups and password.
+        handler.restore_user("bob").await.unwrap();
+        handler.restore_user("bob").await.unwrap_err();
+        assert!(handler.list_deleted_users().await.unwrap().is_empty());
+        assert_eq!(
+            handler.list_groups(None).await.unwrap()[0].users,
+            vec!["bob"]
+        );
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap();
+
+        // Only the users deleted before the date are purged.
+        handler.delete_user("bob").await.unwrap();
+        assert!(
+            purge_deleted_users(&sql_pool, chrono::Utc::now() - chrono::Duration::days(1))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            purge_deleted_users(&sql_pool, chrono::Utc::now() + chrono::Duration::seconds(1))
+                .await
+                .unwrap(),
+            vec!["bob"]
+        );
+        handler.restore_user("bob").await.unwrap_err();
+        insert_user(&handler, "bob", "bob00").await;
+    }
+
+    #[tokio::test]
+    async fn test_restore_user() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        // Only the deleted users can be restored.
+        handler.restore_user("bob").await.unwrap_err();
+        handler.restore_user("unknown").await.unwrap_err();
+
+        handler.delete_user("bob").await.unwrap();
+        handler.restore_user("BOB").await.unwrap();
+        assert_eq!(
+            handler.get_user_details("bob").await.unwrap().user_id,
+            "bob"
+        );
+        assert_eq!(
+            handler
+                .list_users(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>(),
+            vec!["bob", "patrick"]
+        );
+        assert!(handler.list_deleted_users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_purges_references() {
+        use crate::infra::configuration::{CustomAttributeConfig, LdapSchemaConfig};
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .ldap_schema(LdapSchemaConfig {
+                object_classes: vec![],
+                attributes: vec![CustomAttributeConfig {
+                    name: "roomNumber".to_string(),
+                    oid: None,
+                    single_value: true,
+                }],
+            })
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        let parent = insert_group(&handler, "parent").await;
+        let child = insert_group(&handler, "child").await;
+        insert_membership(&handler, parent, "bob").await;
+        insert_membership(&handler, child, "bob").await;
+        handler.add_group_to_group(child, parent).await.unwrap();
+        handler
+            .set_group_mail(
+                child,
+                GroupMail {
+                    email: Some("child@example.com".to_string()),
+                    aliases: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        handler
+            .set_user_hosts("bob", vec!["*.example.com".to_string()])
+            .await
+            .unwrap();
+        handler
+            .set_user_attribute("bob", "roomNumber", vec!["42".to_string()])
+            .await
+            .unwrap();
+        handler.lock_user("bob", "left").await.unwrap();
+        let count = |table: &'static str| {
+            let sql_pool = sql_pool.clone();
+            async move {
+                sqlx::query(&format!("SELECT COUNT(*) AS count FROM {}", table))
+                    .map(|row: DbRow| row.get::<i64, _>("count"))
+                    .fetch_one(&sql_pool)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_ne!(count("password_changes").await, 0);
+
+        handler.delete_group(child).await.unwrap();
+        assert_eq!(count("group_memberships").await, 0);
+        assert_eq!(count("group_mail_addresses").await, 0);
+        assert_eq!(count("memberships").await, 1);
+
+        handler.delete_user("bob").await.unwrap();
+        assert_eq!(count("memberships").await, 1);
+        purge_deleted_users(&sql_pool, chrono::Utc::now() + chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        for table in [
+            "memberships",
+            "user_hosts",
+            "user_attributes",
+            "locked_users",
+            "password_changes",
+        ] {
+            assert_eq!(count(table).await, 0, "{}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_hosts() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        assert!(handler.get_user_hosts("bob").await.unwrap().is_empty());
+
+        handler
+            .set_user_hosts(
+                "bob",
+                vec!["web.example.com".to_string(), "db.example.com".to_string()],
+            )
+            .await
+            .unwrap();
+        handler
+            .set_user_hosts("patrick", vec!["web.example.com".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_hosts("bob").await.unwrap(),
+            vec!["db.example.com", "web.example.com"]
+        );
+
+        // Setting the hosts replaces the previous ones.
+        handler
+            .set_user_hosts("bob", vec!["*.example.com".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_hosts("bob").await.unwrap(),
+            vec!["*.example.com"]
+        );
+        assert_eq!(
+            handler.get_user_hosts("patrick").await.unwrap(),
+            vec!["web.example.com"]
+        );
+
+        handler.delete_user("patrick").await.unwrap();
+        purge_deleted_users(&sql_pool, chrono::Utc::now() + chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert!(handler.get_user_hosts("patrick").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_group_mail() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        let group_1 = insert_group(&handler, "family").await;
+        let group_2 = insert_group(&handler, "friends").await;
+        assert_eq!(
+            handler.get_group_mail(group_1).await.unwrap(),
+            GroupMail::default()
+        );
+
+        handler
+            .set_group_mail(
+                group_1,
+                GroupMail {
+                    email: Some(" family@example.com ".to_string()),
+                    aliases: vec![
+                        "relatives@example.com".to_string(),
+                        "".to_string(),
+                        "family@example.com".to_string(),
+                        "home@example.com".to_string(),
+                        "relatives@example.com".to_string(),
+                    ],
+                },
+            )
+            .await
+            .unwrap();
+        handler
+            .set_group_mail(
+                group_2,
+                GroupMail {
+                    email: Some("friends@example.com".to_string()),
+                    aliases: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_group_mail(group_1).await.unwrap(),
+            GroupMail {
+                email: Some("family@example.com".to_string()),
+                aliases: vec![
+                    "home@example.com".to_string(),
+                    "relatives@example.com".to_string()
+                ],
+            }
+        );
+
+        // Setting the mail replaces the previous addresses.
+        handler
+            .set_group_mail(
+                group_1,
+                GroupMail {
+                    email: None,
+                    aliases: vec!["home@example.com".to_string()],
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_group_mail(group_1).await.unwrap(),
+            GroupMail {
+                email: None,
+                aliases: vec!["home@example.com".to_string()],
+            }
+        );
+        assert_eq!(
+            handler.get_group_mail(group_2).await.unwrap().email,
+            Some("friends@example.com".to_string())
+        );
+
+        handler.delete_group(group_2).await.unwrap();
+        assert_eq!(
+            handler.get_group_mail(group_2).await.unwrap(),
+            GroupMail::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let failure = |user_id: Option<&str>, reason| AuthFailure {
+            time: chrono::Utc::now(),
+            user_id: user_id.map(str::to_string),
+            source_ip: Some("10.0.0.1".parse().unwrap()),
+            protocol: AuthProtocol::Ldap,
+            reason,
+        };
+        handler
+            .record_auth_failure(failure(Some("bob"), AuthFailureReason::InvalidCredentials))
+            .await
+            .unwrap();
+        handler
+            .record_auth_failure(failure(Some("cn=bob,ou=x"), AuthFailureReason::InvalidDn))
+            .await
+            .unwrap();
+        handler
+            .record_auth_failure(AuthFailure {
+                source_ip: None,
+                protocol: AuthProtocol::Web,
+                ..failure(Some("patrick"), AuthFailureReason::UserLocked)
+            })
+            .await
+            .unwrap();
+
+        let failures = handler.list_auth_failures(None, 0, 10).await.unwrap();
+        assert_eq!(
+            failures
+                .iter()
+                .map(|f| (f.user_id.as_deref(), f.protocol, f.reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Some("patrick"),
+                    AuthProtocol::Web,
+                    AuthFailureReason::UserLocked
+                ),
+                (None, AuthProtocol::Ldap, AuthFailureReason::InvalidDn),
+                (
+                    Some("bob"),
+                    AuthProtocol::Ldap,
+                    AuthFailureReason::InvalidCredentials
+                ),
+            ]
+        );
+        assert_eq!(failures[0].source_ip, None);
+        assert_eq!(failures[1].source_ip, Some("10.0.0.1".parse().unwrap()));
+
+        let failures = handler.list_auth_failures(None, 1, 1).await.unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].reason, AuthFailureReason::InvalidDn);
+
+        let failures = handler
+            .list_auth_failures(Some("bob"), 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].reason, AuthFailureReason::InvalidCredentials);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .record_audit_event(AuditEvent {
+                time: chrono::Utc::now(),
+                actor: Some("admin".to_string()),
+                source_ip: Some("10.0.0.1".parse().unwrap()),
+                action: "create_user".to_string(),
+                target: "bob".to_string(),
+                success: true,
+            })
+            .await
+            .unwrap();
+        handler
+            .record_audit_event(AuditEvent {
+                time: chrono::Utc::now(),
+                actor: Some("bob".to_string()),
+                source_ip: None,
+                action: "bind".to_string(),
+                target: "bob".to_string(),
+                success: false,
+            })
+            .await
+            .unwrap();
+
+        let events = handler.list_audit_events(0, 10).await.unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.action.as_str(), e.actor.as_deref(), e.success))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bind", Some("bob"), false),
+                ("create_user", Some("admin"), true)
+            ]
+        );
+        assert_eq!(events[0].source_ip, None);
+        assert_eq!(events[1].source_ip, Some("10.0.0.1".parse().unwrap()));
+
+        let events = handler.list_audit_events(1, 1).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].target, "bob");
+        assert_eq!(events[0].action, "create_user");
+    }
+
+    #[tokio::test]
+    async fn test_user_attributes() {
+        use crate::infra::configuration::{CustomAttributeConfig, LdapSchemaConfig};
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .ldap_schema(LdapSchemaConfig {
+                object_classes: vec!["eduPerson".to_string()],
+                attributes: vec![
+                    CustomAttributeConfig {
+                        name: "eduPersonAffiliation".to_string(),
+                        oid: None,
+                        single_value: false,
+                    },
+                    CustomAttributeConfig {
+                        name: "employeeType".to_string(),
+                        oid: None,
+                        single_value: true,
+                    },
+                ],
+            })
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        handler
+            .set_user_attribute(
+                "bob",
+                "edupersonaffiliation",
+                vec!["staff".to_string(), "member".to_string()],
+            )
+            .await
+            .unwrap();
+        handler
+            .set_user_attribute(
+                "patrick",
+                "eduPersonAffiliation",
+                vec!["member".to_string()],
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .set_user_attribute("bob", "unknown", vec!["x".to_string()])
+                .await,
+            Err(DomainError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            handler
+                .set_user_attribute(
+                    "bob",
+                    "employeeType",
+                    vec!["a".to_string(), "b".to_string()]
+                )
+                .await,
+            Err(DomainError::InvalidInput(_))
+        ));
+        let attributes = handler.get_user_attributes("bob").await.unwrap();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes["eduPersonAffiliation"], vec!["member", "staff"]);
+
+        let users = handler
+            .list_users(Some(RequestFilter::AttributeEquality(
+                "eduPersonAffiliation".to_string(),
+                "staff".to_string(),
+            )))
+            .await
+            .unwrap();
+        assert_eq!(
+            users.into_iter().map(|u| u.user_id).collect::<Vec<_>>(),
+            vec!["bob"]
+        );
+
+        // Setting no values removes the attribute.
+        handler
+            .set_user_attribute("bob", "eduPersonAffiliation", vec![])
+            .await
+            .unwrap();
+        assert!(handler.get_user_attributes("bob").await.unwrap().is_empty());
+        handler.delete_user("patrick").await.unwrap();
+        purge_deleted_users(&sql_pool, chrono::Utc::now() + chrono::Duration::seconds(1))
+            .await
+            .unwrap();
+        assert!(handler
+            .get_user_attributes("patrick")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Runs against the databases of `LLDAP_TEST_POSTGRES_URL` and `LLDAP_TEST_MYSQL_URL`, when
+    /// they are set.
+    #[tokio::test]
+    async fn test_other_database_backends() {
+        for (variable, backend) in &[
+            ("LLDAP_TEST_POSTGRES_URL", DbBackend::Postgres),
+            ("LLDAP_TEST_MYSQL_URL", DbBackend::Mysql),
+        ] {
+            let url = match std::env::var(variable) {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+            assert_eq!(DbBackend::from_url(&url), Some(*backend));
+            let sql_pool = PoolOptions::new().connect(&url).await.unwrap();
+            init_table(&sql_pool).await.unwrap();
+            // Initializing an existing database is a no-op.
+            init_table(&sql_pool).await.unwrap();
+            let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+            assert_eq!(handler.backend(), *backend);
+            insert_user(&handler, "backend_test_bob", "bob00").await;
+            let group_id = insert_group(&handler, "Backend Test Group").await;
+            insert_membership(&handler, group_id, "backend_test_bob").await;
+            let mut bob_groups = HashSet::new();
+            bob_groups.insert(GroupIdAndName(group_id, "Backend Test Group".to_string()));
+            assert_eq!(
+                handler.get_user_groups("backend_test_bob").await.unwrap(),
+                bob_groups
+            );
+            let users = handler
+                .list_users(Some(RequestFilter::Substring(
+                    "user_id".to_string(),
+                    SubstringFilter {
+                        initial: Some("BACKEND_TEST".to_string()),
+                        any: vec![],
+                        final_: None,
+                    },
+                )))
+                .await
+                .unwrap();
+            assert_eq!(
+                users.into_iter().map(|u| u.user_id).collect::<Vec<_>>(),
+                vec!["backend_test_bob"]
+            );
+            assert!(handler
+                .get_password_changes(Some("backend_test_bob"))
+                .await
+                .unwrap()
+                .contains_key("backend_test_bob"));
+            handler.delete_user("backend_test_bob").await.unwrap();
+            assert!(handler.list_groups(None).await.unwrap()[0].users.is_empty());
+            handler.restore_user("backend_test_bob").await.unwrap();
+            assert_eq!(
+                handler.list_groups(None).await.unwrap()[0].users,
+                vec!["backend_test_bob"]
+            );
+            handler.delete_group(group_id).await.unwrap();
+            handler.delete_user("backend_test_bob").await.unwrap();
+            purge_deleted_users(
+                &handler.sql_pool,
+                chrono::Utc::now() + chrono::Duration::seconds(1),
+            )
+            .await
+            .unwrap();
+        }
+    }
 }
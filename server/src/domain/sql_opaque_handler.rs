@@ -1,20 +1,24 @@
 use super::{
     error::*,
-    handler::{BindRequest, LoginHandler},
+    handler::{BackendHandler, BindRequest, LoginHandler},
+    identifiers::fold_case,
     opaque_handler::*,
     sql_backend_handler::SqlBackendHandler,
     sql_tables::*,
 };
 use async_trait::async_trait;
 use lldap_auth::opaque;
-use log::*;
-use sea_query::{Expr, Iden, Query};
+use sea_query::{Expr, Iden, Order, Query, Value};
 use sqlx::Row;
+use tracing::{debug, instrument};
 
 type SqlOpaqueHandler = SqlBackendHandler;
 
+/// Runs the whole OPAQUE login against the password file. Without password file, it runs against
+/// a dummy one and fails, taking as long as a wrong password: the unknown users can't be told
+/// apart from the known ones by the time it takes to refuse them.
 fn passwords_match(
-    password_file_bytes: &[u8],
+    password_file_bytes: Option<&[u8]>,
     clear_password: &str,
     server_setup: &opaque::server::ServerSetup,
     username: &str,
@@ -23,12 +27,14 @@ fn passwords_match(
     let mut rng = rand::rngs::OsRng;
     let client_login_start_result = client::login::start_login(clear_password, &mut rng)?;
 
-    let password_file = server::ServerRegistration::deserialize(password_file_bytes)
+    let password_file = password_file_bytes
+        .map(server::ServerRegistration::deserialize)
+        .transpose()
         .map_err(opaque::AuthenticationError::ProtocolError)?;
     let server_login_start_result = server::login::start_login(
         &mut rng,
         server_setup,
-        Some(password_file),
+        password_file,
         client_login_start_result.message,
         username,
     )?;
@@ -39,6 +45,13 @@ fn passwords_match(
     Ok(())
 }
 
+/// The identifier the password file was registered with: the user ID, unless the user was renamed
+/// since.
+fn get_password_identifier(row: &DbRow, username: &str) -> String {
+    row.get::<Option<String>, _>(&*Users::PasswordIdentifier.to_string())
+        .unwrap_or_else(|| username.to_string())
+}
+
 impl SqlBackendHandler {
     fn get_orion_secret_key(&self) -> Result<orion::aead::SecretKey> {
         Ok(orion::aead::SecretKey::from_slice(
@@ -46,22 +59,28 @@ impl SqlBackendHandler {
         )?)
     }
 
+    /// The password file of the user if set, and the identifier it was registered with.
     async fn get_password_file_for_user(
         &self,
         username: &str,
-    ) -> Result<Option<opaque::server::ServerRegistration>> {
+    ) -> Result<Option<(opaque::server::ServerRegistration, String)>> {
         // Fetch the previously registered password file from the DB.
-        let password_file_bytes = {
-            let query = Query::select()
+        let (password_file_bytes, identifier) = {
+            let (query, values) = Query::select()
                 .column(Users::PasswordHash)
+                .column(Users::PasswordIdentifier)
                 .from(Users::Table)
-                .and_where(Expr::col(Users::UserId).eq(username))
-                .to_string(DbQueryBuilder {});
-            if let Some(row) = sqlx::query(&query).fetch_optional(&self.sql_pool).await? {
+                .and_where(Expr::col(Users::UserId).eq(fold_case(username)))
+                .and_where(Expr::col(Users::DeletedAt).is_null())
+                .build_db_query(self.backend());
+            if let Some(row) = sqlx::query_with(&query, values)
+                .fetch_optional(&self.sql_pool)
+                .await?
+            {
                 if let Some(bytes) =
                     row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
                 {
-                    bytes
+                    (bytes, get_password_identifier(&row, username))
                 } else {
                     // No password set.
                     return Ok(None);
@@ -72,48 +91,118 @@ impl SqlBackendHandler {
             }
         };
         opaque::server::ServerRegistration::deserialize(&password_file_bytes)
-            .map(Option::Some)
+            .map(|password_file| Some((password_file, identifier)))
             .map_err(|_| {
                 DomainError::InternalError(format!("Corrupted password file for {}", username))
             })
     }
+
+    /// The current password file of the user and the previous ones kept in the history, newest
+    /// first, with the identifiers they were registered with.
+    async fn get_password_files_with_history(
+        &self,
+        username: &str,
+    ) -> Result<Vec<(Vec<u8>, String)>> {
+        let history_size = self.config.password_policy.history_size;
+        let mut password_files = Vec::new();
+        if history_size == 0 {
+            return Ok(password_files);
+        }
+        let (query, values) = Query::select()
+            .column(Users::PasswordHash)
+            .column(Users::PasswordIdentifier)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(username))
+            .build_db_query(self.backend());
+        if let Some(row) = sqlx::query_with(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+        {
+            if let Some(bytes) = row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string()) {
+                password_files.push((bytes, get_password_identifier(&row, username)));
+            }
+        }
+        let (query, values) = Query::select()
+            .column(PasswordHistory::PasswordHash)
+            .column(PasswordHistory::PasswordIdentifier)
+            .from(PasswordHistory::Table)
+            .and_where(Expr::col(PasswordHistory::UserId).eq(username))
+            .order_by(PasswordHistory::PasswordHistoryId, Order::Desc)
+            .limit(history_size as u64 - 1)
+            .build_db_query(self.backend());
+        let history = sqlx::query_with(&query, values)
+            .map(|row: DbRow| {
+                (
+                    row.get::<Vec<u8>, _>(&*PasswordHistory::PasswordHash.to_string()),
+                    row.get::<String, _>(&*PasswordHistory::PasswordIdentifier.to_string()),
+                )
+            })
+            .fetch_all(&self.sql_pool)
+            .await?;
+        password_files.extend(history);
+        Ok(password_files)
+    }
 }
 
 #[async_trait]
 impl LoginHandler for SqlBackendHandler {
-    async fn bind(&self, request: BindRequest) -> Result<()> {
+    #[instrument(level = "debug", skip(self, request), fields(user_id = %request.name))]
+    async fn bind(&self, mut request: BindRequest) -> Result<()> {
+        request.name = fold_case(&request.name);
         if request.name == self.config.ldap_user_dn {
-            if request.password == self.config.ldap_user_pass {
+            if orion::util::secure_cmp(
+                request.password.as_bytes(),
+                self.config.ldap_user_pass.as_bytes(),
+            )
+            .is_ok()
+            {
                 return Ok(());
             } else {
                 debug!(r#"Invalid password for LDAP bind user"#);
                 return Err(DomainError::AuthenticationError(request.name));
             }
         }
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(Users::PasswordHash)
+            .column(Users::PasswordIdentifier)
             .from(Users::Table)
             .and_where(Expr::col(Users::UserId).eq(request.name.as_str()))
-            .to_string(DbQueryBuilder {});
-        if let Ok(row) = sqlx::query(&query).fetch_one(&self.sql_pool).await {
-            if let Some(password_hash) =
-                row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
-            {
-                if let Err(e) = passwords_match(
-                    &password_hash,
-                    &request.password,
-                    self.config.get_server_setup(),
-                    &request.name,
-                ) {
-                    debug!(r#"Invalid password for "{}": {}"#, request.name, e);
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .build_db_query(self.backend());
+        let (password_hash, identifier) = match sqlx::query_with(&query, values)
+            .fetch_one(&self.sql_pool)
+            .await
+        {
+            Ok(row) => {
+                let password_hash =
+                    row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string());
+                if password_hash.is_none() {
+                    debug!(r#"User "{}" has no password"#, request.name);
+                }
+                (password_hash, get_password_identifier(&row, &request.name))
+            }
+            Err(_) => {
+                debug!(r#"No user found for "{}""#, request.name);
+                (None, request.name.clone())
+            }
+        };
+        // Also without password, against a dummy password file.
+        match passwords_match(
+            password_hash.as_deref(),
+            &request.password,
+            self.config.get_server_setup(),
+            &identifier,
+        ) {
+            Err(e) => debug!(r#"Invalid password for "{}": {}"#, request.name, e),
+            // The dummy password file matches no password, but never let it through.
+            Ok(()) if password_hash.is_none() => (),
+            Ok(()) => {
+                if self.get_user_lock(&request.name).await?.is_some() {
+                    debug!(r#"User "{}" is locked"#, request.name);
                 } else {
                     return Ok(());
                 }
-            } else {
-                debug!(r#"User "{}" has no password"#, request.name);
             }
-        } else {
-            debug!(r#"No user found for "{}""#, request.name);
         }
         Err(DomainError::AuthenticationError(request.name))
     }
@@ -123,9 +212,14 @@ impl LoginHandler for SqlBackendHandler {
 impl OpaqueHandler for SqlOpaqueHandler {
     async fn login_start(
         &self,
-        request: login::ClientLoginStartRequest,
+        mut request: login::ClientLoginStartRequest,
     ) -> Result<login::ServerLoginStartResponse> {
-        let maybe_password_file = self.get_password_file_for_user(&request.username).await?;
+        request.username = fold_case(&request.username);
+        let (maybe_password_file, identifier) =
+            match self.get_password_file_for_user(&request.username).await? {
+                Some((password_file, identifier)) => (Some(password_file), identifier),
+                None => (None, request.username.clone()),
+            };
 
         let mut rng = rand::rngs::OsRng;
         // Get the CredentialResponse for the user, or a dummy one if no user/no password.
@@ -134,7 +228,7 @@ impl OpaqueHandler for SqlOpaqueHandler {
             self.config.get_server_setup(),
             maybe_password_file,
             request.login_start_request,
-            &request.username,
+            &identifier,
         )?;
         let secret_key = self.get_orion_secret_key()?;
         let server_data = login::ServerData {
@@ -160,17 +254,29 @@ impl OpaqueHandler for SqlOpaqueHandler {
         )?)?;
         // Finish the login: this makes sure the client data is correct, and gives a session key we
         // don't need.
-        let _session_key =
-            opaque::server::login::finish_login(server_login, request.credential_finalization)?
-                .session_key;
+        let _session_key = match opaque::server::login::finish_login(
+            server_login,
+            request.credential_finalization,
+        ) {
+            Ok(finish) => finish.session_key,
+            Err(e) => {
+                debug!(r#"Invalid password for "{}": {}"#, username, e);
+                return Err(DomainError::AuthenticationError(username));
+            }
+        };
+        if self.get_user_lock(&username).await?.is_some() {
+            debug!(r#"User "{}" is locked"#, username);
+            return Err(DomainError::AuthenticationError(username));
+        }
 
         Ok(username)
     }
 
     async fn registration_start(
         &self,
-        request: registration::ClientRegistrationStartRequest,
+        mut request: registration::ClientRegistrationStartRequest,
     ) -> Result<registration::ServerRegistrationStartResponse> {
+        request.username = fold_case(&request.username);
         // Generate the server-side key and derive the data to send back.
         let start_response = opaque::server::registration::start_registration(
             self.config.get_server_setup(),
@@ -200,25 +306,129 @@ impl OpaqueHandler for SqlOpaqueHandler {
 
         let password_file =
             opaque::server::registration::get_password_file(request.registration_upload);
+        let mut transaction = self.sql_pool.begin().await?;
+        let history_size = self.config.password_policy.history_size;
+        if history_size > 0 {
+            // The history holds the passwords before the current one: keep the replaced one, and
+            // drop the ones that don't fit anymore.
+            let (query, values) = Query::select()
+                .column(Users::PasswordHash)
+                .column(Users::PasswordIdentifier)
+                .from(Users::Table)
+                .and_where(Expr::col(Users::UserId).eq(username.as_str()))
+                .build_db_query(self.backend());
+            let previous = sqlx::query_with(&query, values)
+                .fetch_optional(&mut transaction)
+                .await?
+                .and_then(|row| {
+                    row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
+                        .map(|bytes| (bytes, get_password_identifier(&row, &username)))
+                });
+            if let Some((bytes, identifier)) = previous.filter(|_| history_size > 1) {
+                let (insert_query, insert_values) = Query::insert()
+                    .into_table(PasswordHistory::Table)
+                    .columns(vec![
+                        PasswordHistory::UserId,
+                        PasswordHistory::PasswordHash,
+                        PasswordHistory::PasswordIdentifier,
+                        PasswordHistory::ChangedAt,
+                    ])
+                    .values_panic(vec![
+                        username.as_str().into(),
+                        bytes.into(),
+                        identifier.into(),
+                        chrono::Utc::now().naive_utc().into(),
+                    ])
+                    .build_db_query(self.backend());
+                sqlx::query_with(&insert_query, insert_values)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+            let (query, values) = Query::select()
+                .column(PasswordHistory::PasswordHistoryId)
+                .from(PasswordHistory::Table)
+                .and_where(Expr::col(PasswordHistory::UserId).eq(username.as_str()))
+                .order_by(PasswordHistory::PasswordHistoryId, Order::Desc)
+                .build_db_query(self.backend());
+            let expired_ids = sqlx::query_with(&query, values)
+                .map(|row: DbRow| {
+                    row.get::<i32, _>(&*PasswordHistory::PasswordHistoryId.to_string())
+                })
+                .fetch_all(&mut transaction)
+                .await?
+                .into_iter()
+                .skip(history_size - 1)
+                .collect::<Vec<_>>();
+            if !expired_ids.is_empty() {
+                let (delete_query, delete_values) = Query::delete()
+                    .from_table(PasswordHistory::Table)
+                    .and_where(Expr::col(PasswordHistory::PasswordHistoryId).is_in(expired_ids))
+                    .build_db_query(self.backend());
+                sqlx::query_with(&delete_query, delete_values)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
         {
             // Set the user password to the new password.
-            let update_query = Query::update()
+            let (update_query, update_values) = Query::update()
                 .table(Users::Table)
-                .values(vec![(
-                    Users::PasswordHash,
-                    password_file.serialize().into(),
-                )])
-                .and_where(Expr::col(Users::UserId).eq(username))
-                .to_string(DbQueryBuilder {});
-            sqlx::query(&update_query).execute(&self.sql_pool).await?;
+                .values(vec![
+                    (Users::PasswordHash, password_file.serialize().into()),
+                    // Registered with the current user ID.
+                    (Users::PasswordIdentifier, Value::Null),
+                ])
+                .and_where(Expr::col(Users::UserId).eq(username.as_str()))
+                .build_db_query(self.backend());
+            sqlx::query_with(&update_query, update_values)
+                .execute(&mut transaction)
+                .await?;
+        }
+        {
+            // Record the change, for the password expiry.
+            let (delete_query, delete_values) = Query::delete()
+                .from_table(PasswordChanges::Table)
+                .and_where(Expr::col(PasswordChanges::UserId).eq(username.as_str()))
+                .build_db_query(self.backend());
+            sqlx::query_with(&delete_query, delete_values)
+                .execute(&mut transaction)
+                .await?;
+            let (insert_query, insert_values) = Query::insert()
+                .into_table(PasswordChanges::Table)
+                .columns(vec![PasswordChanges::UserId, PasswordChanges::ChangedAt])
+                .values_panic(vec![
+                    username.as_str().into(),
+                    chrono::Utc::now().naive_utc().into(),
+                ])
+                .build_db_query(self.backend());
+            sqlx::query_with(&insert_query, insert_values)
+                .execute(&mut transaction)
+                .await?;
         }
+        transaction.commit().await?;
         Ok(())
     }
+
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(self
+            .get_password_files_with_history(&fold_case(username))
+            .await?
+            .iter()
+            .any(|(bytes, identifier)| {
+                passwords_match(
+                    Some(bytes.as_slice()),
+                    password,
+                    self.config.get_server_setup(),
+                    identifier,
+                )
+                .is_ok()
+            }))
+    }
 }
 
 /// Convenience function to set a user's password.
-pub(crate) async fn register_password(
-    opaque_handler: &SqlOpaqueHandler,
+pub async fn register_password<Handler: OpaqueHandler>(
+    opaque_handler: &Handler,
     username: &str,
     password: &str,
 ) -> Result<()> {
@@ -253,7 +463,7 @@ mod tests {
             sql_backend_handler::SqlBackendHandler,
             sql_tables::init_table,
         },
-        infra::configuration::{Configuration, ConfigurationBuilder},
+        infra::configuration::{Configuration, ConfigurationBuilder, PasswordPolicyConfig},
     };
 
     fn get_default_config() -> Configuration {
@@ -328,4 +538,137 @@ mod tests {
         attempt_login(&opaque_handler, "bob", "bob00").await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_locked_user() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        register_password(&handler, "bob", "bob00").await?;
+        let bind_request = || BindRequest {
+            name: "bob".to_string(),
+            password: "bob00".to_string(),
+        };
+
+        handler.lock_user("bob", "Left the company").await?;
+        let lock = handler.get_user_lock("bob").await?.unwrap();
+        assert_eq!(lock.reason, "Left the company");
+        attempt_login(&handler, "bob", "bob00").await.unwrap_err();
+        handler.bind(bind_request()).await.unwrap_err();
+
+        handler.unlock_user("bob").await?;
+        assert_eq!(handler.get_user_lock("bob").await?, None);
+        attempt_login(&handler, "bob", "bob00").await?;
+        handler.bind(bind_request()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_failures_look_the_same() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "john".to_string(),
+                email: "john@example.com".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        register_password(&handler, "john", "john00").await?;
+        // Unknown user, no password and wrong password.
+        for name in &["patrick", "bob", "john"] {
+            let error = handler
+                .bind(BindRequest {
+                    name: name.to_string(),
+                    password: "wrong".to_string(),
+                })
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&error, DomainError::AuthenticationError(user) if user == *name),
+                "{}",
+                error
+            );
+        }
+        // The dummy password file matches no password.
+        passwords_match(None, "john00", handler.config.get_server_setup(), "john").unwrap_err();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_renamed_user() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        register_password(&handler, "bob", "bob00").await?;
+
+        handler.rename_user("bob", "robert").await?;
+        attempt_login(&handler, "robert", "bob00").await?;
+        handler
+            .bind(BindRequest {
+                name: "robert".to_string(),
+                password: "bob00".to_string(),
+            })
+            .await?;
+
+        // Both the passwords registered before and after a rename keep working after another.
+        register_password(&handler, "robert", "robert00").await?;
+        handler.rename_user("robert", "rob").await?;
+        attempt_login(&handler, "rob", "robert00").await?;
+        attempt_login(&handler, "rob", "bob00").await.unwrap_err();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_password_changes() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        assert!(handler.list_password_changes().await?.is_empty());
+
+        let before = chrono::Utc::now() - chrono::Duration::seconds(1);
+        register_password(&handler, "bob", "bob00").await?;
+        let changes = handler.list_password_changes().await?;
+        assert_eq!(changes.len(), 1);
+        assert!(changes["bob"] >= before);
+        assert_eq!(
+            handler.get_password_change("bob").await?,
+            Some(changes["bob"])
+        );
+        assert_eq!(handler.get_password_change("patrick").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_password_history() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .password_policy(PasswordPolicyConfig {
+                history_size: 3,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        assert!(!handler.is_password_reused("bob", "bob00").await?);
+
+        register_password(&handler, "bob", "bob00").await?;
+        register_password(&handler, "bob", "bob01").await?;
+        assert!(handler.is_password_reused("bob", "bob00").await?);
+        assert!(handler.is_password_reused("bob", "bob01").await?);
+        assert!(!handler.is_password_reused("bob", "bob02").await?);
+
+        // Only the last 3 passwords are kept, even across a rename.
+        register_password(&handler, "bob", "bob02").await?;
+        handler.rename_user("bob", "robert").await?;
+        register_password(&handler, "robert", "robert00").await?;
+        assert!(!handler.is_password_reused("robert", "bob00").await?);
+        assert!(handler.is_password_reused("robert", "bob01").await?);
+        assert!(handler.is_password_reused("robert", "bob02").await?);
+        assert!(handler.is_password_reused("robert", "robert00").await?);
+        Ok(())
+    }
 }
@@ -0,0 +1,921 @@
+//! An in-memory implementation of the backend traits, to write tests without a database.
+//!
+//! Enable the `test-utils` feature to use it from another crate:
+//!
+//! ```
+//! # use lldap::domain::{handler::*, test_backend_handler::TestBackendHandler};
+//! # async fn example() {
+//! let handler = TestBackendHandler::new();
+//! handler.insert_user("bob", "bob@example.com", Some("bob_password"));
+//! let group_id = handler.insert_group("family");
+//! handler.insert_membership("bob", group_id);
+//! assert_eq!(handler.get_user_groups("bob").await.unwrap().len(), 1);
+//! # }
+//! ```
+
+use super::{
+    error::*,
+    handler::*,
+    identifiers::{fold_case, generate_uuid, UserIdPolicy},
+    opaque_handler::*,
+    sql_tables::DEFAULT_FIRST_UID_NUMBER,
+    validation::{validate_create_user, validate_update_user},
+};
+use async_trait::async_trait;
+use lldap_auth::opaque;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    // Sorted by ID, like the SQL implementation.
+    users: BTreeMap<String, User>,
+    /// Clear-text passwords, for `bind`.
+    passwords: HashMap<String, String>,
+    /// OPAQUE password files, for the `OpaqueHandler` flows.
+    password_files: HashMap<String, Vec<u8>>,
+    avatars: HashMap<String, Vec<u8>>,
+    groups: BTreeMap<GroupId, String>,
+    memberships: BTreeSet<(String, GroupId)>,
+    /// (member group, parent group) pairs.
+    group_memberships: BTreeSet<(GroupId, GroupId)>,
+    hosts: BTreeSet<(String, String)>,
+    group_mails: HashMap<GroupId, GroupMail>,
+    group_metadata: HashMap<GroupId, GroupMetadata>,
+    /// (user, attribute, value) triples.
+    attributes: BTreeSet<(String, String, String)>,
+    locks: HashMap<String, UserLock>,
+    /// When the password was last set through `registration_finish`.
+    password_changes: HashMap<String, chrono::DateTime<chrono::Utc>>,
+    /// Oldest first.
+    auth_failures: Vec<AuthFailure>,
+    /// Oldest first.
+    audit_events: Vec<AuditEvent>,
+    next_group_id: i32,
+    next_uid_number: i32,
+}
+
+/// A [`BackendHandler`], [`LoginHandler`] and [`OpaqueHandler`] storing everything in memory.
+///
+/// Clones share the same storage, so a copy can be given to the code under test while keeping one
+/// to seed or inspect the data.
+#[derive(Clone)]
+pub struct TestBackendHandler {
+    state: Arc<Mutex<State>>,
+    server_setup: Arc<opaque::server::ServerSetup>,
+}
+
+impl Default for TestBackendHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn group_nesting(&self) -> GroupNesting {
+        GroupNesting::new(self.group_memberships.iter().copied())
+    }
+
+    fn allocate_uid_number(&mut self) -> i32 {
+        self.next_uid_number += 1;
+        self.next_uid_number - 1
+    }
+}
+
+fn not_found() -> DomainError {
+    DomainError::DatabaseError(sqlx::Error::RowNotFound)
+}
+
+fn matches_filter(state: &State, user: &User, filter: &RequestFilter) -> bool {
+    use RequestFilter::*;
+    match filter {
+        And(fs) => fs.iter().all(|f| matches_filter(state, user, f)),
+        Or(fs) => fs.iter().any(|f| matches_filter(state, user, f)),
+        Not(f) => !matches_filter(state, user, f),
+        Equality(field, value) => match field.as_str() {
+            "user_id" => &user.user_id == value,
+            "email" => &user.email == value,
+            "display_name" => &user.display_name == value,
+            "first_name" => &user.first_name == value,
+            "last_name" => &user.last_name == value,
+            "uuid" => &user.uuid == value,
+            "uid_number" => user.uid_number.to_string() == *value,
+            _ => false,
+        },
+        Substring(field, substrings) => match field.as_str() {
+            "user_id" => substrings.matches(&user.user_id),
+            "email" => substrings.matches(&user.email),
+            "display_name" => substrings.matches(&user.display_name),
+            "first_name" => substrings.matches(&user.first_name),
+            "last_name" => substrings.matches(&user.last_name),
+            _ => false,
+        },
+        AttributeEquality(name, value) => {
+            state
+                .attributes
+                .contains(&(user.user_id.clone(), name.clone(), value.clone()))
+        }
+        MemberOf(group_name) => {
+            let groups = match state.groups.iter().find(|(_, name)| *name == group_name) {
+                Some((group_id, _)) => state.group_nesting().with_descendants(*group_id),
+                None => return false,
+            };
+            state
+                .memberships
+                .iter()
+                .any(|(u, g)| u == &user.user_id && groups.contains(g))
+        }
+        MemberOfId(group_id) => state
+            .memberships
+            .contains(&(user.user_id.clone(), *group_id)),
+    }
+}
+
+fn matches_group_filter(
+    state: &State,
+    group_id: GroupId,
+    name: &str,
+    filter: &GroupRequestFilter,
+) -> bool {
+    use GroupRequestFilter::*;
+    match filter {
+        And(fs) => fs
+            .iter()
+            .all(|f| matches_group_filter(state, group_id, name, f)),
+        Or(fs) => fs
+            .iter()
+            .any(|f| matches_group_filter(state, group_id, name, f)),
+        Not(f) => !matches_group_filter(state, group_id, name, f),
+        DisplayName(value) => fold_case(name) == fold_case(value),
+        DisplayNameSubstring(substrings) => substrings.matches(name),
+        GroupId(id) => *id == group_id,
+        Member(user) => state
+            .group_nesting()
+            .with_ancestors(
+                state
+                    .memberships
+                    .iter()
+                    .filter(|(u, _)| u == user)
+                    .map(|(_, g)| *g),
+            )
+            .contains(&group_id),
+        Mail(address) => state.group_mails.get(&group_id).map_or(false, |mail| {
+            mail.addresses().any(|a| a.eq_ignore_ascii_case(address))
+        }),
+    }
+}
+
+impl TestBackendHandler {
+    pub fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        Self {
+            state: Arc::new(Mutex::new(State {
+                next_group_id: 1,
+                next_uid_number: DEFAULT_FIRST_UID_NUMBER,
+                ..Default::default()
+            })),
+            server_setup: Arc::new(opaque::server::ServerSetup::new(&mut rng)),
+        }
+    }
+
+    /// Seeds a user, with an optional password usable for `bind`.
+    pub fn insert_user(&self, user_id: &str, email: &str, password: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        let uid_number = state.allocate_uid_number();
+        state.users.insert(
+            user_id.to_string(),
+            User {
+                user_id: user_id.to_string(),
+                email: email.to_string(),
+                display_name: user_id.to_string(),
+                creation_date: chrono::Utc::now(),
+                uuid: generate_uuid(),
+                uid_number,
+            },
+        );
+        if let Some(password) = password {
+            state
+                .passwords
+                .insert(user_id.to_string(), password.to_string());
+        }
+    }
+
+    /// Seeds a group, returning its ID.
+    pub fn insert_group(&self, name: &str) -> GroupId {
+        let mut state = self.state.lock().unwrap();
+        let group_id = GroupId(state.next_group_id);
+        state.next_group_id += 1;
+        state.groups.insert(group_id, name.to_string());
+        state.group_metadata.insert(
+            group_id,
+            GroupMetadata {
+                description: None,
+                creation_date: chrono::Utc::now(),
+                uuid: generate_uuid(),
+            },
+        );
+        group_id
+    }
+
+    pub fn insert_membership(&self, user_id: &str, group_id: GroupId) {
+        self.state
+            .lock()
+            .unwrap()
+            .memberships
+            .insert((user_id.to_string(), group_id));
+    }
+}
+
+#[async_trait]
+impl LoginHandler for TestBackendHandler {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        match state.passwords.get(&request.name) {
+            Some(password)
+                if password == &request.password && !state.locks.contains_key(&request.name) =>
+            {
+                Ok(())
+            }
+            _ => Err(DomainError::AuthenticationError(request.name)),
+        }
+    }
+}
+
+#[async_trait]
+impl BackendHandler for TestBackendHandler {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .values()
+            .filter(|u| {
+                filters
+                    .as_ref()
+                    .map(|f| matches_filter(&state, u, f))
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        let state = self.state.lock().unwrap();
+        let mut groups = state
+            .groups
+            .iter()
+            .filter(|(id, display_name)| {
+                filters
+                    .as_ref()
+                    .map(|f| matches_group_filter(&state, **id, display_name, f))
+                    .unwrap_or(true)
+            })
+            .map(|(id, display_name)| Group {
+                id: *id,
+                display_name: display_name.clone(),
+                users: state
+                    .memberships
+                    .iter()
+                    .filter(|(_, g)| g == id)
+                    .map(|(u, _)| u.clone())
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        Ok(groups)
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        self.state
+            .lock()
+            .unwrap()
+            .users
+            .get(user_id)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+        if !state.users.contains_key(user_id) {
+            return Err(not_found());
+        }
+        Ok(state.avatars.get(user_id).cloned())
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.state
+            .lock()
+            .unwrap()
+            .groups
+            .get(&group_id)
+            .map(|name| GroupIdAndName(group_id, name.clone()))
+            .ok_or_else(not_found)
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        let state = self.state.lock().unwrap();
+        let mut parents = state
+            .group_nesting()
+            .with_ancestors(std::iter::once(group_id));
+        parents.remove(&group_id);
+        Ok(parents
+            .into_iter()
+            .filter_map(|id| {
+                state
+                    .groups
+                    .get(&id)
+                    .map(|name| GroupIdAndName(id, name.clone()))
+            })
+            .collect())
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        validate_create_user(&request, UserIdPolicy::Unicode)?;
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(&request.user_id) {
+            return Err(DomainError::InternalError(format!(
+                "User `{}` already exists",
+                request.user_id
+            )));
+        }
+        let uid_number = state.allocate_uid_number();
+        state.users.insert(
+            request.user_id.clone(),
+            User {
+                user_id: request.user_id,
+                email: request.email,
+                display_name: request.display_name.unwrap_or_default(),
+                first_name: request.first_name.unwrap_or_default(),
+                last_name: request.last_name.unwrap_or_default(),
+                creation_date: chrono::Utc::now(),
+                uuid: generate_uuid(),
+                uid_number,
+            },
+        );
+        Ok(())
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        validate_update_user(&request)?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(avatar) = request.avatar {
+            if !state.users.contains_key(&request.user_id) {
+                return Err(not_found());
+            }
+            state.avatars.insert(request.user_id.clone(), avatar);
+        }
+        let user = state
+            .users
+            .get_mut(&request.user_id)
+            .ok_or_else(not_found)?;
+        if let Some(email) = request.email {
+            user.email = email;
+        }
+        if let Some(display_name) = request.display_name {
+            user.display_name = display_name;
+        }
+        if let Some(first_name) = request.first_name {
+            user.first_name = first_name;
+        }
+        if let Some(last_name) = request.last_name {
+            user.last_name = last_name;
+        }
+        Ok(())
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let name = state
+            .groups
+            .get_mut(&request.group_id)
+            .ok_or_else(not_found)?;
+        if let Some(display_name) = request.display_name {
+            *name = display_name;
+        }
+        if let Some(description) = request.description {
+            let description = description.trim();
+            state
+                .group_metadata
+                .entry(request.group_id)
+                .or_default()
+                .description = Some(description.to_string()).filter(|d| !d.is_empty());
+        }
+        Ok(())
+    }
+
+    /// There is no recycle bin: the users are deleted for good.
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.users.remove(user_id);
+        state.passwords.remove(user_id);
+        state.password_files.remove(user_id);
+        state.avatars.remove(user_id);
+        state.memberships.retain(|(u, _)| u != user_id);
+        state.hosts.retain(|(u, _)| u != user_id);
+        state.attributes.retain(|(u, _, _)| u != user_id);
+        state.locks.remove(user_id);
+        state.password_changes.remove(user_id);
+        Ok(())
+    }
+
+    async fn restore_user(&self, _user_id: &str) -> Result<()> {
+        Err(not_found())
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        Ok(Vec::new())
+    }
+
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(new_user_id) {
+            return Err(DomainError::InvalidInput(format!(
+                "user ID {} is already used",
+                new_user_id
+            )));
+        }
+        let mut user = state.users.remove(user_id).ok_or_else(not_found)?;
+        user.user_id = new_user_id.to_string();
+        state.users.insert(new_user_id.to_string(), user);
+        fn rename<V>(map: &mut HashMap<String, V>, user_id: &str, new_user_id: &str) {
+            if let Some(value) = map.remove(user_id) {
+                map.insert(new_user_id.to_string(), value);
+            }
+        }
+        rename(&mut state.passwords, user_id, new_user_id);
+        rename(&mut state.password_files, user_id, new_user_id);
+        rename(&mut state.avatars, user_id, new_user_id);
+        rename(&mut state.locks, user_id, new_user_id);
+        rename(&mut state.password_changes, user_id, new_user_id);
+        let rename_entry = |u: &String| {
+            if u == user_id {
+                new_user_id.to_string()
+            } else {
+                u.clone()
+            }
+        };
+        state.memberships = state
+            .memberships
+            .iter()
+            .map(|(u, g)| (rename_entry(u), *g))
+            .collect();
+        state.hosts = state
+            .hosts
+            .iter()
+            .map(|(u, h)| (rename_entry(u), h.clone()))
+            .collect();
+        state.attributes = state
+            .attributes
+            .iter()
+            .map(|(u, n, v)| (rename_entry(u), n.clone(), v.clone()))
+            .collect();
+        Ok(())
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .groups
+            .values()
+            .any(|name| name == group_name)
+        {
+            return Err(DomainError::InternalError(format!(
+                "Group `{}` already exists",
+                group_name
+            )));
+        }
+        Ok(self.insert_group(group_name))
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.groups.remove(&group_id);
+        state.memberships.retain(|(_, g)| *g != group_id);
+        state
+            .group_memberships
+            .retain(|(member, parent)| *member != group_id && *parent != group_id);
+        state.group_mails.remove(&group_id);
+        state.group_metadata.remove(&group_id);
+        Ok(())
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(user_id) || !state.groups.contains_key(&group_id) {
+            return Err(not_found());
+        }
+        state.memberships.insert((user_id.to_string(), group_id));
+        Ok(())
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .memberships
+            .remove(&(user_id.to_string(), group_id));
+        Ok(())
+    }
+
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.groups.contains_key(&member_group_id)
+            || !state.groups.contains_key(&parent_group_id)
+        {
+            return Err(not_found());
+        }
+        if state
+            .group_nesting()
+            .would_create_cycle(member_group_id, parent_group_id)
+        {
+            return Err(DomainError::InvalidInput(format!(
+                "group {} is already a parent of group {}",
+                member_group_id.0, parent_group_id.0
+            )));
+        }
+        state
+            .group_memberships
+            .insert((member_group_id, parent_group_id));
+        Ok(())
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .group_memberships
+            .remove(&(member_group_id, parent_group_id));
+        Ok(())
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .group_nesting()
+            .with_ancestors(
+                state
+                    .memberships
+                    .iter()
+                    .filter(|(u, _)| u == user)
+                    .map(|(_, g)| *g),
+            )
+            .into_iter()
+            .map(|g| GroupIdAndName(g, state.groups[&g].clone()))
+            .collect())
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .hosts
+            .iter()
+            .filter(|(u, _)| u == user_id)
+            .map(|(_, h)| h.clone())
+            .collect())
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(user_id) {
+            return Err(not_found());
+        }
+        state.hosts.retain(|(u, _)| u != user_id);
+        state
+            .hosts
+            .extend(hosts.into_iter().map(|h| (user_id.to_string(), h)));
+        Ok(())
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.state
+            .lock()
+            .unwrap()
+            .group_metadata
+            .get(&group_id)
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .group_mails
+            .get(&group_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.groups.contains_key(&group_id) {
+            return Err(not_found());
+        }
+        state.group_mails.insert(group_id, mail.normalize());
+        Ok(())
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        let mut attributes = HashMap::<_, Vec<_>>::new();
+        for (_, name, value) in self
+            .state
+            .lock()
+            .unwrap()
+            .attributes
+            .iter()
+            .filter(|(u, _, _)| u == user_id)
+        {
+            attributes
+                .entry(name.clone())
+                .or_default()
+                .push(value.clone());
+        }
+        Ok(attributes)
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(user_id) {
+            return Err(not_found());
+        }
+        state
+            .attributes
+            .retain(|(u, n, _)| u != user_id || n != name);
+        state.attributes.extend(
+            values
+                .into_iter()
+                .map(|v| (user_id.to_string(), name.to_string(), v)),
+        );
+        Ok(())
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(user_id) {
+            return Err(not_found());
+        }
+        state.locks.insert(
+            user_id.to_string(),
+            UserLock {
+                locked_at: chrono::Utc::now(),
+                reason: reason.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.state.lock().unwrap().locks.remove(user_id);
+        Ok(())
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        Ok(self.state.lock().unwrap().locks.get(user_id).cloned())
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .values()
+            .filter(|u| {
+                state.passwords.contains_key(&u.user_id)
+                    || state.password_files.contains_key(&u.user_id)
+            })
+            .map(|u| {
+                let changed_at = state
+                    .password_changes
+                    .get(&u.user_id)
+                    .copied()
+                    .unwrap_or(u.creation_date);
+                (u.user_id.clone(), changed_at)
+            })
+            .collect())
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.state.lock().unwrap().auth_failures.push(failure);
+        Ok(())
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .auth_failures
+            .iter()
+            .rev()
+            .filter(|f| user_id.is_none() || f.user_id.as_deref() == user_id)
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.state.lock().unwrap().audit_events.push(event);
+        Ok(())
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .audit_events
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl OpaqueHandler for TestBackendHandler {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        let password_file = self
+            .state
+            .lock()
+            .unwrap()
+            .password_files
+            .get(&request.username)
+            .map(|bytes| opaque::server::ServerRegistration::deserialize(bytes))
+            .transpose()
+            .map_err(|_| DomainError::InternalError("Corrupted password file".to_string()))?;
+        let mut rng = rand::rngs::OsRng;
+        let start_response = opaque::server::login::start_login(
+            &mut rng,
+            &self.server_setup,
+            password_file,
+            request.login_start_request,
+            &request.username,
+        )?;
+        // No need to encrypt the state: this is only for tests.
+        let server_data = login::ServerData {
+            username: request.username,
+            server_login: start_response.state,
+        };
+        Ok(login::ServerLoginStartResponse {
+            server_data: base64::encode(&bincode::serialize(&server_data)?),
+            credential_response: start_response.message,
+        })
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        let login::ServerData {
+            username,
+            server_login,
+        } = bincode::deserialize(&base64::decode(&request.server_data)?)?;
+        if opaque::server::login::finish_login(server_login, request.credential_finalization)
+            .is_err()
+        {
+            return Err(DomainError::AuthenticationError(username));
+        }
+        if self.state.lock().unwrap().locks.contains_key(&username) {
+            return Err(DomainError::AuthenticationError(username));
+        }
+        Ok(username)
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let start_response = opaque::server::registration::start_registration(
+            &self.server_setup,
+            request.registration_start_request,
+            &request.username,
+        )?;
+        let server_data = registration::ServerData {
+            username: request.username,
+        };
+        Ok(registration::ServerRegistrationStartResponse {
+            server_data: base64::encode(&bincode::serialize(&server_data)?),
+            registration_response: start_response.message,
+        })
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        let registration::ServerData { username } =
+            bincode::deserialize(&base64::decode(&request.server_data)?)?;
+        let password_file =
+            opaque::server::registration::get_password_file(request.registration_upload);
+        let mut state = self.state.lock().unwrap();
+        state
+            .password_changes
+            .insert(username.clone(), chrono::Utc::now());
+        state
+            .password_files
+            .insert(username, password_file.serialize());
+        Ok(())
+    }
+
+    /// No history is kept: only the current password counts, as for `bind`.
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .passwords
+            .get(username)
+            .map_or(false, |p| p == password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_filters_and_memberships() {
+        let handler = TestBackendHandler::new();
+        handler.insert_user("bob", "bob@bob.bob", Some("bob00"));
+        handler.insert_user("John", "john@john.john", None);
+        handler.insert_user("patrick", "patrick@patrick.patrick", None);
+        let group = handler.insert_group("Best Group");
+        handler.insert_membership("bob", group);
+        handler.insert_membership("patrick", group);
+
+        let user_ids = |users: Vec<User>| users.into_iter().map(|u| u.user_id).collect::<Vec<_>>();
+        assert_eq!(
+            user_ids(handler.list_users(None).await.unwrap()),
+            vec!["John", "bob", "patrick"]
+        );
+        assert_eq!(
+            user_ids(
+                handler
+                    .list_users(Some(RequestFilter::And(vec![
+                        RequestFilter::MemberOf("Best Group".to_string()),
+                        RequestFilter::Not(Box::new(RequestFilter::Equality(
+                            "user_id".to_string(),
+                            "bob".to_string()
+                        ))),
+                    ])))
+                    .await
+                    .unwrap()
+            ),
+            vec!["patrick"]
+        );
+        handler.delete_user("patrick").await.unwrap();
+        assert_eq!(
+            handler.list_groups(None).await.unwrap(),
+            vec![Group {
+                id: group,
+                display_name: "Best Group".to_string(),
+                users: vec!["bob".to_string()],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let handler = TestBackendHandler::new();
+        handler.insert_user("bob", "bob@bob.bob", Some("bob00"));
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap();
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "wrong".to_string(),
+            })
+            .await
+            .unwrap_err();
+    }
+}
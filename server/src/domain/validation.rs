@@ -0,0 +1,244 @@
+//! Validation of the user fields before they are stored. All the invalid fields of a request are
+//! reported at once, so that the clients can highlight each of them.
+
+use super::{
+    error::{DomainError, Result},
+    handler::{CreateUserRequest, UpdateUserRequest},
+    identifiers::{normalize_name, normalize_user_id, UserIdPolicy},
+};
+
+/// The longest user ID, in characters: they are part of the DNs.
+pub const MAX_USER_ID_LENGTH: usize = 64;
+/// The longest email, in characters, as in RFC 5321.
+pub const MAX_EMAIL_LENGTH: usize = 254;
+/// The longest display name, first name or last name, in characters: the size of their columns.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// A field of a request that is not valid, and why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidField {
+    /// The name of the field in the request, e.g. `user_id`.
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl std::fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Collects the invalid fields of a request.
+#[derive(Default)]
+struct Validator(Vec<InvalidField>);
+
+impl Validator {
+    fn check(&mut self, field: &'static str, result: std::result::Result<(), String>) {
+        if let Err(message) = result {
+            self.0.push(InvalidField { field, message });
+        }
+    }
+
+    fn check_optional_name(&mut self, field: &'static str, name: &Option<String>) {
+        if let Some(name) = name {
+            self.check(field, check_name(name));
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(DomainError::InvalidFields(self.0))
+        }
+    }
+}
+
+fn check_user_id(user_id: &str, policy: UserIdPolicy) -> std::result::Result<(), String> {
+    if user_id.chars().count() > MAX_USER_ID_LENGTH {
+        return Err(format!(
+            "at most {} characters are allowed",
+            MAX_USER_ID_LENGTH
+        ));
+    }
+    match normalize_user_id(user_id, policy) {
+        Ok(_) => Ok(()),
+        Err(DomainError::InvalidInput(message)) => Err(message),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn is_valid_domain_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.chars().count() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+}
+
+/// A loose version of the RFC 5322 syntax: no quoted local parts, comments or IP literals. The
+/// empty email is accepted, for the users without one.
+fn check_email(email: &str) -> std::result::Result<(), String> {
+    if email.is_empty() {
+        return Ok(());
+    }
+    if email.chars().count() > MAX_EMAIL_LENGTH {
+        return Err(format!(
+            "at most {} characters are allowed",
+            MAX_EMAIL_LENGTH
+        ));
+    }
+    let (local_part, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return Err("an email needs an @".to_string()),
+    };
+    let is_valid_local_char = |c: char| c.is_alphanumeric() || "!#$%&'*+/=?^_`{|}~.-".contains(c);
+    if local_part.is_empty()
+        || local_part.starts_with('.')
+        || local_part.ends_with('.')
+        || local_part.contains("..")
+        || !local_part.chars().all(is_valid_local_char)
+    {
+        return Err(format!("invalid local part {:?}", local_part));
+    }
+    if !domain.split('.').all(is_valid_domain_label) {
+        return Err(format!("invalid domain {:?}", domain));
+    }
+    Ok(())
+}
+
+fn check_name(name: &str) -> std::result::Result<(), String> {
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "at most {} characters are allowed",
+            MAX_NAME_LENGTH
+        ));
+    }
+    match normalize_name(name) {
+        Ok(_) => Ok(()),
+        Err(DomainError::InvalidInput(message)) => Err(message),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Fails with [`DomainError::InvalidFields`] if the user ID isn't allowed by the policy or is too
+/// long.
+pub fn validate_user_id(user_id: &str, policy: UserIdPolicy) -> Result<()> {
+    let mut validator = Validator::default();
+    validator.check("user_id", check_user_id(user_id, policy));
+    validator.finish()
+}
+
+/// Fails with [`DomainError::InvalidFields`] listing all the invalid fields of the request.
+pub fn validate_create_user(request: &CreateUserRequest, policy: UserIdPolicy) -> Result<()> {
+    let mut validator = Validator::default();
+    validator.check("user_id", check_user_id(&request.user_id, policy));
+    validator.check("email", check_email(&request.email));
+    validator.check_optional_name("display_name", &request.display_name);
+    validator.check_optional_name("first_name", &request.first_name);
+    validator.check_optional_name("last_name", &request.last_name);
+    validator.finish()
+}
+
+/// Fails with [`DomainError::InvalidFields`] listing all the invalid fields of the request.
+pub fn validate_update_user(request: &UpdateUserRequest) -> Result<()> {
+    let mut validator = Validator::default();
+    if let Some(email) = &request.email {
+        validator.check("email", check_email(email));
+    }
+    validator.check_optional_name("display_name", &request.display_name);
+    validator.check_optional_name("first_name", &request.first_name);
+    validator.check_optional_name("last_name", &request.last_name);
+    validator.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_fields(result: Result<()>) -> Vec<&'static str> {
+        match result {
+            Err(DomainError::InvalidFields(fields)) => fields.iter().map(|f| f.field).collect(),
+            r => panic!("Expected invalid fields, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_check_email() {
+        for email in &[
+            "",
+            "bob@example.com",
+            "bob.o'neil+lldap@mail.example.com",
+            "jos\u{e9}@exampl\u{e9}.fr",
+            "admin@localhost",
+        ] {
+            assert_eq!(check_email(email), Ok(()), "{}", email);
+        }
+        for email in &[
+            "bob",
+            "@example.com",
+            "bob@",
+            "bob@example..com",
+            "bob@-example.com",
+            ".bob@example.com",
+            "bob..o@example.com",
+            "bob o@example.com",
+            "bob@exam ple.com",
+            "bob@example.com,jim@example.com",
+        ] {
+            assert!(check_email(email).is_err(), "{}", email);
+        }
+        assert!(check_email(&format!("{}@example.com", "b".repeat(250))).is_err());
+    }
+
+    #[test]
+    fn test_validate_create_user() {
+        let request = CreateUserRequest {
+            user_id: "bob".to_string(),
+            email: "bob@example.com".to_string(),
+            display_name: Some("Bob".to_string()),
+            ..Default::default()
+        };
+        validate_create_user(&request, UserIdPolicy::Ascii).unwrap();
+        assert_eq!(
+            invalid_fields(validate_create_user(
+                &CreateUserRequest {
+                    user_id: "bob,ou=admins".to_string(),
+                    email: "bob".to_string(),
+                    last_name: Some("x".repeat(MAX_NAME_LENGTH + 1)),
+                    ..request.clone()
+                },
+                UserIdPolicy::Ascii
+            )),
+            vec!["user_id", "email", "last_name"]
+        );
+        assert_eq!(
+            invalid_fields(validate_create_user(
+                &CreateUserRequest {
+                    user_id: "b".repeat(MAX_USER_ID_LENGTH + 1),
+                    ..request
+                },
+                UserIdPolicy::Ascii
+            )),
+            vec!["user_id"]
+        );
+    }
+
+    #[test]
+    fn test_validate_update_user() {
+        validate_update_user(&UpdateUserRequest {
+            user_id: "bob".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            invalid_fields(validate_update_user(&UpdateUserRequest {
+                user_id: "bob".to_string(),
+                email: Some("bob@".to_string()),
+                display_name: Some("Bob\u{0}".to_string()),
+                ..Default::default()
+            })),
+            vec!["email", "display_name"]
+        );
+    }
+}
@@ -0,0 +1,790 @@
+//! The permissions of the users, given by the groups they belong to, and a backend handler
+//! enforcing them.
+
+use crate::domain::{error::*, handler::*, identifiers::fold_case, opaque_handler::*};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{HashMap, HashSet};
+
+/// The members can read and modify everything.
+pub const ADMIN_GROUP: &str = "lldap_admin";
+/// The members can read everything, and change the passwords of the users that aren't admins.
+pub const PASSWORD_MANAGER_GROUP: &str = "lldap_password_manager";
+/// The members can read everything, e.g. for the bind account of an application.
+pub const READONLY_GROUP: &str = "lldap_strict_readonly";
+
+/// What a user is allowed to do, from the least to the most. Every user can read and update their
+/// own details, and change their own password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Regular,
+    Readonly,
+    PasswordManager,
+    /// Only given by the API tokens with the
+    /// [`UserManagement`](super::tcp_backend_handler::ApiTokenScope::UserManagement) scope: the
+    /// password manager permissions, and the management of the users that aren't admins.
+    UserManager,
+    Admin,
+}
+
+impl Permission {
+    /// The highest permission given by these groups.
+    pub fn from_groups<'a>(groups: impl IntoIterator<Item = &'a str>) -> Self {
+        groups
+            .into_iter()
+            .map(|group| match group {
+                ADMIN_GROUP => Permission::Admin,
+                PASSWORD_MANAGER_GROUP => Permission::PasswordManager,
+                READONLY_GROUP => Permission::Readonly,
+                _ => Permission::Regular,
+            })
+            .max()
+            .unwrap_or(Permission::Regular)
+    }
+}
+
+/// Who is making the request, and what they're allowed to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationResults {
+    pub user: String,
+    pub permission: Permission,
+    /// The admin acting as the user, with a token of an impersonation.
+    pub impersonator: Option<String>,
+}
+
+impl ValidationResults {
+    #[cfg(test)]
+    pub fn admin() -> Self {
+        Self {
+            user: "admin".to_string(),
+            permission: Permission::Admin,
+            impersonator: None,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.permission == Permission::Admin
+    }
+
+    /// Whether the user can read all the users and groups.
+    pub fn can_read_all(&self) -> bool {
+        self.permission >= Permission::Readonly
+    }
+
+    /// Whether the user ID is the one of the user, whatever its case.
+    pub fn is_user(&self, user: &str) -> bool {
+        fold_case(&self.user) == fold_case(user)
+    }
+
+    pub fn can_read(&self, user: &str) -> bool {
+        self.can_read_all() || self.is_user(user)
+    }
+
+    pub fn can_write(&self, user: &str) -> bool {
+        self.is_admin() || self.is_user(user)
+    }
+
+    /// Whether the user can create and modify the users, except the admins and the membership of
+    /// the admin group which are checked by the [`AccessControlledBackendHandler`].
+    pub fn can_manage_users(&self) -> bool {
+        self.permission >= Permission::UserManager
+    }
+}
+
+/// Whether the user is a member of the admin group.
+async fn is_admin_user<Backend: BackendHandler>(backend: &Backend, user_id: &str) -> Result<bool> {
+    Ok(backend
+        .get_user_groups(user_id)
+        .await?
+        .iter()
+        .any(|group| group.1 == ADMIN_GROUP))
+}
+
+/// Whether these permissions allow changing the password of the user: the admins can change all
+/// of them, the password managers only the ones of the users that aren't admins.
+pub async fn can_change_password<Backend: BackendHandler>(
+    backend: &Backend,
+    permissions: &ValidationResults,
+    user_id: &str,
+) -> Result<bool> {
+    Ok(permissions.can_write(user_id)
+        || (permissions.permission >= Permission::PasswordManager
+            && !is_admin_user(backend, user_id).await?))
+}
+
+/// A backend handler forwarding to `Backend` the operations that the user is allowed to do, and
+/// failing the others with [`DomainError::PermissionDenied`].
+#[derive(Clone)]
+pub struct AccessControlledBackendHandler<Backend> {
+    inner: Backend,
+    permissions: ValidationResults,
+}
+
+impl<Backend> AccessControlledBackendHandler<Backend> {
+    pub fn new(inner: Backend, permissions: ValidationResults) -> Self {
+        Self { inner, permissions }
+    }
+
+    fn check(&self, allowed: bool, operation: &str) -> Result<()> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(DomainError::PermissionDenied(format!(
+                "{} by {}",
+                operation, self.permissions.user
+            )))
+        }
+    }
+
+    fn check_admin(&self, operation: &str) -> Result<()> {
+        self.check(self.permissions.is_admin(), operation)
+    }
+
+    fn check_read_all(&self, operation: &str) -> Result<()> {
+        self.check(self.permissions.can_read_all(), operation)
+    }
+
+    fn check_read(&self, user_id: &str, operation: &str) -> Result<()> {
+        self.check(self.permissions.can_read(user_id), operation)
+    }
+
+    fn check_manage_users(&self, operation: &str) -> Result<()> {
+        self.check(self.permissions.can_manage_users(), operation)
+    }
+}
+
+impl<Backend: BackendHandler> AccessControlledBackendHandler<Backend> {
+    /// The user managers can't modify the admins, to not give themselves more permissions.
+    async fn check_manage_user(&self, user_id: &str, operation: &str) -> Result<()> {
+        let allowed = self.permissions.is_admin()
+            || (self.permissions.can_manage_users()
+                && !is_admin_user(&self.inner, user_id).await?);
+        self.check(allowed, operation)
+    }
+
+    /// Nor the members of the admin group, or of the groups nested in it, which are admins too.
+    async fn check_manage_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+        operation: &str,
+    ) -> Result<()> {
+        if self.permissions.is_admin() {
+            return Ok(());
+        }
+        self.check_manage_users(operation)?;
+        let makes_admins = self.inner.get_group_details(group_id).await?.1 == ADMIN_GROUP
+            || self
+                .inner
+                .get_parent_groups(group_id)
+                .await?
+                .iter()
+                .any(|group| group.1 == ADMIN_GROUP);
+        self.check(!makes_admins, operation)?;
+        for user_id in user_ids {
+            self.check_manage_user(user_id, operation).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for AccessControlledBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        self.check_read_all("list_users")?;
+        self.inner.list_users(filters).await
+    }
+
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        match self.check_read_all("list_users") {
+            Ok(()) => self.inner.list_users_stream(filters, order),
+            Err(e) => stream::once(async { Err(e) }).boxed(),
+        }
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.check_read_all("list_users")?;
+        self.inner
+            .list_users_page(filters, order, direction, offset, limit)
+            .await
+    }
+
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        self.check_read_all("list_users")?;
+        self.inner.count_users(filters).await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.check_read_all("list_groups")?;
+        self.inner.list_groups(filters).await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        self.check_read_all("list_groups")?;
+        self.inner.list_groups_page(filters, offset, limit).await
+    }
+
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        self.check_read_all("list_groups")?;
+        self.inner.list_groups_with_member_count().await
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        self.check_read(user_id, "get_user_details")?;
+        self.inner.get_user_details(user_id).await
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        self.check_read(user_id, "get_user_avatar")?;
+        self.inner.get_user_avatar(user_id).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.check_read_all("get_user_by_email")?;
+        self.inner.get_user_by_email(email).await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.check_read_all("get_group_details")?;
+        self.inner.get_group_details(group_id).await
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        self.check_read_all("get_parent_groups")?;
+        self.inner.get_parent_groups(group_id).await
+    }
+
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        self.check_read_all("get_group_members")?;
+        self.inner.get_group_members(group_id, offset, limit).await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.check_manage_users("create_user")?;
+        self.inner.create_user(request).await
+    }
+
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        self.check_manage_users("create_user")?;
+        self.inner.bulk_create_users(requests).await
+    }
+
+    /// The users can update their own details, except their email: it's where their password
+    /// resets are sent.
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        if !self.permissions.is_user(&request.user_id) {
+            self.check_manage_user(&request.user_id, "update_user")
+                .await?;
+        } else if request.email.is_some() {
+            self.check_manage_user(&request.user_id, "update_own_email")
+                .await?;
+        }
+        self.inner.update_user(request).await
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.check_admin("update_group")?;
+        self.inner.update_group(request).await
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.check_manage_user(user_id, "delete_user").await?;
+        self.inner.delete_user(user_id).await
+    }
+
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        self.check_admin("restore_user")?;
+        self.inner.restore_user(user_id).await
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        self.check_admin("list_deleted_users")?;
+        self.inner.list_deleted_users().await
+    }
+
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        self.check_manage_user(user_id, "rename_user").await?;
+        self.inner.rename_user(user_id, new_user_id).await
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.check_admin("create_group")?;
+        self.inner.create_group(group_name).await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.check_admin("delete_group")?;
+        self.inner.delete_group(group_id).await
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.check_manage_members(group_id, &[user_id.to_string()], "add_user_to_group")
+            .await?;
+        self.inner.add_user_to_group(user_id, group_id).await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.check_manage_members(group_id, &[user_id.to_string()], "remove_user_from_group")
+            .await?;
+        self.inner.remove_user_from_group(user_id, group_id).await
+    }
+
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        self.check_manage_members(group_id, user_ids, "add_user_to_group")
+            .await?;
+        self.inner.add_users_to_group(group_id, user_ids).await
+    }
+
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        self.check_manage_members(group_id, user_ids, "remove_user_from_group")
+            .await?;
+        self.inner.remove_users_from_group(group_id, user_ids).await
+    }
+
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        self.check_manage_members(group_id, user_ids, "set_group_members")
+            .await?;
+        if !self.permissions.is_admin() {
+            // The members being removed, too.
+            let current = self
+                .inner
+                .list_users(Some(RequestFilter::MemberOfId(group_id)))
+                .await?
+                .into_iter()
+                .map(|user| user.user_id)
+                .collect::<Vec<_>>();
+            for user_id in MembershipChanges::new(&current, user_ids).removed {
+                self.check_manage_user(&user_id, "set_group_members")
+                    .await?;
+            }
+        }
+        self.inner.set_group_members(group_id, user_ids).await
+    }
+
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.check_admin("add_group_to_group")?;
+        self.inner
+            .add_group_to_group(member_group_id, parent_group_id)
+            .await
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.check_admin("remove_group_from_group")?;
+        self.inner
+            .remove_group_from_group(member_group_id, parent_group_id)
+            .await
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        self.check_read(user, "get_user_groups")?;
+        self.inner.get_user_groups(user).await
+    }
+
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        self.check(
+            user_ids
+                .iter()
+                .all(|user_id| self.permissions.can_read(user_id)),
+            "get_user_groups",
+        )?;
+        self.inner.get_users_groups(user_ids).await
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        self.check_read(user_id, "get_user_hosts")?;
+        self.inner.get_user_hosts(user_id).await
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        self.check_manage_user(user_id, "set_user_hosts").await?;
+        self.inner.set_user_hosts(user_id, hosts).await
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.check_read_all("get_group_metadata")?;
+        self.inner.get_group_metadata(group_id).await
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        self.check_read_all("get_group_mail")?;
+        self.inner.get_group_mail(group_id).await
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        self.check_admin("set_group_mail")?;
+        self.inner.set_group_mail(group_id, mail).await
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        self.check_read(user_id, "get_user_attributes")?;
+        self.inner.get_user_attributes(user_id).await
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.check_manage_user(user_id, "set_user_attribute")
+            .await?;
+        self.inner.set_user_attribute(user_id, name, values).await
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        self.check_manage_user(user_id, "lock_user").await?;
+        self.inner.lock_user(user_id, reason).await
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.check_manage_user(user_id, "unlock_user").await?;
+        self.inner.unlock_user(user_id).await
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        self.check_read(user_id, "get_user_lock")?;
+        self.inner.get_user_lock(user_id).await
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.check_read_all("list_password_changes")?;
+        self.inner.list_password_changes().await
+    }
+
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        self.check_read(user_id, "get_password_change")?;
+        self.inner.get_password_change(user_id).await
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.check_admin("record_auth_failure")?;
+        self.inner.record_auth_failure(failure).await
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        self.check_admin("list_auth_failures")?;
+        self.inner.list_auth_failures(user_id, offset, limit).await
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.check_admin("record_audit_event")?;
+        self.inner.record_audit_event(event).await
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.check_admin("list_audit_events")?;
+        self.inner.list_audit_events(offset, limit).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + OpaqueHandler + Sync> OpaqueHandler
+    for AccessControlledBackendHandler<Backend>
+{
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.inner.login_start(request).await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        self.inner.login_finish(request).await
+    }
+
+    /// Only checked here: the registration can't be finished without being started. The
+    /// impersonating admins can't change the password of the user.
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let allowed = self.permissions.impersonator.is_none()
+            && can_change_password(&self.inner, &self.permissions, &request.username).await?;
+        self.check(allowed, "change_password")?;
+        self.inner.registration_start(request).await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        self.inner.registration_finish(request).await
+    }
+
+    /// Checked like the registration: the answer tells whether the password is right.
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        let allowed = can_change_password(&self.inner, &self.permissions, username).await?;
+        self.check(allowed, "change_password")?;
+        self.inner.is_password_reused(username, password).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::test_backend_handler::TestBackendHandler;
+    use lldap_auth::opaque;
+
+    fn permissions(user: &str, permission: Permission) -> ValidationResults {
+        ValidationResults {
+            user: user.to_string(),
+            permission,
+            impersonator: None,
+        }
+    }
+
+    async fn start_registration<Handler: OpaqueHandler>(
+        handler: &Handler,
+        user: &str,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let mut rng = rand::rngs::OsRng;
+        let client_registration_start =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        handler
+            .registration_start(registration::ClientRegistrationStartRequest {
+                username: user.to_string(),
+                registration_start_request: client_registration_start.message,
+            })
+            .await
+    }
+
+    #[test]
+    fn test_permission_from_groups() {
+        assert_eq!(Permission::from_groups(vec![]), Permission::Regular);
+        assert_eq!(
+            Permission::from_groups(vec!["family", READONLY_GROUP]),
+            Permission::Readonly
+        );
+        assert_eq!(
+            Permission::from_groups(vec![READONLY_GROUP, ADMIN_GROUP, PASSWORD_MANAGER_GROUP]),
+            Permission::Admin
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readonly_user() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let handler =
+            AccessControlledBackendHandler::new(backend, permissions("app", Permission::Readonly));
+        assert_eq!(handler.list_users(None).await.unwrap().len(), 1);
+        assert!(handler.get_user_details("bob").await.is_ok());
+        assert!(matches!(
+            handler.delete_user("bob").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            handler.create_group("family").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            start_registration(&handler, "bob").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_impersonated_user() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let handler = AccessControlledBackendHandler::new(
+            backend,
+            ValidationResults {
+                impersonator: Some("admin".to_string()),
+                ..permissions("bob", Permission::Regular)
+            },
+        );
+        assert!(handler.get_user_details("bob").await.is_ok());
+        assert!(matches!(
+            start_registration(&handler, "bob").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_regular_user() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("john", "john@john.john", None);
+        let handler =
+            AccessControlledBackendHandler::new(backend, permissions("bob", Permission::Regular));
+        assert!(handler.get_user_details("bob").await.is_ok());
+        // Whatever the case of the user ID.
+        assert!(handler.permissions.can_read("BOB"));
+        assert!(handler.permissions.can_write("Bob"));
+        assert!(!handler.permissions.can_write("john"));
+        assert!(handler
+            .update_user(UpdateUserRequest {
+                user_id: "bob".to_string(),
+                display_name: Some("Bob".to_string()),
+                ..Default::default()
+            })
+            .await
+            .is_ok());
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: "bob".to_string(),
+                    email: Some("bob@example.com".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(start_registration(&handler, "bob").await.is_ok());
+        assert!(handler.list_users(None).await.is_err());
+        assert!(handler.get_user_details("john").await.is_err());
+        assert!(handler
+            .update_user(UpdateUserRequest {
+                user_id: "john".to_string(),
+                ..Default::default()
+            })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_password_manager() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("admin", "admin@admin.admin", None);
+        let admin_group = backend.insert_group(ADMIN_GROUP);
+        backend.insert_membership("admin", admin_group);
+        let handler = AccessControlledBackendHandler::new(
+            backend,
+            permissions("manager", Permission::PasswordManager),
+        );
+        assert!(start_registration(&handler, "bob").await.is_ok());
+        assert!(matches!(
+            start_registration(&handler, "admin").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(handler.delete_user("bob").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_manager() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("admin", "admin@admin.admin", None);
+        let admin_group = backend.insert_group(ADMIN_GROUP);
+        backend.insert_membership("admin", admin_group);
+        let family = backend.insert_group("family");
+        let handler = AccessControlledBackendHandler::new(
+            backend,
+            permissions("terraform", Permission::UserManager),
+        );
+        assert!(handler
+            .create_user(CreateUserRequest {
+                user_id: "john".to_string(),
+                email: "john@john.john".to_string(),
+                ..Default::default()
+            })
+            .await
+            .is_ok());
+        assert!(handler.add_user_to_group("john", family).await.is_ok());
+        assert!(handler.lock_user("bob", "left").await.is_ok());
+        assert!(start_registration(&handler, "bob").await.is_ok());
+        assert!(matches!(
+            handler.add_user_to_group("john", admin_group).await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            handler.delete_user("admin").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            handler.create_group("friends").await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_manager_nested_admin_group() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("admin", "admin@admin.admin", None);
+        let admin_group = backend.insert_group(ADMIN_GROUP);
+        let ops = backend.insert_group("ops");
+        let on_call = backend.insert_group("on_call");
+        backend.add_group_to_group(ops, admin_group).await.unwrap();
+        backend.add_group_to_group(on_call, ops).await.unwrap();
+        backend.insert_membership("admin", admin_group);
+        let family = backend.insert_group("family");
+        backend.insert_membership("admin", family);
+        let handler = AccessControlledBackendHandler::new(
+            backend.clone(),
+            permissions("terraform", Permission::UserManager),
+        );
+        for group in &[ops, on_call] {
+            assert!(matches!(
+                handler.add_user_to_group("bob", *group).await,
+                Err(DomainError::PermissionDenied(_))
+            ));
+            assert!(matches!(
+                handler
+                    .set_group_members(*group, &["bob".to_string()])
+                    .await,
+                Err(DomainError::PermissionDenied(_))
+            ));
+        }
+        assert!(backend.get_user_groups("bob").await.unwrap().is_empty());
+        // Nor drop an admin from a group.
+        assert!(matches!(
+            handler
+                .set_group_members(family, &["bob".to_string()])
+                .await,
+            Err(DomainError::PermissionDenied(_))
+        ));
+        assert!(handler
+            .set_group_members(family, &["admin".to_string(), "bob".to_string()])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! The `user`, `group` and `password` commands, for the basic administration from a shell. They
+//! go through the GraphQL API of a running server when given its URL and an admin token, or
+//! directly to the database otherwise, e.g. while the server is stopped.
+
+use crate::domain::{
+    handler::{BackendHandler, CreateUserRequest, GroupRequestFilter},
+    opaque_handler::OpaqueHandler,
+    sql_opaque_handler::register_password,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use lldap_auth::{opaque, registration};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+/// What the command does.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    AddUser {
+        request: CreateUserRequest,
+        password: Option<String>,
+    },
+    ListUsers,
+    DeleteUser(String),
+    AddGroup(String),
+    AddMember {
+        group: String,
+        user_id: String,
+    },
+    SetPassword {
+        user_id: String,
+        password: String,
+    },
+}
+
+/// A user, as listed by `user list`.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSummary {
+    #[serde(rename = "id")]
+    pub user_id: String,
+    pub email: String,
+    pub display_name: String,
+}
+
+/// The operations of the commands, on the server or on the database.
+#[async_trait(?Send)]
+pub trait Directory {
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+    /// The users, sorted by user ID.
+    async fn list_users(&self) -> Result<Vec<UserSummary>>;
+    async fn delete_user(&self, user_id: &str) -> Result<()>;
+    async fn create_group(&self, name: &str) -> Result<()>;
+    async fn add_member(&self, group: &str, user_id: &str) -> Result<()>;
+    async fn set_password(&self, user_id: &str, password: &str) -> Result<()>;
+}
+
+/// Runs the action, and returns what to print.
+pub async fn execute<D: Directory>(directory: &D, action: Action) -> Result<String> {
+    Ok(match action {
+        Action::AddUser { request, password } => {
+            let user_id = request.user_id.clone();
+            directory
+                .create_user(request)
+                .await
+                .with_context(|| format!("while creating user {}", user_id))?;
+            if let Some(password) = password {
+                directory
+                    .set_password(&user_id, &password)
+                    .await
+                    .with_context(|| format!("while setting the password of {}", user_id))?;
+            }
+            format!("Created user {}\n", user_id)
+        }
+        Action::ListUsers => {
+            let mut output = String::new();
+            for user in directory.list_users().await? {
+                writeln!(
+                    output,
+                    "{}\t{}\t{}",
+                    user.user_id, user.email, user.display_name
+                )?;
+            }
+            output
+        }
+        Action::DeleteUser(user_id) => {
+            directory
+                .delete_user(&user_id)
+                .await
+                .with_context(|| format!("while deleting user {}", user_id))?;
+            format!("Deleted user {}\n", user_id)
+        }
+        Action::AddGroup(name) => {
+            directory
+                .create_group(&name)
+                .await
+                .with_context(|| format!("while creating group {}", name))?;
+            format!("Created group {}\n", name)
+        }
+        Action::AddMember { group, user_id } => {
+            directory
+                .add_member(&group, &user_id)
+                .await
+                .with_context(|| format!("while adding {} to {}", user_id, group))?;
+            format!("Added {} to {}\n", user_id, group)
+        }
+        Action::SetPassword { user_id, password } => {
+            directory
+                .set_password(&user_id, &password)
+                .await
+                .with_context(|| format!("while setting the password of {}", user_id))?;
+            format!("Changed the password of {}\n", user_id)
+        }
+    })
+}
+
+/// The directory of the database, for when the server isn't running.
+pub struct DatabaseDirectory<'a, Handler> {
+    handler: &'a Handler,
+}
+
+impl<'a, Handler> DatabaseDirectory<'a, Handler> {
+    pub fn new(handler: &'a Handler) -> Self {
+        Self { handler }
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a, Handler: BackendHandler + OpaqueHandler> Directory for DatabaseDirectory<'a, Handler> {
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        Ok(self.handler.create_user(request).await?)
+    }
+
+    async fn list_users(&self) -> Result<Vec<UserSummary>> {
+        let mut users = self
+            .handler
+            .list_users(None)
+            .await?
+            .into_iter()
+            .map(|user| UserSummary {
+                user_id: user.user_id,
+                email: user.email,
+                display_name: user.display_name,
+            })
+            .collect::<Vec<_>>();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        Ok(users)
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        Ok(self.handler.delete_user(user_id).await?)
+    }
+
+    async fn create_group(&self, name: &str) -> Result<()> {
+        self.handler.create_group(name).await?;
+        Ok(())
+    }
+
+    async fn add_member(&self, group: &str, user_id: &str) -> Result<()> {
+        let group_id = self
+            .handler
+            .list_groups(Some(GroupRequestFilter::DisplayName(group.to_string())))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No such group: {}", group))?
+            .id;
+        Ok(self.handler.add_user_to_group(user_id, group_id).await?)
+    }
+
+    async fn set_password(&self, user_id: &str, password: &str) -> Result<()> {
+        Ok(register_password(self.handler, user_id, password).await?)
+    }
+}
+
+/// The directory of a running server, through its GraphQL API and its OPAQUE endpoints.
+pub struct ServerDirectory<'a> {
+    client: reqwest::Client,
+    base_url: &'a str,
+    token: &'a str,
+}
+
+impl<'a> ServerDirectory<'a> {
+    pub fn new(base_url: &'a str, token: &'a str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/'),
+            token,
+        }
+    }
+
+    /// Runs the GraphQL query, and returns its data, or its errors as one.
+    async fn graphql(&self, query: &str, variables: Value) -> Result<Value> {
+        let response: Value = self
+            .client
+            .post(format!("{}/api/graphql", self.base_url))
+            .bearer_auth(self.token)
+            .json(&json!({ "query": query, "variables": variables }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(errors) = response["errors"].as_array().filter(|e| !e.is_empty()) {
+            bail!(
+                "{}",
+                errors
+                    .iter()
+                    .map(|e| e["message"].as_str().unwrap_or("Unknown error"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(response["data"].clone())
+    }
+}
+
+#[async_trait(?Send)]
+impl<'a> Directory for ServerDirectory<'a> {
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.graphql(
+            "mutation($user: CreateUserInput!) { createUser(user: $user) { id } }",
+            json!({ "user": {
+                "id": request.user_id,
+                "email": request.email,
+                "displayName": request.display_name,
+                "firstName": request.first_name,
+                "lastName": request.last_name,
+            }}),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn list_users(&self) -> Result<Vec<UserSummary>> {
+        let data = self
+            .graphql("{ users { id email displayName } }", json!({}))
+            .await?;
+        Ok(serde_json::from_value(data["users"].clone())?)
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.graphql(
+            "mutation($userId: String!) { deleteUser(userId: $userId) { ok } }",
+            json!({ "userId": user_id }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn create_group(&self, name: &str) -> Result<()> {
+        self.graphql(
+            "mutation($name: String!) { createGroup(name: $name) { id } }",
+            json!({ "name": name }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_member(&self, group: &str, user_id: &str) -> Result<()> {
+        let data = self
+            .graphql("{ groups { id displayName } }", json!({}))
+            .await?;
+        let group_id = data["groups"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|g| {
+                g["displayName"]
+                    .as_str()
+                    .map_or(false, |name| name.eq_ignore_ascii_case(group))
+            })
+            .and_then(|g| g["id"].as_i64())
+            .ok_or_else(|| anyhow!("No such group: {}", group))?;
+        self.graphql(
+            "mutation($userId: String!, $groupId: Int!) { \
+                addUserToGroup(userId: $userId, groupId: $groupId) { ok } }",
+            json!({ "userId": user_id, "groupId": group_id }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Registers the password with OPAQUE, like the web UI: the server never sees it.
+    async fn set_password(&self, user_id: &str, password: &str) -> Result<()> {
+        let mut rng = rand::rngs::OsRng;
+        let registration_start =
+            opaque::client::registration::start_registration(password, &mut rng)?;
+        let start_response: registration::ServerRegistrationStartResponse = self
+            .client
+            .post(format!("{}/auth/opaque/register/start", self.base_url))
+            .bearer_auth(self.token)
+            .json(&registration::ClientRegistrationStartRequest {
+                username: user_id.to_string(),
+                registration_start_request: registration_start.message,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let registration_finish = opaque::client::registration::finish_registration(
+            registration_start.state,
+            start_response.registration_response,
+            &mut rng,
+        )?;
+        self.client
+            .post(format!("{}/auth/opaque/register/finish", self.base_url))
+            .json(&registration::ClientRegistrationFinishRequest {
+                server_data: start_response.server_data,
+                registration_upload: registration_finish.message,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::test_backend_handler::TestBackendHandler;
+
+    #[tokio::test]
+    async fn test_database_directory() {
+        let handler = TestBackendHandler::new();
+        let directory = DatabaseDirectory::new(&handler);
+        assert_eq!(
+            execute(
+                &directory,
+                Action::AddUser {
+                    request: CreateUserRequest {
+                        user_id: "bob".to_string(),
+                        email: "bob@example.com".to_string(),
+                        display_name: Some("Bob".to_string()),
+                        ..Default::default()
+                    },
+                    password: None,
+                },
+            )
+            .await
+            .unwrap(),
+            "Created user bob\n"
+        );
+        handler.insert_user("alice", "alice@example.com", None);
+        execute(&directory, Action::AddGroup("family".to_string()))
+            .await
+            .unwrap();
+        execute(
+            &directory,
+            Action::AddMember {
+                group: "Family".to_string(),
+                user_id: "bob".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        assert!(execute(
+            &directory,
+            Action::AddMember {
+                group: "friends".to_string(),
+                user_id: "bob".to_string(),
+            },
+        )
+        .await
+        .is_err());
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(groups[0].users, vec!["bob"]);
+        assert_eq!(
+            execute(&directory, Action::ListUsers).await.unwrap(),
+            "alice\talice@example.com\talice\nbob\tbob@example.com\tBob\n"
+        );
+        assert_eq!(
+            execute(
+                &directory,
+                Action::SetPassword {
+                    user_id: "bob".to_string(),
+                    password: "bob00".to_string(),
+                },
+            )
+            .await
+            .unwrap(),
+            "Changed the password of bob\n"
+        );
+        execute(&directory, Action::DeleteUser("alice".to_string()))
+            .await
+            .unwrap();
+        assert!(handler.get_user_details("alice").await.is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! The long-lived tokens of the HTTP API, for the automation tools such as Terraform or Ansible.
+//!
+//! They are sent like the JWTs, as `Authorization: Bearer` headers, and told apart by their prefix.
+//! Only their hash is stored: the secret is shown once, when the token is created.
+
+use crate::{
+    domain::{
+        error::{DomainError, Result},
+        handler::BackendHandler,
+    },
+    infra::{
+        access_control::{Permission, ValidationResults},
+        tcp_backend_handler::{ApiToken, ApiTokenScope, TcpBackendHandler},
+    },
+};
+use async_trait::async_trait;
+use sha2::{Digest, Sha512};
+
+/// The start of the API tokens, which the JWTs can't start with.
+pub const API_TOKEN_PREFIX: &str = "lldap_";
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(API_TOKEN_PREFIX)
+}
+
+/// The hash of the token stored in the database. The tokens are random enough not to need a salt.
+pub fn hash_api_token(token: &str) -> String {
+    base64::encode(Sha512::digest(token.as_bytes()))
+}
+
+fn generate_api_token() -> String {
+    use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+    let secret: String = OsRng
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(40)
+        .collect();
+    format!("{}{}", API_TOKEN_PREFIX, secret)
+}
+
+/// Returns the permissions given by the token: the ones of its user, limited by its scope. The
+/// tokens of the locked users are refused.
+pub async fn validate_api_token<Backend: BackendHandler + TcpBackendHandler>(
+    backend_handler: &Backend,
+    token: &str,
+) -> Result<ValidationResults> {
+    let api_token = backend_handler
+        .get_api_token(&hash_api_token(token))
+        .await?
+        .ok_or_else(|| DomainError::AuthenticationError("Invalid API token".to_string()))?;
+    if backend_handler
+        .get_user_lock(&api_token.user_id)
+        .await?
+        .is_some()
+    {
+        return Err(DomainError::AuthenticationError(format!(
+            "{} is locked",
+            api_token.user_id
+        )));
+    }
+    let groups = backend_handler.get_user_groups(&api_token.user_id).await?;
+    let permission = Permission::from_groups(groups.iter().map(|group| group.1.as_str()));
+    Ok(ValidationResults {
+        user: api_token.user_id,
+        permission: permission.min(api_token.scope.max_permission()),
+        impersonator: None,
+    })
+}
+
+/// The API tokens, for the GraphQL API which doesn't depend on the [`TcpBackendHandler`].
+#[async_trait]
+pub trait ApiTokenManager: Send + Sync {
+    /// Creates a token for the user, and returns it with its secret.
+    async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        scope: ApiTokenScope,
+    ) -> Result<(ApiToken, String)>;
+    /// The tokens, the oldest first, optionally only the ones of a user.
+    async fn list_api_tokens(&self, user_id: Option<&str>) -> Result<Vec<ApiToken>>;
+    /// Deletes the token, which is refused from now on.
+    async fn revoke_api_token(&self, token_id: i32) -> Result<()>;
+}
+
+pub struct WebApiTokenManager<Backend> {
+    backend_handler: Backend,
+}
+
+impl<Backend> WebApiTokenManager<Backend> {
+    pub fn new(backend_handler: Backend) -> Self {
+        Self { backend_handler }
+    }
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Send + Sync> ApiTokenManager for WebApiTokenManager<Backend> {
+    async fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        scope: ApiTokenScope,
+    ) -> Result<(ApiToken, String)> {
+        if name.is_empty() {
+            return Err(DomainError::InvalidInput(
+                "The name of the API token can't be empty".to_string(),
+            ));
+        }
+        let secret = generate_api_token();
+        let api_token = self
+            .backend_handler
+            .create_api_token(user_id, name, scope, &hash_api_token(&secret))
+            .await?;
+        Ok((api_token, secret))
+    }
+
+    async fn list_api_tokens(&self, user_id: Option<&str>) -> Result<Vec<ApiToken>> {
+        self.backend_handler.list_api_tokens(user_id).await
+    }
+
+    async fn revoke_api_token(&self, token_id: i32) -> Result<()> {
+        self.backend_handler.delete_api_token(token_id).await
+    }
+}
+
+#[cfg(test)]
+mockall::mock! {
+    pub TestApiTokenManager{}
+    #[async_trait]
+    impl ApiTokenManager for TestApiTokenManager {
+        async fn create_api_token(
+            &self,
+            user_id: &str,
+            name: &str,
+            scope: ApiTokenScope,
+        ) -> Result<(ApiToken, String)>;
+        async fn list_api_tokens(&self, user_id: Option<&str>) -> Result<Vec<ApiToken>>;
+        async fn revoke_api_token(&self, token_id: i32) -> Result<()>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::handler::{GroupId, GroupIdAndName},
+        infra::{access_control::ADMIN_GROUP, tcp_backend_handler::MockTestTcpBackendHandler},
+    };
+    use chrono::{TimeZone, Utc};
+    use mockall::predicate::eq;
+    use std::collections::HashSet;
+
+    fn api_token(scope: ApiTokenScope) -> ApiToken {
+        ApiToken {
+            id: 1,
+            user_id: "admin".to_string(),
+            name: "terraform".to_string(),
+            scope,
+            creation_date: Utc.ymd(2022, 1, 1).and_hms(0, 0, 0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_api_token() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_create_api_token()
+            .withf(|user, name, scope, token_hash| {
+                user == "admin"
+                    && name == "terraform"
+                    && *scope == ApiTokenScope::Full
+                    && !token_hash.starts_with(API_TOKEN_PREFIX)
+            })
+            .times(1)
+            .return_once(|_, _, scope, _| Ok(api_token(scope)));
+        let manager = WebApiTokenManager::new(mock);
+        let (token, secret) = manager
+            .create_api_token("admin", "terraform", ApiTokenScope::Full)
+            .await
+            .unwrap();
+        assert_eq!(token, api_token(ApiTokenScope::Full));
+        assert!(is_api_token(&secret));
+        assert!(manager
+            .create_api_token("admin", "", ApiTokenScope::Full)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_api_token() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_api_token()
+            .with(eq(hash_api_token("lldap_secret")))
+            .return_once(|_| Ok(Some(api_token(ApiTokenScope::UserManagement))));
+        mock.expect_get_api_token()
+            .with(eq(hash_api_token("lldap_other")))
+            .return_once(|_| Ok(None));
+        mock.expect_get_user_lock()
+            .with(eq("admin"))
+            .return_once(|_| Ok(None));
+        mock.expect_get_user_groups()
+            .with(eq("admin"))
+            .return_once(|_| {
+                let mut groups = HashSet::new();
+                groups.insert(GroupIdAndName(GroupId(1), ADMIN_GROUP.to_string()));
+                Ok(groups)
+            });
+        assert_eq!(
+            validate_api_token(&mock, "lldap_secret").await.unwrap(),
+            ValidationResults {
+                user: "admin".to_string(),
+                permission: Permission::UserManager,
+                impersonator: None,
+            }
+        );
+        assert!(matches!(
+            validate_api_token(&mock, "lldap_other").await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+}
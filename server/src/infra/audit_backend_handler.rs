@@ -0,0 +1,704 @@
+use crate::{
+    domain::{error::*, handler::*, opaque_handler::*},
+    infra::tcp_backend_handler::{ApiToken, ApiTokenScope, Session, TcpBackendHandler},
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use tracing::warn;
+
+/// Who is making the current request, recorded with the audit events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditContext {
+    pub actor: Option<String>,
+    pub source_ip: Option<std::net::IpAddr>,
+}
+
+tokio::task_local! {
+    static AUDIT_CONTEXT: AuditContext;
+}
+
+/// Runs the future with this context: the events recorded by the [`AuditBackendHandler`] while
+/// it runs get its actor and source IP.
+pub async fn with_audit_context<F: Future>(context: AuditContext, future: F) -> F::Output {
+    AUDIT_CONTEXT.scope(context, future).await
+}
+
+fn current_context() -> AuditContext {
+    AUDIT_CONTEXT.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Records an event with the actor and the source IP of the current request, unless `actor` is
+/// given. Failing to do so is logged but doesn't fail the operation.
+pub async fn record_event<Backend: BackendHandler>(
+    backend: &Backend,
+    actor: Option<String>,
+    action: &str,
+    target: String,
+    success: bool,
+) {
+    let context = current_context();
+    let event = AuditEvent {
+        time: chrono::Utc::now(),
+        actor: actor.or(context.actor),
+        source_ip: context.source_ip,
+        action: action.to_string(),
+        target,
+        success,
+    };
+    if let Err(e) = backend.record_audit_event(event).await {
+        warn!("Could not record the audit event: {}", e);
+    }
+}
+
+fn group_target(group_id: GroupId) -> String {
+    format!("group {}", group_id.0)
+}
+
+fn membership_target(member: &str, group_id: GroupId) -> String {
+    format!("{} in group {}", member, group_id.0)
+}
+
+/// A backend handler forwarding everything to `Backend`, and recording the modifications and the
+/// authentication attempts, successful or not, in the audit log.
+#[derive(Clone)]
+pub struct AuditBackendHandler<Backend> {
+    inner: Backend,
+}
+
+impl<Backend> AuditBackendHandler<Backend> {
+    pub fn new(inner: Backend) -> Self {
+        Self { inner }
+    }
+}
+
+impl<Backend: BackendHandler + Sync> AuditBackendHandler<Backend> {
+    async fn record(&self, actor: Option<String>, action: &str, target: String, success: bool) {
+        record_event(&self.inner, actor, action, target, success).await
+    }
+
+    async fn audit<T>(
+        &self,
+        action: &str,
+        target: String,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let result = future.await;
+        self.record(None, action, target, result.is_ok()).await;
+        result
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + LoginHandler + Sync> LoginHandler for AuditBackendHandler<Backend> {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        let name = request.name.clone();
+        let result = self.inner.bind(request).await;
+        self.record(Some(name.clone()), "bind", name, result.is_ok())
+            .await;
+        result
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for AuditBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        self.inner.list_users(filters).await
+    }
+
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        self.inner.list_users_stream(filters, order)
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.inner
+            .list_users_page(filters, order, direction, offset, limit)
+            .await
+    }
+
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        self.inner.count_users(filters).await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.inner.list_groups(filters).await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        self.inner.list_groups_page(filters, offset, limit).await
+    }
+
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        self.inner.list_groups_with_member_count().await
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        self.inner.get_user_details(user_id).await
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_user_avatar(user_id).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.inner.get_user_by_email(email).await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.inner.get_group_details(group_id).await
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        self.inner.get_parent_groups(group_id).await
+    }
+
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        self.inner.get_group_members(group_id, offset, limit).await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        self.audit("create_user", user_id, self.inner.create_user(request))
+            .await
+    }
+
+    /// Records a `create_user` event per request.
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        let user_ids: Vec<String> = requests.iter().map(|r| r.user_id.clone()).collect();
+        let results = self.inner.bulk_create_users(requests).await?;
+        for (user_id, result) in user_ids.into_iter().zip(&results) {
+            self.record(None, "create_user", user_id, result.is_ok())
+                .await;
+        }
+        Ok(results)
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        self.audit("update_user", user_id, self.inner.update_user(request))
+            .await
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let target = group_target(request.group_id);
+        self.audit("update_group", target, self.inner.update_group(request))
+            .await
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.audit(
+            "delete_user",
+            user_id.to_string(),
+            self.inner.delete_user(user_id),
+        )
+        .await
+    }
+
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        self.audit(
+            "restore_user",
+            user_id.to_string(),
+            self.inner.restore_user(user_id),
+        )
+        .await
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        self.inner.list_deleted_users().await
+    }
+
+    /// The target is the previous ID: the audit events of the user keep it.
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        self.audit(
+            "rename_user",
+            user_id.to_string(),
+            self.inner.rename_user(user_id, new_user_id),
+        )
+        .await
+    }
+
+    /// The target is the name: the ID is only known on success.
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.audit(
+            "create_group",
+            group_name.to_string(),
+            self.inner.create_group(group_name),
+        )
+        .await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.audit(
+            "delete_group",
+            group_target(group_id),
+            self.inner.delete_group(group_id),
+        )
+        .await
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.audit(
+            "add_user_to_group",
+            membership_target(user_id, group_id),
+            self.inner.add_user_to_group(user_id, group_id),
+        )
+        .await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.audit(
+            "remove_user_from_group",
+            membership_target(user_id, group_id),
+            self.inner.remove_user_from_group(user_id, group_id),
+        )
+        .await
+    }
+
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.audit(
+            "add_group_to_group",
+            membership_target(&group_target(member_group_id), parent_group_id),
+            self.inner
+                .add_group_to_group(member_group_id, parent_group_id),
+        )
+        .await
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.audit(
+            "remove_group_from_group",
+            membership_target(&group_target(member_group_id), parent_group_id),
+            self.inner
+                .remove_group_from_group(member_group_id, parent_group_id),
+        )
+        .await
+    }
+
+    /// One event per user, with the outcome of the whole operation.
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let result = self.inner.add_users_to_group(group_id, user_ids).await;
+        for user_id in user_ids {
+            self.record(
+                None,
+                "add_user_to_group",
+                membership_target(user_id, group_id),
+                result.is_ok(),
+            )
+            .await;
+        }
+        result
+    }
+
+    /// One event per user, with the outcome of the whole operation.
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let result = self.inner.remove_users_from_group(group_id, user_ids).await;
+        for user_id in user_ids {
+            self.record(
+                None,
+                "remove_user_from_group",
+                membership_target(user_id, group_id),
+                result.is_ok(),
+            )
+            .await;
+        }
+        result
+    }
+
+    /// On success, one event per added or removed member.
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        let changes = match self.inner.set_group_members(group_id, user_ids).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                self.record(None, "set_group_members", group_target(group_id), false)
+                    .await;
+                return Err(e);
+            }
+        };
+        for user_id in &changes.added {
+            self.record(
+                None,
+                "add_user_to_group",
+                membership_target(user_id, group_id),
+                true,
+            )
+            .await;
+        }
+        for user_id in &changes.removed {
+            self.record(
+                None,
+                "remove_user_from_group",
+                membership_target(user_id, group_id),
+                true,
+            )
+            .await;
+        }
+        Ok(changes)
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        self.inner.get_user_groups(user).await
+    }
+
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        self.inner.get_users_groups(user_ids).await
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        self.inner.get_user_hosts(user_id).await
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        self.audit(
+            "set_user_hosts",
+            user_id.to_string(),
+            self.inner.set_user_hosts(user_id, hosts),
+        )
+        .await
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.inner.get_group_metadata(group_id).await
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        self.inner.get_group_mail(group_id).await
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        self.audit(
+            "set_group_mail",
+            group_target(group_id),
+            self.inner.set_group_mail(group_id, mail),
+        )
+        .await
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        self.inner.get_user_attributes(user_id).await
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.audit(
+            "set_user_attribute",
+            format!("{} {}", user_id, name),
+            self.inner.set_user_attribute(user_id, name, values),
+        )
+        .await
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        self.audit(
+            "lock_user",
+            user_id.to_string(),
+            self.inner.lock_user(user_id, reason),
+        )
+        .await
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.audit(
+            "unlock_user",
+            user_id.to_string(),
+            self.inner.unlock_user(user_id),
+        )
+        .await
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        self.inner.get_user_lock(user_id).await
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.inner.list_password_changes().await
+    }
+
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        self.inner.get_password_change(user_id).await
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.inner.record_auth_failure(failure).await
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        self.inner.list_auth_failures(user_id, offset, limit).await
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.inner.record_audit_event(event).await
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.inner.list_audit_events(offset, limit).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + OpaqueHandler + Sync> OpaqueHandler
+    for AuditBackendHandler<Backend>
+{
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.inner.login_start(request).await
+    }
+
+    /// Recorded like a bind. The failures are only attributed to a user when they were for
+    /// invalid credentials.
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        let result = self.inner.login_finish(request).await;
+        let user_id = match &result {
+            Ok(user_id) | Err(DomainError::AuthenticationError(user_id)) => Some(user_id.clone()),
+            Err(_) => None,
+        };
+        self.record(
+            user_id.clone(),
+            "login",
+            user_id.unwrap_or_default(),
+            result.is_ok(),
+        )
+        .await;
+        result
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.inner.registration_start(request).await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        self.inner.registration_finish(request).await
+    }
+
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        self.inner.is_password_reused(username, password).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + TcpBackendHandler + Sync> TcpBackendHandler
+    for AuditBackendHandler<Backend>
+{
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+        self.inner.get_jwt_blacklist().await
+    }
+
+    async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
+        self.inner.create_refresh_token(user).await
+    }
+
+    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
+        self.inner.check_token(refresh_token_hash, user).await
+    }
+
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.inner.register_jwt(user, jwt_hash, expiry_date).await
+    }
+
+    async fn blacklist_jwts(&self, user: &str) -> Result<HashSet<u64>> {
+        self.inner.blacklist_jwts(user).await
+    }
+
+    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
+        self.inner.delete_refresh_token(refresh_token_hash).await
+    }
+
+    async fn delete_refresh_tokens(&self, user: &str) -> Result<()> {
+        self.audit(
+            "logout_all_sessions",
+            user.to_string(),
+            self.inner.delete_refresh_tokens(user),
+        )
+        .await
+    }
+
+    async fn list_sessions(&self, user: Option<&str>) -> Result<Vec<Session>> {
+        self.inner.list_sessions(user).await
+    }
+
+    async fn start_password_reset(&self, user: &str) -> Result<Option<String>> {
+        self.audit(
+            "start_password_reset",
+            user.to_string(),
+            self.inner.start_password_reset(user),
+        )
+        .await
+    }
+
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<String> {
+        self.inner.get_user_id_for_password_reset_token(token).await
+    }
+
+    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
+        self.inner.delete_password_reset_token(token).await
+    }
+
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> Result<ApiToken> {
+        self.audit(
+            "create_api_token",
+            format!("{} of {}", name, user),
+            self.inner.create_api_token(user, name, scope, token_hash),
+        )
+        .await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        self.inner.get_api_token(token_hash).await
+    }
+
+    async fn list_api_tokens(&self, user: Option<&str>) -> Result<Vec<ApiToken>> {
+        self.inner.list_api_tokens(user).await
+    }
+
+    async fn delete_api_token(&self, token_id: i32) -> Result<()> {
+        self.audit(
+            "revoke_api_token",
+            format!("API token {}", token_id),
+            self.inner.delete_api_token(token_id),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::test_backend_handler::TestBackendHandler;
+
+    #[tokio::test]
+    async fn test_records_changes_with_context() {
+        let backend = TestBackendHandler::new();
+        let handler = AuditBackendHandler::new(backend.clone());
+        let context = AuditContext {
+            actor: Some("admin".to_string()),
+            source_ip: Some("10.0.0.1".parse().unwrap()),
+        };
+        let group_id = with_audit_context(context, handler.create_group("family"))
+            .await
+            .unwrap();
+        handler
+            .add_user_to_group("nobody", group_id)
+            .await
+            .unwrap_err();
+        let events = backend.list_audit_events(0, 10).await.unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (
+                    e.actor.as_deref(),
+                    e.action.as_str(),
+                    e.target.as_str(),
+                    e.success
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    None,
+                    "add_user_to_group",
+                    membership_target("nobody", group_id).as_str(),
+                    false
+                ),
+                (Some("admin"), "create_group", "family", true),
+            ]
+        );
+        assert_eq!(events[1].source_ip, Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_records_binds() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", Some("pass"));
+        let handler = AuditBackendHandler::new(backend.clone());
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "wrong".to_string(),
+            })
+            .await
+            .unwrap_err();
+        handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+            })
+            .await
+            .unwrap();
+        let events = backend.list_audit_events(0, 10).await.unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.actor.as_deref(), e.action.as_str(), e.success))
+                .collect::<Vec<_>>(),
+            vec![(Some("bob"), "bind", true), (Some("bob"), "bind", false)]
+        );
+    }
+}
@@ -1,10 +1,25 @@
 use crate::{
     domain::{
         error::DomainError,
-        handler::{BackendHandler, BindRequest, GroupIdAndName, LoginHandler},
+        handler::{
+            AuthFailure, AuthFailureReason, AuthProtocol, BackendHandler, BindRequest,
+            GroupIdAndName, LoginHandler, RequestFilter, User,
+        },
+        identifiers::fold_case,
         opaque_handler::OpaqueHandler,
     },
     infra::{
+        access_control::{
+            AccessControlledBackendHandler, Permission, ValidationResults, ADMIN_GROUP,
+        },
+        api_tokens::{is_api_token, validate_api_token},
+        audit_backend_handler::{with_audit_context, AuditContext},
+        configuration::{OidcConfig, OidcUserMatch},
+        jwt_keys::JwtKeys,
+        mail::{self, EmailTemplate},
+        oidc,
+        password_policy::evaluate_password,
+        sessions::blacklist_jwts,
         tcp_backend_handler::*,
         tcp_server::{error_to_http_response, AppState},
     },
@@ -12,38 +27,98 @@ use crate::{
 use actix_web::{
     cookie::{Cookie, SameSite},
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    error::{ErrorBadRequest, ErrorUnauthorized},
-    web, HttpRequest, HttpResponse,
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorUnauthorized},
+    http::header::LOCATION,
+    web, HttpRequest, HttpResponse, HttpResponseBuilder,
 };
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 use anyhow::Result;
 use chrono::prelude::*;
 use futures::future::{ok, Ready};
 use futures_util::{FutureExt, TryFutureExt};
-use hmac::Hmac;
-use jwt::{SignWithKey, VerifyWithKey};
-use lldap_auth::{login, registration, JWTClaims};
-use sha2::Sha512;
+use jwt::SignWithKey;
+use lldap_auth::{login, password::PasswordStrengthRequest, registration, JWTClaims};
+use serde::Deserialize;
 use std::collections::{hash_map::DefaultHasher, HashSet};
 use std::hash::{Hash, Hasher};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use time::ext::NumericalDuration;
+use tracing::warn;
 
 type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
-type SignedToken = Token<jwt::token::Signed>;
+pub(crate) type SignedToken = Token<jwt::token::Signed>;
 
-fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<GroupIdAndName>) -> SignedToken {
+fn create_jwt(
+    keys: &JwtKeys,
+    user: String,
+    groups: HashSet<GroupIdAndName>,
+    duration: chrono::Duration,
+    impersonator: Option<String>,
+) -> SignedToken {
     let claims = JWTClaims {
-        exp: Utc::now() + chrono::Duration::days(1),
+        exp: Utc::now() + duration,
         iat: Utc::now(),
         user,
         groups: groups.into_iter().map(|g| g.1).collect(),
+        impersonator,
     };
-    let header = jwt::Header {
-        algorithm: jwt::AlgorithmType::Hs512,
-        ..Default::default()
-    };
-    jwt::Token::new(header, claims).sign_with_key(key).unwrap()
+    jwt::Token::new(keys.header(), claims)
+        .sign_with_key(keys.signing_key())
+        .unwrap()
+}
+
+/// The hash identifying a JWT in the blacklist.
+fn get_jwt_hash(token: &str) -> u64 {
+    let mut s = DefaultHasher::new();
+    token.hash(&mut s);
+    s.finish()
+}
+
+/// Creates a JWT for the user, valid for `duration`, and records it so that it can be
+/// blacklisted. The `impersonator` is the admin it's issued to, if it's not the user.
+pub(crate) async fn register_jwt<Backend>(
+    backend_handler: &Backend,
+    keys: &JwtKeys,
+    user: &str,
+    groups: HashSet<GroupIdAndName>,
+    duration: chrono::Duration,
+    impersonator: Option<&str>,
+) -> std::result::Result<SignedToken, DomainError>
+where
+    Backend: TcpBackendHandler,
+{
+    let token = create_jwt(
+        keys,
+        user.to_string(),
+        groups,
+        duration,
+        impersonator.map(str::to_string),
+    );
+    backend_handler
+        .register_jwt(user, get_jwt_hash(token.as_str()), token.claims().exp)
+        .await?;
+    Ok(token)
+}
+
+/// Creates a JWT for the user, valid for a day, and records it so that it can be blacklisted.
+pub(crate) async fn create_registered_jwt<Backend>(
+    data: &AppState<Backend>,
+    user: &str,
+    groups: HashSet<GroupIdAndName>,
+) -> std::result::Result<SignedToken, DomainError>
+where
+    Backend: TcpBackendHandler,
+{
+    register_jwt(
+        &data.backend_handler,
+        &data.jwt_keys,
+        user,
+        groups,
+        chrono::Duration::days(1),
+        None,
+    )
+    .await
 }
 
 fn get_refresh_token_from_cookie(
@@ -73,7 +148,6 @@ where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
     let backend_handler = &data.backend_handler;
-    let jwt_key = &data.jwt_key;
     let (refresh_token_hash, user) = match get_refresh_token_from_cookie(request) {
         Ok(t) => t,
         Err(http_response) => return http_response,
@@ -83,7 +157,7 @@ where
         .check_token(refresh_token_hash, &user)
         .await;
     // Async closures are not supported yet.
-    match res_found {
+    let res_groups = match res_found {
         Ok(found) => {
             if found {
                 backend_handler.get_user_groups(&user).await
@@ -94,8 +168,11 @@ where
             }
         }
         Err(e) => Err(e),
+    };
+    match res_groups {
+        Ok(groups) => create_registered_jwt(&data, &user, groups).await,
+        Err(e) => Err(e),
     }
-    .map(|groups| create_jwt(jwt_key, user.to_string(), groups))
     .map(|token| {
         HttpResponse::Ok()
             .cookie(
@@ -130,20 +207,9 @@ where
     {
         return response;
     };
-    match data
-        .backend_handler
-        .blacklist_jwts(&user)
-        .map_err(error_to_http_response)
-        .await
-    {
-        Ok(new_blacklisted_jwts) => {
-            let mut jwt_blacklist = data.jwt_blacklist.write().unwrap();
-            for jwt in new_blacklisted_jwts {
-                jwt_blacklist.insert(jwt);
-            }
-        }
-        Err(response) => return response,
-    };
+    if let Err(e) = blacklist_jwts(&data.backend_handler, &data.jwt_blacklist, &user).await {
+        return error_to_http_response(e);
+    }
     HttpResponse::Ok()
         .cookie(
             Cookie::build("token", "")
@@ -172,11 +238,15 @@ pub type ApiResult<M> = actix_web::Either<web::Json<M>, HttpResponse>;
 
 async fn opaque_login_start<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientLoginStartRequest>,
 ) -> ApiResult<login::ServerLoginStartResponse>
 where
     Backend: OpaqueHandler + 'static,
 {
+    if let Some(response) = get_throttled_response(&data, &http_request, Some(&request.username)) {
+        return ApiResult::Right(response);
+    }
     data.backend_handler
         .login_start(request.into_inner())
         .await
@@ -184,87 +254,357 @@ where
         .unwrap_or_else(error_to_api_response)
 }
 
-async fn get_login_successful_response<Backend>(
+/// Creates the JWT and the refresh token of a user who just authenticated, and sets their
+/// cookies in the response.
+async fn start_session<Backend>(
     data: &web::Data<AppState<Backend>>,
     name: &str,
-) -> HttpResponse
+    response: &mut HttpResponseBuilder,
+) -> std::result::Result<SignedToken, DomainError>
 where
     Backend: TcpBackendHandler + BackendHandler,
 {
+    // The logins already refuse the locked users, this makes sure they never get a session.
+    if data.backend_handler.get_user_lock(name).await?.is_some() {
+        return Err(DomainError::AuthenticationError(format!(
+            "User {} is locked",
+            name
+        )));
+    }
     // The authentication was successful, we need to fetch the groups to create the JWT
     // token.
-    data.backend_handler
-        .get_user_groups(name)
-        .and_then(|g| async { Ok((g, data.backend_handler.create_refresh_token(name).await?)) })
-        .await
-        .map(|(groups, (refresh_token, max_age))| {
-            let token = create_jwt(&data.jwt_key, name.to_string(), groups);
-            HttpResponse::Ok()
-                .cookie(
-                    Cookie::build("token", token.as_str())
-                        .max_age(1.days())
-                        .path("/api")
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
-                        .finish(),
-                )
-                .cookie(
-                    Cookie::build("refresh_token", refresh_token + "+" + name)
-                        .max_age(max_age.num_days().days())
-                        .path("/auth")
-                        .http_only(true)
-                        .same_site(SameSite::Strict)
-                        .finish(),
-                )
-                .body(token.as_str().to_owned())
-        })
-        .unwrap_or_else(error_to_http_response)
+    let groups = data.backend_handler.get_user_groups(name).await?;
+    let (refresh_token, max_age) = data.backend_handler.create_refresh_token(name).await?;
+    let token = create_registered_jwt(data, name, groups).await?;
+    response
+        .cookie(
+            Cookie::build("token", token.as_str())
+                .max_age(1.days())
+                .path("/api")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .cookie(
+            Cookie::build("refresh_token", refresh_token + "+" + name)
+                .max_age(max_age.num_days().days())
+                .path("/auth")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish(),
+        );
+    Ok(token)
+}
+
+async fn get_login_successful_response<Backend>(
+    data: &web::Data<AppState<Backend>>,
+    name: &str,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler,
+{
+    let mut response = HttpResponse::Ok();
+    match start_session(data, name, &mut response).await {
+        Ok(token) => response.body(token.as_str().to_owned()),
+        Err(e) => error_to_http_response(e),
+    }
+}
+
+/// The context of the audit events of an unauthenticated request: only the address is known.
+pub(crate) fn anonymous_audit_context(http_request: &HttpRequest) -> AuditContext {
+    AuditContext {
+        actor: None,
+        source_ip: http_request.peer_addr().map(|address| address.ip()),
+    }
+}
+
+/// The response refusing the login if the address of the client or the user failed too many
+/// times lately, without checking the credentials.
+pub(crate) fn get_throttled_response<Backend>(
+    data: &AppState<Backend>,
+    http_request: &HttpRequest,
+    user_id: Option<&str>,
+) -> Option<HttpResponse> {
+    let source_ip = http_request.peer_addr().map(|address| address.ip());
+    let blocked_until = data.bind_throttle.check(source_ip, user_id)?;
+    warn!(
+        "Throttled login of {} from {:?}",
+        user_id.unwrap_or("an unknown user"),
+        source_ip
+    );
+    Some(HttpResponse::TooManyRequests().body(format!(
+        "Too many failed attempts, try again after {}",
+        blocked_until.to_rfc3339()
+    )))
+}
+
+/// Records the failed login, if it failed because of the credentials.
+pub(crate) async fn record_login_failure<Backend>(
+    data: &AppState<Backend>,
+    http_request: &HttpRequest,
+    error: &DomainError,
+) where
+    Backend: BackendHandler,
+{
+    let user_id = match error {
+        DomainError::AuthenticationError(user_id) => user_id.clone(),
+        _ => return,
+    };
+    let source_ip = http_request.peer_addr().map(|address| address.ip());
+    data.bind_throttle
+        .record_failure(source_ip, Some(&user_id))
+        .await;
+    let backend_handler = &data.backend_handler;
+    let reason = match backend_handler.get_user_lock(&user_id).await {
+        Ok(Some(_)) => AuthFailureReason::UserLocked,
+        _ => AuthFailureReason::InvalidCredentials,
+    };
+    let failure = AuthFailure {
+        time: Utc::now(),
+        user_id: Some(user_id),
+        source_ip,
+        protocol: AuthProtocol::Web,
+        reason,
+    };
+    warn!("Failed authentication: {}", failure);
+    if let Err(e) = backend_handler.record_auth_failure(failure).await {
+        warn!("Could not record the failed login: {}", e);
+    }
 }
 
 async fn opaque_login_finish<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<login::ClientLoginFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    let name = match data
-        .backend_handler
-        .login_finish(request.into_inner())
-        .await
+    let name = match with_audit_context(
+        anonymous_audit_context(&http_request),
+        data.backend_handler.login_finish(request.into_inner()),
+    )
+    .await
     {
         Ok(n) => n,
-        Err(e) => return error_to_http_response(e),
+        Err(e) => {
+            record_login_failure(&data, &http_request, &e).await;
+            return error_to_http_response(e);
+        }
     };
+    data.bind_throttle.record_success(&name).await;
     get_login_successful_response(&data, &name).await
 }
 
 async fn post_authorize<Backend>(
     data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
     request: web::Json<BindRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
 {
-    let name = request.name.clone();
-    if let Err(e) = data.backend_handler.bind(request.into_inner()).await {
+    // The session is the one of the user ID as stored, whatever the case it was typed in.
+    let name = fold_case(&request.name);
+    if let Some(response) = get_throttled_response(&data, &http_request, Some(&name)) {
+        return response;
+    }
+    if let Err(e) = with_audit_context(
+        anonymous_audit_context(&http_request),
+        data.backend_handler.bind(request.into_inner()),
+    )
+    .await
+    {
+        record_login_failure(&data, &http_request, &e).await;
         return error_to_http_response(e);
     }
+    data.bind_throttle.record_success(&name).await;
     get_login_successful_response(&data, &name).await
 }
 
+/// The name of the OIDC provider to show on the login page, or null if there is none.
+async fn get_oidc_provider<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: 'static,
+{
+    HttpResponse::Ok().json(
+        data.oidc_config
+            .as_ref()
+            .map(|config| config.display_name.clone()),
+    )
+}
+
+/// Redirects to the OIDC provider, which redirects back to [`get_oidc_callback`].
+async fn get_oidc_login<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: 'static,
+{
+    let config = match &data.oidc_config {
+        Some(config) => config,
+        None => return HttpResponse::NotFound().body("OIDC is not configured"),
+    };
+    let state = oidc::generate_state();
+    match oidc::get_provider_metadata(config)
+        .await
+        .and_then(|metadata| oidc::get_authorization_url(config, &metadata, &state))
+    {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((LOCATION, url.as_str()))
+            .cookie(
+                // Lax, to be sent along with the redirection from the provider.
+                Cookie::build("oidc_state", state)
+                    .max_age(10.minutes())
+                    .path("/auth/oidc")
+                    .http_only(true)
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            )
+            .finish(),
+        Err(e) => {
+            warn!("Could not start the OIDC login: {:#}", e);
+            HttpResponse::InternalServerError().body("Could not reach the OIDC provider")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// The account an OIDC login maps to, if it exists and isn't locked.
+async fn get_oidc_user<Backend>(
+    backend_handler: &Backend,
+    config: &OidcConfig,
+    claim: String,
+) -> std::result::Result<String, DomainError>
+where
+    Backend: BackendHandler,
+{
+    let user = match config.match_by {
+        OidcUserMatch::UserId => match backend_handler.get_user_details(&claim).await {
+            Ok(user) => Some(user),
+            Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => None,
+            Err(e) => return Err(e),
+        },
+        // The email must designate a single user, even without `unique_emails`.
+        OidcUserMatch::Email => {
+            let mut users = backend_handler
+                .list_users(Some(RequestFilter::Equality(
+                    "email".to_string(),
+                    claim.clone(),
+                )))
+                .await?;
+            if users.len() > 1 {
+                warn!(
+                    "Refusing the OIDC login of {}: several users have this email",
+                    claim
+                );
+                return Err(DomainError::AuthenticationError(claim));
+            }
+            users.pop()
+        }
+    };
+    let user_id = user.ok_or(DomainError::AuthenticationError(claim))?.user_id;
+    if backend_handler.get_user_lock(&user_id).await?.is_some() {
+        return Err(DomainError::AuthenticationError(user_id));
+    }
+    Ok(user_id)
+}
+
+/// Logs in the user the provider authenticated, and redirects to the web UI.
+async fn get_oidc_callback<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    query: web::Query<OidcCallbackQuery>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let config = match &data.oidc_config {
+        Some(config) => config,
+        None => return HttpResponse::NotFound().body("OIDC is not configured"),
+    };
+    let expected_state = http_request
+        .cookie("oidc_state")
+        .map(|cookie| cookie.value().to_string());
+    if query.state.is_none() || query.state != expected_state {
+        return HttpResponse::BadRequest().body("Invalid OIDC state, try logging in again");
+    }
+    let code = match (&query.code, &query.error) {
+        (Some(code), _) => code,
+        (None, error) => {
+            return HttpResponse::Unauthorized().body(format!(
+                "The OIDC provider refused the login: {}",
+                error.as_deref().unwrap_or("no code")
+            ))
+        }
+    };
+    let claim = match oidc::get_user_claim(config, code).await {
+        Ok(claim) => claim,
+        Err(e) => {
+            warn!("OIDC login failed: {:#}", e);
+            return HttpResponse::Unauthorized().body("OIDC login failed");
+        }
+    };
+    let name = match get_oidc_user(&data.backend_handler, config, claim).await {
+        Ok(name) => name,
+        Err(e) => {
+            record_login_failure(&data, &http_request, &e).await;
+            return error_to_http_response(e);
+        }
+    };
+    let mut response = HttpResponse::Found();
+    response.insert_header((LOCATION, "/")).cookie(
+        Cookie::build("oidc_state", "")
+            .max_age(0.days())
+            .path("/auth/oidc")
+            .http_only(true)
+            .finish(),
+    );
+    let token = match start_session(&data, &name, &mut response).await {
+        Ok(token) => token,
+        Err(e) => return error_to_http_response(e),
+    };
+    // The web UI reads who is logged in from these, like the ones it sets after a password login.
+    let is_admin = token.claims().groups.contains(ADMIN_GROUP);
+    response
+        .cookie(
+            Cookie::build("user_id", name)
+                .max_age(1.days())
+                .path("/")
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .cookie(
+            Cookie::build("is_admin", is_admin.to_string())
+                .max_age(1.days())
+                .path("/")
+                .same_site(SameSite::Strict)
+                .finish(),
+        )
+        .finish()
+}
+
+/// Only the user themselves, an admin or a password manager can change the password of a user.
 async fn opaque_register_start<Backend>(
     data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
     request: web::Json<registration::ClientRegistrationStartRequest>,
-) -> ApiResult<registration::ServerRegistrationStartResponse>
+) -> actix_web::Result<ApiResult<registration::ServerRegistrationStartResponse>>
 where
-    Backend: OpaqueHandler + 'static,
+    Backend: BackendHandler + TcpBackendHandler + OpaqueHandler + Sync + 'static,
 {
-    data.backend_handler
-        .registration_start(request.into_inner())
-        .await
-        .map(|res| ApiResult::Left(web::Json(res)))
-        .unwrap_or_else(error_to_api_response)
+    let validation_result = check_if_token_is_valid(&data, bearer.token()).await?;
+    Ok(
+        AccessControlledBackendHandler::new(data.backend_handler.clone(), validation_result)
+            .registration_start(request.into_inner())
+            .await
+            .map(|res| ApiResult::Left(web::Json(res)))
+            .unwrap_or_else(error_to_api_response),
+    )
 }
 
 async fn opaque_register_finish<Backend>(
@@ -284,6 +624,231 @@ where
     HttpResponse::Ok().finish()
 }
 
+/// Looks up the user by ID, or by email if the parameter contains an '@'.
+async fn find_user_for_password_reset<Backend>(
+    backend_handler: &Backend,
+    user_string: &str,
+) -> Result<Option<User>, DomainError>
+where
+    Backend: BackendHandler,
+{
+    if user_string.contains('@') {
+        backend_handler.get_user_by_email(user_string).await
+    } else {
+        match backend_handler.get_user_details(user_string).await {
+            Ok(user) => Ok(Some(user)),
+            Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Creates a password reset token for the user, looked up by ID or by email. Returns `None` if
+/// there is no such user.
+async fn start_password_reset_for_user<Backend>(
+    backend_handler: &Backend,
+    user_string: &str,
+) -> Result<Option<(User, String)>, DomainError>
+where
+    Backend: TcpBackendHandler + BackendHandler,
+{
+    let user = match find_user_for_password_reset(backend_handler, user_string).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    Ok(backend_handler
+        .start_password_reset(&user.user_id)
+        .await?
+        .map(|token| (user, token)))
+}
+
+/// Sends a password reset link to the email of the user. The response is the same whether or
+/// not the user exists, to avoid leaking the list of users.
+async fn post_password_reset_step1<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let smtp_config = match &data.smtp_config {
+        Some(config) => config,
+        None => return HttpResponse::NotFound().body("Password reset is not configured"),
+    };
+    let user_string = match request.match_info().get("user_id") {
+        Some(user_string) => user_string,
+        None => return HttpResponse::BadRequest().body("Missing user ID"),
+    };
+    let (user, token) = match with_audit_context(
+        anonymous_audit_context(&request),
+        start_password_reset_for_user(&data.backend_handler, user_string),
+    )
+    .await
+    {
+        Ok(Some(user_and_token)) => user_and_token,
+        Ok(None) => return HttpResponse::Ok().finish(),
+        Err(e) => return error_to_http_response(e),
+    };
+    let mut context = tera::Context::new();
+    context.insert("display_name", &user.display_name);
+    context.insert("user_id", &user.user_id);
+    context.insert(
+        "link",
+        &format!(
+            "{}/reset-password/step2/{}",
+            data.http_url.trim_end_matches('/'),
+            token
+        ),
+    );
+    if let Err(e) = mail::send_template_email(
+        smtp_config,
+        &user.display_name,
+        &user.email,
+        EmailTemplate::PasswordReset,
+        &context,
+    )
+    .await
+    {
+        warn!("Error sending the password reset email: {:#}", e);
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// Returns the user of the password reset token, and deletes the token so that it can only be
+/// used once, even if the password registration then fails.
+async fn consume_password_reset_token<Backend>(
+    backend_handler: &Backend,
+    token: &str,
+) -> Result<String, DomainError>
+where
+    Backend: TcpBackendHandler,
+{
+    let user_id = backend_handler
+        .get_user_id_for_password_reset_token(token)
+        .await?;
+    backend_handler.delete_password_reset_token(token).await?;
+    Ok(user_id)
+}
+
+/// Starts the OPAQUE registration of a new password for the user of the reset token, for the
+/// `/reset-password/step2/{token}` page of the links. The client then completes the registration
+/// with `/auth/opaque/register/finish`.
+async fn post_password_reset_step2<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    registration_request: web::Json<registration::ClientRegistrationStartRequest>,
+) -> ApiResult<registration::ServerRegistrationStartResponse>
+where
+    Backend: TcpBackendHandler + OpaqueHandler + 'static,
+{
+    let token = match request.match_info().get("token") {
+        Some(token) => token,
+        None => return ApiResult::Right(HttpResponse::BadRequest().body("Missing token")),
+    };
+    let user_id = match consume_password_reset_token(&data.backend_handler, token).await {
+        Ok(user_id) => user_id,
+        Err(e) => return error_to_api_response(e),
+    };
+    let registration_request = registration::ClientRegistrationStartRequest {
+        username: user_id,
+        ..registration_request.into_inner()
+    };
+    data.backend_handler
+        .registration_start(registration_request)
+        .await
+        .map(|res| ApiResult::Left(web::Json(res)))
+        .unwrap_or_else(error_to_api_response)
+}
+
+/// Whether the request carries a valid token of the user.
+async fn is_authenticated_as<Backend>(
+    data: &AppState<Backend>,
+    request: &HttpRequest,
+    user_id: &str,
+) -> bool
+where
+    Backend: BackendHandler + TcpBackendHandler,
+{
+    let token = match request
+        .headers()
+        .get(actix_http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+    {
+        Some(token) => token,
+        None => return false,
+    };
+    check_if_token_is_valid(data, token)
+        .await
+        .map(|validation| validation.user == fold_case(user_id))
+        .unwrap_or(false)
+}
+
+/// The longest password `/auth/password/strength` evaluates: zxcvbn gets slow on long inputs.
+const MAX_PASSWORD_STRENGTH_LENGTH: usize = 256;
+
+/// Evaluates a password against the password policy, for the forms to check it before
+/// registering it with OPAQUE. It doesn't need a token, for the password reset form. The
+/// password history is only checked for the logged in user, not to tell anyone else whether a
+/// password was used by the user. Since it tells whether a password is one of the user's, each
+/// check counts as a failed login for the throttling.
+async fn post_password_strength<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    request: web::Json<PasswordStrengthRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
+{
+    let PasswordStrengthRequest { password, user_id } = request.into_inner();
+    let user_id = user_id.map(|user_id| fold_case(&user_id));
+    if let Some(response) = get_throttled_response(&data, &http_request, user_id.as_deref()) {
+        return response;
+    }
+    if password.chars().count() > MAX_PASSWORD_STRENGTH_LENGTH {
+        return HttpResponse::BadRequest().body(format!(
+            "The password is longer than {} characters",
+            MAX_PASSWORD_STRENGTH_LENGTH
+        ));
+    }
+    let policy = data.password_policy_config.clone();
+    let (password, user_id, mut strength) = match web::block(move || {
+        let strength = evaluate_password(
+            &policy,
+            &password,
+            &user_id.as_deref().into_iter().collect::<Vec<_>>(),
+        );
+        (password, user_id, strength)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if let Some(user_id) = &user_id {
+        if is_authenticated_as(&data, &http_request, user_id).await {
+            data.bind_throttle
+                .record_failure(
+                    http_request.peer_addr().map(|address| address.ip()),
+                    Some(user_id.as_str()),
+                )
+                .await;
+            match data
+                .backend_handler
+                .is_password_reused(user_id, &password)
+                .await
+            {
+                Ok(false) => (),
+                Ok(true) => strength
+                    .violations
+                    .push("not one of the recently used passwords".to_string()),
+                Err(e) => return error_to_http_response(e),
+            }
+        }
+    }
+    HttpResponse::Ok().json(strength)
+}
+
 pub struct CookieToHeaderTranslatorFactory;
 
 impl<S> Transform<S, ServiceRequest> for CookieToHeaderTranslatorFactory
@@ -340,58 +905,48 @@ where
     }
 }
 
-pub struct ValidationResults {
-    pub user: String,
-    pub is_admin: bool,
-}
-
-impl ValidationResults {
-    #[cfg(test)]
-    pub fn admin() -> Self {
-        Self {
-            user: "admin".to_string(),
-            is_admin: true,
-        }
-    }
-
-    pub fn can_access(&self, user: &str) -> bool {
-        self.is_admin || self.user == user
-    }
-}
-
-pub(crate) fn check_if_token_is_valid<Backend>(
+/// Checks the JWT or the API token, and returns the permissions it gives.
+pub(crate) async fn check_if_token_is_valid<Backend>(
     state: &AppState<Backend>,
     token_str: &str,
-) -> Result<ValidationResults, actix_web::Error> {
-    let token: Token<_> = VerifyWithKey::verify_with_key(token_str, &state.jwt_key)
+) -> Result<ValidationResults, actix_web::Error>
+where
+    Backend: BackendHandler + TcpBackendHandler,
+{
+    if is_api_token(token_str) {
+        return validate_api_token(&state.backend_handler, token_str)
+            .await
+            .map_err(|e| match e {
+                DomainError::AuthenticationError(_) => ErrorUnauthorized(e.to_string()),
+                _ => ErrorInternalServerError(e.to_string()),
+            });
+    }
+    // Also refuses the algorithms other than the one of the key.
+    let token: Token<_> = state
+        .jwt_keys
+        .verify(token_str)
         .map_err(|_| ErrorUnauthorized("Invalid JWT"))?;
     if token.claims().exp.lt(&Utc::now()) {
         return Err(ErrorUnauthorized("Expired JWT"));
     }
-    if token.header().algorithm != jwt::AlgorithmType::Hs512 {
-        return Err(ErrorUnauthorized(format!(
-            "Unsupported JWT algorithm: '{:?}'. Supported ones are: ['HS512']",
-            token.header().algorithm
-        )));
-    }
-    let jwt_hash = {
-        let mut s = DefaultHasher::new();
-        token_str.hash(&mut s);
-        s.finish()
-    };
-    if state.jwt_blacklist.read().unwrap().contains(&jwt_hash) {
+    if state
+        .jwt_blacklist
+        .read()
+        .unwrap()
+        .contains(&get_jwt_hash(token_str))
+    {
         return Err(ErrorUnauthorized("JWT was logged out"));
     }
-    let is_admin = token.claims().groups.contains("lldap_admin");
     Ok(ValidationResults {
         user: token.claims().user.clone(),
-        is_admin,
+        permission: Permission::from_groups(token.claims().groups.iter().map(String::as_str)),
+        impersonator: token.claims().impersonator.clone(),
     })
 }
 
 pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
 where
-    Backend: TcpBackendHandler + LoginHandler + OpaqueHandler + BackendHandler + 'static,
+    Backend: TcpBackendHandler + LoginHandler + OpaqueHandler + BackendHandler + Sync + 'static,
 {
     cfg.service(web::resource("").route(web::post().to(post_authorize::<Backend>)))
         .service(
@@ -404,12 +959,97 @@ where
         )
         .service(
             web::resource("/opaque/register/start")
+                .wrap(CookieToHeaderTranslatorFactory)
                 .route(web::post().to(opaque_register_start::<Backend>)),
         )
         .service(
             web::resource("/opaque/register/finish")
                 .route(web::post().to(opaque_register_finish::<Backend>)),
         )
+        .service(web::resource("/oidc").route(web::get().to(get_oidc_provider::<Backend>)))
+        .service(web::resource("/oidc/login").route(web::get().to(get_oidc_login::<Backend>)))
+        .service(web::resource("/oidc/callback").route(web::get().to(get_oidc_callback::<Backend>)))
+        .service(
+            web::resource("/reset/step1/{user_id}")
+                .route(web::post().to(post_password_reset_step1::<Backend>)),
+        )
+        .service(
+            web::resource("/reset/step2/{token}")
+                .route(web::post().to(post_password_reset_step2::<Backend>)),
+        )
+        .service(
+            web::resource("/password/strength")
+                .wrap(CookieToHeaderTranslatorFactory)
+                .route(web::post().to(post_password_strength::<Backend>)),
+        )
         .service(web::resource("/refresh").route(web::get().to(get_refresh::<Backend>)))
         .service(web::resource("/logout").route(web::get().to(get_logout::<Backend>)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::predicate::eq;
+
+    #[tokio::test]
+    async fn test_start_password_reset_for_user() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users().returning(|_| {
+            Ok(vec![User {
+                user_id: "bob".to_string(),
+                email: "bob@example.com".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_get_user_details()
+            .with(eq("alice"))
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        mock.expect_start_password_reset()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(Some("token".to_string())));
+        let (user, token) = start_password_reset_for_user(&mock, "Bob@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.user_id, "bob");
+        assert_eq!(token, "token");
+        // Unknown users don't get a token.
+        assert!(start_password_reset_for_user(&mock, "alice")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(start_password_reset_for_user(&mock, "alice@example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_consume_password_reset_token() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_user_id_for_password_reset_token()
+            .with(eq("token"))
+            .return_once(|_| Ok("bob".to_string()));
+        mock.expect_delete_password_reset_token()
+            .with(eq("token"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_user_id_for_password_reset_token()
+            .with(eq("expired"))
+            .return_once(|_| {
+                Err(DomainError::AuthenticationError(
+                    "Invalid or expired password reset token".to_string(),
+                ))
+            });
+        assert_eq!(
+            consume_password_reset_token(&mock, "token").await.unwrap(),
+            "bob"
+        );
+        // The expired or unknown tokens are refused, and not deleted.
+        assert!(matches!(
+            consume_password_reset_token(&mock, "expired").await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+}
@@ -0,0 +1,346 @@
+//! Validation and normalization of the avatars uploaded through the API, before they reach the
+//! database, and the endpoint serving them.
+
+use crate::{
+    domain::handler::BackendHandler,
+    infra::{
+        access_control::AccessControlledBackendHandler,
+        auth_service::check_if_token_is_valid,
+        configuration::{AvatarConfig, AvatarFormat},
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::{error_to_http_response, AppState},
+    },
+};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use image::{
+    codecs,
+    imageops::FilterType,
+    io::{Limits, Reader},
+    ColorType, DynamicImage, GenericImageView,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AvatarError {
+    #[error("Invalid avatar: not valid base64")]
+    InvalidBase64,
+    #[error("Avatar too large: {size} bytes, the maximum is {max} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("Invalid avatar: not a recognized image format")]
+    UnknownFormat,
+    #[error("Avatar type `{0}` is not allowed")]
+    TypeNotAllowed(&'static str),
+    #[error("Invalid avatar: {0}")]
+    InvalidImage(String),
+}
+
+impl From<image::ImageError> for AvatarError {
+    fn from(e: image::ImageError) -> Self {
+        AvatarError::InvalidImage(e.to_string())
+    }
+}
+
+/// The MIME type of the image, guessed from its first bytes.
+pub fn detect_mime_type(image: &[u8]) -> Option<&'static str> {
+    if image.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if image.len() >= 12 && image.starts_with(b"RIFF") && &image[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Decodes the base64-encoded avatar and checks it against the configured limits.
+pub fn decode_and_validate(encoded: &str, config: &AvatarConfig) -> Result<Vec<u8>, AvatarError> {
+    // Reject obviously oversized payloads before decoding them.
+    let estimated_size = encoded.len() / 4 * 3;
+    if estimated_size > config.max_size_bytes + 3 {
+        return Err(AvatarError::TooLarge {
+            size: estimated_size,
+            max: config.max_size_bytes,
+        });
+    }
+    let image = base64::decode(encoded).map_err(|_| AvatarError::InvalidBase64)?;
+    if image.len() > config.max_size_bytes {
+        return Err(AvatarError::TooLarge {
+            size: image.len(),
+            max: config.max_size_bytes,
+        });
+    }
+    let mime_type = detect_mime_type(&image).ok_or(AvatarError::UnknownFormat)?;
+    if !config.allowed_types.iter().any(|t| t == mime_type) {
+        return Err(AvatarError::TypeNotAllowed(mime_type));
+    }
+    Ok(image)
+}
+
+/// Crops the image to a centered square and scales it to the configured size.
+fn make_square(image: DynamicImage, size: u32) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    let square = image.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+    if side == size {
+        square
+    } else {
+        square.resize_exact(size, size, FilterType::Lanczos3)
+    }
+}
+
+/// The largest width and height of the uploaded images. A small file can declare huge
+/// dimensions, which the decoder would allocate.
+const MAX_IMAGE_DIMENSION: u32 = 4096;
+
+/// The most memory the decoder can allocate, enough for a [`MAX_IMAGE_DIMENSION`] square image.
+const MAX_DECODER_ALLOCATION: u64 = 4 * (MAX_IMAGE_DIMENSION as u64) * (MAX_IMAGE_DIMENSION as u64);
+
+/// Decodes the image, within the [`MAX_IMAGE_DIMENSION`] and [`MAX_DECODER_ALLOCATION`] limits.
+fn decode(image: &[u8]) -> Result<DynamicImage, AvatarError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODER_ALLOCATION);
+    let mut reader = Reader::new(std::io::Cursor::new(image))
+        .with_guessed_format()
+        .map_err(|e| AvatarError::InvalidImage(e.to_string()))?;
+    reader.limits(limits);
+    Ok(reader.decode()?)
+}
+
+/// Decodes the image and re-encodes it in the configured format and size. Only the pixels are
+/// kept: the metadata (EXIF with the location, camera, ...) is dropped.
+pub fn normalize(image: &[u8], config: &AvatarConfig) -> Result<Vec<u8>, AvatarError> {
+    let image = make_square(decode(image)?, config.size);
+    let mut output = Vec::new();
+    match config.format {
+        AvatarFormat::Jpeg => {
+            codecs::jpeg::JpegEncoder::new_with_quality(&mut output, config.jpeg_quality)
+                .encode_image(&image.to_rgb8())?
+        }
+        AvatarFormat::Webp => {
+            let pixels = image.to_rgba8();
+            codecs::webp::WebPEncoder::new_lossless(&mut output).encode(
+                pixels.as_raw(),
+                pixels.width(),
+                pixels.height(),
+                ColorType::Rgba8,
+            )?
+        }
+    }
+    Ok(output)
+}
+
+/// Validates the base64-encoded avatar, and normalizes it for storage. The decoding and the
+/// resizing take a while: they run on the blocking threads, not on the ones serving the requests.
+pub async fn process(encoded: String, config: AvatarConfig) -> Result<Vec<u8>, AvatarError> {
+    tokio::task::spawn_blocking(move || {
+        normalize(&decode_and_validate(&encoded, &config)?, &config)
+    })
+    .await
+    .map_err(|e| AvatarError::InvalidImage(e.to_string()))?
+}
+
+/// The entity tag of the avatar, a hash of its content.
+fn get_etag(avatar: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(avatar))
+}
+
+/// Whether the `If-None-Match` header of the request matches the entity tag.
+fn is_cached(request: &HttpRequest, etag: &str) -> bool {
+    request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |tags| {
+            tags.split(',')
+                .map(str::trim)
+                .any(|tag| tag == etag || tag == "*")
+        })
+}
+
+/// Serves the avatar of the user to the users allowed to read their details. The browsers keep it
+/// for an hour, then revalidate it with its `ETag`.
+pub(crate) async fn get_avatar<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+    user_id: web::Path<String>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let validation_result = check_if_token_is_valid(&data, bearer.token()).await?;
+    let handler =
+        AccessControlledBackendHandler::new(data.backend_handler.clone(), validation_result);
+    let avatar = match handler.get_user_avatar(&user_id).await {
+        Ok(Some(avatar)) => avatar,
+        Ok(None) => return Ok(HttpResponse::NotFound().body("The user has no avatar")),
+        Err(e) => return Ok(error_to_http_response(e)),
+    };
+    let etag = get_etag(&avatar);
+    let cached = is_cached(&request, &etag);
+    let mut response = if cached {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::ETAG, etag.as_str()))
+        .insert_header((header::CACHE_CONTROL, "private, max-age=3600"));
+    Ok(if cached {
+        response.finish()
+    } else {
+        response
+            .content_type(detect_mime_type(&avatar).unwrap_or("application/octet-stream"))
+            .body(avatar)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn test_detect_mime_type() {
+        assert_eq!(detect_mime_type(PNG_HEADER), Some("image/png"));
+        assert_eq!(
+            detect_mime_type(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(
+            detect_mime_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(detect_mime_type(b"<svg></svg>"), None);
+    }
+
+    #[test]
+    fn test_decode_and_validate() {
+        let config = AvatarConfig {
+            max_size_bytes: 32,
+            allowed_types: vec!["image/png".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_and_validate(&base64::encode(PNG_HEADER), &config),
+            Ok(PNG_HEADER.to_vec())
+        );
+        assert_eq!(
+            decode_and_validate("not base64!", &config),
+            Err(AvatarError::InvalidBase64)
+        );
+        assert_eq!(
+            decode_and_validate(&base64::encode(&[0u8; 100]), &config),
+            Err(AvatarError::TooLarge { size: 102, max: 32 })
+        );
+        assert_eq!(
+            decode_and_validate(&base64::encode(b"hello"), &config),
+            Err(AvatarError::UnknownFormat)
+        );
+        assert_eq!(
+            decode_and_validate(&base64::encode(b"GIF89a...."), &config),
+            Err(AvatarError::TypeNotAllowed("image/gif"))
+        );
+    }
+
+    fn encode_png(image: &image::RgbImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(image.clone())
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_normalize() {
+        // Red on the left, blue on the right, so that cropping keeps the middle.
+        let image = image::RgbImage::from_fn(60, 20, |x, _| {
+            if x < 30 {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 255])
+            }
+        });
+        let config = AvatarConfig {
+            size: 10,
+            ..Default::default()
+        };
+        let normalized = normalize(&encode_png(&image), &config).unwrap();
+        assert_eq!(detect_mime_type(&normalized), Some("image/jpeg"));
+        let decoded = image::load_from_memory(&normalized).unwrap();
+        assert_eq!(decoded.dimensions(), (10, 10));
+
+        let config = AvatarConfig {
+            size: 10,
+            format: AvatarFormat::Webp,
+            ..Default::default()
+        };
+        let normalized = normalize(&encode_png(&image), &config).unwrap();
+        assert_eq!(detect_mime_type(&normalized), Some("image/webp"));
+        let decoded = image::load_from_memory(&normalized).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (10, 10));
+        let is_red = |image::Rgb([r, _, b]): image::Rgb<u8>| r > 200 && b < 50;
+        assert!(is_red(*decoded.get_pixel(0, 5)));
+        assert!(!is_red(*decoded.get_pixel(9, 5)));
+    }
+
+    #[test]
+    fn test_normalize_too_large_image() {
+        let image = image::RgbImage::new(MAX_IMAGE_DIMENSION + 1, 1);
+        assert!(matches!(
+            normalize(&encode_png(&image), &AvatarConfig::default()),
+            Err(AvatarError::InvalidImage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process() {
+        let image = image::RgbImage::new(20, 20);
+        let config = AvatarConfig {
+            size: 10,
+            ..Default::default()
+        };
+        let processed = process(base64::encode(encode_png(&image)), config)
+            .await
+            .unwrap();
+        assert_eq!(detect_mime_type(&processed), Some("image/jpeg"));
+        assert_eq!(
+            process("not base64!".to_string(), AvatarConfig::default()).await,
+            Err(AvatarError::InvalidBase64)
+        );
+    }
+
+    #[test]
+    fn test_normalize_invalid_image() {
+        assert!(matches!(
+            normalize(PNG_HEADER, &AvatarConfig::default()),
+            Err(AvatarError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_cached() {
+        use actix_web::test::TestRequest;
+        let etag = get_etag(b"avatar");
+        assert!(!is_cached(&TestRequest::default().to_http_request(), &etag));
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, format!("\"other\", {}", etag)))
+            .to_http_request();
+        assert!(is_cached(&request, &etag));
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .to_http_request();
+        assert!(!is_cached(&request, &etag));
+    }
+}
@@ -0,0 +1,423 @@
+//! Backs up the database to a `.tar.gz` archive, and restores it.
+//!
+//! The SQLite databases are snapshotted with `VACUUM INTO`, which is consistent even while the
+//! server is writing, unlike a copy of the file. The PostgreSQL ones are dumped with `pg_dump`,
+//! which must be installed. The archive also holds a manifest with the version of the schema, so
+//! that a restore can refuse a backup from a newer version of LLDAP, or for another database.
+
+use crate::domain::sql_tables::{read_schema_version, DbBackend, PoolOptions, LAST_SCHEMA_VERSION};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const SQLITE_SNAPSHOT_FILE: &str = "lldap.db";
+const POSTGRES_DUMP_FILE: &str = "lldap.pgdump";
+
+/// Bumped when the layout of the archive changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// The description of a backup, stored next to the snapshot in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// The version of LLDAP that made the backup.
+    pub lldap_version: String,
+    pub schema_version: i32,
+    /// `sqlite` or `postgres`.
+    pub database: String,
+    pub creation_date: DateTime<Utc>,
+}
+
+fn database_name(backend: DbBackend) -> &'static str {
+    match backend {
+        DbBackend::Sqlite => "sqlite",
+        DbBackend::Postgres => "postgres",
+        DbBackend::Mysql => "mysql",
+    }
+}
+
+fn get_backend(database_url: &str) -> Result<DbBackend> {
+    match DbBackend::from_url(database_url) {
+        Some(DbBackend::Mysql) => bail!("The backups of MySQL databases are not supported"),
+        Some(backend) => Ok(backend),
+        None => bail!("Unsupported database_url"),
+    }
+}
+
+/// The file of a `sqlite://` URL.
+fn sqlite_path(database_url: &str) -> Result<PathBuf> {
+    let path = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))
+        .unwrap_or(database_url)
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    ensure!(
+        !path.is_empty() && path != ":memory:",
+        "The database_url doesn't point to a file"
+    );
+    Ok(PathBuf::from(path))
+}
+
+/// A temporary directory holding the content of the archive, removed when dropped.
+struct StagingDir(PathBuf);
+
+impl StagingDir {
+    fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("lldap-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&path).with_context(|| format!("while creating {}", path.display()))?;
+        Ok(Self(path))
+    }
+
+    fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn run(command: &mut Command) -> Result<()> {
+    let program = format!("{:?}", command.as_std().get_program());
+    let output = command
+        .output()
+        .await
+        .with_context(|| format!("while running {}", program))?;
+    ensure!(
+        output.status.success(),
+        "{} failed: {}",
+        program,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// Decodes the `%XX` escapes of a component of the `database_url`.
+fn percent_decode(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = iter.by_ref().take(2).map(char::from).collect::<String>();
+            ensure!(
+                hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+                "Invalid escape %{} in the database_url",
+                hex
+            );
+            bytes.push(u8::from_str_radix(&hex, 16)?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).context("The password of the database_url is not valid UTF-8")
+}
+
+/// A `pg_dump` or `pg_restore` command for the database. The password is moved from the URL to
+/// `PGPASSWORD`: unlike the environment, the command line is visible to all the users of the
+/// machine.
+fn postgres_command(program: &str, database_url: &str) -> Result<Command> {
+    let mut url = reqwest::Url::parse(database_url).context("Invalid database_url")?;
+    let mut command = Command::new(program);
+    if let Some(password) = url.password() {
+        command.env("PGPASSWORD", percent_decode(password)?);
+        url.set_password(None)
+            .map_err(|_| anyhow!("Invalid database_url"))?;
+    }
+    let (passwords, parameters): (Vec<_>, Vec<_>) = url
+        .query_pairs()
+        .into_owned()
+        .partition(|(name, _)| name == "password");
+    if let Some((_, password)) = passwords.into_iter().last() {
+        command.env("PGPASSWORD", password);
+        if parameters.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(parameters);
+        }
+    }
+    command.arg("--dbname").arg(url.as_str());
+    Ok(command)
+}
+
+fn quote_sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Writes the database and its manifest to the `.tar.gz` archive.
+pub async fn backup(database_url: &str, output: &Path) -> Result<Manifest> {
+    let backend = get_backend(database_url)?;
+    let staging = StagingDir::new()?;
+    let pool = PoolOptions::new()
+        .max_connections(1)
+        .connect(database_url)
+        .await?;
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        lldap_version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_version: read_schema_version(&pool).await?,
+        database: database_name(backend).to_string(),
+        creation_date: Utc::now(),
+    };
+    let snapshot_file = match backend {
+        DbBackend::Sqlite => {
+            let snapshot = staging.join(SQLITE_SNAPSHOT_FILE);
+            sqlx::query(&format!(
+                "VACUUM INTO {}",
+                quote_sql_string(&snapshot.to_string_lossy())
+            ))
+            .execute(&pool)
+            .await
+            .context("while snapshotting the database")?;
+            SQLITE_SNAPSHOT_FILE
+        }
+        _ => {
+            run(postgres_command("pg_dump", database_url)?
+                .arg("--format=custom")
+                .arg("--no-owner")
+                .arg("--file")
+                .arg(staging.join(POSTGRES_DUMP_FILE)))
+            .await?;
+            POSTGRES_DUMP_FILE
+        }
+    };
+    pool.close().await;
+    std::fs::write(
+        staging.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    run(Command::new("tar")
+        .arg("-czf")
+        .arg(output)
+        .arg("-C")
+        .arg(&staging.0)
+        .arg(MANIFEST_FILE)
+        .arg(snapshot_file))
+    .await?;
+    info!(
+        "Backed up the database at schema version {} to {}",
+        manifest.schema_version,
+        output.display()
+    );
+    Ok(manifest)
+}
+
+/// Checks that the backup can be restored by this version, in this database.
+fn validate_manifest(manifest: &Manifest, backend: DbBackend) -> Result<()> {
+    ensure!(
+        manifest.format_version == FORMAT_VERSION,
+        "Unsupported backup format version {}",
+        manifest.format_version
+    );
+    ensure!(
+        manifest.database == database_name(backend),
+        "The backup is of a {} database, but the database_url is for {}",
+        manifest.database,
+        database_name(backend)
+    );
+    ensure!(
+        manifest.schema_version <= LAST_SCHEMA_VERSION,
+        "The backup was made by LLDAP {}, at schema version {}, but this version only supports up \
+         to {}",
+        manifest.lldap_version,
+        manifest.schema_version,
+        LAST_SCHEMA_VERSION
+    );
+    Ok(())
+}
+
+/// Replaces the database with the content of the archive. The server must be stopped. An
+/// existing SQLite file is kept, with a `.before-restore` suffix.
+pub async fn restore(database_url: &str, input: &Path) -> Result<Manifest> {
+    let backend = get_backend(database_url)?;
+    let staging = StagingDir::new()?;
+    run(Command::new("tar")
+        .arg("-xzf")
+        .arg(input)
+        .arg("-C")
+        .arg(&staging.0))
+    .await?;
+    let manifest: Manifest = serde_json::from_str(
+        &std::fs::read_to_string(staging.join(MANIFEST_FILE))
+            .context("The archive has no manifest")?,
+    )
+    .context("while reading the manifest")?;
+    validate_manifest(&manifest, backend)?;
+    match backend {
+        DbBackend::Sqlite => {
+            let snapshot = staging.join(SQLITE_SNAPSHOT_FILE);
+            let snapshot_pool = PoolOptions::new()
+                .max_connections(1)
+                .connect(&format!("sqlite://{}", snapshot.display()))
+                .await
+                .context("while opening the snapshot")?;
+            let snapshot_version = read_schema_version(&snapshot_pool).await?;
+            snapshot_pool.close().await;
+            ensure!(
+                snapshot_version == manifest.schema_version,
+                "The snapshot is at schema version {}, but the manifest says {}",
+                snapshot_version,
+                manifest.schema_version
+            );
+            let target = sqlite_path(database_url)?;
+            if target.exists() {
+                let mut previous = target.clone().into_os_string();
+                previous.push(".before-restore");
+                std::fs::rename(&target, &previous)
+                    .with_context(|| format!("while moving away {}", target.display()))?;
+                info!("Moved the previous database to {:?}", previous);
+            }
+            for suffix in &["-wal", "-shm"] {
+                let mut journal = target.clone().into_os_string();
+                journal.push(suffix);
+                let _ = std::fs::remove_file(journal);
+            }
+            std::fs::copy(&snapshot, &target)
+                .with_context(|| format!("while writing {}", target.display()))?;
+        }
+        _ => {
+            run(postgres_command("pg_restore", database_url)?
+                .arg("--clean")
+                .arg("--if-exists")
+                .arg("--no-owner")
+                .arg("--single-transaction")
+                .arg(staging.join(POSTGRES_DUMP_FILE)))
+            .await?;
+        }
+    }
+    let pool = PoolOptions::new()
+        .max_connections(1)
+        .connect(database_url)
+        .await?;
+    let restored_version = read_schema_version(&pool).await?;
+    pool.close().await;
+    if restored_version != manifest.schema_version {
+        return Err(anyhow!(
+            "The restored database is at schema version {}, expected {}",
+            restored_version,
+            manifest.schema_version
+        ));
+    }
+    info!(
+        "Restored the backup of {} at schema version {}",
+        manifest.creation_date, manifest.schema_version
+    );
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::init_table;
+    use sqlx::Row;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_sqlite_path() {
+        assert_eq!(
+            sqlite_path("sqlite://users.db?mode=rwc").unwrap(),
+            PathBuf::from("users.db")
+        );
+        assert_eq!(
+            sqlite_path("sqlite:/data/users.db").unwrap(),
+            PathBuf::from("/data/users.db")
+        );
+        sqlite_path("sqlite::memory:").unwrap_err();
+    }
+
+    #[test]
+    fn test_postgres_command() {
+        let command = postgres_command(
+            "pg_dump",
+            "postgres://lldap:p%40ss%3Aword@db:5432/lldap?sslmode=require",
+        )
+        .unwrap();
+        let command = command.as_std();
+        assert_eq!(
+            command.get_args().map(OsStr::to_str).collect::<Vec<_>>(),
+            vec![
+                Some("--dbname"),
+                Some("postgres://lldap@db:5432/lldap?sslmode=require")
+            ]
+        );
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            vec![(OsStr::new("PGPASSWORD"), Some(OsStr::new("p@ss:word")))]
+        );
+        let command =
+            postgres_command("pg_restore", "postgres://lldap@db/lldap?password=secret").unwrap();
+        let command = command.as_std();
+        assert_eq!(
+            command.get_args().map(OsStr::to_str).collect::<Vec<_>>(),
+            vec![Some("--dbname"), Some("postgres://lldap@db/lldap")]
+        );
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            vec![(OsStr::new("PGPASSWORD"), Some(OsStr::new("secret")))]
+        );
+    }
+
+    #[test]
+    fn test_validate_manifest() {
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            lldap_version: "0.2.0".to_string(),
+            schema_version: LAST_SCHEMA_VERSION,
+            database: "sqlite".to_string(),
+            creation_date: Utc::now(),
+        };
+        validate_manifest(&manifest, DbBackend::Sqlite).unwrap();
+        validate_manifest(&manifest, DbBackend::Postgres).unwrap_err();
+        validate_manifest(
+            &Manifest {
+                schema_version: LAST_SCHEMA_VERSION + 1,
+                ..manifest
+            },
+            DbBackend::Sqlite,
+        )
+        .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let database_url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("users.db").display()
+        );
+        let pool = PoolOptions::new().connect(&database_url).await.unwrap();
+        init_table(&pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO groups (display_name, creation_date, uuid) \
+             VALUES ('family', '2021-01-01', 'abc')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let archive = dir.path().join("backup.tar.gz");
+        let manifest = backup(&database_url, &archive).await.unwrap();
+        assert_eq!(manifest.schema_version, LAST_SCHEMA_VERSION);
+        assert_eq!(manifest.database, "sqlite");
+
+        sqlx::query("DELETE FROM groups")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        assert_eq!(restore(&database_url, &archive).await.unwrap(), manifest);
+        assert!(dir.path().join("users.db.before-restore").exists());
+        let pool = PoolOptions::new().connect(&database_url).await.unwrap();
+        let row = sqlx::query("SELECT display_name FROM groups")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>("display_name"), "family");
+    }
+}
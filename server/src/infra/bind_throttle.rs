@@ -0,0 +1,423 @@
+//! Throttling of the failed LDAP binds and web logins, against brute-force attacks.
+//!
+//! The failures are counted per source address and per user: after
+//! [`max_failures`](BindThrottleConfig::max_failures) of them, the next attempts are refused until
+//! a delay, doubled by each new failure, has passed since the last one. The counts are shared by
+//! the LDAP and HTTP servers, in memory and optionally in the database.
+
+use crate::{
+    domain::sql_tables::{BindThrottles, BuildDbQuery, DbBackend, DbRow, Pool},
+    infra::configuration::BindThrottleConfig,
+};
+use chrono::{DateTime, Duration, Utc};
+use sea_query::{Expr, Query};
+use sqlx::Row;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// What the failures are counted for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ThrottleKey {
+    SourceIp(IpAddr),
+    /// The user ID, lowercase.
+    User(String),
+}
+
+impl ThrottleKey {
+    pub fn user(user_id: &str) -> Self {
+        ThrottleKey::User(user_id.to_lowercase())
+    }
+
+    fn parse(key: &str) -> Option<Self> {
+        match key.strip_prefix("ip:") {
+            Some(ip) => ip.parse().ok().map(ThrottleKey::SourceIp),
+            None => key
+                .strip_prefix("user:")
+                .map(|user_id| ThrottleKey::User(user_id.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for ThrottleKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThrottleKey::SourceIp(ip) => write!(f, "ip:{}", ip),
+            ThrottleKey::User(user_id) => write!(f, "user:{}", user_id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+/// An address or user that failed too many times.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lockout {
+    pub key: ThrottleKey,
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    /// `None` if the delay has passed: the next failure is throttled again, for longer.
+    pub blocked_until: Option<DateTime<Utc>>,
+}
+
+fn get_keys(source_ip: Option<IpAddr>, user_id: Option<&str>) -> Vec<ThrottleKey> {
+    source_ip
+        .map(ThrottleKey::SourceIp)
+        .into_iter()
+        .chain(user_id.map(ThrottleKey::user))
+        .collect()
+}
+
+/// The failures of the addresses and users, shared by all the LDAP connections and HTTP workers.
+#[derive(Clone, Debug, Default)]
+pub struct BindThrottle {
+    config: BindThrottleConfig,
+    failures: Arc<Mutex<HashMap<ThrottleKey, Failures>>>,
+    /// Where the failures are persisted, if they are.
+    sql_pool: Option<Pool>,
+}
+
+impl BindThrottle {
+    pub fn new(config: BindThrottleConfig) -> Self {
+        Self {
+            config,
+            failures: Default::default(),
+            sql_pool: None,
+        }
+    }
+
+    /// Keeps the failures in the database too, loading the ones that aren't forgotten yet.
+    pub async fn with_persistence(mut self, sql_pool: Pool) -> sqlx::Result<Self> {
+        let backend = DbBackend::of(&sql_pool);
+        delete_expired(&sql_pool, Utc::now() - self.reset_after()).await?;
+        let (query, values) = Query::select()
+            .column(BindThrottles::ThrottleKey)
+            .column(BindThrottles::Failures)
+            .column(BindThrottles::LastFailure)
+            .from(BindThrottles::Table)
+            .build_db_query(backend);
+        let rows = sqlx::query_with(&query, values)
+            .map(|row: DbRow| {
+                (
+                    row.get::<String, _>(&*BindThrottles::ThrottleKey.to_string()),
+                    row.get::<i32, _>(&*BindThrottles::Failures.to_string()),
+                    row.get::<DateTime<Utc>, _>(&*BindThrottles::LastFailure.to_string()),
+                )
+            })
+            .fetch_all(&sql_pool)
+            .await?;
+        {
+            let mut failures = self.failures.lock().unwrap();
+            for (key, count, last) in rows {
+                match ThrottleKey::parse(&key) {
+                    Some(key) => {
+                        failures.insert(
+                            key,
+                            Failures {
+                                count: count.max(0) as u32,
+                                last,
+                            },
+                        );
+                    }
+                    None => warn!("Ignoring the invalid bind throttling key `{}`", key),
+                }
+            }
+        }
+        self.sql_pool = Some(sql_pool);
+        Ok(self)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config.max_failures > 0
+    }
+
+    fn reset_after(&self) -> Duration {
+        Duration::seconds(self.config.reset_after_seconds.into())
+    }
+
+    /// How long the attempts are refused after the last of these failures, if they are.
+    fn get_delay(&self, count: u32) -> Option<Duration> {
+        if !self.is_enabled() || count < self.config.max_failures {
+            return None;
+        }
+        let exponent = (count - self.config.max_failures).min(31);
+        let delay = (u64::from(self.config.base_delay_seconds) << exponent)
+            .min(self.config.max_delay_seconds.into());
+        Some(Duration::seconds(delay as i64))
+    }
+
+    fn is_expired(&self, failures: &Failures, now: DateTime<Utc>) -> bool {
+        now - failures.last >= self.reset_after()
+    }
+
+    fn get_blocked_until(&self, failures: &Failures, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.is_expired(failures, now) {
+            return None;
+        }
+        self.get_delay(failures.count)
+            .map(|delay| failures.last + delay)
+            .filter(|blocked_until| *blocked_until > now)
+    }
+
+    /// Until when the attempts from the address or for the user are refused, if they are.
+    pub fn check(&self, source_ip: Option<IpAddr>, user_id: Option<&str>) -> Option<DateTime<Utc>> {
+        self.check_at(source_ip, user_id, Utc::now())
+    }
+
+    fn check_at(
+        &self,
+        source_ip: Option<IpAddr>,
+        user_id: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let failures = self.failures.lock().unwrap();
+        get_keys(source_ip, user_id)
+            .iter()
+            .filter_map(|key| failures.get(key))
+            .filter_map(|failures| self.get_blocked_until(failures, now))
+            .max()
+    }
+
+    /// Counts a failure of the address and of the user.
+    pub async fn record_failure(&self, source_ip: Option<IpAddr>, user_id: Option<&str>) {
+        self.record_failure_at(source_ip, user_id, Utc::now()).await
+    }
+
+    async fn record_failure_at(
+        &self,
+        source_ip: Option<IpAddr>,
+        user_id: Option<&str>,
+        now: DateTime<Utc>,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let updated = {
+            let mut failures = self.failures.lock().unwrap();
+            // The map would otherwise grow with every address that ever failed.
+            failures.retain(|_, failures| !self.is_expired(failures, now));
+            get_keys(source_ip, user_id)
+                .into_iter()
+                .map(|key| {
+                    let failures = failures.entry(key.clone()).or_insert(Failures {
+                        count: 0,
+                        last: now,
+                    });
+                    failures.count += 1;
+                    failures.last = now;
+                    (key, *failures)
+                })
+                .collect::<Vec<_>>()
+        };
+        if let Some(sql_pool) = &self.sql_pool {
+            if let Err(e) = delete_expired(sql_pool, now - self.reset_after()).await {
+                warn!("Could not delete the expired bind failures: {}", e);
+            }
+            for (key, failures) in updated {
+                if let Err(e) = store(sql_pool, &key, Some(failures)).await {
+                    warn!("Could not persist the bind failures of {}: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Forgets the failures of the user, once they logged in. The ones of the address are kept.
+    pub async fn record_success(&self, user_id: &str) {
+        self.clear(&ThrottleKey::user(user_id)).await;
+    }
+
+    /// Forgets the failures of the address or user. Returns whether there were any.
+    pub async fn clear(&self, key: &ThrottleKey) -> bool {
+        if self.failures.lock().unwrap().remove(key).is_none() {
+            return false;
+        }
+        if let Some(sql_pool) = &self.sql_pool {
+            if let Err(e) = store(sql_pool, key, None).await {
+                warn!("Could not delete the bind failures of {}: {}", key, e);
+            }
+        }
+        true
+    }
+
+    /// The addresses and users that reached the maximum number of failures, the last to fail
+    /// first.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = Utc::now();
+        let mut lockouts = self
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, failures)| {
+                self.get_delay(failures.count).is_some() && !self.is_expired(failures, now)
+            })
+            .map(|(key, failures)| Lockout {
+                key: key.clone(),
+                failures: failures.count,
+                last_failure: failures.last,
+                blocked_until: self.get_blocked_until(failures, now),
+            })
+            .collect::<Vec<_>>();
+        lockouts.sort_by(|a, b| b.last_failure.cmp(&a.last_failure));
+        lockouts
+    }
+}
+
+async fn delete_expired(sql_pool: &Pool, before: DateTime<Utc>) -> sqlx::Result<()> {
+    let (query, values) = Query::delete()
+        .from_table(BindThrottles::Table)
+        .and_where(Expr::col(BindThrottles::LastFailure).lt(before.naive_utc()))
+        .build_db_query(DbBackend::of(sql_pool));
+    sqlx::query_with(&query, values).execute(sql_pool).await?;
+    Ok(())
+}
+
+/// Replaces the failures of the key in the database, or deletes them.
+async fn store(sql_pool: &Pool, key: &ThrottleKey, failures: Option<Failures>) -> sqlx::Result<()> {
+    let backend = DbBackend::of(sql_pool);
+    let mut transaction = sql_pool.begin().await?;
+    let (query, values) = Query::delete()
+        .from_table(BindThrottles::Table)
+        .and_where(Expr::col(BindThrottles::ThrottleKey).eq(key.to_string()))
+        .build_db_query(backend);
+    sqlx::query_with(&query, values)
+        .execute(&mut transaction)
+        .await?;
+    if let Some(failures) = failures {
+        let (query, values) = Query::insert()
+            .into_table(BindThrottles::Table)
+            .columns(vec![
+                BindThrottles::ThrottleKey,
+                BindThrottles::Failures,
+                BindThrottles::LastFailure,
+            ])
+            .values_panic(vec![
+                key.to_string().into(),
+                (failures.count as i32).into(),
+                failures.last.naive_utc().into(),
+            ])
+            .build_db_query(backend);
+        sqlx::query_with(&query, values)
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::{init_table, PoolOptions};
+    use chrono::TimeZone;
+
+    fn get_throttle() -> BindThrottle {
+        BindThrottle::new(BindThrottleConfig {
+            max_failures: 3,
+            base_delay_seconds: 10,
+            max_delay_seconds: 60,
+            reset_after_seconds: 3600,
+            persist: false,
+        })
+    }
+
+    fn ip() -> Option<IpAddr> {
+        Some("10.0.0.1".parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_exponential_backoff() {
+        let throttle = get_throttle();
+        let start = Utc.timestamp(1_000_000, 0);
+        for _ in 0..2 {
+            throttle.record_failure_at(ip(), Some("bob"), start).await;
+        }
+        assert_eq!(throttle.check_at(ip(), Some("bob"), start), None);
+        throttle.record_failure_at(ip(), Some("bob"), start).await;
+        let blocked_until = start + Duration::seconds(10);
+        assert_eq!(
+            throttle.check_at(None, Some("Bob"), start),
+            Some(blocked_until)
+        );
+        assert_eq!(throttle.check_at(ip(), None, start), Some(blocked_until));
+        assert_eq!(throttle.check_at(None, Some("alice"), start), None);
+        assert_eq!(throttle.check_at(ip(), Some("bob"), blocked_until), None);
+        // Each new failure doubles the delay, up to the maximum.
+        throttle.record_failure_at(ip(), None, blocked_until).await;
+        assert_eq!(
+            throttle.check_at(ip(), None, blocked_until),
+            Some(blocked_until + Duration::seconds(20))
+        );
+        for _ in 0..5 {
+            throttle.record_failure_at(ip(), None, blocked_until).await;
+        }
+        assert_eq!(
+            throttle.check_at(ip(), None, blocked_until),
+            Some(blocked_until + Duration::seconds(60))
+        );
+        // The failures are forgotten after a while.
+        assert_eq!(
+            throttle.check_at(ip(), Some("bob"), start + Duration::hours(2)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_success_and_clear() {
+        let throttle = get_throttle();
+        for _ in 0..3 {
+            throttle.record_failure(ip(), Some("bob")).await;
+        }
+        assert_eq!(throttle.lockouts().len(), 2);
+        throttle.record_success("bob").await;
+        assert!(throttle.check(None, Some("bob")).is_none());
+        assert!(throttle.check(ip(), None).is_some());
+        let lockouts = throttle.lockouts();
+        assert_eq!(lockouts.len(), 1);
+        assert_eq!(lockouts[0].key, ThrottleKey::SourceIp(ip().unwrap()));
+        assert_eq!(lockouts[0].failures, 3);
+        assert!(throttle.clear(&lockouts[0].key).await);
+        assert!(!throttle.clear(&lockouts[0].key).await);
+        assert!(throttle.check(ip(), Some("bob")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let throttle = BindThrottle::new(BindThrottleConfig {
+            max_failures: 0,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            throttle.record_failure(ip(), Some("bob")).await;
+        }
+        assert!(throttle.check(ip(), Some("bob")).is_none());
+        assert!(throttle.lockouts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let throttle = get_throttle()
+            .with_persistence(sql_pool.clone())
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            throttle.record_failure(ip(), Some("bob")).await;
+        }
+        throttle.clear(&ThrottleKey::user("bob")).await;
+        // Like after a restart.
+        let throttle = get_throttle().with_persistence(sql_pool).await.unwrap();
+        assert!(throttle.check(ip(), None).is_some());
+        assert!(throttle.check(None, Some("bob")).is_none());
+    }
+}
@@ -0,0 +1,266 @@
+//! Reconciles the database with the users, groups and memberships declared in the `bootstrap`
+//! section of the configuration: what's missing is created, what differs is updated, and with
+//! `prune` what isn't declared is deleted.
+
+use crate::{
+    domain::{
+        handler::{BackendHandler, CreateUserRequest, GroupId, UpdateUserRequest, User},
+        opaque_handler::OpaqueHandler,
+        sql_opaque_handler::register_password,
+    },
+    infra::{
+        access_control::ADMIN_GROUP,
+        configuration::{BootstrapConfig, BootstrapUser},
+    },
+};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The changes made to the database.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BootstrapReport {
+    pub users_created: Vec<String>,
+    pub users_updated: Vec<String>,
+    pub users_deleted: Vec<String>,
+    pub groups_created: Vec<String>,
+    pub groups_deleted: Vec<String>,
+    /// (user, group) pairs.
+    pub memberships_added: Vec<(String, String)>,
+    pub memberships_removed: Vec<(String, String)>,
+}
+
+impl BootstrapReport {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The fields of the user that differ from the declaration, if any.
+fn get_user_update(current: &User, declared: &BootstrapUser) -> Option<UpdateUserRequest> {
+    let changed = |current: &String, declared: &Option<String>| match declared {
+        Some(value) if value != current => Some(value.clone()),
+        _ => None,
+    };
+    let request = UpdateUserRequest {
+        user_id: current.user_id.clone(),
+        email: changed(&current.email, &Some(declared.email.clone())),
+        display_name: changed(&current.display_name, &declared.display_name),
+        first_name: changed(&current.first_name, &declared.first_name),
+        last_name: changed(&current.last_name, &declared.last_name),
+        avatar: None,
+    };
+    if request.email.is_none()
+        && request.display_name.is_none()
+        && request.first_name.is_none()
+        && request.last_name.is_none()
+    {
+        None
+    } else {
+        Some(request)
+    }
+}
+
+pub async fn reconcile<Handler: BackendHandler + OpaqueHandler>(
+    handler: &Handler,
+    config: &BootstrapConfig,
+    admin_user_id: &str,
+) -> Result<BootstrapReport> {
+    let mut report = BootstrapReport::default();
+
+    let declared_groups = config
+        .groups
+        .iter()
+        .chain(config.users.iter().flat_map(|u| u.groups.iter()))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let mut groups = handler
+        .list_groups(None)
+        .await?
+        .into_iter()
+        .map(|g| (g.display_name, (g.id, g.users)))
+        .collect::<BTreeMap<_, _>>();
+    for name in &declared_groups {
+        if !groups.contains_key(name) {
+            let id = handler
+                .create_group(name)
+                .await
+                .with_context(|| format!("while creating group {}", name))?;
+            groups.insert(name.clone(), (id, Vec::new()));
+            report.groups_created.push(name.clone());
+        }
+    }
+
+    let users = handler
+        .list_users(None)
+        .await?
+        .into_iter()
+        .map(|u| (u.user_id.clone(), u))
+        .collect::<HashMap<_, _>>();
+    for declared in &config.users {
+        match users.get(&declared.user_id) {
+            Some(current) => {
+                if let Some(request) = get_user_update(current, declared) {
+                    handler
+                        .update_user(request)
+                        .await
+                        .with_context(|| format!("while updating user {}", declared.user_id))?;
+                    report.users_updated.push(declared.user_id.clone());
+                }
+            }
+            None => {
+                handler
+                    .create_user(CreateUserRequest {
+                        user_id: declared.user_id.clone(),
+                        email: declared.email.clone(),
+                        display_name: declared.display_name.clone(),
+                        first_name: declared.first_name.clone(),
+                        last_name: declared.last_name.clone(),
+                    })
+                    .await
+                    .with_context(|| format!("while creating user {}", declared.user_id))?;
+                if let Some(password) = &declared.password {
+                    register_password(handler, &declared.user_id, password)
+                        .await
+                        .with_context(|| {
+                            format!("while setting the password of {}", declared.user_id)
+                        })?;
+                }
+                report.users_created.push(declared.user_id.clone());
+            }
+        }
+    }
+
+    let group_id = |name: &str| -> GroupId { groups[name].0 };
+    for declared in &config.users {
+        let current = groups
+            .iter()
+            .filter(|(_, (_, members))| members.contains(&declared.user_id))
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+        let wanted = declared.groups.iter().cloned().collect::<BTreeSet<_>>();
+        for group in wanted.difference(&current) {
+            handler
+                .add_user_to_group(&declared.user_id, group_id(group))
+                .await?;
+            report
+                .memberships_added
+                .push((declared.user_id.clone(), group.clone()));
+        }
+        if config.prune {
+            for group in current.difference(&wanted) {
+                if declared.user_id == admin_user_id && group == ADMIN_GROUP {
+                    continue;
+                }
+                handler
+                    .remove_user_from_group(&declared.user_id, group_id(group))
+                    .await?;
+                report
+                    .memberships_removed
+                    .push((declared.user_id.clone(), group.clone()));
+            }
+        }
+    }
+
+    if config.prune {
+        let declared_users = config
+            .users
+            .iter()
+            .map(|u| u.user_id.as_str())
+            .collect::<BTreeSet<_>>();
+        let mut extra_users = users
+            .keys()
+            .filter(|u| *u != admin_user_id && !declared_users.contains(u.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        extra_users.sort();
+        for user_id in extra_users {
+            handler.delete_user(&user_id).await?;
+            report.users_deleted.push(user_id);
+        }
+        for (name, (id, _)) in &groups {
+            if name != ADMIN_GROUP && !declared_groups.contains(name) {
+                handler.delete_group(*id).await?;
+                report.groups_deleted.push(name.clone());
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::test_backend_handler::TestBackendHandler;
+
+    fn make_user(user_id: &str, groups: Vec<&str>) -> BootstrapUser {
+        BootstrapUser {
+            user_id: user_id.to_string(),
+            email: format!("{}@example.com", user_id),
+            display_name: None,
+            first_name: None,
+            last_name: None,
+            password: None,
+            groups: groups.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    fn setup_handler() -> TestBackendHandler {
+        let handler = TestBackendHandler::new();
+        handler.insert_user("admin", "admin@example.com", None);
+        handler.insert_user("bob", "bob@example.com", None);
+        let admin_group = handler.insert_group("lldap_admin");
+        let old_group = handler.insert_group("old");
+        handler.insert_membership("admin", admin_group);
+        handler.insert_membership("bob", old_group);
+        handler
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_creates_and_updates() {
+        let handler = setup_handler();
+        let mut bob = make_user("bob", vec!["dev"]);
+        bob.email = "bob@new.example.com".to_string();
+        let config = BootstrapConfig {
+            prune: false,
+            groups: vec!["ops".to_string()],
+            users: vec![bob, make_user("jim", vec!["dev", "ops"])],
+        };
+        let report = reconcile(&handler, &config, "admin").await.unwrap();
+        assert_eq!(report.groups_created, vec!["dev", "ops"]);
+        assert_eq!(report.users_created, vec!["jim"]);
+        assert_eq!(report.users_updated, vec!["bob"]);
+        assert_eq!(report.memberships_added.len(), 3);
+        assert!(report.memberships_removed.is_empty());
+        assert!(report.users_deleted.is_empty());
+        assert!(report.groups_deleted.is_empty());
+        assert_eq!(
+            handler.get_user_details("bob").await.unwrap().email,
+            "bob@new.example.com"
+        );
+        // Reconciling again is a no-op.
+        assert!(reconcile(&handler, &config, "admin")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_prune() {
+        let handler = setup_handler();
+        let config = BootstrapConfig {
+            prune: true,
+            groups: vec![],
+            users: vec![make_user("jim", vec!["dev"]), make_user("admin", vec![])],
+        };
+        let report = reconcile(&handler, &config, "admin").await.unwrap();
+        assert_eq!(report.users_deleted, vec!["bob"]);
+        assert_eq!(report.groups_deleted, vec!["old"]);
+        assert!(report.memberships_removed.is_empty());
+        let groups = handler.list_groups(None).await.unwrap();
+        let admin_group = groups
+            .iter()
+            .find(|g| g.display_name == "lldap_admin")
+            .unwrap();
+        assert_eq!(admin_group.users, vec!["admin"]);
+    }
+}
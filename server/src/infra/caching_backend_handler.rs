@@ -0,0 +1,643 @@
+use crate::{
+    domain::{error::*, handler::*, opaque_handler::*},
+    infra::{
+        configuration::CacheConfig,
+        metrics::Metrics,
+        tcp_backend_handler::{ApiToken, ApiTokenScope, Session, TcpBackendHandler},
+    },
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use lru::LruCache;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The values of a cache, by user ID, until they expire or the least recently used ones make room
+/// for new ones.
+struct TtlCache<V> {
+    entries: LruCache<String, (Instant, V)>,
+    ttl: Duration,
+}
+
+impl<V: Clone> TtlCache<V> {
+    fn new(config: &CacheConfig) -> Self {
+        Self {
+            entries: LruCache::new(config.max_entries),
+            ttl: Duration::from_secs(config.ttl_seconds),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        let expired = match self.entries.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => return Some(value.clone()),
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.entries.pop(key);
+        }
+        None
+    }
+
+    fn put(&mut self, key: &str, value: V) {
+        self.entries.put(key.to_string(), (Instant::now(), value));
+    }
+}
+
+struct Caches {
+    user_details: TtlCache<User>,
+    user_groups: TtlCache<HashSet<GroupIdAndName>>,
+    /// Incremented by each invalidation: a value read from the database before it is not cached,
+    /// since it may be outdated.
+    generation: u64,
+}
+
+/// A backend handler forwarding everything to `Backend`, but keeping the details and the groups
+/// of the users in memory: some LDAP clients ask for them on every search.
+///
+/// The modifications going through the handler invalidate the entries they affect; the others,
+/// e.g. made directly in the database, are only seen once the entries expire.
+#[derive(Clone)]
+pub struct CachingBackendHandler<Backend> {
+    inner: Backend,
+    /// `None` if the cache is disabled.
+    caches: Option<Arc<Mutex<Caches>>>,
+    metrics: Metrics,
+}
+
+impl<Backend> CachingBackendHandler<Backend> {
+    pub fn new(inner: Backend, config: &CacheConfig) -> Self {
+        let caches = if config.max_entries == 0 {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(Caches {
+                user_details: TtlCache::new(config),
+                user_groups: TtlCache::new(config),
+                generation: 0,
+            })))
+        };
+        Self {
+            inner,
+            caches,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn invalidate(&self, apply: impl FnOnce(&mut Caches)) {
+        if let Some(caches) = &self.caches {
+            let mut caches = caches.lock().unwrap();
+            caches.generation += 1;
+            apply(&mut caches);
+        }
+    }
+
+    fn invalidate_user(&self, user_id: &str) {
+        self.invalidate(|caches| {
+            caches.user_details.entries.pop(user_id);
+            caches.user_groups.entries.pop(user_id);
+        })
+    }
+
+    /// For the changes of the groups themselves, which can affect any user.
+    fn invalidate_all_groups(&self) {
+        self.invalidate(|caches| caches.user_groups.entries.clear())
+    }
+}
+
+#[async_trait]
+impl<Backend: LoginHandler + Sync> LoginHandler for CachingBackendHandler<Backend> {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        self.inner.bind(request).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for CachingBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        self.inner.list_users(filters).await
+    }
+
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        self.inner.list_users_stream(filters, order)
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.inner
+            .list_users_page(filters, order, direction, offset, limit)
+            .await
+    }
+
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        self.inner.count_users(filters).await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.inner.list_groups(filters).await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        self.inner.list_groups_page(filters, offset, limit).await
+    }
+
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        self.inner.list_groups_with_member_count().await
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        let caches = match &self.caches {
+            Some(caches) => caches,
+            None => return self.inner.get_user_details(user_id).await,
+        };
+        let (cached, generation) = {
+            let mut caches = caches.lock().unwrap();
+            (caches.user_details.get(user_id), caches.generation)
+        };
+        self.metrics
+            .record_cache_lookup("user_details", cached.is_some());
+        if let Some(user) = cached {
+            return Ok(user);
+        }
+        let user = self.inner.get_user_details(user_id).await?;
+        let mut caches = caches.lock().unwrap();
+        if caches.generation == generation {
+            caches.user_details.put(user_id, user.clone());
+        }
+        Ok(user)
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_user_avatar(user_id).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.inner.get_user_by_email(email).await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.inner.get_group_details(group_id).await
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        self.inner.get_parent_groups(group_id).await
+    }
+
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        self.inner.get_group_members(group_id, offset, limit).await
+    }
+
+    /// The groups of a user that doesn't exist are cached too (none), hence the invalidation.
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        let result = self.inner.create_user(request).await;
+        self.invalidate_user(&user_id);
+        result
+    }
+
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        let user_ids: Vec<String> = requests.iter().map(|r| r.user_id.clone()).collect();
+        let result = self.inner.bulk_create_users(requests).await;
+        for user_id in &user_ids {
+            self.invalidate_user(user_id);
+        }
+        result
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        let result = self.inner.update_user(request).await;
+        self.invalidate_user(&user_id);
+        result
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let result = self.inner.update_group(request).await;
+        self.invalidate_all_groups();
+        result
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        let result = self.inner.delete_user(user_id).await;
+        self.invalidate_user(user_id);
+        result
+    }
+
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        let result = self.inner.restore_user(user_id).await;
+        self.invalidate_user(user_id);
+        result
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        self.inner.list_deleted_users().await
+    }
+
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        let result = self.inner.rename_user(user_id, new_user_id).await;
+        self.invalidate_user(user_id);
+        self.invalidate_user(new_user_id);
+        result
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.inner.create_group(group_name).await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        let result = self.inner.delete_group(group_id).await;
+        self.invalidate_all_groups();
+        result
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        let result = self.inner.add_user_to_group(user_id, group_id).await;
+        self.invalidate_user(user_id);
+        result
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        let result = self.inner.remove_user_from_group(user_id, group_id).await;
+        self.invalidate_user(user_id);
+        result
+    }
+
+    /// The members of the group get the parent group through the nesting.
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let result = self
+            .inner
+            .add_group_to_group(member_group_id, parent_group_id)
+            .await;
+        self.invalidate_all_groups();
+        result
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        let result = self
+            .inner
+            .remove_group_from_group(member_group_id, parent_group_id)
+            .await;
+        self.invalidate_all_groups();
+        result
+    }
+
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let result = self.inner.add_users_to_group(group_id, user_ids).await;
+        for user_id in user_ids {
+            self.invalidate_user(user_id);
+        }
+        result
+    }
+
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let result = self.inner.remove_users_from_group(group_id, user_ids).await;
+        for user_id in user_ids {
+            self.invalidate_user(user_id);
+        }
+        result
+    }
+
+    /// The previous members aren't known here.
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        let result = self.inner.set_group_members(group_id, user_ids).await;
+        self.invalidate_all_groups();
+        result
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        let caches = match &self.caches {
+            Some(caches) => caches,
+            None => return self.inner.get_user_groups(user).await,
+        };
+        let (cached, generation) = {
+            let mut caches = caches.lock().unwrap();
+            (caches.user_groups.get(user), caches.generation)
+        };
+        self.metrics
+            .record_cache_lookup("user_groups", cached.is_some());
+        if let Some(groups) = cached {
+            return Ok(groups);
+        }
+        let groups = self.inner.get_user_groups(user).await?;
+        let mut caches = caches.lock().unwrap();
+        if caches.generation == generation {
+            caches.user_groups.put(user, groups.clone());
+        }
+        Ok(groups)
+    }
+
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        self.inner.get_users_groups(user_ids).await
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        self.inner.get_user_hosts(user_id).await
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        self.inner.set_user_hosts(user_id, hosts).await
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.inner.get_group_metadata(group_id).await
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        self.inner.get_group_mail(group_id).await
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        self.inner.set_group_mail(group_id, mail).await
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        self.inner.get_user_attributes(user_id).await
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.inner.set_user_attribute(user_id, name, values).await
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        self.inner.lock_user(user_id, reason).await
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.inner.unlock_user(user_id).await
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        self.inner.get_user_lock(user_id).await
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.inner.list_password_changes().await
+    }
+
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        self.inner.get_password_change(user_id).await
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.inner.record_auth_failure(failure).await
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        self.inner.list_auth_failures(user_id, offset, limit).await
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.inner.record_audit_event(event).await
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.inner.list_audit_events(offset, limit).await
+    }
+}
+
+#[async_trait]
+impl<Backend: OpaqueHandler + Sync> OpaqueHandler for CachingBackendHandler<Backend> {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.inner.login_start(request).await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        self.inner.login_finish(request).await
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.inner.registration_start(request).await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        self.inner.registration_finish(request).await
+    }
+
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        self.inner.is_password_reused(username, password).await
+    }
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync> TcpBackendHandler for CachingBackendHandler<Backend> {
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+        self.inner.get_jwt_blacklist().await
+    }
+
+    async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
+        self.inner.create_refresh_token(user).await
+    }
+
+    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
+        self.inner.check_token(refresh_token_hash, user).await
+    }
+
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.inner.register_jwt(user, jwt_hash, expiry_date).await
+    }
+
+    async fn blacklist_jwts(&self, user: &str) -> Result<HashSet<u64>> {
+        self.inner.blacklist_jwts(user).await
+    }
+
+    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
+        self.inner.delete_refresh_token(refresh_token_hash).await
+    }
+
+    async fn delete_refresh_tokens(&self, user: &str) -> Result<()> {
+        self.inner.delete_refresh_tokens(user).await
+    }
+
+    async fn list_sessions(&self, user: Option<&str>) -> Result<Vec<Session>> {
+        self.inner.list_sessions(user).await
+    }
+
+    async fn start_password_reset(&self, user: &str) -> Result<Option<String>> {
+        self.inner.start_password_reset(user).await
+    }
+
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<String> {
+        self.inner.get_user_id_for_password_reset_token(token).await
+    }
+
+    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
+        self.inner.delete_password_reset_token(token).await
+    }
+
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> Result<ApiToken> {
+        self.inner
+            .create_api_token(user, name, scope, token_hash)
+            .await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        self.inner.get_api_token(token_hash).await
+    }
+
+    async fn list_api_tokens(&self, user: Option<&str>) -> Result<Vec<ApiToken>> {
+        self.inner.list_api_tokens(user).await
+    }
+
+    async fn delete_api_token(&self, token_id: i32) -> Result<()> {
+        self.inner.delete_api_token(token_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::MockTestBackendHandler;
+    use mockall::predicate::eq;
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            max_entries: 10,
+            ttl_seconds: 60,
+        }
+    }
+
+    fn family() -> HashSet<GroupIdAndName> {
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName(GroupId(3), "family".to_string()));
+        groups
+    }
+
+    #[tokio::test]
+    async fn test_caches_until_modified() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq("bob"))
+            .times(2)
+            .returning(|_| Ok(family()));
+        mock.expect_get_user_details()
+            .with(eq("bob"))
+            .times(1)
+            .returning(|_| {
+                Ok(User {
+                    user_id: "bob".to_string(),
+                    ..User::default()
+                })
+            });
+        mock.expect_add_user_to_group()
+            .with(eq("bob"), eq(GroupId(4)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let handler = CachingBackendHandler::new(mock, &config());
+        assert_eq!(handler.get_user_groups("bob").await.unwrap(), family());
+        assert_eq!(handler.get_user_groups("bob").await.unwrap(), family());
+        handler.get_user_details("bob").await.unwrap();
+        handler.get_user_details("bob").await.unwrap();
+        handler.add_user_to_group("bob", GroupId(4)).await.unwrap();
+        // Fetched again.
+        handler.get_user_groups("bob").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expires() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .times(2)
+            .returning(|_| Ok(family()));
+        let handler = CachingBackendHandler::new(
+            mock,
+            &CacheConfig {
+                ttl_seconds: 0,
+                ..config()
+            },
+        );
+        handler.get_user_groups("bob").await.unwrap();
+        handler.get_user_groups("bob").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .times(2)
+            .returning(|_| Ok(family()));
+        let handler = CachingBackendHandler::new(
+            mock,
+            &CacheConfig {
+                max_entries: 0,
+                ..config()
+            },
+        );
+        handler.get_user_groups("bob").await.unwrap();
+        handler.get_user_groups("bob").await.unwrap();
+    }
+}
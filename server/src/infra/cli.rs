@@ -11,12 +11,40 @@ pub struct CLIOpts {
 
 #[derive(Debug, Clap, Clone)]
 pub enum Command {
-    /// Export the GraphQL schema to *.graphql.
-    #[clap(name = "export_graphql_schema")]
+    /// Export the GraphQL schema (SDL) of this version of the server to *.graphql.
+    #[clap(name = "export-graphql-schema", alias = "export_graphql_schema")]
     ExportGraphQLSchema(ExportGraphQLSchemaOpts),
     /// Run the LDAP and GraphQL server.
     #[clap(name = "run")]
     Run(RunOpts),
+    /// Copy the users, groups and memberships of another LDAP server to the database.
+    #[clap(name = "migrate")]
+    Migrate(MigrateOpts),
+    /// Fill the database with fake users, groups and memberships, for demos and tests.
+    #[clap(name = "seed")]
+    Seed(SeedOpts),
+    /// Create or update the users listed in a CSV, LDIF or JSON file, and the groups of an LDIF
+    /// file.
+    #[clap(name = "import")]
+    Import(ImportOpts),
+    /// Export the users, groups and memberships as LDIF, for backups and migrations.
+    #[clap(name = "export")]
+    Export(ExportOpts),
+    /// Snapshot the database to a .tar.gz archive.
+    #[clap(name = "backup")]
+    Backup(BackupOpts),
+    /// Replace the database with a backup. The server must be stopped.
+    #[clap(name = "restore")]
+    Restore(RestoreOpts),
+    /// Add, list or delete users.
+    #[clap(name = "user")]
+    User(UserCommand),
+    /// Add groups, or add users to them.
+    #[clap(name = "group")]
+    Group(GroupCommand),
+    /// Set the password of a user.
+    #[clap(name = "password")]
+    Password(PasswordCommand),
 }
 
 #[derive(Debug, Clap, Clone)]
@@ -33,6 +61,11 @@ pub struct RunOpts {
     #[clap(long)]
     pub ldaps_port: Option<u16>,
 
+    /// Reconcile the database with this TOML or YAML manifest of users and groups, instead of
+    /// the bootstrap section of the config file.
+    #[clap(long)]
+    pub bootstrap_file: Option<String>,
+
     /// Set verbose logging
     #[clap(short, long)]
     pub verbose: bool,
@@ -40,11 +73,304 @@ pub struct RunOpts {
 
 #[derive(Debug, Clap, Clone)]
 pub struct ExportGraphQLSchemaOpts {
-    /// Output to a file. If not specified, the config is printed to the standard output.
+    /// Output to a file. If not specified, the schema is printed to the standard output.
     #[clap(short, long)]
     pub output_file: Option<String>,
 }
 
+#[derive(Debug, Clap, Clone)]
+pub struct MigrateOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// URL of the source LDAP server, e.g. ldap://old-server:389
+    #[clap(long)]
+    pub from: String,
+
+    /// DN to bind with on the source server. Anonymous bind if not specified.
+    #[clap(long)]
+    pub bind_dn: Option<String>,
+
+    /// Password for the bind DN.
+    #[clap(long, default_value = "")]
+    pub bind_password: String,
+
+    /// Base DN of the users on the source server.
+    #[clap(long)]
+    pub users_dn: String,
+
+    /// Base DN of the groups on the source server.
+    #[clap(long)]
+    pub groups_dn: String,
+
+    /// LDAP filter selecting the users.
+    #[clap(
+        long,
+        default_value = "(|(objectClass=inetOrgPerson)(objectClass=posixAccount))"
+    )]
+    pub user_filter: String,
+
+    /// LDAP filter selecting the groups.
+    #[clap(
+        long,
+        default_value = "(|(objectClass=groupOfNames)(objectClass=groupOfUniqueNames)(objectClass=posixGroup))"
+    )]
+    pub group_filter: String,
+
+    /// Only print what would be imported, without changing the database.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct SeedOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// Number of users to create.
+    #[clap(long, default_value = "100")]
+    pub users: usize,
+
+    /// Number of groups to create. Each user is added to 1 to 3 of them.
+    #[clap(long, default_value = "10")]
+    pub groups: usize,
+
+    /// Password to set for all the users. The users have no password if not specified.
+    #[clap(long)]
+    pub password: Option<String>,
+
+    /// Seed of the random generator, to generate the same data every time.
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Csv,
+    Ldif,
+    Json,
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ImportFormat::Csv),
+            "ldif" => Ok(ImportFormat::Ldif),
+            "json" => Ok(ImportFormat::Json),
+            _ => Err(format!("Unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct ImportOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// File to import.
+    pub file: String,
+
+    /// Format of the file. If not specified, it is guessed from the extension.
+    #[clap(long, possible_values = &["csv", "ldif", "json"])]
+    pub format: Option<ImportFormat>,
+
+    /// Only print the action for each row, without changing the database.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Email the created users without a password a link to choose one. Requires the smtp
+    /// configuration.
+    #[clap(long)]
+    pub send_invitations: bool,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct ExportOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// Base DN of the exported entries. Defaults to the `ldap_base_dn` of the configuration.
+    #[clap(long)]
+    pub base_dn: Option<String>,
+
+    /// Output to a file. If not specified, the LDIF is printed to the standard output.
+    #[clap(short, long)]
+    pub output_file: Option<String>,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct BackupOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// Archive to write, e.g. backup.tar.gz.
+    #[clap(short, long)]
+    pub output: String,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct RestoreOpts {
+    /// Change config file name
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// Archive written by `lldap backup`.
+    pub input: String,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+/// Where the administration commands apply: a running server, or the database.
+#[derive(Debug, Clap, Clone)]
+pub struct AdminTargetOpts {
+    /// Change config file name, to go directly to the database.
+    #[clap(short, long, default_value = "lldap_config.toml")]
+    pub config_file: String,
+
+    /// URL of a running server, e.g. http://localhost:17170, to go through its GraphQL API
+    /// instead of the database.
+    #[clap(long, requires = "token")]
+    pub server_url: Option<String>,
+
+    /// JWT or API token of an admin, for the server.
+    #[clap(long)]
+    pub token: Option<String>,
+
+    /// Set verbose logging
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub enum UserCommand {
+    /// Create a user.
+    #[clap(name = "add")]
+    Add(UserAddOpts),
+    /// List the users.
+    #[clap(name = "list")]
+    List(AdminTargetOpts),
+    /// Delete a user.
+    #[clap(name = "del")]
+    Del(UserDelOpts),
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct UserAddOpts {
+    #[clap(flatten)]
+    pub target: AdminTargetOpts,
+
+    pub user_id: String,
+
+    #[clap(long)]
+    pub email: String,
+
+    #[clap(long)]
+    pub display_name: Option<String>,
+
+    #[clap(long)]
+    pub first_name: Option<String>,
+
+    #[clap(long)]
+    pub last_name: Option<String>,
+
+    /// Initial password. The user has no password if not specified.
+    #[clap(long)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct UserDelOpts {
+    #[clap(flatten)]
+    pub target: AdminTargetOpts,
+
+    pub user_id: String,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub enum GroupCommand {
+    /// Create a group.
+    #[clap(name = "add")]
+    Add(GroupAddOpts),
+    /// Manage the members of a group.
+    #[clap(name = "member")]
+    Member(GroupMemberCommand),
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct GroupAddOpts {
+    #[clap(flatten)]
+    pub target: AdminTargetOpts,
+
+    pub name: String,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub enum GroupMemberCommand {
+    /// Add a user to a group.
+    #[clap(name = "add")]
+    Add(GroupMemberAddOpts),
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct GroupMemberAddOpts {
+    #[clap(flatten)]
+    pub target: AdminTargetOpts,
+
+    /// Name of the group.
+    pub group: String,
+
+    pub user_id: String,
+}
+
+#[derive(Debug, Clap, Clone)]
+pub enum PasswordCommand {
+    /// Set the password of a user, without knowing the current one.
+    #[clap(name = "reset")]
+    Reset(PasswordResetOpts),
+}
+
+#[derive(Debug, Clap, Clone)]
+pub struct PasswordResetOpts {
+    #[clap(flatten)]
+    pub target: AdminTargetOpts,
+
+    pub user_id: String,
+
+    /// New password. Read from the standard input if not specified, to keep it out of the shell
+    /// history.
+    #[clap(long)]
+    pub password: Option<String>,
+}
+
 pub fn init() -> CLIOpts {
     CLIOpts::parse()
 }
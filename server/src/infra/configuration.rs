@@ -1,13 +1,544 @@
 use anyhow::{Context, Result};
 use figment::{
-    providers::{Env, Format, Serialized, Toml},
+    providers::{Env, Format, Serialized, Toml, Yaml},
     Figment,
 };
 use lldap_auth::opaque::{server::ServerSetup, KeyPair};
-use log::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
 
-use crate::infra::cli::RunOpts;
+use crate::{
+    domain::{
+        handler::{UserOrder, USER_FILTER_FIELDS},
+        identifiers::{fold_case, UserIdPolicy},
+        sql_tables::{DbBackend, DEFAULT_FIRST_UID_NUMBER},
+    },
+    infra::{cli::RunOpts, ldap_handler::AttributeTemplate},
+};
+
+/// Set of extra LDAP attribute names exposed to clients, on top of the standard ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LdapAttributeProfile {
+    /// Only the standard inetOrgPerson/groupOfUniqueNames attributes.
+    Standard,
+    /// Also expose (and accept in filters) the Active Directory names: `sAMAccountName`,
+    /// `userPrincipalName`, `objectSid` and the `user`/`group` object classes.
+    ActiveDirectory,
+}
+
+/// Casing of the attribute names in the LDAP search results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LdapAttributeCasing {
+    /// As written in the request.
+    AsRequested,
+    /// The usual casing, e.g. `objectClass` or `sAMAccountName`.
+    Canonical,
+    Lowercase,
+}
+
+/// What the anonymous LDAP sessions can do. The root DSE and the subschema are always readable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LdapAnonymousAccess {
+    /// Reject the anonymous binds.
+    Deny,
+    /// Accept the anonymous binds, without access to the users and groups.
+    RootDse,
+    /// Read all the users and groups, like the `lldap_strict_readonly` group.
+    ReadOnly,
+}
+
+/// Format of the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of the spans, e.g. the `request_id`.
+    Json,
+}
+
+/// Algorithm signing the JWTs of the web UI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum JwtAlgorithm {
+    /// With the `jwt_secret`, shared with the services checking the tokens.
+    Hs512,
+    /// With the RSA key of the `jwt_key_file`, whose public key is served as a JWKS.
+    Rs256,
+}
+
+/// How a connector delivers the changes to the external system.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConnectorKind {
+    /// POST a JSON description of each change to the URL.
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Provision the users and groups to a SCIM 2.0 server.
+    Scim { base_url: String, token: String },
+    /// Run a command for each change, with the JSON description on stdin.
+    Script {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Publish the JSON description of each change to the `<subject>.<event type>` NATS subject.
+    Nats { url: String, subject: String },
+    /// Publish the JSON description of each change to the Kafka topic, keyed by user or group.
+    Kafka { brokers: String, topic: String },
+    /// Publish the JSON description of each change to the `<topic>/<event type>` MQTT topic.
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+    },
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+/// An external system to which the changes to users and groups are pushed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConnectorConfig {
+    /// Name used in the logs and the delivery dashboard.
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ConnectorKind,
+    /// Only push the changes concerning these groups or their members. Empty means everything.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Renames the user fields in the HTTP and script payloads, e.g. `email = "mail"`.
+    #[serde(default)]
+    pub field_mapping: HashMap<String, String>,
+}
+
+/// A user that must exist, declared in the `bootstrap` section.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BootstrapUser {
+    pub user_id: String,
+    pub email: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub first_name: Option<String>,
+    #[serde(default)]
+    pub last_name: Option<String>,
+    /// Only set when the user is created, so that users can change it afterwards.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Names of the groups the user is a member of.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// The baseline directory, reconciled with the database at startup.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BootstrapConfig {
+    /// Delete the users, groups and memberships that are not declared. The admin user and group
+    /// are always kept.
+    #[serde(default)]
+    pub prune: bool,
+    /// Names of the groups that must exist, on top of the ones the users are members of.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<BootstrapUser>,
+}
+
+/// A custom attribute of the users, see [`LdapSchemaConfig`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CustomAttributeConfig {
+    pub name: String,
+    /// The OID advertised in the schema, `<name>-oid` by default.
+    #[serde(default)]
+    pub oid: Option<String>,
+    /// Whether a user can have at most one value.
+    #[serde(default)]
+    pub single_value: bool,
+}
+
+impl CustomAttributeConfig {
+    pub fn oid(&self) -> String {
+        self.oid
+            .clone()
+            .unwrap_or_else(|| format!("{}-oid", self.name))
+    }
+}
+
+/// Extra object classes and user attributes, for the applications that require them. The values
+/// of the attributes are stored for each user, served and filterable over LDAP, and advertised
+/// with the object classes in the `cn=Subschema` entry.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LdapSchemaConfig {
+    /// Added to the object classes of all the users.
+    pub object_classes: Vec<String>,
+    pub attributes: Vec<CustomAttributeConfig>,
+}
+
+impl LdapSchemaConfig {
+    /// The declared attribute with this name, compared case-insensitively.
+    pub fn get_attribute(&self, name: &str) -> Option<&CustomAttributeConfig> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Extra attributes of the LDAP entries, for the applications expecting other names or attributes
+/// that lldap doesn't have. Each attribute name is mapped to a template of its value, with `{name}`
+/// placeholders for the built-in attributes: e.g. "{uid}" for an alias of `uid`, "/home/{uid}", or
+/// "/bin/bash" for a constant. A mapped attribute replaces the built-in one with the same name.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LdapAttributeMappingConfig {
+    pub user: HashMap<String, String>,
+    pub group: HashMap<String, String>,
+}
+
+/// The POSIX attributes of the users and groups (`posixAccount` and `posixGroup`), for the logins
+/// with SSSD or nslcd. The users get their `uidNumber` when they are created, and the groups have
+/// `first_gid_number` plus their ID as `gidNumber`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PosixConfig {
+    /// The `uidNumber` of the first user. The following users get the next ones.
+    pub first_uid_number: i32,
+    /// The `gidNumber` of the group with ID 0. Without a `user_gid_number`, the users' primary
+    /// groups have their `uidNumber` as `gidNumber`: the two ranges must be
+    /// [`POSIX_RANGE_SIZE`] apart.
+    pub first_gid_number: i32,
+    /// The `gidNumber` of the users' primary group. By default, the user's `uidNumber`.
+    pub user_gid_number: Option<i32>,
+    /// Template of the `homeDirectory`, with the same placeholders as the
+    /// [`LdapAttributeMappingConfig`].
+    pub home_directory: String,
+    pub login_shell: String,
+}
+
+/// The numbers reserved for the users and for the groups from their first `uidNumber` and
+/// `gidNumber`, so that the users' primary groups don't share a `gidNumber` with a group.
+pub const POSIX_RANGE_SIZE: i32 = 10000;
+
+impl Default for PosixConfig {
+    fn default() -> Self {
+        PosixConfig {
+            first_uid_number: DEFAULT_FIRST_UID_NUMBER,
+            first_gid_number: DEFAULT_FIRST_UID_NUMBER + POSIX_RANGE_SIZE,
+            user_gid_number: None,
+            home_directory: "/home/{uid}".to_string(),
+            login_shell: "/bin/bash".to_string(),
+        }
+    }
+}
+
+/// The image format the avatars are stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AvatarFormat {
+    Jpeg,
+    /// Lossless WebP.
+    Webp,
+}
+
+/// Restrictions on the avatars uploaded by the users, and how they are stored.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AvatarConfig {
+    /// Maximum size of an uploaded avatar, in bytes.
+    pub max_size_bytes: usize,
+    /// MIME types of the accepted images.
+    pub allowed_types: Vec<String>,
+    /// Width and height of the stored avatars, in pixels.
+    pub size: u32,
+    pub format: AvatarFormat,
+    /// Quality of the JPEG encoding, from 1 to 100.
+    pub jpeg_quality: u8,
+}
+
+impl Default for AvatarConfig {
+    fn default() -> Self {
+        AvatarConfig {
+            max_size_bytes: 512 * 1024,
+            allowed_types: vec![
+                "image/jpeg".to_string(),
+                "image/png".to_string(),
+                "image/gif".to_string(),
+                "image/webp".to_string(),
+            ],
+            size: 256,
+            format: AvatarFormat::Jpeg,
+            jpeg_quality: 85,
+        }
+    }
+}
+
+/// The certificate of the LDAP server, for the clients that encrypt the connection with the
+/// StartTLS extended operation.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LdapTlsConfig {
+    /// The certificate chain, in PEM format.
+    pub cert_file: String,
+    /// The private key of the certificate, in PKCS#8 PEM format.
+    pub key_file: String,
+}
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpEncryption {
+    None,
+    /// Upgrade the plain connection with STARTTLS, usually on port 587.
+    StartTls,
+    /// Implicit TLS, usually on port 465.
+    Tls,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_smtp_encryption() -> SmtpEncryption {
+    SmtpEncryption::StartTls
+}
+
+/// The SMTP server used to send emails to the users.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SmtpConfig {
+    pub server: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default = "default_smtp_encryption")]
+    pub encryption: SmtpEncryption,
+    /// Login for the SMTP server, if it requires authentication.
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+    /// Sender of the emails, e.g. `LLDAP <lldap@example.com>`.
+    pub from: String,
+    /// Directory of the templates replacing the default emails, see
+    /// [`mail`](crate::infra::mail).
+    #[serde(default)]
+    pub templates_dir: Option<String>,
+}
+
+/// When the passwords expire, and when the users are warned about it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordExpiryConfig {
+    /// Number of days after which a password expires. 0 means never.
+    pub max_age_days: u32,
+    /// The users are warned this many days before their password expires: by email (on that day
+    /// and the day before the expiry) if `smtp` is configured, and with a banner in the web UI.
+    pub warning_days: u32,
+    /// The users whose password expired can only change it on the web UI: the rest of the API is
+    /// refused to them until they do.
+    pub force_change: bool,
+}
+
+impl Default for PasswordExpiryConfig {
+    fn default() -> Self {
+        PasswordExpiryConfig {
+            max_age_days: 0,
+            warning_days: 14,
+            force_change: false,
+        }
+    }
+}
+
+/// The rules the new passwords have to follow, see
+/// [`check_password`](crate::infra::password_policy::check_password).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    /// Require a character that is neither a letter nor a digit.
+    pub require_special: bool,
+    /// Minimum strength estimated by zxcvbn, from 0 to 4, which rejects the common and
+    /// dictionary-based passwords. 0 accepts all of them.
+    pub min_strength: u8,
+    /// Number of passwords, including the current one, that can't be reused. 0 keeps no history.
+    pub history_size: usize,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        PasswordPolicyConfig {
+            min_length: 8,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_special: false,
+            min_strength: 0,
+            history_size: 0,
+        }
+    }
+}
+
+/// The throttling of the failed LDAP binds and web logins, see
+/// [`BindThrottle`](crate::infra::bind_throttle::BindThrottle).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BindThrottleConfig {
+    /// Number of failures of an address or a user allowed before throttling it. 0 disables the
+    /// throttling.
+    pub max_failures: u32,
+    /// The wait after the first throttled failure, doubled for each following one.
+    pub base_delay_seconds: u32,
+    pub max_delay_seconds: u32,
+    /// The failures are forgotten this long after the last one.
+    pub reset_after_seconds: u32,
+    /// Whether the failures are kept in the database, to survive the restarts.
+    pub persist: bool,
+}
+
+impl Default for BindThrottleConfig {
+    fn default() -> Self {
+        BindThrottleConfig {
+            max_failures: 5,
+            base_delay_seconds: 1,
+            max_delay_seconds: 900,
+            reset_after_seconds: 3600,
+            persist: false,
+        }
+    }
+}
+
+/// The cache of the details and the groups of the users, see
+/// [`CachingBackendHandler`](crate::infra::caching_backend_handler::CachingBackendHandler).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Number of users whose details, and whose groups, are kept. 0 disables the cache.
+    pub max_entries: usize,
+    /// How long the entries are used, for the changes made outside of lldap to show up.
+    pub ttl_seconds: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 1000,
+            ttl_seconds: 60,
+        }
+    }
+}
+
+impl PasswordExpiryConfig {
+    /// When a password changed at `changed_at` expires, if they expire at all.
+    pub fn expires_at(
+        &self,
+        changed_at: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        if self.max_age_days == 0 {
+            None
+        } else {
+            Some(changed_at + chrono::Duration::days(self.max_age_days.into()))
+        }
+    }
+
+    /// Whether a password expiring at `expires_at` is in the warning period.
+    pub fn is_warning_due(
+        &self,
+        expires_at: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        expires_at - chrono::Duration::days(self.warning_days.into()) <= now
+    }
+}
+
+/// Which lldap account an OIDC login maps to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OidcUserMatch {
+    /// The claim is the user ID.
+    UserId,
+    /// The claim is the email of the user (case-insensitive). The provider must have verified it
+    /// (`email_verified`), and a single user must have it.
+    Email,
+}
+
+fn default_oidc_display_name() -> String {
+    "SSO".to_string()
+}
+
+fn default_oidc_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_oidc_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_oidc_match_by() -> OidcUserMatch {
+    OidcUserMatch::UserId
+}
+
+/// An external OpenID Connect provider to log into the web UI with. The logins are mapped to
+/// existing accounts, no user is created.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OidcConfig {
+    /// Name of the provider on the login page.
+    #[serde(default = "default_oidc_display_name")]
+    pub display_name: String,
+    /// The provider's discovery document is at `<issuer_url>/.well-known/openid-configuration`.
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// The public URL of `/auth/oidc/callback`, as registered with the provider.
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: String,
+    /// The userinfo claim identifying the account.
+    #[serde(default = "default_oidc_claim")]
+    pub claim: String,
+    #[serde(default = "default_oidc_match_by")]
+    pub match_by: OidcUserMatch,
+}
+
+/// An application logging in its users through the OpenID Connect provider of lldap.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OidcClientConfig {
+    pub client_id: String,
+    /// Empty for the public clients, e.g. single-page apps, which have to use PKCE instead.
+    #[serde(default)]
+    pub client_secret: String,
+    /// The URLs the users can be sent back to with the authorization code, compared exactly.
+    pub redirect_uris: Vec<String>,
+}
+
+fn default_oidc_provider_key_file() -> String {
+    "oidc_key.pem".to_string()
+}
+
+fn default_oidc_provider_token_lifetime_seconds() -> u64 {
+    3600
+}
+
+/// The OpenID Connect provider of lldap, whose issuer is the `http_url`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OidcProviderConfig {
+    /// The RSA key signing the ID tokens, in PKCS#8 PEM, generated if the file doesn't exist.
+    #[serde(default = "default_oidc_provider_key_file")]
+    pub key_file: String,
+    /// Validity of the ID and access tokens, in seconds.
+    #[serde(default = "default_oidc_provider_token_lifetime_seconds")]
+    pub token_lifetime_seconds: u64,
+    #[serde(default)]
+    pub clients: Vec<OidcClientConfig>,
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize, derive_builder::Builder)]
 #[builder(
@@ -19,20 +550,93 @@ pub struct Configuration {
     pub ldap_port: u16,
     pub ldaps_port: u16,
     pub http_port: u16,
+    /// The public URL of the web interface, for the links in the emails.
+    pub http_url: String,
     pub jwt_secret: String,
+    /// File containing the `jwt_secret`, e.g. a Docker secret, read on startup.
+    pub jwt_secret_file: Option<String>,
+    pub jwt_algorithm: JwtAlgorithm,
+    /// The RSA key signing the JWTs with RS256, in PKCS#8 PEM, generated if the file doesn't exist.
+    pub jwt_key_file: String,
+    /// The secrets of the previous HS512 keys, still accepted to check the JWTs after a rotation.
+    pub jwt_previous_secrets: Vec<String>,
+    /// The files of the previous RS256 keys, still accepted to check the JWTs after a rotation.
+    pub jwt_previous_key_files: Vec<String>,
     pub ldap_base_dn: String,
     pub ldap_user_dn: String,
     pub ldap_user_pass: String,
+    /// File containing the `ldap_user_pass`.
+    pub ldap_user_pass_file: Option<String>,
     pub database_url: String,
+    /// File containing the `database_url`, which can include a password.
+    pub database_url_file: Option<String>,
+    /// Maximum duration of each database operation, in seconds.
+    pub database_timeout_seconds: u64,
     pub verbose: bool,
+    pub log_format: LogFormat,
+    /// Log levels per module, in the `RUST_LOG` syntax, e.g. `info,sqlx=warn`. Empty means `info`,
+    /// or `debug` when verbose.
+    pub log_filter: String,
+    /// The OTLP endpoint receiving the traces, with the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+    /// Number of actix workers accepting the LDAP and HTTP connections, each a thread with its
+    /// own single-threaded runtime. It's not a number of tokio threads: the background tasks
+    /// run on the main thread. At least 1.
+    pub worker_threads: usize,
+    /// On SIGTERM, how long the connections have to finish their operations before they are
+    /// closed, in seconds.
+    pub shutdown_grace_period_seconds: u64,
+    /// Maximum number of LDAP operations handled at the same time, 0 for no limit.
+    pub ldap_max_concurrent_operations: usize,
+    /// Reject the LDAP write operations on `ldap_port`.
+    pub ldap_read_only: bool,
+    /// Port of an additional LDAP listener that rejects the write operations, 0 for none.
+    pub ldap_read_only_port: u16,
     pub key_file: String,
+    pub ldap_attribute_profile: LdapAttributeProfile,
+    pub ldap_attribute_casing: LdapAttributeCasing,
+    /// Reject the users whose email (case-insensitive) is already used by another user.
+    pub unique_emails: bool,
+    /// Number of days the failed authentications are kept.
+    pub auth_failure_retention_days: u32,
+    /// Number of days the entries of the audit log are kept.
+    pub audit_log_retention_days: u32,
+    /// Number of days the deleted users stay in the recycle bin before being purged. With 0, they
+    /// are purged right away.
+    pub deleted_user_retention_days: u32,
+    /// Characters accepted in the user IDs.
+    pub user_id_policy: UserIdPolicy,
+    /// Order of the users in the LDAP search results.
+    pub ldap_user_order: UserOrder,
+    pub ldap_anonymous_access: LdapAnonymousAccess,
+    /// Extra LDAP attribute names accepted in the user filters, with the user field (one of
+    /// [`USER_FILTER_FIELDS`]) they stand for.
+    pub ldap_attribute_aliases: HashMap<String, String>,
+    pub ldap_attribute_mapping: LdapAttributeMappingConfig,
+    pub ldap_schema: LdapSchemaConfig,
+    pub posix: PosixConfig,
+    pub ldap_tls: Option<LdapTlsConfig>,
+    pub connectors: Vec<ConnectorConfig>,
+    pub bootstrap: BootstrapConfig,
+    /// A TOML or YAML (`.yaml` or `.yml`) file with the fields of the `bootstrap` section, which
+    /// it replaces.
+    pub bootstrap_file: Option<String>,
+    pub avatar: AvatarConfig,
+    pub smtp: Option<SmtpConfig>,
+    pub password_expiry: PasswordExpiryConfig,
+    pub password_policy: PasswordPolicyConfig,
+    pub bind_throttle: BindThrottleConfig,
+    pub cache: CacheConfig,
+    pub oidc: Option<OidcConfig>,
+    pub oidc_provider: Option<OidcProviderConfig>,
     #[serde(skip)]
     #[builder(field(private), setter(strip_option))]
     server_setup: Option<ServerSetup>,
 }
 
 impl ConfigurationBuilder {
-    #[cfg(test)]
+    /// Builds the configuration, loading the server keys from `key_file` (or generating them if
+    /// the file doesn't exist).
     pub fn build(self) -> Result<Configuration> {
         let server_setup = get_server_setup(self.key_file.as_deref().unwrap_or("server_key"))?;
         Ok(self.server_setup(server_setup).private_build()?)
@@ -48,6 +652,210 @@ impl ConfigurationBuilder {
 }
 
 impl Configuration {
+    fn check_attribute_aliases(&self) -> Result<()> {
+        for (alias, field) in &self.ldap_attribute_aliases {
+            if !USER_FILTER_FIELDS.contains(&field.as_str()) {
+                anyhow::bail!(
+                    "Invalid ldap_attribute_aliases: {} is an alias of {}, which isn't one of {}",
+                    alias,
+                    field,
+                    USER_FILTER_FIELDS.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_attribute_mapping(&self) -> Result<()> {
+        let mapping = &self.ldap_attribute_mapping;
+        for (name, template) in mapping.user.iter().chain(mapping.group.iter()) {
+            AttributeTemplate::parse(template)
+                .with_context(|| format!("Invalid ldap_attribute_mapping for {}", name))?;
+        }
+        AttributeTemplate::parse(&self.posix.home_directory)
+            .context("Invalid posix.home_directory")?;
+        Ok(())
+    }
+
+    fn check_posix(&self) -> Result<()> {
+        let posix = &self.posix;
+        if posix.user_gid_number.is_none()
+            && (posix.first_gid_number - posix.first_uid_number).abs() < POSIX_RANGE_SIZE
+        {
+            anyhow::bail!(
+                "posix.first_uid_number {} and posix.first_gid_number {} are less than {} apart: \
+                 the users' primary groups would share their gidNumber with the groups",
+                posix.first_uid_number,
+                posix.first_gid_number,
+                POSIX_RANGE_SIZE
+            );
+        }
+        Ok(())
+    }
+
+    fn check_worker_threads(&self) -> Result<()> {
+        if self.worker_threads == 0 {
+            anyhow::bail!("worker_threads is 0: at least one worker is needed");
+        }
+        Ok(())
+    }
+
+    fn check_database_url(&self) -> Result<()> {
+        if DbBackend::from_url(&self.database_url).is_none() {
+            anyhow::bail!(
+                "Invalid database_url {}: only sqlite://, postgres:// and mysql:// URLs are supported",
+                &self.database_url
+            );
+        }
+        Ok(())
+    }
+
+    fn check_ports(&self) -> Result<()> {
+        let mut ports = vec![("ldap_port", self.ldap_port), ("http_port", self.http_port)];
+        if self.ldap_read_only_port != 0 {
+            ports.push(("ldap_read_only_port", self.ldap_read_only_port));
+        }
+        for (index, (name, port)) in ports.iter().enumerate() {
+            if let Some((other_name, _)) = ports[..index].iter().find(|(_, p)| p == port) {
+                anyhow::bail!("{} and {} are both {}", other_name, name, port);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_secrets(&self) -> Result<()> {
+        if self.jwt_algorithm == JwtAlgorithm::Hs512 && self.jwt_secret.is_empty() {
+            anyhow::bail!("jwt_secret is empty");
+        }
+        if self.jwt_previous_secrets.iter().any(String::is_empty) {
+            anyhow::bail!("jwt_previous_secrets has an empty secret");
+        }
+        // Unlike the current one, they are not generated.
+        for file in &self.jwt_previous_key_files {
+            if !std::path::Path::new(file).is_file() {
+                anyhow::bail!("Invalid jwt_previous_key_files: `{}` doesn't exist", file);
+            }
+        }
+        if self.ldap_user_pass.is_empty() {
+            anyhow::bail!("ldap_user_pass is empty");
+        }
+        Ok(())
+    }
+
+    fn check_ldap_tls(&self) -> Result<()> {
+        if let Some(tls) = &self.ldap_tls {
+            for file in &[&tls.cert_file, &tls.key_file] {
+                if !std::path::Path::new(file).is_file() {
+                    anyhow::bail!("Invalid ldap_tls: `{}` doesn't exist", file);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_smtp(&self) -> Result<()> {
+        if let Some(smtp) = &self.smtp {
+            smtp.from
+                .parse::<lettre::message::Mailbox>()
+                .with_context(|| format!("Invalid smtp.from `{}`", smtp.from))?;
+            crate::infra::mail::check_templates(smtp)?;
+        }
+        Ok(())
+    }
+
+    fn check_oidc_provider(&self) -> Result<()> {
+        let provider = match &self.oidc_provider {
+            Some(provider) => provider,
+            None => return Ok(()),
+        };
+        // The issuer of the tokens.
+        reqwest::Url::parse(&self.http_url)
+            .with_context(|| format!("Invalid http_url `{}`", self.http_url))?;
+        for (index, client) in provider.clients.iter().enumerate() {
+            if provider.clients[..index]
+                .iter()
+                .any(|other| other.client_id == client.client_id)
+            {
+                anyhow::bail!("oidc_provider has two clients `{}`", client.client_id);
+            }
+            if client.redirect_uris.is_empty() {
+                anyhow::bail!(
+                    "The oidc_provider client `{}` has no redirect_uris",
+                    client.client_id
+                );
+            }
+            for uri in &client.redirect_uris {
+                reqwest::Url::parse(uri).with_context(|| {
+                    format!(
+                        "Invalid redirect URI `{}` of the oidc_provider client `{}`",
+                        uri, client.client_id
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the secrets with the content of their `*_file`, if set, without the final newline.
+    fn read_secret_files(&mut self) -> Result<()> {
+        for (file, value) in [
+            (&self.jwt_secret_file, &mut self.jwt_secret),
+            (&self.ldap_user_pass_file, &mut self.ldap_user_pass),
+            (&self.database_url_file, &mut self.database_url),
+        ] {
+            if let Some(file) = file {
+                let content = std::fs::read_to_string(file)
+                    .with_context(|| format!("Could not read the secret file `{}`", file))?;
+                *value = content.trim_end_matches(&['\r', '\n'][..]).to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the `bootstrap` section with the content of `bootstrap_file`, if set. A missing
+    /// file is an error rather than an empty directory, which `prune` would apply.
+    fn read_bootstrap_file(&mut self) -> Result<()> {
+        let file = match &self.bootstrap_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Could not read the bootstrap file `{}`", file))?;
+        let figment = if file.ends_with(".yaml") || file.ends_with(".yml") {
+            Figment::from(Yaml::string(&content))
+        } else {
+            Figment::from(Toml::string(&content))
+        };
+        self.bootstrap = figment
+            .extract()
+            .with_context(|| format!("Invalid bootstrap file `{}`", file))?;
+        Ok(())
+    }
+
+    /// Runs all the checks, to report all the errors at once.
+    fn validate(&self) -> Result<()> {
+        let errors = [
+            self.check_attribute_aliases(),
+            self.check_attribute_mapping(),
+            self.check_posix(),
+            self.check_database_url(),
+            self.check_ports(),
+            self.check_worker_threads(),
+            self.check_secrets(),
+            self.check_ldap_tls(),
+            self.check_smtp(),
+            self.check_oidc_provider(),
+        ]
+        .iter()
+        .filter_map(|result| result.as_ref().err())
+        .map(|e| format!("\n  - {:#}", e))
+        .collect::<String>();
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid configuration:{}", errors);
+        }
+        Ok(())
+    }
+
     pub fn get_server_setup(&self) -> &ServerSetup {
         self.server_setup.as_ref().unwrap()
     }
@@ -69,6 +877,10 @@ impl Configuration {
             self.ldaps_port = port;
         }
 
+        if let Some(file) = cli_opts.bootstrap_file {
+            self.bootstrap_file = Some(file);
+        }
+
         self
     }
 
@@ -77,14 +889,56 @@ impl Configuration {
             ldap_port: 3890,
             ldaps_port: 6360,
             http_port: 17170,
+            http_url: String::from("http://localhost"),
             jwt_secret: String::from("secretjwtsecret"),
+            jwt_secret_file: None,
+            jwt_algorithm: JwtAlgorithm::Hs512,
+            jwt_key_file: String::from("jwt_private_key.pem"),
+            jwt_previous_secrets: Vec::new(),
+            jwt_previous_key_files: Vec::new(),
             ldap_base_dn: String::from("dc=example,dc=com"),
             // cn=admin,dc=example,dc=com
             ldap_user_dn: String::from("admin"),
             ldap_user_pass: String::from("password"),
+            ldap_user_pass_file: None,
             database_url: String::from("sqlite://users.db?mode=rwc"),
+            database_url_file: None,
+            database_timeout_seconds: 10,
             verbose: false,
+            log_format: LogFormat::Text,
+            log_filter: String::new(),
+            otlp_endpoint: None,
+            worker_threads: 1,
+            shutdown_grace_period_seconds: 30,
+            ldap_max_concurrent_operations: 0,
+            ldap_read_only: false,
+            ldap_read_only_port: 0,
             key_file: String::from("server_key"),
+            ldap_attribute_profile: LdapAttributeProfile::Standard,
+            ldap_attribute_casing: LdapAttributeCasing::AsRequested,
+            unique_emails: false,
+            auth_failure_retention_days: 30,
+            audit_log_retention_days: 365,
+            deleted_user_retention_days: 30,
+            user_id_policy: UserIdPolicy::SingleScript,
+            ldap_user_order: UserOrder::UserId,
+            ldap_anonymous_access: LdapAnonymousAccess::RootDse,
+            ldap_attribute_aliases: HashMap::new(),
+            ldap_attribute_mapping: LdapAttributeMappingConfig::default(),
+            ldap_schema: LdapSchemaConfig::default(),
+            posix: PosixConfig::default(),
+            ldap_tls: None,
+            connectors: Vec::new(),
+            bootstrap: BootstrapConfig::default(),
+            bootstrap_file: None,
+            avatar: AvatarConfig::default(),
+            smtp: None,
+            password_expiry: PasswordExpiryConfig::default(),
+            password_policy: PasswordPolicyConfig::default(),
+            bind_throttle: BindThrottleConfig::default(),
+            cache: CacheConfig::default(),
+            oidc: None,
+            oidc_provider: None,
             server_setup: None,
         }
     }
@@ -108,17 +962,146 @@ fn get_server_setup(file_path: &str) -> Result<ServerSetup> {
     }
 }
 
+/// The configuration sources, from the lowest to the highest precedence: the defaults, the TOML
+/// file, then the `LLDAP_` environment variables, e.g. `LLDAP_LDAP_PORT` for `ldap_port` or
+/// `LLDAP_SMTP__SERVER` (with a double underscore) for `server` in the `smtp` section. The command
+/// line flags override them all.
+fn figment(config_file: &str) -> Figment {
+    Figment::from(Serialized::defaults(Configuration::default()))
+        .merge(Toml::file(config_file))
+        .merge(Env::prefixed("LLDAP_").split("__"))
+}
+
 pub fn init(cli_opts: RunOpts) -> Result<Configuration> {
     let config_file = cli_opts.config_file.clone();
 
     info!("Loading configuration from {}", cli_opts.config_file);
 
-    let config: Configuration = Figment::from(Serialized::defaults(Configuration::default()))
-        .merge(Toml::file(config_file))
-        .merge(Env::prefixed("LLDAP_"))
-        .extract()?;
+    let config: Configuration = figment(&config_file).extract().map_err(|e| {
+        // All the invalid or missing fields, not only the first one.
+        let errors = e
+            .into_iter()
+            .map(|e| format!("\n  - {}", e))
+            .collect::<String>();
+        anyhow::anyhow!("Invalid configuration:{}", errors)
+    })?;
 
     let mut config = config.merge_with_cli(cli_opts);
+    // The user IDs are stored folded.
+    config.ldap_user_dn = fold_case(&config.ldap_user_dn);
+    config.read_secret_files()?;
+    config.read_bootstrap_file()?;
+    config.validate()?;
     config.server_setup = Some(get_server_setup(&config.key_file)?);
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reports_all_errors() {
+        let mut config = Configuration::default();
+        config.validate().unwrap();
+        config.database_url = "redis://localhost".to_string();
+        config.http_port = config.ldap_port;
+        config.jwt_secret = String::new();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("database_url"), "{}", error);
+        assert!(error.contains("ldap_port and http_port"), "{}", error);
+        assert!(error.contains("jwt_secret"), "{}", error);
+    }
+
+    #[test]
+    fn test_validate_posix_ranges() {
+        let mut config = Configuration::default();
+        config.posix.first_gid_number = 15000;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("posix.first_gid_number"), "{}", error);
+        // Unless the users all have the same primary group.
+        config.posix.user_gid_number = Some(15000);
+        config.validate().unwrap();
+        config.posix.user_gid_number = None;
+        config.posix.first_gid_number = 0;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_worker_threads() {
+        let mut config = Configuration::default();
+        config.worker_threads = 0;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("worker_threads"), "{}", error);
+        config.worker_threads = 4;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_nested_environment_variables() {
+        std::env::set_var("LLDAP_SMTP__SERVER", "smtp.example.com");
+        std::env::set_var("LLDAP_SMTP__FROM", "LLDAP <lldap@example.com>");
+        let config: Configuration = figment("nonexistent.toml").extract().unwrap();
+        std::env::remove_var("LLDAP_SMTP__SERVER");
+        std::env::remove_var("LLDAP_SMTP__FROM");
+        let smtp = config.smtp.unwrap();
+        assert_eq!(smtp.server, "smtp.example.com");
+        assert_eq!(smtp.from, "LLDAP <lldap@example.com>");
+    }
+
+    #[test]
+    fn test_read_secret_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jwt_secret");
+        std::fs::write(&path, "from_the_file\n").unwrap();
+        let mut config = Configuration {
+            jwt_secret_file: Some(path.to_str().unwrap().to_string()),
+            ..Configuration::default()
+        };
+        config.read_secret_files().unwrap();
+        assert_eq!(config.jwt_secret, "from_the_file");
+        assert_eq!(config.ldap_user_pass, "password");
+        config.database_url_file = Some(dir.path().join("missing").to_str().unwrap().to_string());
+        assert!(config.read_secret_files().is_err());
+    }
+
+    #[test]
+    fn test_read_bootstrap_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = dir.path().join("bootstrap.yaml");
+        std::fs::write(
+            &yaml,
+            "prune: true\nusers:\n  - user_id: bob\n    email: bob@example.com\n    groups: [family]\n",
+        )
+        .unwrap();
+        let mut config = Configuration {
+            bootstrap_file: Some(yaml.to_str().unwrap().to_string()),
+            ..Configuration::default()
+        };
+        config.read_bootstrap_file().unwrap();
+        assert!(config.bootstrap.prune);
+        assert_eq!(config.bootstrap.users[0].user_id, "bob");
+        assert_eq!(config.bootstrap.users[0].groups, vec!["family"]);
+
+        let toml = dir.path().join("bootstrap.toml");
+        std::fs::write(
+            &toml,
+            "groups = [\"family\"]\n[[users]]\nuser_id = \"jim\"\nemail = \"jim@example.com\"\n",
+        )
+        .unwrap();
+        config.bootstrap_file = Some(toml.to_str().unwrap().to_string());
+        config.read_bootstrap_file().unwrap();
+        assert!(!config.bootstrap.prune);
+        assert_eq!(config.bootstrap.groups, vec!["family"]);
+        assert_eq!(config.bootstrap.users[0].user_id, "jim");
+
+        config.bootstrap_file = Some(
+            dir.path()
+                .join("missing.toml")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        assert!(config.read_bootstrap_file().is_err());
+    }
+}
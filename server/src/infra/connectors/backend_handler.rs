@@ -0,0 +1,759 @@
+use super::{delivery, ChangeEvent, DeliveryLog, EventBus};
+use crate::{
+    domain::{error::*, handler::*, opaque_handler::*},
+    infra::{
+        configuration::ConnectorConfig,
+        tcp_backend_handler::{ApiToken, ApiTokenScope, Session, TcpBackendHandler},
+    },
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Password registrations left unfinished, e.g. abandoned by the client, before we start
+/// forgetting them.
+const MAX_PENDING_REGISTRATIONS: usize = 1000;
+
+/// A backend handler forwarding everything to `Backend`, and notifying the connectors and the
+/// subscribers of the event bus of the successful modifications.
+#[derive(Clone)]
+pub struct ConnectorBackendHandler<Backend> {
+    inner: Backend,
+    sender: Option<mpsc::UnboundedSender<ChangeEvent>>,
+    event_bus: EventBus,
+    /// The user of each password registration in progress, by `server_data`: the finish request
+    /// doesn't say whose password changed.
+    pending_registrations: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl<Backend> ConnectorBackendHandler<Backend>
+where
+    Backend: BackendHandler + Sync + 'static,
+{
+    /// Wraps the backend, and starts the delivery task if there are any connectors.
+    pub fn new(
+        inner: Backend,
+        connectors: Vec<ConnectorConfig>,
+        log: DeliveryLog,
+        event_bus: EventBus,
+    ) -> Self {
+        let sender = if connectors.is_empty() {
+            None
+        } else {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(delivery::deliver_events(
+                inner.clone(),
+                connectors,
+                log,
+                receiver,
+            ));
+            Some(sender)
+        };
+        Self {
+            inner,
+            sender,
+            event_bus,
+            pending_registrations: Default::default(),
+        }
+    }
+}
+
+impl<Backend> ConnectorBackendHandler<Backend> {
+    /// Whether anyone is interested in the events, to skip the extra queries otherwise.
+    fn is_enabled(&self) -> bool {
+        self.sender.is_some() || self.event_bus.has_subscribers()
+    }
+
+    fn notify(&self, event: ChangeEvent) {
+        self.event_bus.publish(event.clone());
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.send(event) {
+                error!("Connector delivery task stopped, dropping {:?}", e.0);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<Backend: LoginHandler + Sync> LoginHandler for ConnectorBackendHandler<Backend> {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        self.inner.bind(request).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for ConnectorBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        self.inner.list_users(filters).await
+    }
+
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        self.inner.list_users_stream(filters, order)
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.inner
+            .list_users_page(filters, order, direction, offset, limit)
+            .await
+    }
+
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        self.inner.count_users(filters).await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.inner.list_groups(filters).await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        self.inner.list_groups_page(filters, offset, limit).await
+    }
+
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        self.inner.list_groups_with_member_count().await
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        self.inner.get_user_details(user_id).await
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get_user_avatar(user_id).await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.inner.get_user_by_email(email).await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.inner.get_group_details(group_id).await
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        self.inner.get_parent_groups(group_id).await
+    }
+
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        self.inner.get_group_members(group_id, offset, limit).await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        self.inner.create_user(request).await?;
+        self.notify(ChangeEvent::UserCreated { user_id });
+        Ok(())
+    }
+
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        let user_ids: Vec<String> = requests.iter().map(|r| r.user_id.clone()).collect();
+        let results = self.inner.bulk_create_users(requests).await?;
+        for (user_id, result) in user_ids.into_iter().zip(&results) {
+            if result.is_ok() {
+                self.notify(ChangeEvent::UserCreated { user_id });
+            }
+        }
+        Ok(results)
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        let user_id = request.user_id.clone();
+        self.inner.update_user(request).await?;
+        self.notify(ChangeEvent::UserUpdated { user_id });
+        Ok(())
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let group_id = request.group_id;
+        // The connectors need the previous name to find the group in the external system.
+        let previous_name = if self.is_enabled() {
+            self.inner.get_group_details(group_id).await?.1
+        } else {
+            String::new()
+        };
+        self.inner.update_group(request).await?;
+        self.notify(ChangeEvent::GroupUpdated {
+            group_id,
+            previous_name,
+        });
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        // The memberships are deleted with the user, keep them for the group filters.
+        let groups = if self.is_enabled() {
+            self.inner
+                .get_user_groups(user_id)
+                .await?
+                .into_iter()
+                .map(|g| g.1)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.inner.delete_user(user_id).await?;
+        self.notify(ChangeEvent::UserDeleted {
+            user_id: user_id.to_string(),
+            groups,
+        });
+        Ok(())
+    }
+
+    /// For the external systems, the restored user is created again, with its groups.
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        self.inner.restore_user(user_id).await?;
+        self.notify(ChangeEvent::UserCreated {
+            user_id: user_id.to_string(),
+        });
+        if self.is_enabled() {
+            for group in self.inner.get_user_groups(user_id).await? {
+                self.notify(ChangeEvent::UserAddedToGroup {
+                    user_id: user_id.to_string(),
+                    group_id: group.0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        self.inner.list_deleted_users().await
+    }
+
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        self.inner.rename_user(user_id, new_user_id).await?;
+        self.notify(ChangeEvent::UserRenamed {
+            user_id: new_user_id.to_string(),
+            previous_user_id: user_id.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        let group_id = self.inner.create_group(group_name).await?;
+        self.notify(ChangeEvent::GroupCreated { group_id });
+        Ok(group_id)
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        let name = if self.is_enabled() {
+            self.inner.get_group_details(group_id).await?.1
+        } else {
+            String::new()
+        };
+        self.inner.delete_group(group_id).await?;
+        self.notify(ChangeEvent::GroupDeleted { group_id, name });
+        Ok(())
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.inner.add_user_to_group(user_id, group_id).await?;
+        self.notify(ChangeEvent::UserAddedToGroup {
+            user_id: user_id.to_string(),
+            group_id,
+        });
+        Ok(())
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.inner.remove_user_from_group(user_id, group_id).await?;
+        self.notify(ChangeEvent::UserRemovedFromGroup {
+            user_id: user_id.to_string(),
+            group_id,
+        });
+        Ok(())
+    }
+
+    /// The members of the parent group change, but the users aren't listed: the event is a
+    /// group update.
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.inner
+            .add_group_to_group(member_group_id, parent_group_id)
+            .await?;
+        let previous_name = if self.is_enabled() {
+            self.inner.get_group_details(parent_group_id).await?.1
+        } else {
+            String::new()
+        };
+        self.notify(ChangeEvent::GroupUpdated {
+            group_id: parent_group_id,
+            previous_name,
+        });
+        Ok(())
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.inner
+            .remove_group_from_group(member_group_id, parent_group_id)
+            .await?;
+        let previous_name = if self.is_enabled() {
+            self.inner.get_group_details(parent_group_id).await?.1
+        } else {
+            String::new()
+        };
+        self.notify(ChangeEvent::GroupUpdated {
+            group_id: parent_group_id,
+            previous_name,
+        });
+        Ok(())
+    }
+
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        self.inner.add_users_to_group(group_id, user_ids).await?;
+        for user_id in user_ids {
+            self.notify(ChangeEvent::UserAddedToGroup {
+                user_id: user_id.clone(),
+                group_id,
+            });
+        }
+        Ok(())
+    }
+
+    /// The events are only sent for the users that were members.
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        let members = self
+            .inner
+            .list_users(Some(RequestFilter::MemberOfId(group_id)))
+            .await?;
+        self.inner
+            .remove_users_from_group(group_id, user_ids)
+            .await?;
+        for user_id in user_ids {
+            if members.iter().any(|u| &u.user_id == user_id) {
+                self.notify(ChangeEvent::UserRemovedFromGroup {
+                    user_id: user_id.clone(),
+                    group_id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        let changes = self.inner.set_group_members(group_id, user_ids).await?;
+        for user_id in &changes.added {
+            self.notify(ChangeEvent::UserAddedToGroup {
+                user_id: user_id.clone(),
+                group_id,
+            });
+        }
+        for user_id in &changes.removed {
+            self.notify(ChangeEvent::UserRemovedFromGroup {
+                user_id: user_id.clone(),
+                group_id,
+            });
+        }
+        Ok(changes)
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        self.inner.get_user_groups(user).await
+    }
+
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        self.inner.get_users_groups(user_ids).await
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        self.inner.get_user_hosts(user_id).await
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        self.inner.set_user_hosts(user_id, hosts).await?;
+        self.notify(ChangeEvent::UserUpdated {
+            user_id: user_id.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.inner.get_group_metadata(group_id).await
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        self.inner.get_group_mail(group_id).await
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        // The name doesn't change, it is the current one.
+        let previous_name = if self.is_enabled() {
+            self.inner.get_group_details(group_id).await?.1
+        } else {
+            String::new()
+        };
+        self.inner.set_group_mail(group_id, mail).await?;
+        self.notify(ChangeEvent::GroupUpdated {
+            group_id,
+            previous_name,
+        });
+        Ok(())
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        self.inner.get_user_attributes(user_id).await
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.inner.set_user_attribute(user_id, name, values).await?;
+        self.notify(ChangeEvent::UserUpdated {
+            user_id: user_id.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        self.inner.lock_user(user_id, reason).await?;
+        self.notify(ChangeEvent::UserLocked {
+            user_id: user_id.to_string(),
+            reason: reason.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.inner.unlock_user(user_id).await?;
+        self.notify(ChangeEvent::UserUnlocked {
+            user_id: user_id.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        self.inner.get_user_lock(user_id).await
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.inner.list_password_changes().await
+    }
+
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        self.inner.get_password_change(user_id).await
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.inner.record_auth_failure(failure).await
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        self.inner.list_auth_failures(user_id, offset, limit).await
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.inner.record_audit_event(event).await
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.inner.list_audit_events(offset, limit).await
+    }
+}
+
+#[async_trait]
+impl<Backend: OpaqueHandler + Sync> OpaqueHandler for ConnectorBackendHandler<Backend> {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.inner.login_start(request).await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        self.inner.login_finish(request).await
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        let user_id = request.username.clone();
+        let response = self.inner.registration_start(request).await?;
+        if self.is_enabled() {
+            let mut pending = self.pending_registrations.lock().unwrap();
+            if pending.len() >= MAX_PENDING_REGISTRATIONS {
+                pending.clear();
+            }
+            pending.insert(response.server_data.clone(), user_id);
+        }
+        Ok(response)
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        let user_id = self
+            .pending_registrations
+            .lock()
+            .unwrap()
+            .remove(&request.server_data);
+        self.inner.registration_finish(request).await?;
+        if let Some(user_id) = user_id {
+            self.notify(ChangeEvent::PasswordChanged { user_id });
+        }
+        Ok(())
+    }
+
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        self.inner.is_password_reused(username, password).await
+    }
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync> TcpBackendHandler for ConnectorBackendHandler<Backend> {
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+        self.inner.get_jwt_blacklist().await
+    }
+
+    async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
+        self.inner.create_refresh_token(user).await
+    }
+
+    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
+        self.inner.check_token(refresh_token_hash, user).await
+    }
+
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.inner.register_jwt(user, jwt_hash, expiry_date).await
+    }
+
+    async fn blacklist_jwts(&self, user: &str) -> Result<HashSet<u64>> {
+        self.inner.blacklist_jwts(user).await
+    }
+
+    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
+        self.inner.delete_refresh_token(refresh_token_hash).await
+    }
+
+    async fn delete_refresh_tokens(&self, user: &str) -> Result<()> {
+        self.inner.delete_refresh_tokens(user).await
+    }
+
+    async fn list_sessions(&self, user: Option<&str>) -> Result<Vec<Session>> {
+        self.inner.list_sessions(user).await
+    }
+
+    async fn start_password_reset(&self, user: &str) -> Result<Option<String>> {
+        self.inner.start_password_reset(user).await
+    }
+
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<String> {
+        self.inner.get_user_id_for_password_reset_token(token).await
+    }
+
+    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
+        self.inner.delete_password_reset_token(token).await
+    }
+
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> Result<ApiToken> {
+        self.inner
+            .create_api_token(user, name, scope, token_hash)
+            .await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        self.inner.get_api_token(token_hash).await
+    }
+
+    async fn list_api_tokens(&self, user: Option<&str>) -> Result<Vec<ApiToken>> {
+        self.inner.list_api_tokens(user).await
+    }
+
+    async fn delete_api_token(&self, token_id: i32) -> Result<()> {
+        self.inner.delete_api_token(token_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::MockTestBackendHandler;
+    use mockall::predicate::eq;
+
+    fn make_handler(
+        mock: MockTestBackendHandler,
+    ) -> (
+        ConnectorBackendHandler<MockTestBackendHandler>,
+        mpsc::UnboundedReceiver<ChangeEvent>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            ConnectorBackendHandler {
+                inner: mock,
+                sender: Some(sender),
+                event_bus: EventBus::default(),
+                pending_registrations: Default::default(),
+            },
+            receiver,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_notifies_successful_changes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_create_group()
+            .with(eq("family"))
+            .times(1)
+            .return_once(|_| Ok(GroupId(3)));
+        mock.expect_get_user_groups()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| {
+                let mut groups = HashSet::new();
+                groups.insert(GroupIdAndName(GroupId(3), "family".to_string()));
+                Ok(groups)
+            });
+        mock.expect_delete_user()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let (handler, mut receiver) = make_handler(mock);
+        handler.create_group("family").await.unwrap();
+        handler.delete_user("bob").await.unwrap();
+        assert_eq!(
+            receiver.recv().await,
+            Some(ChangeEvent::GroupCreated {
+                group_id: GroupId(3)
+            })
+        );
+        assert_eq!(
+            receiver.recv().await,
+            Some(ChangeEvent::UserDeleted {
+                user_id: "bob".to_string(),
+                groups: vec!["family".to_string()],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_does_not_notify_failures() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_add_user_to_group()
+            .times(1)
+            .return_once(|_, _| Err(DomainError::InternalError("error".to_string())));
+        let (handler, mut receiver) = make_handler(mock);
+        handler
+            .add_user_to_group("bob", GroupId(3))
+            .await
+            .unwrap_err();
+        drop(handler);
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_notifies_password_changes() {
+        use crate::domain::{
+            sql_opaque_handler::register_password, test_backend_handler::TestBackendHandler,
+        };
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let handler = ConnectorBackendHandler {
+            inner: backend,
+            sender: Some(sender),
+            event_bus: EventBus::default(),
+            pending_registrations: Default::default(),
+        };
+        register_password(&handler, "bob", "password")
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.recv().await,
+            Some(ChangeEvent::PasswordChanged {
+                user_id: "bob".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publishes_on_the_event_bus() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_group_details()
+            .with(eq(GroupId(3)))
+            .times(1)
+            .return_once(|_| Ok(GroupIdAndName(GroupId(3), "family".to_string())));
+        mock.expect_delete_group()
+            .with(eq(GroupId(3)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let event_bus = EventBus::default();
+        // No connectors, only a subscriber.
+        let handler = ConnectorBackendHandler::new(
+            mock,
+            Vec::new(),
+            DeliveryLog::default(),
+            event_bus.clone(),
+        );
+        let mut receiver = event_bus.subscribe();
+        handler.delete_group(GroupId(3)).await.unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            ChangeEvent::GroupDeleted {
+                group_id: GroupId(3),
+                name: "family".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use super::{message_bus::Publishers, ChangeEvent, DeliveryLog, DeliveryRecord};
+use crate::{
+    domain::handler::{BackendHandler, GroupIdAndName, User},
+    infra::configuration::{ConnectorConfig, ConnectorKind},
+};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Maximum time spent delivering one event to one connector.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What we know about the users and groups concerned by an event, fetched once for all the
+/// connectors.
+#[derive(Debug, Default)]
+struct EventDetails {
+    /// The user, unless it was deleted.
+    user: Option<User>,
+    /// The groups of the user, for the group filters.
+    user_groups: Vec<String>,
+    /// Whether the user is locked by an admin.
+    user_locked: bool,
+    group: Option<GroupIdAndName>,
+}
+
+async fn get_event_details<Backend: BackendHandler>(
+    backend: &Backend,
+    event: &ChangeEvent,
+) -> Result<EventDetails> {
+    let get_user = |user_id: String| async move {
+        let user = backend.get_user_details(&user_id).await?;
+        let user_groups = backend
+            .get_user_groups(&user_id)
+            .await?
+            .into_iter()
+            .map(|g| g.1)
+            .collect();
+        let user_locked = backend.get_user_lock(&user_id).await?.is_some();
+        Ok::<_, anyhow::Error>((user, user_groups, user_locked))
+    };
+    Ok(match event {
+        ChangeEvent::UserCreated { user_id }
+        | ChangeEvent::UserUpdated { user_id }
+        | ChangeEvent::UserRenamed { user_id, .. }
+        | ChangeEvent::PasswordChanged { user_id }
+        | ChangeEvent::UserLocked { user_id, .. }
+        | ChangeEvent::UserUnlocked { user_id } => {
+            let (user, user_groups, user_locked) = get_user(user_id.clone()).await?;
+            EventDetails {
+                user: Some(user),
+                user_groups,
+                user_locked,
+                group: None,
+            }
+        }
+        ChangeEvent::UserDeleted { groups, .. } => EventDetails {
+            user_groups: groups.clone(),
+            ..Default::default()
+        },
+        ChangeEvent::GroupCreated { group_id } | ChangeEvent::GroupUpdated { group_id, .. } => {
+            EventDetails {
+                group: Some(backend.get_group_details(*group_id).await?),
+                ..Default::default()
+            }
+        }
+        ChangeEvent::GroupDeleted { group_id, name } => EventDetails {
+            group: Some(GroupIdAndName(*group_id, name.clone())),
+            ..Default::default()
+        },
+        ChangeEvent::UserAddedToGroup { user_id, group_id }
+        | ChangeEvent::UserRemovedFromGroup { user_id, group_id } => {
+            let (user, user_groups, user_locked) = get_user(user_id.clone()).await?;
+            EventDetails {
+                user: Some(user),
+                user_groups,
+                user_locked,
+                group: Some(backend.get_group_details(*group_id).await?),
+            }
+        }
+    })
+}
+
+/// Whether the event concerns one of the groups the connector is restricted to, or one of their
+/// members.
+fn is_relevant(connector: &ConnectorConfig, details: &EventDetails) -> bool {
+    connector.groups.is_empty()
+        || details
+            .user_groups
+            .iter()
+            .chain(details.group.iter().map(|g| &g.1))
+            .any(|g| connector.groups.contains(g))
+}
+
+fn map_user_fields(user: &User, field_mapping: &HashMap<String, String>) -> Value {
+    let fields = vec![
+        ("user_id", json!(user.user_id)),
+        ("email", json!(user.email)),
+        ("display_name", json!(user.display_name)),
+        ("first_name", json!(user.first_name)),
+        ("last_name", json!(user.last_name)),
+        ("creation_date", json!(user.creation_date)),
+    ];
+    Value::Object(
+        fields
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    field_mapping
+                        .get(name)
+                        .cloned()
+                        .unwrap_or_else(|| name.to_string()),
+                    value,
+                )
+            })
+            .collect(),
+    )
+}
+
+/// The JSON description of the event, sent by the HTTP, script and message bus connectors.
+fn make_payload(
+    event: &ChangeEvent,
+    details: &EventDetails,
+    field_mapping: &HashMap<String, String>,
+) -> Value {
+    json!({
+        "event": event,
+        "user": details.user.as_ref().map(|u| map_user_fields(u, field_mapping)),
+        "group": details.group.as_ref().map(|g| json!({"id": g.0, "name": g.1})),
+    })
+}
+
+async fn deliver_http(url: &str, headers: &HashMap<String, String>, payload: &Value) -> Result<()> {
+    let mut request = reqwest::Client::new().post(url).json(payload);
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request.send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn deliver_script(
+    command: &str,
+    args: &[String],
+    event: &str,
+    payload: &Value,
+) -> Result<()> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+    let mut child = tokio::process::Command::new(command)
+        .args(args)
+        .env("LLDAP_EVENT", event)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("while starting `{}`", command))?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&serde_json::to_vec(payload)?).await?;
+    drop(stdin);
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("`{}` failed with {}", command, status);
+    }
+    Ok(())
+}
+
+struct ScimClient<'a> {
+    client: reqwest::Client,
+    base_url: &'a str,
+    token: &'a str,
+}
+
+impl<'a> ScimClient<'a> {
+    const USER_SCHEMA: &'static str = "urn:ietf:params:scim:schemas:core:2.0:User";
+    const GROUP_SCHEMA: &'static str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+    const PATCH_SCHEMA: &'static str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+
+    fn new(base_url: &'a str, token: &'a str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/'),
+            token,
+        }
+    }
+
+    async fn send(&self, method: reqwest::Method, path: &str, body: Option<Value>) -> Result<()> {
+        let mut request = self
+            .client
+            .request(method, format!("{}/{}", self.base_url, path))
+            .bearer_auth(self.token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Finds the SCIM ID of the resource (`Users` or `Groups`) with the given attribute value.
+    async fn find_id(
+        &self,
+        resource: &str,
+        attribute: &str,
+        value: &str,
+    ) -> Result<Option<String>> {
+        let response: Value = self
+            .client
+            .get(format!("{}/{}", self.base_url, resource))
+            .bearer_auth(self.token)
+            .query(&[(
+                "filter",
+                format!(r#"{} eq "{}""#, attribute, value.replace('"', "\\\"")),
+            )])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response["Resources"]
+            .get(0)
+            .and_then(|r| r["id"].as_str())
+            .map(str::to_string))
+    }
+
+    async fn find_user(&self, user_id: &str) -> Result<Option<String>> {
+        self.find_id("Users", "userName", user_id).await
+    }
+
+    async fn find_group(&self, name: &str) -> Result<Option<String>> {
+        self.find_id("Groups", "displayName", name).await
+    }
+
+    /// Creates or replaces the user, who is inactive while locked. The user is looked up by the
+    /// previous ID, to replace it after a rename.
+    async fn upsert_user(&self, user: &User, previous_user_id: &str, locked: bool) -> Result<()> {
+        let body = json!({
+            "schemas": [Self::USER_SCHEMA],
+            "userName": user.user_id,
+            "displayName": user.display_name,
+            "name": {"givenName": user.first_name, "familyName": user.last_name},
+            "emails": [{"value": user.email, "primary": true}],
+            "active": !locked,
+        });
+        match self.find_user(previous_user_id).await? {
+            Some(id) => {
+                self.send(reqwest::Method::PUT, &format!("Users/{}", id), Some(body))
+                    .await
+            }
+            None => self.send(reqwest::Method::POST, "Users", Some(body)).await,
+        }
+    }
+
+    /// Adds the user to the group or removes them from it. The user is created first if needed:
+    /// with the connectors restricted to some groups, the users only become relevant when they
+    /// are added to one of them, after their creation was skipped.
+    async fn update_membership(
+        &self,
+        user: &User,
+        locked: bool,
+        group: &str,
+        add: bool,
+    ) -> Result<()> {
+        let group_id = self
+            .find_group(group)
+            .await?
+            .with_context(|| format!("group `{}` is not provisioned", group))?;
+        let operation = if add {
+            self.upsert_user(user, &user.user_id, locked).await?;
+            let scim_user_id = self
+                .find_user(&user.user_id)
+                .await?
+                .with_context(|| format!("user `{}` was not provisioned", user.user_id))?;
+            json!({"op": "add", "path": "members", "value": [{"value": scim_user_id}]})
+        } else {
+            match self.find_user(&user.user_id).await? {
+                Some(scim_user_id) => json!({
+                    "op": "remove",
+                    "path": format!(r#"members[value eq "{}"]"#, scim_user_id),
+                }),
+                // Never provisioned, so not a member.
+                None => return Ok(()),
+            }
+        };
+        self.send(
+            reqwest::Method::PATCH,
+            &format!("Groups/{}", group_id),
+            Some(json!({"schemas": [Self::PATCH_SCHEMA], "Operations": [operation]})),
+        )
+        .await
+    }
+
+    async fn deliver(&self, event: &ChangeEvent, details: &EventDetails) -> Result<()> {
+        let group_body = |name: &str| json!({"schemas": [Self::GROUP_SCHEMA], "displayName": name});
+        match (event, &details.user, &details.group) {
+            (ChangeEvent::UserCreated { .. }, Some(user), _)
+            | (ChangeEvent::UserUpdated { .. }, Some(user), _)
+            | (ChangeEvent::UserLocked { .. }, Some(user), _)
+            | (ChangeEvent::UserUnlocked { .. }, Some(user), _) => {
+                self.upsert_user(user, &user.user_id, details.user_locked)
+                    .await
+            }
+            (
+                ChangeEvent::UserRenamed {
+                    previous_user_id, ..
+                },
+                Some(user),
+                _,
+            ) => {
+                self.upsert_user(user, previous_user_id, details.user_locked)
+                    .await
+            }
+            (ChangeEvent::UserDeleted { user_id, .. }, _, _) => {
+                match self.find_user(user_id).await? {
+                    Some(id) => {
+                        self.send(reqwest::Method::DELETE, &format!("Users/{}", id), None)
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+            (ChangeEvent::GroupCreated { .. }, _, Some(group)) => {
+                self.send(reqwest::Method::POST, "Groups", Some(group_body(&group.1)))
+                    .await
+            }
+            (ChangeEvent::GroupUpdated { previous_name, .. }, _, Some(group)) => {
+                match self.find_group(previous_name).await? {
+                    Some(id) => {
+                        let operation =
+                            json!({"op": "replace", "path": "displayName", "value": group.1});
+                        self.send(
+                            reqwest::Method::PATCH,
+                            &format!("Groups/{}", id),
+                            Some(
+                                json!({"schemas": [Self::PATCH_SCHEMA], "Operations": [operation]}),
+                            ),
+                        )
+                        .await
+                    }
+                    None => {
+                        self.send(reqwest::Method::POST, "Groups", Some(group_body(&group.1)))
+                            .await
+                    }
+                }
+            }
+            (ChangeEvent::GroupDeleted { name, .. }, _, _) => match self.find_group(name).await? {
+                Some(id) => {
+                    self.send(reqwest::Method::DELETE, &format!("Groups/{}", id), None)
+                        .await
+                }
+                None => Ok(()),
+            },
+            (ChangeEvent::UserAddedToGroup { .. }, Some(user), Some(group)) => {
+                self.update_membership(user, details.user_locked, &group.1, true)
+                    .await
+            }
+            (ChangeEvent::UserRemovedFromGroup { .. }, Some(user), Some(group)) => {
+                self.update_membership(user, details.user_locked, &group.1, false)
+                    .await
+            }
+            // The SCIM server doesn't manage the passwords.
+            (ChangeEvent::PasswordChanged { .. }, _, _) => Ok(()),
+            _ => bail!("missing details for {:?}", event),
+        }
+    }
+}
+
+async fn deliver(
+    connector: &ConnectorConfig,
+    event: &ChangeEvent,
+    details: &EventDetails,
+    publishers: &mut Publishers,
+) -> Result<()> {
+    match &connector.kind {
+        ConnectorKind::Http { url, headers } => {
+            deliver_http(
+                url,
+                headers,
+                &make_payload(event, details, &connector.field_mapping),
+            )
+            .await
+        }
+        ConnectorKind::Script { command, args } => {
+            deliver_script(
+                command,
+                args,
+                event.name(),
+                &make_payload(event, details, &connector.field_mapping),
+            )
+            .await
+        }
+        ConnectorKind::Scim { base_url, token } => {
+            ScimClient::new(base_url, token)
+                .deliver(event, details)
+                .await
+        }
+        ConnectorKind::Nats { .. } | ConnectorKind::Kafka { .. } | ConnectorKind::Mqtt { .. } => {
+            publishers
+                .publish(
+                    connector,
+                    event,
+                    &make_payload(event, details, &connector.field_mapping),
+                )
+                .await
+        }
+    }
+}
+
+/// Delivers the events to the connectors, one at a time to preserve their order.
+pub(super) async fn deliver_events<Backend: BackendHandler>(
+    backend: Backend,
+    connectors: Vec<ConnectorConfig>,
+    log: DeliveryLog,
+    mut receiver: mpsc::UnboundedReceiver<ChangeEvent>,
+) {
+    let mut publishers = Publishers::default();
+    while let Some(event) = receiver.recv().await {
+        let record = |connector: &ConnectorConfig, error: Option<String>| DeliveryRecord {
+            connector: connector.name.clone(),
+            event: event.name().to_string(),
+            time: chrono::Utc::now(),
+            error,
+        };
+        let details = match get_event_details(&backend, &event).await {
+            Ok(details) => details,
+            Err(e) => {
+                // The user or group was probably deleted in the meantime.
+                warn!("Could not get the details of {:?}: {:#}", event, e);
+                for connector in &connectors {
+                    log.push(record(connector, Some(format!("{:#}", e))));
+                }
+                continue;
+            }
+        };
+        for connector in connectors.iter().filter(|c| is_relevant(c, &details)) {
+            let error = match tokio::time::timeout(
+                DELIVERY_TIMEOUT,
+                deliver(connector, &event, &details, &mut publishers),
+            )
+            .await
+            {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(format!("{:#}", e)),
+                Err(_) => Some("Timed out".to_string()),
+            };
+            if let Some(e) = &error {
+                warn!(
+                    "Could not deliver {} to connector {}: {}",
+                    event.name(),
+                    connector.name,
+                    e
+                );
+            }
+            log.push(record(connector, error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    fn make_connector(groups: Vec<&str>) -> ConnectorConfig {
+        ConnectorConfig {
+            name: "test".to_string(),
+            kind: ConnectorKind::Script {
+                command: "true".to_string(),
+                args: vec![],
+            },
+            groups: groups.into_iter().map(str::to_string).collect(),
+            field_mapping: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_relevant() {
+        let details = EventDetails {
+            user_groups: vec!["family".to_string()],
+            group: Some(GroupIdAndName(GroupId(2), "friends".to_string())),
+            ..Default::default()
+        };
+        assert!(is_relevant(&make_connector(vec![]), &details));
+        assert!(is_relevant(&make_connector(vec!["family"]), &details));
+        assert!(is_relevant(&make_connector(vec!["friends"]), &details));
+        assert!(!is_relevant(&make_connector(vec!["work"]), &details));
+    }
+
+    #[test]
+    fn test_payload_field_mapping() {
+        let details = EventDetails {
+            user: Some(User {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut field_mapping = HashMap::new();
+        field_mapping.insert("user_id".to_string(), "username".to_string());
+        let payload = make_payload(
+            &ChangeEvent::UserCreated {
+                user_id: "bob".to_string(),
+            },
+            &details,
+            &field_mapping,
+        );
+        assert_eq!(payload["event"]["type"], "user_created");
+        assert_eq!(payload["user"]["username"], "bob");
+        assert_eq!(payload["user"]["email"], "bob@bob.bob");
+        assert!(payload["user"].get("user_id").is_none());
+        assert!(payload["group"].is_null());
+    }
+}
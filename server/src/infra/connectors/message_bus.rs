@@ -0,0 +1,208 @@
+//! Publishes the change events to message buses. Each client is behind the cargo feature of the
+//! same name (`nats`, `kafka`, `mqtt`), so that the default build doesn't pull them in.
+
+use super::ChangeEvent;
+use crate::infra::configuration::{ConnectorConfig, ConnectorKind};
+#[allow(unused_imports)]
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The key of the Kafka messages: the events about the same user or group go to the same
+/// partition, which keeps them in order.
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+fn partition_key(event: &ChangeEvent) -> String {
+    match event {
+        ChangeEvent::UserCreated { user_id }
+        | ChangeEvent::UserUpdated { user_id }
+        | ChangeEvent::UserRenamed { user_id, .. }
+        | ChangeEvent::UserDeleted { user_id, .. }
+        | ChangeEvent::UserAddedToGroup { user_id, .. }
+        | ChangeEvent::UserRemovedFromGroup { user_id, .. }
+        | ChangeEvent::PasswordChanged { user_id }
+        | ChangeEvent::UserLocked { user_id, .. }
+        | ChangeEvent::UserUnlocked { user_id } => format!("user:{}", user_id),
+        ChangeEvent::GroupCreated { group_id }
+        | ChangeEvent::GroupUpdated { group_id, .. }
+        | ChangeEvent::GroupDeleted { group_id, .. } => format!("group:{}", group_id.0),
+    }
+}
+
+/// A connected client.
+enum Publisher {
+    #[cfg(feature = "nats")]
+    Nats(nats_client::Connection),
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::producer::FutureProducer),
+    #[cfg(feature = "mqtt")]
+    Mqtt(rumqttc::AsyncClient),
+}
+
+/// The clients of the message bus connectors, by connector name, connected on first use.
+#[derive(Default)]
+pub(super) struct Publishers(HashMap<String, Publisher>);
+
+#[cfg(feature = "nats")]
+async fn connect_nats(url: &str) -> Result<Publisher> {
+    let url = url.to_string();
+    let connection = tokio::task::spawn_blocking(move || nats_client::connect(&url))
+        .await?
+        .context("while connecting to NATS")?;
+    Ok(Publisher::Nats(connection))
+}
+
+#[cfg(feature = "kafka")]
+fn connect_kafka(brokers: &str) -> Result<Publisher> {
+    let producer = rdkafka::ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("message.timeout.ms", "30000")
+        .create()
+        .context("while creating the Kafka producer")?;
+    Ok(Publisher::Kafka(producer))
+}
+
+#[cfg(feature = "mqtt")]
+fn connect_mqtt(name: &str, host: &str, port: u16) -> Publisher {
+    let options = rumqttc::MqttOptions::new(format!("lldap-{}", name), host, port);
+    let (client, mut event_loop) = rumqttc::AsyncClient::new(options, 100);
+    // The event loop does the actual network I/O, and reconnects after errors.
+    let name = name.to_string();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = event_loop.poll().await {
+                tracing::warn!("MQTT connection of connector {} failed: {:#}", name, e);
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+        }
+    });
+    Publisher::Mqtt(client)
+}
+
+impl Publishers {
+    async fn get_publisher(&mut self, connector: &ConnectorConfig) -> Result<&Publisher> {
+        if !self.0.contains_key(&connector.name) {
+            #[allow(unreachable_patterns)]
+            let publisher = match &connector.kind {
+                #[cfg(feature = "nats")]
+                ConnectorKind::Nats { url, .. } => connect_nats(url).await?,
+                #[cfg(feature = "kafka")]
+                ConnectorKind::Kafka { brokers, .. } => connect_kafka(brokers)?,
+                #[cfg(feature = "mqtt")]
+                ConnectorKind::Mqtt { host, port, .. } => {
+                    connect_mqtt(&connector.name, host, *port)
+                }
+                ConnectorKind::Nats { .. } => bail!("lldap was built without the `nats` feature"),
+                ConnectorKind::Kafka { .. } => bail!("lldap was built without the `kafka` feature"),
+                ConnectorKind::Mqtt { .. } => bail!("lldap was built without the `mqtt` feature"),
+                _ => bail!("{} is not a message bus connector", connector.name),
+            };
+            self.0.insert(connector.name.clone(), publisher);
+        }
+        Ok(&self.0[&connector.name])
+    }
+
+    /// Publishes the payload describing the event with the connector's client.
+    #[allow(clippy::match_single_binding)]
+    #[cfg_attr(
+        not(any(feature = "nats", feature = "kafka", feature = "mqtt")),
+        allow(unused_variables)
+    )]
+    pub(super) async fn publish(
+        &mut self,
+        connector: &ConnectorConfig,
+        event: &ChangeEvent,
+        payload: &Value,
+    ) -> Result<()> {
+        let payload = serde_json::to_vec(payload)?;
+        match (self.get_publisher(connector).await?, &connector.kind) {
+            #[cfg(feature = "nats")]
+            (Publisher::Nats(connection), ConnectorKind::Nats { subject, .. }) => {
+                let connection = connection.clone();
+                let subject = format!("{}.{}", subject, event.name());
+                tokio::task::spawn_blocking(move || connection.publish(&subject, payload))
+                    .await??;
+                Ok(())
+            }
+            #[cfg(feature = "kafka")]
+            (Publisher::Kafka(producer), ConnectorKind::Kafka { topic, .. }) => {
+                let key = partition_key(event);
+                producer
+                    .send(
+                        rdkafka::producer::FutureRecord::to(topic)
+                            .key(&key)
+                            .payload(&payload),
+                        std::time::Duration::from_secs(0),
+                    )
+                    .await
+                    .map_err(|(e, _)| e)?;
+                Ok(())
+            }
+            #[cfg(feature = "mqtt")]
+            (Publisher::Mqtt(client), ConnectorKind::Mqtt { topic, .. }) => {
+                client
+                    .publish(
+                        format!("{}/{}", topic, event.name()),
+                        rumqttc::QoS::AtLeastOnce,
+                        false,
+                        payload,
+                    )
+                    .await?;
+                Ok(())
+            }
+            #[allow(unreachable_patterns)]
+            _ => bail!("Unexpected client for connector {}", connector.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    #[test]
+    fn test_partition_key() {
+        assert_eq!(
+            partition_key(&ChangeEvent::UserAddedToGroup {
+                user_id: "bob".to_string(),
+                group_id: GroupId(3),
+            }),
+            "user:bob"
+        );
+        assert_eq!(
+            partition_key(&ChangeEvent::GroupDeleted {
+                group_id: GroupId(3),
+                name: "family".to_string(),
+            }),
+            "group:3"
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(not(feature = "nats"))]
+    async fn test_missing_feature() {
+        let connector = ConnectorConfig {
+            name: "bus".to_string(),
+            kind: ConnectorKind::Nats {
+                url: "nats://localhost".to_string(),
+                subject: "lldap".to_string(),
+            },
+            groups: vec![],
+            field_mapping: HashMap::new(),
+        };
+        let error = Publishers::default()
+            .publish(
+                &connector,
+                &ChangeEvent::PasswordChanged {
+                    user_id: "bob".to_string(),
+                },
+                &Value::Null,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "lldap was built without the `nats` feature"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Connectors push the changes made to users and groups to external systems (see
+//! [`ConnectorConfig`](crate::infra::configuration::ConnectorConfig)).
+//!
+//! [`ConnectorBackendHandler`] wraps the real backend handler: every successful modification emits
+//! a [`ChangeEvent`], delivered in the background to each interested connector. The outcome of the
+//! last deliveries is kept in a [`DeliveryLog`], displayed in the administration interface. The
+//! events are also published on the [`EventBus`], for the GraphQL subscriptions.
+//!
+//! The message bus connectors (NATS, Kafka, MQTT) are only available when the server is built
+//! with the corresponding cargo feature.
+
+mod backend_handler;
+mod delivery;
+mod message_bus;
+
+pub use backend_handler::ConnectorBackendHandler;
+
+use crate::domain::handler::GroupId;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// A modification of the users, groups or memberships.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    UserCreated {
+        user_id: String,
+    },
+    UserUpdated {
+        user_id: String,
+    },
+    UserRenamed {
+        user_id: String,
+        previous_user_id: String,
+    },
+    UserDeleted {
+        user_id: String,
+        /// The groups the user was a member of before the deletion.
+        groups: Vec<String>,
+    },
+    GroupCreated {
+        group_id: GroupId,
+    },
+    GroupUpdated {
+        group_id: GroupId,
+        previous_name: String,
+    },
+    GroupDeleted {
+        group_id: GroupId,
+        name: String,
+    },
+    UserAddedToGroup {
+        user_id: String,
+        group_id: GroupId,
+    },
+    UserRemovedFromGroup {
+        user_id: String,
+        group_id: GroupId,
+    },
+    PasswordChanged {
+        user_id: String,
+    },
+    UserLocked {
+        user_id: String,
+        reason: String,
+    },
+    UserUnlocked {
+        user_id: String,
+    },
+}
+
+impl ChangeEvent {
+    /// The name of the event type, as found in the `type` field of the payloads.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeEvent::UserCreated { .. } => "user_created",
+            ChangeEvent::UserUpdated { .. } => "user_updated",
+            ChangeEvent::UserRenamed { .. } => "user_renamed",
+            ChangeEvent::UserDeleted { .. } => "user_deleted",
+            ChangeEvent::GroupCreated { .. } => "group_created",
+            ChangeEvent::GroupUpdated { .. } => "group_updated",
+            ChangeEvent::GroupDeleted { .. } => "group_deleted",
+            ChangeEvent::UserAddedToGroup { .. } => "user_added_to_group",
+            ChangeEvent::UserRemovedFromGroup { .. } => "user_removed_from_group",
+            ChangeEvent::PasswordChanged { .. } => "password_changed",
+            ChangeEvent::UserLocked { .. } => "user_locked",
+            ChangeEvent::UserUnlocked { .. } => "user_unlocked",
+        }
+    }
+}
+
+/// The outcome of the delivery of an event to a connector.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeliveryRecord {
+    pub connector: String,
+    pub event: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// `None` if the delivery succeeded.
+    pub error: Option<String>,
+}
+
+const MAX_DELIVERY_RECORDS: usize = 100;
+
+/// The last deliveries to the connectors, shared between the delivery task and the HTTP server.
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryLog(Arc<Mutex<VecDeque<DeliveryRecord>>>);
+
+impl DeliveryLog {
+    pub(crate) fn push(&self, record: DeliveryRecord) {
+        let mut records = self.0.lock().unwrap();
+        if records.len() == MAX_DELIVERY_RECORDS {
+            records.pop_back();
+        }
+        records.push_front(record);
+    }
+
+    /// The last deliveries, most recent first.
+    pub fn records(&self) -> Vec<DeliveryRecord> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The events not yet received by a subscriber before it starts missing them.
+const EVENT_BUS_CAPACITY: usize = 1000;
+
+/// The changes as they happen, for whoever subscribes: a slow subscriber misses events rather than
+/// slowing the modifications down.
+#[derive(Clone, Debug)]
+pub struct EventBus(broadcast::Sender<ChangeEvent>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_BUS_CAPACITY).0)
+    }
+}
+
+impl EventBus {
+    pub(crate) fn publish(&self, event: ChangeEvent) {
+        // Only fails if nobody is listening.
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn has_subscribers(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    /// The events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.0.subscribe()
+    }
+}
@@ -1,6 +1,9 @@
 use crate::{
-    domain::sql_tables::{DbQueryBuilder, Pool},
-    infra::jwt_sql_tables::{JwtRefreshStorage, JwtStorage},
+    domain::{
+        sql_backend_handler::purge_deleted_users,
+        sql_tables::{AuditLog, AuthFailures, BuildDbQuery, DbBackend, Pool},
+    },
+    infra::jwt_sql_tables::{JwtRefreshStorage, JwtStorage, PasswordResetTokens},
 };
 use actix::prelude::*;
 use chrono::Local;
@@ -12,6 +15,12 @@ use std::{str::FromStr, time::Duration};
 pub struct Scheduler {
     schedule: Schedule,
     sql_pool: Pool,
+    /// How long the failed authentications are kept.
+    auth_failure_retention: chrono::Duration,
+    /// How long the entries of the audit log are kept.
+    audit_log_retention: chrono::Duration,
+    /// How long the deleted users stay in the recycle bin.
+    deleted_user_retention: chrono::Duration,
 }
 
 // Provide Actor implementation for our actor
@@ -19,7 +28,7 @@ impl Actor for Scheduler {
     type Context = Context<Self>;
 
     fn started(&mut self, context: &mut Context<Self>) {
-        log::info!("DB Cleanup Cron started");
+        tracing::info!("DB Cleanup Cron started");
 
         context.run_later(self.duration_until_next(), move |this, ctx| {
             this.schedule_task(ctx)
@@ -27,19 +36,36 @@ impl Actor for Scheduler {
     }
 
     fn stopped(&mut self, _ctx: &mut Context<Self>) {
-        log::info!("DB Cleanup stopped");
+        tracing::info!("DB Cleanup stopped");
     }
 }
 
 impl Scheduler {
-    pub fn new(cron_expression: &str, sql_pool: Pool) -> Self {
+    pub fn new(
+        cron_expression: &str,
+        sql_pool: Pool,
+        auth_failure_retention: chrono::Duration,
+        audit_log_retention: chrono::Duration,
+        deleted_user_retention: chrono::Duration,
+    ) -> Self {
         let schedule = Schedule::from_str(cron_expression).unwrap();
-        Self { schedule, sql_pool }
+        Self {
+            schedule,
+            sql_pool,
+            auth_failure_retention,
+            audit_log_retention,
+            deleted_user_retention,
+        }
     }
 
     fn schedule_task(&self, ctx: &mut Context<Self>) {
-        log::info!("Cleaning DB");
-        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(self.sql_pool.clone()));
+        tracing::info!("Cleaning DB");
+        let future = actix::fut::wrap_future::<_, Self>(Self::cleanup_db(
+            self.sql_pool.clone(),
+            self.auth_failure_retention,
+            self.audit_log_retention,
+            self.deleted_user_retention,
+        ));
         ctx.spawn(future);
 
         ctx.run_later(self.duration_until_next(), move |this, ctx| {
@@ -47,30 +73,68 @@ impl Scheduler {
         });
     }
 
-    async fn cleanup_db(sql_pool: Pool) {
-        if let Err(e) = sqlx::query(
-            &Query::delete()
-                .from_table(JwtRefreshStorage::Table)
-                .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).lt(Local::now().naive_utc()))
-                .to_string(DbQueryBuilder {}),
-        )
-        .execute(&sql_pool)
-        .await
-        {
-            log::error!("DB error while cleaning up JWT refresh tokens: {}", e);
+    async fn cleanup_db(
+        sql_pool: Pool,
+        auth_failure_retention: chrono::Duration,
+        audit_log_retention: chrono::Duration,
+        deleted_user_retention: chrono::Duration,
+    ) {
+        let backend = DbBackend::of(&sql_pool);
+        let (query, values) = Query::delete()
+            .from_table(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).lt(Local::now().naive_utc()))
+            .build_db_query(backend);
+        if let Err(e) = sqlx::query_with(&query, values).execute(&sql_pool).await {
+            tracing::error!("DB error while cleaning up JWT refresh tokens: {}", e);
         };
-        if let Err(e) = sqlx::query(
-            &Query::delete()
-                .from_table(JwtStorage::Table)
-                .and_where(Expr::col(JwtStorage::ExpiryDate).lt(Local::now().naive_utc()))
-                .to_string(DbQueryBuilder {}),
-        )
-        .execute(&sql_pool)
-        .await
-        {
-            log::error!("DB error while cleaning up JWT storage: {}", e);
+        let (query, values) = Query::delete()
+            .from_table(JwtStorage::Table)
+            .and_where(Expr::col(JwtStorage::ExpiryDate).lt(Local::now().naive_utc()))
+            .build_db_query(backend);
+        if let Err(e) = sqlx::query_with(&query, values).execute(&sql_pool).await {
+            tracing::error!("DB error while cleaning up JWT storage: {}", e);
         };
-        log::info!("DB cleaned!");
+        let (query, values) = Query::delete()
+            .from_table(PasswordResetTokens::Table)
+            .and_where(Expr::col(PasswordResetTokens::ExpiryDate).lt(Local::now().naive_utc()))
+            .build_db_query(backend);
+        if let Err(e) = sqlx::query_with(&query, values).execute(&sql_pool).await {
+            tracing::error!(
+                "DB error while cleaning up the password reset tokens: {}",
+                e
+            );
+        };
+        let (query, values) = Query::delete()
+            .from_table(AuthFailures::Table)
+            .and_where(
+                Expr::col(AuthFailures::Time)
+                    .lt((chrono::Utc::now() - auth_failure_retention).naive_utc()),
+            )
+            .build_db_query(backend);
+        if let Err(e) = sqlx::query_with(&query, values).execute(&sql_pool).await {
+            tracing::error!(
+                "DB error while cleaning up the failed authentications: {}",
+                e
+            );
+        };
+        let (query, values) = Query::delete()
+            .from_table(AuditLog::Table)
+            .and_where(
+                Expr::col(AuditLog::Time)
+                    .lt((chrono::Utc::now() - audit_log_retention).naive_utc()),
+            )
+            .build_db_query(backend);
+        if let Err(e) = sqlx::query_with(&query, values).execute(&sql_pool).await {
+            tracing::error!("DB error while cleaning up the audit log: {}", e);
+        };
+        match purge_deleted_users(&sql_pool, chrono::Utc::now() - deleted_user_retention).await {
+            Ok(user_ids) if !user_ids.is_empty() => {
+                tracing::info!("Purged the deleted users {}", user_ids.join(", "))
+            }
+            Ok(_) => (),
+            Err(e) => tracing::error!("DB error while purging the deleted users: {}", e),
+        }
+        tracing::info!("DB cleaned!");
     }
 
     fn duration_until_next(&self) -> Duration {
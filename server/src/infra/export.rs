@@ -0,0 +1,280 @@
+//! Exports the users, groups and memberships as LDIF, for backups and migrations to other LDAP
+//! servers such as OpenLDAP.
+//!
+//! The entries have the same DNs as the ones served by the LDAP server, under `ou=people` and
+//! `ou=groups` of the base DN, and only standard object classes so that they can be loaded with
+//! `ldapadd` or `slapadd`. The passwords are not exported.
+
+use crate::{
+    domain::{
+        error::Result,
+        handler::{BackendHandler, Group, User},
+    },
+    infra::{
+        access_control::AccessControlledBackendHandler,
+        auth_service::check_if_token_is_valid,
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::{error_to_http_response, AppState},
+    },
+};
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::Deserialize;
+
+/// The maximum length of an LDIF line, after which it is folded.
+const MAX_LINE_LENGTH: usize = 76;
+
+/// Escapes the special characters of a DN attribute value, as in RFC 4514.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (index, c) in value.chars().enumerate() {
+        let is_special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (index == 0 && (c == ' ' || c == '#'))
+            || (index == last && c == ' ');
+        if is_special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether the value can be written as is: a SAFE-STRING of RFC 2849, without trailing space.
+/// The other values are base64-encoded.
+fn is_safe_string(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b.is_ascii() && b != b'\0' && b != b'\n' && b != b'\r')
+        && !value.starts_with(&[' ', ':', '<'][..])
+        && !value.ends_with(' ')
+}
+
+/// Writes the line, folded into continuation lines starting with a space if it's too long. The
+/// lines are ASCII, so they can be split anywhere.
+fn write_line(ldif: &mut String, line: &str) {
+    let (first, mut rest) = line.split_at(line.len().min(MAX_LINE_LENGTH));
+    ldif.push_str(first);
+    ldif.push('\n');
+    while !rest.is_empty() {
+        let (next, remaining) = rest.split_at(rest.len().min(MAX_LINE_LENGTH - 1));
+        ldif.push(' ');
+        ldif.push_str(next);
+        ldif.push('\n');
+        rest = remaining;
+    }
+}
+
+fn write_attribute(ldif: &mut String, name: &str, value: &str) {
+    let line = if value.is_empty() {
+        format!("{}:", name)
+    } else if is_safe_string(value) {
+        format!("{}: {}", name, value)
+    } else {
+        format!("{}:: {}", name, base64::encode(value))
+    };
+    write_line(ldif, &line);
+}
+
+fn write_organizational_unit(ldif: &mut String, name: &str, base_dn: &str) {
+    write_attribute(ldif, "dn", &format!("ou={},{}", name, base_dn));
+    write_attribute(ldif, "objectClass", "organizationalUnit");
+    write_attribute(ldif, "ou", name);
+    ldif.push('\n');
+}
+
+fn get_user_dn(user_id: &str, base_dn: &str) -> String {
+    format!("cn={},ou=people,{}", escape_dn_value(user_id), base_dn)
+}
+
+/// Writes the entries of the organizational units, the users and the groups. The members of the
+/// groups are listed as `uniqueMember`, which is required by `groupOfUniqueNames`: the groups
+/// without members get an empty one.
+pub fn write_ldif(users: &[User], groups: &[Group], base_dn: &str) -> String {
+    let mut ldif = String::from("version: 1\n\n");
+    write_organizational_unit(&mut ldif, "people", base_dn);
+    write_organizational_unit(&mut ldif, "groups", base_dn);
+    for user in users {
+        write_attribute(&mut ldif, "dn", &get_user_dn(&user.user_id, base_dn));
+        write_attribute(&mut ldif, "objectClass", "inetOrgPerson");
+        // The naming attribute has to be present in the entry.
+        write_attribute(&mut ldif, "cn", &user.user_id);
+        write_attribute(&mut ldif, "uid", &user.user_id);
+        write_attribute(&mut ldif, "mail", &user.email);
+        if !user.display_name.is_empty() {
+            write_attribute(&mut ldif, "displayName", &user.display_name);
+        }
+        if !user.first_name.is_empty() {
+            write_attribute(&mut ldif, "givenName", &user.first_name);
+        }
+        // The surname is required by the `person` object class.
+        let last_name = if user.last_name.is_empty() {
+            &user.user_id
+        } else {
+            &user.last_name
+        };
+        write_attribute(&mut ldif, "sn", last_name);
+        ldif.push('\n');
+    }
+    for group in groups {
+        write_attribute(
+            &mut ldif,
+            "dn",
+            &format!(
+                "cn={},ou=groups,{}",
+                escape_dn_value(&group.display_name),
+                base_dn
+            ),
+        );
+        write_attribute(&mut ldif, "objectClass", "groupOfUniqueNames");
+        write_attribute(&mut ldif, "cn", &group.display_name);
+        if group.users.is_empty() {
+            write_attribute(&mut ldif, "uniqueMember", "");
+        }
+        for user_id in &group.users {
+            write_attribute(&mut ldif, "uniqueMember", &get_user_dn(user_id, base_dn));
+        }
+        ldif.push('\n');
+    }
+    ldif
+}
+
+/// Reads all the users and groups, and writes them as LDIF under the base DN.
+pub async fn export_ldif<Handler: BackendHandler>(
+    handler: &Handler,
+    base_dn: &str,
+) -> Result<String> {
+    let users = handler.list_users(None).await?;
+    let groups = handler.list_groups(None).await?;
+    Ok(write_ldif(&users, &groups, base_dn))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExportQuery {
+    /// Defaults to the base DN of the LDAP server.
+    base_dn: Option<String>,
+}
+
+/// Serves the export to the users that can read all the users and groups.
+pub(crate) async fn get_ldif_export<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+    query: web::Query<ExportQuery>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let validation_result = check_if_token_is_valid(&data, bearer.token()).await?;
+    let handler =
+        AccessControlledBackendHandler::new(data.backend_handler.clone(), validation_result);
+    let base_dn = query
+        .into_inner()
+        .base_dn
+        .unwrap_or_else(|| data.ldap_base_dn.clone());
+    Ok(match export_ldif(&handler, &base_dn).await {
+        Ok(ldif) => HttpResponse::Ok()
+            .content_type("text/x-ldif; charset=utf-8")
+            .body(ldif),
+        Err(e) => error_to_http_response(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("bob"), "bob");
+        assert_eq!(escape_dn_value("Smith, John"), "Smith\\, John");
+        assert_eq!(escape_dn_value("#a=b "), "\\#a\\=b\\ ");
+    }
+
+    #[test]
+    fn test_write_ldif() {
+        let users = vec![
+            User {
+                user_id: "bob".to_string(),
+                email: "bob@example.com".to_string(),
+                display_name: "Bob Bobberson".to_string(),
+                first_name: "Bob".to_string(),
+                last_name: "Bobberson".to_string(),
+                ..Default::default()
+            },
+            User {
+                user_id: "jose".to_string(),
+                email: "jose@example.com".to_string(),
+                display_name: "Jos\u{e9}".to_string(),
+                ..Default::default()
+            },
+        ];
+        let groups = vec![
+            Group {
+                id: GroupId(1),
+                display_name: "Admins, IT".to_string(),
+                users: vec!["bob".to_string(), "jose".to_string()],
+            },
+            Group {
+                id: GroupId(2),
+                display_name: "empty".to_string(),
+                users: vec![],
+            },
+        ];
+        assert_eq!(
+            write_ldif(&users, &groups, "dc=example,dc=com"),
+            "version: 1\n\
+             \n\
+             dn: ou=people,dc=example,dc=com\n\
+             objectClass: organizationalUnit\n\
+             ou: people\n\
+             \n\
+             dn: ou=groups,dc=example,dc=com\n\
+             objectClass: organizationalUnit\n\
+             ou: groups\n\
+             \n\
+             dn: cn=bob,ou=people,dc=example,dc=com\n\
+             objectClass: inetOrgPerson\n\
+             cn: bob\n\
+             uid: bob\n\
+             mail: bob@example.com\n\
+             displayName: Bob Bobberson\n\
+             givenName: Bob\n\
+             sn: Bobberson\n\
+             \n\
+             dn: cn=jose,ou=people,dc=example,dc=com\n\
+             objectClass: inetOrgPerson\n\
+             cn: jose\n\
+             uid: jose\n\
+             mail: jose@example.com\n\
+             displayName:: Sm9zw6k=\n\
+             sn: jose\n\
+             \n\
+             dn: cn=Admins\\, IT,ou=groups,dc=example,dc=com\n\
+             objectClass: groupOfUniqueNames\n\
+             cn: Admins, IT\n\
+             uniqueMember: cn=bob,ou=people,dc=example,dc=com\n\
+             uniqueMember: cn=jose,ou=people,dc=example,dc=com\n\
+             \n\
+             dn: cn=empty,ou=groups,dc=example,dc=com\n\
+             objectClass: groupOfUniqueNames\n\
+             cn: empty\n\
+             uniqueMember:\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn test_long_lines_are_folded() {
+        let mut ldif = String::new();
+        write_attribute(&mut ldif, "description", &"a".repeat(200));
+        let lines = ldif.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.len() <= MAX_LINE_LENGTH));
+        assert!(lines[1].starts_with(' ') && lines[2].starts_with(' '));
+        assert_eq!(
+            lines[0].to_string() + &lines[1][1..] + &lines[2][1..],
+            format!("description: {}", "a".repeat(200))
+        );
+    }
+}
@@ -1,33 +1,72 @@
 use crate::{
     domain::handler::BackendHandler,
     infra::{
-        auth_service::{check_if_token_is_valid, ValidationResults},
+        access_control::{AccessControlledBackendHandler, ValidationResults},
+        api_tokens::{ApiTokenManager, WebApiTokenManager},
+        audit_backend_handler::{with_audit_context, AuditContext},
+        auth_service::check_if_token_is_valid,
+        bind_throttle::BindThrottle,
         cli::ExportGraphQLSchemaOpts,
-        tcp_server::AppState,
+        configuration::{AvatarConfig, PasswordExpiryConfig, PasswordPolicyConfig},
+        connectors::{DeliveryLog, EventBus},
+        mail::{Mailer, SmtpMailer},
+        sessions::{SessionManager, WebSessionManager},
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::{error_to_http_response, AppState},
     },
 };
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, InternalError},
+    http::header::AUTHORIZATION,
+    web, Error, HttpRequest, HttpResponse,
+};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use juniper::{EmptySubscription, RootNode};
-use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
+use juniper::{EmptyMutation, RootNode, Variables};
+use juniper_actix::{
+    graphiql_handler, graphql_handler, playground_handler, subscriptions::subscriptions_handler,
+};
+use juniper_graphql_ws::ConnectionConfig;
+use std::sync::Arc;
 
-use super::{mutation::Mutation, query::Query};
+use super::{mutation::Mutation, query::Query, subscription::Subscription};
 
 pub struct Context<Handler: BackendHandler> {
     pub handler: Box<Handler>,
     pub validation_result: ValidationResults,
+    pub delivery_log: DeliveryLog,
+    pub event_bus: EventBus,
+    pub bind_throttle: BindThrottle,
+    pub sessions: Arc<dyn SessionManager>,
+    pub api_tokens: Arc<dyn ApiTokenManager>,
+    pub mailer: Arc<dyn Mailer>,
+    pub avatar_config: AvatarConfig,
+    pub password_expiry_config: PasswordExpiryConfig,
+    pub password_policy_config: PasswordPolicyConfig,
 }
 
 impl<Handler: BackendHandler> juniper::Context for Context<Handler> {}
 
-type Schema<Handler> =
-    RootNode<'static, Query<Handler>, Mutation<Handler>, EmptySubscription<Context<Handler>>>;
+type Schema<Handler> = RootNode<'static, Query<Handler>, Mutation<Handler>, Subscription<Handler>>;
 
 fn schema<Handler: BackendHandler + Sync>() -> Schema<Handler> {
     Schema::new(
         Query::<Handler>::new(),
         Mutation::<Handler>::new(),
-        EmptySubscription::<Context<Handler>>::new(),
+        Subscription::<Handler>::new(),
+    )
+}
+
+/// The schema of the WebSocket connections, without the mutations: they go through the HTTP
+/// endpoint, where they are recorded in the audit log with their author.
+type SubscriptionSchema<Handler> =
+    RootNode<'static, Query<Handler>, EmptyMutation<Context<Handler>>, Subscription<Handler>>;
+
+fn subscription_schema<Handler: BackendHandler + Sync>() -> SubscriptionSchema<Handler> {
+    SubscriptionSchema::new(
+        Query::<Handler>::new(),
+        EmptyMutation::<Context<Handler>>::new(),
+        Subscription::<Handler>::new(),
     )
 }
 
@@ -52,36 +91,159 @@ pub fn export_schema(opts: ExportGraphQLSchemaOpts) -> anyhow::Result<()> {
 }
 
 async fn graphiql_route() -> Result<HttpResponse, Error> {
-    graphiql_handler("/api/graphql", None).await
+    graphiql_handler("/api/graphql", Some("/api/graphql/subscriptions")).await
 }
 async fn playground_route() -> Result<HttpResponse, Error> {
-    playground_handler("/api/graphql", None).await
+    playground_handler("/api/graphql", Some("/api/graphql/subscriptions")).await
 }
 
-async fn graphql_route<Handler: BackendHandler + Sync>(
-    req: actix_web::HttpRequest,
-    mut payload: actix_web::web::Payload,
-    data: web::Data<AppState<Handler>>,
-) -> Result<HttpResponse, Error> {
+/// Authenticates the request, refusing the users whose password expired: they can only change it,
+/// which doesn't go through GraphQL.
+async fn authenticate<Handler>(
+    req: &HttpRequest,
+    payload: &mut Payload,
+    data: &AppState<Handler>,
+) -> Result<ValidationResults, Error>
+where
+    Handler: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
     use actix_web::FromRequest;
-    let bearer = BearerAuth::from_request(&req, &mut payload.0).await?;
-    let validation_result = check_if_token_is_valid(&data, bearer.token())?;
-    let context = Context::<Handler> {
-        handler: Box::new(data.backend_handler.clone()),
+    let bearer = BearerAuth::from_request(req, payload).await?;
+    authenticate_token(data, bearer.token()).await
+}
+
+/// Same as [`authenticate`], with the token sent some other way.
+async fn authenticate_token<Handler>(
+    data: &AppState<Handler>,
+    token: &str,
+) -> Result<ValidationResults, Error>
+where
+    Handler: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let validation_result = check_if_token_is_valid(data, token).await?;
+    if data.password_expiry_config.force_change {
+        let expires_at = data
+            .backend_handler
+            .get_password_change(&validation_result.user)
+            .await
+            .map_err(|e| InternalError::from_response(e.to_string(), error_to_http_response(e)))?
+            .and_then(|changed_at| data.password_expiry_config.expires_at(changed_at));
+        if matches!(expires_at, Some(expires_at) if expires_at <= chrono::Utc::now()) {
+            return Err(ErrorForbidden("The password has expired, change it first"));
+        }
+    }
+    Ok(validation_result)
+}
+
+fn make_context<Handler>(
+    data: &AppState<Handler>,
+    validation_result: ValidationResults,
+) -> Context<AccessControlledBackendHandler<Handler>>
+where
+    Handler: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    // The resolvers check the permissions too, but the handler makes sure nothing is missed.
+    Context::<AccessControlledBackendHandler<Handler>> {
+        handler: Box::new(AccessControlledBackendHandler::new(
+            data.backend_handler.clone(),
+            validation_result.clone(),
+        )),
         validation_result,
+        delivery_log: data.delivery_log.clone(),
+        event_bus: data.event_bus.clone(),
+        bind_throttle: data.bind_throttle.clone(),
+        sessions: Arc::new(WebSessionManager::new(
+            data.backend_handler.clone(),
+            data.jwt_blacklist.clone(),
+            data.jwt_keys.clone(),
+        )),
+        api_tokens: Arc::new(WebApiTokenManager::new(data.backend_handler.clone())),
+        mailer: Arc::new(SmtpMailer::new(data.smtp_config.clone())),
+        avatar_config: data.avatar_config.clone(),
+        password_expiry_config: data.password_expiry_config.clone(),
+        password_policy_config: data.password_policy_config.clone(),
+    }
+}
+
+async fn graphql_route<Handler: BackendHandler + TcpBackendHandler + Sync + 'static>(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    data: web::Data<AppState<Handler>>,
+) -> Result<HttpResponse, Error> {
+    let validation_result = authenticate(&req, &mut payload.0, &data).await?;
+    // The changes made with the token of an impersonation are the admin's.
+    let audit_context = AuditContext {
+        actor: Some(
+            validation_result
+                .impersonator
+                .clone()
+                .unwrap_or_else(|| validation_result.user.clone()),
+        ),
+        source_ip: req.peer_addr().map(|address| address.ip()),
+    };
+    let context = make_context(&data, validation_result);
+    with_audit_context(
+        audit_context,
+        graphql_handler(&schema(), &context, req, payload),
+    )
+    .await
+}
+
+/// Refuses the `connection_init` of a WebSocket without a valid token.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct ConnectionInitError(String);
+
+/// The token of the `connection_init` payload, in its `Authorization` entry (whatever the case),
+/// optionally after "Bearer ": the browsers can't set the headers of a WebSocket.
+fn get_connection_init_token(params: &Variables) -> Option<&str> {
+    let value = params
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))?
+        .1
+        .as_string_value()?;
+    Some(value.strip_prefix("Bearer ").unwrap_or(value))
+}
+
+/// The subscriptions (and the queries) over a WebSocket, with the `graphql-ws` protocol. The token
+/// is in the `Authorization` header of the upgrade request, or else in the `connection_init`
+/// payload.
+async fn subscriptions_route<Handler: BackendHandler + TcpBackendHandler + Sync + 'static>(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    data: web::Data<AppState<Handler>>,
+) -> Result<HttpResponse, Error> {
+    let header_validation_result = if req.headers().contains_key(AUTHORIZATION) {
+        Some(authenticate(&req, &mut payload.0, &data).await?)
+    } else {
+        None
+    };
+    let init = move |params: Variables| async move {
+        let validation_result = match header_validation_result {
+            Some(validation_result) => validation_result,
+            None => {
+                let token = get_connection_init_token(&params)
+                    .ok_or_else(|| ConnectionInitError("Missing token".to_string()))?;
+                authenticate_token(&data, token)
+                    .await
+                    .map_err(|e| ConnectionInitError(e.to_string()))?
+            }
+        };
+        let context = make_context(&data, validation_result);
+        Ok::<_, ConnectionInitError>(ConnectionConfig::new(context))
     };
-    graphql_handler(&schema(), &context, req, payload).await
+    subscriptions_handler(req, payload, Arc::new(subscription_schema()), init).await
 }
 
 pub fn configure_endpoint<Backend>(cfg: &mut web::ServiceConfig)
 where
-    Backend: BackendHandler + Sync + 'static,
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
 {
     let json_config = web::JsonConfig::default()
         .limit(4096)
         .error_handler(|err, _req| {
             // create custom error response
-            log::error!("API error: {}", err);
+            tracing::error!("API error: {}", err);
             let msg = err.to_string();
             actix_web::error::InternalError::from_response(
                 err,
@@ -95,6 +257,37 @@ where
             .route(web::post().to(graphql_route::<Backend>))
             .route(web::get().to(graphql_route::<Backend>)),
     );
+    cfg.service(
+        web::resource("/graphql/subscriptions")
+            .route(web::get().to(subscriptions_route::<Backend>)),
+    );
     cfg.service(web::resource("/graphql/playground").route(web::get().to(playground_route)));
     cfg.service(web::resource("/graphql/graphiql").route(web::get().to(graphiql_route)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::InputValue;
+
+    fn params(name: &str, value: &str) -> Variables {
+        std::iter::once((name.to_string(), InputValue::scalar(value.to_string()))).collect()
+    }
+
+    #[test]
+    fn test_connection_init_token() {
+        assert_eq!(
+            get_connection_init_token(&params("Authorization", "Bearer abc")),
+            Some("abc")
+        );
+        assert_eq!(
+            get_connection_init_token(&params("authorization", "abc")),
+            Some("abc")
+        );
+        assert_eq!(get_connection_init_token(&params("token", "abc")), None);
+        assert_eq!(get_connection_init_token(&Variables::new()), None);
+        let mut not_a_string = Variables::new();
+        not_a_string.insert("Authorization".to_string(), InputValue::scalar(3));
+        assert_eq!(get_connection_init_token(&not_a_string), None);
+    }
+}
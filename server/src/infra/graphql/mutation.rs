@@ -1,7 +1,19 @@
-use crate::domain::handler::{
-    BackendHandler, CreateUserRequest, GroupId, UpdateGroupRequest, UpdateUserRequest,
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{
+            BackendHandler, CreateUserRequest, GroupId, GroupMail, UpdateGroupRequest,
+            UpdateUserRequest,
+        },
+    },
+    infra::{
+        access_control::Permission, avatar, bind_throttle::ThrottleKey,
+        tcp_backend_handler::ApiTokenScope,
+    },
+};
+use juniper::{
+    graphql_object, FieldError, FieldResult, GraphQLInputObject, GraphQLObject, Object, Value,
 };
-use juniper::{graphql_object, FieldResult, GraphQLInputObject, GraphQLObject};
 
 use super::api::Context;
 
@@ -19,6 +31,42 @@ impl<Handler: BackendHandler> Mutation<Handler> {
     }
 }
 
+/// The GraphQL name of a field of the user requests.
+fn get_input_field_name(field: &str) -> &str {
+    match field {
+        "user_id" => "id",
+        "display_name" => "displayName",
+        "first_name" => "firstName",
+        "last_name" => "lastName",
+        _ => field,
+    }
+}
+
+/// Lists the invalid fields in the `invalidFields` extension of the error, for the clients to
+/// highlight them.
+fn to_field_error(error: DomainError) -> FieldError {
+    match &error {
+        DomainError::InvalidFields(fields) => {
+            let fields = fields
+                .iter()
+                .map(|f| {
+                    let mut field = Object::with_capacity(2);
+                    field.add_field(
+                        "field",
+                        Value::scalar(get_input_field_name(f.field).to_string()),
+                    );
+                    field.add_field("message", Value::scalar(f.message.clone()));
+                    Value::object(field)
+                })
+                .collect();
+            let mut extensions = Object::with_capacity(1);
+            extensions.add_field("invalidFields", Value::list(fields));
+            FieldError::new(error, Value::object(extensions))
+        }
+        _ => error.into(),
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
 /// The details required to create a user.
 pub struct CreateUserInput {
@@ -37,6 +85,18 @@ pub struct UpdateUserInput {
     display_name: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
+    /// Base64-encoded image.
+    avatar: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// The fields that the users can update in their own profile: not their email, nor their groups.
+pub struct UpdateOwnProfileInput {
+    display_name: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    /// Base64-encoded image.
+    avatar: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -44,6 +104,8 @@ pub struct UpdateUserInput {
 pub struct UpdateGroupInput {
     id: i32,
     display_name: Option<String>,
+    /// An empty description removes it.
+    description: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLObject)]
@@ -51,6 +113,35 @@ pub struct Success {
     ok: bool,
 }
 
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The outcome of the creation of a user in a bulk creation.
+pub struct BulkCreateUserResult {
+    id: String,
+    error: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A new API token, with its secret.
+pub struct CreatedApiToken {
+    token: super::query::ApiToken,
+    /// The token to send, which can't be retrieved later.
+    secret: String,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A short-lived JWT of a user, for an admin impersonating them.
+pub struct ImpersonationToken {
+    token: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The users added to and removed from a group.
+pub struct MembershipChangesOutput {
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
 impl Success {
     fn new() -> Self {
         Self { ok: true }
@@ -63,7 +154,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         context: &Context<Handler>,
         user: CreateUserInput,
     ) -> FieldResult<super::query::User<Handler>> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_manage_users() {
             return Err("Unauthorized user creation".into());
         }
         context
@@ -75,7 +166,8 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 first_name: user.first_name,
                 last_name: user.last_name,
             })
-            .await?;
+            .await
+            .map_err(to_field_error)?;
         Ok(context
             .handler
             .get_user_details(&user.id)
@@ -83,11 +175,42 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
             .map(Into::into)?)
     }
 
+    /// Creates the users in a single transaction, reporting the error of each user that couldn't
+    /// be created.
+    async fn bulk_create_users(
+        context: &Context<Handler>,
+        users: Vec<CreateUserInput>,
+    ) -> FieldResult<Vec<BulkCreateUserResult>> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user creation".into());
+        }
+        let ids: Vec<String> = users.iter().map(|user| user.id.clone()).collect();
+        let requests = users
+            .into_iter()
+            .map(|user| CreateUserRequest {
+                user_id: user.id,
+                email: user.email,
+                display_name: user.display_name,
+                first_name: user.first_name,
+                last_name: user.last_name,
+            })
+            .collect();
+        let results = context.handler.bulk_create_users(requests).await?;
+        Ok(ids
+            .into_iter()
+            .zip(results)
+            .map(|(id, result)| BulkCreateUserResult {
+                id,
+                error: result.err().map(|e| e.to_string()),
+            })
+            .collect())
+    }
+
     async fn create_group(
         context: &Context<Handler>,
         name: String,
     ) -> FieldResult<super::query::Group<Handler>> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.is_admin() {
             return Err("Unauthorized group creation".into());
         }
         let group_id = context.handler.create_group(&name).await?;
@@ -102,9 +225,15 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         context: &Context<Handler>,
         user: UpdateUserInput,
     ) -> FieldResult<Success> {
-        if !context.validation_result.can_access(&user.id) {
+        if !context.validation_result.can_write(&user.id)
+            && !context.validation_result.can_manage_users()
+        {
             return Err("Unauthorized user update".into());
         }
+        let avatar = match user.avatar {
+            Some(avatar) => Some(avatar::process(avatar, context.avatar_config.clone()).await?),
+            None => None,
+        };
         context
             .handler
             .update_user(UpdateUserRequest {
@@ -113,8 +242,34 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 display_name: user.display_name,
                 first_name: user.first_name,
                 last_name: user.last_name,
+                avatar,
             })
-            .await?;
+            .await
+            .map_err(to_field_error)?;
+        Ok(Success::new())
+    }
+
+    /// Updates the profile of the user making the request.
+    async fn update_own_profile(
+        context: &Context<Handler>,
+        profile: UpdateOwnProfileInput,
+    ) -> FieldResult<Success> {
+        let avatar = match profile.avatar {
+            Some(avatar) => Some(avatar::process(avatar, context.avatar_config.clone()).await?),
+            None => None,
+        };
+        context
+            .handler
+            .update_user(UpdateUserRequest {
+                user_id: context.validation_result.user.clone(),
+                email: None,
+                display_name: profile.display_name,
+                first_name: profile.first_name,
+                last_name: profile.last_name,
+                avatar,
+            })
+            .await
+            .map_err(to_field_error)?;
         Ok(Success::new())
     }
 
@@ -122,7 +277,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         context: &Context<Handler>,
         group: UpdateGroupInput,
     ) -> FieldResult<Success> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.is_admin() {
             return Err("Unauthorized group update".into());
         }
         if group.id == 1 {
@@ -133,6 +288,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
             .update_group(UpdateGroupRequest {
                 group_id: GroupId(group.id),
                 display_name: group.display_name,
+                description: group.description,
             })
             .await?;
         Ok(Success::new())
@@ -143,7 +299,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         user_id: String,
         group_id: i32,
     ) -> FieldResult<Success> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_manage_users() {
             return Err("Unauthorized group membership modification".into());
         }
         context
@@ -158,10 +314,10 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         user_id: String,
         group_id: i32,
     ) -> FieldResult<Success> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_manage_users() {
             return Err("Unauthorized group membership modification".into());
         }
-        if context.validation_result.user == user_id && group_id == 1 {
+        if context.validation_result.is_user(&user_id) && group_id == 1 {
             return Err("Cannot remove admin rights for current user".into());
         }
         context
@@ -171,19 +327,339 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         Ok(Success::new())
     }
 
+    async fn add_users_to_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        user_ids: Vec<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized group membership modification".into());
+        }
+        context
+            .handler
+            .add_users_to_group(GroupId(group_id), &user_ids)
+            .await?;
+        Ok(Success::new())
+    }
+
+    async fn remove_users_from_group(
+        context: &Context<Handler>,
+        group_id: i32,
+        user_ids: Vec<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized group membership modification".into());
+        }
+        if group_id == 1 && user_ids.contains(&context.validation_result.user) {
+            return Err("Cannot remove admin rights for current user".into());
+        }
+        context
+            .handler
+            .remove_users_from_group(GroupId(group_id), &user_ids)
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Makes the members of the first group members of the second one too.
+    async fn add_group_to_group(
+        context: &Context<Handler>,
+        member_group_id: i32,
+        parent_group_id: i32,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized group membership modification".into());
+        }
+        context
+            .handler
+            .add_group_to_group(GroupId(member_group_id), GroupId(parent_group_id))
+            .await?;
+        Ok(Success::new())
+    }
+
+    async fn remove_group_from_group(
+        context: &Context<Handler>,
+        member_group_id: i32,
+        parent_group_id: i32,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized group membership modification".into());
+        }
+        context
+            .handler
+            .remove_group_from_group(GroupId(member_group_id), GroupId(parent_group_id))
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Makes the users the exact list of members of the group.
+    async fn set_group_members(
+        context: &Context<Handler>,
+        group_id: i32,
+        user_ids: Vec<String>,
+    ) -> FieldResult<MembershipChangesOutput> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized group membership modification".into());
+        }
+        if group_id == 1 && !user_ids.contains(&context.validation_result.user) {
+            return Err("Cannot remove admin rights for current user".into());
+        }
+        let changes = context
+            .handler
+            .set_group_members(GroupId(group_id), &user_ids)
+            .await?;
+        Ok(MembershipChangesOutput {
+            added: changes.added,
+            removed: changes.removed,
+        })
+    }
+
+    async fn set_user_hosts(
+        context: &Context<Handler>,
+        user_id: String,
+        hosts: Vec<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user hosts modification".into());
+        }
+        let hosts = hosts
+            .into_iter()
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty())
+            .collect();
+        context.handler.set_user_hosts(&user_id, hosts).await?;
+        Ok(Success::new())
+    }
+
+    /// Replaces the email address and the aliases of the group, for the mail servers to resolve
+    /// them to the members over LDAP.
+    async fn set_group_mail(
+        context: &Context<Handler>,
+        group_id: i32,
+        email: Option<String>,
+        aliases: Vec<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized group mail modification".into());
+        }
+        context
+            .handler
+            .set_group_mail(GroupId(group_id), GroupMail { email, aliases })
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Replaces the values of a custom attribute (declared in the `ldap_schema` configuration) of
+    /// the user. Without values, the user no longer has the attribute.
+    async fn set_user_attribute(
+        context: &Context<Handler>,
+        user_id: String,
+        name: String,
+        values: Vec<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user attribute modification".into());
+        }
+        context
+            .handler
+            .set_user_attribute(&user_id, &name, values)
+            .await?;
+        Ok(Success::new())
+    }
+
+    /// Suspends the user: they can no longer log in or bind, and their sessions are ended, until
+    /// they are unlocked. This is independent of any automatic lockout.
+    async fn lock_user(
+        context: &Context<Handler>,
+        user_id: String,
+        reason: String,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user lock".into());
+        }
+        if context.validation_result.is_user(&user_id) {
+            return Err("Cannot lock current user".into());
+        }
+        context.handler.lock_user(&user_id, reason.trim()).await?;
+        context.sessions.logout_all_sessions(&user_id).await?;
+        Ok(Success::new())
+    }
+
+    async fn unlock_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user unlock".into());
+        }
+        context.handler.unlock_user(&user_id).await?;
+        Ok(Success::new())
+    }
+
+    /// Ends all the sessions of the user on the web UI: their tokens are refused from now on, and
+    /// they have to log in again. The users can end their own sessions.
+    async fn logout_all_sessions(
+        context: &Context<Handler>,
+        user_id: String,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() && !context.validation_result.is_user(&user_id) {
+            return Err("Unauthorized session logout".into());
+        }
+        context.sessions.logout_all_sessions(&user_id).await?;
+        Ok(Success::new())
+    }
+
+    /// Creates a JWT of the user for the current admin, to see what the user sees in the web UI
+    /// without their password. It expires after a few minutes, and is recorded in the audit log.
+    /// The locked users can't be impersonated.
+    async fn impersonate_user(
+        context: &Context<Handler>,
+        user_id: String,
+    ) -> FieldResult<ImpersonationToken> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized impersonation".into());
+        }
+        context.handler.get_user_details(&user_id).await?;
+        let validation_result = &context.validation_result;
+        let admin = validation_result
+            .impersonator
+            .as_deref()
+            .unwrap_or(&validation_result.user);
+        let (token, expires_at) = context.sessions.impersonate(admin, &user_id).await?;
+        Ok(ImpersonationToken { token, expires_at })
+    }
+
+    /// Creates an API token for the current user. The scope is "read_only", "user_management" or
+    /// "full", and can't give more than the current permissions, e.g. when using an API token.
+    /// The impersonating admins can't create tokens of the user, which would outlive the
+    /// impersonation.
+    async fn create_api_token(
+        context: &Context<Handler>,
+        name: String,
+        scope: String,
+    ) -> FieldResult<CreatedApiToken> {
+        if context.validation_result.impersonator.is_some() {
+            return Err("API tokens can't be created while impersonating a user".into());
+        }
+        let scope = match ApiTokenScope::parse(&scope) {
+            Some(scope) => scope,
+            None => {
+                return Err(
+                    "The scope must be \"read_only\", \"user_management\" or \"full\"".into(),
+                )
+            }
+        };
+        let user_id = &context.validation_result.user;
+        let groups = context.handler.get_user_groups(user_id).await?;
+        let user_permission = Permission::from_groups(groups.iter().map(|group| group.1.as_str()));
+        if scope.max_permission().min(user_permission) > context.validation_result.permission {
+            return Err("Unauthorized API token scope".into());
+        }
+        let (token, secret) = context
+            .api_tokens
+            .create_api_token(user_id, &name, scope)
+            .await?;
+        Ok(CreatedApiToken {
+            token: token.into(),
+            secret,
+        })
+    }
+
+    /// Revokes the API token, which is refused from now on. The users can revoke their own tokens.
+    async fn revoke_api_token(context: &Context<Handler>, token_id: i32) -> FieldResult<Success> {
+        if !context.validation_result.is_admin()
+            && !context
+                .api_tokens
+                .list_api_tokens(Some(&context.validation_result.user))
+                .await?
+                .iter()
+                .any(|token| token.id == token_id)
+        {
+            return Err("Unauthorized API token revocation".into());
+        }
+        context.api_tokens.revoke_api_token(token_id).await?;
+        Ok(Success::new())
+    }
+
+    /// Sends a test email to the current user, to check the SMTP configuration. The error of the
+    /// SMTP server, if any, is returned.
+    async fn test_email(context: &Context<Handler>) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized test email".into());
+        }
+        let user = context
+            .handler
+            .get_user_details(&context.validation_result.user)
+            .await?;
+        context
+            .mailer
+            .send_test_email(&user.display_name, &user.email)
+            .await
+            .map_err(|e| format!("{:#}", e))?;
+        Ok(Success::new())
+    }
+
+    /// Lifts the throttling of the address or of the user after too many failed LDAP binds and
+    /// web logins. Exactly one of them must be given.
+    async fn clear_bind_lockout(
+        context: &Context<Handler>,
+        source_ip: Option<String>,
+        user_id: Option<String>,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized bind lockout clearing".into());
+        }
+        let key = match (source_ip, user_id) {
+            (Some(source_ip), None) => ThrottleKey::SourceIp(
+                source_ip
+                    .parse()
+                    .map_err(|_| format!("Invalid IP address: {}", source_ip))?,
+            ),
+            (None, Some(user_id)) => ThrottleKey::user(&user_id),
+            _ => return Err("Exactly one of sourceIp and userId must be given".into()),
+        };
+        if !context.bind_throttle.clear(&key).await {
+            return Err(format!("No failed attempt of {}", key).into());
+        }
+        Ok(Success::new())
+    }
+
     async fn delete_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_manage_users() {
             return Err("Unauthorized user deletion".into());
         }
-        if context.validation_result.user == user_id {
+        if context.validation_result.is_user(&user_id) {
             return Err("Cannot delete current user".into());
         }
         context.handler.delete_user(&user_id).await?;
         Ok(Success::new())
     }
 
+    /// Takes a deleted user out of the recycle bin, with their groups and password.
+    async fn restore_user(context: &Context<Handler>, user_id: String) -> FieldResult<Success> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized user restore".into());
+        }
+        context.handler.restore_user(&user_id).await?;
+        Ok(Success::new())
+    }
+
+    /// Changes the ID of the user, keeping the groups, the password and the UUID. Their sessions
+    /// are ended: the tokens name the previous ID.
+    async fn rename_user(
+        context: &Context<Handler>,
+        user_id: String,
+        new_user_id: String,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_manage_users() {
+            return Err("Unauthorized user rename".into());
+        }
+        if context.validation_result.is_user(&user_id) {
+            return Err("Cannot rename current user".into());
+        }
+        context.handler.rename_user(&user_id, &new_user_id).await?;
+        context.sessions.logout_all_sessions(&user_id).await?;
+        Ok(Success::new())
+    }
+
     async fn delete_group(context: &Context<Handler>, group_id: i32) -> FieldResult<Success> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.is_admin() {
             return Err("Unauthorized group deletion".into());
         }
         if group_id == 1 {
@@ -193,3 +669,675 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         Ok(Success::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::test_backend_handler::TestBackendHandler,
+        infra::{
+            access_control::{AccessControlledBackendHandler, Permission, ValidationResults},
+            api_tokens::MockTestApiTokenManager,
+            graphql::query::Query,
+            mail::MockTestMailer,
+            sessions::MockTestSessionManager,
+            tcp_backend_handler::ApiToken,
+        },
+    };
+    use juniper::{
+        execute, graphql_value, DefaultScalarValue, EmptySubscription, ExecutionError, RootNode,
+        Value, Variables,
+    };
+    use mockall::predicate::eq;
+    use std::sync::Arc;
+
+    type Handler = AccessControlledBackendHandler<TestBackendHandler>;
+
+    /// Runs the query as the user, with the same access control as the API endpoint.
+    async fn run(
+        backend: &TestBackendHandler,
+        validation_result: ValidationResults,
+        query: &str,
+    ) -> (Value, Vec<ExecutionError<DefaultScalarValue>>) {
+        run_with_managers(
+            backend,
+            MockTestSessionManager::new(),
+            MockTestApiTokenManager::new(),
+            MockTestMailer::new(),
+            validation_result,
+            query,
+        )
+        .await
+    }
+
+    async fn run_with_managers(
+        backend: &TestBackendHandler,
+        sessions: MockTestSessionManager,
+        api_tokens: MockTestApiTokenManager,
+        mailer: MockTestMailer,
+        validation_result: ValidationResults,
+        query: &str,
+    ) -> (Value, Vec<ExecutionError<DefaultScalarValue>>) {
+        let context = Context::<Handler> {
+            handler: Box::new(AccessControlledBackendHandler::new(
+                backend.clone(),
+                validation_result.clone(),
+            )),
+            validation_result,
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(sessions),
+            api_tokens: Arc::new(api_tokens),
+            mailer: Arc::new(mailer),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
+        };
+        let schema = RootNode::new(
+            Query::<Handler>::new(),
+            Mutation::<Handler>::new(),
+            EmptySubscription::<Context<Handler>>::new(),
+        );
+        execute(query, None, &schema, &Variables::new(), &context)
+            .await
+            .unwrap()
+    }
+
+    fn user(user_id: &str, permission: Permission) -> ValidationResults {
+        ValidationResults {
+            user: user_id.to_string(),
+            permission,
+            impersonator: None,
+        }
+    }
+
+    fn error_messages(errors: &[ExecutionError<DefaultScalarValue>]) -> Vec<&str> {
+        errors.iter().map(|e| e.error().message()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_update_user() {
+        const QUERY: &str = r#"mutation {
+          updateUser(user: {
+            id: "bob"
+            email: "bob@bobbers.on"
+            displayName: "Bob Bobberson"
+            firstName: "Bob"
+            lastName: "Bobberson"
+          }) {
+            ok
+          }
+        }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+
+        let (_, errors) = run(&backend, user("jim", Permission::Regular), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user update"]);
+        let (_, errors) = run(&backend, user("app", Permission::Readonly), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user update"]);
+        assert_eq!(
+            backend.get_user_details("bob").await.unwrap().email,
+            "bob@bob.bob"
+        );
+
+        // The users can't change their own email.
+        let (_, errors) = run(&backend, user("bob", Permission::Regular), QUERY).await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Permission denied: `update_own_email by bob`"]
+        );
+        assert_eq!(
+            run(&backend, ValidationResults::admin(), QUERY).await,
+            (graphql_value!({"updateUser": {"ok": true}}), vec![])
+        );
+        let bob = backend.get_user_details("bob").await.unwrap();
+        assert_eq!(bob.email, "bob@bobbers.on");
+        assert_eq!(bob.display_name, "Bob Bobberson");
+        assert_eq!(bob.first_name, "Bob");
+        assert_eq!(bob.last_name, "Bobberson");
+    }
+
+    #[tokio::test]
+    async fn test_update_own_profile() {
+        const QUERY: &str = r#"mutation {
+          updateOwnProfile(profile: {displayName: "Bob Bobberson", firstName: "Bob"}) {
+            ok
+          }
+        }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("jim", "jim@jim.jim", None);
+
+        assert_eq!(
+            run(&backend, user("bob", Permission::Regular), QUERY).await,
+            (graphql_value!({"updateOwnProfile": {"ok": true}}), vec![])
+        );
+        let bob = backend.get_user_details("bob").await.unwrap();
+        assert_eq!(bob.display_name, "Bob Bobberson");
+        assert_eq!(bob.first_name, "Bob");
+        assert_eq!(bob.email, "bob@bob.bob");
+        assert_eq!(
+            backend.get_user_details("jim").await.unwrap().display_name,
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_user_invalid_avatar() {
+        const QUERY: &str = r#"mutation {
+          updateUser(user: {id: "bob", displayName: "Bob", avatar: "not an image"}) {
+            ok
+          }
+        }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let (_, errors) = run(&backend, ValidationResults::admin(), QUERY).await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            backend.get_user_details("bob").await.unwrap().display_name,
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_user_invalid_fields() {
+        let query = format!(
+            r#"mutation {{
+              updateUser(user: {{id: "bob", email: "bob@", lastName: "{}"}}) {{
+                ok
+              }}
+            }}"#,
+            "x".repeat(256)
+        );
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let (_, errors) = run(&backend, ValidationResults::admin(), &query).await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].error().extensions(),
+            &graphql_value!({
+                "invalidFields": [
+                    {"field": "email", "message": "invalid domain \"\""},
+                    {"field": "lastName", "message": "at most 255 characters are allowed"}
+                ]
+            })
+        );
+        assert_eq!(
+            backend.get_user_details("bob").await.unwrap().email,
+            "bob@bob.bob"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_group() {
+        const QUERY: &str = r#"mutation {
+          updateGroup(group: {id: 2, displayName: "family", description: " The Smiths "}) {
+            ok
+          }
+        }"#;
+        let backend = TestBackendHandler::new();
+        let admin_group = backend.insert_group("lldap_admin");
+        let group = backend.insert_group("familly");
+
+        let (_, errors) = run(&backend, user("app", Permission::Readonly), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized group update"]);
+        assert_eq!(
+            run(&backend, ValidationResults::admin(), QUERY).await,
+            (graphql_value!({"updateGroup": {"ok": true}}), vec![])
+        );
+        assert_eq!(backend.get_group_details(group).await.unwrap().1, "family");
+        assert_eq!(
+            backend.get_group_metadata(group).await.unwrap().description,
+            Some("The Smiths".to_string())
+        );
+
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { updateGroup(group: {id: 1, displayName: "admins"}) { ok } }"#,
+        )
+        .await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Cannot change admin group details"]
+        );
+        assert_eq!(
+            backend.get_group_details(admin_group).await.unwrap().1,
+            "lldap_admin"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_group() {
+        let backend = TestBackendHandler::new();
+        backend.insert_group("lldap_admin");
+        backend.insert_group("family");
+
+        let (_, errors) = run(
+            &backend,
+            user("bob", Permission::PasswordManager),
+            r#"mutation { deleteGroup(groupId: 2) { ok } }"#,
+        )
+        .await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized group deletion"]);
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { deleteGroup(groupId: 1) { ok } }"#,
+        )
+        .await;
+        assert_eq!(error_messages(&errors), vec!["Cannot delete admin group"]);
+        assert_eq!(
+            run(
+                &backend,
+                ValidationResults::admin(),
+                r#"mutation { deleteGroup(groupId: 2) { ok } }"#,
+            )
+            .await,
+            (graphql_value!({"deleteGroup": {"ok": true}}), vec![])
+        );
+        assert_eq!(
+            backend
+                .list_groups(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.display_name)
+                .collect::<Vec<_>>(),
+            vec!["lldap_admin"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_user_from_group() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("admin", "admin@admin.admin", None);
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let admin_group = backend.insert_group("lldap_admin");
+        let group = backend.insert_group("family");
+        backend.insert_membership("admin", admin_group);
+        backend.insert_membership("bob", group);
+
+        let (_, errors) = run(
+            &backend,
+            user("bob", Permission::Regular),
+            r#"mutation { removeUserFromGroup(userId: "bob", groupId: 2) { ok } }"#,
+        )
+        .await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Unauthorized group membership modification"]
+        );
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { removeUserFromGroup(userId: "admin", groupId: 1) { ok } }"#,
+        )
+        .await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Cannot remove admin rights for current user"]
+        );
+        assert_eq!(
+            run(
+                &backend,
+                ValidationResults::admin(),
+                r#"mutation { removeUserFromGroup(userId: "bob", groupId: 2) { ok } }"#,
+            )
+            .await,
+            (
+                graphql_value!({"removeUserFromGroup": {"ok": true}}),
+                vec![]
+            )
+        );
+        assert!(backend.get_user_groups("bob").await.unwrap().is_empty());
+        assert_eq!(backend.get_user_groups("admin").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nested_groups() {
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let parent = backend.insert_group("family");
+        let member = backend.insert_group("children");
+        backend.insert_membership("bob", member);
+
+        let (_, errors) = run(
+            &backend,
+            user("app", Permission::Readonly),
+            r#"mutation { addGroupToGroup(memberGroupId: 2, parentGroupId: 1) { ok } }"#,
+        )
+        .await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Unauthorized group membership modification"]
+        );
+        assert_eq!(
+            run(
+                &backend,
+                ValidationResults::admin(),
+                r#"mutation { addGroupToGroup(memberGroupId: 2, parentGroupId: 1) { ok } }"#,
+            )
+            .await,
+            (graphql_value!({"addGroupToGroup": {"ok": true}}), vec![])
+        );
+        assert!(backend
+            .get_user_groups("bob")
+            .await
+            .unwrap()
+            .iter()
+            .any(|g| g.0 == parent));
+        // The other way around would make a cycle.
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { addGroupToGroup(memberGroupId: 1, parentGroupId: 2) { ok } }"#,
+        )
+        .await;
+        assert_eq!(errors.len(), 1);
+
+        assert_eq!(
+            run(
+                &backend,
+                ValidationResults::admin(),
+                r#"mutation { removeGroupFromGroup(memberGroupId: 2, parentGroupId: 1) { ok } }"#,
+            )
+            .await,
+            (
+                graphql_value!({"removeGroupFromGroup": {"ok": true}}),
+                vec![]
+            )
+        );
+        assert_eq!(
+            backend
+                .get_user_groups("bob")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| g.0)
+                .collect::<Vec<_>>(),
+            vec![member]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_user() {
+        const QUERY: &str = r#"mutation { renameUser(userId: "bob", newUserId: "robert") { ok } }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        backend.insert_user("jim", "jim@jim.jim", None);
+        let group_id = backend.insert_group("family");
+        backend.add_user_to_group("bob", group_id).await.unwrap();
+        let uuid = backend.get_user_details("bob").await.unwrap().uuid;
+
+        let (_, errors) = run(&backend, user("jim", Permission::Regular), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user rename"]);
+        let (_, errors) = run(&backend, user("bob", Permission::Admin), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Cannot rename current user"]);
+
+        let mut sessions = MockTestSessionManager::new();
+        sessions
+            .expect_logout_all_sessions()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        assert_eq!(
+            run_with_managers(
+                &backend,
+                sessions,
+                MockTestApiTokenManager::new(),
+                MockTestMailer::new(),
+                ValidationResults::admin(),
+                QUERY
+            )
+            .await,
+            (graphql_value!({"renameUser": {"ok": true}}), vec![])
+        );
+        assert!(backend.get_user_details("bob").await.is_err());
+        assert_eq!(backend.get_user_details("robert").await.unwrap().uuid, uuid);
+        assert_eq!(backend.get_user_groups("robert").await.unwrap().len(), 1);
+
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { renameUser(userId: "jim", newUserId: "robert") { ok } }"#,
+        )
+        .await;
+        assert_eq!(errors.len(), 1);
+        assert!(backend.get_user_details("jim").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_restore_user() {
+        const QUERY: &str = r#"mutation { restoreUser(userId: "bob") { ok } }"#;
+        let backend = TestBackendHandler::new();
+        let (_, errors) = run(&backend, user("jim", Permission::UserManager), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user restore"]);
+        // The in-memory backend has no recycle bin.
+        let (_, errors) = run(&backend, ValidationResults::admin(), QUERY).await;
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lock_user() {
+        const QUERY: &str = r#"mutation { lockUser(userId: "bob", reason: "left") { ok } }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let (_, errors) = run(&backend, user("jim", Permission::Regular), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user lock"]);
+
+        let mut sessions = MockTestSessionManager::new();
+        sessions
+            .expect_logout_all_sessions()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        assert_eq!(
+            run_with_managers(
+                &backend,
+                sessions,
+                MockTestApiTokenManager::new(),
+                MockTestMailer::new(),
+                ValidationResults::admin(),
+                QUERY
+            )
+            .await,
+            (graphql_value!({"lockUser": {"ok": true}}), vec![])
+        );
+        assert!(backend.get_user_lock("bob").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_logout_all_sessions() {
+        const QUERY: &str = r#"mutation { logoutAllSessions(userId: "bob") { ok } }"#;
+        let backend = TestBackendHandler::new();
+        let (_, errors) = run(&backend, user("jim", Permission::Regular), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized session logout"]);
+
+        for validation_result in vec![user("bob", Permission::Regular), ValidationResults::admin()]
+        {
+            let mut sessions = MockTestSessionManager::new();
+            sessions
+                .expect_logout_all_sessions()
+                .with(eq("bob"))
+                .times(1)
+                .return_once(|_| Ok(()));
+            assert_eq!(
+                run_with_managers(
+                    &backend,
+                    sessions,
+                    MockTestApiTokenManager::new(),
+                    MockTestMailer::new(),
+                    validation_result,
+                    QUERY
+                )
+                .await,
+                (graphql_value!({"logoutAllSessions": {"ok": true}}), vec![])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_impersonate_user() {
+        const QUERY: &str = r#"mutation { impersonateUser(userId: "bob") { token } }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+        let (_, errors) = run(&backend, user("jim", Permission::UserManager), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized impersonation"]);
+        let (_, errors) = run(
+            &backend,
+            ValidationResults::admin(),
+            r#"mutation { impersonateUser(userId: "jim") { token } }"#,
+        )
+        .await;
+        assert_eq!(errors.len(), 1);
+
+        let mut sessions = MockTestSessionManager::new();
+        sessions
+            .expect_impersonate()
+            .with(eq("admin"), eq("bob"))
+            .times(1)
+            .return_once(|_, _| Ok(("token".to_string(), chrono::Utc::now())));
+        assert_eq!(
+            run_with_managers(
+                &backend,
+                sessions,
+                MockTestApiTokenManager::new(),
+                MockTestMailer::new(),
+                ValidationResults::admin(),
+                QUERY
+            )
+            .await,
+            (
+                graphql_value!({"impersonateUser": {"token": "token"}}),
+                vec![]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_api_token() {
+        const QUERY: &str = r#"mutation {
+          createApiToken(name: "terraform", scope: "full") { token { name scope } secret }
+        }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("admin", "admin@admin.admin", None);
+        let admin_group = backend.insert_group("lldap_admin");
+        backend.insert_membership("admin", admin_group);
+        let (_, errors) = run(&backend, user("admin", Permission::Readonly), QUERY).await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["Unauthorized API token scope"]
+        );
+        let (_, errors) = run(
+            &backend,
+            ValidationResults {
+                impersonator: Some("root".to_string()),
+                ..ValidationResults::admin()
+            },
+            QUERY,
+        )
+        .await;
+        assert_eq!(
+            error_messages(&errors),
+            vec!["API tokens can't be created while impersonating a user"]
+        );
+
+        let mut api_tokens = MockTestApiTokenManager::new();
+        api_tokens
+            .expect_create_api_token()
+            .with(eq("admin"), eq("terraform"), eq(ApiTokenScope::Full))
+            .times(1)
+            .return_once(|user_id, name, scope| {
+                Ok((
+                    ApiToken {
+                        id: 1,
+                        user_id: user_id.to_string(),
+                        name: name.to_string(),
+                        scope,
+                        creation_date: chrono::Utc::now(),
+                    },
+                    "lldap_secret".to_string(),
+                ))
+            });
+        assert_eq!(
+            run_with_managers(
+                &backend,
+                MockTestSessionManager::new(),
+                api_tokens,
+                MockTestMailer::new(),
+                ValidationResults::admin(),
+                QUERY
+            )
+            .await,
+            (
+                graphql_value!({"createApiToken": {
+                    "token": {"name": "terraform", "scope": "full"},
+                    "secret": "lldap_secret"
+                }}),
+                vec![]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_test_email() {
+        const QUERY: &str = r#"mutation { testEmail { ok } }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("admin", "admin@admin.admin", None);
+        let (_, errors) = run(&backend, user("admin", Permission::UserManager), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized test email"]);
+
+        let mut mailer = MockTestMailer::new();
+        mailer
+            .expect_send_test_email()
+            .with(eq("admin"), eq("admin@admin.admin"))
+            .times(1)
+            .return_once(|_, _| Err(anyhow::anyhow!("Connection refused")));
+        let (_, errors) = run_with_managers(
+            &backend,
+            MockTestSessionManager::new(),
+            MockTestApiTokenManager::new(),
+            mailer,
+            ValidationResults::admin(),
+            QUERY,
+        )
+        .await;
+        assert_eq!(error_messages(&errors), vec!["Connection refused"]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_create_users() {
+        const QUERY: &str = r#"mutation {
+          bulkCreateUsers(users: [
+            {id: "bob", email: "bob@bob.bob"}
+            {id: "jim", email: "jim@jim.jim", displayName: "Jim"}
+          ]) {
+            id
+            error
+          }
+        }"#;
+        let backend = TestBackendHandler::new();
+        backend.insert_user("bob", "bob@bob.bob", None);
+
+        let (_, errors) = run(&backend, user("bob", Permission::Regular), QUERY).await;
+        assert_eq!(error_messages(&errors), vec!["Unauthorized user creation"]);
+        assert!(backend.get_user_details("jim").await.is_err());
+
+        assert_eq!(
+            run(&backend, ValidationResults::admin(), QUERY).await,
+            (
+                graphql_value!({"bulkCreateUsers": [
+                    {"id": "bob", "error": "Internal error: `User `bob` already exists`"},
+                    {"id": "jim", "error": None},
+                ]}),
+                vec![]
+            )
+        );
+        assert_eq!(
+            backend.get_user_details("jim").await.unwrap().display_name,
+            "Jim"
+        );
+    }
+}
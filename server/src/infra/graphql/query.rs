@@ -1,11 +1,31 @@
-use crate::domain::handler::{BackendHandler, GroupId, GroupIdAndName};
-use juniper::{graphql_object, FieldResult, GraphQLInputObject};
+use crate::{
+    domain::handler::{
+        BackendHandler, GroupId, GroupIdAndName, GroupSummary, SortDirection, SubstringFilter,
+        UserOrder, USER_FILTER_FIELDS,
+    },
+    infra::{
+        bind_throttle::{Lockout, ThrottleKey},
+        configuration::PasswordPolicyConfig,
+        connectors::DeliveryRecord,
+    },
+};
+use juniper::{
+    graphql_object, DefaultScalarValue, Executor, FieldResult, GraphQLEnum, GraphQLInputObject,
+    GraphQLObject, LookAheadMethods,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::TryInto;
 
 type DomainRequestFilter = crate::domain::handler::RequestFilter;
 type DomainUser = crate::domain::handler::User;
+type DomainUserLock = crate::domain::handler::UserLock;
+type DomainAuthFailure = crate::domain::handler::AuthFailure;
+type DomainAuditEvent = crate::domain::handler::AuditEvent;
+type DomainDeletedUser = crate::domain::handler::DeletedUser;
 type DomainGroup = crate::domain::handler::Group;
+type DomainSession = crate::infra::tcp_backend_handler::Session;
+type DomainApiToken = crate::infra::tcp_backend_handler::ApiToken;
 use super::api::Context;
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -49,6 +69,13 @@ impl TryInto<DomainRequestFilter> for RequestFilter {
             return Err("Multiple fields specified in request filter".to_string());
         }
         if let Some(e) = self.eq {
+            if !USER_FILTER_FIELDS.contains(&e.field.as_str()) {
+                return Err(format!(
+                    "Unknown field {}, expected one of {}",
+                    e.field,
+                    USER_FILTER_FIELDS.join(", ")
+                ));
+            }
             return Ok(DomainRequestFilter::Equality(e.field, e.value));
         }
         if let Some(c) = self.any {
@@ -84,6 +111,79 @@ pub struct EqualityConstraint {
     value: String,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, GraphQLEnum)]
+/// The fields the users can be sorted by. The ties are sorted by user ID.
+pub enum UserSortField {
+    UserId,
+    Email,
+    DisplayName,
+    CreationDate,
+}
+
+impl From<UserSortField> for UserOrder {
+    fn from(field: UserSortField) -> Self {
+        match field {
+            UserSortField::UserId => UserOrder::UserId,
+            UserSortField::Email => UserOrder::Email,
+            UserSortField::DisplayName => UserOrder::DisplayName,
+            UserSortField::CreationDate => UserOrder::CreationDate,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, GraphQLEnum)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl From<SortOrder> for SortDirection {
+    fn from(order: SortOrder) -> Self {
+        match order {
+            SortOrder::Asc => SortDirection::Ascending,
+            SortOrder::Desc => SortDirection::Descending,
+        }
+    }
+}
+
+/// The size of the pages of users when only the page is given.
+const DEFAULT_USERS_PAGE_SIZE: i32 = 50;
+/// The largest page of users a client can request at once.
+const MAX_USERS_PAGE_SIZE: i32 = 1000;
+
+/// The fields matched by the free-text search of the users.
+const USER_SEARCH_FIELDS: &[&str] = &["user_id", "email", "display_name"];
+
+/// Combines the filter with the free-text search, which matches the users containing the text
+/// (ignoring the case) in one of the [`USER_SEARCH_FIELDS`].
+fn get_users_filter(
+    filters: Option<RequestFilter>,
+    search: Option<String>,
+) -> Result<Option<DomainRequestFilter>, String> {
+    let filters = filters.map(TryInto::try_into).transpose()?;
+    let search = match search.as_deref().map(str::trim) {
+        None | Some("") => return Ok(filters),
+        Some(search) => DomainRequestFilter::Or(
+            USER_SEARCH_FIELDS
+                .iter()
+                .map(|field| {
+                    DomainRequestFilter::Substring(
+                        field.to_string(),
+                        SubstringFilter {
+                            any: vec![search.to_string()],
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+        ),
+    };
+    Ok(Some(match filters {
+        None => search,
+        Some(filters) => DomainRequestFilter::And(vec![filters, search]),
+    }))
+}
+
 #[derive(PartialEq, Eq, Debug)]
 /// The top-level GraphQL query type.
 pub struct Query<Handler: BackendHandler> {
@@ -105,7 +205,7 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
     }
 
     pub async fn user(context: &Context<Handler>, user_id: String) -> FieldResult<User<Handler>> {
-        if !context.validation_result.can_access(&user_id) {
+        if !context.validation_result.can_read(&user_id) {
             return Err("Unauthorized access to user data".into());
         }
         Ok(context
@@ -115,33 +215,112 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
             .map(Into::into)?)
     }
 
+    /// The user with this email (case-insensitive), if any.
+    async fn user_by_email(
+        context: &Context<Handler>,
+        email: String,
+    ) -> FieldResult<Option<User<Handler>>> {
+        if !context.validation_result.can_read_all() {
+            return Err("Unauthorized access to user data".into());
+        }
+        Ok(context
+            .handler
+            .get_user_by_email(&email)
+            .await?
+            .map(Into::into))
+    }
+
+    /// The users matching the filter and the search, sorted by user ID. With a page, a page size
+    /// or a sort, only that page of the users is returned, the first page being 1.
+    #[allow(clippy::too_many_arguments)]
     async fn users(
+        executor: &Executor<'_, '_, Context<Handler>, DefaultScalarValue>,
         context: &Context<Handler>,
         #[graphql(name = "where")] filters: Option<RequestFilter>,
+        page: Option<i32>,
+        page_size: Option<i32>,
+        sort_by: Option<UserSortField>,
+        sort_order: Option<SortOrder>,
+        search: Option<String>,
     ) -> FieldResult<Vec<User<Handler>>> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_read_all() {
             return Err("Unauthorized access to user list".into());
         }
-        Ok(context
+        let filters = get_users_filter(filters, search)?;
+        let users =
+            if page.is_some() || page_size.is_some() || sort_by.is_some() || sort_order.is_some() {
+                let page = page.unwrap_or(1);
+                let page_size = page_size.unwrap_or(DEFAULT_USERS_PAGE_SIZE);
+                if page < 1 {
+                    return Err("The pages start at 1".into());
+                }
+                if !(1..=MAX_USERS_PAGE_SIZE).contains(&page_size) {
+                    return Err(format!(
+                        "The page size must be between 1 and {}",
+                        MAX_USERS_PAGE_SIZE
+                    )
+                    .into());
+                }
+                context
+                    .handler
+                    .list_users_page(
+                        filters,
+                        sort_by.unwrap_or(UserSortField::UserId).into(),
+                        sort_order.unwrap_or(SortOrder::Asc).into(),
+                        (page as usize - 1) * page_size as usize,
+                        page_size as usize,
+                    )
+                    .await?
+            } else {
+                context.handler.list_users(filters).await?
+            };
+        if !executor.look_ahead().has_child("groups") {
+            return Ok(users.into_iter().map(Into::into).collect());
+        }
+        // Fetch the groups of all the users at once, rather than one query per user.
+        let mut groups = context
             .handler
-            .list_users(filters.map(TryInto::try_into).transpose()?)
-            .await
-            .map(|v| v.into_iter().map(Into::into).collect())?)
+            .get_users_groups(&users.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>())
+            .await?;
+        Ok(users
+            .into_iter()
+            .map(|user| User {
+                groups: groups.remove(&user.user_id),
+                user,
+                _phantom: std::marker::PhantomData,
+            })
+            .collect())
+    }
+
+    /// The number of users matching the filter and the search, to count the pages of `users`.
+    async fn users_count(
+        context: &Context<Handler>,
+        #[graphql(name = "where")] filters: Option<RequestFilter>,
+        search: Option<String>,
+    ) -> FieldResult<i32> {
+        if !context.validation_result.can_read_all() {
+            return Err("Unauthorized access to user list".into());
+        }
+        let count = context
+            .handler
+            .count_users(get_users_filter(filters, search)?)
+            .await?;
+        Ok(count.try_into().unwrap_or(i32::MAX))
     }
 
     async fn groups(context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_read_all() {
             return Err("Unauthorized access to group list".into());
         }
         Ok(context
             .handler
-            .list_groups()
+            .list_groups_with_member_count()
             .await
             .map(|v| v.into_iter().map(Into::into).collect())?)
     }
 
     async fn group(context: &Context<Handler>, group_id: i32) -> FieldResult<Group<Handler>> {
-        if !context.validation_result.is_admin {
+        if !context.validation_result.can_read_all() {
             return Err("Unauthorized access to group data".into());
         }
         Ok(context
@@ -150,12 +329,330 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
             .await
             .map(Into::into)?)
     }
+
+    /// The rules the new passwords have to follow. The passwords set through the web UI are
+    /// registered with OPAQUE, so the server can't check them: the clients have to.
+    fn password_policy(context: &Context<Handler>) -> PasswordPolicy {
+        (&context.password_policy_config).into()
+    }
+
+    /// The last deliveries of changes to the connectors, most recent first.
+    fn connector_deliveries(context: &Context<Handler>) -> FieldResult<Vec<ConnectorDelivery>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to connector deliveries".into());
+        }
+        Ok(context
+            .delivery_log
+            .records()
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// The addresses and users throttled after too many failed LDAP binds and web logins, the
+    /// last to fail first.
+    fn bind_lockouts(context: &Context<Handler>) -> FieldResult<Vec<BindLockout>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to bind lockouts".into());
+        }
+        Ok(context
+            .bind_throttle
+            .lockouts()
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    /// The sessions of the web UI that haven't expired, the soonest to expire first, optionally
+    /// only the ones of a user.
+    async fn sessions(
+        context: &Context<Handler>,
+        user_id: Option<String>,
+    ) -> FieldResult<Vec<Session>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to sessions".into());
+        }
+        Ok(context
+            .sessions
+            .list_sessions(user_id.as_deref())
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The API tokens, the oldest first. The admins get the ones of all the users unless a user
+    /// is given, the other users only their own.
+    async fn api_tokens(
+        context: &Context<Handler>,
+        user_id: Option<String>,
+    ) -> FieldResult<Vec<ApiToken>> {
+        let user_id = if context.validation_result.is_admin() {
+            user_id
+        } else {
+            match user_id {
+                Some(user_id) if user_id != context.validation_result.user => {
+                    return Err("Unauthorized access to API tokens".into())
+                }
+                _ => Some(context.validation_result.user.clone()),
+            }
+        };
+        Ok(context
+            .api_tokens
+            .list_api_tokens(user_id.as_deref())
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The failed LDAP binds and web logins, most recent first, optionally only the ones of a
+    /// user. At most 100 are returned by default.
+    async fn auth_failures(
+        context: &Context<Handler>,
+        user_id: Option<String>,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<AuthFailure>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to authentication failures".into());
+        }
+        let offset = offset.unwrap_or(0);
+        let limit = limit.unwrap_or(100);
+        if offset < 0 || limit < 0 {
+            return Err("The offset and limit can't be negative".into());
+        }
+        Ok(context
+            .handler
+            .list_auth_failures(user_id.as_deref(), offset as usize, limit as usize)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The changes to the directory and the authentication attempts, most recent first. At most
+    /// 100 are returned by default.
+    async fn audit_log(
+        context: &Context<Handler>,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<AuditEvent>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to the audit log".into());
+        }
+        let offset = offset.unwrap_or(0);
+        let limit = limit.unwrap_or(100);
+        if offset < 0 || limit < 0 {
+            return Err("The offset and limit can't be negative".into());
+        }
+        Ok(context
+            .handler
+            .list_audit_events(offset as usize, limit as usize)
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The users in the recycle bin, most recently deleted first, see `restoreUser`.
+    async fn deleted_users(context: &Context<Handler>) -> FieldResult<Vec<DeletedUser>> {
+        if !context.validation_result.is_admin() {
+            return Err("Unauthorized access to the deleted users".into());
+        }
+        Ok(context
+            .handler
+            .list_deleted_users()
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A user in the recycle bin, until purged after `deleted_user_retention_days`.
+pub struct DeletedUser {
+    id: String,
+    email: String,
+    display_name: String,
+    deleted_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainDeletedUser> for DeletedUser {
+    fn from(user: DomainDeletedUser) -> Self {
+        Self {
+            id: user.user_id,
+            email: user.email,
+            display_name: user.display_name,
+            deleted_at: user.deleted_at,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A failed LDAP bind or web login.
+pub struct AuthFailure {
+    time: chrono::DateTime<chrono::Utc>,
+    /// The user the client tried to authenticate as, if it's a valid user ID.
+    user_id: Option<String>,
+    source_ip: Option<String>,
+    /// "ldap" or "web".
+    protocol: String,
+    /// "invalid_dn", "invalid_credentials" or "user_locked".
+    reason: String,
+}
+
+impl From<DomainAuthFailure> for AuthFailure {
+    fn from(failure: DomainAuthFailure) -> Self {
+        Self {
+            time: failure.time,
+            user_id: failure.user_id,
+            source_ip: failure.source_ip.map(|ip| ip.to_string()),
+            protocol: failure.protocol.as_str().to_string(),
+            reason: failure.reason.as_str().to_string(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// An entry of the audit log.
+pub struct AuditEvent {
+    time: chrono::DateTime<chrono::Utc>,
+    /// The user who made the change or tried to authenticate, if known.
+    actor: Option<String>,
+    source_ip: Option<String>,
+    /// The operation, e.g. "create_user", "add_user_to_group", "bind" or "login".
+    action: String,
+    /// What the operation applied to, e.g. "bob" or "bob in group 3".
+    target: String,
+    success: bool,
+}
+
+impl From<DomainAuditEvent> for AuditEvent {
+    fn from(event: DomainAuditEvent) -> Self {
+        Self {
+            time: event.time,
+            actor: event.actor,
+            source_ip: event.source_ip.map(|ip| ip.to_string()),
+            action: event.action,
+            target: event.target,
+            success: event.success,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// An address or a user throttled after too many failed LDAP binds and web logins: only one of
+/// `sourceIp` and `userId` is set.
+pub struct BindLockout {
+    source_ip: Option<String>,
+    user_id: Option<String>,
+    failures: i32,
+    last_failure: chrono::DateTime<chrono::Utc>,
+    /// Until when the attempts are refused. Null if the delay has passed: the next failure is
+    /// throttled again, for longer.
+    blocked_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<Lockout> for BindLockout {
+    fn from(lockout: Lockout) -> Self {
+        let (source_ip, user_id) = match lockout.key {
+            ThrottleKey::SourceIp(ip) => (Some(ip.to_string()), None),
+            ThrottleKey::User(user_id) => (None, Some(user_id)),
+        };
+        Self {
+            source_ip,
+            user_id,
+            failures: lockout.failures as i32,
+            last_failure: lockout.last_failure,
+            blocked_until: lockout.blocked_until,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A session of the web UI, from the login until its refresh token expires.
+pub struct Session {
+    user_id: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainSession> for Session {
+    fn from(session: DomainSession) -> Self {
+        Self {
+            user_id: session.user_id,
+            expires_at: session.expiry_date,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// A long-lived token of the HTTP API, sent like the JWTs in the `Authorization: Bearer` header.
+pub struct ApiToken {
+    id: i32,
+    user_id: String,
+    name: String,
+    /// "read_only", "user_management" or "full", within the permissions of the user.
+    scope: String,
+    creation_date: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<DomainApiToken> for ApiToken {
+    fn from(token: DomainApiToken) -> Self {
+        Self {
+            id: token.id,
+            user_id: token.user_id,
+            name: token.name,
+            scope: token.scope.as_str().to_string(),
+            creation_date: token.creation_date,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The rules the new passwords have to follow.
+pub struct PasswordPolicy {
+    min_length: i32,
+    require_lowercase: bool,
+    require_uppercase: bool,
+    require_digit: bool,
+    /// Whether a character that is neither a letter nor a digit is required.
+    require_special: bool,
+    /// Minimum strength estimated by zxcvbn, from 0 to 4.
+    min_strength: i32,
+}
+
+impl From<&PasswordPolicyConfig> for PasswordPolicy {
+    fn from(policy: &PasswordPolicyConfig) -> Self {
+        Self {
+            min_length: policy.min_length as i32,
+            require_lowercase: policy.require_lowercase,
+            require_uppercase: policy.require_uppercase,
+            require_digit: policy.require_digit,
+            require_special: policy.require_special,
+            min_strength: policy.min_strength.into(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The outcome of the delivery of a change to a connector.
+pub struct ConnectorDelivery {
+    connector: String,
+    event: String,
+    time: chrono::DateTime<chrono::Utc>,
+    /// The error message, if the delivery failed.
+    error: Option<String>,
+}
+
+impl From<DeliveryRecord> for ConnectorDelivery {
+    fn from(record: DeliveryRecord) -> Self {
+        Self {
+            connector: record.connector,
+            event: record.event,
+            time: record.time,
+            error: record.error,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// Represents a single user.
 pub struct User<Handler: BackendHandler> {
     user: DomainUser,
+    /// The groups, when fetched along with the user.
+    groups: Option<HashSet<GroupIdAndName>>,
     _phantom: std::marker::PhantomData<Box<Handler>>,
 }
 
@@ -163,11 +660,25 @@ impl<Handler: BackendHandler> Default for User<Handler> {
     fn default() -> Self {
         Self {
             user: DomainUser::default(),
+            groups: None,
             _phantom: std::marker::PhantomData,
         }
     }
 }
 
+impl<Handler: BackendHandler> User<Handler> {
+    async fn get_password_expiry(
+        &self,
+        context: &Context<Handler>,
+    ) -> FieldResult<Option<chrono::DateTime<chrono::Utc>>> {
+        Ok(context
+            .handler
+            .get_password_change(&self.user.user_id)
+            .await?
+            .and_then(|changed_at| context.password_expiry_config.expires_at(changed_at)))
+    }
+}
+
 #[graphql_object(context = Context<Handler>)]
 impl<Handler: BackendHandler + Sync> User<Handler> {
     fn id(&self) -> &str {
@@ -194,20 +705,113 @@ impl<Handler: BackendHandler + Sync> User<Handler> {
         self.user.creation_date
     }
 
+    /// Generated when the user is created and never changed, exposed as the LDAP `entryUUID`.
+    fn uuid(&self) -> &str {
+        &self.user.uuid
+    }
+
+    /// Allocated when the user is created, exposed as the LDAP `uidNumber`.
+    fn uid_number(&self) -> i32 {
+        self.user.uid_number
+    }
+
     /// The groups to which this user belongs.
     async fn groups(&self, context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
+        let groups = match &self.groups {
+            Some(groups) => groups.clone(),
+            None => context.handler.get_user_groups(&self.user.user_id).await?,
+        };
+        Ok(groups.into_iter().map(Into::into).collect())
+    }
+
+    /// The hosts this user is allowed to log into, exposed as the LDAP `host` attribute.
+    async fn hosts(&self, context: &Context<Handler>) -> FieldResult<Vec<String>> {
+        Ok(context.handler.get_user_hosts(&self.user.user_id).await?)
+    }
+
+    /// The base64-encoded avatar of the user, also served at `/api/user/{id}/avatar`.
+    async fn avatar(&self, context: &Context<Handler>) -> FieldResult<Option<String>> {
         Ok(context
             .handler
-            .get_user_groups(&self.user.user_id)
-            .await
-            .map(|set| set.into_iter().map(Into::into).collect())?)
+            .get_user_avatar(&self.user.user_id)
+            .await?
+            .map(base64::encode))
+    }
+
+    /// The values of the custom attributes declared in the `ldap_schema` configuration, sorted
+    /// by name.
+    async fn attributes(&self, context: &Context<Handler>) -> FieldResult<Vec<UserAttribute>> {
+        let mut attributes = context
+            .handler
+            .get_user_attributes(&self.user.user_id)
+            .await?
+            .into_iter()
+            .map(|(name, values)| UserAttribute { name, values })
+            .collect::<Vec<_>>();
+        attributes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(attributes)
+    }
+
+    /// When the password of the user expires, if they have one and passwords expire.
+    async fn password_expires_at(
+        &self,
+        context: &Context<Handler>,
+    ) -> FieldResult<Option<chrono::DateTime<chrono::Utc>>> {
+        self.get_password_expiry(context).await
+    }
+
+    /// Whether the password expires within the `warning_days` of the password expiry
+    /// configuration (or has already expired), to remind the user to change it.
+    async fn password_expires_soon(&self, context: &Context<Handler>) -> FieldResult<bool> {
+        Ok(self
+            .get_password_expiry(context)
+            .await?
+            .map(|expires_at| {
+                context
+                    .password_expiry_config
+                    .is_warning_due(expires_at, chrono::Utc::now())
+            })
+            .unwrap_or(false))
+    }
+
+    /// The suspension of the user by an admin, if they are locked.
+    async fn lock(&self, context: &Context<Handler>) -> FieldResult<Option<UserLock>> {
+        Ok(context
+            .handler
+            .get_user_lock(&self.user.user_id)
+            .await?
+            .map(Into::into))
     }
 }
 
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The suspension of a user: a locked user can't log in or bind until an admin unlocks them.
+pub struct UserLock {
+    locked_at: chrono::DateTime<chrono::Utc>,
+    reason: String,
+}
+
+impl From<DomainUserLock> for UserLock {
+    fn from(lock: DomainUserLock) -> Self {
+        Self {
+            locked_at: lock.locked_at,
+            reason: lock.reason,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLObject)]
+/// The values of a custom attribute of a user.
+pub struct UserAttribute {
+    name: String,
+    values: Vec<String>,
+}
+
 impl<Handler: BackendHandler> From<DomainUser> for User<Handler> {
     fn from(user: DomainUser) -> Self {
         Self {
             user,
+            groups: None,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -219,6 +823,7 @@ pub struct Group<Handler: BackendHandler> {
     group_id: i32,
     display_name: String,
     members: Option<Vec<String>>,
+    member_count: Option<usize>,
     _phantom: std::marker::PhantomData<Box<Handler>>,
 }
 
@@ -230,11 +835,32 @@ impl<Handler: BackendHandler + Sync> Group<Handler> {
     fn display_name(&self) -> String {
         self.display_name.clone()
     }
-    /// The groups to which this user belongs.
-    async fn users(&self, context: &Context<Handler>) -> FieldResult<Vec<User<Handler>>> {
-        if !context.validation_result.is_admin {
+    /// The members of the group. With an offset or a limit, only that page of the members, sorted
+    /// by user ID, is returned.
+    async fn users(
+        &self,
+        context: &Context<Handler>,
+        offset: Option<i32>,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<User<Handler>>> {
+        if !context.validation_result.can_read_all() {
             return Err("Unauthorized access to group data".into());
         }
+        if offset.is_some() || limit.is_some() {
+            let offset = offset.unwrap_or(0);
+            let limit = limit.unwrap_or(i32::MAX);
+            if offset < 0 || limit < 0 {
+                return Err("The offset and limit can't be negative".into());
+            }
+            return Ok(context
+                .handler
+                .get_group_members(GroupId(self.group_id), offset as usize, limit as usize)
+                .await?
+                .users
+                .into_iter()
+                .map(Into::into)
+                .collect());
+        }
         Ok(context
             .handler
             .list_users(Some(DomainRequestFilter::MemberOfId(GroupId(
@@ -243,6 +869,66 @@ impl<Handler: BackendHandler + Sync> Group<Handler> {
             .await
             .map(|v| v.into_iter().map(Into::into).collect())?)
     }
+    /// The number of members, without fetching them.
+    async fn member_count(&self, context: &Context<Handler>) -> FieldResult<i32> {
+        if !context.validation_result.can_read_all() {
+            return Err("Unauthorized access to group data".into());
+        }
+        let count = match (self.member_count, &self.members) {
+            (Some(count), _) => count,
+            (None, Some(members)) => members.len(),
+            (None, None) => {
+                context
+                    .handler
+                    .get_group_members(GroupId(self.group_id), 0, 0)
+                    .await?
+                    .total
+            }
+        };
+        Ok(count as i32)
+    }
+    /// The email address of the group, exposed as the first LDAP `mail` value.
+    async fn email(&self, context: &Context<Handler>) -> FieldResult<Option<String>> {
+        Ok(context
+            .handler
+            .get_group_mail(GroupId(self.group_id))
+            .await?
+            .email)
+    }
+    /// The other addresses of the group, sorted, exposed as the other LDAP `mail` values.
+    async fn aliases(&self, context: &Context<Handler>) -> FieldResult<Vec<String>> {
+        Ok(context
+            .handler
+            .get_group_mail(GroupId(self.group_id))
+            .await?
+            .aliases)
+    }
+    /// Exposed as the LDAP `description`.
+    async fn description(&self, context: &Context<Handler>) -> FieldResult<Option<String>> {
+        Ok(context
+            .handler
+            .get_group_metadata(GroupId(self.group_id))
+            .await?
+            .description)
+    }
+    async fn creation_date(
+        &self,
+        context: &Context<Handler>,
+    ) -> FieldResult<chrono::DateTime<chrono::Utc>> {
+        Ok(context
+            .handler
+            .get_group_metadata(GroupId(self.group_id))
+            .await?
+            .creation_date)
+    }
+    /// Generated when the group is created, and never changed: exposed as the LDAP `entryUUID`.
+    async fn uuid(&self, context: &Context<Handler>) -> FieldResult<String> {
+        Ok(context
+            .handler
+            .get_group_metadata(GroupId(self.group_id))
+            .await?
+            .uuid)
+    }
 }
 
 impl<Handler: BackendHandler> From<GroupIdAndName> for Group<Handler> {
@@ -251,6 +937,7 @@ impl<Handler: BackendHandler> From<GroupIdAndName> for Group<Handler> {
             group_id: group_id_and_name.0 .0,
             display_name: group_id_and_name.1,
             members: None,
+            member_count: None,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -262,6 +949,19 @@ impl<Handler: BackendHandler> From<DomainGroup> for Group<Handler> {
             group_id: group.id.0,
             display_name: group.display_name,
             members: Some(group.users.into_iter().map(Into::into).collect()),
+            member_count: None,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<Handler: BackendHandler> From<GroupSummary> for Group<Handler> {
+    fn from(group: GroupSummary) -> Self {
+        Self {
+            group_id: group.id.0,
+            display_name: group.display_name,
+            members: None,
+            member_count: Some(group.member_count),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -270,13 +970,20 @@ impl<Handler: BackendHandler> From<DomainGroup> for Group<Handler> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{domain::handler::MockTestBackendHandler, infra::auth_service::ValidationResults};
+    use crate::{
+        domain::handler::{GroupMembersPage, MockTestBackendHandler},
+        infra::{
+            access_control::ValidationResults, api_tokens::MockTestApiTokenManager,
+            mail::MockTestMailer, sessions::MockTestSessionManager,
+        },
+    };
     use juniper::{
         execute, graphql_value, DefaultScalarValue, EmptyMutation, EmptySubscription, GraphQLType,
         RootNode, Variables,
     };
     use mockall::predicate::eq;
     use std::collections::HashSet;
+    use std::sync::Arc;
 
     fn schema<'q, C, Q>(query_root: Q) -> RootNode<'q, Q, EmptyMutation<C>, EmptySubscription<C>>
     where
@@ -320,6 +1027,15 @@ mod tests {
         let context = Context::<MockTestBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(MockTestSessionManager::new()),
+            api_tokens: Arc::new(MockTestApiTokenManager::new()),
+            mailer: Arc::new(MockTestMailer::new()),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
         };
 
         let schema = schema(Query::<MockTestBackendHandler>::new());
@@ -345,7 +1061,7 @@ mod tests {
           users(filters: {
             any: [
               {eq: {
-                field: "id"
+                field: "user_id"
                 value: "bob"
               }},
               {eq: {
@@ -362,7 +1078,7 @@ mod tests {
         use crate::domain::handler::RequestFilter;
         mock.expect_list_users()
             .with(eq(Some(RequestFilter::Or(vec![
-                RequestFilter::Equality("id".to_string(), "bob".to_string()),
+                RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
                 RequestFilter::Equality("email".to_string(), "robert@bobbers.on".to_string()),
             ]))))
             .return_once(|_| {
@@ -383,6 +1099,15 @@ mod tests {
         let context = Context::<MockTestBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(MockTestSessionManager::new()),
+            api_tokens: Arc::new(MockTestApiTokenManager::new()),
+            mailer: Arc::new(MockTestMailer::new()),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
         };
 
         let schema = schema(Query::<MockTestBackendHandler>::new());
@@ -406,4 +1131,237 @@ mod tests {
             ))
         );
     }
+
+    #[tokio::test]
+    async fn list_users_page_with_search() {
+        const QUERY: &str = r#"{
+          users(page: 2, pageSize: 1, sortBy: DISPLAY_NAME, sortOrder: DESC, search: " ob ") {
+            id
+          }
+          usersCount(search: "ob")
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        use crate::domain::handler::RequestFilter;
+        let search = RequestFilter::Or(
+            ["user_id", "email", "display_name"]
+                .iter()
+                .map(|field| {
+                    RequestFilter::Substring(
+                        field.to_string(),
+                        SubstringFilter {
+                            any: vec!["ob".to_string()],
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+        );
+        // The default `list_users_page` and `count_users` go through `list_users`.
+        mock.expect_list_users()
+            .with(eq(Some(search)))
+            .times(2)
+            .returning(|_| {
+                Ok(vec![
+                    DomainUser {
+                        user_id: "bob".to_string(),
+                        display_name: "Bob".to_string(),
+                        ..Default::default()
+                    },
+                    DomainUser {
+                        user_id: "robert".to_string(),
+                        display_name: "Robert".to_string(),
+                        ..Default::default()
+                    },
+                    DomainUser {
+                        user_id: "snob".to_string(),
+                        display_name: "Snob".to_string(),
+                        ..Default::default()
+                    },
+                ])
+            });
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(MockTestSessionManager::new()),
+            api_tokens: Arc::new(MockTestApiTokenManager::new()),
+            mailer: Arc::new(MockTestMailer::new()),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "users": [{"id": "robert"}],
+                    "usersCount": 3
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn list_users_with_groups() {
+        const QUERY: &str = r#"{
+          users {
+            id
+            groups {
+              id
+            }
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().return_once(|_| {
+            Ok(vec![
+                DomainUser {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                },
+                DomainUser {
+                    user_id: "robert".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        // A single call for all the users.
+        mock.expect_get_users_groups()
+            .with(eq(vec!["bob".to_string(), "robert".to_string()]))
+            .times(1)
+            .return_once(|_| {
+                let mut groups = std::collections::HashMap::new();
+                groups.insert(
+                    "bob".to_string(),
+                    vec![GroupIdAndName(GroupId(3), "Bobbersons".to_string())]
+                        .into_iter()
+                        .collect(),
+                );
+                groups.insert("robert".to_string(), HashSet::new());
+                Ok(groups)
+            });
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(MockTestSessionManager::new()),
+            api_tokens: Arc::new(MockTestApiTokenManager::new()),
+            mailer: Arc::new(MockTestMailer::new()),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "users": [
+                        {"id": "bob", "groups": [{"id": 3}]},
+                        {"id": "robert", "groups": []}
+                    ]
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn get_group_members_page() {
+        const QUERY: &str = r#"{
+          group(groupId: 3) {
+            memberCount
+            users(offset: 2, limit: 1) {
+              id
+            }
+          }
+        }"#;
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_group_details()
+            .with(eq(GroupId(3)))
+            .return_once(|_| Ok(GroupIdAndName(GroupId(3), "Bobbersons".to_string())));
+        mock.expect_get_group_members()
+            .with(eq(GroupId(3)), eq(0), eq(0))
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(GroupMembersPage {
+                    users: vec![],
+                    total: 3,
+                })
+            });
+        mock.expect_get_group_members()
+            .with(eq(GroupId(3)), eq(2), eq(1))
+            .times(1)
+            .return_once(|_, _, _| {
+                Ok(GroupMembersPage {
+                    users: vec![DomainUser {
+                        user_id: "robert".to_string(),
+                        ..Default::default()
+                    }],
+                    total: 3,
+                })
+            });
+
+        let context = Context::<MockTestBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            delivery_log: Default::default(),
+            event_bus: Default::default(),
+            bind_throttle: Default::default(),
+            sessions: Arc::new(MockTestSessionManager::new()),
+            api_tokens: Arc::new(MockTestApiTokenManager::new()),
+            mailer: Arc::new(MockTestMailer::new()),
+            avatar_config: Default::default(),
+            password_expiry_config: Default::default(),
+            password_policy_config: Default::default(),
+        };
+
+        let schema = schema(Query::<MockTestBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "group": {
+                        "memberCount": 3,
+                        "users": [{"id": "robert"}]
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_request_filter_unknown_field() {
+        let filter = RequestFilter {
+            any: None,
+            all: None,
+            not: None,
+            eq: Some(EqualityConstraint {
+                field: "password_hash) OR (1 = 1".to_string(),
+                value: "bob".to_string(),
+            }),
+            member_of: None,
+            member_of_id: None,
+        };
+        let result: Result<DomainRequestFilter, String> = filter.try_into();
+        assert!(result
+            .unwrap_err()
+            .starts_with("Unknown field password_hash) OR (1 = 1"));
+    }
 }
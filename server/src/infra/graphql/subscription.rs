@@ -0,0 +1,162 @@
+use crate::{domain::handler::BackendHandler, infra::connectors::ChangeEvent};
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use juniper::{graphql_subscription, FieldError, FieldResult, GraphQLObject};
+use std::pin::Pin;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::api::Context;
+
+#[derive(PartialEq, Eq, Debug, Default, GraphQLObject)]
+/// A modification of the users, groups or memberships.
+pub struct DirectoryChange {
+    /// The type of the change, e.g. "user_created" or "user_added_to_group", as in the payloads of
+    /// the connectors.
+    event_type: String,
+    /// The user, with their new ID if they were renamed.
+    user_id: Option<String>,
+    group_id: Option<i32>,
+    /// The previous ID of a renamed user, the previous name of an updated group, or the name of a
+    /// deleted group.
+    previous_name: Option<String>,
+    /// Why the user was locked.
+    reason: Option<String>,
+}
+
+impl From<ChangeEvent> for DirectoryChange {
+    fn from(event: ChangeEvent) -> Self {
+        let event_type = event.name().to_string();
+        let change = match event {
+            ChangeEvent::UserCreated { user_id }
+            | ChangeEvent::UserUpdated { user_id }
+            | ChangeEvent::UserDeleted { user_id, .. }
+            | ChangeEvent::PasswordChanged { user_id }
+            | ChangeEvent::UserUnlocked { user_id } => DirectoryChange {
+                user_id: Some(user_id),
+                ..Default::default()
+            },
+            ChangeEvent::UserRenamed {
+                user_id,
+                previous_user_id,
+            } => DirectoryChange {
+                user_id: Some(user_id),
+                previous_name: Some(previous_user_id),
+                ..Default::default()
+            },
+            ChangeEvent::UserLocked { user_id, reason } => DirectoryChange {
+                user_id: Some(user_id),
+                reason: Some(reason),
+                ..Default::default()
+            },
+            ChangeEvent::GroupCreated { group_id } => DirectoryChange {
+                group_id: Some(group_id.0),
+                ..Default::default()
+            },
+            ChangeEvent::GroupUpdated {
+                group_id,
+                previous_name: name,
+            }
+            | ChangeEvent::GroupDeleted { group_id, name } => DirectoryChange {
+                group_id: Some(group_id.0),
+                previous_name: Some(name),
+                ..Default::default()
+            },
+            ChangeEvent::UserAddedToGroup { user_id, group_id }
+            | ChangeEvent::UserRemovedFromGroup { user_id, group_id } => DirectoryChange {
+                user_id: Some(user_id),
+                group_id: Some(group_id.0),
+                ..Default::default()
+            },
+        };
+        DirectoryChange {
+            event_type,
+            ..change
+        }
+    }
+}
+
+type DirectoryChangeStream = Pin<Box<dyn Stream<Item = FieldResult<DirectoryChange>> + Send>>;
+
+#[derive(PartialEq, Eq, Debug)]
+/// The top-level GraphQL subscription type.
+pub struct Subscription<Handler: BackendHandler> {
+    _phantom: std::marker::PhantomData<Box<Handler>>,
+}
+
+impl<Handler: BackendHandler> Subscription<Handler> {
+    pub fn new() -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[graphql_subscription(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> Subscription<Handler> {
+    /// The changes made from now on, optionally only the ones of these types. A subscriber too
+    /// slow to keep up gets an error for the changes it missed, and should fetch the lists again.
+    async fn directory_changes(
+        context: &Context<Handler>,
+        event_types: Option<Vec<String>>,
+    ) -> FieldResult<DirectoryChangeStream> {
+        if !context.validation_result.can_read_all() {
+            return Err("Unauthorized access to the directory changes".into());
+        }
+        let receiver = context.event_bus.subscribe();
+        let events = stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), receiver)),
+                Err(RecvError::Lagged(missed)) => Some((Err(missed), receiver)),
+                Err(RecvError::Closed) => None,
+            }
+        });
+        let changes = events
+            .filter(move |event| {
+                future::ready(match (event, &event_types) {
+                    (Ok(event), Some(event_types)) => event_types.iter().any(|t| t == event.name()),
+                    _ => true,
+                })
+            })
+            .map(|event| match event {
+                Ok(event) => Ok(event.into()),
+                Err(missed) => Err(FieldError::from(format!("{} changes were missed", missed))),
+            });
+        Ok(Box::pin(changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    #[test]
+    fn test_directory_change() {
+        assert_eq!(
+            DirectoryChange::from(ChangeEvent::UserRenamed {
+                user_id: "robert".to_string(),
+                previous_user_id: "bob".to_string(),
+            }),
+            DirectoryChange {
+                event_type: "user_renamed".to_string(),
+                user_id: Some("robert".to_string()),
+                previous_name: Some("bob".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DirectoryChange::from(ChangeEvent::UserAddedToGroup {
+                user_id: "bob".to_string(),
+                group_id: GroupId(3),
+            }),
+            DirectoryChange {
+                event_type: "user_added_to_group".to_string(),
+                user_id: Some("bob".to_string()),
+                group_id: Some(3),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! The `/health` and `/ready` endpoints, for the healthchecks of Docker and the probes of
+//! Kubernetes. They don't need authentication, and don't say more than what is wrong.
+
+use crate::{
+    domain::sql_tables::{ping, Pool},
+    infra::tcp_server::AppState,
+};
+use actix_web::{web, HttpResponse};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+/// What the server needs to accept traffic: a reachable database, and the LDAP server listening.
+#[derive(Clone)]
+pub struct Readiness {
+    sql_pool: Pool,
+    /// Bounds the database check, which would otherwise wait for a connection of the pool.
+    timeout: Duration,
+    ldap_listening: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn new(sql_pool: Pool, timeout: Duration) -> Self {
+        Self {
+            sql_pool,
+            timeout,
+            ldap_listening: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Called when a worker of the LDAP server starts accepting connections.
+    pub fn set_ldap_listening(&self) {
+        self.ldap_listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns what isn't ready, if anything.
+    pub async fn check(&self) -> Result<(), String> {
+        if !self.ldap_listening.load(Ordering::Relaxed) {
+            return Err("The LDAP server isn't listening yet".to_string());
+        }
+        match tokio::time::timeout(self.timeout, ping(&self.sql_pool)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(format!("The database is unreachable: {}", e)),
+            Err(_) => Err("The database didn't answer in time".to_string()),
+        }
+    }
+}
+
+/// The process is up, and the HTTP server answers.
+pub(crate) async fn get_health() -> HttpResponse {
+    HttpResponse::Ok().body("OK")
+}
+
+pub(crate) async fn get_ready<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse {
+    match data.readiness.check().await {
+        Ok(()) => HttpResponse::Ok().body("OK"),
+        Err(reason) => HttpResponse::ServiceUnavailable().body(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::PoolOptions;
+
+    #[actix_rt::test]
+    async fn test_readiness() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        let readiness = Readiness::new(sql_pool.clone(), Duration::from_secs(1));
+        assert!(readiness.check().await.is_err());
+        readiness.clone().set_ldap_listening();
+        assert_eq!(readiness.check().await, Ok(()));
+        sql_pool.close().await;
+        assert!(readiness.check().await.is_err());
+    }
+}
@@ -0,0 +1,938 @@
+//! Imports users from a CSV, LDIF or JSON file.
+//!
+//! All the formats go through the same engine: the rows are first planned against the file itself
+//! and the database, so that a dry run reports exactly what a real import would do, then applied.
+//! A row is created, updated (when the user exists with different fields), skipped (when nothing
+//! changed), or left out as a conflict, e.g. a user ID or an email used twice. The groups of an
+//! LDIF file are created too, with the memberships of the known users.
+//!
+//! The changes are applied in a single transaction, then the created users are given the password
+//! of their row or, on request, invited by email to choose one.
+
+use crate::{
+    domain::{
+        handler::{BackendHandler, CreateUserRequest, Group, UpdateUserRequest, User},
+        identifiers::{normalize_user_id, UserIdPolicy},
+        sql_backend_handler::{DirectoryChanges, SqlBackendHandler},
+        sql_opaque_handler::register_password,
+    },
+    infra::{
+        cli::{ImportFormat, ImportOpts},
+        mail::{self, EmailTemplate},
+        tcp_backend_handler::TcpBackendHandler,
+    },
+};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+/// A user read from the file. The empty fields are left unchanged on update.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ImportRow {
+    /// Where the row comes from, e.g. "line 3", for the report.
+    #[serde(skip)]
+    pub source: String,
+    pub user_id: String,
+    pub email: String,
+    pub display_name: String,
+    pub first_name: String,
+    pub last_name: String,
+    /// The initial password of a created user.
+    pub password: String,
+}
+
+/// A group read from an LDIF file, with the user IDs of its members.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportGroup {
+    pub source: String,
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RowAction {
+    Create(CreateUserRequest),
+    Update(UpdateUserRequest),
+    Skip,
+    Conflict(String),
+}
+
+impl RowAction {
+    fn name(&self) -> &'static str {
+        match self {
+            RowAction::Create(_) => "create",
+            RowAction::Update(_) => "update",
+            RowAction::Skip => "skip",
+            RowAction::Conflict(_) => "conflict",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PlannedRow {
+    pub source: String,
+    pub user_id: String,
+    pub action: RowAction,
+}
+
+/// What is done for a group: its creation if it doesn't exist, and the members to add.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PlannedGroup {
+    pub source: String,
+    pub name: String,
+    pub create: bool,
+    /// The members to add, that are not members already.
+    pub new_members: Vec<String>,
+    /// The members that are neither existing nor imported users, left out.
+    pub unknown_members: Vec<String>,
+}
+
+/// The action taken (or, for a dry run, that would be taken) for each row and group.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub rows: Vec<PlannedRow>,
+    pub groups: Vec<PlannedGroup>,
+    /// Created users whose password couldn't be set or invitation sent, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for row in &self.rows {
+            write!(f, "{} {}: {}", row.source, row.user_id, row.action.name())?;
+            if let RowAction::Conflict(reason) = &row.action {
+                write!(f, " ({})", reason)?;
+            }
+            writeln!(f)?;
+        }
+        for group in &self.groups {
+            write!(
+                f,
+                "{} group {}: {}{} new members",
+                group.source,
+                group.name,
+                if group.create { "create, " } else { "" },
+                group.new_members.len()
+            )?;
+            if !group.unknown_members.is_empty() {
+                write!(
+                    f,
+                    " (unknown members: {})",
+                    group.unknown_members.join(", ")
+                )?;
+            }
+            writeln!(f)?;
+        }
+        let count = |name| self.rows.iter().filter(|r| r.action.name() == name).count();
+        writeln!(
+            f,
+            "Created: {}, updated: {}, skipped: {}, conflicts: {}",
+            count("create"),
+            count("update"),
+            count("skip"),
+            count("conflict")
+        )?;
+        if !self.groups.is_empty() {
+            writeln!(
+                f,
+                "Groups created: {}, memberships added: {}",
+                self.groups.iter().filter(|g| g.create).count(),
+                self.groups
+                    .iter()
+                    .map(|g| g.new_members.len())
+                    .sum::<usize>()
+            )?;
+        }
+        if !self.failed.is_empty() {
+            writeln!(f, "Failed rows ({}):", self.failed.len())?;
+            for (source, error) in &self.failed {
+                writeln!(f, "  - {}: {}", source, error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits the CSV records into fields, with the quoting of RFC 4180. Returns the line each record
+/// starts on with its fields.
+fn parse_csv_records(content: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {}
+            '\n' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|f| !f.is_empty()) {
+                    records.push((record_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                record_line = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if in_quotes {
+        bail!("Unterminated quoted field starting on line {}", record_line);
+    }
+    fields.push(field);
+    if fields.iter().any(|f| !f.is_empty()) {
+        records.push((record_line, fields));
+    }
+    Ok(records)
+}
+
+/// Reads a CSV file with a header naming the columns, among `user_id`, `email`, `display_name`,
+/// `first_name`, `last_name` and `password`. The other columns are ignored.
+pub fn parse_csv(content: &str) -> Result<Vec<ImportRow>> {
+    let mut records = parse_csv_records(content)?.into_iter();
+    let header = match records.next() {
+        Some((_, header)) => header,
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let user_id = column("user_id").context("The CSV header has no user_id column")?;
+    let email = column("email").context("The CSV header has no email column")?;
+    let (display_name, first_name, last_name, password) = (
+        column("display_name"),
+        column("first_name"),
+        column("last_name"),
+        column("password"),
+    );
+    Ok(records
+        .map(|(line, fields)| {
+            let get = |index: Option<usize>| {
+                index
+                    .and_then(|i| fields.get(i))
+                    .map(|f| f.trim().to_string())
+                    .unwrap_or_default()
+            };
+            ImportRow {
+                source: format!("line {}", line),
+                user_id: get(Some(user_id)),
+                email: get(Some(email)),
+                display_name: get(display_name),
+                first_name: get(first_name),
+                last_name: get(last_name),
+                password: get(password),
+            }
+        })
+        .collect())
+}
+
+/// An LDIF entry, with the line it starts on and the values of its attributes by lowercase name.
+struct LdifEntry {
+    line: usize,
+    attributes: HashMap<String, Vec<String>>,
+}
+
+impl LdifEntry {
+    fn get(&self, name: &str) -> Option<&String> {
+        self.attributes.get(name).and_then(|values| values.first())
+    }
+
+    fn get_all(&self, name: &str) -> &[String] {
+        self.attributes
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn has_object_class(&self, classes: &[&str]) -> bool {
+        self.get_all("objectclass")
+            .iter()
+            .any(|c| classes.iter().any(|class| c.eq_ignore_ascii_case(class)))
+    }
+}
+
+fn parse_ldif_entries(content: &str) -> Result<Vec<LdifEntry>> {
+    // Unfold the continuation lines first, remembering where each logical line starts.
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if let (Some(continuation), Some((_, previous))) =
+            (line.strip_prefix(' '), lines.last_mut())
+        {
+            if !previous.is_empty() {
+                previous.push_str(continuation);
+                continue;
+            }
+        }
+        lines.push((index + 1, line.to_string()));
+    }
+    let mut entries = Vec::new();
+    let mut entry: Option<LdifEntry> = None;
+    for (line, content) in lines {
+        if content.is_empty() {
+            entries.extend(entry.take());
+            continue;
+        }
+        if content.starts_with('#') {
+            continue;
+        }
+        let (name, value) = content
+            .split_once(':')
+            .with_context(|| format!("Invalid LDIF line {}", line))?;
+        let value = if let Some(encoded) = value.strip_prefix(':') {
+            base64::decode(encoded.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .with_context(|| format!("Invalid base64 value on line {}", line))?
+        } else {
+            value.trim().to_string()
+        };
+        entry
+            .get_or_insert_with(|| LdifEntry {
+                line,
+                attributes: HashMap::new(),
+            })
+            .attributes
+            .entry(name.trim().to_lowercase())
+            .or_default()
+            .push(value);
+    }
+    entries.extend(entry);
+    Ok(entries)
+}
+
+/// The user ID of a member DN: the user entry of the file with this DN, or else the value of the
+/// first RDN if it's a `uid` or a `cn`, as in the DNs of lldap and most servers.
+fn get_member_user_id(dn: &str, dn_to_user_id: &HashMap<String, String>) -> Option<String> {
+    if let Some(user_id) = dn_to_user_id.get(&dn.to_lowercase()) {
+        return Some(user_id.clone());
+    }
+    let rdn = dn.split(',').next()?;
+    let (attribute, value) = rdn.split_once('=')?;
+    match attribute.trim().to_lowercase().as_str() {
+        "uid" | "cn" => Some(value.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Reads the users and the groups of the LDIF entries.
+///
+/// The users are the entries with a `uid`, with their `mail`, `displayName` (or `cn`),
+/// `givenName` and `sn`. The groups are the `groupOfNames`, `groupOfUniqueNames` and `posixGroup`
+/// entries, named by their `cn`, with the members of their `member`, `uniqueMember` and
+/// `memberUid` attributes. Only the first value of the single-valued attributes is used, and the
+/// other entries, such as the organizational units, are ignored.
+pub fn parse_ldif(content: &str) -> Result<(Vec<ImportRow>, Vec<ImportGroup>)> {
+    let entries = parse_ldif_entries(content)?;
+    let mut rows = Vec::new();
+    let mut dn_to_user_id = HashMap::new();
+    for entry in &entries {
+        if let Some(user_id) = entry.get("uid") {
+            if let Some(dn) = entry.get("dn") {
+                dn_to_user_id.insert(dn.to_lowercase(), user_id.clone());
+            }
+            let get = |name: &str| entry.get(name).cloned().unwrap_or_default();
+            rows.push(ImportRow {
+                source: format!("line {}", entry.line),
+                user_id: user_id.clone(),
+                email: get("mail"),
+                display_name: entry
+                    .get("displayname")
+                    .or_else(|| entry.get("cn"))
+                    .cloned()
+                    .unwrap_or_default(),
+                first_name: get("givenname"),
+                last_name: get("sn"),
+                ..Default::default()
+            });
+        }
+    }
+    let groups = entries
+        .iter()
+        .filter(|entry| {
+            entry.get("uid").is_none()
+                && entry.has_object_class(&["groupOfNames", "groupOfUniqueNames", "posixGroup"])
+        })
+        .filter_map(|entry| {
+            let mut members = Vec::new();
+            for member in entry
+                .get_all("member")
+                .iter()
+                .chain(entry.get_all("uniquemember"))
+                .filter_map(|dn| get_member_user_id(dn, &dn_to_user_id))
+                .chain(entry.get_all("memberuid").iter().cloned())
+            {
+                if !members.contains(&member) {
+                    members.push(member);
+                }
+            }
+            Some(ImportGroup {
+                source: format!("line {}", entry.line),
+                name: entry.get("cn")?.clone(),
+                members,
+            })
+        })
+        .collect();
+    Ok((rows, groups))
+}
+
+/// Reads a JSON array of objects with the `user_id`, `email`, `display_name`, `first_name`,
+/// `last_name` and `password` fields.
+pub fn parse_json(content: &str) -> Result<Vec<ImportRow>> {
+    let mut rows: Vec<ImportRow> = serde_json::from_str(content).context("Invalid JSON")?;
+    for (index, row) in rows.iter_mut().enumerate() {
+        row.source = format!("entry {}", index + 1);
+    }
+    Ok(rows)
+}
+
+/// The fields of the row that differ from the user, if any.
+fn get_user_update(current: &User, row: &ImportRow) -> Option<UpdateUserRequest> {
+    let changed = |current: &String, new: &String| {
+        if new.is_empty() || new == current {
+            None
+        } else {
+            Some(new.clone())
+        }
+    };
+    let request = UpdateUserRequest {
+        user_id: current.user_id.clone(),
+        email: changed(&current.email, &row.email),
+        display_name: changed(&current.display_name, &row.display_name),
+        first_name: changed(&current.first_name, &row.first_name),
+        last_name: changed(&current.last_name, &row.last_name),
+        avatar: None,
+    };
+    if request
+        == (UpdateUserRequest {
+            user_id: current.user_id.clone(),
+            ..Default::default()
+        })
+    {
+        None
+    } else {
+        Some(request)
+    }
+}
+
+/// Decides the action for each row, detecting the user IDs and emails used twice in the file, and
+/// the emails of other users in the database. Emails are compared without case.
+pub fn plan(rows: Vec<ImportRow>, existing: &[User], policy: UserIdPolicy) -> Vec<PlannedRow> {
+    let users = existing
+        .iter()
+        .map(|u| (u.user_id.as_str(), u))
+        .collect::<HashMap<_, _>>();
+    let email_owners = existing
+        .iter()
+        .map(|u| (u.email.to_lowercase(), u.user_id.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut seen_user_ids = HashMap::new();
+    let mut seen_emails = HashMap::new();
+    rows.into_iter()
+        .map(|row| {
+            let user_id = match normalize_user_id(row.user_id.trim(), policy) {
+                Ok(user_id) => user_id,
+                Err(e) => {
+                    return PlannedRow {
+                        source: row.source,
+                        user_id: row.user_id,
+                        action: RowAction::Conflict(e.to_string()),
+                    }
+                }
+            };
+            let email = row.email.to_lowercase();
+            let conflict = if let Some(first) = seen_user_ids.get(&user_id) {
+                Some(format!("user ID already used on {}", first))
+            } else if let Some(first) = seen_emails.get(&email) {
+                Some(format!("email already used on {}", first))
+            } else {
+                match email_owners.get(&email) {
+                    Some(owner) if !email.is_empty() && *owner != user_id => {
+                        Some(format!("email already used by {}", owner))
+                    }
+                    _ => None,
+                }
+            };
+            seen_user_ids
+                .entry(user_id.clone())
+                .or_insert_with(|| row.source.clone());
+            if !email.is_empty() {
+                seen_emails
+                    .entry(email)
+                    .or_insert_with(|| row.source.clone());
+            }
+            let action = if let Some(reason) = conflict {
+                RowAction::Conflict(reason)
+            } else if let Some(current) = users.get(user_id.as_str()) {
+                get_user_update(current, &row).map_or(RowAction::Skip, RowAction::Update)
+            } else if row.email.is_empty() {
+                RowAction::Conflict("missing email".to_string())
+            } else {
+                let optional = |value: String| Some(value).filter(|v| !v.is_empty());
+                RowAction::Create(CreateUserRequest {
+                    user_id: user_id.clone(),
+                    email: row.email,
+                    display_name: optional(row.display_name),
+                    first_name: optional(row.first_name),
+                    last_name: optional(row.last_name),
+                })
+            };
+            PlannedRow {
+                source: row.source,
+                user_id,
+                action,
+            }
+        })
+        .collect()
+}
+
+/// Decides what to do for each group: create it if no existing or previous group has its name, and
+/// add the members that are not members already. `users` are the existing and created users: the
+/// other members are left out.
+pub fn plan_groups(
+    groups: Vec<ImportGroup>,
+    existing: &[Group],
+    users: &HashSet<String>,
+) -> Vec<PlannedGroup> {
+    let mut members_by_name = existing
+        .iter()
+        .map(|g| {
+            (
+                g.display_name.clone(),
+                g.users.iter().cloned().collect::<HashSet<_>>(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    groups
+        .into_iter()
+        .map(|group| {
+            let create = !members_by_name.contains_key(&group.name);
+            let members = members_by_name.entry(group.name.clone()).or_default();
+            let (known, unknown_members): (Vec<_>, Vec<_>) = group
+                .members
+                .into_iter()
+                .partition(|member| users.contains(member));
+            PlannedGroup {
+                source: group.source,
+                name: group.name,
+                create,
+                new_members: known
+                    .into_iter()
+                    .filter(|member| members.insert(member.clone()))
+                    .collect(),
+                unknown_members,
+            }
+        })
+        .collect()
+}
+
+fn get_format(opts: &ImportOpts) -> Result<ImportFormat> {
+    if let Some(format) = opts.format {
+        return Ok(format);
+    }
+    let extension = std::path::Path::new(&opts.file)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("csv") => Ok(ImportFormat::Csv),
+        Some("ldif") => Ok(ImportFormat::Ldif),
+        Some("json") => Ok(ImportFormat::Json),
+        _ => bail!("Unknown file extension, specify the --format"),
+    }
+}
+
+/// Emails the user a link to choose their password.
+async fn send_invitation(handler: &SqlBackendHandler, user_id: &str) -> Result<()> {
+    let smtp_config = handler
+        .config
+        .smtp
+        .as_ref()
+        .context("Sending invitations requires the smtp configuration")?;
+    let user = handler.get_user_details(user_id).await?;
+    let token = handler
+        .start_password_reset(user_id)
+        .await?
+        .context("The user doesn't exist")?;
+    let mut context = tera::Context::new();
+    context.insert("display_name", &user.display_name);
+    context.insert("user_id", &user.user_id);
+    context.insert(
+        "link",
+        &format!(
+            "{}/reset-password/step2/{}",
+            handler.config.http_url.trim_end_matches('/'),
+            token
+        ),
+    );
+    mail::send_template_email(
+        smtp_config,
+        &user.display_name,
+        &user.email,
+        EmailTemplate::Invitation,
+        &context,
+    )
+    .await
+}
+
+/// Sets the password of a created user, or sends the invitation.
+async fn set_up_created_user(
+    handler: &SqlBackendHandler,
+    opts: &ImportOpts,
+    user_id: &str,
+    password: &str,
+) -> Result<()> {
+    if !password.is_empty() {
+        register_password(handler, user_id, password)
+            .await
+            .context("while setting the password")?;
+    } else if opts.send_invitations {
+        send_invitation(handler, user_id)
+            .await
+            .context("while sending the invitation")?;
+    }
+    Ok(())
+}
+
+/// Reads the file and plans the rows and the groups against the database. Unless `dry_run` is set,
+/// the planned changes are then applied, in a single transaction.
+pub async fn import(handler: &SqlBackendHandler, opts: &ImportOpts) -> Result<ImportReport> {
+    let content = std::fs::read_to_string(&opts.file)
+        .with_context(|| format!("while reading {}", opts.file))?;
+    let (rows, groups) = match get_format(opts)? {
+        ImportFormat::Csv => (parse_csv(&content)?, Vec::new()),
+        ImportFormat::Ldif => parse_ldif(&content)?,
+        ImportFormat::Json => (parse_json(&content)?, Vec::new()),
+    };
+    if opts.send_invitations && handler.config.smtp.is_none() {
+        bail!("Sending invitations requires the smtp configuration");
+    }
+    let passwords = rows
+        .iter()
+        .map(|row| (row.source.clone(), row.password.clone()))
+        .collect::<HashMap<_, _>>();
+    let existing = handler.list_users(None).await?;
+    let rows = plan(rows, &existing, handler.config.user_id_policy);
+    let users = existing
+        .into_iter()
+        .map(|u| u.user_id)
+        .chain(
+            rows.iter()
+                .filter(|row| matches!(row.action, RowAction::Create(_)))
+                .map(|row| row.user_id.clone()),
+        )
+        .collect::<HashSet<_>>();
+    let mut report = ImportReport {
+        rows,
+        groups: plan_groups(groups, &handler.list_groups(None).await?, &users),
+        failed: Vec::new(),
+    };
+    if opts.dry_run {
+        return Ok(report);
+    }
+    let mut changes = DirectoryChanges::default();
+    for row in &report.rows {
+        match &row.action {
+            RowAction::Create(request) => changes.created_users.push(request.clone()),
+            RowAction::Update(request) => changes.updated_users.push(request.clone()),
+            RowAction::Skip | RowAction::Conflict(_) => {}
+        }
+    }
+    changes.group_members = report
+        .groups
+        .iter()
+        .filter(|group| group.create || !group.new_members.is_empty())
+        .map(|group| (group.name.clone(), group.new_members.clone()))
+        .collect();
+    handler
+        .apply_changes(changes)
+        .await
+        .context("while applying the changes, nothing was imported")?;
+    for row in &report.rows {
+        if let RowAction::Create(_) = row.action {
+            if let Err(e) =
+                set_up_created_user(handler, opts, &row.user_id, &passwords[&row.source]).await
+            {
+                report.failed.push((row.source.clone(), format!("{:#}", e)));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_user(user_id: &str, email: &str) -> User {
+        User {
+            user_id: user_id.to_string(),
+            email: email.to_string(),
+            display_name: "Name".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn make_row(source: &str, user_id: &str, email: &str) -> ImportRow {
+        ImportRow {
+            source: source.to_string(),
+            user_id: user_id.to_string(),
+            email: email.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let content = "email,user_id,Display_Name,team\r\n\
+                       bob@example.com,bob,\"Bobberson, Bob\",a\r\n\
+                       \n\
+                       \"john\"\"@example.com\",john,\"Two\nlines\"\n\
+                       ,patrick\n";
+        assert_eq!(
+            parse_csv(content).unwrap(),
+            vec![
+                ImportRow {
+                    source: "line 2".to_string(),
+                    user_id: "bob".to_string(),
+                    email: "bob@example.com".to_string(),
+                    display_name: "Bobberson, Bob".to_string(),
+                    ..Default::default()
+                },
+                ImportRow {
+                    source: "line 4".to_string(),
+                    user_id: "john".to_string(),
+                    email: "john\"@example.com".to_string(),
+                    display_name: "Two\nlines".to_string(),
+                    ..Default::default()
+                },
+                make_row("line 6", "patrick", ""),
+            ]
+        );
+        assert!(parse_csv("user_id,mail\nbob,bob@example.com").is_err());
+        assert!(parse_csv("user_id,email\n\"bob,bob@example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_ldif() {
+        let content = "version: 1\n\
+                       \n\
+                       dn: ou=people,dc=example,dc=com\n\
+                       ou: people\n\
+                       \n\
+                       # Bob\n\
+                       dn: uid=bob,ou=people,dc=example,dc=com\n\
+                       uid: bob\n\
+                       mail: bob@exam\n ple.com\n\
+                       cn: Bob Bobberson\n\
+                       givenName: Bob\n\
+                       \n\
+                       dn: uid=john,ou=people,dc=example,dc=com\n\
+                       uid: john\n\
+                       mail: john@example.com\n\
+                       displayName:: Sm/Dq2w=\n\
+                       \n\
+                       dn: cn=admins,ou=groups,dc=example,dc=com\n\
+                       objectClass: groupOfNames\n\
+                       cn: admins\n\
+                       member: uid=bob,ou=people,dc=example,dc=com\n\
+                       member: cn=patrick,ou=people,dc=example,dc=com\n\
+                       member: ou=people,dc=example,dc=com\n\
+                       memberUid: bob\n\
+                       \n\
+                       dn: cn=empty,ou=groups,dc=example,dc=com\n\
+                       objectClass: top\n\
+                       objectClass: posixGroup\n\
+                       cn: empty\n";
+        let (rows, groups) = parse_ldif(content).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ImportRow {
+                    source: "line 7".to_string(),
+                    user_id: "bob".to_string(),
+                    email: "bob@example.com".to_string(),
+                    display_name: "Bob Bobberson".to_string(),
+                    first_name: "Bob".to_string(),
+                    ..Default::default()
+                },
+                ImportRow {
+                    source: "line 14".to_string(),
+                    user_id: "john".to_string(),
+                    email: "john@example.com".to_string(),
+                    display_name: "Joël".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            groups,
+            vec![
+                ImportGroup {
+                    source: "line 19".to_string(),
+                    name: "admins".to_string(),
+                    members: vec!["bob".to_string(), "patrick".to_string()],
+                },
+                ImportGroup {
+                    source: "line 27".to_string(),
+                    name: "empty".to_string(),
+                    members: vec![],
+                },
+            ]
+        );
+        assert!(parse_ldif("dn uid=bob\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        assert_eq!(
+            parse_json(r#"[{"user_id": "bob", "email": "bob@example.com"}, {"user_id": "john"}]"#)
+                .unwrap(),
+            vec![
+                make_row("entry 1", "bob", "bob@example.com"),
+                make_row("entry 2", "john", ""),
+            ]
+        );
+        assert!(parse_json(r#"{"user_id": "bob"}"#).is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let existing = vec![
+            make_user("bob", "bob@example.com"),
+            make_user("patrick", "patrick@example.com"),
+        ];
+        let rows = vec![
+            make_row("line 2", "bob", "bob@example.com"),
+            make_row("line 3", "patrick", "patrick@new.example.com"),
+            make_row("line 4", "john", "john@example.com"),
+            make_row("line 5", "john", "john2@example.com"),
+            make_row("line 6", "jane", "JOHN@example.com"),
+            make_row("line 7", "jim", "Bob@example.com"),
+            make_row("line 8", "jill", ""),
+            make_row("line 9", "jack,ou=admins", "jack@example.com"),
+        ];
+        let actions = plan(rows, &existing, UserIdPolicy::Unicode)
+            .into_iter()
+            .map(|row| (row.source, row.action))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                ("line 2".to_string(), RowAction::Skip),
+                (
+                    "line 3".to_string(),
+                    RowAction::Update(UpdateUserRequest {
+                        user_id: "patrick".to_string(),
+                        email: Some("patrick@new.example.com".to_string()),
+                        ..Default::default()
+                    })
+                ),
+                (
+                    "line 4".to_string(),
+                    RowAction::Create(CreateUserRequest {
+                        user_id: "john".to_string(),
+                        email: "john@example.com".to_string(),
+                        ..Default::default()
+                    })
+                ),
+                (
+                    "line 5".to_string(),
+                    RowAction::Conflict("user ID already used on line 4".to_string())
+                ),
+                (
+                    "line 6".to_string(),
+                    RowAction::Conflict("email already used on line 4".to_string())
+                ),
+                (
+                    "line 7".to_string(),
+                    RowAction::Conflict("email already used by bob".to_string())
+                ),
+                (
+                    "line 8".to_string(),
+                    RowAction::Conflict("missing email".to_string())
+                ),
+                (
+                    "line 9".to_string(),
+                    RowAction::Conflict(
+                        normalize_user_id("jack,ou=admins", UserIdPolicy::Unicode)
+                            .unwrap_err()
+                            .to_string()
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_groups() {
+        let existing = vec![Group {
+            id: crate::domain::handler::GroupId(1),
+            display_name: "admins".to_string(),
+            users: vec!["bob".to_string()],
+        }];
+        let users = ["bob", "john"]
+            .iter()
+            .map(|u| u.to_string())
+            .collect::<HashSet<_>>();
+        let group = |source: &str, name: &str, members: &[&str]| ImportGroup {
+            source: source.to_string(),
+            name: name.to_string(),
+            members: members.iter().map(|m| m.to_string()).collect(),
+        };
+        assert_eq!(
+            plan_groups(
+                vec![
+                    group("line 1", "admins", &["bob", "john", "patrick"]),
+                    group("line 5", "staff", &["john"]),
+                    group("line 9", "staff", &["john", "bob"]),
+                ],
+                &existing,
+                &users
+            ),
+            vec![
+                PlannedGroup {
+                    source: "line 1".to_string(),
+                    name: "admins".to_string(),
+                    create: false,
+                    new_members: vec!["john".to_string()],
+                    unknown_members: vec!["patrick".to_string()],
+                },
+                PlannedGroup {
+                    source: "line 5".to_string(),
+                    name: "staff".to_string(),
+                    create: true,
+                    new_members: vec!["john".to_string()],
+                    unknown_members: vec![],
+                },
+                PlannedGroup {
+                    source: "line 9".to_string(),
+                    name: "staff".to_string(),
+                    create: false,
+                    new_members: vec!["bob".to_string()],
+                    unknown_members: vec![],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! The keys signing the JWTs of the web UI, and the previous ones still accepted after a rotation.
+//!
+//! With HS512, the tokens are signed with the `jwt_secret`, which the services checking them must
+//! share. With RS256, they are signed with the RSA key of the `jwt_key_file`, and the public keys
+//! are served at `/.well-known/jwks.json` for the services to check them without any secret.
+//!
+//! The tokens name their key in the `kid` header, derived with the server key for the secrets so
+//! that it doesn't help guessing them. To rotate the key, move the current one to
+//! `jwt_previous_secrets` or `jwt_previous_key_files` and set a new one: the tokens signed with the
+//! previous key stay valid until they expire, a day later, after which it can be removed.
+
+use crate::infra::{
+    configuration::{Configuration, JwtAlgorithm},
+    oidc_provider::get_signing_key,
+    tcp_server::AppState,
+};
+use actix_web::{web, HttpResponse};
+use anyhow::Result;
+use hmac::{Hmac, Mac, NewMac};
+use jwt::{
+    token::{Unverified, Verified},
+    AlgorithmType, FromBase64, Header, SigningAlgorithm, Token, VerifyWithKey, VerifyingAlgorithm,
+};
+use rsa::{Hash, PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+use std::sync::Arc;
+
+fn base64_url(bytes: impl AsRef<[u8]>) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Changes with the key, so that the tokens name the one to check them with.
+fn get_key_id(key_bytes: &[u8]) -> String {
+    base64_url(&Sha256::digest(key_bytes)[..12])
+}
+
+/// Changes with the secret too, but keyed with the server key: a hash of the secret alone could
+/// be checked against guesses of it.
+fn get_secret_key_id(secret: &str, server_key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(server_key).unwrap();
+    mac.update(secret.as_bytes());
+    base64_url(&mac.finalize().into_bytes()[..12])
+}
+
+fn rsa_padding() -> PaddingScheme {
+    PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256))
+}
+
+fn signed_payload(header: &str, claims: &str) -> Vec<u8> {
+    Sha256::digest(format!("{}.{}", header, claims).as_bytes()).to_vec()
+}
+
+#[derive(Clone)]
+enum KeyMaterial {
+    Hmac(Hmac<Sha512>),
+    Rsa {
+        private_key: Arc<RsaPrivateKey>,
+        public_key: RsaPublicKey,
+    },
+}
+
+/// A key signing or checking the JWTs, with its key ID.
+#[derive(Clone)]
+pub struct JwtKey {
+    key_id: String,
+    material: KeyMaterial,
+}
+
+impl JwtKey {
+    /// The key of a shared secret. Its key ID is derived with the `server_key`, which stays
+    /// private.
+    pub fn hmac(secret: &str, server_key: &[u8]) -> Self {
+        Self {
+            key_id: get_secret_key_id(secret, server_key),
+            material: KeyMaterial::Hmac(Hmac::new_varkey(secret.as_bytes()).unwrap()),
+        }
+    }
+
+    pub fn rsa(private_key: RsaPrivateKey) -> Self {
+        Self {
+            key_id: get_key_id(&private_key.n().to_bytes_be()),
+            material: KeyMaterial::Rsa {
+                public_key: private_key.to_public_key(),
+                private_key: Arc::new(private_key),
+            },
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn get_algorithm(&self) -> AlgorithmType {
+        match self.material {
+            KeyMaterial::Hmac(_) => AlgorithmType::Hs512,
+            KeyMaterial::Rsa { .. } => AlgorithmType::Rs256,
+        }
+    }
+
+    /// The public key, in the JWK format, or nothing for a shared secret.
+    fn get_jwk(&self) -> Option<Value> {
+        match &self.material {
+            KeyMaterial::Hmac(_) => None,
+            KeyMaterial::Rsa { public_key, .. } => Some(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.key_id,
+                "n": base64_url(public_key.n().to_bytes_be()),
+                "e": base64_url(public_key.e().to_bytes_be()),
+            })),
+        }
+    }
+}
+
+impl SigningAlgorithm for JwtKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        self.get_algorithm()
+    }
+
+    fn sign(&self, header: &str, claims: &str) -> Result<String, jwt::Error> {
+        match &self.material {
+            KeyMaterial::Hmac(key) => SigningAlgorithm::sign(key, header, claims),
+            KeyMaterial::Rsa { private_key, .. } => private_key
+                .sign(rsa_padding(), &signed_payload(header, claims))
+                .map(base64_url)
+                // Only for an invalid key, which wouldn't have been loaded.
+                .map_err(|_| jwt::Error::InvalidSignature),
+        }
+    }
+}
+
+impl VerifyingAlgorithm for JwtKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        self.get_algorithm()
+    }
+
+    fn verify_bytes(
+        &self,
+        header: &str,
+        claims: &str,
+        signature: &[u8],
+    ) -> Result<bool, jwt::Error> {
+        match &self.material {
+            KeyMaterial::Hmac(key) => key.verify_bytes(header, claims, signature),
+            KeyMaterial::Rsa { public_key, .. } => Ok(public_key
+                .verify(rsa_padding(), &signed_payload(header, claims), signature)
+                .is_ok()),
+        }
+    }
+}
+
+/// The key signing the new JWTs, and the previous ones, only checking them.
+#[derive(Clone)]
+pub struct JwtKeys {
+    current: JwtKey,
+    previous: Vec<JwtKey>,
+}
+
+impl JwtKeys {
+    pub fn new(current: JwtKey, previous: Vec<JwtKey>) -> Self {
+        Self { current, previous }
+    }
+
+    /// Loads the keys of the configuration, generating the `jwt_key_file` if it doesn't exist.
+    pub fn from_config(config: &Configuration) -> Result<Self> {
+        let server_key = config.get_server_keys().private();
+        let current = match config.jwt_algorithm {
+            JwtAlgorithm::Hs512 => JwtKey::hmac(&config.jwt_secret, server_key),
+            JwtAlgorithm::Rs256 => JwtKey::rsa(get_signing_key(&config.jwt_key_file, "JWT")?),
+        };
+        let mut previous = config
+            .jwt_previous_secrets
+            .iter()
+            .map(|secret| JwtKey::hmac(secret, server_key))
+            .collect::<Vec<_>>();
+        for file in &config.jwt_previous_key_files {
+            previous.push(JwtKey::rsa(get_signing_key(file, "previous JWT")?));
+        }
+        Ok(Self::new(current, previous))
+    }
+
+    /// The key signing the new tokens.
+    pub fn signing_key(&self) -> &JwtKey {
+        &self.current
+    }
+
+    /// The header of the new tokens, naming the current key.
+    pub fn header(&self) -> Header {
+        Header {
+            algorithm: self.current.get_algorithm(),
+            key_id: Some(self.current.key_id.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Checks the token with the key it names. The tokens without key ID, signed before the key
+    /// IDs, are checked with the current key.
+    pub fn verify<Claims: FromBase64>(
+        &self,
+        token: &str,
+    ) -> Result<Token<Header, Claims, Verified>, jwt::Error> {
+        let token: Token<Header, Claims, Unverified> = Token::parse_unverified(token)?;
+        let key = match &token.header().key_id {
+            None => &self.current,
+            Some(key_id) => std::iter::once(&self.current)
+                .chain(self.previous.iter())
+                .find(|key| &key.key_id == key_id)
+                .ok_or_else(|| jwt::Error::NoKeyWithKeyId(key_id.clone()))?,
+        };
+        token.verify_with_key(key)
+    }
+
+    /// The public keys, for the other services to check the tokens.
+    pub fn get_jwks(&self) -> Value {
+        json!({
+            "keys": std::iter::once(&self.current)
+                .chain(self.previous.iter())
+                .filter_map(JwtKey::get_jwk)
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub(crate) async fn get_jwks<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.jwt_keys.get_jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jwt::SignWithKey;
+    use std::collections::BTreeMap;
+
+    type Claims = BTreeMap<String, String>;
+
+    const SERVER_KEY: &[u8] = b"server key";
+
+    fn sign(keys: &JwtKeys) -> String {
+        let mut claims = Claims::new();
+        claims.insert("user".to_string(), "bob".to_string());
+        Token::new(keys.header(), claims)
+            .sign_with_key(keys.signing_key())
+            .unwrap()
+            .as_str()
+            .to_string()
+    }
+
+    fn rsa_key() -> JwtKey {
+        // Small, for the tests to be fast.
+        JwtKey::rsa(RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap())
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        for key in [JwtKey::hmac("secret", SERVER_KEY), rsa_key()] {
+            let keys = JwtKeys::new(key.clone(), vec![]);
+            let token = keys.verify::<Claims>(&sign(&keys)).unwrap();
+            assert_eq!(token.claims()["user"], "bob");
+            assert_eq!(token.header().key_id.as_deref(), Some(key.key_id()));
+            // Another key with the same algorithm doesn't validate it.
+            let other = match key.get_algorithm() {
+                AlgorithmType::Hs512 => JwtKey::hmac("other", SERVER_KEY),
+                _ => rsa_key(),
+            };
+            JwtKeys::new(other, vec![])
+                .verify::<Claims>(&sign(&keys))
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old_keys = JwtKeys::new(JwtKey::hmac("old", SERVER_KEY), vec![]);
+        let old_token = sign(&old_keys);
+        let new_keys = JwtKeys::new(rsa_key(), vec![JwtKey::hmac("old", SERVER_KEY)]);
+        new_keys.verify::<Claims>(&old_token).unwrap();
+        let new_token = sign(&new_keys);
+        new_keys.verify::<Claims>(&new_token).unwrap();
+        old_keys.verify::<Claims>(&new_token).unwrap_err();
+        // Once the previous key is removed.
+        JwtKeys::new(new_keys.signing_key().clone(), vec![])
+            .verify::<Claims>(&old_token)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_token_without_key_id() {
+        let keys = JwtKeys::new(JwtKey::hmac("secret", SERVER_KEY), vec![]);
+        let mut claims = Claims::new();
+        claims.insert("user".to_string(), "bob".to_string());
+        let header = Header {
+            algorithm: AlgorithmType::Hs512,
+            ..Default::default()
+        };
+        let token = Token::new(header, claims)
+            .sign_with_key(&Hmac::<Sha512>::new_varkey(b"secret").unwrap())
+            .unwrap();
+        keys.verify::<Claims>(token.as_str()).unwrap();
+    }
+
+    #[test]
+    fn test_hmac_key_id() {
+        let key = JwtKey::hmac("secret", SERVER_KEY);
+        assert_eq!(key.key_id(), JwtKey::hmac("secret", SERVER_KEY).key_id());
+        assert_ne!(key.key_id(), JwtKey::hmac("other", SERVER_KEY).key_id());
+        // Not a hash of the secret alone.
+        assert_ne!(key.key_id(), get_key_id(b"secret"));
+        assert_ne!(key.key_id(), JwtKey::hmac("secret", b"other key").key_id());
+    }
+
+    #[test]
+    fn test_jwks() {
+        let current = rsa_key();
+        let previous = rsa_key();
+        let keys = JwtKeys::new(
+            current.clone(),
+            vec![JwtKey::hmac("old", SERVER_KEY), previous.clone()],
+        );
+        let jwks = keys.get_jwks();
+        let key_ids = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["kid"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(key_ids, vec![current.key_id(), previous.key_id()]);
+        assert_eq!(jwks["keys"][0]["alg"], "RS256");
+        assert_eq!(
+            JwtKeys::new(JwtKey::hmac("secret", SERVER_KEY), vec![]).get_jwks(),
+            json!({ "keys": [] })
+        );
+    }
+}
@@ -21,8 +21,18 @@ pub enum JwtStorage {
     Blacklisted,
 }
 
+/// Contains the one-time tokens of the password resets in progress.
+#[derive(Iden)]
+pub enum PasswordResetTokens {
+    Table,
+    Token,
+    UserId,
+    ExpiryDate,
+}
+
 /// This needs to be initialized after the domain tables are.
 pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
+    let backend = DbBackend::of(pool);
     sqlx::query(
         &Table::create()
             .table(JwtRefreshStorage::Table)
@@ -38,11 +48,7 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .string_len(255)
                     .not_null(),
             )
-            .col(
-                ColumnDef::new(JwtRefreshStorage::ExpiryDate)
-                    .date_time()
-                    .not_null(),
-            )
+            .col(date_time_column(JwtRefreshStorage::ExpiryDate, backend).not_null())
             .foreign_key(
                 ForeignKey::create()
                     .name("JwtRefreshStorageUserForeignKey")
@@ -51,7 +57,7 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .on_delete(ForeignKeyAction::Cascade)
                     .on_update(ForeignKeyAction::Cascade),
             )
-            .to_string(DbQueryBuilder {}),
+            .to_db_string(backend),
     )
     .execute(pool)
     .await?;
@@ -71,11 +77,7 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .string_len(255)
                     .not_null(),
             )
-            .col(
-                ColumnDef::new(JwtStorage::ExpiryDate)
-                    .date_time()
-                    .not_null(),
-            )
+            .col(date_time_column(JwtStorage::ExpiryDate, backend).not_null())
             .col(
                 ColumnDef::new(JwtStorage::Blacklisted)
                     .boolean()
@@ -90,7 +92,36 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .on_delete(ForeignKeyAction::Cascade)
                     .on_update(ForeignKeyAction::Cascade),
             )
-            .to_string(DbQueryBuilder {}),
+            .to_db_string(backend),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(PasswordResetTokens::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(PasswordResetTokens::Token)
+                    .string_len(255)
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(PasswordResetTokens::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(date_time_column(PasswordResetTokens::ExpiryDate, backend).not_null())
+            .foreign_key(
+                ForeignKey::create()
+                    .name("PasswordResetTokensUserForeignKey")
+                    .table(PasswordResetTokens::Table, Users::Table)
+                    .col(PasswordResetTokens::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_db_string(backend),
     )
     .execute(pool)
     .await?;
@@ -1,19 +1,42 @@
-use crate::domain::{
-    handler::{
-        BackendHandler, BindRequest, Group, GroupIdAndName, LoginHandler, RequestFilter, User,
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{
+            AuthFailure, AuthFailureReason, AuthProtocol, BackendHandler, BindRequest, Group,
+            GroupId, GroupIdAndName, GroupMail, GroupMetadata, GroupRequestFilter, LoginHandler,
+            RequestFilter, SortDirection, SubstringFilter, User, UserLock, UserOrder,
+        },
+        identifiers::fold_case,
+        opaque_handler::OpaqueHandler,
+    },
+    infra::{
+        access_control::{can_change_password, Permission, ValidationResults},
+        audit_backend_handler::AuditContext,
+        bind_throttle::BindThrottle,
+        configuration::{
+            LdapAnonymousAccess, LdapAttributeCasing, LdapAttributeMappingConfig,
+            LdapAttributeProfile, LdapSchemaConfig, PasswordPolicyConfig, PosixConfig,
+        },
+        ldap_monitor::{is_monitor_dn, LdapMonitor, LdapOperation},
+        metrics::Metrics,
+        password_policy::check_password,
     },
-    opaque_handler::OpaqueHandler,
 };
 use anyhow::{bail, Context, Result};
-use futures::stream::StreamExt;
-use futures_util::TryStreamExt;
+use futures::{
+    future,
+    stream::{self, LocalBoxStream, StreamExt},
+};
 use ldap3_server::proto::{
-    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest, LdapExtendedResponse,
-    LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult,
-    LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapControl, LdapExtendedRequest,
+    LdapExtendedResponse, LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest,
+    LdapResult, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+    LdapSubstringFilter,
 };
-use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::rc::Rc;
+use tracing::{debug, warn};
 
 fn make_dn_pair<I>(mut iter: I) -> Result<(String, String)>
 where
@@ -96,14 +119,352 @@ fn get_user_id_from_distinguished_name(
     }
 }
 
-fn get_user_attribute(user: &User, attribute: &str, dn: &str) -> Result<Vec<String>> {
+/// A part of an [`AttributeTemplate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TemplatePart {
+    Text(String),
+    /// Replaced with the value of this built-in attribute.
+    Attribute(String),
+}
+
+/// The value of a mapped attribute (see [`LdapAttributeMappingConfig`]): a text with `{name}`
+/// placeholders for the built-in attributes of the entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeTemplate(Vec<TemplatePart>);
+
+impl AttributeTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let check_text = |text: &str| {
+            if text.contains('}') {
+                bail!(r#"Unexpected "}}" in "{}""#, template);
+            }
+            Ok(TemplatePart::Text(text.to_string()))
+        };
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(check_text(&rest[..start])?);
+            }
+            let length = rest[start..]
+                .find('}')
+                .with_context(|| format!(r#"Unclosed placeholder in "{}""#, template))?;
+            let name = &rest[start + 1..start + length];
+            if name.is_empty() || name.contains('{') {
+                bail!(r#"Invalid placeholder "{}" in "{}""#, name, template);
+            }
+            parts.push(TemplatePart::Attribute(name.to_string()));
+            rest = &rest[start + length + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(check_text(rest)?);
+        }
+        Ok(Self(parts))
+    }
+
+    /// The attribute it is an alias of, if the template is a single placeholder.
+    fn alias(&self) -> Option<&str> {
+        match self.0.as_slice() {
+            [TemplatePart::Attribute(name)] => Some(name),
+            _ => None,
+        }
+    }
+
+    fn attributes(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|part| match part {
+            TemplatePart::Attribute(name) => Some(name.as_str()),
+            TemplatePart::Text(_) => None,
+        })
+    }
+
+    /// All the values of the attribute for an alias, otherwise a single value with the
+    /// placeholders replaced by the first value of their attribute.
+    fn expand(&self, get_attribute: impl Fn(&str) -> Result<Vec<String>>) -> Result<Vec<String>> {
+        if let Some(name) = self.alias() {
+            return get_attribute(name);
+        }
+        let mut value = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Text(text) => value.push_str(text),
+                TemplatePart::Attribute(name) => {
+                    if let Some(first) = get_attribute(name)?.into_iter().next() {
+                        value.push_str(&first);
+                    }
+                }
+            }
+        }
+        Ok(vec![value])
+    }
+}
+
+/// The mapped attributes of the users or of the groups, by lowercase name, with their configured
+/// name.
+#[derive(Clone, Debug, Default)]
+struct AttributeMapping(HashMap<String, (String, AttributeTemplate)>);
+
+impl AttributeMapping {
+    fn new(config: &HashMap<String, String>) -> Self {
+        Self(
+            config
+                .iter()
+                .filter_map(
+                    |(name, template)| match AttributeTemplate::parse(template) {
+                        Ok(template) => Some((name.to_lowercase(), (name.clone(), template))),
+                        Err(e) => {
+                            warn!("Ignoring the mapped attribute {}: {:#}", name, e);
+                            None
+                        }
+                    },
+                )
+                .collect(),
+        )
+    }
+
+    fn get(&self, attribute: &str) -> Option<&AttributeTemplate> {
+        self.0
+            .get(&attribute.to_lowercase())
+            .map(|(_, template)| template)
+    }
+
+    fn name(&self, attribute: &str) -> Option<&str> {
+        self.0
+            .get(&attribute.to_lowercase())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The built-in attributes the requested one is made of.
+    fn sources<'a>(&'a self, requested: &'a str) -> Vec<&'a str> {
+        match self.get(requested) {
+            Some(template) => template.attributes().collect(),
+            None => vec![requested],
+        }
+    }
+
+    /// The built-in attribute that the filters on this attribute apply to. Only the aliases of
+    /// the mapped attributes can be filtered on.
+    fn filtered_attribute<'a>(&'a self, attribute: &'a str) -> Result<&'a str> {
+        match self.get(attribute) {
+            Some(template) => template.alias().with_context(|| {
+                format!("The mapped attribute {} can't be filtered on", attribute)
+            }),
+            None => Ok(attribute),
+        }
+    }
+}
+
+/// The attribute profile of a handler: the values derived from the base DN that the Active
+/// Directory attributes need, and the mapped attributes.
+#[derive(Clone, Debug)]
+struct AttributeProfile {
+    kind: LdapAttributeProfile,
+    casing: LdapAttributeCasing,
+    user_mapping: AttributeMapping,
+    group_mapping: AttributeMapping,
+    posix: PosixConfig,
+    /// The parsed `posix.home_directory`.
+    home_directory: AttributeTemplate,
+    /// Domain for the `userPrincipalName`, e.g. "example.com" for "dc=example,dc=com".
+    principal_domain: String,
+    /// Domain part of the `objectSid`, e.g. "S-1-5-21-1-2-3".
+    domain_sid: String,
+}
+
+impl AttributeProfile {
+    fn new(kind: LdapAttributeProfile, base_dn: &[(String, String)], base_dn_str: &str) -> Self {
+        let domain_hash = sid_sub_authorities(base_dn_str);
+        Self {
+            kind,
+            casing: LdapAttributeCasing::AsRequested,
+            user_mapping: AttributeMapping::default(),
+            group_mapping: AttributeMapping::default(),
+            home_directory: AttributeTemplate::parse(&PosixConfig::default().home_directory)
+                .unwrap(),
+            posix: PosixConfig::default(),
+            principal_domain: base_dn
+                .iter()
+                .filter(|(k, _)| k == "dc")
+                .map(|(_, v)| v.as_str())
+                .collect::<Vec<_>>()
+                .join("."),
+            domain_sid: format!(
+                "S-1-5-21-{}-{}-{}",
+                domain_hash[0], domain_hash[1], domain_hash[2]
+            ),
+        }
+    }
+
+    fn is_active_directory(&self) -> bool {
+        self.kind == LdapAttributeProfile::ActiveDirectory
+    }
+
+    /// The name of a requested attribute in the results, in the configured casing. The mapped
+    /// attributes and the custom attributes of the `schema` keep their configured casing.
+    fn attribute_type(&self, requested: &str, schema: Option<&LdapSchemaConfig>) -> String {
+        match self.casing {
+            LdapAttributeCasing::AsRequested => requested.to_string(),
+            LdapAttributeCasing::Lowercase => requested.to_lowercase(),
+            LdapAttributeCasing::Canonical => self
+                .user_mapping
+                .name(requested)
+                .or_else(|| self.group_mapping.name(requested))
+                .or_else(|| {
+                    CANONICAL_ATTRIBUTE_NAMES
+                        .iter()
+                        .find(|name| name.eq_ignore_ascii_case(requested))
+                        .copied()
+                })
+                .map(|name| name.to_string())
+                .or_else(|| Some(schema?.get_attribute(requested)?.name.clone()))
+                .unwrap_or_else(|| requested.to_string()),
+        }
+    }
+
+    fn user_principal_name(&self, user_id: &str) -> String {
+        format!("{}@{}", user_id, self.principal_domain)
+    }
+
+    /// Strips the domain from a `userPrincipalName`, if it is ours.
+    fn user_id_from_principal_name<'a>(&self, principal_name: &'a str) -> Option<&'a str> {
+        let (user_id, domain) = principal_name.rsplit_once('@')?;
+        if domain.eq_ignore_ascii_case(&self.principal_domain) {
+            Some(user_id)
+        } else {
+            None
+        }
+    }
+
+    /// The RID of a user is derived from its ID, with the top bit set so that it never collides
+    /// with a group RID (the group ID).
+    fn user_sid(&self, user_id: &str) -> String {
+        format!(
+            "{}-{}",
+            self.domain_sid,
+            sid_sub_authorities(user_id)[0] | 0x8000_0000
+        )
+    }
+
+    fn group_sid(&self, group: &Group) -> String {
+        format!("{}-{}", self.domain_sid, group.id.0)
+    }
+
+    /// The `gidNumber` of the user's primary group.
+    fn user_gid_number(&self, user: &User) -> i32 {
+        self.posix.user_gid_number.unwrap_or(user.uid_number)
+    }
+
+    fn group_gid_number(&self, group_id: GroupId) -> i32 {
+        self.posix.first_gid_number + group_id.0
+    }
+
+    fn group_id_from_gid_number(&self, gid_number: &str) -> Result<GroupId> {
+        let gid_number = gid_number
+            .parse::<i32>()
+            .with_context(|| format!("Invalid gidNumber: {}", gid_number))?;
+        Ok(GroupId(gid_number - self.posix.first_gid_number))
+    }
+}
+
+/// The usual casing of the built-in attributes.
+const CANONICAL_ATTRIBUTE_NAMES: &[&str] = &[
+    "objectClass",
+    "dn",
+    "uid",
+    "mail",
+    "givenName",
+    "sn",
+    "cn",
+    "displayName",
+    "description",
+    "createTimestamp",
+    "modifyTimestamp",
+    "entryUUID",
+    "host",
+    "sAMAccountName",
+    "userPrincipalName",
+    "objectSid",
+    "objectGUID",
+    "member",
+    "uniqueMember",
+    "memberUid",
+    "memberOf",
+    "pwdAccountLockedTime",
+    "jpegPhoto",
+    "uidNumber",
+    "gidNumber",
+    "homeDirectory",
+    "loginShell",
+];
+
+/// Stable pseudo-random sub-authorities derived from a string, to build SIDs.
+fn sid_sub_authorities(value: &str) -> [u32; 3] {
+    use sha2::{Digest, Sha256};
+    let hash = Sha256::digest(value.as_bytes());
+    let sub_authority =
+        |i: usize| u32::from_le_bytes(<[u8; 4]>::try_from(&hash[4 * i..4 * i + 4]).unwrap());
+    [sub_authority(0), sub_authority(1), sub_authority(2)]
+}
+
+/// The user data stored outside of the users table, only fetched when requested.
+#[derive(Default)]
+struct UserExtraData {
+    hosts: Vec<String>,
+    /// The values of the custom attributes, by attribute name.
+    attributes: HashMap<String, Vec<String>>,
+    /// The DNs of the groups of the user, sorted.
+    member_of: Vec<String>,
+    lock: Option<UserLock>,
+    avatar: Option<Vec<u8>>,
+}
+
+/// Which parts of the [`UserExtraData`] are needed for the requested attributes.
+#[derive(Clone, Copy)]
+struct UserExtraDataRequest {
+    hosts: bool,
+    attributes: bool,
+    member_of: bool,
+    lock: bool,
+    avatar: bool,
+}
+
+/// The values of a user attribute: a mapped one if configured, otherwise a built-in one.
+fn get_user_attribute(
+    user: &User,
+    extra: &UserExtraData,
+    attribute: &str,
+    dn: &str,
+    profile: &AttributeProfile,
+    schema: &LdapSchemaConfig,
+) -> Result<Vec<String>> {
+    match profile.user_mapping.get(attribute) {
+        Some(template) => template
+            .expand(|name| get_builtin_user_attribute(user, extra, name, dn, profile, schema)),
+        None => get_builtin_user_attribute(user, extra, attribute, dn, profile, schema),
+    }
+}
+
+fn get_builtin_user_attribute(
+    user: &User,
+    extra: &UserExtraData,
+    attribute: &str,
+    dn: &str,
+    profile: &AttributeProfile,
+    schema: &LdapSchemaConfig,
+) -> Result<Vec<String>> {
     match attribute.to_lowercase().as_str() {
-        "objectclass" => Ok(vec![
-            "inetOrgPerson".to_string(),
-            "posixAccount".to_string(),
-            "mailAccount".to_string(),
-            "person".to_string(),
-        ]),
+        "objectclass" => {
+            let mut classes = vec![
+                "inetOrgPerson".to_string(),
+                "posixAccount".to_string(),
+                "mailAccount".to_string(),
+                "person".to_string(),
+            ];
+            if profile.is_active_directory() {
+                classes.push("user".to_string());
+            }
+            classes.extend(schema.object_classes.iter().cloned());
+            Ok(classes)
+        }
         "dn" => Ok(vec![dn.to_string()]),
         "uid" => Ok(vec![user.user_id.clone()]),
         "mail" => Ok(vec![user.email.clone()]),
@@ -111,14 +472,52 @@ fn get_user_attribute(user: &User, attribute: &str, dn: &str) -> Result<Vec<Stri
         "sn" => Ok(vec![user.last_name.clone()]),
         "cn" | "displayname" => Ok(vec![user.display_name.clone()]),
         "createtimestamp" | "modifytimestamp" => Ok(vec![user.creation_date.to_rfc3339()]),
-        _ => bail!("Unsupported user attribute: {}", attribute),
+        "entryuuid" => Ok(vec![user.uuid.clone()]),
+        "host" => Ok(extra.hosts.clone()),
+        "memberof" => Ok(extra.member_of.clone()),
+        // As set by the password policy overlay of OpenLDAP: without lockout duration, the
+        // account is locked until an admin unlocks it.
+        "pwdaccountlockedtime" => Ok(extra
+            .lock
+            .iter()
+            .map(|lock| lock.locked_at.format("%Y%m%d%H%M%SZ").to_string())
+            .collect()),
+        // Binary, which the LDAP server can't send: it's in base64, as in the LDIF.
+        "jpegphoto" => Ok(extra.avatar.iter().map(base64::encode).collect()),
+        "uidnumber" => Ok(vec![user.uid_number.to_string()]),
+        "gidnumber" => Ok(vec![profile.user_gid_number(user).to_string()]),
+        "homedirectory" => profile.home_directory.expand(|name| {
+            if name.eq_ignore_ascii_case("homedirectory") {
+                bail!("The homeDirectory can't contain itself");
+            }
+            get_builtin_user_attribute(user, extra, name, dn, profile, schema)
+        }),
+        "loginshell" => Ok(vec![profile.posix.login_shell.clone()]),
+        "samaccountname" if profile.is_active_directory() => Ok(vec![user.user_id.clone()]),
+        "userprincipalname" if profile.is_active_directory() => {
+            Ok(vec![profile.user_principal_name(&user.user_id)])
+        }
+        "objectsid" if profile.is_active_directory() => Ok(vec![profile.user_sid(&user.user_id)]),
+        // Active Directory has it in binary, which the LDAP server can't send: it's in text.
+        "objectguid" if profile.is_active_directory() => Ok(vec![user.uuid.clone()]),
+        _ => match schema.get_attribute(attribute) {
+            Some(custom) => Ok(extra
+                .attributes
+                .get(&custom.name)
+                .cloned()
+                .unwrap_or_default()),
+            None => bail!("Unsupported user attribute: {}", attribute),
+        },
     }
 }
 
 fn make_ldap_search_user_result_entry(
     user: User,
+    extra: &UserExtraData,
     base_dn_str: &str,
     attributes: &[String],
+    profile: &AttributeProfile,
+    schema: &LdapSchemaConfig,
 ) -> Result<LdapSearchResultEntry> {
     let dn = format!("cn={},ou=people,{}", user.user_id, base_dn_str);
     Ok(LdapSearchResultEntry {
@@ -127,44 +526,172 @@ fn make_ldap_search_user_result_entry(
             .iter()
             .map(|a| {
                 Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
-                    vals: get_user_attribute(&user, a, &dn)?,
+                    atype: profile.attribute_type(a, Some(schema)),
+                    vals: get_user_attribute(&user, extra, a, &dn, profile, schema)?,
                 })
             })
             .collect::<Result<Vec<LdapPartialAttribute>>>()?,
     })
 }
 
-fn get_group_attribute(group: &Group, base_dn_str: &str, attribute: &str) -> Result<Vec<String>> {
+/// The group attributes read from the [`GroupMetadata`].
+const GROUP_METADATA_ATTRIBUTES: &[&str] = &[
+    "description",
+    "createTimestamp",
+    "modifyTimestamp",
+    "entryUUID",
+    "objectGUID",
+];
+
+/// The group data stored outside of the groups table, only fetched when requested.
+#[derive(Default)]
+struct GroupExtraData {
+    mail: GroupMail,
+    metadata: GroupMetadata,
+}
+
+/// The values of a group attribute: a mapped one if configured, otherwise a built-in one.
+fn get_group_attribute(
+    group: &Group,
+    extra: &GroupExtraData,
+    base_dn_str: &str,
+    attribute: &str,
+    profile: &AttributeProfile,
+) -> Result<Vec<String>> {
+    match profile.group_mapping.get(attribute) {
+        Some(template) => template
+            .expand(|name| get_builtin_group_attribute(group, extra, base_dn_str, name, profile)),
+        None => get_builtin_group_attribute(group, extra, base_dn_str, attribute, profile),
+    }
+}
+
+fn get_builtin_group_attribute(
+    group: &Group,
+    extra: &GroupExtraData,
+    base_dn_str: &str,
+    attribute: &str,
+    profile: &AttributeProfile,
+) -> Result<Vec<String>> {
     match attribute.to_lowercase().as_str() {
-        "objectclass" => Ok(vec!["groupOfUniqueNames".to_string()]),
+        "objectclass" => {
+            let mut classes = vec!["groupOfUniqueNames".to_string(), "posixGroup".to_string()];
+            if profile.is_active_directory() {
+                classes.push("group".to_string());
+            }
+            Ok(classes)
+        }
         "dn" => Ok(vec![format!(
             "cn={},ou=groups,{}",
             group.display_name, base_dn_str
         )]),
         "cn" | "uid" => Ok(vec![group.display_name.clone()]),
+        "mail" => Ok(extra.mail.addresses().cloned().collect()),
+        "description" => Ok(extra.metadata.description.iter().cloned().collect()),
+        "createtimestamp" | "modifytimestamp" => {
+            Ok(vec![extra.metadata.creation_date.to_rfc3339()])
+        }
+        "entryuuid" => Ok(vec![extra.metadata.uuid.clone()]),
         "member" | "uniquemember" => Ok(group
             .users
             .iter()
             .map(|u| format!("cn={},ou=people,{}", u, base_dn_str))
             .collect()),
+        // The posixGroup members, by user ID.
+        "memberuid" => Ok(group.users.clone()),
+        "gidnumber" => Ok(vec![profile.group_gid_number(group.id).to_string()]),
+        "samaccountname" if profile.is_active_directory() => Ok(vec![group.display_name.clone()]),
+        "objectsid" if profile.is_active_directory() => Ok(vec![profile.group_sid(group)]),
+        "objectguid" if profile.is_active_directory() => Ok(vec![extra.metadata.uuid.clone()]),
         _ => bail!("Unsupported group attribute: {}", attribute),
     }
 }
 
+fn convert_substring_filter(substrings: &LdapSubstringFilter) -> SubstringFilter {
+    SubstringFilter {
+        initial: substrings.initial.clone(),
+        any: substrings.any.clone(),
+        final_: substrings.final_.clone(),
+    }
+}
+
+/// The values requested with Active Directory's range retrieval, e.g. `member;range=0-1499`, for
+/// the clients that read the members of large groups a page at a time.
+#[derive(Debug, PartialEq, Eq)]
+struct ValueRange {
+    start: usize,
+    /// Inclusive, `None` for `*` (up to the last value).
+    end: Option<usize>,
+}
+
+impl ValueRange {
+    /// Keeps the values in the range. The attribute type in the result gives the range actually
+    /// returned, ending with `*` when it contains the last value.
+    fn apply(&self, attribute: &str, mut values: Vec<String>) -> LdapPartialAttribute {
+        let start = self.start.min(values.len());
+        let end = self
+            .end
+            .map_or(values.len(), |end| end.saturating_add(1))
+            .min(values.len());
+        let atype = if end == values.len() {
+            format!("{};range={}-*", attribute, start)
+        } else {
+            format!("{};range={}-{}", attribute, start, end - 1)
+        };
+        values.truncate(end);
+        values.drain(..start);
+        LdapPartialAttribute {
+            atype,
+            vals: values,
+        }
+    }
+}
+
+/// Splits the range option from an attribute: `member;range=0-1499` is the attribute `member`
+/// with the range 0 to 1499.
+fn split_range_option(attribute: &str) -> Result<(&str, Option<ValueRange>)> {
+    let (name, option) = match attribute.split_once(';') {
+        None => return Ok((attribute, None)),
+        Some(split) => split,
+    };
+    let parse_range = |range: &str| -> Option<ValueRange> {
+        let (start, end) = range.split_once('-')?;
+        let start = start.parse().ok()?;
+        let end = match end {
+            "*" => None,
+            end => Some(end.parse().ok().filter(|end| *end >= start)?),
+        };
+        Some(ValueRange { start, end })
+    };
+    match option
+        .to_lowercase()
+        .strip_prefix("range=")
+        .map(parse_range)
+    {
+        Some(Some(range)) => Ok((name, Some(range))),
+        _ => bail!("Unsupported attribute option: {}", attribute),
+    }
+}
+
 fn make_ldap_search_group_result_entry(
     group: Group,
+    extra: &GroupExtraData,
     base_dn_str: &str,
     attributes: &[String],
+    profile: &AttributeProfile,
 ) -> Result<LdapSearchResultEntry> {
     Ok(LdapSearchResultEntry {
         dn: format!("cn={},ou=groups,{}", group.display_name, base_dn_str),
         attributes: attributes
             .iter()
             .map(|a| {
-                Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
-                    vals: get_group_attribute(&group, base_dn_str, a)?,
+                let (name, range) = split_range_option(a)?;
+                let vals = get_group_attribute(&group, extra, base_dn_str, name, profile)?;
+                Ok(match range {
+                    Some(range) => range.apply(&profile.attribute_type(name, None), vals),
+                    None => LdapPartialAttribute {
+                        atype: profile.attribute_type(a, None),
+                        vals,
+                    },
                 })
             })
             .collect::<Result<Vec<LdapPartialAttribute>>>()?,
@@ -184,6 +711,11 @@ fn is_subtree(subtree: &[(String, String)], base_tree: &[(String, String)]) -> b
     true
 }
 
+/// An attribute of a filter that isn't one of the user fields, nor mapped to one.
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown field: {0}")]
+struct UnknownField(String);
+
 fn map_field(field: &str) -> Result<String> {
     Ok(if field == "uid" {
         "user_id".to_string()
@@ -195,16 +727,58 @@ fn map_field(field: &str) -> Result<String> {
         "first_name".to_string()
     } else if field == "sn" {
         "last_name".to_string()
-    } else if field == "avatar" {
+    } else if field == "avatar" || field.to_lowercase() == "jpegphoto" {
         "avatar".to_string()
     } else if field.to_lowercase() == "creationdate"
         || field.to_lowercase() == "createtimestamp"
         || field.to_lowercase() == "modifytimestamp"
     {
         "creation_date".to_string()
+    } else if field.to_lowercase() == "entryuuid" {
+        "uuid".to_string()
+    } else if field.to_lowercase() == "uidnumber" {
+        "uid_number".to_string()
     } else {
-        bail!("Unknown field: {}", field);
+        return Err(UnknownField(field.to_string()).into());
+    })
+}
+
+/// Appends the final success to the search results, unless they already end with an error.
+fn end_with_search_success(results: LocalBoxStream<'_, LdapOp>) -> LocalBoxStream<'_, LdapOp> {
+    // The state is the remaining results, and whether the success is still needed.
+    stream::unfold(Some((results, true)), |state| async move {
+        let (mut results, needs_success) = state?;
+        match results.next().await {
+            Some(op) => {
+                let needs_success = matches!(op, LdapOp::SearchResultEntry(_));
+                Some((op, Some((results, needs_success))))
+            }
+            None if needs_success => Some((make_search_success(), None)),
+            None => None,
+        }
     })
+    .boxed_local()
+}
+
+/// The result code for an error of the backend: timeouts are reported as such, so that the clients
+/// can tell them apart from other failures and retry.
+/// The result code of a user filter that can't be converted: the unknown attributes are reported
+/// as such, rather than as an unsupported filter.
+fn get_filter_error_code(error: &anyhow::Error) -> LdapResultCode {
+    if error.is::<UnknownField>() {
+        LdapResultCode::UndefinedAttributeType
+    } else {
+        LdapResultCode::UnwillingToPerform
+    }
+}
+
+fn get_backend_error_code(error: &DomainError) -> LdapResultCode {
+    match error {
+        DomainError::TimeoutError(_) => LdapResultCode::TimeLimitExceeded,
+        DomainError::PermissionDenied(_) => LdapResultCode::InsufficentAccessRights,
+        DomainError::Conflict(_) => LdapResultCode::EntryAlreadyExists,
+        _ => LdapResultCode::Other,
+    }
 }
 
 fn make_search_success() -> LdapOp {
@@ -233,7 +807,13 @@ fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn root_dse_response(base_dn: &str) -> LdapOp {
+/// The root DSE (RFC 4512 section 5.1), describing the server to the clients probing it before
+/// binding.
+fn root_dse_response(base_dn: &str, start_tls: bool) -> LdapOp {
+    let mut extensions = vec![PASSWORD_MODIFY_OID.to_string()];
+    if start_tls {
+        extensions.push(START_TLS_OID.to_string());
+    }
     LdapOp::SearchResultEntry(LdapSearchResultEntry {
         dn: "".to_string(),
         attributes: vec![
@@ -247,7 +827,7 @@ fn root_dse_response(base_dn: &str) -> LdapOp {
             },
             LdapPartialAttribute {
                 atype: "vendorVersion".to_string(),
-                vals: vec!["lldap_0.2.0".to_string()],
+                vals: vec![concat!("lldap_", env!("CARGO_PKG_VERSION")).to_string()],
             },
             LdapPartialAttribute {
                 atype: "supportedLDAPVersion".to_string(),
@@ -255,158 +835,817 @@ fn root_dse_response(base_dn: &str) -> LdapOp {
             },
             LdapPartialAttribute {
                 atype: "supportedExtension".to_string(),
-                vals: vec!["1.3.6.1.4.1.4203.1.11.1".to_string()],
+                vals: extensions,
+            },
+            LdapPartialAttribute {
+                atype: "supportedControl".to_string(),
+                vals: vec![PAGED_RESULTS_OID.to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "namingContexts".to_string(),
+                vals: vec![base_dn.to_string()],
             },
             LdapPartialAttribute {
-                atype: "defaultnamingcontext".to_string(),
+                atype: "defaultNamingContext".to_string(),
                 vals: vec![base_dn.to_string()],
             },
+            LdapPartialAttribute {
+                atype: "subschemaSubentry".to_string(),
+                vals: vec![SUBSCHEMA_DN.to_string()],
+            },
+        ],
+    })
+}
+
+/// The Password Modify extended operation (RFC 3062).
+const PASSWORD_MODIFY_OID: &str = "1.3.6.1.4.1.4203.1.11.1";
+
+/// The StartTLS extended operation (RFC 4511).
+const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+
+/// Where the connection is in the StartTLS upgrade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StartTlsState {
+    /// There is no certificate to upgrade the connection with.
+    Unavailable,
+    Available,
+    /// The client asked for the upgrade: the server starts the TLS handshake once the response
+    /// is sent.
+    Requested,
+    /// The connection is encrypted.
+    Established,
+}
+
+/// How many users of a search are converted to entries at once, fetching their groups together.
+const USER_ENTRY_BATCH_SIZE: usize = 100;
+
+/// The Simple Paged Results control (RFC 2696).
+const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
+
+/// Where the next page of a paged search starts: the entries of the users are returned first,
+/// then the ones of the groups. It is sent to the client as the cookie of the control, e.g.
+/// "u200" for the users after the first 200 ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageCursor {
+    Users(usize),
+    Groups(usize),
+}
+
+impl PageCursor {
+    fn to_cookie(self) -> Vec<u8> {
+        match self {
+            PageCursor::Users(offset) => format!("u{}", offset),
+            PageCursor::Groups(offset) => format!("g{}", offset),
+        }
+        .into_bytes()
+    }
+
+    fn from_cookie(cookie: &[u8]) -> Option<Self> {
+        let cookie = std::str::from_utf8(cookie).ok()?;
+        let offset = |rest: &str| rest.parse::<usize>().ok();
+        if let Some(rest) = cookie.strip_prefix('u') {
+            Some(PageCursor::Users(offset(rest)?))
+        } else {
+            Some(PageCursor::Groups(offset(cookie.strip_prefix('g')?)?))
+        }
+    }
+}
+
+/// The `dn` of the sessions that haven't bound, or have bound anonymously.
+const ANONYMOUS_DN: &str = "Unauthenticated";
+
+/// The DN of the subschema entry, advertised in the root DSE.
+const SUBSCHEMA_DN: &str = "cn=Subschema";
+
+/// The standard attributes of the user and group entries, as defined in RFC 4519, 4524, 2798,
+/// 4530 and 2307.
+const STANDARD_ATTRIBUTE_TYPES: &[&str] = &[
+    "( 2.5.4.0 NAME 'objectClass' EQUALITY objectIdentifierMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.38 )",
+    "( 2.5.4.3 NAME ( 'cn' 'commonName' ) EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 2.5.4.4 NAME ( 'sn' 'surname' ) EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 2.5.4.42 NAME 'givenName' EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 2.16.840.1.113730.3.1.241 NAME 'displayName' EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 SINGLE-VALUE )",
+    "( 0.9.2342.19200300.100.1.1 NAME ( 'uid' 'userid' ) EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 0.9.2342.19200300.100.1.3 NAME ( 'mail' 'rfc822Mailbox' ) EQUALITY caseIgnoreIA5Match \
+     SUBSTR caseIgnoreIA5SubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+    "( 2.5.4.13 NAME 'description' EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 0.9.2342.19200300.100.1.9 NAME 'host' EQUALITY caseIgnoreMatch \
+     SUBSTR caseIgnoreSubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+    "( 2.5.4.31 NAME 'member' EQUALITY distinguishedNameMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 )",
+    "( 2.5.4.50 NAME 'uniqueMember' EQUALITY uniqueMemberMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.34 )",
+    "( 1.2.840.113556.1.2.102 NAME 'memberOf' EQUALITY distinguishedNameMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.12 NO-USER-MODIFICATION USAGE dSAOperation )",
+    "( 1.3.6.1.4.1.42.2.27.8.1.17 NAME 'pwdAccountLockedTime' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 1.3.6.1.1.16.4 NAME 'entryUUID' EQUALITY UUIDMatch ORDERING UUIDOrderingMatch \
+     SYNTAX 1.3.6.1.1.16.1 SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 2.5.18.1 NAME 'createTimestamp' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 2.5.18.2 NAME 'modifyTimestamp' EQUALITY generalizedTimeMatch \
+     ORDERING generalizedTimeOrderingMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.24 \
+     SINGLE-VALUE NO-USER-MODIFICATION USAGE directoryOperation )",
+    "( 1.3.6.1.1.1.1.0 NAME 'uidNumber' EQUALITY integerMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.1 NAME 'gidNumber' EQUALITY integerMatch \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.27 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.3 NAME 'homeDirectory' EQUALITY caseExactIA5Match \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.4 NAME 'loginShell' EQUALITY caseExactIA5Match \
+     SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+    "( 1.3.6.1.1.1.1.12 NAME 'memberUid' EQUALITY caseExactIA5Match \
+     SUBSTR caseExactIA5SubstringsMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 )",
+];
+
+/// The object classes of the user and group entries, with only the attributes that the entries
+/// have: e.g. no `userPassword` or `gecos` for `posixAccount`.
+const STANDARD_OBJECT_CLASSES: &[&str] = &[
+    "( 2.5.6.0 NAME 'top' ABSTRACT MUST objectClass )",
+    "( 2.5.6.6 NAME 'person' SUP top STRUCTURAL MUST ( sn $ cn ) )",
+    "( 2.5.6.7 NAME 'organizationalPerson' SUP person STRUCTURAL )",
+    "( 2.16.840.1.113730.3.2.2 NAME 'inetOrgPerson' SUP organizationalPerson STRUCTURAL \
+     MAY ( displayName $ givenName $ mail $ uid ) )",
+    "( 1.3.6.1.1.1.2.0 NAME 'posixAccount' SUP top AUXILIARY \
+     MUST ( cn $ uid $ uidNumber $ gidNumber $ homeDirectory ) MAY ( loginShell $ host ) )",
+    "( mailAccount-oid NAME 'mailAccount' SUP top AUXILIARY MAY mail )",
+    "( 2.5.6.17 NAME 'groupOfUniqueNames' SUP top STRUCTURAL MUST ( uniqueMember $ cn ) \
+     MAY ( description $ member $ mail ) )",
+    "( 1.3.6.1.1.1.2.2 NAME 'posixGroup' SUP top AUXILIARY MUST ( cn $ gidNumber ) \
+     MAY memberUid )",
+];
+
+/// The subschema entry (RFC 4512), describing the standard object classes and attributes of the
+/// entries, followed by the custom ones.
+fn subschema_response(schema: &LdapSchemaConfig) -> LdapOp {
+    // Directory String syntax.
+    const STRING_SYNTAX: &str = "1.3.6.1.4.1.1466.115.121.1.15";
+    let custom_attribute_types = schema.attributes.iter().map(|attribute| {
+        format!(
+            "( {} NAME '{}' SYNTAX {}{} )",
+            attribute.oid(),
+            attribute.name,
+            STRING_SYNTAX,
+            if attribute.single_value {
+                " SINGLE-VALUE"
+            } else {
+                ""
+            }
+        )
+    });
+    let may = match schema.attributes.len() {
+        0 => String::new(),
+        _ => format!(
+            " MAY ( {} )",
+            schema
+                .attributes
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+                .join(" $ ")
+        ),
+    };
+    let custom_object_classes = schema.object_classes.iter().map(|class| {
+        format!(
+            "( {}-oid NAME '{}' SUP top AUXILIARY{} )",
+            class, class, may
+        )
+    });
+    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+        dn: SUBSCHEMA_DN.to_string(),
+        attributes: vec![
+            LdapPartialAttribute {
+                atype: "objectClass".to_string(),
+                vals: vec!["top".to_string(), "subschema".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "cn".to_string(),
+                vals: vec!["Subschema".to_string()],
+            },
+            LdapPartialAttribute {
+                atype: "attributeTypes".to_string(),
+                vals: STANDARD_ATTRIBUTE_TYPES
+                    .iter()
+                    .map(|a| a.to_string())
+                    .chain(custom_attribute_types)
+                    .collect(),
+            },
+            LdapPartialAttribute {
+                atype: "objectClasses".to_string(),
+                vals: STANDARD_OBJECT_CLASSES
+                    .iter()
+                    .map(|c| c.to_string())
+                    .chain(custom_object_classes)
+                    .collect(),
+            },
         ],
     })
 }
 
 pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler> {
     dn: String,
+    /// The permissions of the bound user, or of the anonymous session if it can read anything.
+    permissions: Option<ValidationResults>,
+    anonymous_access: LdapAnonymousAccess,
     backend_handler: Backend,
     pub base_dn: Vec<(String, String)>,
     base_dn_str: String,
     ldap_user_dn: String,
+    attribute_profile: AttributeProfile,
+    user_order: UserOrder,
+    /// Extra attribute names for the user fields, lowercase.
+    attribute_aliases: HashMap<String, String>,
+    schema: LdapSchemaConfig,
+    monitor: LdapMonitor,
+    metrics: Metrics,
+    bind_throttle: BindThrottle,
+    password_policy: PasswordPolicyConfig,
+    /// The address of the client, recorded with the failed binds.
+    source_ip: Option<std::net::IpAddr>,
+    /// Whether the write operations are rejected.
+    read_only: bool,
+    start_tls: StartTlsState,
 }
 
 impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend> {
     pub fn new(backend_handler: Backend, ldap_base_dn: String, ldap_user_dn: String) -> Self {
+        let base_dn = parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
+            panic!(
+                "Invalid value for ldap_base_dn in configuration: {}",
+                ldap_base_dn
+            )
+        });
         Self {
-            dn: "Unauthenticated".to_string(),
+            dn: ANONYMOUS_DN.to_string(),
+            permissions: None,
+            anonymous_access: LdapAnonymousAccess::RootDse,
             backend_handler,
-            base_dn: parse_distinguished_name(&ldap_base_dn).unwrap_or_else(|_| {
-                panic!(
-                    "Invalid value for ldap_base_dn in configuration: {}",
-                    ldap_base_dn
-                )
-            }),
+            attribute_profile: AttributeProfile::new(
+                LdapAttributeProfile::Standard,
+                &base_dn,
+                &ldap_base_dn,
+            ),
+            base_dn,
+            user_order: UserOrder::default(),
+            attribute_aliases: HashMap::new(),
+            schema: LdapSchemaConfig::default(),
+            monitor: LdapMonitor::default(),
+            metrics: Metrics::default(),
+            bind_throttle: BindThrottle::default(),
+            password_policy: PasswordPolicyConfig::default(),
+            source_ip: None,
+            read_only: false,
+            start_tls: StartTlsState::Unavailable,
             ldap_user_dn: format!("cn={},ou=people,{}", ldap_user_dn, &ldap_base_dn),
             base_dn_str: ldap_base_dn,
         }
     }
 
-    pub async fn do_bind(&mut self, request: &LdapBindRequest) -> (LdapResultCode, String) {
-        debug!(r#"Received bind request for "{}""#, &request.dn);
-        let user_id = match get_user_id_from_distinguished_name(
-            &request.dn,
-            &self.base_dn,
-            &self.base_dn_str,
-        ) {
-            Ok(s) => s,
-            Err(e) => return (LdapResultCode::NamingViolation, e.to_string()),
-        };
-        let LdapBindCred::Simple(password) = &request.cred;
-        match self
-            .backend_handler
-            .bind(BindRequest {
-                name: user_id,
-                password: password.clone(),
-            })
-            .await
-        {
-            Ok(()) => {
-                self.dn = request.dn.clone();
-                (LdapResultCode::Success, "".to_string())
-            }
-            Err(_) => (LdapResultCode::InvalidCredentials, "".to_string()),
-        }
+    pub fn with_attribute_profile(mut self, profile: LdapAttributeProfile) -> Self {
+        self.attribute_profile.kind = profile;
+        self
     }
 
-    async fn change_password(&mut self, user: &str, password: &str) -> Result<()> {
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration(password, &mut rng)?;
-        let req = registration::ClientRegistrationStartRequest {
-            username: user.to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let registration_start_response = self.backend_handler.registration_start(req).await?;
-        let registration_finish = opaque::client::registration::finish_registration(
-            registration_start_request.state,
-            registration_start_response.registration_response,
-            &mut rng,
-        )?;
-        let req = registration::ClientRegistrationFinishRequest {
-            server_data: registration_start_response.server_data,
-            registration_upload: registration_finish.message,
-        };
-        self.backend_handler.registration_finish(req).await?;
-        Ok(())
+    pub fn with_attribute_casing(mut self, casing: LdapAttributeCasing) -> Self {
+        self.attribute_profile.casing = casing;
+        self
     }
 
-    async fn do_password_modification(
-        &mut self,
-        request: &LdapPasswordModifyRequest,
-    ) -> Vec<LdapOp> {
-        match (&request.user_identity, &request.new_password) {
-            (Some(user), Some(password)) => {
-                match get_user_id_from_distinguished_name(user, &self.base_dn, &self.base_dn_str) {
-                    Ok(uid) => {
-                        if let Err(e) = self.change_password(&uid, password).await {
-                            vec![make_extended_response(
-                                LdapResultCode::Other,
-                                format!("Error while changing the password: {:#?}", e),
-                            )]
-                        } else {
-                            vec![make_extended_response(
-                                LdapResultCode::Success,
-                                "".to_string(),
-                            )]
-                        }
-                    }
-                    Err(e) => vec![make_extended_response(
-                        LdapResultCode::InvalidDNSyntax,
-                        format!("Invalid username: {:#?}", e),
-                    )],
-                }
-            }
-            _ => vec![make_extended_response(
-                LdapResultCode::ConstraintViolation,
-                "Missing either user_id or password".to_string(),
-            )],
+    /// Sets what the session can do before binding, or after an anonymous bind.
+    pub fn with_anonymous_access(mut self, access: LdapAnonymousAccess) -> Self {
+        self.anonymous_access = access;
+        if self.dn == ANONYMOUS_DN {
+            self.permissions = self.get_anonymous_permissions();
         }
+        self
     }
 
-    async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
-        match LdapPasswordModifyRequest::try_from(request) {
-            Ok(password_request) => self.do_password_modification(&password_request).await,
-            Err(_) => vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                format!("Unsupported extended operation: {}", &request.name),
-            )],
-        }
+    pub fn with_user_order(mut self, order: UserOrder) -> Self {
+        self.user_order = order;
+        self
     }
 
-    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
-        if self.dn != self.ldap_user_dn {
-            return vec![make_search_error(
+    /// Accepts these attribute names in the user filters, each standing for the given user field
+    /// (see [`USER_FILTER_FIELDS`](crate::domain::handler::USER_FILTER_FIELDS)).
+    pub fn with_attribute_aliases(mut self, aliases: &HashMap<String, String>) -> Self {
+        self.attribute_aliases = aliases
+            .iter()
+            .map(|(alias, field)| (alias.to_lowercase(), field.clone()))
+            .collect();
+        self
+    }
+
+    /// Adds the mapped attributes to the user and group entries, or replaces the built-in ones.
+    pub fn with_attribute_mapping(mut self, mapping: &LdapAttributeMappingConfig) -> Self {
+        self.attribute_profile.user_mapping = AttributeMapping::new(&mapping.user);
+        self.attribute_profile.group_mapping = AttributeMapping::new(&mapping.group);
+        self
+    }
+
+    /// Sets the `gidNumber`, `homeDirectory` and `loginShell` of the entries.
+    pub fn with_posix(mut self, posix: PosixConfig) -> Self {
+        match AttributeTemplate::parse(&posix.home_directory) {
+            Ok(template) => self.attribute_profile.home_directory = template,
+            Err(e) => warn!("Ignoring the posix home_directory: {:#}", e),
+        }
+        self.attribute_profile.posix = posix;
+        self
+    }
+
+    /// Serves the custom object classes and attributes, and advertises them in the subschema.
+    pub fn with_schema(mut self, schema: LdapSchemaConfig) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Shares the statistics with the other connections, instead of keeping them private.
+    pub fn with_monitor(mut self, monitor: LdapMonitor) -> Self {
+        self.monitor = monitor;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Shares the failed binds with the other connections and the web logins, instead of
+    /// throttling them per connection.
+    pub fn with_bind_throttle(mut self, bind_throttle: BindThrottle) -> Self {
+        self.bind_throttle = bind_throttle;
+        self
+    }
+
+    pub fn with_password_policy(mut self, policy: PasswordPolicyConfig) -> Self {
+        self.password_policy = policy;
+        self
+    }
+
+    pub fn with_source_ip(mut self, source_ip: Option<std::net::IpAddr>) -> Self {
+        self.source_ip = source_ip;
+        self
+    }
+
+    /// Rejects the write operations (e.g. password changes) with `unwillingToPerform`, whoever
+    /// is bound.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Accepts the StartTLS extended operation: the server can upgrade the connection.
+    pub fn with_start_tls(mut self, available: bool) -> Self {
+        self.start_tls = if available {
+            StartTlsState::Available
+        } else {
+            StartTlsState::Unavailable
+        };
+        self
+    }
+
+    /// Whether the client asked for StartTLS in the last operation, in which case the server must
+    /// start the TLS handshake after sending the response. The connection is then considered
+    /// encrypted.
+    pub fn take_start_tls_request(&mut self) -> bool {
+        if self.start_tls == StartTlsState::Requested {
+            self.start_tls = StartTlsState::Established;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The bound user and the address of the client, for the audit log.
+    pub fn audit_context(&self) -> AuditContext {
+        AuditContext {
+            actor: get_user_id_from_distinguished_name(&self.dn, &self.base_dn, &self.base_dn_str)
+                .ok(),
+            source_ip: self.source_ip,
+        }
+    }
+
+    async fn record_bind_failure(&mut self, user_id: Option<String>, reason: AuthFailureReason) {
+        self.monitor.record_bind_failure();
+        self.bind_throttle
+            .record_failure(self.source_ip, user_id.as_deref())
+            .await;
+        let failure = AuthFailure {
+            time: chrono::Utc::now(),
+            user_id,
+            source_ip: self.source_ip,
+            protocol: AuthProtocol::Ldap,
+            reason,
+        };
+        warn!("Failed authentication: {}", failure);
+        if let Err(e) = self.backend_handler.record_auth_failure(failure).await {
+            warn!("Could not record the failed bind: {}", e);
+        }
+    }
+
+    /// The configured admin is always an admin, the other users get the permissions of their
+    /// groups.
+    async fn get_permission(&self, dn: &str, user_id: &str) -> Permission {
+        if dn == self.ldap_user_dn {
+            return Permission::Admin;
+        }
+        match self.backend_handler.get_user_groups(user_id).await {
+            Ok(groups) => Permission::from_groups(groups.iter().map(|group| group.1.as_str())),
+            Err(e) => {
+                warn!("Could not get the groups of {}: {}", user_id, e);
+                Permission::Regular
+            }
+        }
+    }
+
+    fn get_anonymous_permissions(&self) -> Option<ValidationResults> {
+        match self.anonymous_access {
+            LdapAnonymousAccess::ReadOnly => Some(ValidationResults {
+                user: String::new(),
+                permission: Permission::Readonly,
+                impersonator: None,
+            }),
+            LdapAnonymousAccess::Deny | LdapAnonymousAccess::RootDse => None,
+        }
+    }
+
+    /// Ends the authentication of the session, as after an unbind.
+    fn reset_to_anonymous(&mut self) {
+        self.dn = ANONYMOUS_DN.to_string();
+        self.permissions = self.get_anonymous_permissions();
+    }
+
+    /// The error message of the operations that the session isn't allowed to do.
+    fn get_not_allowed_message(&self) -> String {
+        if self.dn == ANONYMOUS_DN {
+            "Anonymous sessions can only read the root DSE and the subschema, bind first"
+                .to_string()
+        } else {
+            format!(
+                r#"Current user `{}` is not allowed to query LDAP"#,
+                &self.dn
+            )
+        }
+    }
+
+    /// A bind with an empty DN and password (RFC 4513 section 5.1.1).
+    fn do_anonymous_bind(&mut self) -> (LdapResultCode, String) {
+        if self.anonymous_access == LdapAnonymousAccess::Deny {
+            return (
+                LdapResultCode::InappropriateAuthentication,
+                "Anonymous binds are not allowed".to_string(),
+            );
+        }
+        self.reset_to_anonymous();
+        (LdapResultCode::Success, "".to_string())
+    }
+
+    fn can_read_all(&self) -> bool {
+        self.permissions
+            .as_ref()
+            .map_or(false, ValidationResults::can_read_all)
+    }
+
+    pub async fn do_bind(&mut self, request: &LdapBindRequest) -> (LdapResultCode, String) {
+        debug!(r#"Received bind request for "{}""#, &request.dn);
+        let LdapBindCred::Simple(password) = &request.cred;
+        match (request.dn.is_empty(), password.is_empty()) {
+            (true, true) => return self.do_anonymous_bind(),
+            // Unauthenticated binds, with a DN but no password (RFC 4513 section 5.1.2).
+            (false, true) => {
+                return (
+                    LdapResultCode::UnwillingToPerform,
+                    "Unauthenticated binds, with a DN but no password, are not allowed".to_string(),
+                )
+            }
+            (true, false) => {
+                return (
+                    LdapResultCode::InvalidCredentials,
+                    "A password needs the DN of a user".to_string(),
+                )
+            }
+            (false, false) => (),
+        }
+        let user_id = match get_user_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+        ) {
+            Ok(s) => fold_case(&s),
+            Err(e) => {
+                self.record_bind_failure(None, AuthFailureReason::InvalidDn)
+                    .await;
+                return (LdapResultCode::NamingViolation, e.to_string());
+            }
+        };
+        // The password isn't even checked, so that guessing it is as slow as the throttling.
+        if let Some(message) = self.get_throttled_message(&user_id) {
+            warn!("Throttled bind of {} from {:?}", user_id, self.source_ip);
+            return (LdapResultCode::UnwillingToPerform, message);
+        }
+        match self
+            .backend_handler
+            .bind(BindRequest {
+                name: user_id.clone(),
+                password: password.clone(),
+            })
+            .await
+        {
+            Ok(()) => {
+                self.bind_throttle.record_success(&user_id).await;
+                self.set_bound_user(request.dn.clone(), user_id).await;
+                (LdapResultCode::Success, "".to_string())
+            }
+            Err(DomainError::TimeoutError(_)) => (
+                LdapResultCode::Unavailable,
+                "The server is too busy, try again later".to_string(),
+            ),
+            Err(_) => {
+                let reason = match self.backend_handler.get_user_lock(&user_id).await {
+                    Ok(Some(_)) => AuthFailureReason::UserLocked,
+                    _ => AuthFailureReason::InvalidCredentials,
+                };
+                self.record_bind_failure(Some(user_id), reason).await;
+                (LdapResultCode::InvalidCredentials, "".to_string())
+            }
+        }
+    }
+
+    /// The error message if the address of the client or the user failed too many times lately.
+    fn get_throttled_message(&self, user_id: &str) -> Option<String> {
+        self.bind_throttle
+            .check(self.source_ip, Some(user_id))
+            .map(|blocked_until| {
+                format!(
+                    "Too many failed attempts, try again after {}",
+                    blocked_until.to_rfc3339()
+                )
+            })
+    }
+
+    async fn set_bound_user(&mut self, dn: String, user_id: String) {
+        self.permissions = Some(ValidationResults {
+            permission: self.get_permission(&dn, &user_id).await,
+            user: user_id,
+            impersonator: None,
+        });
+        self.dn = dn;
+    }
+
+    async fn can_change_password(&self, user_id: &str) -> bool {
+        let permissions = match &self.permissions {
+            Some(permissions) => permissions,
+            None => return false,
+        };
+        can_change_password(&self.backend_handler, permissions, user_id)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Could not check the permissions of {}: {}",
+                    permissions.user, e
+                );
+                false
+            })
+    }
+
+    async fn change_password(&mut self, user: &str, password: &str) -> Result<()> {
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration(password, &mut rng)?;
+        let req = registration::ClientRegistrationStartRequest {
+            username: user.to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let registration_start_response = self.backend_handler.registration_start(req).await?;
+        let registration_finish = opaque::client::registration::finish_registration(
+            registration_start_request.state,
+            registration_start_response.registration_response,
+            &mut rng,
+        )?;
+        let req = registration::ClientRegistrationFinishRequest {
+            server_data: registration_start_response.server_data,
+            registration_upload: registration_finish.message,
+        };
+        self.backend_handler.registration_finish(req).await?;
+        Ok(())
+    }
+
+    /// Changes the password of the user, after checking the old one if given, for the Password
+    /// Modify extended operation and the Modify operation on `userPassword`.
+    async fn modify_password(
+        &mut self,
+        user_id: &str,
+        old_password: Option<&str>,
+        new_password: &str,
+    ) -> (LdapResultCode, String) {
+        if !self.can_change_password(user_id).await {
+            return (
                 LdapResultCode::InsufficentAccessRights,
                 format!(
-                    r#"Current user `{}` is not allowed to query LDAP, expected {}"#,
-                    &self.dn, &self.ldap_user_dn
+                    r#"Current user `{}` is not allowed to change the password of {}"#,
+                    &self.dn, user_id
                 ),
-            )];
+            );
+        }
+        if let Some(old_password) = old_password {
+            if let Some(message) = self.get_throttled_message(user_id) {
+                return (LdapResultCode::UnwillingToPerform, message);
+            }
+            let request = BindRequest {
+                name: user_id.to_string(),
+                password: old_password.to_string(),
+            };
+            if self.backend_handler.bind(request).await.is_err() {
+                self.record_bind_failure(
+                    Some(user_id.to_string()),
+                    AuthFailureReason::InvalidCredentials,
+                )
+                .await;
+                return (
+                    LdapResultCode::InvalidCredentials,
+                    "Wrong old password".to_string(),
+                );
+            }
+        }
+        if let Err(e) = check_password(&self.password_policy, new_password, &[user_id]) {
+            return (LdapResultCode::ConstraintViolation, e.to_string());
+        }
+        match self
+            .backend_handler
+            .is_password_reused(user_id, new_password)
+            .await
+        {
+            Ok(false) => (),
+            Ok(true) => {
+                return (
+                    LdapResultCode::ConstraintViolation,
+                    "The password was used recently, choose another one".to_string(),
+                )
+            }
+            Err(e) => {
+                return (
+                    LdapResultCode::Other,
+                    format!("Error while checking the password history: {:#?}", e),
+                )
+            }
+        }
+        match self.change_password(user_id, new_password).await {
+            Ok(()) => (LdapResultCode::Success, "".to_string()),
+            Err(e) => (
+                LdapResultCode::Other,
+                format!("Error while changing the password: {:#?}", e),
+            ),
+        }
+    }
+
+    /// The Password Modify extended operation (RFC 3062). Without a user identity, changes the
+    /// password of the bound user. Generating the new password isn't supported.
+    async fn do_password_modification(
+        &mut self,
+        request: &LdapPasswordModifyRequest,
+    ) -> Vec<LdapOp> {
+        let new_password = match &request.new_password {
+            Some(password) => password,
+            None => {
+                return vec![make_extended_response(
+                    LdapResultCode::ConstraintViolation,
+                    "Missing the new password: generating one is not supported".to_string(),
+                )]
+            }
+        };
+        let user_id = match (&request.user_identity, &self.permissions) {
+            (Some(user), _) => {
+                match get_user_id_from_distinguished_name(user, &self.base_dn, &self.base_dn_str) {
+                    Ok(user_id) => user_id,
+                    Err(e) => {
+                        return vec![make_extended_response(
+                            LdapResultCode::InvalidDNSyntax,
+                            format!("Invalid username: {:#?}", e),
+                        )]
+                    }
+                }
+            }
+            (None, Some(permissions)) if self.dn != ANONYMOUS_DN => permissions.user.clone(),
+            (None, _) => {
+                return vec![make_extended_response(
+                    LdapResultCode::UnwillingToPerform,
+                    "Missing the user: bind first, or give the user identity".to_string(),
+                )]
+            }
+        };
+        let (code, message) = self
+            .modify_password(&user_id, request.old_password.as_deref(), new_password)
+            .await;
+        vec![make_extended_response(code, message)]
+    }
+
+    fn do_start_tls(&mut self) -> Vec<LdapOp> {
+        match self.start_tls {
+            StartTlsState::Unavailable => vec![make_extended_response(
+                LdapResultCode::Unavailable,
+                "StartTLS is not configured on this server".to_string(),
+            )],
+            StartTlsState::Available => {
+                self.start_tls = StartTlsState::Requested;
+                vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    res: LdapResult {
+                        code: LdapResultCode::Success,
+                        matcheddn: "".to_string(),
+                        message: "".to_string(),
+                        referral: vec![],
+                    },
+                    name: Some(START_TLS_OID.to_string()),
+                    value: None,
+                })]
+            }
+            StartTlsState::Requested | StartTlsState::Established => {
+                vec![make_extended_response(
+                    LdapResultCode::OperationsError,
+                    "TLS is already established".to_string(),
+                )]
+            }
+        }
+    }
+
+    async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
+        if request.name == START_TLS_OID {
+            return self.do_start_tls();
+        }
+        match LdapPasswordModifyRequest::try_from(request) {
+            Ok(_) if self.read_only => vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "This LDAP listener is read-only".to_string(),
+            )],
+            Ok(password_request) => self.do_password_modification(&password_request).await,
+            Err(_) => vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported extended operation: {}", &request.name),
+            )],
         }
+    }
+
+    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        self.do_search_stream(request.clone()).collect().await
+    }
+
+    /// Produces the search results as they are computed: the user entries are produced while the
+    /// users are read from the database, instead of after reading all of them.
+    fn do_search_stream(&self, request: LdapSearchRequest) -> LocalBoxStream<'_, LdapOp> {
+        let results = |ops: Vec<LdapOp>| stream::iter(ops).boxed_local();
+        // The root DSE and the subschema are read by the clients before binding.
+        let is_object_class_filter = matches!(
+            &request.filter,
+            LdapFilter::Present(attribute) if attribute.eq_ignore_ascii_case("objectClass")
+        );
         if request.base.is_empty()
             && request.scope == LdapSearchScope::Base
-            && request.filter == LdapFilter::Present("objectClass".to_string())
+            && is_object_class_filter
         {
             debug!("Received rootDSE request");
-            return vec![root_dse_response(&self.base_dn_str), make_search_success()];
+            return results(vec![
+                root_dse_response(
+                    &self.base_dn_str,
+                    self.start_tls == StartTlsState::Available,
+                ),
+                make_search_success(),
+            ]);
+        }
+        if request.base.eq_ignore_ascii_case(SUBSCHEMA_DN) {
+            debug!("Received subschema request");
+            return results(vec![
+                subschema_response(&self.schema),
+                make_search_success(),
+            ]);
+        }
+        if !self.can_read_all() {
+            return results(vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                self.get_not_allowed_message(),
+            )]);
+        }
+        if is_monitor_dn(&request.base) {
+            debug!("Received monitor request: {:?}", &request);
+            let mut ops = self.monitor.search(&request);
+            ops.push(make_search_success());
+            return results(ops);
         }
         debug!("Received search request: {:?}", &request);
         let dn_parts = match parse_distinguished_name(&request.base) {
             Ok(dn) => dn,
             Err(_) => {
-                return vec![make_search_error(
+                return results(vec![make_search_error(
                     LdapResultCode::OperationsError,
                     format!(r#"Could not parse base DN: "{}""#, request.base),
-                )]
+                )])
             }
         };
         if !is_subtree(&dn_parts, &self.base_dn) {
@@ -415,136 +1654,429 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 "The specified search tree {:?} is not under the common subtree {:?}",
                 &dn_parts, &self.base_dn
             );
-            return vec![make_search_success()];
+            return results(vec![make_search_success()]);
         }
-        let mut results = Vec::new();
-        let mut got_match = false;
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "people".to_string()))
-        {
-            got_match = true;
-            results.extend(self.get_user_list(request).await);
+        let request = Rc::new(request);
+        let mut streams = Vec::new();
+        let (search_users, search_groups) = self.get_searched_subtrees(&dn_parts);
+        if search_users {
+            streams.push(self.get_user_stream(request.clone()));
         }
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "groups".to_string()))
-        {
-            got_match = true;
-            results.extend(self.get_groups_list(request).await);
+        if search_groups {
+            let request = request.clone();
+            streams.push(
+                stream::once(async move { self.get_groups_list(&request, None).await })
+                    .flat_map(stream::iter)
+                    .boxed_local(),
+            );
         }
-        if !got_match {
+        if streams.is_empty() {
             warn!(
                 r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
                 &request.base, &self.base_dn_str, &self.base_dn_str
             );
         }
-        if results.is_empty() || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
-        {
-            results.push(make_search_success());
-        }
-        results
+        end_with_search_success(stream::iter(streams).flatten().boxed_local())
     }
 
-    async fn get_user_list(&self, request: &LdapSearchRequest) -> Vec<LdapOp> {
-        let filters = match self.convert_user_filter(&request.filter) {
-            Ok(f) => Some(f),
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported user filter: {:#}", e),
-                )]
-            }
-        };
-        let users = match self.backend_handler.list_users(filters).await {
-            Ok(users) => users,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
-                )]
-            }
+    /// Whether the search under these DN parts covers the users and the groups subtrees.
+    fn get_searched_subtrees(&self, dn_parts: &[(String, String)]) -> (bool, bool) {
+        let is_subtree_root = |ou: &str| {
+            dn_parts.len() == self.base_dn.len()
+                || (dn_parts.len() == self.base_dn.len() + 1
+                    && dn_parts[0] == ("ou".to_string(), ou.to_string()))
         };
-
-        users
-            .into_iter()
-            .map(|u| make_ldap_search_user_result_entry(u, &self.base_dn_str, &request.attrs))
-            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| {
-                vec![make_search_error(
-                    LdapResultCode::NoSuchAttribute,
-                    e.to_string(),
-                )]
-            })
+        (is_subtree_root("people"), is_subtree_root("groups"))
     }
 
-    async fn get_groups_list(&self, request: &LdapSearchRequest) -> Vec<LdapOp> {
-        let for_user = match self.get_group_filter(&request.filter) {
-            Ok(u) => u,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported group filter: {:#}", e),
-                )]
+    /// Handles a search with the Simple Paged Results control (RFC 2696). Returns the entries of
+    /// the requested page, and the cookie to get the next one, empty after the last page.
+    async fn do_paged_search(
+        &self,
+        request: LdapSearchRequest,
+        size: usize,
+        cookie: &[u8],
+    ) -> (Vec<LdapOp>, Vec<u8>) {
+        let dn_parts = match parse_distinguished_name(&request.base) {
+            Ok(dn_parts)
+                if self.can_read_all()
+                    && !is_monitor_dn(&request.base)
+                    && is_subtree(&dn_parts, &self.base_dn) =>
+            {
+                dn_parts
             }
+            // Not a search of the users or groups: the results are small, return them all.
+            _ => return (self.do_search_stream(request).collect().await, Vec::new()),
         };
-
-        async fn get_users_for_group<Backend: BackendHandler>(
-            backend_handler: &Backend,
-            g: &GroupIdAndName,
-        ) -> Result<Group> {
-            let users = backend_handler
-                .list_users(Some(RequestFilter::MemberOfId(g.0)))
-                .await?;
-            Ok(Group {
-                id: g.0,
-                display_name: g.1.clone(),
-                users: users.into_iter().map(|u| u.user_id).collect(),
-            })
-        }
-
-        let groups: Vec<Group> = if let Some(user) = for_user {
-            let groups_without_users = match self.backend_handler.get_user_groups(&user).await {
-                Ok(groups) => groups,
-                Err(e) => {
-                    return vec![make_search_error(
-                        LdapResultCode::Other,
-                        format!(
-                            r#"Error while listing user groups: "{}": {:#}"#,
-                            request.base, e
-                        ),
-                    )]
-                }
-            };
-            match tokio_stream::iter(groups_without_users.iter())
-                .then(|g| async move { get_users_for_group::<Backend>(&self.backend_handler, g).await })
-                .try_collect::<Vec<Group>>()
-                .await
-            {
-                Ok(groups) => groups,
-                Err(e) => {
-                    return vec![make_search_error(
-                        LdapResultCode::Other,
-                        format!(r#"Error while listing user groups: "{}": {:#}"#, request.base, e),
-                    )]
-                }
+        let (search_users, search_groups) = self.get_searched_subtrees(&dn_parts);
+        let cursor = if cookie.is_empty() {
+            if search_users {
+                PageCursor::Users(0)
+            } else {
+                PageCursor::Groups(0)
             }
         } else {
-            match self.backend_handler.list_groups().await {
-                Ok(groups) => groups,
-                Err(e) => {
-                    return vec![make_search_error(
-                        LdapResultCode::Other,
-                        format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
-                    )]
+            match PageCursor::from_cookie(cookie) {
+                Some(cursor) => cursor,
+                None => {
+                    return (
+                        vec![make_search_error(
+                            LdapResultCode::UnwillingToPerform,
+                            "Invalid paged results cookie".to_string(),
+                        )],
+                        Vec::new(),
+                    )
                 }
             }
         };
-
-        groups
+        // A size of 0 abandons the search.
+        if size == 0 {
+            return (vec![make_search_success()], Vec::new());
+        }
+        // Each page is read with one more entry than requested, to know if there is a next one.
+        let mut entries = Vec::new();
+        let mut next = None;
+        let mut group_offset = 0;
+        match cursor {
+            PageCursor::Users(offset) if search_users => {
+                match self.get_users_page(&request, offset, size + 1).await {
+                    Ok(users) => entries = users,
+                    Err(error) => return (vec![error], Vec::new()),
+                }
+                if entries.len() > size {
+                    entries.truncate(size);
+                    next = Some(PageCursor::Users(offset + size));
+                }
+            }
+            PageCursor::Groups(offset) => group_offset = offset,
+            PageCursor::Users(_) => (),
+        }
+        if next.is_none() && search_groups {
+            let remaining = size - entries.len();
+            let mut groups = self
+                .get_groups_list(&request, Some((group_offset, remaining + 1)))
+                .await;
+            if let Some(LdapOp::SearchResultDone(_)) = groups.last() {
+                return (groups, Vec::new());
+            }
+            if groups.len() > remaining {
+                groups.truncate(remaining);
+                next = Some(PageCursor::Groups(group_offset + remaining));
+            }
+            entries.append(&mut groups);
+        }
+        entries.push(make_search_success());
+        (entries, next.map(PageCursor::to_cookie).unwrap_or_default())
+    }
+
+    /// The users that `get_user_stream` would produce, only reading the requested page.
+    async fn get_users_page(
+        &self,
+        request: &LdapSearchRequest,
+        offset: usize,
+        limit: usize,
+    ) -> std::result::Result<Vec<LdapOp>, LdapOp> {
+        let filters = self.convert_user_filter(&request.filter).map_err(|e| {
+            make_search_error(
+                get_filter_error_code(&e),
+                format!("Unsupported user filter: {:#}", e),
+            )
+        })?;
+        let extra_data = self.get_user_extra_data_requested(request);
+        let users = self
+            .backend_handler
+            .list_users_page(
+                Some(filters),
+                self.user_order,
+                SortDirection::Ascending,
+                offset,
+                limit,
+            )
+            .await
+            .map_err(|e| {
+                make_search_error(
+                    get_backend_error_code(&e),
+                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
+                )
+            })?;
+        self.make_user_entries(users, request, extra_data).await
+    }
+
+    /// Whether the hosts, the custom attributes, the groups, the locks and the avatars of the users
+    /// are requested.
+    fn get_user_extra_data_requested(&self, request: &LdapSearchRequest) -> UserExtraDataRequest {
+        let sources = request
+            .attrs
+            .iter()
+            .flat_map(|a| self.attribute_profile.user_mapping.sources(a))
+            .collect::<Vec<_>>();
+        UserExtraDataRequest {
+            hosts: sources.iter().any(|a| a.eq_ignore_ascii_case("host")),
+            attributes: sources
+                .iter()
+                .any(|a| self.schema.get_attribute(a).is_some()),
+            member_of: sources.iter().any(|a| a.eq_ignore_ascii_case("memberOf")),
+            lock: sources
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case("pwdAccountLockedTime")),
+            avatar: sources.iter().any(|a| a.eq_ignore_ascii_case("jpegPhoto")),
+        }
+    }
+
+    /// Converts the users read from the backend to search result entries, or to the error ending
+    /// the search. The groups of all the users are fetched at once.
+    async fn make_user_entries(
+        &self,
+        users: Vec<User>,
+        request: &LdapSearchRequest,
+        extra_data: UserExtraDataRequest,
+    ) -> std::result::Result<Vec<LdapOp>, LdapOp> {
+        let mut users_groups = if extra_data.member_of {
+            let user_ids = users
+                .iter()
+                .map(|user| user.user_id.clone())
+                .collect::<Vec<_>>();
+            self.backend_handler
+                .get_users_groups(&user_ids)
+                .await
+                .map_err(|e| {
+                    make_search_error(
+                        get_backend_error_code(&e),
+                        format!(r#"Error while listing the groups of the users: {:#}"#, e),
+                    )
+                })?
+        } else {
+            HashMap::new()
+        };
+        let mut entries = Vec::with_capacity(users.len());
+        for user in users {
+            let groups = users_groups.remove(&user.user_id);
+            entries.push(
+                self.make_user_entry(user, groups, request, extra_data)
+                    .await?,
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Converts a user read from the backend to a search result entry, or to the error ending the
+    /// search. `groups` are the groups of the user, if `memberOf` was requested.
+    async fn make_user_entry(
+        &self,
+        user: User,
+        groups: Option<HashSet<GroupIdAndName>>,
+        request: &LdapSearchRequest,
+        extra_data: UserExtraDataRequest,
+    ) -> std::result::Result<LdapOp, LdapOp> {
+        let fetch_error = |what: &str, e: DomainError| {
+            make_search_error(
+                get_backend_error_code(&e),
+                format!(
+                    r#"Error while listing the {} of "{}": {:#}"#,
+                    what, user.user_id, e
+                ),
+            )
+        };
+        // The hosts, custom attributes, groups, locks and avatars are stored separately, or are
+        // large, only fetch them if they were requested.
+        let mut extra = UserExtraData::default();
+        if extra_data.hosts {
+            extra.hosts = self
+                .backend_handler
+                .get_user_hosts(&user.user_id)
+                .await
+                .map_err(|e| fetch_error("hosts", e))?;
+        }
+        if extra_data.attributes {
+            extra.attributes = self
+                .backend_handler
+                .get_user_attributes(&user.user_id)
+                .await
+                .map_err(|e| fetch_error("attributes", e))?;
+        }
+        if extra_data.member_of {
+            let mut group_names = groups
+                .unwrap_or_default()
+                .into_iter()
+                .map(|group| group.1)
+                .collect::<Vec<_>>();
+            group_names.sort();
+            extra.member_of = group_names
+                .into_iter()
+                .map(|name| format!("cn={},ou=groups,{}", name, self.base_dn_str))
+                .collect();
+        }
+        if extra_data.lock {
+            extra.lock = self
+                .backend_handler
+                .get_user_lock(&user.user_id)
+                .await
+                .map_err(|e| fetch_error("lock", e))?;
+        }
+        if extra_data.avatar {
+            extra.avatar = self
+                .backend_handler
+                .get_user_avatar(&user.user_id)
+                .await
+                .map_err(|e| fetch_error("avatar", e))?;
+        }
+        make_ldap_search_user_result_entry(
+            user,
+            &extra,
+            &self.base_dn_str,
+            &request.attrs,
+            &self.attribute_profile,
+            &self.schema,
+        )
+        .map(LdapOp::SearchResultEntry)
+        .map_err(|e| make_search_error(LdapResultCode::NoSuchAttribute, e.to_string()))
+    }
+
+    fn get_user_stream(&self, request: Rc<LdapSearchRequest>) -> LocalBoxStream<'_, LdapOp> {
+        let filters = match self.convert_user_filter(&request.filter) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                return stream::iter(vec![make_search_error(
+                    get_filter_error_code(&e),
+                    format!("Unsupported user filter: {:#}", e),
+                )])
+                .boxed_local()
+            }
+        };
+        let extra_data = self.get_user_extra_data_requested(&request);
+        self.backend_handler
+            .list_users_stream(filters, self.user_order)
+            // In batches, to fetch the groups of the users at once.
+            .chunks(USER_ENTRY_BATCH_SIZE)
+            .then(move |users| {
+                let request = request.clone();
+                async move {
+                    let mut read_users = Vec::with_capacity(users.len());
+                    let mut read_error = None;
+                    for user in users {
+                        match user {
+                            Ok(user) => read_users.push(user),
+                            Err(e) => {
+                                read_error = Some(make_search_error(
+                                    get_backend_error_code(&e),
+                                    format!(
+                                        r#"Error during searching user "{}": {:#}"#,
+                                        request.base, e
+                                    ),
+                                ));
+                                break;
+                            }
+                        }
+                    }
+                    let mut entries = match self
+                        .make_user_entries(read_users, &request, extra_data)
+                        .await
+                    {
+                        Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>(),
+                        Err(error) => vec![Err(error)],
+                    };
+                    entries.extend(read_error.map(Err));
+                    stream::iter(entries)
+                }
+            })
+            .flatten()
+            // The first error ends the search.
+            .scan(false, |failed, entry| {
+                future::ready(if *failed {
+                    None
+                } else {
+                    *failed = entry.is_err();
+                    Some(entry.unwrap_or_else(|error| error))
+                })
+            })
+            .boxed_local()
+    }
+
+    /// The group entries, or the error ending the search. With a page, only returns `limit`
+    /// entries after skipping `offset` ones.
+    async fn get_groups_list(
+        &self,
+        request: &LdapSearchRequest,
+        page: Option<(usize, usize)>,
+    ) -> Vec<LdapOp> {
+        let filter = match self.convert_group_filter(&request.filter) {
+            Ok(filter) => filter,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!("Unsupported group filter: {:#}", e),
+                )]
+            }
+        };
+        let groups = match page {
+            Some((offset, limit)) => {
+                self.backend_handler
+                    .list_groups_page(Some(filter), offset, limit)
+                    .await
+            }
+            None => self.backend_handler.list_groups(Some(filter)).await,
+        };
+        let groups = match groups {
+            Ok(groups) => groups,
+            Err(e) => {
+                return vec![make_search_error(
+                    get_backend_error_code(&e),
+                    format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+
+        // The addresses and the metadata are stored separately, only fetch them if they are
+        // requested.
+        let sources = request
+            .attrs
+            .iter()
+            .flat_map(|a| self.attribute_profile.group_mapping.sources(a))
+            .collect::<Vec<_>>();
+        let with_mail = sources.iter().any(|a| a.eq_ignore_ascii_case("mail"));
+        let with_metadata = sources.iter().any(|a| {
+            GROUP_METADATA_ATTRIBUTES
+                .iter()
+                .any(|m| a.eq_ignore_ascii_case(m))
+        });
+        let mut groups_with_extra = Vec::new();
+        for group in groups {
+            let fetch_error = |what: &str, e: DomainError| {
+                vec![make_search_error(
+                    get_backend_error_code(&e),
+                    format!(
+                        r#"Error while listing the {} of "{}": {:#}"#,
+                        what, group.display_name, e
+                    ),
+                )]
+            };
+            let mut extra = GroupExtraData::default();
+            if with_mail {
+                match self.backend_handler.get_group_mail(group.id).await {
+                    Ok(mail) => extra.mail = mail,
+                    Err(e) => return fetch_error("addresses", e),
+                }
+            }
+            if with_metadata {
+                match self.backend_handler.get_group_metadata(group.id).await {
+                    Ok(metadata) => extra.metadata = metadata,
+                    Err(e) => return fetch_error("metadata", e),
+                }
+            }
+            groups_with_extra.push((group, extra));
+        }
+
+        groups_with_extra
             .into_iter()
-            .map(|u| make_ldap_search_group_result_entry(u, &self.base_dn_str, &request.attrs))
+            .map(|(group, extra)| {
+                make_ldap_search_group_result_entry(
+                    group,
+                    &extra,
+                    &self.base_dn_str,
+                    &request.attrs,
+                    &self.attribute_profile,
+                )
+            })
             .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
             .collect::<Result<Vec<_>>>()
             .unwrap_or_else(|e| {
@@ -556,9 +2088,105 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
     }
 
     pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
+        Some(self.handle_ldap_message_stream(ldap_op)?.collect().await)
+    }
+
+    /// Same as `handle_ldap_message`, but produces the responses as they are computed, so that
+    /// they can be sent while the rest of a large search is read from the database. Returns
+    /// `None` when the connection should be closed.
+    pub fn handle_ldap_message_stream(
+        &mut self,
+        ldap_op: LdapOp,
+    ) -> Option<LocalBoxStream<'_, LdapOp>> {
+        self.monitor.record_operation(match &ldap_op {
+            LdapOp::BindRequest(_) => LdapOperation::Bind,
+            LdapOp::SearchRequest(_) => LdapOperation::Search,
+            LdapOp::UnbindRequest => LdapOperation::Unbind,
+            LdapOp::ExtendedRequest(_) => LdapOperation::Extended,
+            _ => LdapOperation::Other,
+        });
         Some(match ldap_op {
+            LdapOp::SearchRequest(request) => {
+                let metrics = self.metrics.clone();
+                let mut entries = 0;
+                self.do_search_stream(request)
+                    .inspect(move |op| match op {
+                        LdapOp::SearchResultEntry(_) => entries += 1,
+                        LdapOp::SearchResultDone(_) => metrics.record_ldap_search(entries),
+                        _ => {}
+                    })
+                    .boxed_local()
+            }
+            LdapOp::UnbindRequest => {
+                self.reset_to_anonymous();
+                // No need to notify on unbind (per rfc4511)
+                return None;
+            }
+            op => stream::once(self.handle_single_response_message(op))
+                .flat_map(stream::iter)
+                .boxed_local(),
+        })
+    }
+
+    /// Same as `handle_ldap_message_stream`, but with the controls of the request, producing the
+    /// controls of each response along with it. Only the Simple Paged Results control of the
+    /// searches is supported, the others are ignored.
+    pub fn handle_ldap_message_with_controls(
+        &mut self,
+        ldap_op: LdapOp,
+        controls: Vec<LdapControl>,
+    ) -> Option<LocalBoxStream<'_, (LdapOp, Vec<LdapControl>)>> {
+        let paged_results = controls.into_iter().find_map(|control| match control {
+            LdapControl::SimplePagedResults { size, cookie } => Some((size, cookie)),
+            _ => None,
+        });
+        match (ldap_op, paged_results) {
+            (LdapOp::SearchRequest(request), Some((size, cookie))) => {
+                self.monitor.record_operation(LdapOperation::Search);
+                let metrics = self.metrics.clone();
+                let this = &*self;
+                Some(
+                    stream::once(async move {
+                        this.do_paged_search(request, size.max(0) as usize, &cookie)
+                            .await
+                    })
+                    .flat_map(move |(ops, cookie)| {
+                        metrics.record_ldap_search(
+                            ops.iter()
+                                .filter(|op| matches!(op, LdapOp::SearchResultEntry(_)))
+                                .count(),
+                        );
+                        stream::iter(ops.into_iter().map(move |op| {
+                            // The cookie goes with the end of the search.
+                            let controls = match op {
+                                LdapOp::SearchResultDone(_) => {
+                                    vec![LdapControl::SimplePagedResults {
+                                        size: 0,
+                                        cookie: cookie.clone(),
+                                    }]
+                                }
+                                _ => Vec::new(),
+                            };
+                            (op, controls)
+                        }))
+                    })
+                    .boxed_local(),
+                )
+            }
+            (ldap_op, _) => Some(
+                self.handle_ldap_message_stream(ldap_op)?
+                    .map(|op| (op, Vec::new()))
+                    .boxed_local(),
+            ),
+        }
+    }
+
+    async fn handle_single_response_message(&mut self, ldap_op: LdapOp) -> Vec<LdapOp> {
+        match ldap_op {
             LdapOp::BindRequest(request) => {
                 let (code, message) = self.do_bind(&request).await;
+                self.metrics
+                    .record_ldap_bind(code == LdapResultCode::Success);
                 vec![LdapOp::BindResponse(LdapBindResponse {
                     res: LdapResult {
                         code,
@@ -569,43 +2197,89 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                     saslcreds: None,
                 })]
             }
-            LdapOp::SearchRequest(request) => self.do_search(&request).await,
-            LdapOp::UnbindRequest => {
-                self.dn = "Unauthenticated".to_string();
-                // No need to notify on unbind (per rfc4511)
-                return None;
-            }
             LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
+            // The Modify, ModifyDN and Compare requests, and the SASL credentials of the binds,
+            // aren't parsed by the LDAP library: the passwords are changed with the Password
+            // Modify extended operation, and the users renamed through GraphQL.
             op => vec![make_extended_response(
                 LdapResultCode::UnwillingToPerform,
                 format!("Unsupported operation: {:#?}", op),
             )],
-        })
+        }
     }
 
-    fn get_group_filter(&self, filter: &LdapFilter) -> Result<Option<String>> {
+    fn convert_group_filter(&self, filter: &LdapFilter) -> Result<GroupRequestFilter> {
+        let mapping = &self.attribute_profile.group_mapping;
         match filter {
+            LdapFilter::And(filters) => Ok(GroupRequestFilter::And(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Or(filters) => Ok(GroupRequestFilter::Or(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(
+                self.convert_group_filter(&*filter)?,
+            ))),
             LdapFilter::Equality(field, value) => {
-                if field == "member" || field.to_lowercase() == "uniquemember" {
-                    let user_name = get_user_id_from_distinguished_name(
-                        value,
-                        &self.base_dn,
-                        &self.base_dn_str,
-                    )?;
-                    Ok(Some(user_name))
-                } else if field.to_lowercase() == "objectclass" && value == "groupOfUniqueNames" {
-                    Ok(None)
-                } else {
-                    bail!("Unsupported group filter: {:?}", filter)
+                let field = mapping.filtered_attribute(field)?.to_lowercase();
+                match field.as_str() {
+                    "cn" | "uid" => Ok(GroupRequestFilter::DisplayName(value.clone())),
+                    "memberuid" => Ok(GroupRequestFilter::Member(value.clone())),
+                    "member" | "uniquemember" => Ok(GroupRequestFilter::Member(
+                        get_user_id_from_distinguished_name(
+                            value,
+                            &self.base_dn,
+                            &self.base_dn_str,
+                        )?,
+                    )),
+                    // The groups have the `member` attribute of groupOfNames too.
+                    "objectclass"
+                        if value == "groupOfUniqueNames"
+                            || value == "groupOfNames"
+                            || value == "posixGroup"
+                            || (self.attribute_profile.is_active_directory()
+                                && value == "group") =>
+                    {
+                        Ok(GroupRequestFilter::And(vec![]))
+                    }
+                    "gidnumber" => Ok(GroupRequestFilter::GroupId(
+                        self.attribute_profile.group_id_from_gid_number(value)?,
+                    )),
+                    "mail" => Ok(GroupRequestFilter::Mail(value.clone())),
+                    _ => bail!("Unsupported group filter: {:?}", filter),
                 }
             }
-            LdapFilter::And(v) => v
-                .iter()
-                .fold(Ok(None), |o, f| Ok(o?.xor(self.get_group_filter(f)?))),
+            LdapFilter::Substring(field, substrings)
+                if mapping
+                    .filtered_attribute(field)
+                    .map_or(false, |field| field.eq_ignore_ascii_case("cn")) =>
+            {
+                Ok(GroupRequestFilter::DisplayNameSubstring(
+                    convert_substring_filter(substrings),
+                ))
+            }
             _ => bail!("Unsupported group filter: {:?}", filter),
         }
     }
 
+    fn map_user_field(&self, field: &str) -> Result<String> {
+        if let Some(column) = self.attribute_aliases.get(&field.to_lowercase()) {
+            Ok(column.clone())
+        } else if self.attribute_profile.is_active_directory()
+            && field.to_lowercase() == "samaccountname"
+        {
+            Ok("user_id".to_string())
+        } else {
+            map_field(field)
+        }
+    }
+
     fn convert_user_filter(&self, filter: &LdapFilter) -> Result<RequestFilter> {
         match filter {
             LdapFilter::And(filters) => Ok(RequestFilter::And(
@@ -624,6 +2298,10 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                 self.convert_user_filter(&*filter)?,
             ))),
             LdapFilter::Equality(field, value) => {
+                let field = self
+                    .attribute_profile
+                    .user_mapping
+                    .filtered_attribute(field)?;
                 if field.to_lowercase() == "memberof" {
                     let group_name = get_group_id_from_distinguished_name(
                         value,
@@ -636,18 +2314,84 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                         || value == "inetOrgPerson"
                         || value == "posixAccount"
                         || value == "mailAccount"
+                        || (self.attribute_profile.is_active_directory() && value == "user")
+                        || self
+                            .schema
+                            .object_classes
+                            .iter()
+                            .any(|c| c.eq_ignore_ascii_case(value))
                     {
                         Ok(RequestFilter::And(vec![]))
                     } else {
                         Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![]))))
                     }
+                } else if self.attribute_profile.is_active_directory()
+                    && field.to_lowercase() == "userprincipalname"
+                {
+                    match self.attribute_profile.user_id_from_principal_name(value) {
+                        Some(user_id) => Ok(RequestFilter::Equality(
+                            "user_id".to_string(),
+                            user_id.to_string(),
+                        )),
+                        // Not in our domain, nothing can match.
+                        None => Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![])))),
+                    }
+                } else if field.to_lowercase() == "uidnumber" && value.parse::<i32>().is_err() {
+                    // Not a number, nothing can match.
+                    Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![]))))
+                } else if field.to_lowercase() == "gidnumber" {
+                    match self.attribute_profile.posix.user_gid_number {
+                        Some(gid_number) if gid_number.to_string() == *value => {
+                            Ok(RequestFilter::And(vec![]))
+                        }
+                        Some(_) => Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![])))),
+                        // The primary group of a user has its uidNumber.
+                        None => self.convert_user_filter(&LdapFilter::Equality(
+                            "uidNumber".to_string(),
+                            value.clone(),
+                        )),
+                    }
+                } else if field.to_lowercase() == "loginshell" {
+                    if *value == self.attribute_profile.posix.login_shell {
+                        Ok(RequestFilter::And(vec![]))
+                    } else {
+                        Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![]))))
+                    }
+                } else if let Some(custom) = self.schema.get_attribute(field) {
+                    Ok(RequestFilter::AttributeEquality(
+                        custom.name.clone(),
+                        value.clone(),
+                    ))
                 } else {
-                    Ok(RequestFilter::Equality(map_field(field)?, value.clone()))
+                    Ok(RequestFilter::Equality(
+                        self.map_user_field(field)?,
+                        value.clone(),
+                    ))
                 }
             }
+            LdapFilter::Substring(field, substrings) => match self
+                .attribute_profile
+                .user_mapping
+                .filtered_attribute(field)
+                .and_then(|field| self.map_user_field(field))
+            {
+                Ok(field) => Ok(RequestFilter::Substring(
+                    field,
+                    convert_substring_filter(substrings),
+                )),
+                Err(_) => bail!("Unsupported user filter: {:?}", filter),
+            },
             LdapFilter::Present(field) => {
                 // Check that it's a field we support.
-                if field.to_lowercase() == "objectclass" || map_field(field).is_ok() {
+                if self.attribute_profile.user_mapping.get(field).is_some()
+                    || field.to_lowercase() == "objectclass"
+                    || ["gidnumber", "homedirectory", "loginshell"]
+                        .contains(&field.to_lowercase().as_str())
+                    || (self.attribute_profile.is_active_directory()
+                        && field.to_lowercase() == "userprincipalname")
+                    || self.schema.get_attribute(field).is_some()
+                    || self.map_user_field(field).is_ok()
+                {
                     Ok(RequestFilter::And(vec![]))
                 } else {
                     Ok(RequestFilter::Not(Box::new(RequestFilter::And(vec![]))))
@@ -680,18 +2424,71 @@ mod tests {
         #[async_trait]
         impl BackendHandler for TestBackendHandler {
             async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>>;
-            async fn list_groups(&self) -> Result<Vec<Group>>;
+            async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
             async fn get_user_details(&self, user_id: &str) -> Result<User>;
+            async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>>;
             async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+            async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>>;
             async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>>;
+            async fn get_users_groups(
+                &self,
+                user_ids: &[String],
+            ) -> Result<HashMap<String, HashSet<GroupIdAndName>>>;
             async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
             async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
             async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
             async fn delete_user(&self, user_id: &str) -> Result<()>;
+            async fn restore_user(&self, user_id: &str) -> Result<()>;
+            async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>>;
+            async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()>;
             async fn create_group(&self, group_name: &str) -> Result<GroupId>;
             async fn delete_group(&self, group_id: GroupId) -> Result<()>;
             async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
             async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()>;
+            async fn add_group_to_group(
+                &self,
+                member_group_id: GroupId,
+                parent_group_id: GroupId,
+            ) -> Result<()>;
+            async fn remove_group_from_group(
+                &self,
+                member_group_id: GroupId,
+                parent_group_id: GroupId,
+            ) -> Result<()>;
+            async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>>;
+            async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()>;
+            async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata>;
+            async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail>;
+            async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()>;
+            async fn get_user_attributes(
+                &self,
+                user_id: &str,
+            ) -> Result<std::collections::HashMap<String, Vec<String>>>;
+            async fn set_user_attribute(
+                &self,
+                user_id: &str,
+                name: &str,
+                values: Vec<String>,
+            ) -> Result<()>;
+            async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()>;
+            async fn unlock_user(&self, user_id: &str) -> Result<()>;
+            async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>>;
+            async fn list_password_changes(
+                &self,
+            ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>>;
+            async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()>;
+            async fn list_auth_failures(
+                &self,
+                user_id: Option<&str>,
+                offset: usize,
+                limit: usize,
+            ) -> Result<Vec<AuthFailure>>;
+            async fn record_audit_event(&self, event: AuditEvent) -> Result<()>;
+            async fn list_audit_events(
+                &self,
+                offset: usize,
+                limit: usize,
+            ) -> Result<Vec<AuditEvent>>;
         }
         #[async_trait]
         impl OpaqueHandler for TestBackendHandler {
@@ -708,6 +2505,7 @@ mod tests {
                 &self,
                 request: registration::ClientRegistrationFinishRequest
             ) -> Result<()>;
+            async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool>;
         }
     }
 
@@ -767,6 +2565,10 @@ mod tests {
             }))
             .times(1)
             .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
         let mut ldap_handler =
             LdapHandler::new(mock, "dc=example,dc=com".to_string(), "test".to_string());
 
@@ -821,6 +2623,10 @@ mod tests {
             }))
             .times(1)
             .return_once(|_| Ok(()));
+        mock.expect_get_user_groups()
+            .with(eq("test"))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
         let mut ldap_handler =
             LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string());
 
@@ -838,14 +2644,78 @@ mod tests {
             ldap_handler.do_search(&request).await,
             vec![make_search_error(
                 LdapResultCode::InsufficentAccessRights,
-                r#"Current user `cn=test,ou=people,dc=example,dc=com` is not allowed to query LDAP, expected cn=admin,ou=people,dc=example,dc=com"#.to_string()
+                r#"Current user `cn=test,ou=people,dc=example,dc=com` is not allowed to query LDAP"#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_bind() {
+        let bind_request = |dn: &str, password: &str| LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(password.to_string()),
+        };
+        let users_request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&bind_request("", "")).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler.do_search(&users_request).await,
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the subschema, bind first"
+                    .to_string()
             )]
         );
+        assert_eq!(
+            ldap_handler.do_bind(&bind_request("", "pass")).await.0,
+            LdapResultCode::InvalidCredentials
+        );
+        assert_eq!(
+            ldap_handler
+                .do_bind(&bind_request("cn=bob,ou=people,dc=example,dc=com", ""))
+                .await
+                .0,
+            LdapResultCode::UnwillingToPerform
+        );
+
+        let mut ldap_handler = ldap_handler.with_anonymous_access(LdapAnonymousAccess::Deny);
+        assert_eq!(
+            ldap_handler.do_bind(&bind_request("", "")).await.0,
+            LdapResultCode::InappropriateAuthentication
+        );
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string())
+                .with_anonymous_access(LdapAnonymousAccess::ReadOnly);
+        assert_eq!(
+            ldap_handler.do_search(&users_request).await,
+            vec![make_search_success()]
+        );
     }
 
     #[tokio::test]
     async fn test_bind_invalid_dn() {
-        let mock = MockTestBackendHandler::new();
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_record_auth_failure()
+            .withf(|failure| {
+                failure.user_id.is_none()
+                    && failure.reason == AuthFailureReason::InvalidDn
+                    && failure.protocol == AuthProtocol::Ldap
+            })
+            .times(4)
+            .returning(|_| Ok(()));
         let mut ldap_handler =
             LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string());
 
@@ -883,6 +2753,92 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_bind_failure_recorded() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .times(2)
+            .returning(|request| Err(DomainError::AuthenticationError(request.name)));
+        mock.expect_get_user_lock()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(None));
+        mock.expect_get_user_lock()
+            .with(eq("jim"))
+            .times(1)
+            .return_once(|_| {
+                Ok(Some(UserLock {
+                    locked_at: chrono::Utc::now(),
+                    reason: "".to_string(),
+                }))
+            });
+        mock.expect_record_auth_failure()
+            .withf(|failure| {
+                failure.user_id.as_deref() == Some("bob")
+                    && failure.reason == AuthFailureReason::InvalidCredentials
+                    && failure.source_ip == Some("10.0.0.1".parse().unwrap())
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_record_auth_failure()
+            .withf(|failure| {
+                failure.user_id.as_deref() == Some("jim")
+                    && failure.reason == AuthFailureReason::UserLocked
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string())
+                .with_source_ip(Some("10.0.0.1".parse().unwrap()));
+        for user in &["bob", "jim"] {
+            let request = LdapBindRequest {
+                dn: format!("cn={},ou=people,dc=example,dc=com", user),
+                cred: LdapBindCred::Simple("wrong".to_string()),
+            };
+            assert_eq!(
+                ldap_handler.do_bind(&request).await.0,
+                LdapResultCode::InvalidCredentials
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_throttling() {
+        use crate::infra::configuration::BindThrottleConfig;
+        let mut mock = MockTestBackendHandler::new();
+        // The third attempt is refused before checking the password.
+        mock.expect_bind()
+            .times(2)
+            .returning(|request| Err(DomainError::AuthenticationError(request.name)));
+        mock.expect_get_user_lock().returning(|_| Ok(None));
+        mock.expect_record_auth_failure()
+            .times(2)
+            .returning(|_| Ok(()));
+        let bind_throttle = BindThrottle::new(BindThrottleConfig {
+            max_failures: 2,
+            ..Default::default()
+        });
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string())
+                .with_source_ip(Some("10.0.0.1".parse().unwrap()))
+                .with_bind_throttle(bind_throttle.clone());
+        let request = LdapBindRequest {
+            dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("wrong".to_string()),
+        };
+        for _ in 0..2 {
+            assert_eq!(
+                ldap_handler.do_bind(&request).await.0,
+                LdapResultCode::InvalidCredentials
+            );
+        }
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::UnwillingToPerform
+        );
+        assert_eq!(bind_throttle.lockouts().len(), 2);
+    }
+
     #[test]
     fn test_is_subtree() {
         let subtree1 = &[
@@ -932,6 +2888,7 @@ mod tests {
                     first_name: "Jim".to_string(),
                     last_name: "Cricket".to_string(),
                     creation_date: Utc.ymd(2014, 7, 8).and_hms(9, 10, 11),
+                    ..Default::default()
                 },
             ])
         });
@@ -1027,13 +2984,1023 @@ mod tests {
                             vals: vec!["Cricket".to_string()]
                         },
                         LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Jimminy Cricket".to_string()]
+                            atype: "cn".to_string(),
+                            vals: vec!["Jimminy Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["2014-07-08T09:10:11+00:00".to_string()]
+                        }
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_active_directory_profile() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::Or(vec![
+                RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
+                RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
+                RequestFilter::Not(Box::new(RequestFilter::And(vec![]))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_attribute_profile(LdapAttributeProfile::ActiveDirectory);
+        let request = make_user_search_request(
+            LdapFilter::Or(vec![
+                LdapFilter::Equality("sAMAccountName".to_string(), "bob".to_string()),
+                LdapFilter::Equality(
+                    "userPrincipalName".to_string(),
+                    "bob@example.com".to_string(),
+                ),
+                LdapFilter::Equality(
+                    "userPrincipalName".to_string(),
+                    "bob@example.org".to_string(),
+                ),
+            ]),
+            vec!["sAMAccountName", "userPrincipalName", "objectSid"],
+        );
+        let user_sid = format!(
+            "{}-{}",
+            ldap_handler.attribute_profile.domain_sid,
+            sid_sub_authorities("bob")[0] | 0x8000_0000
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "sAMAccountName".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "userPrincipalName".to_string(),
+                            vals: vec!["bob@example.com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "objectSid".to_string(),
+                            vals: vec![user_sid]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_attribute_casing() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(2).returning(|_| {
+            Ok(vec![User {
+                user_id: "bob".to_string(),
+                first_name: "Bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_attribute_casing(LdapAttributeCasing::Canonical)
+            .with_attribute_profile(LdapAttributeProfile::ActiveDirectory);
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["samaccountname", "GIVENNAME", "uid"],
+        );
+        let make_entry = |names: [&str; 3]| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![
+                    LdapPartialAttribute {
+                        atype: names[0].to_string(),
+                        vals: vec!["bob".to_string()],
+                    },
+                    LdapPartialAttribute {
+                        atype: names[1].to_string(),
+                        vals: vec!["Bob".to_string()],
+                    },
+                    LdapPartialAttribute {
+                        atype: names[2].to_string(),
+                        vals: vec!["bob".to_string()],
+                    },
+                ],
+            })
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                make_entry(["sAMAccountName", "givenName", "uid"]),
+                make_search_success(),
+            ]
+        );
+        let mut ldap_handler = ldap_handler.with_attribute_casing(LdapAttributeCasing::Lowercase);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                make_entry(["samaccountname", "givenname", "uid"]),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_standard_profile_rejects_ad_attributes() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality("sAMAccountName".to_string(), "bob".to_string()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UndefinedAttributeType,
+                "Unsupported user filter: Unknown field: sAMAccountName".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_timeout() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .times(1)
+            .return_once(|_| Err(DomainError::TimeoutError("bind".to_string())));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "test".to_string());
+        let request = LdapBindRequest {
+            dn: "cn=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Unavailable
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_stops_at_first_error() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: "jim".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: "tom".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_get_user_hosts()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_get_user_hosts()
+            .with(eq("jim"))
+            .times(1)
+            .return_once(|_| Err(DomainError::InternalError("error".to_string())));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["host"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "host".to_string(),
+                        vals: vec![]
+                    }],
+                }),
+                make_search_error(
+                    LdapResultCode::Other,
+                    r#"Error while listing the hosts of "jim": Internal error: `error`"#
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_monitor() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_search_request(
+            "cn=Search,cn=Operations,cn=Monitor",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["monitorOpCompleted"],
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request))
+                .await,
+            Some(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Search,cn=Operations,cn=Monitor".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "monitorOpCompleted".to_string(),
+                        vals: vec!["1".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_monitor_not_admin() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+        );
+        let request = make_search_request(
+            "cn=Monitor",
+            LdapFilter::Present("objectClass".to_string()),
+            Vec::<String>::new(),
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous sessions can only read the root DSE and the subschema, bind first"
+                    .to_string()
+            )]
+        );
+    }
+
+    fn make_test_schema() -> LdapSchemaConfig {
+        use crate::infra::configuration::CustomAttributeConfig;
+        LdapSchemaConfig {
+            object_classes: vec!["eduPerson".to_string()],
+            attributes: vec![CustomAttributeConfig {
+                name: "eduPersonAffiliation".to_string(),
+                oid: Some("1.3.6.1.4.1.5923.1.1.1.1".to_string()),
+                single_value: false,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_users_custom_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![
+                RequestFilter::AttributeEquality(
+                    "eduPersonAffiliation".to_string(),
+                    "staff".to_string(),
+                ),
+                RequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_get_user_attributes()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| {
+                let mut attributes = HashMap::new();
+                attributes.insert(
+                    "eduPersonAffiliation".to_string(),
+                    vec!["member".to_string(), "staff".to_string()],
+                );
+                Ok(attributes)
+            });
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_schema(make_test_schema());
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("edupersonaffiliation".to_string(), "staff".to_string()),
+                LdapFilter::Equality("objectClass".to_string(), "eduPerson".to_string()),
+            ]),
+            vec!["eduPersonAffiliation"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "eduPersonAffiliation".to_string(),
+                        vals: vec!["member".to_string(), "staff".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_subschema() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new())
+            .await
+            .with_schema(make_test_schema());
+        let request = make_search_request(
+            "cn=subschema",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["attributeTypes", "objectClasses"],
+        );
+        let results = ldap_handler.do_search(&request).await;
+        assert_eq!(results.len(), 2);
+        let attributes = match &results[0] {
+            LdapOp::SearchResultEntry(entry) => &entry.attributes,
+            op => panic!("Unexpected result: {:?}", op),
+        };
+        let values = |name: &str| {
+            attributes
+                .iter()
+                .find(|a| a.atype == name)
+                .unwrap()
+                .vals
+                .clone()
+        };
+        // The custom definitions follow the standard ones.
+        let attribute_types = values("attributeTypes");
+        assert_eq!(attribute_types.len(), STANDARD_ATTRIBUTE_TYPES.len() + 1);
+        assert!(attribute_types[0].starts_with("( 2.5.4.0 NAME 'objectClass' "));
+        assert_eq!(
+            attribute_types.last().unwrap(),
+            "( 1.3.6.1.4.1.5923.1.1.1.1 NAME 'eduPersonAffiliation' \
+             SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )"
+        );
+        let object_classes = values("objectClasses");
+        assert!(object_classes.contains(
+            &"( 2.5.6.17 NAME 'groupOfUniqueNames' SUP top STRUCTURAL \
+              MUST ( uniqueMember $ cn ) MAY ( description $ member $ mail ) )"
+                .to_string()
+        ));
+        assert_eq!(
+            object_classes.last().unwrap(),
+            "( eduPerson-oid NAME 'eduPerson' SUP top AUXILIARY \
+             MAY ( eduPersonAffiliation ) )"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_subschema_before_bind() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+        );
+        let request = make_search_request(
+            "cn=Subschema",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["objectClasses"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                subschema_response(&LdapSchemaConfig::default()),
+                make_search_success()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users_host() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: "jim".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_get_user_hosts()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(vec!["web.example.com".to_string()]));
+        mock.expect_get_user_hosts()
+            .with(eq("jim"))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "host"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "host".to_string(),
+                            vals: vec!["web.example.com".to_string()]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "host".to_string(),
+                            vals: vec![]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![
+                Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec!["bob".to_string(), "john".to_string()],
+                },
+                Group {
+                    id: GroupId(3),
+                    display_name: "bestgroup".to_string(),
+                    users: vec!["john".to_string()],
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["objectClass", "dn", "cn", "uniqueMember"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["groupOfUniqueNames".to_string(), "posixGroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec![
+                                "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                                "cn=john,ou=people,dc=example,dc=com".to_string(),
+                            ]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["groupOfUniqueNames".to_string(), "posixGroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["bestgroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec!["cn=john,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_member_range() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups().times(3).returning(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "group_1".to_string(),
+                users: vec!["bob".to_string(), "jim".to_string(), "john".to_string()],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        for (attribute, atype, users) in vec![
+            ("member;range=0-1", "member;range=0-1", vec!["bob", "jim"]),
+            ("member;range=2-3", "member;range=2-*", vec!["john"]),
+            (
+                "uniqueMember;Range=1-*",
+                "uniqueMember;range=1-*",
+                vec!["jim", "john"],
+            ),
+        ] {
+            let request = make_search_request(
+                "ou=groups,dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec![attribute],
+            );
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![
+                    LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                        dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                        attributes: vec![LdapPartialAttribute {
+                            atype: atype.to_string(),
+                            vals: users
+                                .into_iter()
+                                .map(|u| format!("cn={},ou=people,dc=example,dc=com", u))
+                                .collect(),
+                        }],
+                    }),
+                    make_search_success(),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_users_mapped_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![
+                RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
+                RequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    first_name: "Bob".to_string(),
+                    last_name: "Bobberson".to_string(),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_get_user_hosts()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    "a.example.com".to_string(),
+                    "b.example.com".to_string(),
+                ])
+            });
+        let mapping = LdapAttributeMappingConfig {
+            user: vec![
+                ("sAMAccountName", "{uid}"),
+                ("homeDirectory", "/home/{uid}"),
+                ("loginShell", "/bin/bash"),
+                ("cn", "{givenName} {sn}"),
+                ("hostName", "{host}"),
+            ]
+            .into_iter()
+            .map(|(name, template)| (name.to_string(), template.to_string()))
+            .collect(),
+            ..Default::default()
+        };
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_attribute_mapping(&mapping)
+            .with_attribute_casing(LdapAttributeCasing::Canonical);
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("samaccountname".to_string(), "bob".to_string()),
+                LdapFilter::Present("loginShell".to_string()),
+            ]),
+            vec![
+                "samaccountname",
+                "homedirectory",
+                "loginShell",
+                "cn",
+                "hostName",
+                "uid",
+            ],
+        );
+        let attribute = |atype: &str, vals: Vec<&str>| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vals.into_iter().map(str::to_string).collect(),
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        attribute("sAMAccountName", vec!["bob"]),
+                        attribute("homeDirectory", vec!["/home/bob"]),
+                        attribute("loginShell", vec!["/bin/bash"]),
+                        attribute("cn", vec!["Bob Bobberson"]),
+                        attribute("hostName", vec!["a.example.com", "b.example.com"]),
+                        attribute("uid", vec!["bob"]),
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+        // Only the aliases can be filtered on.
+        let request = make_user_search_request(
+            LdapFilter::Equality("homeDirectory".to_string(), "/home/bob".to_string()),
+            vec!["uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported user filter: The mapped attribute homeDirectory can't be filtered on"
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_mapped_attributes() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Member("bob".to_string()))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec!["bob".to_string()],
+                }])
+            });
+        let mut mapping = LdapAttributeMappingConfig::default();
+        mapping
+            .group
+            .insert("member".to_string(), "{memberUid}".to_string());
+        mapping
+            .group
+            .insert("description".to_string(), "Group {cn}".to_string());
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_attribute_mapping(&mapping);
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Equality("member".to_string(), "bob".to_string()),
+            vec!["member", "memberUid", "uniqueMember", "description"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "member".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec!["cn=bob,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "description".to_string(),
+                            vals: vec!["Group group_1".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_posix_users() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![
+                RequestFilter::And(vec![]),
+                RequestFilter::Equality("uid_number".to_string(), "10001".to_string()),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    uid_number: 10001,
+                    ..Default::default()
+                }])
+            });
+        let posix = PosixConfig {
+            home_directory: "/home/users/{uid}".to_string(),
+            login_shell: "/bin/zsh".to_string(),
+            ..Default::default()
+        };
+        let mut ldap_handler = setup_bound_handler(mock).await.with_posix(posix);
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixAccount".to_string()),
+                LdapFilter::Equality("uidNumber".to_string(), "10001".to_string()),
+            ]),
+            vec![
+                "uid",
+                "uidNumber",
+                "gidNumber",
+                "homeDirectory",
+                "loginShell",
+            ],
+        );
+        let attribute = |atype: &str, vals: Vec<&str>| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vals.into_iter().map(str::to_string).collect(),
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        attribute("uid", vec!["bob"]),
+                        attribute("uidNumber", vec!["10001"]),
+                        attribute("gidNumber", vec!["10001"]),
+                        attribute("homeDirectory", vec!["/home/users/bob"]),
+                        attribute("loginShell", vec!["/bin/zsh"]),
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_posix_groups() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::GroupId(GroupId(2)),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(2),
+                    display_name: "group_2".to_string(),
+                    users: vec!["bob".to_string(), "john".to_string()],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "posixGroup".to_string()),
+                LdapFilter::Equality("gidNumber".to_string(), "20002".to_string()),
+            ]),
+            vec!["cn", "gidNumber", "memberUid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_2,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_2".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "gidNumber".to_string(),
+                            vals: vec!["20002".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberUid".to_string(),
+                            vals: vec!["bob".to_string(), "john".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attribute_template() {
+        let template = AttributeTemplate::parse("/home/{uid}/{sn}").unwrap();
+        let get_attribute = |name: &str| {
+            Ok(match name {
+                "uid" => vec!["bob".to_string()],
+                _ => vec![],
+            })
+        };
+        assert_eq!(template.alias(), None);
+        assert_eq!(template.attributes().collect::<Vec<_>>(), vec!["uid", "sn"]);
+        assert_eq!(template.expand(get_attribute).unwrap(), vec!["/home/bob/"]);
+        let template = AttributeTemplate::parse("{uid}").unwrap();
+        assert_eq!(template.alias(), Some("uid"));
+        assert_eq!(
+            AttributeTemplate::parse("/bin/bash")
+                .unwrap()
+                .expand(get_attribute)
+                .unwrap(),
+            vec!["/bin/bash"]
+        );
+        AttributeTemplate::parse("{uid").unwrap_err();
+        AttributeTemplate::parse("uid}").unwrap_err();
+        AttributeTemplate::parse("{}").unwrap_err();
+        AttributeTemplate::parse("{{uid}}").unwrap_err();
+    }
+
+    #[test]
+    fn test_split_range_option() {
+        assert_eq!(split_range_option("member").unwrap(), ("member", None));
+        assert_eq!(
+            split_range_option("member;range=10-19").unwrap(),
+            (
+                "member",
+                Some(ValueRange {
+                    start: 10,
+                    end: Some(19)
+                })
+            )
+        );
+        assert!(split_range_option("member;range=10-9").is_err());
+        assert!(split_range_option("member;range=a-*").is_err());
+        assert!(split_range_option("member;binary").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::Member("bob".to_string()),
+                GroupRequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "group_1".to_string(),
+                    users: vec!["bob".to_string()],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality(
+                    "uniqueMember".to_string(),
+                    "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                ),
+                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
+            ]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["group_1".to_string()]
+                    },],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_by_name() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::Or(vec![
+                    GroupRequestFilter::DisplayName("admins".to_string()),
+                    GroupRequestFilter::Not(Box::new(GroupRequestFilter::GroupId(GroupId(1)))),
+                ]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(2),
+                    display_name: "admins".to_string(),
+                    users: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "groupOfNames".to_string()),
+                LdapFilter::Or(vec![
+                    LdapFilter::Equality("cn".to_string(), "admins".to_string()),
+                    LdapFilter::Not(Box::new(LdapFilter::Equality(
+                        "gidNumber".to_string(),
+                        "20001".to_string(),
+                    ))),
+                ]),
+            ]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["admins".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_by_mail() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::Mail("Relatives@example.com".to_string()),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "family".to_string(),
+                    users: vec!["bob".to_string()],
+                }])
+            });
+        mock.expect_get_group_mail()
+            .with(eq(GroupId(1)))
+            .times(1)
+            .return_once(|_| {
+                Ok(GroupMail {
+                    email: Some("family@example.com".to_string()),
+                    aliases: vec!["relatives@example.com".to_string()],
+                })
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "groupOfUniqueNames".to_string()),
+                LdapFilter::Equality("mail".to_string(), "Relatives@example.com".to_string()),
+            ]),
+            vec!["mail", "member"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=family,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![
+                                "family@example.com".to_string(),
+                                "relatives@example.com".to_string()
+                            ]
                         },
                         LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec!["2014-07-08T09:10:11+00:00".to_string()]
-                        }
+                            atype: "member".to_string(),
+                            vals: vec!["cn=bob,ou=people,dc=example,dc=com".to_string()]
+                        },
                     ],
                 }),
                 make_search_success(),
@@ -1042,73 +4009,52 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_search_groups() {
+    async fn test_search_groups_metadata() {
+        use chrono::TimeZone;
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups().times(1).return_once(|| {
-            Ok(vec![
-                Group {
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
                     id: GroupId(1),
-                    display_name: "group_1".to_string(),
-                    users: vec!["bob".to_string(), "john".to_string()],
-                },
-                Group {
-                    id: GroupId(3),
-                    display_name: "bestgroup".to_string(),
-                    users: vec!["john".to_string()],
-                },
-            ])
-        });
+                    display_name: "family".to_string(),
+                    users: vec![],
+                }])
+            });
+        mock.expect_get_group_metadata()
+            .with(eq(GroupId(1)))
+            .times(1)
+            .return_once(|_| {
+                Ok(GroupMetadata {
+                    description: Some("The Smiths".to_string()),
+                    creation_date: chrono::Utc.ymd(2021, 5, 3).and_hms(14, 30, 0),
+                    uuid: "698e1d5f-7a40-3151-8745-b9b8a37839da".to_string(),
+                })
+            });
         let mut ldap_handler = setup_bound_handler(mock).await;
         let request = make_search_request(
             "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![]),
-            vec!["objectClass", "dn", "cn", "uniqueMember"],
+            LdapFilter::Equality("objectClass".to_string(), "groupOfUniqueNames".to_string()),
+            vec!["description", "entryUUID", "createTimestamp"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["group_1".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec![
-                                "cn=bob,ou=people,dc=example,dc=com".to_string(),
-                                "cn=john,ou=people,dc=example,dc=com".to_string(),
-                            ]
-                        },
-                    ],
-                }),
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "cn=family,ou=groups,dc=example,dc=com".to_string(),
                     attributes: vec![
                         LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
+                            atype: "description".to_string(),
+                            vals: vec!["The Smiths".to_string()]
                         },
                         LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["bestgroup".to_string()]
+                            atype: "entryUUID".to_string(),
+                            vals: vec!["698e1d5f-7a40-3151-8745-b9b8a37839da".to_string()]
                         },
                         LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec!["cn=john,ou=people,dc=example,dc=com".to_string()]
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["2021-05-03T14:30:00+00:00".to_string()]
                         },
                     ],
                 }),
@@ -1118,52 +4064,203 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_search_groups_filter() {
+    async fn test_search_substring_filters() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_get_user_groups()
-            .with(eq("bob"))
-            .times(1)
-            .return_once(|_| {
-                let mut set = HashSet::new();
-                set.insert(GroupIdAndName(GroupId(1), "group_1".to_string()));
-                Ok(set)
-            });
         mock.expect_list_users()
-            .with(eq(Some(RequestFilter::MemberOfId(GroupId(1)))))
+            .with(eq(Some(RequestFilter::And(vec![
+                RequestFilter::And(vec![]),
+                RequestFilter::Substring(
+                    "display_name".to_string(),
+                    SubstringFilter {
+                        initial: Some("jo".to_string()),
+                        any: vec!["h".to_string()],
+                        final_: None,
+                    },
+                ),
+            ]))))
             .times(1)
             .return_once(|_| {
                 Ok(vec![User {
-                    user_id: "bob".to_string(),
+                    user_id: "john".to_string(),
                     ..Default::default()
                 }])
             });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayNameSubstring(
+                SubstringFilter {
+                    initial: None,
+                    any: vec!["MIL".to_string()],
+                    final_: None,
+                },
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "Family".to_string(),
+                    users: vec![],
+                }])
+            });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
+        let request = make_user_search_request(
             LdapFilter::And(vec![
-                LdapFilter::Equality(
-                    "uniqueMember".to_string(),
-                    "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+                LdapFilter::Substring(
+                    "cn".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("jo".to_string()),
+                        any: vec!["h".to_string()],
+                        final_: None,
+                    },
                 ),
-                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
             ]),
+            vec!["uid"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=john,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["john".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Substring(
+                "cn".to_string(),
+                LdapSubstringFilter {
+                    initial: None,
+                    any: vec!["MIL".to_string()],
+                    final_: None,
+                },
+            ),
             vec!["cn"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "cn=Family,ou=groups,dc=example,dc=com".to_string(),
                     attributes: vec![LdapPartialAttribute {
                         atype: "cn".to_string(),
-                        vals: vec!["group_1".to_string()]
-                    },],
+                        vals: vec!["Family".to_string()]
+                    }],
                 }),
                 make_search_success(),
             ]
         );
     }
 
+    #[tokio::test]
+    async fn test_search_paged_results() {
+        let mut mock = MockTestBackendHandler::new();
+        // The default pagination reads all the users for each page.
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![]))))
+            .times(2)
+            .returning(|_| {
+                Ok(["bob", "jim", "john"]
+                    .iter()
+                    .map(|user_id| User {
+                        user_id: user_id.to_string(),
+                        ..Default::default()
+                    })
+                    .collect())
+            });
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(1),
+                display_name: "family".to_string(),
+                users: vec!["bob".to_string()],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request("dc=example,dc=com", LdapFilter::And(vec![]), vec!["cn"]);
+
+        async fn search_page(
+            ldap_handler: &mut LdapHandler<MockTestBackendHandler>,
+            request: &LdapSearchRequest,
+            cookie: Vec<u8>,
+        ) -> (Vec<String>, Vec<u8>) {
+            let results = ldap_handler
+                .handle_ldap_message_with_controls(
+                    LdapOp::SearchRequest(request.clone()),
+                    vec![LdapControl::SimplePagedResults { size: 2, cookie }],
+                )
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+            let (done, controls) = results.last().unwrap().clone();
+            assert_eq!(done, make_search_success());
+            let cookie = match controls.as_slice() {
+                [LdapControl::SimplePagedResults { cookie, .. }] => cookie.clone(),
+                _ => panic!("Unexpected controls: {:?}", controls),
+            };
+            let dns = results
+                .into_iter()
+                .filter_map(|(op, _)| match op {
+                    LdapOp::SearchResultEntry(entry) => Some(entry.dn),
+                    _ => None,
+                })
+                .collect();
+            (dns, cookie)
+        }
+
+        let (dns, cookie) = search_page(&mut ldap_handler, &request, Vec::new()).await;
+        assert_eq!(
+            dns,
+            vec![
+                "cn=bob,ou=people,dc=example,dc=com",
+                "cn=jim,ou=people,dc=example,dc=com"
+            ]
+        );
+        assert_eq!(cookie, b"u2".to_vec());
+        let (dns, cookie) = search_page(&mut ldap_handler, &request, cookie).await;
+        assert_eq!(
+            dns,
+            vec![
+                "cn=john,ou=people,dc=example,dc=com",
+                "cn=family,ou=groups,dc=example,dc=com"
+            ]
+        );
+        assert!(cookie.is_empty());
+
+        // An invalid cookie ends the search.
+        let results = ldap_handler
+            .handle_ldap_message_with_controls(
+                LdapOp::SearchRequest(request),
+                vec![LdapControl::SimplePagedResults {
+                    size: 2,
+                    cookie: b"x1".to_vec(),
+                }],
+            )
+            .unwrap()
+            .map(|(op, _)| op)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            results,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Invalid paged results cookie".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_page_cursor() {
+        for cursor in vec![PageCursor::Users(0), PageCursor::Groups(120)] {
+            assert_eq!(PageCursor::from_cookie(&cursor.to_cookie()), Some(cursor));
+        }
+        assert_eq!(PageCursor::from_cookie(b"u"), None);
+        assert_eq!(PageCursor::from_cookie(b"12"), None);
+    }
+
     #[tokio::test]
     async fn test_search_filters() {
         let mut mock = MockTestBackendHandler::new();
@@ -1202,6 +4299,43 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_search_entry_uuid() {
+        let uuid = "0b7c7e1e-7a6b-4a4b-9a5e-8f2d0c1b3a4d";
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::Equality(
+                "uuid".to_string(),
+                uuid.to_string(),
+            ))))
+            .times(1)
+            .return_once(move |_| {
+                Ok(vec![User {
+                    user_id: "bob".to_string(),
+                    uuid: uuid.to_string(),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality("entryUUID".to_string(), uuid.to_string()),
+            vec!["entryUUID"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "entryUUID".to_string(),
+                        vals: vec![uuid.to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_search_member_of() {
         let mut mock = MockTestBackendHandler::new();
@@ -1233,18 +4367,193 @@ mod tests {
             )]
         );
         let request = make_user_search_request(
-            LdapFilter::Equality(
-                "memberOf".to_string(),
-                "cn=mygroup,dc=example,dc=com".to_string(),
-            ),
-            vec!["objectClass"],
+            LdapFilter::Equality(
+                "memberOf".to_string(),
+                "cn=mygroup,dc=example,dc=com".to_string(),
+            ),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported user filter: Unexpected group DN format. Got \"cn=mygroup,dc=example,dc=com\", expected: \"cn=groupname,ou=groups,dc=example,dc=com\"".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_locked_users() {
+        use chrono::TimeZone;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: "jim".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_get_user_lock()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(None));
+        mock.expect_get_user_lock()
+            .with(eq("jim"))
+            .times(1)
+            .return_once(|_| {
+                Ok(Some(UserLock {
+                    locked_at: chrono::Utc.ymd(2021, 5, 3).and_hms(14, 30, 0),
+                    reason: "Left the company".to_string(),
+                }))
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request =
+            make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "pwdAccountLockedTime"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "pwdAccountLockedTime".to_string(),
+                            vals: vec![]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "pwdAccountLockedTime".to_string(),
+                            vals: vec!["20210503143000Z".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_jpeg_photo() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_get_user_avatar()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(Some(vec![0xff, 0xd8, 0xff, 0xe0])));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "jpegPhoto"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "jpegPhoto".to_string(),
+                            vals: vec!["/9j/4A==".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_member_of_attribute() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    User {
+                        user_id: "bob".to_string(),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: "jim".to_string(),
+                        ..Default::default()
+                    },
+                ])
+            });
+        // The groups of all the users are fetched in a single call.
+        mock.expect_get_users_groups()
+            .with(eq(vec!["bob".to_string(), "jim".to_string()]))
+            .times(1)
+            .return_once(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupIdAndName(GroupId(2), "lldap_admin".to_string()));
+                set.insert(GroupIdAndName(GroupId(1), "group_1".to_string()));
+                let mut groups = HashMap::new();
+                groups.insert("bob".to_string(), set);
+                groups.insert("jim".to_string(), HashSet::new());
+                Ok(groups)
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+            vec!["uid", "memberOf"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: Unexpected group DN format. Got \"cn=mygroup,dc=example,dc=com\", expected: \"cn=groupname,ou=groups,dc=example,dc=com\"".to_string()
-            )]
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberOf".to_string(),
+                            vals: vec![
+                                "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                                "cn=lldap_admin,ou=groups,dc=example,dc=com".to_string(),
+                            ]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberOf".to_string(),
+                            vals: vec![]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
         );
     }
 
@@ -1292,6 +4601,34 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_search_filters_alias() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(RequestFilter::And(vec![
+                RequestFilter::Equality("user_id".to_string(), "bob".to_string()),
+                RequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut aliases = HashMap::new();
+        aliases.insert("employeeNumber".to_string(), "user_id".to_string());
+        let mut ldap_handler = setup_bound_handler(mock)
+            .await
+            .with_attribute_aliases(&aliases);
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("employeenumber".to_string(), "bob".to_string()),
+                LdapFilter::Present("employeeNumber".to_string()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
     #[tokio::test]
     async fn test_search_both() {
         let mut mock = MockTestBackendHandler::new();
@@ -1305,7 +4642,7 @@ mod tests {
                 ..Default::default()
             }])
         });
-        mock.expect_list_groups().times(1).return_once(|| {
+        mock.expect_list_groups().times(1).return_once(|_| {
             Ok(vec![Group {
                 id: GroupId(1),
                 display_name: "group_1".to_string(),
@@ -1348,7 +4685,7 @@ mod tests {
                     attributes: vec![
                         LdapPartialAttribute {
                             atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
+                            vals: vec!["groupOfUniqueNames".to_string(), "posixGroup".to_string()]
                         },
                         LdapPartialAttribute {
                             atype: "dn".to_string(),
@@ -1383,24 +4720,19 @@ mod tests {
     async fn test_search_unsupported_filters() {
         let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
         let request = make_user_search_request(
-            LdapFilter::Substring(
-                "uid".to_string(),
-                ldap3_server::proto::LdapSubstringFilter::default(),
-            ),
+            LdapFilter::Substring("memberOf".to_string(), LdapSubstringFilter::default()),
             vec!["objectClass"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![make_search_error(
                 LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: Unsupported user filter: Substring(\"uid\", LdapSubstringFilter { initial: None, any: [], final_: None })".to_string()
+                "Unsupported user filter: Unsupported user filter: Substring(\"memberOf\", LdapSubstringFilter { initial: None, any: [], final_: None })".to_string()
             )]
         );
     }
 
-    #[tokio::test]
-    async fn test_password_change() {
-        let mut mock = MockTestBackendHandler::new();
+    fn expect_password_registration(mock: &mut MockTestBackendHandler) {
         use lldap_auth::*;
         let mut rng = rand::rngs::OsRng;
         let registration_start_request =
@@ -1415,6 +4747,10 @@ mod tests {
             &request.username,
         )
         .unwrap();
+        mock.expect_is_password_reused()
+            .with(eq("bob"), eq("password"))
+            .times(1)
+            .return_once(|_, _| Ok(false));
         mock.expect_registration_start().times(1).return_once(|_| {
             Ok(registration::ServerRegistrationStartResponse {
                 server_data: "".to_string(),
@@ -1424,6 +4760,12 @@ mod tests {
         mock.expect_registration_finish()
             .times(1)
             .return_once(|_| Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_password_change() {
+        let mut mock = MockTestBackendHandler::new();
+        expect_password_registration(&mut mock);
         let mut ldap_handler = setup_bound_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
@@ -1442,6 +4784,210 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_password_change_of_bound_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: "test".to_string(),
+                password: "wrong".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Err(DomainError::AuthenticationError("wrong".to_string())));
+        mock.expect_record_auth_failure()
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        // Without user identity, the password of the bound user is changed.
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: None,
+                old_password: Some("wrong".to_string()),
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InvalidCredentials,
+                "Wrong old password".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_policy() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_is_password_reused()
+            .with(eq("bob"), eq("password1"))
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let mut ldap_handler =
+            setup_bound_handler(mock)
+                .await
+                .with_password_policy(PasswordPolicyConfig {
+                    require_digit: true,
+                    ..Default::default()
+                });
+        let request = |password: &str| {
+            LdapOp::ExtendedRequest(
+                LdapPasswordModifyRequest {
+                    user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+                    old_password: None,
+                    new_password: Some(password.to_string()),
+                }
+                .into(),
+            )
+        };
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request("password")).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "Invalid input: `The password doesn't follow the password policy, it needs: a \
+                 digit`"
+                    .to_string(),
+            )])
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request("password1")).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::ConstraintViolation,
+                "The password was used recently, choose another one".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_change_read_only() {
+        // No registration expected: the change is rejected before reaching the backend.
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new())
+            .await
+            .with_read_only(true);
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "This LDAP listener is read-only".to_string(),
+            )])
+        );
+    }
+
+    async fn setup_handler_bound_with_group(
+        mut mock: MockTestBackendHandler,
+        group: &str,
+    ) -> LdapHandler<MockTestBackendHandler> {
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: "app".to_string(),
+                password: "pass".to_string(),
+            }))
+            .return_once(|_| Ok(()));
+        let group = group.to_string();
+        mock.expect_get_user_groups()
+            .with(eq("app"))
+            .times(1)
+            .return_once(move |_| {
+                let mut set = HashSet::new();
+                set.insert(GroupIdAndName(GroupId(2), group));
+                Ok(set)
+            });
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), "admin".to_string());
+        let request = LdapBindRequest {
+            dn: "cn=app,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        ldap_handler
+    }
+
+    #[tokio::test]
+    async fn test_readonly_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: "bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_handler_bound_with_group(mock, "lldap_strict_readonly").await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["bob".to_string()]
+                    }],
+                }),
+                make_search_success(),
+            ]
+        );
+        // No registration expected: the change is rejected before reaching the backend.
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("cn=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Current user `cn=app,ou=people,dc=example,dc=com` is not allowed to change the \
+                 password of bob"
+                    .to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_manager_cannot_change_admin_password() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq("admin"))
+            .times(1)
+            .return_once(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
+                Ok(set)
+            });
+        let mut ldap_handler = setup_handler_bound_with_group(mock, "lldap_password_manager").await;
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("cn=admin,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::InsufficentAccessRights,
+                "Current user `cn=app,ou=people,dc=example,dc=com` is not allowed to change the \
+                 password of admin"
+                    .to_string(),
+            )])
+        );
+    }
+
     #[tokio::test]
     async fn test_password_change_errors() {
         let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
@@ -1457,7 +5003,7 @@ mod tests {
             ldap_handler.handle_ldap_message(request).await,
             Some(vec![make_extended_response(
                 LdapResultCode::ConstraintViolation,
-                "Missing either user_id or password".to_string(),
+                "Missing the new password: generating one is not supported".to_string(),
             )])
         );
         let request = LdapOp::ExtendedRequest(
@@ -1488,6 +5034,50 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_start_tls() {
+        let start_tls_request = || {
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: START_TLS_OID.to_string(),
+                value: None,
+            })
+        };
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(start_tls_request()).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Unavailable,
+                "StartTLS is not configured on this server".to_string(),
+            )])
+        );
+        assert!(!ldap_handler.take_start_tls_request());
+
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new())
+            .await
+            .with_start_tls(true);
+        let response = ldap_handler.handle_ldap_message(start_tls_request()).await;
+        assert!(matches!(
+            response.as_deref(),
+            Some([LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    ..
+                },
+                name: Some(name),
+                ..
+            })]) if name == START_TLS_OID
+        ));
+        assert!(ldap_handler.take_start_tls_request());
+        assert!(!ldap_handler.take_start_tls_request());
+        assert_eq!(
+            ldap_handler.handle_ldap_message(start_tls_request()).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::OperationsError,
+                "TLS is already established".to_string(),
+            )])
+        );
+    }
+
     #[tokio::test]
     async fn test_search_root_dse() {
         let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
@@ -1504,9 +5094,39 @@ mod tests {
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
-                root_dse_response("dc=example,dc=com"),
+                root_dse_response("dc=example,dc=com", false),
                 make_search_success()
             ]
         );
     }
+
+    #[tokio::test]
+    async fn test_search_root_dse_before_bind() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            "admin".to_string(),
+        );
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectclass".to_string()),
+            attrs: vec!["namingContexts".to_string()],
+        };
+        let results = ldap_handler.do_search(&request).await;
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            LdapOp::SearchResultEntry(entry) => {
+                assert!(entry.attributes.contains(&LdapPartialAttribute {
+                    atype: "namingContexts".to_string(),
+                    vals: vec!["dc=example,dc=com".to_string()],
+                }))
+            }
+            op => panic!("Unexpected result: {:?}", op),
+        }
+    }
 }
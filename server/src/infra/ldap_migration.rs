@@ -0,0 +1,415 @@
+//! Copies the users, groups and memberships of an existing LDAP server (e.g. OpenLDAP) to lldap.
+//!
+//! Password hashes can't be converted to the OPAQUE password files lldap stores, so only the
+//! passwords stored in clear text on the source server are imported. The report lists the users
+//! that will need a password reset.
+
+use crate::{
+    domain::{
+        handler::{BackendHandler, CreateUserRequest},
+        sql_backend_handler::SqlBackendHandler,
+        sql_opaque_handler::register_password,
+    },
+    infra::cli::MigrateOpts,
+};
+use anyhow::{bail, Context, Result};
+use ldap3::{
+    adapters::{Adapter, EntriesOnly, PagedResults},
+    LdapConnAsync, Scope, SearchEntry,
+};
+use std::collections::{BTreeSet, HashMap};
+use tracing::{info, warn};
+
+/// A user read from the source server.
+#[derive(Debug, PartialEq, Eq)]
+struct SourceUser {
+    dn: String,
+    request: CreateUserRequest,
+    /// The password, if it was stored in clear text.
+    password: Option<String>,
+}
+
+/// A group read from the source server, with the user IDs of its members.
+#[derive(Debug, PartialEq, Eq)]
+struct SourceGroup {
+    name: String,
+    members: BTreeSet<String>,
+}
+
+/// What was (or, for a dry run, would be) imported.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub users_created: Vec<String>,
+    pub groups_created: Vec<String>,
+    pub memberships_added: usize,
+    pub passwords_imported: usize,
+    /// Users created without a password, because it was hashed or missing.
+    pub users_without_password: Vec<String>,
+    /// Entries that were not imported, with the reason.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Users created: {}", self.users_created.len())?;
+        writeln!(f, "Groups created: {}", self.groups_created.len())?;
+        writeln!(f, "Memberships added: {}", self.memberships_added)?;
+        writeln!(f, "Passwords imported: {}", self.passwords_imported)?;
+        if !self.users_without_password.is_empty() {
+            writeln!(
+                f,
+                "Users without a password, that need a password reset ({}):",
+                self.users_without_password.len()
+            )?;
+            for user in &self.users_without_password {
+                writeln!(f, "  - {}", user)?;
+            }
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "Skipped entries ({}):", self.skipped.len())?;
+            for (entry, reason) in &self.skipped {
+                writeln!(f, "  - {}: {}", entry, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gets the first value of the attribute, ignoring the case of the attribute name.
+fn get_attribute<'a>(entry: &'a SearchEntry, attribute: &str) -> Option<&'a String> {
+    get_attributes(entry, attribute).next()
+}
+
+fn get_attributes<'a>(
+    entry: &'a SearchEntry,
+    attribute: &'a str,
+) -> impl Iterator<Item = &'a String> + 'a {
+    entry
+        .attrs
+        .iter()
+        .filter(move |(name, _)| name.eq_ignore_ascii_case(attribute))
+        .flat_map(|(_, values)| values.iter())
+}
+
+/// Returns the password if it is stored in clear text: either without a scheme, or with the
+/// `{CLEARTEXT}` one.
+fn clear_text_password(value: &str) -> Option<&str> {
+    if let Some(rest) = value.strip_prefix('{') {
+        let (scheme, password) = rest.split_at(rest.find('}')?);
+        if scheme.eq_ignore_ascii_case("cleartext") {
+            Some(&password[1..])
+        } else {
+            None
+        }
+    } else {
+        Some(value)
+    }
+}
+
+fn user_from_entry(entry: &SearchEntry) -> Result<SourceUser> {
+    let user_id = get_attribute(entry, "uid").context("missing uid")?;
+    let email = get_attribute(entry, "mail").context("missing mail")?;
+    Ok(SourceUser {
+        dn: entry.dn.clone(),
+        request: CreateUserRequest {
+            user_id: user_id.clone(),
+            email: email.clone(),
+            display_name: get_attribute(entry, "displayName")
+                .or_else(|| get_attribute(entry, "cn"))
+                .cloned(),
+            first_name: get_attribute(entry, "givenName").cloned(),
+            last_name: get_attribute(entry, "sn").cloned(),
+        },
+        password: get_attributes(entry, "userPassword")
+            .find_map(|p| clear_text_password(p))
+            .map(str::to_string),
+    })
+}
+
+/// Reads the group members from the `member`/`uniqueMember` DNs and the `memberUid` IDs. The DNs
+/// that don't belong to a user are ignored.
+fn group_from_entry(
+    entry: &SearchEntry,
+    dn_to_user_id: &HashMap<String, String>,
+) -> Result<SourceGroup> {
+    let name = get_attribute(entry, "cn").context("missing cn")?;
+    let members = get_attributes(entry, "member")
+        .chain(get_attributes(entry, "uniqueMember"))
+        .filter_map(|dn| dn_to_user_id.get(&dn.to_lowercase()).cloned())
+        .chain(get_attributes(entry, "memberUid").cloned())
+        .collect();
+    Ok(SourceGroup {
+        name: name.clone(),
+        members,
+    })
+}
+
+async fn search(
+    ldap: &mut ldap3::Ldap,
+    base: &str,
+    filter: &str,
+    attributes: Vec<&str>,
+) -> Result<Vec<SearchEntry>> {
+    // Page the results, the source server probably has a size limit.
+    let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
+        Box::new(EntriesOnly::new()),
+        Box::new(PagedResults::new(500)),
+    ];
+    let mut search = ldap
+        .streaming_search_with(adapters, base, Scope::Subtree, filter, attributes)
+        .await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = search.next().await? {
+        entries.push(SearchEntry::construct(entry));
+    }
+    search.finish().await.success()?;
+    Ok(entries)
+}
+
+async fn read_source(opts: &MigrateOpts) -> Result<(Vec<SearchEntry>, Vec<SearchEntry>)> {
+    let (connection, mut ldap) = LdapConnAsync::new(&opts.from)
+        .await
+        .with_context(|| format!("while connecting to {}", opts.from))?;
+    ldap3::drive!(connection);
+    if let Some(bind_dn) = &opts.bind_dn {
+        ldap.simple_bind(bind_dn, &opts.bind_password)
+            .await?
+            .success()
+            .with_context(|| format!("while binding as {}", bind_dn))?;
+    }
+    let users = search(
+        &mut ldap,
+        &opts.users_dn,
+        &opts.user_filter,
+        vec![
+            "uid",
+            "mail",
+            "cn",
+            "displayName",
+            "givenName",
+            "sn",
+            "userPassword",
+        ],
+    )
+    .await
+    .context("while reading the users")?;
+    let groups = search(
+        &mut ldap,
+        &opts.groups_dn,
+        &opts.group_filter,
+        vec!["cn", "member", "uniqueMember", "memberUid"],
+    )
+    .await
+    .context("while reading the groups")?;
+    ldap.unbind().await?;
+    Ok((users, groups))
+}
+
+async fn import(
+    handler: &SqlBackendHandler,
+    users: Vec<SourceUser>,
+    groups: Vec<SourceGroup>,
+    dry_run: bool,
+    report: &mut MigrationReport,
+) -> Result<()> {
+    let existing_users = handler
+        .list_users(None)
+        .await?
+        .into_iter()
+        .map(|u| u.user_id)
+        .collect::<BTreeSet<_>>();
+    let mut imported_users = BTreeSet::new();
+    for user in users {
+        let user_id = user.request.user_id.clone();
+        if existing_users.contains(&user_id) {
+            report
+                .skipped
+                .push((user.dn, "user already exists".to_string()));
+            continue;
+        }
+        if !dry_run {
+            if let Err(e) = handler.create_user(user.request).await {
+                report.skipped.push((user.dn, format!("{:#}", e)));
+                continue;
+            }
+        }
+        match user.password {
+            Some(password) => {
+                if !dry_run {
+                    if let Err(e) = register_password(handler, &user_id, &password).await {
+                        warn!("Could not set the password of {}: {:#}", user_id, e);
+                        report.users_without_password.push(user_id.clone());
+                    } else {
+                        report.passwords_imported += 1;
+                    }
+                } else {
+                    report.passwords_imported += 1;
+                }
+            }
+            None => report.users_without_password.push(user_id.clone()),
+        }
+        report.users_created.push(user_id.clone());
+        imported_users.insert(user_id);
+    }
+
+    let existing_groups = handler
+        .list_groups(None)
+        .await?
+        .into_iter()
+        .map(|g| (g.display_name, (g.id, g.users)))
+        .collect::<HashMap<_, _>>();
+    for group in groups {
+        let (group_id, current_members) = match existing_groups.get(&group.name) {
+            Some((id, users)) => (Some(*id), users.iter().cloned().collect()),
+            None => {
+                report.groups_created.push(group.name.clone());
+                let id = if dry_run {
+                    None
+                } else {
+                    Some(handler.create_group(&group.name).await?)
+                };
+                (id, BTreeSet::new())
+            }
+        };
+        for member in group.members.difference(&current_members) {
+            if !imported_users.contains(member) && !existing_users.contains(member) {
+                report.skipped.push((
+                    format!("{} in {}", member, group.name),
+                    "unknown member".to_string(),
+                ));
+                continue;
+            }
+            if let Some(group_id) = group_id {
+                handler.add_user_to_group(member, group_id).await?;
+            }
+            report.memberships_added += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the source server and populates the database, returning what was imported.
+pub async fn migrate(handler: &SqlBackendHandler, opts: &MigrateOpts) -> Result<MigrationReport> {
+    let (user_entries, group_entries) = read_source(opts).await?;
+    info!(
+        "Read {} users and {} groups from {}",
+        user_entries.len(),
+        group_entries.len(),
+        opts.from
+    );
+    if user_entries.is_empty() && group_entries.is_empty() {
+        bail!("No users or groups found, check the DNs and filters");
+    }
+    let mut report = MigrationReport::default();
+    let mut users = Vec::new();
+    for entry in &user_entries {
+        match user_from_entry(entry) {
+            Ok(user) => users.push(user),
+            Err(e) => report.skipped.push((entry.dn.clone(), format!("{:#}", e))),
+        }
+    }
+    let dn_to_user_id = users
+        .iter()
+        .map(|u| (u.dn.to_lowercase(), u.request.user_id.clone()))
+        .collect::<HashMap<_, _>>();
+    let mut groups = Vec::new();
+    for entry in &group_entries {
+        match group_from_entry(entry, &dn_to_user_id) {
+            Ok(group) => groups.push(group),
+            Err(e) => report.skipped.push((entry.dn.clone(), format!("{:#}", e))),
+        }
+    }
+    import(handler, users, groups, opts.dry_run, &mut report).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_entry(dn: &str, attrs: Vec<(&str, Vec<&str>)>) -> SearchEntry {
+        SearchEntry {
+            dn: dn.to_string(),
+            attrs: attrs
+                .into_iter()
+                .map(|(name, values)| {
+                    (
+                        name.to_string(),
+                        values.into_iter().map(str::to_string).collect(),
+                    )
+                })
+                .collect(),
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_clear_text_password() {
+        assert_eq!(clear_text_password("s3cr3t"), Some("s3cr3t"));
+        assert_eq!(clear_text_password("{CLEARTEXT}s3cr3t"), Some("s3cr3t"));
+        assert_eq!(clear_text_password("{SSHA}aGFzaGVk"), None);
+        assert_eq!(clear_text_password("{broken"), None);
+    }
+
+    #[test]
+    fn test_user_from_entry() {
+        let entry = make_entry(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                ("uid", vec!["bob"]),
+                ("mail", vec!["bob@example.com"]),
+                ("cn", vec!["Bob Bobberson"]),
+                ("givenName", vec!["Bob"]),
+                ("SN", vec!["Bobberson"]),
+                ("userPassword", vec!["{SSHA}aGFzaGVk"]),
+            ],
+        );
+        assert_eq!(
+            user_from_entry(&entry).unwrap(),
+            SourceUser {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                request: CreateUserRequest {
+                    user_id: "bob".to_string(),
+                    email: "bob@example.com".to_string(),
+                    display_name: Some("Bob Bobberson".to_string()),
+                    first_name: Some("Bob".to_string()),
+                    last_name: Some("Bobberson".to_string()),
+                },
+                password: None,
+            }
+        );
+        user_from_entry(&make_entry("uid=john", vec![("uid", vec!["john"])])).unwrap_err();
+    }
+
+    #[test]
+    fn test_group_from_entry() {
+        let mut dn_to_user_id = HashMap::new();
+        dn_to_user_id.insert(
+            "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            "bob".to_string(),
+        );
+        let entry = make_entry(
+            "cn=family,ou=groups,dc=example,dc=com",
+            vec![
+                ("cn", vec!["family"]),
+                (
+                    "member",
+                    vec![
+                        "uid=Bob,ou=people,dc=example,dc=com",
+                        "cn=other,ou=groups,dc=example,dc=com",
+                    ],
+                ),
+                ("memberUid", vec!["john"]),
+            ],
+        );
+        assert_eq!(
+            group_from_entry(&entry, &dn_to_user_id).unwrap(),
+            SourceGroup {
+                name: "family".to_string(),
+                members: vec!["bob".to_string(), "john".to_string()]
+                    .into_iter()
+                    .collect(),
+            }
+        );
+    }
+}
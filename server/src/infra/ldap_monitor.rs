@@ -0,0 +1,384 @@
+//! Operational statistics of the LDAP server, exposed under `cn=Monitor` with the same layout and
+//! attributes as OpenLDAP's monitor backend, so that existing monitoring scripts keep working.
+
+use ldap3_server::proto::{
+    LdapFilter, LdapOp, LdapPartialAttribute, LdapSearchRequest, LdapSearchResultEntry,
+    LdapSearchScope,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The LDAP operations, as named in the `cn=Operations,cn=Monitor` subtree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LdapOperation {
+    Bind,
+    Unbind,
+    Search,
+    Extended,
+    Other,
+}
+
+const OPERATIONS: [LdapOperation; 5] = [
+    LdapOperation::Bind,
+    LdapOperation::Unbind,
+    LdapOperation::Search,
+    LdapOperation::Extended,
+    LdapOperation::Other,
+];
+
+impl LdapOperation {
+    fn name(&self) -> &'static str {
+        match self {
+            LdapOperation::Bind => "Bind",
+            LdapOperation::Unbind => "Unbind",
+            LdapOperation::Search => "Search",
+            LdapOperation::Extended => "Extended",
+            LdapOperation::Other => "Other",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Stats {
+    start_time: chrono::DateTime<chrono::Utc>,
+    total_connections: AtomicU64,
+    current_connections: AtomicU64,
+    operations: [AtomicU64; OPERATIONS.len()],
+    bind_failures: AtomicU64,
+}
+
+/// The statistics shared by all the LDAP connections.
+#[derive(Clone, Debug)]
+pub struct LdapMonitor(Arc<Stats>);
+
+impl Default for LdapMonitor {
+    fn default() -> Self {
+        Self(Arc::new(Stats {
+            start_time: chrono::Utc::now(),
+            total_connections: AtomicU64::new(0),
+            current_connections: AtomicU64::new(0),
+            operations: Default::default(),
+            bind_failures: AtomicU64::new(0),
+        }))
+    }
+}
+
+/// Counts a connection as open until dropped.
+pub struct ConnectionGuard(LdapMonitor);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        (self.0)
+            .0
+            .current_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the DN is in the `cn=Monitor` subtree.
+pub fn is_monitor_dn(dn: &str) -> bool {
+    let dn = normalize_dn(dn);
+    dn == "cn=monitor" || dn.ends_with(",cn=monitor")
+}
+
+fn normalize_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|part| part.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn to_generalized_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%Y%m%d%H%M%SZ").to_string()
+}
+
+fn make_entry(dn: &str, attributes: Vec<(&str, String)>) -> LdapSearchResultEntry {
+    let cn = dn.split(',').next().unwrap().trim_start_matches("cn=");
+    LdapSearchResultEntry {
+        dn: dn.to_string(),
+        attributes: std::iter::once(("cn", cn.to_string()))
+            .chain(attributes)
+            .map(|(name, value)| LdapPartialAttribute {
+                atype: name.to_string(),
+                vals: vec![value],
+            })
+            .collect(),
+    }
+}
+
+fn container(dn: &str) -> LdapSearchResultEntry {
+    make_entry(dn, vec![("objectClass", "monitorContainer".to_string())])
+}
+
+fn counter(dn: &str, value: u64) -> LdapSearchResultEntry {
+    make_entry(
+        dn,
+        vec![
+            ("objectClass", "monitorCounterObject".to_string()),
+            ("monitorCounter", value.to_string()),
+        ],
+    )
+}
+
+fn operation(dn: &str, count: u64) -> LdapSearchResultEntry {
+    // Operations are handled one at a time, so they are completed as soon as initiated.
+    make_entry(
+        dn,
+        vec![
+            ("objectClass", "monitorOperation".to_string()),
+            ("monitorOpInitiated", count.to_string()),
+            ("monitorOpCompleted", count.to_string()),
+        ],
+    )
+}
+
+fn matches_filter(entry: &LdapSearchResultEntry, filter: &LdapFilter) -> bool {
+    let values = |attribute: &str| {
+        entry
+            .attributes
+            .iter()
+            .filter(|a| a.atype.eq_ignore_ascii_case(attribute))
+            .flat_map(|a| a.vals.iter())
+            .collect::<Vec<_>>()
+    };
+    match filter {
+        LdapFilter::And(filters) => filters.iter().all(|f| matches_filter(entry, f)),
+        LdapFilter::Or(filters) => filters.iter().any(|f| matches_filter(entry, f)),
+        LdapFilter::Not(filter) => !matches_filter(entry, filter),
+        LdapFilter::Equality(attribute, value) => values(attribute)
+            .iter()
+            .any(|v| v.eq_ignore_ascii_case(value)),
+        LdapFilter::Present(attribute) => {
+            attribute.eq_ignore_ascii_case("objectClass") || !values(attribute).is_empty()
+        }
+        _ => false,
+    }
+}
+
+impl LdapMonitor {
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.0.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.0.current_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    pub fn record_operation(&self, operation: LdapOperation) {
+        let index = OPERATIONS.iter().position(|o| *o == operation).unwrap();
+        self.0.operations[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bind_failure(&self) {
+        self.0.bind_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn entries(&self) -> Vec<LdapSearchResultEntry> {
+        let stats = &self.0;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let now = chrono::Utc::now();
+        let operation_counts = stats.operations.iter().map(load).collect::<Vec<_>>();
+        let mut entries = vec![
+            make_entry(
+                "cn=Monitor",
+                vec![
+                    ("objectClass", "monitorServer".to_string()),
+                    (
+                        "monitoredInfo",
+                        format!("LLDAP {}", env!("CARGO_PKG_VERSION")),
+                    ),
+                ],
+            ),
+            container("cn=Connections,cn=Monitor"),
+            counter(
+                "cn=Total,cn=Connections,cn=Monitor",
+                load(&stats.total_connections),
+            ),
+            counter(
+                "cn=Current,cn=Connections,cn=Monitor",
+                load(&stats.current_connections),
+            ),
+            operation("cn=Operations,cn=Monitor", operation_counts.iter().sum()),
+        ];
+        entries.extend(OPERATIONS.iter().zip(operation_counts).map(|(o, count)| {
+            operation(&format!("cn={},cn=Operations,cn=Monitor", o.name()), count)
+        }));
+        entries.extend(vec![
+            container("cn=Statistics,cn=Monitor"),
+            counter(
+                "cn=Bind Failures,cn=Statistics,cn=Monitor",
+                load(&stats.bind_failures),
+            ),
+            container("cn=Time,cn=Monitor"),
+            make_entry(
+                "cn=Start,cn=Time,cn=Monitor",
+                vec![
+                    ("objectClass", "monitoredObject".to_string()),
+                    ("monitorTimestamp", to_generalized_time(stats.start_time)),
+                ],
+            ),
+            make_entry(
+                "cn=Current,cn=Time,cn=Monitor",
+                vec![
+                    ("objectClass", "monitoredObject".to_string()),
+                    ("monitorTimestamp", to_generalized_time(now)),
+                ],
+            ),
+            make_entry(
+                "cn=Uptime,cn=Time,cn=Monitor",
+                vec![
+                    ("objectClass", "monitoredObject".to_string()),
+                    (
+                        "monitoredInfo",
+                        (now - stats.start_time).num_seconds().to_string(),
+                    ),
+                ],
+            ),
+        ]);
+        entries
+    }
+
+    /// Answers a search request whose base is in the `cn=Monitor` subtree.
+    pub fn search(&self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        let base = normalize_dn(&request.base);
+        let in_scope = |dn: &str| {
+            let dn = normalize_dn(dn);
+            match request.scope {
+                LdapSearchScope::Base => dn == base,
+                LdapSearchScope::OneLevel => dn
+                    .strip_suffix(&base)
+                    .and_then(|prefix| prefix.strip_suffix(','))
+                    .map(|rdn| !rdn.contains(','))
+                    .unwrap_or(false),
+                LdapSearchScope::Subtree => dn == base || dn.ends_with(&format!(",{}", base)),
+            }
+        };
+        // The monitor attributes are operational in OpenLDAP: return everything for "+" too.
+        let all_attributes =
+            request.attrs.is_empty() || request.attrs.iter().any(|a| a == "*" || a == "+");
+        self.entries()
+            .into_iter()
+            .filter(|e| in_scope(&e.dn) && matches_filter(e, &request.filter))
+            .map(|mut e| {
+                if !all_attributes {
+                    e.attributes.retain(|a| {
+                        request
+                            .attrs
+                            .iter()
+                            .any(|r| r.eq_ignore_ascii_case(&a.atype))
+                    });
+                }
+                LdapOp::SearchResultEntry(e)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::LdapDerefAliases;
+
+    fn make_request(base: &str, scope: LdapSearchScope, attrs: Vec<&str>) -> LdapSearchRequest {
+        LdapSearchRequest {
+            base: base.to_string(),
+            scope,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: attrs.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    fn get_dns(ops: Vec<LdapOp>) -> Vec<String> {
+        ops.into_iter()
+            .map(|op| match op {
+                LdapOp::SearchResultEntry(e) => e.dn,
+                op => panic!("Unexpected op: {:?}", op),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_is_monitor_dn() {
+        assert!(is_monitor_dn("cn=monitor"));
+        assert!(is_monitor_dn("cn=Total, cn=Connections, cn=Monitor"));
+        assert!(!is_monitor_dn("cn=monitor,dc=example,dc=com"));
+        assert!(!is_monitor_dn("cn=notmonitor"));
+    }
+
+    #[test]
+    fn test_search_scopes() {
+        let monitor = LdapMonitor::default();
+        assert_eq!(
+            get_dns(monitor.search(&make_request(
+                "cn=connections,cn=monitor",
+                LdapSearchScope::OneLevel,
+                vec![]
+            ))),
+            vec![
+                "cn=Total,cn=Connections,cn=Monitor",
+                "cn=Current,cn=Connections,cn=Monitor"
+            ]
+        );
+        assert_eq!(
+            get_dns(monitor.search(&make_request(
+                "cn=Time,cn=Monitor",
+                LdapSearchScope::Subtree,
+                vec![]
+            ))),
+            vec![
+                "cn=Time,cn=Monitor",
+                "cn=Start,cn=Time,cn=Monitor",
+                "cn=Current,cn=Time,cn=Monitor",
+                "cn=Uptime,cn=Time,cn=Monitor"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        let monitor = LdapMonitor::default();
+        {
+            let _connection = monitor.connection_opened();
+            let _other_connection = monitor.connection_opened();
+        }
+        let _connection = monitor.connection_opened();
+        monitor.record_operation(LdapOperation::Bind);
+        monitor.record_operation(LdapOperation::Bind);
+        monitor.record_operation(LdapOperation::Search);
+        monitor.record_bind_failure();
+        let get_value = |base: &str, attribute: &str| match &monitor.search(&make_request(
+            base,
+            LdapSearchScope::Base,
+            vec![attribute],
+        ))[..]
+        {
+            [LdapOp::SearchResultEntry(e)] => e.attributes[0].vals[0].clone(),
+            ops => panic!("Unexpected ops: {:?}", ops),
+        };
+        assert_eq!(
+            get_value("cn=Total,cn=Connections,cn=Monitor", "monitorCounter"),
+            "3"
+        );
+        assert_eq!(
+            get_value("cn=Current,cn=Connections,cn=Monitor", "monitorCounter"),
+            "1"
+        );
+        assert_eq!(
+            get_value("cn=Bind,cn=Operations,cn=Monitor", "monitorOpCompleted"),
+            "2"
+        );
+        assert_eq!(
+            get_value("cn=Operations,cn=Monitor", "monitorOpInitiated"),
+            "3"
+        );
+        assert_eq!(
+            get_value(
+                "cn=Bind Failures,cn=Statistics,cn=Monitor",
+                "monitorCounter"
+            ),
+            "1"
+        );
+    }
+}
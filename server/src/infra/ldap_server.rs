@@ -3,91 +3,305 @@ use crate::{
         handler::{BackendHandler, LoginHandler},
         opaque_handler::OpaqueHandler,
     },
-    infra::{configuration::Configuration, ldap_handler::LdapHandler},
+    infra::{
+        audit_backend_handler::with_audit_context,
+        bind_throttle::BindThrottle,
+        configuration::{Configuration, LdapTlsConfig},
+        health::Readiness,
+        ldap_handler::LdapHandler,
+        ldap_monitor::LdapMonitor,
+        metrics::Metrics,
+        shutdown::Shutdown,
+        socket_activation::InheritedListeners,
+    },
 };
 use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
 use anyhow::{bail, Context, Result};
 use futures_util::future::ok;
-use ldap3_server::{proto::LdapMsg, LdapCodec};
-use log::*;
-use tokio::net::tcp::WriteHalf;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use ldap3_server::{
+    proto::{LdapExtendedResponse, LdapMsg, LdapOp, LdapResult, LdapResultCode},
+    LdapCodec,
+};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+};
+use tokio_native_tls::TlsAcceptor;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info_span, Instrument};
+use uuid::Uuid;
+
+const NOTICE_OF_DISCONNECTION_OID: &str = "1.3.6.1.4.1.1466.20036";
 
-async fn handle_incoming_message<Backend>(
+async fn handle_incoming_message<Backend, Stream>(
     msg: Result<LdapMsg, std::io::Error>,
-    resp: &mut FramedWrite<WriteHalf<'_>, LdapCodec>,
+    resp: &mut Framed<Stream, LdapCodec>,
     session: &mut LdapHandler<Backend>,
+    operation_limit: Option<&Semaphore>,
 ) -> Result<bool>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler,
+    Stream: AsyncRead + AsyncWrite + Unpin,
 {
-    use futures_util::SinkExt;
+    use futures_util::{SinkExt, StreamExt};
     let msg = msg.context("while receiving LDAP op")?;
-    debug!("Received LDAP message: {:?}", &msg);
-    match session.handle_ldap_message(msg.op).await {
-        None => return Ok(false),
-        Some(result) => {
-            if result.is_empty() {
-                debug!("No response");
-            }
-            for result_op in result.into_iter() {
+    let msgid = msg.msgid;
+    // Like the HTTP requests, for the logs of the operation to be found together.
+    let span = info_span!("ldap_operation", request_id = %Uuid::new_v4(), message_id = msgid);
+    async move {
+        debug!("Received LDAP message: {:?}", &msg);
+        // Wait for a free slot, kept until the whole response is sent.
+        let _permit = match operation_limit {
+            Some(semaphore) => Some(semaphore.acquire().await?),
+            None => None,
+        };
+        // The changes made by the operation are attributed to the user bound before it.
+        let audit_context = session.audit_context();
+        let mut results = match session.handle_ldap_message_with_controls(msg.op, msg.ctrl) {
+            None => return Ok(false),
+            Some(results) => results,
+        };
+        with_audit_context(audit_context, async move {
+            // Send the results as they come, e.g. while the rest of the users are read from the
+            // database.
+            let mut got_result = false;
+            while let Some((result_op, result_ctrl)) = results.next().await {
+                got_result = true;
                 debug!("Replying with LDAP op: {:?}", &result_op);
-                resp.send(LdapMsg {
-                    msgid: msg.msgid,
+                resp.feed(LdapMsg {
+                    msgid,
                     op: result_op,
-                    ctrl: vec![],
+                    ctrl: result_ctrl,
                 })
                 .await
                 .context("while sending a response: {:#}")?
             }
-
+            if !got_result {
+                debug!("No response");
+            }
             if let Err(e) = resp.flush().await {
                 bail!("Error while flushing responses: {:?}", e);
             }
+            Ok(true)
+        })
+        .await
+    }
+    .instrument(span)
+    .await
+}
+
+/// The unsolicited notification of RFC 4511 (section 4.4.1) sent before closing the connection,
+/// for the client to reconnect instead of seeing a reset.
+fn notice_of_disconnection() -> LdapMsg {
+    LdapMsg {
+        msgid: 0,
+        op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Unavailable,
+                matcheddn: "".to_string(),
+                message: "The server is shutting down".to_string(),
+                referral: vec![],
+            },
+            name: Some(NOTICE_OF_DISCONNECTION_OID.to_string()),
+            value: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+/// Handles the LDAP operations of the connection until it is closed, the server shuts down, or the
+/// client asks for StartTLS: the stream is then returned, to be upgraded.
+async fn handle_connection<Backend, Stream>(
+    stream: Stream,
+    session: &mut LdapHandler<Backend>,
+    operation_limit: Option<&Semaphore>,
+    shutdown: &mut Shutdown,
+) -> Result<Option<Stream>>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    use futures_util::{SinkExt, StreamExt};
+    let mut framed = Framed::new(stream, LdapCodec);
+    loop {
+        // The operation in progress is finished before looking at the shutdown.
+        let msg = tokio::select! {
+            msg = framed.next() => match msg {
+                Some(msg) => msg,
+                None => return Ok(None),
+            },
+            _ = shutdown.wait() => {
+                framed
+                    .send(notice_of_disconnection())
+                    .await
+                    .context("while sending the notice of disconnection")?;
+                return Ok(None);
+            }
+        };
+        if !handle_incoming_message(msg, &mut framed, session, operation_limit).await? {
+            return Ok(None);
+        }
+        if session.take_start_tls_request() {
+            let parts = framed.into_parts();
+            // The client waits for the response before starting the handshake.
+            if !parts.read_buf.is_empty() {
+                bail!("Received plain text data after the StartTLS request");
+            }
+            return Ok(Some(parts.io));
         }
     }
-    Ok(true)
+}
+
+/// Loads the certificate for StartTLS.
+fn get_tls_acceptor(config: &LdapTlsConfig) -> Result<TlsAcceptor> {
+    let cert = std::fs::read(&config.cert_file)
+        .with_context(|| format!("Could not read the certificate `{}`", config.cert_file))?;
+    let key = std::fs::read(&config.key_file)
+        .with_context(|| format!("Could not read the private key `{}`", config.key_file))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .context("Invalid LDAP certificate or private key")?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
 }
 
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    bind_throttle: BindThrottle,
+    metrics: Metrics,
+    readiness: Readiness,
+    shutdown: Shutdown,
     server_builder: ServerBuilder,
+    listeners: &mut InheritedListeners,
 ) -> Result<ServerBuilder>
 where
     Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
 {
-    use futures_util::StreamExt;
-
     let ldap_base_dn = config.ldap_base_dn.clone();
     let ldap_user_dn = config.ldap_user_dn.clone();
-    Ok(
-        server_builder.bind("ldap", ("0.0.0.0", config.ldap_port), move || {
+    let attribute_profile = config.ldap_attribute_profile;
+    let attribute_casing = config.ldap_attribute_casing;
+    let user_order = config.ldap_user_order;
+    let anonymous_access = config.ldap_anonymous_access;
+    let attribute_aliases = config.ldap_attribute_aliases.clone();
+    let attribute_mapping = config.ldap_attribute_mapping.clone();
+    let posix = config.posix.clone();
+    let schema = config.ldap_schema.clone();
+    let password_policy = config.password_policy.clone();
+    let monitor = LdapMonitor::default();
+    let tls_acceptor = config.ldap_tls.as_ref().map(get_tls_acceptor).transpose()?;
+    // Shared by all the connections, across the workers.
+    let operation_limit = match config.ldap_max_concurrent_operations {
+        0 => None,
+        limit => Some(Arc::new(Semaphore::new(limit))),
+    };
+    // One factory per listener, sharing the monitor, the metrics, the bind throttling and the
+    // operation limit.
+    let make_factory = |read_only: bool| {
+        let backend_handler = backend_handler.clone();
+        let ldap_base_dn = ldap_base_dn.clone();
+        let ldap_user_dn = ldap_user_dn.clone();
+        let monitor = monitor.clone();
+        let metrics = metrics.clone();
+        let bind_throttle = bind_throttle.clone();
+        let operation_limit = operation_limit.clone();
+        let attribute_aliases = attribute_aliases.clone();
+        let attribute_mapping = attribute_mapping.clone();
+        let posix = posix.clone();
+        let schema = schema.clone();
+        let password_policy = password_policy.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let readiness = readiness.clone();
+        let shutdown = shutdown.clone();
+        move || {
+            // Called by each worker, when it starts accepting the connections.
+            readiness.set_ldap_listening();
             let backend_handler = backend_handler.clone();
             let ldap_base_dn = ldap_base_dn.clone();
             let ldap_user_dn = ldap_user_dn.clone();
-            fn_service(move |mut stream: TcpStream| {
+            let monitor = monitor.clone();
+            let metrics = metrics.clone();
+            let bind_throttle = bind_throttle.clone();
+            let operation_limit = operation_limit.clone();
+            let attribute_aliases = attribute_aliases.clone();
+            let attribute_mapping = attribute_mapping.clone();
+            let posix = posix.clone();
+            let schema = schema.clone();
+            let password_policy = password_policy.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            let shutdown = shutdown.clone();
+            fn_service(move |stream: TcpStream| {
                 let backend_handler = backend_handler.clone();
                 let ldap_base_dn = ldap_base_dn.clone();
                 let ldap_user_dn = ldap_user_dn.clone();
+                let monitor = monitor.clone();
+                let metrics = metrics.clone();
+                let bind_throttle = bind_throttle.clone();
+                let operation_limit = operation_limit.clone();
+                let attribute_aliases = attribute_aliases.clone();
+                let attribute_mapping = attribute_mapping.clone();
+                let posix = posix.clone();
+                let schema = schema.clone();
+                let password_policy = password_policy.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                let mut shutdown = shutdown.clone();
+                let source_ip = stream.peer_addr().ok().map(|address| address.ip());
+                let span = info_span!(
+                    "ldap_connection",
+                    connection_id = %Uuid::new_v4(),
+                    client_ip = ?source_ip
+                );
                 async move {
-                    // Configure the codec etc.
-                    let (r, w) = stream.split();
-                    let mut requests = FramedRead::new(r, LdapCodec);
-                    let mut resp = FramedWrite::new(w, LdapCodec);
+                    let _connection = monitor.connection_opened();
+                    let _metrics_connection = metrics.ldap_connection_opened();
 
-                    let mut session = LdapHandler::new(backend_handler, ldap_base_dn, ldap_user_dn);
+                    let mut session = LdapHandler::new(backend_handler, ldap_base_dn, ldap_user_dn)
+                        .with_attribute_profile(attribute_profile)
+                        .with_attribute_casing(attribute_casing)
+                        .with_user_order(user_order)
+                        .with_anonymous_access(anonymous_access)
+                        .with_attribute_aliases(&attribute_aliases)
+                        .with_attribute_mapping(&attribute_mapping)
+                        .with_posix(posix)
+                        .with_schema(schema)
+                        .with_monitor(monitor)
+                        .with_metrics(metrics)
+                        .with_bind_throttle(bind_throttle)
+                        .with_password_policy(password_policy)
+                        .with_source_ip(source_ip)
+                        .with_read_only(read_only)
+                        .with_start_tls(tls_acceptor.is_some());
 
-                    while let Some(msg) = requests.next().await {
-                        if !handle_incoming_message(msg, &mut resp, &mut session).await? {
-                            break;
-                        }
-                    }
-
-                    Ok(stream)
+                    let stream = match handle_connection(
+                        stream,
+                        &mut session,
+                        operation_limit.as_deref(),
+                        &mut shutdown,
+                    )
+                    .await?
+                    {
+                        Some(stream) => stream,
+                        None => return Ok(()),
+                    };
+                    // The session only accepts StartTLS with an acceptor.
+                    let tls_stream = tls_acceptor
+                        .expect("StartTLS without a certificate")
+                        .accept(stream)
+                        .await
+                        .context("while upgrading the connection with StartTLS")?;
+                    // A second StartTLS is refused by the session: the stream can't come back.
+                    handle_connection(
+                        tls_stream,
+                        &mut session,
+                        operation_limit.as_deref(),
+                        &mut shutdown,
+                    )
+                    .await?;
+                    Ok(())
                 }
+                .instrument(span)
             })
             .map_err(|err: anyhow::Error| error!("Service Error: {:?}", err))
             // catch
@@ -95,6 +309,20 @@ where
                 // finally
                 ok(())
             })
-        })?,
-    )
+        }
+    };
+    let factory = make_factory(config.ldap_read_only);
+    let server_builder = match listeners.take(config.ldap_port) {
+        Some(listener) => server_builder.listen("ldap", listener, factory)?,
+        None => server_builder.bind("ldap", ("0.0.0.0", config.ldap_port), factory)?,
+    };
+    if config.ldap_read_only_port == 0 {
+        return Ok(server_builder);
+    }
+    let port = config.ldap_read_only_port;
+    let factory = make_factory(true);
+    Ok(match listeners.take(port) {
+        Some(listener) => server_builder.listen("ldap_read_only", listener, factory)?,
+        None => server_builder.bind("ldap_read_only", ("0.0.0.0", port), factory)?,
+    })
 }
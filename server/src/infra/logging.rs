@@ -1,25 +1,118 @@
-use crate::infra::configuration::Configuration;
+//! The logs, with `tracing`: the HTTP requests (see `TracingLogger` in `tcp_server.rs`) and the
+//! LDAP operations (see `ldap_server.rs`) open a span with a `request_id`, which the logs of the
+//! backend handlers are nested in.
+
+use crate::infra::configuration::{Configuration, LogFormat};
 use anyhow::Context;
 use tracing::subscriber::set_global_default;
 use tracing_log::LogTracer;
+use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Registry};
 
-pub fn init(config: Configuration) -> anyhow::Result<()> {
-    let max_log_level = log_level_from_config(config);
-    let subscriber = tracing_subscriber::fmt()
-        .with_timer(tracing_subscriber::fmt::time::time())
-        .with_target(false)
-        .with_level(true)
-        .with_max_level(max_log_level)
-        .finish();
+fn log_filter(config: &Configuration) -> anyhow::Result<EnvFilter> {
+    let directives = if !config.log_filter.is_empty() {
+        config.log_filter.as_str()
+    } else if config.verbose {
+        "debug"
+    } else {
+        "info"
+    };
+    EnvFilter::try_new(directives)
+        .with_context(|| format!("Invalid log_filter `{}`", config.log_filter))
+}
+
+fn install(config: &Configuration, export_traces: bool) -> anyhow::Result<()> {
+    let (text, json) = match config.log_format {
+        LogFormat::Text => (
+            Some(
+                fmt::layer()
+                    .with_timer(fmt::time::time())
+                    .with_target(false)
+                    .with_level(true),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            ),
+        ),
+    };
+    let subscriber = Registry::default()
+        .with(log_filter(config)?)
+        .with(text)
+        .with(json);
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(match (&config.otlp_endpoint, export_traces) {
+        (Some(endpoint), true) => Some(otlp_layer(endpoint)?),
+        _ => None,
+    });
     LogTracer::init().context("Failed to set logger")?;
     set_global_default(subscriber).context("Failed to set subscriber")?;
+    if export_traces && config.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+        tracing::warn!("otlp_endpoint is ignored: lldap was built without the `otlp` feature");
+    }
     Ok(())
 }
 
-fn log_level_from_config(config: Configuration) -> tracing::Level {
-    if config.verbose {
-        tracing::Level::DEBUG
-    } else {
-        tracing::Level::INFO
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(
+    endpoint: &str,
+) -> anyhow::Result<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry_otlp::WithExportConfig;
+    // The server runs on a single-threaded runtime: the spans are exported from another thread.
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .install_batch(opentelemetry::runtime::TokioCurrentThread)
+        .context("Failed to set up the OTLP export")?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Logs to stdout, for the commands other than `run`.
+pub fn init(config: Configuration) -> anyhow::Result<()> {
+    install(&config, false)
+}
+
+/// Logs to stdout, and exports the traces to `otlp_endpoint` if set. It has to be called in the
+/// Tokio runtime of the server.
+pub fn init_with_traces(config: &Configuration) -> anyhow::Result<()> {
+    install(config, true)
+}
+
+/// Sends the remaining traces, before the server stops.
+pub async fn shutdown() {
+    #[cfg(feature = "otlp")]
+    if let Err(e) =
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await
+    {
+        tracing::error!("Could not export the remaining traces: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter() {
+        let mut config = Configuration::default();
+        assert_eq!(log_filter(&config).unwrap().to_string(), "info");
+        config.verbose = true;
+        assert_eq!(log_filter(&config).unwrap().to_string(), "debug");
+        config.log_filter = "info,lldap::infra::ldap_handler=debug".to_string();
+        assert!(log_filter(&config).is_ok());
+        config.log_filter = "info,lldap=loud".to_string();
+        assert!(log_filter(&config).is_err());
     }
 }
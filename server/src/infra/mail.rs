@@ -0,0 +1,287 @@
+//! Sending emails to the users through the SMTP server of the `smtp` configuration.
+//!
+//! The emails are rendered from [Tera](https://tera.netlify.app/) templates, whose first line is
+//! the subject and the rest the body. The default ones can be replaced by files of the same name
+//! in `smtp.templates_dir`, e.g. `password_reset.txt`.
+
+use crate::infra::configuration::{SmtpConfig, SmtpEncryption};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::path::Path;
+
+/// The emails sent to the users.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmailTemplate {
+    /// With `display_name`, `user_id` and `link`.
+    PasswordReset,
+    /// With `display_name`, `user_id` and `link`, to choose the first password.
+    Invitation,
+    /// With `display_name`, `user_id` and `expires_at`.
+    PasswordExpiry,
+    /// With `display_name`, to check the configuration.
+    Test,
+}
+
+impl EmailTemplate {
+    const ALL: [EmailTemplate; 4] = [
+        EmailTemplate::PasswordReset,
+        EmailTemplate::Invitation,
+        EmailTemplate::PasswordExpiry,
+        EmailTemplate::Test,
+    ];
+
+    /// The name of the file replacing the default template.
+    fn file_name(self) -> &'static str {
+        match self {
+            EmailTemplate::PasswordReset => "password_reset.txt",
+            EmailTemplate::Invitation => "invitation.txt",
+            EmailTemplate::PasswordExpiry => "password_expiry.txt",
+            EmailTemplate::Test => "test.txt",
+        }
+    }
+
+    fn default_template(self) -> &'static str {
+        match self {
+            EmailTemplate::PasswordReset => {
+                "Password reset\n\
+                Hello {{ display_name }},\n\
+                A password reset was requested for your account. To choose a new password, follow \
+                this link:\n\
+                {{ link }}\n\n\
+                The link expires in 24 hours. If you did not request it, you can ignore this email."
+            }
+            EmailTemplate::Invitation => {
+                "Your new account\n\
+                Hello {{ display_name }},\n\
+                An account was created for you, with the user ID {{ user_id }}. To choose your \
+                password, follow this link:\n\
+                {{ link }}\n\n\
+                The link expires in 24 hours."
+            }
+            EmailTemplate::PasswordExpiry => {
+                "Your password is about to expire\n\
+                Hello {{ display_name }},\n\n\
+                The password of your account \"{{ user_id }}\" expires on {{ expires_at }}. Please \
+                log in to change it before then.\n"
+            }
+            EmailTemplate::Test => {
+                "Test email\n\
+                Hello {{ display_name }},\n\
+                This email was sent to check the SMTP configuration of LLDAP. It works!"
+            }
+        }
+    }
+
+    /// Placeholder values of the variables, to check the templates on startup.
+    fn sample_context(self) -> tera::Context {
+        let mut context = tera::Context::new();
+        context.insert("display_name", "John Doe");
+        context.insert("user_id", "john");
+        match self {
+            EmailTemplate::PasswordReset | EmailTemplate::Invitation => {
+                context.insert("link", "https://example.com/reset-password/step2/token")
+            }
+            EmailTemplate::PasswordExpiry => context.insert("expires_at", "2022-01-01 00:00 UTC"),
+            EmailTemplate::Test => {}
+        }
+        context
+    }
+}
+
+/// Returns the subject and the body of the email.
+fn render_email(
+    config: &SmtpConfig,
+    template: EmailTemplate,
+    context: &tera::Context,
+) -> Result<(String, String)> {
+    let source = match config
+        .templates_dir
+        .as_ref()
+        .map(|dir| Path::new(dir).join(template.file_name()))
+        .filter(|path| path.exists())
+    {
+        Some(path) => std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read the template `{}`", path.display()))?,
+        None => template.default_template().to_string(),
+    };
+    // Plain text, nothing to escape.
+    let email = tera::Tera::one_off(&source, context, false)
+        .with_context(|| format!("Invalid email template {}", template.file_name()))?;
+    match email.split_once('\n') {
+        Some((subject, body)) => Ok((subject.trim().to_string(), body.to_string())),
+        None => anyhow::bail!(
+            "The email template {} needs a subject and a body",
+            template.file_name()
+        ),
+    }
+}
+
+/// Renders all the templates with placeholder values, to find the errors before sending emails.
+pub fn check_templates(config: &SmtpConfig) -> Result<()> {
+    for template in EmailTemplate::ALL.iter() {
+        render_email(config, *template, &template.sample_context())?;
+    }
+    Ok(())
+}
+
+fn get_transport(config: &SmtpConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let builder = match config.encryption {
+        SmtpEncryption::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.server)
+        }
+        SmtpEncryption::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.server)?
+        }
+        SmtpEncryption::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server)?,
+    }
+    .port(config.port);
+    let builder = if config.user.is_empty() {
+        builder
+    } else {
+        builder.credentials(Credentials::new(
+            config.user.clone(),
+            config.password.clone(),
+        ))
+    };
+    Ok(builder.build())
+}
+
+/// Sends a plain text email to `to_name <to_email>`.
+pub async fn send_email(
+    config: &SmtpConfig,
+    to_name: &str,
+    to_email: &str,
+    subject: &str,
+    body: String,
+) -> Result<()> {
+    let from: Mailbox = config
+        .from
+        .parse()
+        .with_context(|| format!("Invalid smtp.from address: {}", config.from))?;
+    let to = Mailbox::new(
+        Some(to_name.to_string()).filter(|n| !n.is_empty()),
+        to_email
+            .parse()
+            .with_context(|| format!("Invalid email address: {}", to_email))?,
+    );
+    let email = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .body(body)?;
+    get_transport(config)?
+        .send(email)
+        .await
+        .with_context(|| format!("while sending an email to {}", to_email))?;
+    Ok(())
+}
+
+/// Renders the template with the variables of `context`, and sends it to `to_name <to_email>`.
+pub async fn send_template_email(
+    config: &SmtpConfig,
+    to_name: &str,
+    to_email: &str,
+    template: EmailTemplate,
+    context: &tera::Context,
+) -> Result<()> {
+    let (subject, body) = render_email(config, template, context)?;
+    send_email(config, to_name, to_email, &subject, body).await
+}
+
+/// The emails sent through the GraphQL API, which doesn't know about the configuration.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    /// Sends the [`EmailTemplate::Test`] email, failing with the error of the SMTP server.
+    async fn send_test_email(&self, to_name: &str, to_email: &str) -> Result<()>;
+}
+
+pub struct SmtpMailer {
+    config: Option<SmtpConfig>,
+}
+
+impl SmtpMailer {
+    pub fn new(config: Option<SmtpConfig>) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send_test_email(&self, to_name: &str, to_email: &str) -> Result<()> {
+        let config = self
+            .config
+            .as_ref()
+            .context("The smtp section isn't configured")?;
+        let mut context = tera::Context::new();
+        context.insert("display_name", to_name);
+        send_template_email(config, to_name, to_email, EmailTemplate::Test, &context).await
+    }
+}
+
+#[cfg(test)]
+mockall::mock! {
+    pub TestMailer{}
+    #[async_trait]
+    impl Mailer for TestMailer {
+        async fn send_test_email(&self, to_name: &str, to_email: &str) -> Result<()>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smtp_config(templates_dir: Option<String>) -> SmtpConfig {
+        SmtpConfig {
+            server: "smtp.example.com".to_string(),
+            port: 587,
+            encryption: SmtpEncryption::StartTls,
+            user: String::new(),
+            password: String::new(),
+            from: "LLDAP <lldap@example.com>".to_string(),
+            templates_dir,
+        }
+    }
+
+    #[test]
+    fn test_render_email() {
+        let mut context = tera::Context::new();
+        context.insert("display_name", "Bob");
+        context.insert("user_id", "bob");
+        context.insert("link", "https://lldap/reset-password/step2/abc");
+        let (subject, body) =
+            render_email(&smtp_config(None), EmailTemplate::Invitation, &context).unwrap();
+        assert_eq!(subject, "Your new account");
+        assert!(body.starts_with("Hello Bob,\n"));
+        assert!(body.contains("with the user ID bob."));
+        assert!(body.contains("https://lldap/reset-password/step2/abc"));
+        check_templates(&smtp_config(None)).unwrap();
+    }
+
+    #[test]
+    fn test_templates_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("test.txt"),
+            "Bonjour\nBonjour {{ display_name }} !",
+        )
+        .unwrap();
+        let config = smtp_config(Some(dir.path().to_str().unwrap().to_string()));
+        let (subject, body) = render_email(
+            &config,
+            EmailTemplate::Test,
+            &EmailTemplate::Test.sample_context(),
+        )
+        .unwrap();
+        assert_eq!(
+            (subject.as_str(), body.as_str()),
+            ("Bonjour", "Bonjour John Doe !")
+        );
+        std::fs::write(dir.path().join("invitation.txt"), "Hello {{ unknown }}").unwrap();
+        assert!(check_templates(&config).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+//! Prometheus metrics of the LDAP and HTTP servers and of the database, served at `/metrics` when
+//! lldap is built with the `metrics` feature. Without it, nothing is recorded, so that the default
+//! build doesn't pull in the Prometheus client.
+
+use crate::domain::sql_tables::Pool;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+#[cfg(feature = "metrics")]
+struct Inner {
+    registry: Registry,
+    sql_pool: Pool,
+    ldap_binds: IntCounterVec,
+    ldap_searches: IntCounter,
+    ldap_search_entries: Histogram,
+    ldap_connections: IntGauge,
+    http_requests: HistogramVec,
+    backend_operations: HistogramVec,
+    cache_lookups: IntCounterVec,
+    sql_pool_connections: IntGauge,
+    sql_pool_idle_connections: IntGauge,
+}
+
+#[cfg(feature = "metrics")]
+impl Inner {
+    fn new(sql_pool: Pool) -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let inner = Self {
+            sql_pool,
+            ldap_binds: IntCounterVec::new(
+                Opts::new("lldap_ldap_binds_total", "The LDAP binds, by result."),
+                &["result"],
+            )?,
+            ldap_searches: IntCounter::new("lldap_ldap_searches_total", "The LDAP searches.")?,
+            ldap_search_entries: Histogram::with_opts(
+                HistogramOpts::new(
+                    "lldap_ldap_search_result_entries",
+                    "The number of entries returned by the LDAP searches.",
+                )
+                .buckets(vec![
+                    0.0, 1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0,
+                ]),
+            )?,
+            ldap_connections: IntGauge::new(
+                "lldap_ldap_connections",
+                "The open LDAP connections.",
+            )?,
+            http_requests: HistogramVec::new(
+                HistogramOpts::new(
+                    "lldap_http_request_duration_seconds",
+                    "The duration of the HTTP requests, including GraphQL, by route.",
+                ),
+                &["route", "method", "status"],
+            )?,
+            backend_operations: HistogramVec::new(
+                HistogramOpts::new(
+                    "lldap_backend_operation_duration_seconds",
+                    "The duration of the database operations, by operation and result.",
+                ),
+                &["operation", "result"],
+            )?,
+            cache_lookups: IntCounterVec::new(
+                Opts::new(
+                    "lldap_cache_lookups_total",
+                    "The lookups in the cache of the users, by cache and result (hit or miss).",
+                ),
+                &["cache", "result"],
+            )?,
+            sql_pool_connections: IntGauge::new(
+                "lldap_sql_pool_connections",
+                "The connections of the database pool, idle or in use.",
+            )?,
+            sql_pool_idle_connections: IntGauge::new(
+                "lldap_sql_pool_idle_connections",
+                "The idle connections of the database pool.",
+            )?,
+            registry,
+        };
+        inner
+            .registry
+            .register(Box::new(inner.ldap_binds.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.ldap_searches.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.ldap_search_entries.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.ldap_connections.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.http_requests.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.backend_operations.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.cache_lookups.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.sql_pool_connections.clone()))?;
+        inner
+            .registry
+            .register(Box::new(inner.sql_pool_idle_connections.clone()))?;
+        Ok(inner)
+    }
+}
+
+/// The metrics shared by the servers. The default one records nothing, e.g. in the tests.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    #[cfg(feature = "metrics")]
+    inner: Option<Arc<Inner>>,
+}
+
+/// Counts an LDAP connection as open until dropped.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub struct ConnectionGuard(Metrics);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &(self.0).inner {
+            inner.ldap_connections.dec();
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+impl Metrics {
+    /// The metrics recorded by the servers, with the utilization of the database pool.
+    pub fn new(sql_pool: Pool) -> anyhow::Result<Self> {
+        Ok(Self {
+            #[cfg(feature = "metrics")]
+            inner: Some(Arc::new(Inner::new(sql_pool)?)),
+        })
+    }
+
+    pub fn ldap_connection_opened(&self) -> ConnectionGuard {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.ldap_connections.inc();
+        }
+        ConnectionGuard(self.clone())
+    }
+
+    pub fn record_ldap_bind(&self, success: bool) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .ldap_binds
+                .with_label_values(&[if success { "success" } else { "failure" }])
+                .inc();
+        }
+    }
+
+    pub fn record_ldap_search(&self, entries: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner.ldap_searches.inc();
+            inner.ldap_search_entries.observe(entries as f64);
+        }
+    }
+
+    /// `route` is the pattern of the route, e.g. `/api/user/{user_id}/avatar`, to keep the number
+    /// of series bounded.
+    pub fn record_http_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .http_requests
+                .with_label_values(&[route, method, &status.to_string()])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_backend_operation(&self, operation: &str, success: bool, duration: Duration) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .backend_operations
+                .with_label_values(&[operation, if success { "success" } else { "error" }])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_cache_lookup(&self, cache: &str, hit: bool) {
+        #[cfg(feature = "metrics")]
+        if let Some(inner) = &self.inner {
+            inner
+                .cache_lookups
+                .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+                .inc();
+        }
+    }
+
+    /// The metrics in the Prometheus text format, with the content type.
+    #[cfg(feature = "metrics")]
+    pub fn render(&self) -> anyhow::Result<(String, Vec<u8>)> {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Some(inner) = &self.inner {
+            inner.sql_pool_connections.set(inner.sql_pool.size() as i64);
+            inner
+                .sql_pool_idle_connections
+                .set(inner.sql_pool.num_idle() as i64);
+            encoder.encode(&inner.registry.gather(), &mut buffer)?;
+        }
+        Ok((encoder.format_type().to_string(), buffer))
+    }
+}
+
+/// Serves the metrics, without authentication like most Prometheus exporters: restrict the access
+/// to `/metrics` in the reverse proxy if needed.
+#[cfg(feature = "metrics")]
+pub(crate) async fn get_metrics<Backend>(
+    data: actix_web::web::Data<crate::infra::tcp_server::AppState<Backend>>,
+) -> actix_web::HttpResponse {
+    match data.metrics.render() {
+        Ok((content_type, body)) => actix_web::HttpResponse::Ok()
+            .content_type(content_type)
+            .body(body),
+        Err(e) => actix_web::HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::PoolOptions;
+
+    #[actix_rt::test]
+    async fn test_render() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        let metrics = Metrics::new(sql_pool).unwrap();
+        metrics.record_ldap_bind(true);
+        metrics.record_ldap_bind(false);
+        metrics.record_ldap_search(3);
+        let _connection = metrics.ldap_connection_opened();
+        let (content_type, body) = metrics.render().unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        assert!(body.contains(r#"lldap_ldap_binds_total{result="failure"} 1"#));
+        assert!(body.contains("lldap_ldap_searches_total 1"));
+        assert!(body.contains("lldap_ldap_search_result_entries_sum 3"));
+        assert!(body.contains("lldap_ldap_connections 1"));
+        assert!(body.contains("lldap_sql_pool_idle_connections "));
+    }
+}
@@ -1,12 +1,41 @@
+pub mod access_control;
+pub mod admin_cli;
+pub mod api_tokens;
+pub mod audit_backend_handler;
 pub mod auth_service;
+pub mod avatar;
+pub mod backup;
+pub mod bind_throttle;
+pub mod bootstrap;
+pub mod caching_backend_handler;
 pub mod cli;
 pub mod configuration;
+pub mod connectors;
 pub mod db_cleaner;
+pub mod export;
 pub mod graphql;
+pub mod health;
+pub mod import;
+pub mod jwt_keys;
 pub mod jwt_sql_tables;
 pub mod ldap_handler;
+pub mod ldap_migration;
+pub mod ldap_monitor;
 pub mod ldap_server;
 pub mod logging;
+pub mod mail;
+pub mod metrics;
+pub mod oidc;
+pub mod oidc_provider;
+pub mod openapi;
+pub mod password_expiry;
+pub mod password_policy;
+pub mod scim;
+pub mod seed;
+pub mod sessions;
+pub mod shutdown;
+pub mod socket_activation;
 pub mod sql_backend_handler;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
+pub mod timeout_backend_handler;
@@ -0,0 +1,177 @@
+//! Client side of the OpenID Connect authorization code flow, to log into the web UI through the
+//! provider of the `oidc` configuration. The endpoints are in [`super::auth_service`].
+
+use crate::infra::configuration::{OidcConfig, OidcUserMatch};
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+
+/// The part of the provider's discovery document that we use.
+#[derive(Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+pub async fn get_provider_metadata(config: &OidcConfig) -> Result<ProviderMetadata> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer_url.trim_end_matches('/')
+    );
+    reqwest::get(&url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("while fetching {}", url))?
+        .json()
+        .await
+        .with_context(|| format!("while parsing {}", url))
+}
+
+/// A random value tying the callback to the login that started it.
+pub fn generate_state() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::rngs::OsRng
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+/// The provider's page to redirect the user to.
+pub fn get_authorization_url(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    state: &str,
+) -> Result<Url> {
+    Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("scope", config.scopes.as_str()),
+            ("state", state),
+        ],
+    )
+    .context("Invalid authorization endpoint")
+}
+
+fn get_claim(userinfo: &serde_json::Value, claim: &str) -> Result<String> {
+    userinfo
+        .get(claim)
+        .and_then(serde_json::Value::as_str)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("The userinfo doesn't have the claim {}", claim))
+}
+
+/// The emails are only trusted once the provider verified them: otherwise, anyone could register
+/// with the email of an lldap user at the provider and log in as them.
+fn check_email_verified(userinfo: &serde_json::Value) -> Result<()> {
+    match userinfo.get("email_verified") {
+        Some(serde_json::Value::Bool(true)) => Ok(()),
+        _ => Err(anyhow!("The provider didn't verify the email of the user")),
+    }
+}
+
+/// Exchanges the code received in the callback for an access token, and returns the configured
+/// claim of the user it belongs to. When the users are matched by email, it must be verified.
+pub async fn get_user_claim(config: &OidcConfig, code: &str) -> Result<String> {
+    let metadata = get_provider_metadata(config).await?;
+    let client = reqwest::Client::new();
+    let token: TokenResponse = client
+        .post(&metadata.token_endpoint)
+        .basic_auth(&config.client_id, Some(&config.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_url.as_str()),
+        ])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("while exchanging the authorization code")?
+        .json()
+        .await
+        .context("while parsing the token response")?;
+    let userinfo: serde_json::Value = client
+        .get(&metadata.userinfo_endpoint)
+        .bearer_auth(&token.access_token)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("while fetching the userinfo")?
+        .json()
+        .await
+        .context("while parsing the userinfo")?;
+    if config.match_by == OidcUserMatch::Email {
+        check_email_verified(&userinfo)?;
+    }
+    get_claim(&userinfo, &config.claim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_authorization_url() {
+        let config = OidcConfig {
+            display_name: "SSO".to_string(),
+            issuer_url: "https://sso.example.com".to_string(),
+            client_id: "lldap".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "https://lldap.example.com/auth/oidc/callback".to_string(),
+            scopes: "openid profile".to_string(),
+            claim: "preferred_username".to_string(),
+            match_by: OidcUserMatch::UserId,
+        };
+        let metadata = ProviderMetadata {
+            authorization_endpoint: "https://sso.example.com/authorize?prompt=login".to_string(),
+            token_endpoint: "https://sso.example.com/token".to_string(),
+            userinfo_endpoint: "https://sso.example.com/userinfo".to_string(),
+        };
+        assert_eq!(
+            get_authorization_url(&config, &metadata, "abc")
+                .unwrap()
+                .as_str(),
+            "https://sso.example.com/authorize?prompt=login&response_type=code&client_id=lldap\
+             &redirect_uri=https%3A%2F%2Flldap.example.com%2Fauth%2Foidc%2Fcallback\
+             &scope=openid+profile&state=abc"
+        );
+    }
+
+    #[test]
+    fn test_get_claim() {
+        let userinfo = serde_json::json!({
+            "sub": "1234",
+            "preferred_username": "bob",
+            "email": "",
+            "email_verified": true,
+        });
+        assert_eq!(get_claim(&userinfo, "preferred_username").unwrap(), "bob");
+        assert!(get_claim(&userinfo, "email").is_err());
+        assert!(get_claim(&userinfo, "email_verified").is_err());
+        assert!(get_claim(&userinfo, "name").is_err());
+    }
+
+    #[test]
+    fn test_check_email_verified() {
+        let userinfo = |verified: serde_json::Value| {
+            serde_json::json!({
+                "email": "bob@example.com",
+                "email_verified": verified,
+            })
+        };
+        check_email_verified(&userinfo(serde_json::json!(true))).unwrap();
+        check_email_verified(&userinfo(serde_json::json!(false))).unwrap_err();
+        check_email_verified(&userinfo(serde_json::json!("true"))).unwrap_err();
+        check_email_verified(&serde_json::json!({ "email": "bob@example.com" })).unwrap_err();
+    }
+}
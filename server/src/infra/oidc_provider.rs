@@ -0,0 +1,969 @@
+//! The OpenID Connect provider of lldap: the applications of the `oidc_provider` configuration
+//! log their users in with the authorization code flow (with PKCE), and get an ID token with the
+//! groups of the user.
+//!
+//! The issuer is the `http_url`, with the discovery document at
+//! `/.well-known/openid-configuration`. The users log in on a page of the server, which keeps
+//! them logged in for a day with the `oidc_session` cookie. The codes and the access tokens are
+//! only kept in memory: a restart logs the applications out, not the users.
+
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{BackendHandler, BindRequest, GroupIdAndName, LoginHandler, User},
+        identifiers::fold_case,
+    },
+    infra::{
+        audit_backend_handler::with_audit_context,
+        auth_service::{
+            anonymous_audit_context, check_if_token_is_valid, create_registered_jwt,
+            get_throttled_response, record_login_failure,
+        },
+        configuration::{OidcClientConfig, OidcProviderConfig},
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::{error_to_http_response, AppState},
+    },
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    http::header::{CACHE_CONTROL, LOCATION, WWW_AUTHENTICATE},
+    web, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use actix_web_httpauth::extractors::{basic::BasicAuth, bearer::BearerAuth};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use rsa::{
+    pkcs8::{FromPrivateKey, ToPrivateKey},
+    Hash, PaddingScheme, PublicKeyParts, RsaPrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use time::ext::NumericalDuration;
+use tracing::{info, warn};
+
+const SESSION_COOKIE: &str = "oidc_session";
+
+/// Enough to be redirected to the client and exchange the code, and short enough not to be
+/// worth stealing.
+fn code_lifetime() -> Duration {
+    Duration::minutes(1)
+}
+
+fn base64_url(bytes: impl AsRef<[u8]>) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn generate_token() -> String {
+    use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(40)
+        .collect()
+}
+
+/// Reads the RSA key, or generates it if the file doesn't exist. The `name` of the key is for the
+/// messages, e.g. `OIDC`.
+pub(crate) fn get_signing_key(file_path: &str, name: &str) -> Result<RsaPrivateKey> {
+    let path = Path::new(file_path);
+    if path.exists() {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the {} key file `{}`", name, file_path))?;
+        RsaPrivateKey::from_pkcs8_pem(&pem)
+            .map_err(|e| anyhow!("Invalid {} key file `{}`: {}", name, file_path, e))
+    } else {
+        info!("Generating the {} signing key in `{}`", name, file_path);
+        let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)
+            .map_err(|e| anyhow!("Could not generate the {} signing key: {}", name, e))?;
+        let pem = key
+            .to_pkcs8_pem()
+            .map_err(|e| anyhow!("Could not encode the {} signing key: {}", name, e))?;
+        std::fs::write(path, pem.as_bytes()).with_context(|| {
+            format!(
+                "Could not write the generated {} key to file `{}`",
+                name, file_path
+            )
+        })?;
+        Ok(key)
+    }
+}
+
+/// Checks the `code_verifier` against the S256 `code_challenge` of the authorization request.
+fn verify_code_challenge(code_challenge: &str, code_verifier: &str) -> bool {
+    base64_url(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+/// An error of the OAuth 2.0 protocol, with its code from RFC 6749.
+#[derive(Debug, PartialEq, Eq)]
+struct OAuthError {
+    error: &'static str,
+    description: String,
+}
+
+impl OAuthError {
+    fn new(error: &'static str, description: impl Into<String>) -> Self {
+        Self {
+            error,
+            description: description.into(),
+        }
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        match self.error {
+            "invalid_client" => HttpResponse::Unauthorized(),
+            _ => HttpResponse::BadRequest(),
+        }
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "error": self.error,
+            "error_description": self.description,
+        }))
+    }
+}
+
+/// The parameters of `/oidc/authorize`, kept in the login form.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct AuthorizationRequest {
+    client_id: String,
+    redirect_uri: String,
+    #[serde(default)]
+    response_type: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge_method: Option<String>,
+    /// `login` to ask for the password even with a session, `none` to fail without one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+}
+
+impl AuthorizationRequest {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
+
+    /// The redirection to the client, with the `state` and the given parameters.
+    fn redirect(&self, params: &[(&str, &str)]) -> HttpResponseBuilder {
+        let mut url = Url::parse(&self.redirect_uri)
+            .expect("The redirect URIs of the clients are checked with the configuration");
+        url.query_pairs_mut()
+            .extend_pairs(params)
+            .extend_pairs(self.state.iter().map(|state| ("state", state)));
+        let mut response = HttpResponse::Found();
+        response.insert_header((LOCATION, url.as_str()));
+        response
+    }
+
+    fn redirect_error(&self, error: &OAuthError) -> HttpResponse {
+        self.redirect(&[
+            ("error", error.error),
+            ("error_description", &error.description),
+        ])
+        .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    #[serde(flatten)]
+    request: AuthorizationRequest,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    redirect_uri: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+}
+
+/// A code given to the client after the login, to exchange for the tokens once.
+#[derive(Clone, Debug)]
+struct AuthorizationCode {
+    client_id: String,
+    redirect_uri: String,
+    user_id: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    expires_at: DateTime<Utc>,
+}
+
+struct AccessToken {
+    /// The answer of `/oidc/userinfo`.
+    claims: Map<String, Value>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Grants {
+    codes: HashMap<String, AuthorizationCode>,
+    access_tokens: HashMap<String, AccessToken>,
+}
+
+impl Grants {
+    /// The expired codes and tokens are otherwise only removed when they are used.
+    fn remove_expired(&mut self, now: DateTime<Utc>) {
+        self.codes.retain(|_, code| code.expires_at > now);
+        self.access_tokens.retain(|_, token| token.expires_at > now);
+    }
+}
+
+/// The signing key, the clients and the pending grants, shared by all the HTTP workers.
+#[derive(Clone)]
+pub struct OidcProvider {
+    config: OidcProviderConfig,
+    issuer: String,
+    key: Arc<RsaPrivateKey>,
+    key_id: String,
+    grants: Arc<Mutex<Grants>>,
+}
+
+impl OidcProvider {
+    /// Loads the signing key from the `key_file` (or generates it if the file doesn't exist).
+    pub fn new(config: &OidcProviderConfig, http_url: &str) -> Result<Self> {
+        let key = get_signing_key(&config.key_file, "OIDC")?;
+        Ok(Self::with_key(config.clone(), http_url, key))
+    }
+
+    fn with_key(config: OidcProviderConfig, http_url: &str, key: RsaPrivateKey) -> Self {
+        // Changes with the key, for the clients to fetch the new one.
+        let key_id = base64_url(&Sha256::digest(&key.n().to_bytes_be())[..12]);
+        Self {
+            config,
+            issuer: http_url.trim_end_matches('/').to_string(),
+            key: Arc::new(key),
+            key_id,
+            grants: Arc::default(),
+        }
+    }
+
+    fn get_client(&self, client_id: &str) -> Option<&OidcClientConfig> {
+        self.config
+            .clients
+            .iter()
+            .find(|client| client.client_id == client_id)
+    }
+
+    fn get_discovery_document(&self) -> Value {
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": format!("{}/oidc/authorize", self.issuer),
+            "token_endpoint": format!("{}/oidc/token", self.issuer),
+            "userinfo_endpoint": format!("{}/oidc/userinfo", self.issuer),
+            "jwks_uri": format!("{}/oidc/jwks", self.issuer),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": ["openid", "profile", "email", "groups"],
+            "claims_supported": [
+                "sub", "preferred_username", "name", "given_name", "family_name", "email", "groups",
+            ],
+            "token_endpoint_auth_methods_supported":
+                ["client_secret_basic", "client_secret_post", "none"],
+            "code_challenge_methods_supported": ["S256"],
+        })
+    }
+
+    fn get_jwks(&self) -> Value {
+        json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.key_id,
+                "n": base64_url(self.key.n().to_bytes_be()),
+                "e": base64_url(self.key.e().to_bytes_be()),
+            }]
+        })
+    }
+
+    /// Signs the claims into a JWT, with RS256.
+    fn sign(&self, claims: &Value) -> Result<String> {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": self.key_id });
+        let payload = format!(
+            "{}.{}",
+            base64_url(header.to_string()),
+            base64_url(claims.to_string())
+        );
+        let signature = self
+            .key
+            .sign(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                &Sha256::digest(payload.as_bytes()),
+            )
+            .map_err(|e| anyhow!("Could not sign the ID token: {}", e))?;
+        Ok(format!("{}.{}", payload, base64_url(signature)))
+    }
+
+    /// Checks the client and its redirect URI: the errors can't be sent back to the client.
+    fn check_client(&self, request: &AuthorizationRequest) -> Result<&OidcClientConfig, String> {
+        let client = self
+            .get_client(&request.client_id)
+            .ok_or_else(|| format!("Unknown client `{}`", request.client_id))?;
+        if !client.redirect_uris.contains(&request.redirect_uri) {
+            return Err(format!(
+                "The redirect URI `{}` isn't registered for the client `{}`",
+                request.redirect_uri, request.client_id
+            ));
+        }
+        Ok(client)
+    }
+
+    /// Checks the rest of the request, for a client checked by [`Self::check_client`].
+    fn check_request(
+        client: &OidcClientConfig,
+        request: &AuthorizationRequest,
+    ) -> Result<(), OAuthError> {
+        if request.response_type != "code" {
+            return Err(OAuthError::new(
+                "unsupported_response_type",
+                "Only the authorization code flow is supported",
+            ));
+        }
+        if !request.has_scope("openid") {
+            return Err(OAuthError::new(
+                "invalid_scope",
+                "The openid scope is required",
+            ));
+        }
+        match (&request.code_challenge, &request.code_challenge_method) {
+            (Some(_), Some(method)) if method == "S256" => Ok(()),
+            (Some(_), _) => Err(OAuthError::new(
+                "invalid_request",
+                "Only the S256 code challenge method is supported",
+            )),
+            (None, _) if client.client_secret.is_empty() => Err(OAuthError::new(
+                "invalid_request",
+                "PKCE is required for the public clients",
+            )),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Issues a code for the user, to redeem with [`Self::redeem_code`].
+    fn create_code(&self, request: &AuthorizationRequest, user_id: &str) -> String {
+        let code = generate_token();
+        let now = Utc::now();
+        let mut grants = self.grants.lock().unwrap();
+        grants.remove_expired(now);
+        grants.codes.insert(
+            code.clone(),
+            AuthorizationCode {
+                client_id: request.client_id.clone(),
+                redirect_uri: request.redirect_uri.clone(),
+                user_id: user_id.to_string(),
+                scope: request.scope.clone(),
+                nonce: request.nonce.clone(),
+                code_challenge: request.code_challenge.clone(),
+                expires_at: now + code_lifetime(),
+            },
+        );
+        code
+    }
+
+    /// Authenticates the client, and consumes the code it was given.
+    fn redeem_code(
+        &self,
+        request: &TokenRequest,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<AuthorizationCode, OAuthError> {
+        if request.grant_type != "authorization_code" {
+            return Err(OAuthError::new(
+                "unsupported_grant_type",
+                "Only the authorization_code grant type is supported",
+            ));
+        }
+        let client = self
+            .get_client(client_id)
+            .ok_or_else(|| OAuthError::new("invalid_client", "Unknown client"))?;
+        if !client.client_secret.is_empty()
+            && orion::util::secure_cmp(
+                client_secret.unwrap_or_default().as_bytes(),
+                client.client_secret.as_bytes(),
+            )
+            .is_err()
+        {
+            return Err(OAuthError::new("invalid_client", "Invalid client secret"));
+        }
+        let code = self
+            .grants
+            .lock()
+            .unwrap()
+            .codes
+            .remove(&request.code)
+            .filter(|code| code.expires_at > Utc::now())
+            .ok_or_else(|| OAuthError::new("invalid_grant", "Invalid or expired code"))?;
+        if code.client_id != client.client_id || code.redirect_uri != request.redirect_uri {
+            return Err(OAuthError::new(
+                "invalid_grant",
+                "The code was issued to another client or redirect URI",
+            ));
+        }
+        let verified = match (&code.code_challenge, &request.code_verifier) {
+            (Some(challenge), Some(verifier)) => verify_code_challenge(challenge, verifier),
+            (None, None) => true,
+            _ => false,
+        };
+        if !verified {
+            return Err(OAuthError::new("invalid_grant", "Invalid code_verifier"));
+        }
+        Ok(code)
+    }
+
+    /// The ID token and the access token for the user of the code.
+    fn create_tokens(&self, code: &AuthorizationCode, claims: Map<String, Value>) -> Result<Value> {
+        let now = Utc::now();
+        let expires_in = self.config.token_lifetime_seconds;
+        let expires_at = now + Duration::seconds(expires_in as i64);
+        let mut id_token_claims = claims.clone();
+        id_token_claims.insert("iss".to_string(), json!(self.issuer));
+        id_token_claims.insert("aud".to_string(), json!(code.client_id));
+        id_token_claims.insert("iat".to_string(), json!(now.timestamp()));
+        id_token_claims.insert("exp".to_string(), json!(expires_at.timestamp()));
+        if let Some(nonce) = &code.nonce {
+            id_token_claims.insert("nonce".to_string(), json!(nonce));
+        }
+        let id_token = self.sign(&Value::Object(id_token_claims))?;
+        let access_token = generate_token();
+        let mut grants = self.grants.lock().unwrap();
+        grants.remove_expired(now);
+        grants
+            .access_tokens
+            .insert(access_token.clone(), AccessToken { claims, expires_at });
+        Ok(json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": expires_in,
+            "id_token": id_token,
+            "scope": code.scope,
+        }))
+    }
+
+    fn get_userinfo(&self, access_token: &str) -> Option<Map<String, Value>> {
+        self.grants
+            .lock()
+            .unwrap()
+            .access_tokens
+            .get(access_token)
+            .filter(|token| token.expires_at > Utc::now())
+            .map(|token| token.claims.clone())
+    }
+}
+
+/// The claims about the user that the scopes give access to. The subject is the UUID of the
+/// user, which doesn't change.
+fn get_user_claims(
+    user: &User,
+    groups: &HashSet<GroupIdAndName>,
+    scope: &str,
+) -> Map<String, Value> {
+    let scopes = scope.split_whitespace().collect::<HashSet<_>>();
+    let mut claims = Map::new();
+    claims.insert("sub".to_string(), json!(user.uuid));
+    if scopes.contains("profile") {
+        claims.insert("preferred_username".to_string(), json!(user.user_id));
+        claims.insert("name".to_string(), json!(user.display_name));
+        if !user.first_name.is_empty() {
+            claims.insert("given_name".to_string(), json!(user.first_name));
+        }
+        if !user.last_name.is_empty() {
+            claims.insert("family_name".to_string(), json!(user.last_name));
+        }
+    }
+    if scopes.contains("email") {
+        claims.insert("email".to_string(), json!(user.email));
+    }
+    if scopes.contains("groups") {
+        let mut group_names = groups.iter().map(|g| g.1.as_str()).collect::<Vec<_>>();
+        group_names.sort_unstable();
+        claims.insert("groups".to_string(), json!(group_names));
+    }
+    claims
+}
+
+const LOGIN_PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>LLDAP login</title>
+  <link href="https://cdn.jsdelivr.net/npm/bootstrap@5.0.1/dist/css/bootstrap.min.css" rel="stylesheet" />
+</head>
+<body class="container py-5" style="max-width: 30rem">
+  <h1 class="h3 mb-4">Log in to {{ request.client_id }}</h1>
+  {% if error %}<div class="alert alert-danger">{{ error }}</div>{% endif %}
+  <form method="post" action="/oidc/authorize">
+    {% for name, value in request %}<input type="hidden" name="{{ name }}" value="{{ value }}" />
+    {% endfor %}
+    <div class="mb-3">
+      <label class="form-label" for="username">User ID</label>
+      <input class="form-control" id="username" name="username" autocomplete="username" required autofocus />
+    </div>
+    <div class="mb-3">
+      <label class="form-label" for="password">Password</label>
+      <input class="form-control" id="password" name="password" type="password" autocomplete="current-password" required />
+    </div>
+    <button class="btn btn-primary" type="submit">Log in</button>
+  </form>
+</body>
+</html>
+"#;
+
+fn get_login_page(request: &AuthorizationRequest, error: Option<&str>) -> HttpResponse {
+    let mut context = tera::Context::new();
+    context.insert("request", request);
+    context.insert("error", &error);
+    match tera::Tera::one_off(LOGIN_PAGE, &context, true) {
+        Ok(page) => match error {
+            Some(_) => HttpResponse::Unauthorized(),
+            None => HttpResponse::Ok(),
+        }
+        .content_type("text/html; charset=utf-8")
+        .body(page),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Checks the authorization request, returning the response refusing it if it's invalid.
+fn check_authorization_request(
+    provider: &OidcProvider,
+    request: &AuthorizationRequest,
+) -> Result<(), HttpResponse> {
+    let client = provider
+        .check_client(request)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    OidcProvider::check_request(client, request).map_err(|e| request.redirect_error(&e))
+}
+
+fn redirect_with_code(
+    provider: &OidcProvider,
+    request: &AuthorizationRequest,
+    user_id: &str,
+) -> HttpResponseBuilder {
+    let code = provider.create_code(request, user_id);
+    request.redirect(&[("code", &code)])
+}
+
+fn not_configured() -> HttpResponse {
+    HttpResponse::NotFound().body("The OIDC provider is not configured")
+}
+
+/// The discovery document, served at the root of the issuer.
+pub(crate) async fn get_discovery_document<Backend>(
+    data: web::Data<AppState<Backend>>,
+) -> HttpResponse {
+    match &data.oidc_provider {
+        Some(provider) => HttpResponse::Ok().json(provider.get_discovery_document()),
+        None => not_configured(),
+    }
+}
+
+async fn get_jwks<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse {
+    match &data.oidc_provider {
+        Some(provider) => HttpResponse::Ok().json(provider.get_jwks()),
+        None => not_configured(),
+    }
+}
+
+/// Sends the user back to the client with a code if they have a session, or shows the login page.
+async fn get_authorize<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    query: web::Query<AuthorizationRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let provider = match &data.oidc_provider {
+        Some(provider) => provider,
+        None => return not_configured(),
+    };
+    if let Err(response) = check_authorization_request(provider, &query) {
+        return response;
+    }
+    if query.prompt.as_deref() != Some("login") {
+        if let Some(cookie) = http_request.cookie(SESSION_COOKIE) {
+            if let Ok(validation) = check_if_token_is_valid(&data, cookie.value()).await {
+                return redirect_with_code(provider, &query, &validation.user).finish();
+            }
+        }
+    }
+    if query.prompt.as_deref() == Some("none") {
+        return query.redirect_error(&OAuthError::new("login_required", "Not logged in"));
+    }
+    get_login_page(&query, None)
+}
+
+/// Checks the password from the login page, and opens the session of the user.
+async fn post_authorize<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    form: web::Form<LoginForm>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
+{
+    let provider = match &data.oidc_provider {
+        Some(provider) => provider,
+        None => return not_configured(),
+    };
+    let LoginForm {
+        request,
+        username,
+        password,
+    } = form.into_inner();
+    if let Err(response) = check_authorization_request(provider, &request) {
+        return response;
+    }
+    let username = fold_case(&username);
+    if let Some(response) = get_throttled_response(&data, &http_request, Some(&username)) {
+        return response;
+    }
+    if let Err(e) = with_audit_context(
+        anonymous_audit_context(&http_request),
+        data.backend_handler.bind(BindRequest {
+            name: username.clone(),
+            password,
+        }),
+    )
+    .await
+    {
+        record_login_failure(&data, &http_request, &e).await;
+        return match e {
+            DomainError::AuthenticationError(_) => {
+                get_login_page(&request, Some("Invalid user ID or password"))
+            }
+            e => error_to_http_response(e),
+        };
+    }
+    data.bind_throttle.record_success(&username).await;
+    let token = match data.backend_handler.get_user_groups(&username).await {
+        Ok(groups) => create_registered_jwt(&data, &username, groups).await,
+        Err(e) => Err(e),
+    };
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => return error_to_http_response(e),
+    };
+    redirect_with_code(provider, &request, &username)
+        .cookie(
+            // Lax, to be sent along with the redirections from the clients. Logging out of the web
+            // UI blacklists it like the other JWTs.
+            Cookie::build(SESSION_COOKIE, token.as_str().to_owned())
+                .max_age(1.days())
+                .path("/oidc")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .finish(),
+        )
+        .finish()
+}
+
+/// Exchanges the code for the ID token and an access token to `/oidc/userinfo`.
+async fn post_token<Backend>(
+    data: web::Data<AppState<Backend>>,
+    basic_auth: Option<BasicAuth>,
+    form: web::Form<TokenRequest>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let provider = match &data.oidc_provider {
+        Some(provider) => provider,
+        None => return not_configured(),
+    };
+    // With HTTP basic authentication, or in the form.
+    let (client_id, client_secret) = match &basic_auth {
+        Some(auth) => (
+            auth.user_id().to_string(),
+            auth.password().map(|p| p.to_string()),
+        ),
+        None => (
+            form.client_id.clone().unwrap_or_default(),
+            form.client_secret.clone(),
+        ),
+    };
+    let code = match provider.redeem_code(&form, &client_id, client_secret.as_deref()) {
+        Ok(code) => code,
+        Err(e) => return e.to_response(),
+    };
+    let backend_handler = &data.backend_handler;
+    // The user may have been locked or deleted since the login.
+    let user = match backend_handler.get_user_lock(&code.user_id).await {
+        Ok(None) => backend_handler.get_user_details(&code.user_id).await,
+        Ok(Some(_)) => Err(DomainError::AuthenticationError(code.user_id.clone())),
+        Err(e) => Err(e),
+    };
+    let claims = match user {
+        Ok(user) => match backend_handler.get_user_groups(&user.user_id).await {
+            Ok(groups) => get_user_claims(&user, &groups, &code.scope),
+            Err(e) => return error_to_http_response(e),
+        },
+        Err(DomainError::AuthenticationError(_))
+        | Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => {
+            return OAuthError::new("invalid_grant", "The user can't log in anymore").to_response()
+        }
+        Err(e) => return error_to_http_response(e),
+    };
+    match provider.create_tokens(&code, claims) {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(tokens),
+        Err(e) => {
+            warn!("Could not create the OIDC tokens: {:#}", e);
+            HttpResponse::InternalServerError().body("Could not create the tokens")
+        }
+    }
+}
+
+async fn get_userinfo<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: BearerAuth,
+) -> HttpResponse {
+    let provider = match &data.oidc_provider {
+        Some(provider) => provider,
+        None => return not_configured(),
+    };
+    match provider.get_userinfo(bearer.token()) {
+        Some(claims) => HttpResponse::Ok().json(claims),
+        None => HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#))
+            .finish(),
+    }
+}
+
+pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + LoginHandler + Sync + 'static,
+{
+    cfg.service(
+        web::resource("/authorize")
+            .route(web::get().to(get_authorize::<Backend>))
+            .route(web::post().to(post_authorize::<Backend>)),
+    )
+    .service(web::resource("/token").route(web::post().to(post_token::<Backend>)))
+    .service(
+        web::resource("/userinfo")
+            .route(web::get().to(get_userinfo::<Backend>))
+            .route(web::post().to(get_userinfo::<Backend>)),
+    )
+    .service(web::resource("/jwks").route(web::get().to(get_jwks::<Backend>)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+    use rsa::{BigUint, PublicKey, RsaPublicKey};
+
+    fn get_provider() -> OidcProvider {
+        let config = OidcProviderConfig {
+            key_file: String::new(),
+            token_lifetime_seconds: 3600,
+            clients: vec![
+                OidcClientConfig {
+                    client_id: "grafana".to_string(),
+                    client_secret: "secret".to_string(),
+                    redirect_uris: vec!["https://grafana.example.com/login".to_string()],
+                },
+                OidcClientConfig {
+                    client_id: "spa".to_string(),
+                    client_secret: String::new(),
+                    redirect_uris: vec!["https://spa.example.com/".to_string()],
+                },
+            ],
+        };
+        // Small, for the tests to be fast.
+        let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+        OidcProvider::with_key(config, "https://lldap.example.com/", key)
+    }
+
+    fn get_request(client_id: &str, redirect_uri: &str) -> AuthorizationRequest {
+        AuthorizationRequest {
+            client_id: client_id.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            response_type: "code".to_string(),
+            scope: "openid profile groups".to_string(),
+            state: Some("xyz".to_string()),
+            ..AuthorizationRequest::default()
+        }
+    }
+
+    fn get_token_request(code: &str, redirect_uri: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: "authorization_code".to_string(),
+            code: code.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            client_id: None,
+            client_secret: None,
+            code_verifier: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_code_challenge() {
+        // From RFC 7636, appendix B.
+        assert!(verify_code_challenge(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"
+        ));
+        assert!(!verify_code_challenge(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXj"
+        ));
+    }
+
+    #[test]
+    fn test_authorization_code() {
+        let provider = get_provider();
+        let unknown = get_request("grafana", "https://evil.example.com/");
+        assert!(provider.check_client(&unknown).is_err());
+        let public = get_request("spa", "https://spa.example.com/");
+        let client = provider.check_client(&public).unwrap();
+        assert_eq!(
+            OidcProvider::check_request(client, &public)
+                .unwrap_err()
+                .error,
+            "invalid_request"
+        );
+        let public = AuthorizationRequest {
+            code_challenge: Some("E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_string()),
+            code_challenge_method: Some("S256".to_string()),
+            ..public
+        };
+        OidcProvider::check_request(client, &public).unwrap();
+        let code = provider.create_code(&public, "bob");
+        let mut token_request = get_token_request(&code, "https://spa.example.com/");
+        token_request.code_verifier = Some("wrong".to_string());
+        assert_eq!(
+            provider
+                .redeem_code(&token_request, "spa", None)
+                .unwrap_err()
+                .error,
+            "invalid_grant"
+        );
+        // The code can't be tried again.
+        let code = provider.create_code(&public, "bob");
+        let mut token_request = get_token_request(&code, "https://spa.example.com/");
+        token_request.code_verifier =
+            Some("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!(
+            provider
+                .redeem_code(&token_request, "spa", None)
+                .unwrap()
+                .user_id,
+            "bob"
+        );
+        assert!(provider.redeem_code(&token_request, "spa", None).is_err());
+        // The confidential clients authenticate.
+        let request = get_request("grafana", "https://grafana.example.com/login");
+        let token_request = get_token_request(
+            &provider.create_code(&request, "bob"),
+            "https://grafana.example.com/login",
+        );
+        assert_eq!(
+            provider
+                .redeem_code(&token_request, "grafana", Some("wrong"))
+                .unwrap_err()
+                .error,
+            "invalid_client"
+        );
+    }
+
+    #[test]
+    fn test_id_token() {
+        let provider = get_provider();
+        let user = User {
+            user_id: "bob".to_string(),
+            email: "bob@example.com".to_string(),
+            display_name: "Bob".to_string(),
+            uuid: "698e1d5f-7a40-3151-8745-b9b8a37839da".to_string(),
+            ..User::default()
+        };
+        let groups = vec![
+            GroupIdAndName(GroupId(2), "media".to_string()),
+            GroupIdAndName(GroupId(1), "admins".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let claims = get_user_claims(&user, &groups, "openid profile groups");
+        assert_eq!(
+            Value::Object(claims.clone()),
+            json!({
+                "sub": "698e1d5f-7a40-3151-8745-b9b8a37839da",
+                "preferred_username": "bob",
+                "name": "Bob",
+                "groups": ["admins", "media"],
+            })
+        );
+        let code = AuthorizationCode {
+            client_id: "grafana".to_string(),
+            redirect_uri: "https://grafana.example.com/login".to_string(),
+            user_id: "bob".to_string(),
+            scope: "openid profile groups".to_string(),
+            nonce: Some("n-0S6_WzA2Mj".to_string()),
+            code_challenge: None,
+            expires_at: Utc::now(),
+        };
+        let tokens = provider.create_tokens(&code, claims.clone()).unwrap();
+        let id_token = tokens["id_token"].as_str().unwrap();
+        // Verified with the key of the JWKS.
+        let jwks = provider.get_jwks();
+        let decode = |field: &str| {
+            base64::decode_config(
+                jwks["keys"][0][field].as_str().unwrap(),
+                base64::URL_SAFE_NO_PAD,
+            )
+            .unwrap()
+        };
+        let public_key = RsaPublicKey::new(
+            BigUint::from_bytes_be(&decode("n")),
+            BigUint::from_bytes_be(&decode("e")),
+        )
+        .unwrap();
+        let (payload, signature) = id_token.rsplit_once('.').unwrap();
+        public_key
+            .verify(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                &Sha256::digest(payload.as_bytes()),
+                &base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap(),
+            )
+            .unwrap();
+        let payload: Value = serde_json::from_slice(
+            &base64::decode_config(payload.split('.').nth(1).unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(payload["iss"], "https://lldap.example.com");
+        assert_eq!(payload["aud"], "grafana");
+        assert_eq!(payload["nonce"], "n-0S6_WzA2Mj");
+        assert_eq!(payload["groups"], json!(["admins", "media"]));
+        assert_eq!(
+            provider.get_userinfo(tokens["access_token"].as_str().unwrap()),
+            Some(claims)
+        );
+        assert_eq!(provider.get_userinfo("unknown"), None);
+    }
+}
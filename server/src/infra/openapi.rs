@@ -0,0 +1,491 @@
+//! OpenAPI 3 description of the REST endpoints, served at `/api/openapi.json` to generate
+//! clients. The data itself is managed through GraphQL, see `/api/graphql`. The standard
+//! protocols (SCIM, the OIDC provider and its `/.well-known` documents) and the metrics are
+//! described by their own specifications.
+
+use actix_web::HttpResponse;
+use serde_json::{json, Value};
+
+fn json_body(schema: &str) -> Value {
+    json!({
+        "required": true,
+        "content": {
+            "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } }
+        }
+    })
+}
+
+fn json_response(description: &str, schema: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } }
+        }
+    })
+}
+
+fn token_response() -> Value {
+    json!({
+        "description": "The JWT, also set in the `token` cookie, along with `refresh_token`.",
+        "content": { "text/plain": { "schema": { "type": "string" } } }
+    })
+}
+
+fn error_responses() -> Value {
+    json!({
+        "400": { "description": "Malformed request." },
+        "401": { "description": "Invalid credentials or token." },
+        "500": { "description": "Internal error." }
+    })
+}
+
+fn operation(
+    operation_id: &str,
+    summary: &str,
+    request_body: Option<Value>,
+    success: Value,
+) -> Value {
+    let mut responses = error_responses();
+    responses["200"] = success;
+    let mut operation = json!({
+        "operationId": operation_id,
+        "summary": summary,
+        "tags": ["auth"],
+        "responses": responses,
+    });
+    if let Some(body) = request_body {
+        operation["requestBody"] = body;
+    }
+    operation
+}
+
+fn with_tag(mut operation: Value, tag: &str) -> Value {
+    operation["tags"] = json!([tag]);
+    operation
+}
+
+/// An operation answering with a redirection instead of a 200.
+fn redirect_operation(operation_id: &str, summary: &str, redirection: &str) -> Value {
+    let mut operation = operation(operation_id, summary, None, json!({}));
+    let responses = operation["responses"].as_object_mut().unwrap();
+    responses.remove("200");
+    responses.insert("302".to_string(), json!({ "description": redirection }));
+    operation
+}
+
+fn with_query_parameters(mut operation: Value, parameters: &[(&str, &str)]) -> Value {
+    operation["parameters"] = parameters
+        .iter()
+        .map(|(name, description)| {
+            json!({
+                "name": name,
+                "in": "query",
+                "required": false,
+                "description": description,
+                "schema": { "type": "string" }
+            })
+        })
+        .collect();
+    operation
+}
+
+fn with_path_parameter(mut operation: Value, name: &str, description: &str) -> Value {
+    operation["parameters"] = json!([{
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": { "type": "string" }
+    }]);
+    operation
+}
+
+/// An OPAQUE protocol message, serialized as its bytes.
+fn opaque_message() -> Value {
+    json!({ "type": "array", "items": { "type": "integer", "format": "uint8" } })
+}
+
+fn object(properties: &[(&str, Value)]) -> Value {
+    let properties = properties
+        .iter()
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "type": "object",
+        "required": properties.keys().collect::<Vec<_>>(),
+        "properties": properties,
+    })
+}
+
+pub fn openapi_spec() -> Value {
+    let string = json!({ "type": "string" });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "LLDAP",
+            "description": "REST endpoints of LLDAP: authentication, health checks, avatars \
+                and exports. Users and groups are managed through the GraphQL API at \
+                /api/graphql, with the JWT as a bearer token.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/auth": {
+                "post": operation(
+                    "login",
+                    "Log in with a user name and a password in clear text.",
+                    Some(json_body("BindRequest")),
+                    token_response(),
+                )
+            },
+            "/auth/opaque/login/start": {
+                "post": operation(
+                    "opaqueLoginStart",
+                    "Start an OPAQUE login.",
+                    Some(json_body("ClientLoginStartRequest")),
+                    json_response("The server's OPAQUE response.", "ServerLoginStartResponse"),
+                )
+            },
+            "/auth/opaque/login/finish": {
+                "post": operation(
+                    "opaqueLoginFinish",
+                    "Finish an OPAQUE login.",
+                    Some(json_body("ClientLoginFinishRequest")),
+                    token_response(),
+                )
+            },
+            "/auth/opaque/register/start": {
+                "post": operation(
+                    "opaqueRegisterStart",
+                    "Start setting a user's password with OPAQUE. Only allowed for the user \
+                        themselves, the admins and the password managers.",
+                    Some(json_body("ClientRegistrationStartRequest")),
+                    json_response(
+                        "The server's OPAQUE response.",
+                        "ServerRegistrationStartResponse",
+                    ),
+                )
+            },
+            "/auth/opaque/register/finish": {
+                "post": operation(
+                    "opaqueRegisterFinish",
+                    "Finish setting a user's password with OPAQUE.",
+                    Some(json_body("ClientRegistrationFinishRequest")),
+                    json!({ "description": "The password was changed." }),
+                )
+            },
+            "/auth/reset/step1/{user_id}": {
+                "post": with_path_parameter(
+                    operation(
+                        "passwordResetStep1",
+                        "Email a password reset link to the user, if the SMTP server is \
+                            configured. The response doesn't say whether the user exists.",
+                        None,
+                        json!({ "description": "The link was sent, if the user exists." }),
+                    ),
+                    "user_id",
+                    "The user ID or the email of the user.",
+                )
+            },
+            "/auth/reset/step2/{token}": {
+                "post": with_path_parameter(
+                    operation(
+                        "passwordResetStep2",
+                        "Start setting the password of the user of the reset token with OPAQUE, \
+                            to finish with opaqueRegisterFinish. The token can only be used once.",
+                        Some(json_body("ClientRegistrationStartRequest")),
+                        json_response(
+                            "The server's OPAQUE response.",
+                            "ServerRegistrationStartResponse",
+                        ),
+                    ),
+                    "token",
+                    "The token of the reset link.",
+                )
+            },
+            "/auth/password/strength": {
+                "post": operation(
+                    "passwordStrength",
+                    "Evaluate a password of at most 256 characters against the password \
+                        policy, before setting it with OPAQUE. No token is needed, but the \
+                        password history is only checked with the token of the user. Throttled \
+                        like the logins.",
+                    Some(json_body("PasswordStrengthRequest")),
+                    json_response(
+                        "The zxcvbn score of the password, from 0 to 4, and the rules of the \
+                            policy it doesn't follow.",
+                        "PasswordStrength",
+                    ),
+                )
+            },
+            "/auth/oidc": {
+                "get": operation(
+                    "oidcProvider",
+                    "The name of the OIDC provider to log in with, for the login page.",
+                    None,
+                    json!({
+                        "description": "The display name of the provider, or null if OIDC \
+                            isn't configured.",
+                        "content": {
+                            "application/json": {
+                                "schema": { "type": "string", "nullable": true }
+                            }
+                        }
+                    }),
+                )
+            },
+            "/auth/oidc/login": {
+                "get": redirect_operation(
+                    "oidcLogin",
+                    "Start a login with the OIDC provider. 404 if OIDC isn't configured.",
+                    "To the authorization page of the provider, with the `oidc_state` cookie.",
+                )
+            },
+            "/auth/oidc/callback": {
+                "get": with_query_parameters(
+                    redirect_operation(
+                        "oidcCallback",
+                        "Finish the login with the OIDC provider, which redirects here. 404 if \
+                            OIDC isn't configured.",
+                        "To the web UI, with the `token` and `refresh_token` cookies.",
+                    ),
+                    &[
+                        ("code", "The authorization code."),
+                        ("state", "Has to match the `oidc_state` cookie."),
+                        ("error", "Why the provider refused the login."),
+                    ],
+                )
+            },
+            "/auth/refresh": {
+                "get": operation(
+                    "refresh",
+                    "Get a new JWT from the `refresh_token` cookie.",
+                    None,
+                    token_response(),
+                )
+            },
+            "/auth/logout": {
+                "get": operation(
+                    "logout",
+                    "Revoke the refresh token and the JWTs of the user, and clear the cookies.",
+                    None,
+                    json!({ "description": "Logged out." }),
+                )
+            },
+            "/health": {
+                "get": with_tag(
+                    operation(
+                        "health",
+                        "Whether the HTTP server answers. No token is needed.",
+                        None,
+                        json!({ "description": "OK." }),
+                    ),
+                    "health",
+                )
+            },
+            "/ready": {
+                "get": with_tag(
+                    operation(
+                        "ready",
+                        "Whether the database is reachable and the LDAP server listening. No \
+                            token is needed.",
+                        None,
+                        json!({ "description": "OK." }),
+                    ),
+                    "health",
+                )
+            },
+            "/api/user/{user_id}/avatar": {
+                "get": with_tag(
+                    with_path_parameter(
+                        operation(
+                            "getAvatar",
+                            "The avatar of the user, for the users allowed to read their \
+                                details. 404 if the user has none, 304 if the `If-None-Match` \
+                                header has its `ETag`.",
+                            None,
+                            json!({
+                                "description": "The avatar, with its `ETag`.",
+                                "content": {
+                                    "image/*": {
+                                        "schema": { "type": "string", "format": "binary" }
+                                    }
+                                }
+                            }),
+                        ),
+                        "user_id",
+                        "The ID of the user.",
+                    ),
+                    "users",
+                )
+            },
+            "/api/export.ldif": {
+                "get": with_tag(
+                    with_query_parameters(
+                        operation(
+                            "exportLdif",
+                            "Export the users and groups as LDIF, for the users that can read \
+                                them all.",
+                            None,
+                            json!({
+                                "description": "The LDIF export.",
+                                "content": { "text/x-ldif": { "schema": { "type": "string" } } }
+                            }),
+                        ),
+                        &[("base_dn", "Defaults to the base DN of the LDAP server.")],
+                    ),
+                    "users",
+                )
+            },
+        },
+        "components": {
+            "schemas": {
+                "BindRequest": object(&[("name", string.clone()), ("password", string.clone())]),
+                "ClientLoginStartRequest": object(&[
+                    ("username", string.clone()),
+                    ("login_start_request", opaque_message()),
+                ]),
+                "ServerLoginStartResponse": object(&[
+                    ("server_data", string.clone()),
+                    ("credential_response", opaque_message()),
+                ]),
+                "ClientLoginFinishRequest": object(&[
+                    ("server_data", string.clone()),
+                    ("credential_finalization", opaque_message()),
+                ]),
+                "ClientRegistrationStartRequest": object(&[
+                    ("username", string.clone()),
+                    ("registration_start_request", opaque_message()),
+                ]),
+                "ServerRegistrationStartResponse": object(&[
+                    ("server_data", string.clone()),
+                    ("registration_response", opaque_message()),
+                ]),
+                "ClientRegistrationFinishRequest": object(&[
+                    ("server_data", string.clone()),
+                    ("registration_upload", opaque_message()),
+                ]),
+                "PasswordStrengthRequest": json!({
+                    "type": "object",
+                    "required": ["password"],
+                    "properties": {
+                        "password": string.clone(),
+                        "user_id": string.clone(),
+                    },
+                }),
+                "PasswordStrength": object(&[
+                    ("score", json!({ "type": "integer", "minimum": 0, "maximum": 4 })),
+                    ("violations", json!({ "type": "array", "items": string })),
+                ]),
+            }
+        }
+    })
+}
+
+pub(crate) async fn get_openapi_spec() -> HttpResponse {
+    HttpResponse::Ok().json(openapi_spec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_references_exist() {
+        let spec = openapi_spec();
+        let serialized = spec.to_string();
+        for reference in serialized
+            .split("\"#/components/schemas/")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap())
+        {
+            assert!(
+                spec["components"]["schemas"].get(reference).is_some(),
+                "Missing schema {}",
+                reference
+            );
+        }
+    }
+
+    fn get_paths() -> Vec<String> {
+        let mut paths = openapi_spec()["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(
+            get_paths(),
+            vec![
+                "/api/export.ldif",
+                "/api/user/{user_id}/avatar",
+                "/auth",
+                "/auth/logout",
+                "/auth/oidc",
+                "/auth/oidc/callback",
+                "/auth/oidc/login",
+                "/auth/opaque/login/finish",
+                "/auth/opaque/login/start",
+                "/auth/opaque/register/finish",
+                "/auth/opaque/register/start",
+                "/auth/password/strength",
+                "/auth/refresh",
+                "/auth/reset/step1/{user_id}",
+                "/auth/reset/step2/{token}",
+                "/health",
+                "/ready",
+            ]
+        );
+    }
+
+    /// All the routes of `/auth` are described.
+    #[test]
+    fn test_auth_routes_are_described() {
+        let source = include_str!("auth_service.rs");
+        let configure_server = &source[source.find("pub fn configure_server").unwrap()..];
+        let paths = get_paths();
+        for route in configure_server
+            .split("web::resource(\"")
+            .skip(1)
+            .map(|s| format!("/auth{}", s.split('"').next().unwrap()))
+        {
+            assert!(paths.contains(&route), "{} is not described", route);
+        }
+    }
+
+    /// All the described operations are routed to a handler.
+    #[actix_rt::test]
+    async fn test_paths_are_registered() {
+        use crate::infra::{
+            tcp_backend_handler::MockTestTcpBackendHandler, tcp_server::configure_routes,
+        };
+        use actix_web::{http::Method, test, App};
+        let app =
+            test::init_service(App::new().configure(configure_routes::<MockTestTcpBackendHandler>))
+                .await;
+        let spec = openapi_spec();
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for method in item.as_object().unwrap().keys() {
+                let uri = path.replace("{user_id}", "bob").replace("{token}", "token");
+                let request = test::TestRequest::default()
+                    .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+                    .uri(&uri)
+                    .to_request();
+                // Without the state of the server, the handlers fail instead.
+                let status = test::call_service(&app, request).await.status();
+                assert!(
+                    status != 404 && status != 405,
+                    "{} {} is not routed: {}",
+                    method,
+                    path,
+                    status
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Daily job emailing the users whose password is about to expire, see
+//! [`PasswordExpiryConfig`].
+
+use crate::{
+    domain::handler::BackendHandler,
+    infra::{
+        configuration::{PasswordExpiryConfig, SmtpConfig},
+        mail::{self, EmailTemplate},
+    },
+};
+use actix::prelude::*;
+use chrono::{DateTime, Local, Utc};
+use cron::Schedule;
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+/// The users to warn today, with the expiry date of their password, sorted by user ID: the ones
+/// whose password expires in exactly `warning_days` days, or in less than a day. Running this
+/// once a day warns each user twice.
+pub fn get_warnings_due(
+    password_changes: HashMap<String, DateTime<Utc>>,
+    config: &PasswordExpiryConfig,
+    now: DateTime<Utc>,
+) -> Vec<(String, DateTime<Utc>)> {
+    let mut warnings = password_changes
+        .into_iter()
+        .filter_map(|(user_id, changed_at)| {
+            let expires_at = config.expires_at(changed_at)?;
+            if expires_at <= now {
+                return None;
+            }
+            // Rounded up: a password expiring in 23 hours has 1 day left.
+            let days_left = (expires_at - now - chrono::Duration::nanoseconds(1)).num_days() + 1;
+            if days_left == 1 || days_left == i64::from(config.warning_days) {
+                Some((user_id, expires_at))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    warnings.sort();
+    warnings
+}
+
+async fn send_warnings<Handler: BackendHandler>(
+    handler: Handler,
+    config: PasswordExpiryConfig,
+    smtp: SmtpConfig,
+) {
+    let password_changes = match handler.list_password_changes().await {
+        Ok(changes) => changes,
+        Err(e) => {
+            tracing::error!("Could not list the password changes: {}", e);
+            return;
+        }
+    };
+    for (user_id, expires_at) in get_warnings_due(password_changes, &config, Utc::now()) {
+        let user = match handler.get_user_details(&user_id).await {
+            Ok(user) => user,
+            Err(e) => {
+                tracing::error!("Could not get the details of {}: {}", user_id, e);
+                continue;
+            }
+        };
+        let mut context = tera::Context::new();
+        context.insert("display_name", &user.display_name);
+        context.insert("user_id", &user.user_id);
+        context.insert(
+            "expires_at",
+            &expires_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+        );
+        match mail::send_template_email(
+            &smtp,
+            &user.display_name,
+            &user.email,
+            EmailTemplate::PasswordExpiry,
+            &context,
+        )
+        .await
+        {
+            Ok(()) => tracing::info!("Sent the password expiry warning to {}", user_id),
+            Err(e) => tracing::error!("{:#}", e),
+        }
+    }
+}
+
+/// Actor running [`get_warnings_due`] and sending the emails on a schedule.
+pub struct ExpiryNotifier<Handler> {
+    schedule: Schedule,
+    handler: Handler,
+    config: PasswordExpiryConfig,
+    smtp: SmtpConfig,
+}
+
+impl<Handler: BackendHandler + Unpin + 'static> Actor for ExpiryNotifier<Handler> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Context<Self>) {
+        tracing::info!("Password expiry notifications started");
+
+        context.run_later(self.duration_until_next(), move |this, ctx| {
+            this.schedule_task(ctx)
+        });
+    }
+}
+
+impl<Handler: BackendHandler + Unpin + 'static> ExpiryNotifier<Handler> {
+    pub fn new(
+        cron_expression: &str,
+        handler: Handler,
+        config: PasswordExpiryConfig,
+        smtp: SmtpConfig,
+    ) -> Self {
+        let schedule = Schedule::from_str(cron_expression).unwrap();
+        Self {
+            schedule,
+            handler,
+            config,
+            smtp,
+        }
+    }
+
+    fn schedule_task(&self, ctx: &mut Context<Self>) {
+        tracing::info!("Sending the password expiry warnings");
+        let future = actix::fut::wrap_future::<_, Self>(send_warnings(
+            self.handler.clone(),
+            self.config.clone(),
+            self.smtp.clone(),
+        ));
+        ctx.spawn(future);
+
+        ctx.run_later(self.duration_until_next(), move |this, ctx| {
+            this.schedule_task(ctx)
+        });
+    }
+
+    fn duration_until_next(&self) -> Duration {
+        let now = Local::now();
+        let next = self.schedule.upcoming(Local).next().unwrap();
+        let duration_until = next.signed_duration_since(now);
+        duration_until.to_std().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_get_warnings_due() {
+        let config = PasswordExpiryConfig {
+            max_age_days: 90,
+            warning_days: 14,
+            force_change: false,
+        };
+        let now = Utc.ymd(2021, 6, 1).and_hms(8, 0, 0);
+        let days_ago = |days: i64, hours: i64| {
+            now - chrono::Duration::days(days) - chrono::Duration::hours(hours)
+        };
+        let changes = vec![
+            ("recent", days_ago(1, 0)),
+            // Expires in 13 days and 23 hours.
+            ("two_weeks", days_ago(76, 1)),
+            ("thirteen_days", days_ago(77, 1)),
+            ("tomorrow", days_ago(89, 1)),
+            ("expired", days_ago(91, 0)),
+        ]
+        .into_iter()
+        .map(|(user, date)| (user.to_string(), date))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            get_warnings_due(changes.clone(), &config, now),
+            vec![
+                (
+                    "tomorrow".to_string(),
+                    days_ago(89, 1) + chrono::Duration::days(90)
+                ),
+                (
+                    "two_weeks".to_string(),
+                    days_ago(76, 1) + chrono::Duration::days(90)
+                ),
+            ]
+        );
+        let no_expiry = PasswordExpiryConfig {
+            max_age_days: 0,
+            ..config
+        };
+        assert!(get_warnings_due(changes, &no_expiry, now).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Checks of the new passwords against the [`PasswordPolicyConfig`].
+//!
+//! The server only sees the passwords in clear when they are changed through LDAP: the web UI
+//! registers them with OPAQUE, so it has to check them before, with the
+//! `/auth/password/strength` endpoint or the policy returned by the `passwordPolicy` GraphQL
+//! query. Nothing stops a client from registering a password that doesn't follow the policy with
+//! OPAQUE, since the server never sees it: the policy is only enforced server-side for the LDAP
+//! password changes.
+
+use crate::{
+    domain::error::{DomainError, Result},
+    infra::configuration::PasswordPolicyConfig,
+};
+pub use lldap_auth::password::PasswordStrength;
+
+fn get_score(password: &str, user_inputs: &[&str]) -> u8 {
+    // The empty passwords are the only errors, and are as weak as can be.
+    zxcvbn::zxcvbn(password, user_inputs)
+        .map(|entropy| entropy.score())
+        .unwrap_or(0)
+}
+
+/// Returns the rules of the policy the password doesn't follow. `user_inputs` are the words the
+/// password shouldn't be based on, such as the user ID or the name of the user.
+pub fn get_policy_violations(
+    policy: &PasswordPolicyConfig,
+    password: &str,
+    user_inputs: &[&str],
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    if password.chars().count() < policy.min_length {
+        violations.push(format!("at least {} characters", policy.min_length));
+    }
+    let classes: [(bool, &str, fn(char) -> bool); 4] = [
+        (policy.require_lowercase, "a lowercase letter", |c| {
+            c.is_lowercase()
+        }),
+        (policy.require_uppercase, "an uppercase letter", |c| {
+            c.is_uppercase()
+        }),
+        (policy.require_digit, "a digit", |c| c.is_ascii_digit()),
+        (policy.require_special, "a special character", |c| {
+            !c.is_alphanumeric()
+        }),
+    ];
+    for (required, description, is_in_class) in classes.iter() {
+        if *required && !password.chars().any(|c| is_in_class(c)) {
+            violations.push(description.to_string());
+        }
+    }
+    if policy.min_strength > 0 && get_score(password, user_inputs) < policy.min_strength {
+        violations.push("not a common or easily guessed password".to_string());
+    }
+    violations
+}
+
+/// The score of the password and the rules of the policy it doesn't follow, for the strength
+/// meters of the registration and reset forms.
+pub fn evaluate_password(
+    policy: &PasswordPolicyConfig,
+    password: &str,
+    user_inputs: &[&str],
+) -> PasswordStrength {
+    PasswordStrength {
+        score: get_score(password, user_inputs),
+        violations: get_policy_violations(policy, password, user_inputs),
+    }
+}
+
+/// Fails with an [`InvalidInput`](DomainError::InvalidInput) listing the rules the password
+/// doesn't follow, if any.
+pub fn check_password(
+    policy: &PasswordPolicyConfig,
+    password: &str,
+    user_inputs: &[&str],
+) -> Result<()> {
+    let violations = get_policy_violations(policy, password, user_inputs);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(DomainError::InvalidInput(format!(
+            "The password doesn't follow the password policy, it needs: {}",
+            violations.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = PasswordPolicyConfig::default();
+        assert!(check_password(&policy, "password", &[]).is_ok());
+        assert_eq!(
+            get_policy_violations(&policy, "pass", &[]),
+            vec!["at least 8 characters"]
+        );
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let policy = PasswordPolicyConfig {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            get_policy_violations(&policy, "lowercase", &[]),
+            vec!["an uppercase letter", "a digit", "a special character"]
+        );
+        assert!(check_password(&policy, "L0wer-Upper", &[]).is_ok());
+        assert_eq!(
+            check_password(&policy, "ALL CAPS", &[])
+                .unwrap_err()
+                .to_string(),
+            "Invalid input: `The password doesn't follow the password policy, it needs: a \
+             lowercase letter, a digit`"
+        );
+    }
+
+    #[test]
+    fn test_min_strength() {
+        let policy = PasswordPolicyConfig {
+            min_strength: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            get_policy_violations(&policy, "password1", &[]),
+            vec!["not a common or easily guessed password"]
+        );
+        assert!(!get_policy_violations(&policy, "bob.bobberson", &["bob.bobberson"]).is_empty());
+        assert!(check_password(&policy, "correct horse battery staple", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_evaluate_password() {
+        let policy = PasswordPolicyConfig {
+            min_strength: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate_password(&policy, "", &[]),
+            PasswordStrength {
+                score: 0,
+                violations: vec![
+                    "at least 8 characters".to_string(),
+                    "not a common or easily guessed password".to_string()
+                ]
+            }
+        );
+        assert_eq!(
+            evaluate_password(&policy, "correct horse battery staple", &[]),
+            PasswordStrength {
+                score: 4,
+                violations: vec![]
+            }
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! The SCIM filters (RFC 7644, section 3.4.2.2), e.g. `userName sw "j" and not (emails pr)`,
+//! translated into a [`RequestFilter`] for the users, and evaluated in memory for the groups.
+
+use crate::domain::handler::{Group, RequestFilter, SubstringFilter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Contains,
+    StartsWith,
+    EndsWith,
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual,
+}
+
+impl CompareOp {
+    fn parse(op: &str) -> Option<Self> {
+        Some(match op.to_lowercase().as_str() {
+            "eq" => CompareOp::Equal,
+            "ne" => CompareOp::NotEqual,
+            "co" => CompareOp::Contains,
+            "sw" => CompareOp::StartsWith,
+            "ew" => CompareOp::EndsWith,
+            "gt" => CompareOp::GreaterThan,
+            "ge" => CompareOp::GreaterOrEqual,
+            "lt" => CompareOp::LessThan,
+            "le" => CompareOp::LessOrEqual,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    /// The attribute has a non-empty value.
+    Present(String),
+    /// The attribute, and the value as a string, even for the booleans and the numbers.
+    Compare(String, CompareOp, String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    OpenParen,
+    CloseParen,
+    /// An attribute path, an operator, or a literal such as `true` or `42`.
+    Word(String),
+    String(String),
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParen);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(escaped) => value.push(escaped),
+                            None => return Err("Unterminated string".to_string()),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            '[' | ']' => return Err("The complex attribute filters are not supported".to_string()),
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"' | '[' | ']') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses with the precedence of the RFC: `not`, then `and`, then `or`.
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn next_is_keyword(&mut self, keyword: &str) -> bool {
+        matches!(self.tokens.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.tokens.next() {
+            Some(t) if t == token => Ok(()),
+            t => Err(format!("Expected {:?}, got {:?}", token, t)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            self.tokens.next();
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.parse_not()?;
+        while self.next_is_keyword("and") {
+            self.tokens.next();
+            filter = Filter::And(Box::new(filter), Box::new(self.parse_not()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_not(&mut self) -> Result<Filter, String> {
+        if self.next_is_keyword("not") {
+            self.tokens.next();
+            self.expect(Token::OpenParen)?;
+            let filter = self.parse_or()?;
+            self.expect(Token::CloseParen)?;
+            return Ok(Filter::Not(Box::new(filter)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Filter, String> {
+        let attribute = match self.tokens.next() {
+            Some(Token::OpenParen) => {
+                let filter = self.parse_or()?;
+                self.expect(Token::CloseParen)?;
+                return Ok(filter);
+            }
+            Some(Token::Word(attribute)) => attribute,
+            t => return Err(format!("Expected an attribute, got {:?}", t)),
+        };
+        let op = match self.tokens.next() {
+            Some(Token::Word(op)) if op.eq_ignore_ascii_case("pr") => {
+                return Ok(Filter::Present(attribute))
+            }
+            Some(Token::Word(op)) => CompareOp::parse(&op)
+                .ok_or_else(|| format!("Unknown comparison operator `{}`", op))?,
+            t => return Err(format!("Expected an operator, got {:?}", t)),
+        };
+        match self.tokens.next() {
+            Some(Token::String(value)) | Some(Token::Word(value)) => {
+                Ok(Filter::Compare(attribute, op, value))
+            }
+            t => Err(format!("Expected a value, got {:?}", t)),
+        }
+    }
+}
+
+pub fn parse_filter(filter: &str) -> Result<Filter, String> {
+    let mut parser = Parser {
+        tokens: tokenize(filter)?.into_iter().peekable(),
+    };
+    let parsed = parser.parse_or()?;
+    match parser.tokens.next() {
+        None => Ok(parsed),
+        Some(t) => Err(format!("Unexpected {:?}", t)),
+    }
+}
+
+/// The attribute path in lowercase, without the schema URN, since the attribute names are
+/// case-insensitive, e.g. `name.givenname` for
+/// `urn:ietf:params:scim:schemas:core:2.0:User:name.givenName`.
+pub fn normalize_attribute(attribute: &str) -> String {
+    let attribute = attribute.to_lowercase();
+    match attribute.strip_prefix("urn:ietf:params:scim:schemas:core:2.0:") {
+        Some(rest) => rest
+            .split_once(':')
+            .map(|(_, path)| path.to_string())
+            .unwrap_or_else(|| rest.to_string()),
+        None => attribute,
+    }
+}
+
+/// The field of [`USER_FILTER_FIELDS`](crate::domain::handler::USER_FILTER_FIELDS) (or `uuid`)
+/// of a User attribute.
+fn get_user_field(attribute: &str) -> Result<&'static str, String> {
+    Ok(match normalize_attribute(attribute).as_str() {
+        "id" => "uuid",
+        "username" => "user_id",
+        "displayname" => "display_name",
+        "name.givenname" => "first_name",
+        "name.familyname" => "last_name",
+        "emails" | "emails.value" => "email",
+        _ => return Err(format!("Unsupported filter attribute `{}`", attribute)),
+    })
+}
+
+fn get_substring_filter(op: CompareOp, value: &str) -> Option<SubstringFilter> {
+    let value = Some(value.to_string());
+    match op {
+        CompareOp::Contains => Some(SubstringFilter {
+            any: value.into_iter().collect(),
+            ..SubstringFilter::default()
+        }),
+        CompareOp::StartsWith => Some(SubstringFilter {
+            initial: value,
+            ..SubstringFilter::default()
+        }),
+        CompareOp::EndsWith => Some(SubstringFilter {
+            final_: value,
+            ..SubstringFilter::default()
+        }),
+        _ => None,
+    }
+}
+
+const UNSUPPORTED_ORDERING: &str = "The gt, ge, lt and le operators are not supported";
+
+pub fn to_user_filter(filter: &Filter) -> Result<RequestFilter, String> {
+    Ok(match filter {
+        Filter::And(left, right) => {
+            RequestFilter::And(vec![to_user_filter(left)?, to_user_filter(right)?])
+        }
+        Filter::Or(left, right) => {
+            RequestFilter::Or(vec![to_user_filter(left)?, to_user_filter(right)?])
+        }
+        Filter::Not(filter) => RequestFilter::Not(Box::new(to_user_filter(filter)?)),
+        Filter::Present(attribute) => RequestFilter::Not(Box::new(RequestFilter::Equality(
+            get_user_field(attribute)?.to_string(),
+            String::new(),
+        ))),
+        Filter::Compare(attribute, op, value) => {
+            let field = get_user_field(attribute)?.to_string();
+            match op {
+                CompareOp::Equal => RequestFilter::Equality(field, value.clone()),
+                CompareOp::NotEqual => {
+                    RequestFilter::Not(Box::new(RequestFilter::Equality(field, value.clone())))
+                }
+                _ => match get_substring_filter(*op, value) {
+                    Some(substrings) => RequestFilter::Substring(field, substrings),
+                    None => return Err(UNSUPPORTED_ORDERING.to_string()),
+                },
+            }
+        }
+    })
+}
+
+/// Whether the group matches the filter, on its `id` or its `displayName`, compared
+/// case-insensitively.
+pub fn matches_group(filter: &Filter, group: &Group) -> Result<bool, String> {
+    let get_value = |attribute: &str| match normalize_attribute(attribute).as_str() {
+        "id" => Ok(group.id.0.to_string()),
+        "displayname" => Ok(group.display_name.clone()),
+        _ => Err(format!("Unsupported filter attribute `{}`", attribute)),
+    };
+    Ok(match filter {
+        Filter::And(left, right) => matches_group(left, group)? && matches_group(right, group)?,
+        Filter::Or(left, right) => matches_group(left, group)? || matches_group(right, group)?,
+        Filter::Not(filter) => !matches_group(filter, group)?,
+        Filter::Present(attribute) => !get_value(attribute)?.is_empty(),
+        Filter::Compare(attribute, op, expected) => {
+            let value = get_value(attribute)?;
+            match op {
+                CompareOp::Equal => value.to_lowercase() == expected.to_lowercase(),
+                CompareOp::NotEqual => value.to_lowercase() != expected.to_lowercase(),
+                _ => match get_substring_filter(*op, expected) {
+                    Some(substrings) => substrings.matches(&value),
+                    None => return Err(UNSUPPORTED_ORDERING.to_string()),
+                },
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse_filter(r#"userName eq "bob""#).unwrap(),
+            Filter::Compare("userName".to_string(), CompareOp::Equal, "bob".to_string())
+        );
+        assert_eq!(
+            parse_filter(r#"emails pr and not (name.givenName SW "J\"o") or active eq true"#)
+                .unwrap(),
+            Filter::Or(
+                Box::new(Filter::And(
+                    Box::new(Filter::Present("emails".to_string())),
+                    Box::new(Filter::Not(Box::new(Filter::Compare(
+                        "name.givenName".to_string(),
+                        CompareOp::StartsWith,
+                        "J\"o".to_string()
+                    ))))
+                )),
+                Box::new(Filter::Compare(
+                    "active".to_string(),
+                    CompareOp::Equal,
+                    "true".to_string()
+                ))
+            )
+        );
+        assert!(parse_filter(r#"userName eq "bob"#).is_err());
+        assert!(parse_filter(r#"userName is "bob""#).is_err());
+        assert!(parse_filter(r#"emails[type eq "work"] pr"#).is_err());
+        assert!(parse_filter(r#"(userName eq "bob""#).is_err());
+    }
+
+    #[test]
+    fn test_to_user_filter() {
+        let filter = parse_filter(
+            r#"urn:ietf:params:scim:schemas:core:2.0:User:userName ne "bob" and emails.value ew "@example.com""#,
+        )
+        .unwrap();
+        assert_eq!(
+            to_user_filter(&filter).unwrap(),
+            RequestFilter::And(vec![
+                RequestFilter::Not(Box::new(RequestFilter::Equality(
+                    "user_id".to_string(),
+                    "bob".to_string()
+                ))),
+                RequestFilter::Substring(
+                    "email".to_string(),
+                    SubstringFilter {
+                        final_: Some("@example.com".to_string()),
+                        ..SubstringFilter::default()
+                    }
+                ),
+            ])
+        );
+        assert!(to_user_filter(&parse_filter(r#"active eq true"#).unwrap()).is_err());
+        assert!(to_user_filter(&parse_filter(r#"userName gt "a""#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_matches_group() {
+        let group = Group {
+            id: GroupId(3),
+            display_name: "Media Users".to_string(),
+            users: vec![],
+        };
+        let matches = |filter: &str| matches_group(&parse_filter(filter).unwrap(), &group);
+        assert_eq!(matches(r#"displayName eq "media users""#), Ok(true));
+        assert_eq!(matches(r#"displayName co "admin" or id eq "3""#), Ok(true));
+        assert_eq!(matches(r#"not (displayName sw "Media")"#), Ok(false));
+        assert!(matches(r#"members eq "bob""#).is_err());
+    }
+}
@@ -0,0 +1,901 @@
+//! A SCIM 2.0 server (RFC 7643 and 7644) at `/scim/v2`, for the identity providers and the tools
+//! that provision the users and the groups, e.g. Okta or Azure AD.
+//!
+//! The requests are authenticated like the API, usually with an API token, and go through the
+//! [`AccessControlledBackendHandler`]. The users are identified by their UUID, which doesn't
+//! change when they are renamed, and the groups by their ID. Deactivating a user
+//! (`"active": false`) locks them; the passwords are not provisioned.
+
+mod filter;
+mod resources;
+
+use self::{
+    filter::{matches_group, parse_filter, to_user_filter},
+    resources::{
+        group_to_json, user_to_json, GroupChange, PatchRequest, ScimGroup, ScimUser, UserChanges,
+        GROUP_SCHEMA, USER_SCHEMA,
+    },
+};
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{
+            BackendHandler, CreateUserRequest, GroupId, GroupIdAndName, GroupRequestFilter,
+            RequestFilter, UpdateGroupRequest, UpdateUserRequest, User,
+        },
+    },
+    infra::{
+        access_control::AccessControlledBackendHandler,
+        audit_backend_handler::{with_audit_context, AuditContext},
+        auth_service::check_if_token_is_valid,
+        sessions::{SessionManager, WebSessionManager},
+        tcp_backend_handler::TcpBackendHandler,
+        tcp_server::AppState,
+    },
+};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, ResponseError};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const CONTENT_TYPE: &str = "application/scim+json";
+/// The page size when the client doesn't give one, and the maximum one.
+const DEFAULT_COUNT: usize = 100;
+const MAX_COUNT: usize = 1000;
+const LOCK_REASON: &str = "Deactivated through SCIM";
+
+/// An error, with its `scimType` from RFC 7644 if any.
+#[derive(Debug)]
+struct ScimError {
+    status: StatusCode,
+    scim_type: Option<&'static str>,
+    detail: String,
+}
+
+impl ScimError {
+    fn new(status: StatusCode, scim_type: Option<&'static str>, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            scim_type,
+            detail: detail.into(),
+        }
+    }
+
+    fn invalid_value(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, Some("invalidValue"), detail)
+    }
+
+    fn invalid_filter(detail: String) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, Some("invalidFilter"), detail)
+    }
+
+    fn not_found(detail: String) -> Self {
+        Self::new(StatusCode::NOT_FOUND, None, detail)
+    }
+}
+
+impl std::fmt::Display for ScimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.detail)
+    }
+}
+
+impl From<DomainError> for ScimError {
+    fn from(error: DomainError) -> Self {
+        let (status, scim_type) = match &error {
+            DomainError::DatabaseError(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND, None),
+            DomainError::InvalidInput(_) | DomainError::InvalidFields(_) => {
+                (StatusCode::BAD_REQUEST, Some("invalidValue"))
+            }
+            DomainError::Conflict(_) => (StatusCode::CONFLICT, Some("uniqueness")),
+            DomainError::AuthenticationError(_) => (StatusCode::UNAUTHORIZED, None),
+            DomainError::PermissionDenied(_) => (StatusCode::FORBIDDEN, None),
+            DomainError::TimeoutError(_) => (StatusCode::SERVICE_UNAVAILABLE, None),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        };
+        Self::new(status, scim_type, error.to_string())
+    }
+}
+
+impl ResponseError for ScimError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut error = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.as_u16().to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            error["scimType"] = json!(scim_type);
+        }
+        scim_response(self.status, error)
+    }
+}
+
+type ScimResult = Result<HttpResponse, ScimError>;
+
+fn scim_response(status: StatusCode, body: Value) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(CONTENT_TYPE)
+        .body(body.to_string())
+}
+
+/// Parses the body, whatever its content type, usually `application/scim+json`.
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ScimError> {
+    serde_json::from_slice(body).map_err(|e| {
+        ScimError::new(
+            StatusCode::BAD_REQUEST,
+            Some("invalidSyntax"),
+            e.to_string(),
+        )
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    filter: Option<String>,
+    /// 1-based.
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+impl ListQuery {
+    /// The number of resources to skip, and the page size.
+    fn get_page(&self) -> (usize, usize) {
+        (
+            self.start_index.unwrap_or(1).max(1) - 1,
+            self.count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT),
+        )
+    }
+}
+
+fn list_response(total: usize, offset: usize, resources: Vec<Value>) -> HttpResponse {
+    scim_response(
+        StatusCode::OK,
+        json!({
+            "schemas": [LIST_RESPONSE_SCHEMA],
+            "totalResults": total,
+            "startIndex": offset + 1,
+            "itemsPerPage": resources.len(),
+            "Resources": resources,
+        }),
+    )
+}
+
+/// Checks the token of the request: the handler has the permissions of its user, who is recorded
+/// as the actor of the audit events.
+async fn authenticate<Backend>(
+    data: &AppState<Backend>,
+    http_request: &HttpRequest,
+    bearer: &BearerAuth,
+) -> Result<(AccessControlledBackendHandler<Backend>, AuditContext), ScimError>
+where
+    Backend: BackendHandler + TcpBackendHandler,
+{
+    let validation_result = check_if_token_is_valid(data, bearer.token())
+        .await
+        .map_err(|e| ScimError::new(StatusCode::UNAUTHORIZED, None, e.to_string()))?;
+    // Like for GraphQL, the users whose password expired can only change it.
+    if data.password_expiry_config.force_change {
+        let expires_at = data
+            .backend_handler
+            .get_password_change(&validation_result.user)
+            .await?
+            .and_then(|changed_at| data.password_expiry_config.expires_at(changed_at));
+        if matches!(expires_at, Some(expires_at) if expires_at <= chrono::Utc::now()) {
+            return Err(ScimError::new(
+                StatusCode::FORBIDDEN,
+                None,
+                "The password has expired, change it first",
+            ));
+        }
+    }
+    let audit_context = AuditContext {
+        actor: Some(validation_result.user.clone()),
+        source_ip: http_request.peer_addr().map(|address| address.ip()),
+    };
+    Ok((
+        AccessControlledBackendHandler::new(data.backend_handler.clone(), validation_result),
+        audit_context,
+    ))
+}
+
+fn get_session_manager<Backend: Clone>(data: &AppState<Backend>) -> WebSessionManager<Backend> {
+    WebSessionManager::new(
+        data.backend_handler.clone(),
+        data.jwt_blacklist.clone(),
+        data.jwt_keys.clone(),
+    )
+}
+
+fn get_base_url<Backend>(data: &AppState<Backend>) -> String {
+    format!("{}/scim/v2", data.http_url.trim_end_matches('/'))
+}
+
+async fn find_user<Handler: BackendHandler>(
+    handler: &Handler,
+    id: &str,
+) -> Result<User, ScimError> {
+    handler
+        .list_users(Some(RequestFilter::Equality(
+            "uuid".to_string(),
+            id.to_string(),
+        )))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| ScimError::not_found(format!("User {} not found", id)))
+}
+
+async fn list_users_by_uuid<Handler: BackendHandler>(
+    handler: &Handler,
+    uuids: &[String],
+) -> Result<Vec<User>, ScimError> {
+    if uuids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let filters = uuids
+        .iter()
+        .map(|uuid| RequestFilter::Equality("uuid".to_string(), uuid.clone()))
+        .collect();
+    Ok(handler.list_users(Some(RequestFilter::Or(filters))).await?)
+}
+
+/// The IDs of the users with these UUIDs, failing if one of them doesn't exist.
+async fn get_user_ids<Handler: BackendHandler>(
+    handler: &Handler,
+    uuids: &[String],
+) -> Result<Vec<String>, ScimError> {
+    let users = list_users_by_uuid(handler, uuids).await?;
+    if let Some(unknown) = uuids
+        .iter()
+        .find(|uuid| !users.iter().any(|user| &user.uuid == *uuid))
+    {
+        return Err(ScimError::invalid_value(format!(
+            "Unknown member {}",
+            unknown
+        )));
+    }
+    Ok(users.into_iter().map(|user| user.user_id).collect())
+}
+
+async fn get_user_json<Handler: BackendHandler>(
+    handler: &Handler,
+    user: &User,
+    base_url: &str,
+) -> Result<Value, ScimError> {
+    let mut groups = handler
+        .get_user_groups(&user.user_id)
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+    groups.sort_by(|a, b| a.1.cmp(&b.1));
+    let active = handler.get_user_lock(&user.user_id).await?.is_none();
+    Ok(user_to_json(user, &groups, active, base_url))
+}
+
+/// Applies the changes to the user, renaming them first if the `userName` changed. Renaming or
+/// deactivating the user ends their sessions, like through GraphQL.
+async fn apply_user_changes<Handler: BackendHandler>(
+    handler: &Handler,
+    sessions: &dyn SessionManager,
+    user: &User,
+    changes: UserChanges,
+) -> Result<User, ScimError> {
+    let mut user_id = user.user_id.clone();
+    if let Some(new_user_id) = changes
+        .user_id
+        .filter(|new_user_id| new_user_id != &user_id)
+    {
+        handler.rename_user(&user_id, &new_user_id).await?;
+        sessions.logout_all_sessions(&user_id).await?;
+        user_id = new_user_id;
+    }
+    if changes.email.is_some()
+        || changes.display_name.is_some()
+        || changes.first_name.is_some()
+        || changes.last_name.is_some()
+    {
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: user_id.clone(),
+                email: changes.email,
+                display_name: changes.display_name,
+                first_name: changes.first_name,
+                last_name: changes.last_name,
+                ..UpdateUserRequest::default()
+            })
+            .await?;
+    }
+    if let Some(active) = changes.active {
+        let locked = handler.get_user_lock(&user_id).await?.is_some();
+        if active && locked {
+            handler.unlock_user(&user_id).await?;
+        } else if !active && !locked {
+            handler.lock_user(&user_id, LOCK_REASON).await?;
+            sessions.logout_all_sessions(&user_id).await?;
+        }
+    }
+    Ok(handler.get_user_details(&user_id).await?)
+}
+
+async fn list_users<Handler: BackendHandler>(
+    handler: &Handler,
+    query: &ListQuery,
+    base_url: &str,
+) -> ScimResult {
+    let filter = query
+        .filter
+        .as_deref()
+        .map(|filter| parse_filter(filter).and_then(|filter| to_user_filter(&filter)))
+        .transpose()
+        .map_err(ScimError::invalid_filter)?;
+    let users = handler.list_users(filter).await?;
+    let (offset, count) = query.get_page();
+    let mut resources = Vec::new();
+    for user in users.iter().skip(offset).take(count) {
+        resources.push(get_user_json(handler, user, base_url).await?);
+    }
+    Ok(list_response(users.len(), offset, resources))
+}
+
+async fn create_user<Handler: BackendHandler>(
+    handler: &Handler,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let changes = UserChanges::replace(parse_body::<ScimUser>(body)?);
+    let user_id = changes.user_id.clone().unwrap_or_default();
+    match handler.get_user_details(&user_id).await {
+        Ok(_) => {
+            return Err(ScimError::new(
+                StatusCode::CONFLICT,
+                Some("uniqueness"),
+                format!("User {} already exists", user_id),
+            ))
+        }
+        Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => (),
+        Err(e) => return Err(e.into()),
+    }
+    handler
+        .create_user(CreateUserRequest {
+            user_id: user_id.clone(),
+            email: changes
+                .email
+                .ok_or_else(|| ScimError::invalid_value("The email is required"))?,
+            display_name: changes.display_name,
+            first_name: changes.first_name,
+            last_name: changes.last_name,
+        })
+        .await?;
+    if changes.active == Some(false) {
+        handler.lock_user(&user_id, LOCK_REASON).await?;
+    }
+    let user = handler.get_user_details(&user_id).await?;
+    Ok(scim_response(
+        StatusCode::CREATED,
+        get_user_json(handler, &user, base_url).await?,
+    ))
+}
+
+async fn replace_user<Handler: BackendHandler>(
+    handler: &Handler,
+    sessions: &dyn SessionManager,
+    id: &str,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let changes = UserChanges::replace(parse_body::<ScimUser>(body)?);
+    let user =
+        apply_user_changes(handler, sessions, &find_user(handler, id).await?, changes).await?;
+    Ok(scim_response(
+        StatusCode::OK,
+        get_user_json(handler, &user, base_url).await?,
+    ))
+}
+
+async fn patch_user<Handler: BackendHandler>(
+    handler: &Handler,
+    sessions: &dyn SessionManager,
+    id: &str,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let request = parse_body::<PatchRequest>(body)?;
+    let changes = UserChanges::patch(&request.operations).map_err(ScimError::invalid_value)?;
+    let user =
+        apply_user_changes(handler, sessions, &find_user(handler, id).await?, changes).await?;
+    Ok(scim_response(
+        StatusCode::OK,
+        get_user_json(handler, &user, base_url).await?,
+    ))
+}
+
+async fn find_group<Handler: BackendHandler>(
+    handler: &Handler,
+    id: &str,
+) -> Result<GroupIdAndName, ScimError> {
+    let group_id = id
+        .parse()
+        .map(GroupId)
+        .map_err(|_| ScimError::not_found(format!("Group {} not found", id)))?;
+    Ok(handler.get_group_details(group_id).await?)
+}
+
+async fn get_group_json<Handler: BackendHandler>(
+    handler: &Handler,
+    group: &GroupIdAndName,
+    base_url: &str,
+) -> Result<Value, ScimError> {
+    let members = handler
+        .list_users(Some(RequestFilter::MemberOfId(group.0)))
+        .await?;
+    Ok(group_to_json(group, &members, base_url))
+}
+
+async fn apply_group_changes<Handler: BackendHandler>(
+    handler: &Handler,
+    group_id: GroupId,
+    changes: Vec<GroupChange>,
+) -> Result<(), ScimError> {
+    for change in changes {
+        match change {
+            GroupChange::Rename(display_name) => {
+                handler
+                    .update_group(UpdateGroupRequest {
+                        group_id,
+                        display_name: Some(display_name),
+                        description: None,
+                    })
+                    .await?
+            }
+            // Adding a member twice isn't an error.
+            GroupChange::AddMembers(uuids) => {
+                let members = handler
+                    .list_users(Some(RequestFilter::MemberOfId(group_id)))
+                    .await?;
+                let new_members = get_user_ids(handler, &uuids)
+                    .await?
+                    .into_iter()
+                    .filter(|user_id| !members.iter().any(|member| &member.user_id == user_id))
+                    .collect::<Vec<_>>();
+                handler.add_users_to_group(group_id, &new_members).await?
+            }
+            // Nor removing a user that doesn't exist anymore.
+            GroupChange::RemoveMembers(uuids) => {
+                let user_ids = list_users_by_uuid(handler, &uuids)
+                    .await?
+                    .into_iter()
+                    .map(|user| user.user_id)
+                    .collect::<Vec<_>>();
+                handler.remove_users_from_group(group_id, &user_ids).await?
+            }
+            GroupChange::SetMembers(uuids) => {
+                handler
+                    .set_group_members(group_id, &get_user_ids(handler, &uuids).await?)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn list_groups<Handler: BackendHandler>(
+    handler: &Handler,
+    query: &ListQuery,
+    base_url: &str,
+) -> ScimResult {
+    let filter = query
+        .filter
+        .as_deref()
+        .map(parse_filter)
+        .transpose()
+        .map_err(ScimError::invalid_filter)?;
+    let mut groups = Vec::new();
+    for group in handler.list_groups(None).await? {
+        let matches = match &filter {
+            Some(filter) => matches_group(filter, &group).map_err(ScimError::invalid_filter)?,
+            None => true,
+        };
+        if matches {
+            groups.push(GroupIdAndName(group.id, group.display_name));
+        }
+    }
+    let (offset, count) = query.get_page();
+    let mut resources = Vec::new();
+    for group in groups.iter().skip(offset).take(count) {
+        resources.push(get_group_json(handler, group, base_url).await?);
+    }
+    Ok(list_response(groups.len(), offset, resources))
+}
+
+async fn create_group<Handler: BackendHandler>(
+    handler: &Handler,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let group = parse_body::<ScimGroup>(body)?;
+    if !handler
+        .list_groups(Some(GroupRequestFilter::DisplayName(
+            group.display_name.clone(),
+        )))
+        .await?
+        .is_empty()
+    {
+        return Err(ScimError::new(
+            StatusCode::CONFLICT,
+            Some("uniqueness"),
+            format!("Group {} already exists", group.display_name),
+        ));
+    }
+    let user_ids = get_user_ids(
+        handler,
+        &group
+            .members
+            .into_iter()
+            .map(|member| member.value)
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+    let group_id = handler.create_group(&group.display_name).await?;
+    handler.add_users_to_group(group_id, &user_ids).await?;
+    let group = GroupIdAndName(group_id, group.display_name);
+    Ok(scim_response(
+        StatusCode::CREATED,
+        get_group_json(handler, &group, base_url).await?,
+    ))
+}
+
+async fn replace_group<Handler: BackendHandler>(
+    handler: &Handler,
+    id: &str,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let group = find_group(handler, id).await?;
+    let replacement = parse_body::<ScimGroup>(body)?;
+    let changes = vec![
+        GroupChange::Rename(replacement.display_name),
+        GroupChange::SetMembers(
+            replacement
+                .members
+                .into_iter()
+                .map(|member| member.value)
+                .collect(),
+        ),
+    ];
+    apply_group_changes(handler, group.0, changes).await?;
+    let group = handler.get_group_details(group.0).await?;
+    Ok(scim_response(
+        StatusCode::OK,
+        get_group_json(handler, &group, base_url).await?,
+    ))
+}
+
+async fn patch_group<Handler: BackendHandler>(
+    handler: &Handler,
+    id: &str,
+    body: &[u8],
+    base_url: &str,
+) -> ScimResult {
+    let group = find_group(handler, id).await?;
+    let request = parse_body::<PatchRequest>(body)?;
+    let changes = GroupChange::patch(&request.operations).map_err(ScimError::invalid_value)?;
+    apply_group_changes(handler, group.0, changes).await?;
+    let group = handler.get_group_details(group.0).await?;
+    Ok(scim_response(
+        StatusCode::OK,
+        get_group_json(handler, &group, base_url).await?,
+    ))
+}
+
+async fn get_users<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    query: web::Query<ListQuery>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        list_users(&handler, &query, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn post_user<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        create_user(&handler, &body, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn get_user<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(audit_context, async {
+        let user = find_user(&handler, &id).await?;
+        Ok(scim_response(
+            StatusCode::OK,
+            get_user_json(&handler, &user, &get_base_url(&data)).await?,
+        ))
+    })
+    .await
+}
+
+async fn put_user<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        replace_user(
+            &handler,
+            &get_session_manager(&data),
+            &id,
+            &body,
+            &get_base_url(&data),
+        ),
+    )
+    .await
+}
+
+async fn patch_user_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        patch_user(
+            &handler,
+            &get_session_manager(&data),
+            &id,
+            &body,
+            &get_base_url(&data),
+        ),
+    )
+    .await
+}
+
+async fn delete_user<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(audit_context, async {
+        let user = find_user(&handler, &id).await?;
+        handler.delete_user(&user.user_id).await?;
+        Ok(HttpResponse::NoContent().finish())
+    })
+    .await
+}
+
+async fn get_groups<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    query: web::Query<ListQuery>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        list_groups(&handler, &query, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn post_group<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        create_group(&handler, &body, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn get_group<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(audit_context, async {
+        let group = find_group(&handler, &id).await?;
+        Ok(scim_response(
+            StatusCode::OK,
+            get_group_json(&handler, &group, &get_base_url(&data)).await?,
+        ))
+    })
+    .await
+}
+
+async fn put_group<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        replace_group(&handler, &id, &body, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn patch_group_handler<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+    body: web::Bytes,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(
+        audit_context,
+        patch_group(&handler, &id, &body, &get_base_url(&data)),
+    )
+    .await
+}
+
+async fn delete_group<Backend>(
+    data: web::Data<AppState<Backend>>,
+    http_request: HttpRequest,
+    bearer: BearerAuth,
+    id: web::Path<String>,
+) -> ScimResult
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    let (handler, audit_context) = authenticate(&data, &http_request, &bearer).await?;
+    with_audit_context(audit_context, async {
+        let group = find_group(&handler, &id).await?;
+        handler.delete_group(group.0).await?;
+        Ok(HttpResponse::NoContent().finish())
+    })
+    .await
+}
+
+/// What the server supports, for the clients to adapt. No authentication needed.
+async fn get_service_provider_config() -> HttpResponse {
+    scim_response(
+        StatusCode::OK,
+        json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_COUNT },
+            "changePassword": { "supported": false },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "An API token, or the JWT of a user",
+                "primary": true,
+            }],
+        }),
+    )
+}
+
+async fn get_resource_types() -> HttpResponse {
+    let resource_type = |name: &str, schema: &str| {
+        json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ResourceType"],
+            "id": name,
+            "name": name,
+            "endpoint": format!("/{}s", name),
+            "schema": schema,
+        })
+    };
+    list_response(
+        2,
+        0,
+        vec![
+            resource_type("User", USER_SCHEMA),
+            resource_type("Group", GROUP_SCHEMA),
+        ],
+    )
+}
+
+pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
+{
+    cfg.route(
+        "/ServiceProviderConfig",
+        web::get().to(get_service_provider_config),
+    )
+    .route("/ResourceTypes", web::get().to(get_resource_types))
+    .service(
+        web::resource("/Users")
+            .route(web::get().to(get_users::<Backend>))
+            .route(web::post().to(post_user::<Backend>)),
+    )
+    .service(
+        web::resource("/Users/{id}")
+            .route(web::get().to(get_user::<Backend>))
+            .route(web::put().to(put_user::<Backend>))
+            .route(web::patch().to(patch_user_handler::<Backend>))
+            .route(web::delete().to(delete_user::<Backend>)),
+    )
+    .service(
+        web::resource("/Groups")
+            .route(web::get().to(get_groups::<Backend>))
+            .route(web::post().to(post_group::<Backend>)),
+    )
+    .service(
+        web::resource("/Groups/{id}")
+            .route(web::get().to(get_group::<Backend>))
+            .route(web::put().to(put_group::<Backend>))
+            .route(web::patch().to(patch_group_handler::<Backend>))
+            .route(web::delete().to(delete_group::<Backend>)),
+    );
+}
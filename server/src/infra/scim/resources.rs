@@ -0,0 +1,397 @@
+//! The SCIM representation of the users and groups, and the changes of the PUT and PATCH
+//! requests, before they are applied to the backend.
+
+use super::filter::{normalize_attribute, parse_filter, CompareOp, Filter};
+use crate::domain::handler::{GroupIdAndName, User};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+
+/// A user as returned by the API. Its `id` is the UUID of the user, which doesn't change when
+/// the user is renamed.
+pub fn user_to_json(user: &User, groups: &[GroupIdAndName], active: bool, base_url: &str) -> Value {
+    json!({
+        "schemas": [USER_SCHEMA],
+        "id": user.uuid,
+        "userName": user.user_id,
+        "displayName": user.display_name,
+        "name": {
+            "givenName": user.first_name,
+            "familyName": user.last_name,
+        },
+        "emails": [{ "value": user.email, "primary": true }],
+        "active": active,
+        "groups": groups
+            .iter()
+            .map(|group| json!({
+                "value": group.0 .0.to_string(),
+                "display": group.1,
+                "$ref": format!("{}/Groups/{}", base_url, group.0 .0),
+            }))
+            .collect::<Vec<_>>(),
+        "meta": {
+            "resourceType": "User",
+            "created": user.creation_date.to_rfc3339(),
+            "location": format!("{}/Users/{}", base_url, user.uuid),
+        },
+    })
+}
+
+/// A group as returned by the API, with its direct members.
+pub fn group_to_json(group: &GroupIdAndName, members: &[User], base_url: &str) -> Value {
+    json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": group.0 .0.to_string(),
+        "displayName": group.1,
+        "members": members
+            .iter()
+            .map(|user| json!({
+                "value": user.uuid,
+                "display": user.user_id,
+                "$ref": format!("{}/Users/{}", base_url, user.uuid),
+            }))
+            .collect::<Vec<_>>(),
+        "meta": {
+            "resourceType": "Group",
+            "location": format!("{}/Groups/{}", base_url, group.0 .0),
+        },
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimName {
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScimEmail {
+    value: String,
+    #[serde(default)]
+    primary: bool,
+}
+
+/// The user of a POST or a PUT. The other attributes, e.g. `password` or `externalId`, are
+/// ignored.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    user_name: String,
+    display_name: Option<String>,
+    #[serde(default)]
+    name: ScimName,
+    #[serde(default)]
+    emails: Vec<ScimEmail>,
+    active: Option<bool>,
+}
+
+fn get_primary_email(emails: &[ScimEmail]) -> Option<String> {
+    emails
+        .iter()
+        .find(|email| email.primary)
+        .or_else(|| emails.first())
+        .map(|email| email.value.clone())
+}
+
+/// The changes to make to a user: the fields left to `None` are not changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UserChanges {
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    /// `false` locks the user.
+    pub active: Option<bool>,
+}
+
+impl UserChanges {
+    /// The changes of a PUT, which replaces all the attributes.
+    pub fn replace(user: ScimUser) -> Self {
+        UserChanges {
+            user_id: Some(user.user_name),
+            email: get_primary_email(&user.emails),
+            display_name: Some(user.display_name.unwrap_or_default()),
+            first_name: Some(user.name.given_name.unwrap_or_default()),
+            last_name: Some(user.name.family_name.unwrap_or_default()),
+            active: Some(user.active.unwrap_or(true)),
+        }
+    }
+
+    /// Sets the attribute at `path` to `value`, `null` removing it.
+    fn set(&mut self, path: &str, value: &Value) -> Result<(), String> {
+        let get_string = || match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Null => Ok(String::new()),
+            _ => Err(format!("Invalid value for `{}`: {}", path, value)),
+        };
+        let path = normalize_attribute(path);
+        match path.as_str() {
+            "username" => match value {
+                Value::String(s) if !s.is_empty() => self.user_id = Some(s.clone()),
+                _ => return Err("userName is required".to_string()),
+            },
+            "displayname" => self.display_name = Some(get_string()?),
+            "name.givenname" => self.first_name = Some(get_string()?),
+            "name.familyname" => self.last_name = Some(get_string()?),
+            "name" | "" => match value {
+                Value::Object(attributes) => {
+                    for (name, value) in attributes {
+                        let name = if path.is_empty() {
+                            name.clone()
+                        } else {
+                            format!("name.{}", name)
+                        };
+                        self.set(&name, value)?;
+                    }
+                }
+                _ => return Err(format!("Invalid value for `{}`: {}", path, value)),
+            },
+            "emails" => {
+                let emails = serde_json::from_value::<Vec<ScimEmail>>(value.clone())
+                    .map_err(|e| format!("Invalid emails: {}", e))?;
+                self.email = Some(
+                    get_primary_email(&emails)
+                        .ok_or_else(|| "The email is required".to_string())?,
+                );
+            }
+            // Only one email: the filter of e.g. `emails[type eq "work"].value` is ignored.
+            path if path == "emails.value"
+                || (path.starts_with("emails[") && path.ends_with("].value")) =>
+            {
+                match value {
+                    Value::String(s) if !s.is_empty() => self.email = Some(s.clone()),
+                    _ => return Err("The email is required".to_string()),
+                }
+            }
+            // Some clients send the booleans as strings.
+            "active" => {
+                self.active = Some(match value {
+                    Value::Bool(active) => *active,
+                    Value::String(s) if s.eq_ignore_ascii_case("true") => true,
+                    Value::String(s) if s.eq_ignore_ascii_case("false") => false,
+                    _ => return Err(format!("Invalid value for active: {}", value)),
+                })
+            }
+            // Not stored, like the extensions such as the enterprise user.
+            "externalid" | "schemas" | "id" | "meta" | "password" | "groups" => (),
+            path if path.starts_with("urn:") => (),
+            _ => return Err(format!("Unsupported attribute `{}`", path)),
+        }
+        Ok(())
+    }
+
+    /// The changes of a PATCH, with its `add`, `replace` and `remove` operations.
+    pub fn patch(operations: &[PatchOperation]) -> Result<Self, String> {
+        let mut changes = UserChanges::default();
+        for operation in operations {
+            let path = operation.path.as_deref().unwrap_or_default();
+            match operation.op.to_lowercase().as_str() {
+                "add" | "replace" => changes.set(path, &operation.value)?,
+                "remove" if !path.is_empty() => changes.set(path, &Value::Null)?,
+                _ => return Err(format!("Unsupported operation `{}`", operation.op)),
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// The group of a POST or a PUT.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimMember>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScimMember {
+    /// The UUID of the user.
+    pub value: String,
+}
+
+/// A change to a group, the members being identified by their UUID.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupChange {
+    Rename(String),
+    AddMembers(Vec<String>),
+    RemoveMembers(Vec<String>),
+    SetMembers(Vec<String>),
+}
+
+fn get_member_ids(value: &Value) -> Result<Vec<String>, String> {
+    serde_json::from_value::<Vec<ScimMember>>(value.clone())
+        .map(|members| members.into_iter().map(|member| member.value).collect())
+        .map_err(|e| format!("Invalid members: {}", e))
+}
+
+/// The members selected by the filter of a path such as `members[value eq "<uuid>"]`.
+fn get_filtered_member_ids(filter: &Filter) -> Result<Vec<String>, String> {
+    match filter {
+        Filter::Compare(attribute, CompareOp::Equal, value)
+            if normalize_attribute(attribute) == "value" =>
+        {
+            Ok(vec![value.clone()])
+        }
+        Filter::Or(left, right) => {
+            let mut ids = get_filtered_member_ids(left)?;
+            ids.extend(get_filtered_member_ids(right)?);
+            Ok(ids)
+        }
+        _ => Err("Only the members can be selected, by value".to_string()),
+    }
+}
+
+fn get_group_change(op: &str, path: &str, value: &Value) -> Result<Option<GroupChange>, String> {
+    let path = normalize_attribute(path);
+    let op = op.to_lowercase();
+    Ok(Some(match (op.as_str(), path.as_str()) {
+        ("add", "displayname") | ("replace", "displayname") => match value {
+            Value::String(name) if !name.is_empty() => GroupChange::Rename(name.clone()),
+            _ => return Err("displayName is required".to_string()),
+        },
+        ("add", "members") => GroupChange::AddMembers(get_member_ids(value)?),
+        ("replace", "members") => GroupChange::SetMembers(get_member_ids(value)?),
+        // Without a value, all the members.
+        ("remove", "members") if value.is_null() => GroupChange::SetMembers(Vec::new()),
+        ("remove", "members") => GroupChange::RemoveMembers(get_member_ids(value)?),
+        (_, "externalid") | (_, "id") | (_, "schemas") | (_, "meta") => return Ok(None),
+        _ => return Err(format!("Unsupported operation `{}` on `{}`", op, path)),
+    }))
+}
+
+impl GroupChange {
+    /// The changes of a PATCH, in order.
+    pub fn patch(operations: &[PatchOperation]) -> Result<Vec<Self>, String> {
+        let mut changes = Vec::new();
+        for operation in operations {
+            match operation.path.as_deref() {
+                // Not normalized, the values of the filter being case-sensitive.
+                Some(path) if path.to_lowercase().starts_with("members[") => {
+                    if !operation.op.eq_ignore_ascii_case("remove") || !path.ends_with(']') {
+                        return Err(format!("Unsupported operation on `{}`", path));
+                    }
+                    let filter = parse_filter(&path["members[".len()..path.len() - 1])?;
+                    changes.push(GroupChange::RemoveMembers(get_filtered_member_ids(
+                        &filter,
+                    )?));
+                }
+                Some(path) => {
+                    changes.extend(get_group_change(&operation.op, path, &operation.value)?)
+                }
+                // The attributes to change are in the value.
+                None => match &operation.value {
+                    Value::Object(attributes) => {
+                        for (name, value) in attributes {
+                            changes.extend(get_group_change(&operation.op, name, value)?);
+                        }
+                    }
+                    _ => return Err("A path or an object value is required".to_string()),
+                },
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchOperation {
+    op: String,
+    path: Option<String>,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchRequest {
+    #[serde(rename = "Operations")]
+    pub operations: Vec<PatchOperation>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_operations(operations: Value) -> Vec<PatchOperation> {
+        serde_json::from_value::<PatchRequest>(json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": operations,
+        }))
+        .unwrap()
+        .operations
+    }
+
+    #[test]
+    fn test_user_changes() {
+        let user = serde_json::from_value::<ScimUser>(json!({
+            "schemas": [USER_SCHEMA],
+            "userName": "bob",
+            "name": { "givenName": "Bob" },
+            "emails": [
+                { "value": "bob@home.example.com" },
+                { "value": "bob@example.com", "primary": true },
+            ],
+            "externalId": "42",
+        }))
+        .unwrap();
+        assert_eq!(
+            UserChanges::replace(user),
+            UserChanges {
+                user_id: Some("bob".to_string()),
+                email: Some("bob@example.com".to_string()),
+                display_name: Some(String::new()),
+                first_name: Some("Bob".to_string()),
+                last_name: Some(String::new()),
+                active: Some(true),
+            }
+        );
+        let operations = get_operations(json!([
+            { "op": "Replace", "path": "emails[type eq \"work\"].value", "value": "b@example.com" },
+            { "op": "replace", "value": { "displayName": "Bobby", "active": "False" } },
+            { "op": "add", "path": "name", "value": { "familyName": "Smith" } },
+            { "op": "remove", "path": "name.givenName" },
+        ]));
+        assert_eq!(
+            UserChanges::patch(&operations).unwrap(),
+            UserChanges {
+                email: Some("b@example.com".to_string()),
+                display_name: Some("Bobby".to_string()),
+                first_name: Some(String::new()),
+                last_name: Some("Smith".to_string()),
+                active: Some(false),
+                ..UserChanges::default()
+            }
+        );
+        let operations = get_operations(json!([{ "op": "remove", "path": "userName" }]));
+        assert!(UserChanges::patch(&operations).is_err());
+        let operations = get_operations(json!([{ "op": "replace", "path": "title", "value": "" }]));
+        assert!(UserChanges::patch(&operations).is_err());
+    }
+
+    #[test]
+    fn test_group_changes() {
+        let operations = get_operations(json!([
+            { "op": "replace", "value": { "displayName": "Media", "externalId": "7" } },
+            { "op": "add", "path": "members", "value": [{ "value": "UUID-1" }, { "value": "uuid-2" }] },
+            { "op": "remove", "path": "members[value eq \"UUID-3\"]" },
+            { "op": "remove", "path": "members", "value": [{ "value": "uuid-4" }] },
+            { "op": "remove", "path": "members" },
+        ]));
+        assert_eq!(
+            GroupChange::patch(&operations).unwrap(),
+            vec![
+                GroupChange::Rename("Media".to_string()),
+                GroupChange::AddMembers(vec!["UUID-1".to_string(), "uuid-2".to_string()]),
+                GroupChange::RemoveMembers(vec!["UUID-3".to_string()]),
+                GroupChange::RemoveMembers(vec!["uuid-4".to_string()]),
+                GroupChange::SetMembers(vec![]),
+            ]
+        );
+        let operations = get_operations(json!([{ "op": "remove", "path": "displayName" }]));
+        assert!(GroupChange::patch(&operations).is_err());
+    }
+}
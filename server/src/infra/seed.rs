@@ -0,0 +1,240 @@
+//! Fills the database with fake users, groups and memberships, for demos and performance tests.
+
+use crate::{
+    domain::{
+        handler::{BackendHandler, CreateUserRequest, GroupId},
+        sql_backend_handler::SqlBackendHandler,
+        sql_opaque_handler::register_password,
+    },
+    infra::cli::SeedOpts,
+};
+use anyhow::{Context, Result};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::collections::{BTreeSet, HashMap};
+use tracing::info;
+
+const FIRST_NAMES: &[&str] = &[
+    "Alice", "Bob", "Carol", "David", "Emma", "Frank", "Grace", "Hugo", "Isabel", "Jack", "Karen",
+    "Liam", "Maria", "Noah", "Olivia", "Paul", "Quinn", "Rosa", "Samuel", "Tara", "Umar", "Vera",
+    "William", "Xenia", "Yusuf", "Zoe",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Anderson", "Brown", "Chen", "Dubois", "Evans", "Fischer", "Garcia", "Hansen", "Ito", "Jansen",
+    "Kowalski", "Lopez", "Martin", "Nguyen", "Okafor", "Petrov", "Rossi", "Silva", "Tanaka",
+    "Uddin", "Varga", "Weber", "Yilmaz", "Zhang",
+];
+
+const GROUP_NAMES: &[&str] = &[
+    "engineering",
+    "sales",
+    "marketing",
+    "support",
+    "finance",
+    "legal",
+    "design",
+    "operations",
+    "security",
+    "research",
+    "hr",
+    "it",
+];
+
+/// The most groups a generated user is a member of.
+const MAX_GROUPS_PER_USER: usize = 3;
+
+/// The generated directory, before being written to the database.
+#[derive(Debug)]
+struct SeedData {
+    users: Vec<CreateUserRequest>,
+    groups: Vec<String>,
+    /// Indices in `users` and `groups`.
+    memberships: Vec<(usize, usize)>,
+}
+
+/// Returns `base`, or `base` with the first number suffix that isn't taken.
+fn make_unique(base: String, taken: &mut BTreeSet<String>) -> String {
+    let mut candidate = base.clone();
+    let mut suffix = 2;
+    while taken.contains(&candidate) {
+        candidate = format!("{}{}", base, suffix);
+        suffix += 1;
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
+
+fn generate(
+    user_count: usize,
+    group_count: usize,
+    existing_user_ids: &BTreeSet<String>,
+    rng: &mut StdRng,
+) -> SeedData {
+    let mut user_ids = existing_user_ids.clone();
+    let users = (0..user_count)
+        .map(|_| {
+            let first_name = *FIRST_NAMES.choose(rng).unwrap();
+            let last_name = *LAST_NAMES.choose(rng).unwrap();
+            let user_id = make_unique(
+                format!("{}.{}", first_name, last_name).to_lowercase(),
+                &mut user_ids,
+            );
+            CreateUserRequest {
+                email: format!("{}@example.com", user_id),
+                user_id,
+                display_name: Some(format!("{} {}", first_name, last_name)),
+                first_name: Some(first_name.to_string()),
+                last_name: Some(last_name.to_string()),
+            }
+        })
+        .collect::<Vec<_>>();
+    let groups = (0..group_count)
+        .map(|i| match i / GROUP_NAMES.len() {
+            0 => GROUP_NAMES[i].to_string(),
+            n => format!("{}-{}", GROUP_NAMES[i % GROUP_NAMES.len()], n + 1),
+        })
+        .collect::<Vec<_>>();
+    let group_indices = (0..group_count).collect::<Vec<_>>();
+    let memberships = if group_count == 0 {
+        Vec::new()
+    } else {
+        (0..user_count)
+            .flat_map(|user| {
+                let count = rng.gen_range(1..=MAX_GROUPS_PER_USER.min(group_count));
+                group_indices
+                    .choose_multiple(rng, count)
+                    .map(|group| (user, *group))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    SeedData {
+        users,
+        groups,
+        memberships,
+    }
+}
+
+/// What was added to the database.
+#[derive(Debug, Default)]
+pub struct SeedReport {
+    pub users_created: usize,
+    pub groups_created: usize,
+    pub memberships_added: usize,
+}
+
+impl std::fmt::Display for SeedReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Users created: {}", self.users_created)?;
+        writeln!(f, "Groups created: {}", self.groups_created)?;
+        writeln!(f, "Memberships added: {}", self.memberships_added)
+    }
+}
+
+/// Generates the users, groups and memberships and adds them to the database. Groups that already
+/// exist are reused, and user IDs that are taken get a number suffix.
+pub async fn seed(handler: &SqlBackendHandler, opts: &SeedOpts) -> Result<SeedReport> {
+    let mut rng = match opts.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let existing_user_ids = handler
+        .list_users(None)
+        .await?
+        .into_iter()
+        .map(|u| u.user_id)
+        .collect::<BTreeSet<_>>();
+    let data = generate(opts.users, opts.groups, &existing_user_ids, &mut rng);
+    let mut report = SeedReport::default();
+
+    let existing_groups = handler
+        .list_groups(None)
+        .await?
+        .into_iter()
+        .map(|g| (g.display_name, g.id))
+        .collect::<HashMap<_, _>>();
+    let mut group_ids: Vec<GroupId> = Vec::with_capacity(data.groups.len());
+    for name in &data.groups {
+        group_ids.push(match existing_groups.get(name) {
+            Some(id) => *id,
+            None => {
+                report.groups_created += 1;
+                handler
+                    .create_group(name)
+                    .await
+                    .with_context(|| format!("while creating group {}", name))?
+            }
+        });
+    }
+
+    for user in &data.users {
+        handler
+            .create_user(user.clone())
+            .await
+            .with_context(|| format!("while creating user {}", user.user_id))?;
+        if let Some(password) = &opts.password {
+            register_password(handler, &user.user_id, password)
+                .await
+                .with_context(|| format!("while setting the password of {}", user.user_id))?;
+        }
+        report.users_created += 1;
+        if report.users_created % 100 == 0 {
+            info!("Created {} users", report.users_created);
+        }
+    }
+
+    for (user, group) in data.memberships {
+        handler
+            .add_user_to_group(&data.users[user].user_id, group_ids[group])
+            .await?;
+        report.memberships_added += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let existing = vec!["alice.brown".to_string()].into_iter().collect();
+        let data = generate(200, 15, &existing, &mut StdRng::seed_from_u64(42));
+        assert_eq!(data.users.len(), 200);
+        let user_ids = data
+            .users
+            .iter()
+            .map(|u| u.user_id.clone())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(user_ids.len(), 200);
+        assert!(!user_ids.contains("alice.brown"));
+        assert_eq!(data.groups.len(), 15);
+        assert_eq!(data.groups[0], "engineering");
+        assert_eq!(data.groups[12], "engineering-2");
+        for user in 0..200 {
+            let groups = data
+                .memberships
+                .iter()
+                .filter(|(u, _)| *u == user)
+                .map(|(_, g)| *g)
+                .collect::<BTreeSet<_>>();
+            assert!((1..=MAX_GROUPS_PER_USER).contains(&groups.len()));
+            assert!(groups.iter().all(|g| *g < 15));
+        }
+    }
+
+    #[test]
+    fn test_generate_no_groups() {
+        let data = generate(3, 0, &BTreeSet::new(), &mut StdRng::seed_from_u64(0));
+        assert_eq!(data.users.len(), 3);
+        assert!(data.memberships.is_empty());
+    }
+
+    #[test]
+    fn test_make_unique() {
+        let mut taken = vec!["bob".to_string()].into_iter().collect();
+        assert_eq!(make_unique("bob".to_string(), &mut taken), "bob2");
+        assert_eq!(make_unique("bob".to_string(), &mut taken), "bob3");
+        assert_eq!(make_unique("jim".to_string(), &mut taken), "jim");
+    }
+}
@@ -0,0 +1,267 @@
+//! The sessions of the web UI: a refresh token, valid for 30 days, and the JWTs issued with it,
+//! valid for a day.
+//!
+//! Ending the sessions of a user deletes their refresh tokens and blacklists their JWTs, in the
+//! database to survive the restarts and in memory to check the requests.
+//!
+//! The admins can impersonate a user, e.g. to see what they see in the web UI: they get a JWT of
+//! the user, valid for a short time and without refresh token. It names the admin, who is the
+//! actor of the changes made with it in the audit log.
+
+use crate::{
+    domain::{
+        error::{DomainError, Result},
+        handler::BackendHandler,
+    },
+    infra::{
+        audit_backend_handler::record_event,
+        auth_service::register_jwt,
+        jwt_keys::JwtKeys,
+        tcp_backend_handler::{Session, TcpBackendHandler},
+    },
+};
+use async_trait::async_trait;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+/// The hashes of the blacklisted JWTs, shared by all the workers.
+pub type JwtBlacklist = Arc<RwLock<HashSet<u64>>>;
+
+/// How long the JWTs of the impersonations are valid.
+pub const IMPERSONATION_DURATION_MINUTES: i64 = 15;
+
+/// Blacklists the JWTs issued to the user, in the database and in memory.
+pub async fn blacklist_jwts<Backend: TcpBackendHandler>(
+    backend_handler: &Backend,
+    jwt_blacklist: &JwtBlacklist,
+    user_id: &str,
+) -> Result<()> {
+    let new_blacklisted_jwts = backend_handler.blacklist_jwts(user_id).await?;
+    jwt_blacklist.write().unwrap().extend(new_blacklisted_jwts);
+    Ok(())
+}
+
+/// The sessions, for the GraphQL API which doesn't depend on the
+/// [`TcpBackendHandler`].
+#[async_trait]
+pub trait SessionManager: Send + Sync {
+    /// The sessions that haven't expired, optionally only the ones of a user.
+    async fn list_sessions(&self, user_id: Option<&str>) -> Result<Vec<Session>>;
+    /// Ends all the sessions of the user: their JWTs are refused from now on, and their refresh
+    /// tokens can't get new ones.
+    async fn logout_all_sessions(&self, user_id: &str) -> Result<()>;
+    /// A JWT of the user for the admin, recorded in the audit log, with its expiry date. The
+    /// locked and deleted users can't be impersonated.
+    async fn impersonate(
+        &self,
+        admin: &str,
+        user_id: &str,
+    ) -> Result<(String, chrono::DateTime<chrono::Utc>)>;
+}
+
+pub struct WebSessionManager<Backend> {
+    backend_handler: Backend,
+    jwt_blacklist: JwtBlacklist,
+    jwt_keys: JwtKeys,
+}
+
+impl<Backend> WebSessionManager<Backend> {
+    pub fn new(backend_handler: Backend, jwt_blacklist: JwtBlacklist, jwt_keys: JwtKeys) -> Self {
+        Self {
+            backend_handler,
+            jwt_blacklist,
+            jwt_keys,
+        }
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + TcpBackendHandler + Send + Sync> SessionManager
+    for WebSessionManager<Backend>
+{
+    async fn list_sessions(&self, user_id: Option<&str>) -> Result<Vec<Session>> {
+        self.backend_handler.list_sessions(user_id).await
+    }
+
+    async fn logout_all_sessions(&self, user_id: &str) -> Result<()> {
+        self.backend_handler.delete_refresh_tokens(user_id).await?;
+        blacklist_jwts(&self.backend_handler, &self.jwt_blacklist, user_id).await
+    }
+
+    async fn impersonate(
+        &self,
+        admin: &str,
+        user_id: &str,
+    ) -> Result<(String, chrono::DateTime<chrono::Utc>)> {
+        let result = async {
+            // Fails for the deleted users.
+            self.backend_handler.get_user_details(user_id).await?;
+            if self.backend_handler.get_user_lock(user_id).await?.is_some() {
+                return Err(DomainError::InvalidInput(format!(
+                    "user {} is locked",
+                    user_id
+                )));
+            }
+            let groups = self.backend_handler.get_user_groups(user_id).await?;
+            register_jwt(
+                &self.backend_handler,
+                &self.jwt_keys,
+                user_id,
+                groups,
+                chrono::Duration::minutes(IMPERSONATION_DURATION_MINUTES),
+                Some(admin),
+            )
+            .await
+        }
+        .await;
+        record_event(
+            &self.backend_handler,
+            Some(admin.to_string()),
+            "impersonate_user",
+            user_id.to_string(),
+            result.is_ok(),
+        )
+        .await;
+        let token = result?;
+        Ok((token.as_str().to_string(), token.claims().exp))
+    }
+}
+
+#[cfg(test)]
+mockall::mock! {
+    pub TestSessionManager{}
+    #[async_trait]
+    impl SessionManager for TestSessionManager {
+        async fn list_sessions(&self, user_id: Option<&str>) -> Result<Vec<Session>>;
+        async fn logout_all_sessions(&self, user_id: &str) -> Result<()>;
+        async fn impersonate(
+            &self,
+            admin: &str,
+            user_id: &str,
+        ) -> Result<(String, chrono::DateTime<chrono::Utc>)>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::handler::{GroupId, GroupIdAndName, User, UserLock},
+        infra::{jwt_keys::JwtKey, tcp_backend_handler::MockTestTcpBackendHandler},
+    };
+    use mockall::predicate::eq;
+
+    fn jwt_keys() -> JwtKeys {
+        JwtKeys::new(JwtKey::hmac("secret", b"server key"), vec![])
+    }
+
+    #[tokio::test]
+    async fn test_logout_all_sessions() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_delete_refresh_tokens()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_blacklist_jwts()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| Ok(vec![1, 2].into_iter().collect()));
+        let jwt_blacklist = JwtBlacklist::default();
+        jwt_blacklist.write().unwrap().insert(3);
+        let sessions = WebSessionManager::new(mock, jwt_blacklist.clone(), jwt_keys());
+        sessions.logout_all_sessions("bob").await.unwrap();
+        assert_eq!(
+            *jwt_blacklist.read().unwrap(),
+            vec![1, 2, 3].into_iter().collect::<HashSet<_>>()
+        );
+    }
+
+    fn expect_user(mock: &mut MockTestTcpBackendHandler, locked: bool) {
+        mock.expect_get_user_details()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: "bob".to_string(),
+                    ..Default::default()
+                })
+            });
+        mock.expect_get_user_lock()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(move |_| {
+                Ok(if locked {
+                    Some(UserLock {
+                        locked_at: chrono::Utc::now(),
+                        reason: "left".to_string(),
+                    })
+                } else {
+                    None
+                })
+            });
+    }
+
+    #[tokio::test]
+    async fn test_impersonate() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        expect_user(&mut mock, false);
+        mock.expect_get_user_groups()
+            .with(eq("bob"))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![GroupIdAndName(GroupId(3), "family".to_string())]
+                    .into_iter()
+                    .collect())
+            });
+        mock.expect_register_jwt()
+            .withf(|user, _, expiry_date| {
+                user == "bob"
+                    && *expiry_date
+                        <= chrono::Utc::now()
+                            + chrono::Duration::minutes(IMPERSONATION_DURATION_MINUTES)
+            })
+            .times(1)
+            .return_once(|_, _, _| Ok(()));
+        mock.expect_record_audit_event()
+            .withf(|event| {
+                event.action == "impersonate_user"
+                    && event.actor.as_deref() == Some("admin")
+                    && event.target == "bob"
+                    && event.success
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        let sessions = WebSessionManager::new(mock, JwtBlacklist::default(), jwt_keys());
+        let (token, _) = sessions.impersonate("admin", "bob").await.unwrap();
+        let token = jwt_keys()
+            .verify::<lldap_auth::JWTClaims>(token.as_str())
+            .unwrap();
+        assert_eq!(token.claims().user, "bob");
+        assert_eq!(
+            token.claims().groups,
+            vec!["family".to_string()].into_iter().collect()
+        );
+        assert_eq!(token.claims().impersonator.as_deref(), Some("admin"));
+    }
+
+    #[tokio::test]
+    async fn test_impersonate_locked_user() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        expect_user(&mut mock, true);
+        mock.expect_record_audit_event()
+            .withf(|event| {
+                event.action == "impersonate_user"
+                    && event.actor.as_deref() == Some("admin")
+                    && !event.success
+            })
+            .times(1)
+            .return_once(|_| Ok(()));
+        let sessions = WebSessionManager::new(mock, JwtBlacklist::default(), jwt_keys());
+        assert!(matches!(
+            sessions.impersonate("admin", "bob").await,
+            Err(DomainError::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+//! The graceful shutdown, on SIGTERM or Ctrl-C: the servers stop accepting connections, the LDAP
+//! connections are closed once their current operation is answered, and the HTTP requests are
+//! finished, within `shutdown_grace_period_seconds`.
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+/// Tells the LDAP connections that the server is shutting down.
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        // Only fails without any connection to tell.
+        let _ = self.0.send(true);
+    }
+}
+
+/// Waited for by the LDAP connections, between the operations.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Waits until the shutdown is triggered, which never happens if the trigger is dropped first.
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), Shutdown(receiver))
+}
+
+/// Waits for SIGTERM, e.g. from `docker stop`, or SIGINT.
+pub async fn wait_for_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (trigger, shutdown) = channel();
+        let mut waiting = shutdown.clone();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), waiting.wait())
+                .await
+                .is_err()
+        );
+        trigger.trigger();
+        waiting.wait().await;
+        // The connections opened after the trigger see it too.
+        shutdown.clone().wait().await;
+    }
+}
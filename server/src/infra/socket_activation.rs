@@ -0,0 +1,75 @@
+//! Listening sockets inherited from the parent process, following the `LISTEN_FDS` protocol of
+//! systemd socket activation (also implemented by tools like `systemfd`).
+//!
+//! The sockets stay open across restarts, so new connections wait in the backlog instead of being
+//! refused while the new process starts.
+
+use anyhow::{Context, Result};
+use listenfd::ListenFd;
+use std::net::TcpListener;
+use tracing::{info, warn};
+
+/// The inherited sockets not claimed by a server yet.
+#[derive(Debug, Default)]
+pub struct InheritedListeners(Vec<TcpListener>);
+
+impl InheritedListeners {
+    /// Takes the sockets passed by the parent process, if any, and clears the environment
+    /// variables so that they aren't passed further down.
+    pub fn from_env() -> Result<Self> {
+        let mut fds = ListenFd::from_env();
+        let mut listeners = Vec::with_capacity(fds.len());
+        for index in 0..fds.len() {
+            match fds
+                .take_tcp_listener(index)
+                .with_context(|| format!("while taking inherited socket #{}", index))?
+            {
+                Some(listener) => {
+                    listener.set_nonblocking(true)?;
+                    info!("Inherited a socket listening on {}", listener.local_addr()?);
+                    listeners.push(listener);
+                }
+                None => warn!(
+                    "Inherited socket #{} is not a TCP listener, ignoring it",
+                    index
+                ),
+            }
+        }
+        Ok(Self(listeners))
+    }
+
+    /// Removes the socket listening on `port` from the inherited ones.
+    pub fn take(&mut self, port: u16) -> Option<TcpListener> {
+        let index = self.0.iter().position(|listener| {
+            listener
+                .local_addr()
+                .map(|addr| addr.port() == port)
+                .unwrap_or(false)
+        })?;
+        Some(self.0.remove(index))
+    }
+
+    /// Warns about the sockets that no server claimed.
+    pub fn warn_unused(&self) {
+        for listener in &self.0 {
+            if let Ok(addr) = listener.local_addr() {
+                warn!("Inherited socket listening on {} is not used", addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut listeners = InheritedListeners(vec![listener]);
+        assert!(listeners.take(port.wrapping_add(1)).is_none());
+        assert!(listeners.take(port).is_some());
+        assert!(listeners.take(port).is_none());
+    }
+}
@@ -1,21 +1,59 @@
 use super::{jwt_sql_tables::*, tcp_backend_handler::*};
-use crate::domain::{error::*, sql_backend_handler::SqlBackendHandler};
+use crate::domain::{
+    error::*, handler::BackendHandler, identifiers::fold_case,
+    sql_backend_handler::SqlBackendHandler,
+};
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Query, SimpleExpr};
+use sea_query::{Expr, Iden, Order, Query, SimpleExpr};
+use sha2::{Digest, Sha512};
 use sqlx::Row;
 use std::collections::HashSet;
 
+/// How long the password reset links sent by email stay valid.
+const PASSWORD_RESET_TOKEN_HOURS: i64 = 24;
+
+/// Only the hash of the password reset tokens is stored, so that a leak of the database doesn't
+/// let anyone change the passwords.
+fn hash_password_reset_token(token: &str) -> String {
+    base64::encode(Sha512::digest(token.as_bytes()))
+}
+
+fn get_api_tokens_query() -> sea_query::SelectStatement {
+    Query::select()
+        .column(ApiTokens::ApiTokenId)
+        .column(ApiTokens::UserId)
+        .column(ApiTokens::Name)
+        .column(ApiTokens::Scope)
+        .column(ApiTokens::CreationDate)
+        .from(ApiTokens::Table)
+        .order_by(ApiTokens::ApiTokenId, Order::Asc)
+        .to_owned()
+}
+
+fn api_token_from_row(row: DbRow) -> DomainResult<ApiToken> {
+    let scope = row.get::<String, _>(&*ApiTokens::Scope.to_string());
+    Ok(ApiToken {
+        id: row.get::<i32, _>(&*ApiTokens::ApiTokenId.to_string()),
+        user_id: row.get::<String, _>(&*ApiTokens::UserId.to_string()),
+        name: row.get::<String, _>(&*ApiTokens::Name.to_string()),
+        scope: ApiTokenScope::parse(&scope)
+            .ok_or_else(|| DomainError::InternalError(format!("Unknown scope {}", scope)))?,
+        creation_date: row
+            .get::<chrono::DateTime<chrono::Utc>, _>(&*ApiTokens::CreationDate.to_string()),
+    })
+}
+
 #[async_trait]
 impl TcpBackendHandler for SqlBackendHandler {
     async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
         use sqlx::Result;
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(JwtStorage::JwtHash)
             .from(JwtStorage::Table)
-            .to_string(DbQueryBuilder {});
+            .build_db_query(self.backend());
 
-        sqlx::query(&query)
+        sqlx::query_with(&query, values)
             .map(|row: DbRow| row.get::<i64, _>(&*JwtStorage::JwtHash.to_string()) as u64)
             .fetch(&self.sql_pool)
             .collect::<Vec<sqlx::Result<u64>>>()
@@ -26,6 +64,7 @@ impl TcpBackendHandler for SqlBackendHandler {
     }
 
     async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
+        let user = fold_case(user);
         use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
         use std::collections::hash_map::DefaultHasher;
         use std::hash::{Hash, Hasher};
@@ -42,7 +81,7 @@ impl TcpBackendHandler for SqlBackendHandler {
             s.finish()
         };
         let duration = chrono::Duration::days(30);
-        let query = Query::insert()
+        let (query, values) = Query::insert()
             .into_table(JwtRefreshStorage::Table)
             .columns(vec![
                 JwtRefreshStorage::RefreshTokenHash,
@@ -54,52 +93,389 @@ impl TcpBackendHandler for SqlBackendHandler {
                 user.into(),
                 (chrono::Utc::now() + duration).naive_utc().into(),
             ])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok((refresh_token, duration))
     }
 
     async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
-        let query = Query::select()
+        let user = fold_case(user);
+        // The locked users and the ones in the recycle bin can't refresh their session.
+        if self.get_user_lock(&user).await?.is_some() || self.get_user_details(&user).await.is_err()
+        {
+            return Ok(false);
+        }
+        let (query, values) = Query::select()
             .expr(SimpleExpr::Value(1.into()))
             .from(JwtRefreshStorage::Table)
             .and_where(Expr::col(JwtRefreshStorage::RefreshTokenHash).eq(refresh_token_hash as i64))
             .and_where(Expr::col(JwtRefreshStorage::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
-        Ok(sqlx::query(&query)
+            .build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
             .fetch_optional(&self.sql_pool)
             .await?
             .is_some())
     }
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()> {
+        let user = fold_case(user);
+        let (query, values) = Query::insert()
+            .into_table(JwtStorage::Table)
+            .columns(vec![
+                JwtStorage::JwtHash,
+                JwtStorage::UserId,
+                JwtStorage::ExpiryDate,
+                JwtStorage::Blacklisted,
+            ])
+            .values_panic(vec![
+                (jwt_hash as i64).into(),
+                user.into(),
+                expiry_date.naive_utc().into(),
+                false.into(),
+            ])
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
     async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>> {
+        let user = fold_case(user);
         use sqlx::Result;
-        let query = Query::select()
+        let (query, values) = Query::select()
             .column(JwtStorage::JwtHash)
             .from(JwtStorage::Table)
-            .and_where(Expr::col(JwtStorage::UserId).eq(user))
-            .and_where(Expr::col(JwtStorage::Blacklisted).eq(true))
-            .to_string(DbQueryBuilder {});
-        let result = sqlx::query(&query)
+            .and_where(Expr::col(JwtStorage::UserId).eq(user.as_str()))
+            .and_where(Expr::col(JwtStorage::Blacklisted).eq(false))
+            .build_db_query(self.backend());
+        let result = sqlx::query_with(&query, values)
             .map(|row: DbRow| row.get::<i64, _>(&*JwtStorage::JwtHash.to_string()) as u64)
             .fetch(&self.sql_pool)
             .collect::<Vec<sqlx::Result<u64>>>()
             .await
             .into_iter()
             .collect::<Result<HashSet<u64>>>();
-        let query = Query::update()
+        let (query, values) = Query::update()
             .table(JwtStorage::Table)
             .values(vec![(JwtStorage::Blacklisted, true.into())])
-            .and_where(Expr::col(JwtStorage::UserId).eq(user))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .and_where(Expr::col(JwtStorage::UserId).eq(user.as_str()))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(result?)
     }
     async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()> {
-        let query = Query::delete()
+        let (query, values) = Query::delete()
             .from_table(JwtRefreshStorage::Table)
             .and_where(Expr::col(JwtRefreshStorage::RefreshTokenHash).eq(refresh_token_hash))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
         Ok(())
     }
+
+    async fn delete_refresh_tokens(&self, user: &str) -> DomainResult<()> {
+        let user = fold_case(user);
+        let (query, values) = Query::delete()
+            .from_table(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(user))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user: Option<&str>) -> DomainResult<Vec<Session>> {
+        let mut query_builder = Query::select()
+            .column(JwtRefreshStorage::UserId)
+            .column(JwtRefreshStorage::ExpiryDate)
+            .from(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .order_by(JwtRefreshStorage::ExpiryDate, Order::Asc)
+            .to_owned();
+        if let Some(user) = user {
+            query_builder.and_where(Expr::col(JwtRefreshStorage::UserId).eq(fold_case(user)));
+        }
+        let (query, values) = query_builder.build_db_query(self.backend());
+        Ok(sqlx::query_with(&query, values)
+            .map(|row: DbRow| Session {
+                user_id: row.get::<String, _>(&*JwtRefreshStorage::UserId.to_string()),
+                expiry_date: row.get::<chrono::DateTime<chrono::Utc>, _>(
+                    &*JwtRefreshStorage::ExpiryDate.to_string(),
+                ),
+            })
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn start_password_reset(&self, user: &str) -> DomainResult<Option<String>> {
+        let user = fold_case(user);
+        use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+        let (query, values) = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user.as_str()))
+            .and_where(Expr::col(Users::DeletedAt).is_null())
+            .build_db_query(self.backend());
+        if sqlx::query_with(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let token: String = OsRng
+            .sample_iter(&Alphanumeric)
+            .map(char::from)
+            .take(100)
+            .collect();
+        let (query, values) = Query::insert()
+            .into_table(PasswordResetTokens::Table)
+            .columns(vec![
+                PasswordResetTokens::Token,
+                PasswordResetTokens::UserId,
+                PasswordResetTokens::ExpiryDate,
+            ])
+            .values_panic(vec![
+                hash_password_reset_token(&token).into(),
+                user.into(),
+                (chrono::Utc::now() + chrono::Duration::hours(PASSWORD_RESET_TOKEN_HOURS))
+                    .naive_utc()
+                    .into(),
+            ])
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(Some(token))
+    }
+
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> DomainResult<String> {
+        let (query, values) = Query::select()
+            .column(PasswordResetTokens::UserId)
+            .from(PasswordResetTokens::Table)
+            .and_where(Expr::col(PasswordResetTokens::Token).eq(hash_password_reset_token(token)))
+            .and_where(
+                Expr::col(PasswordResetTokens::ExpiryDate).gt(chrono::Utc::now().naive_utc()),
+            )
+            .build_db_query(self.backend());
+        match sqlx::query_with(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+        {
+            Some(row) => Ok(row.get::<String, _>(&*PasswordResetTokens::UserId.to_string())),
+            None => Err(DomainError::AuthenticationError(
+                "Invalid or expired password reset token".to_string(),
+            )),
+        }
+    }
+
+    async fn delete_password_reset_token(&self, token: &str) -> DomainResult<()> {
+        let (query, values) = Query::delete()
+            .from_table(PasswordResetTokens::Table)
+            .and_where(Expr::col(PasswordResetTokens::Token).eq(hash_password_reset_token(token)))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> DomainResult<ApiToken> {
+        let user = fold_case(user);
+        let (query, values) = Query::insert()
+            .into_table(ApiTokens::Table)
+            .columns(vec![
+                ApiTokens::UserId,
+                ApiTokens::Name,
+                ApiTokens::TokenHash,
+                ApiTokens::Scope,
+                ApiTokens::CreationDate,
+            ])
+            .values_panic(vec![
+                user.into(),
+                name.into(),
+                token_hash.into(),
+                scope.as_str().into(),
+                chrono::Utc::now().naive_utc().into(),
+            ])
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?;
+        self.get_api_token(token_hash)
+            .await?
+            .ok_or(DomainError::DatabaseError(sqlx::Error::RowNotFound))
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> DomainResult<Option<ApiToken>> {
+        let (query, values) = get_api_tokens_query()
+            .and_where(Expr::col(ApiTokens::TokenHash).eq(token_hash))
+            .build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(api_token_from_row)
+            .transpose()
+    }
+
+    async fn list_api_tokens(&self, user: Option<&str>) -> DomainResult<Vec<ApiToken>> {
+        let mut query_builder = get_api_tokens_query();
+        if let Some(user) = user {
+            query_builder.and_where(Expr::col(ApiTokens::UserId).eq(fold_case(user)));
+        }
+        let (query, values) = query_builder.build_db_query(self.backend());
+        sqlx::query_with(&query, values)
+            .fetch_all(&self.sql_pool)
+            .await?
+            .into_iter()
+            .map(api_token_from_row)
+            .collect()
+    }
+
+    async fn delete_api_token(&self, token_id: i32) -> DomainResult<()> {
+        let (query, values) = Query::delete()
+            .from_table(ApiTokens::Table)
+            .and_where(Expr::col(ApiTokens::ApiTokenId).eq(token_id))
+            .build_db_query(self.backend());
+        if sqlx::query_with(&query, values)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 0
+        {
+            return Err(DomainError::DatabaseError(sqlx::Error::RowNotFound));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{domain::handler::CreateUserRequest, infra::configuration::ConfigurationBuilder};
+
+    async fn get_handler() -> SqlBackendHandler {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        crate::domain::sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: "bob".to_string(),
+                email: "bob@bob.bob".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        handler
+    }
+
+    #[tokio::test]
+    async fn test_user_ids_are_folded() {
+        let handler = get_handler().await;
+        let (refresh_token, _) = handler.create_refresh_token("BOB").await.unwrap();
+        let refresh_token_hash = {
+            use std::hash::{Hash, Hasher};
+            let mut s = std::collections::hash_map::DefaultHasher::new();
+            refresh_token.hash(&mut s);
+            s.finish()
+        };
+        assert!(handler
+            .check_token(refresh_token_hash, "Bob")
+            .await
+            .unwrap());
+        let sessions = handler.list_sessions(Some("BoB")).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].user_id, "bob");
+        handler.delete_refresh_tokens("BOB").await.unwrap();
+        assert!(handler.list_sessions(None).await.unwrap().is_empty());
+        assert!(handler.start_password_reset("Bob").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_token() {
+        let handler = get_handler().await;
+        let token = handler.start_password_reset("bob").await.unwrap().unwrap();
+        assert_eq!(
+            handler
+                .get_user_id_for_password_reset_token(&token)
+                .await
+                .unwrap(),
+            "bob"
+        );
+        // Only the hash is stored.
+        let (query, values) = Query::select()
+            .column(PasswordResetTokens::Token)
+            .from(PasswordResetTokens::Table)
+            .build_db_query(handler.backend());
+        let stored = sqlx::query_with(&query, values)
+            .fetch_one(&handler.sql_pool)
+            .await
+            .unwrap()
+            .get::<String, _>(&*PasswordResetTokens::Token.to_string());
+        assert_eq!(stored, hash_password_reset_token(&token));
+        assert!(handler
+            .get_user_id_for_password_reset_token(&stored)
+            .await
+            .is_err());
+        // The token can only be used once.
+        handler.delete_password_reset_token(&token).await.unwrap();
+        assert!(matches!(
+            handler.get_user_id_for_password_reset_token(&token).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_token_expired() {
+        let handler = get_handler().await;
+        let token = handler.start_password_reset("bob").await.unwrap().unwrap();
+        let (query, values) = Query::update()
+            .table(PasswordResetTokens::Table)
+            .values(vec![(
+                PasswordResetTokens::ExpiryDate,
+                (chrono::Utc::now() - chrono::Duration::minutes(1))
+                    .naive_utc()
+                    .into(),
+            )])
+            .build_db_query(handler.backend());
+        sqlx::query_with(&query, values)
+            .execute(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.get_user_id_for_password_reset_token(&token).await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_unknown_user() {
+        let handler = get_handler().await;
+        assert_eq!(handler.start_password_reset("alice").await.unwrap(), None);
+        assert!(handler
+            .get_user_id_for_password_reset_token("unknown")
+            .await
+            .is_err());
+    }
 }
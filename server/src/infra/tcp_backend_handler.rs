@@ -1,19 +1,110 @@
+use crate::infra::access_control::Permission;
 use async_trait::async_trait;
 use std::collections::HashSet;
 
 pub type DomainResult<T> = crate::domain::error::Result<T>;
 
+/// A session of the web UI, from the login until its refresh token expires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub user_id: String,
+    pub expiry_date: chrono::DateTime<chrono::Utc>,
+}
+
+/// What an API token is allowed to do, within the permissions of its user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiTokenScope {
+    /// Read the users and groups.
+    ReadOnly,
+    /// Create, modify and delete the users that aren't admins, and manage their memberships.
+    UserManagement,
+    /// Everything the user can do.
+    Full,
+}
+
+impl ApiTokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiTokenScope::ReadOnly => "read_only",
+            ApiTokenScope::UserManagement => "user_management",
+            ApiTokenScope::Full => "full",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read_only" => Some(ApiTokenScope::ReadOnly),
+            "user_management" => Some(ApiTokenScope::UserManagement),
+            "full" => Some(ApiTokenScope::Full),
+            _ => None,
+        }
+    }
+
+    /// The highest permission given by the scope, the user's own permission being the limit.
+    pub fn max_permission(&self) -> Permission {
+        match self {
+            ApiTokenScope::ReadOnly => Permission::Readonly,
+            ApiTokenScope::UserManagement => Permission::UserManager,
+            ApiTokenScope::Full => Permission::Admin,
+        }
+    }
+}
+
+/// A long-lived token of the HTTP API, for the automation tools. Only its hash is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: String,
+    pub name: String,
+    pub scope: ApiTokenScope,
+    pub creation_date: chrono::DateTime<chrono::Utc>,
+}
+
 #[async_trait]
 pub trait TcpBackendHandler {
     async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
     async fn create_refresh_token(&self, user: &str) -> DomainResult<(String, chrono::Duration)>;
     async fn check_token(&self, refresh_token_hash: u64, user: &str) -> DomainResult<bool>;
+    /// Records a JWT issued to the user, so that it can be blacklisted.
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> DomainResult<()>;
+    /// Blacklists the JWTs of the user, and returns the ones that weren't already.
     async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>>;
     async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()>;
+    /// Deletes all the refresh tokens of the user, ending their sessions.
+    async fn delete_refresh_tokens(&self, user: &str) -> DomainResult<()>;
+    /// The sessions that haven't expired, the soonest to expire first, optionally only the
+    /// ones of a user.
+    async fn list_sessions(&self, user: Option<&str>) -> DomainResult<Vec<Session>>;
+    /// Creates a one-time password reset token for the user, or returns `None` if the user
+    /// doesn't exist.
+    async fn start_password_reset(&self, user: &str) -> DomainResult<Option<String>>;
+    /// Returns the user of a password reset token that hasn't expired.
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> DomainResult<String>;
+    async fn delete_password_reset_token(&self, token: &str) -> DomainResult<()>;
+    /// Stores an API token of the user, given the hash of its secret.
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> DomainResult<ApiToken>;
+    /// Returns the API token with this hash, if it wasn't revoked.
+    async fn get_api_token(&self, token_hash: &str) -> DomainResult<Option<ApiToken>>;
+    /// The API tokens, the oldest first, optionally only the ones of a user.
+    async fn list_api_tokens(&self, user: Option<&str>) -> DomainResult<Vec<ApiToken>>;
+    async fn delete_api_token(&self, token_id: i32) -> DomainResult<()>;
 }
 
 #[cfg(test)]
-use crate::domain::handler::*;
+use crate::domain::{handler::*, opaque_handler::OpaqueHandler};
+#[cfg(test)]
+use lldap_auth::{login, registration};
 #[cfg(test)]
 mockall::mock! {
     pub TestTcpBackendHandler{}
@@ -25,27 +116,114 @@ mockall::mock! {
         async fn bind(&self, request: BindRequest) -> DomainResult<()>;
     }
     #[async_trait]
+    impl OpaqueHandler for TestTcpBackendHandler {
+        async fn login_start(
+            &self,
+            request: login::ClientLoginStartRequest
+        ) -> DomainResult<login::ServerLoginStartResponse>;
+        async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> DomainResult<String>;
+        async fn registration_start(
+            &self,
+            request: registration::ClientRegistrationStartRequest
+        ) -> DomainResult<registration::ServerRegistrationStartResponse>;
+        async fn registration_finish(
+            &self,
+            request: registration::ClientRegistrationFinishRequest
+        ) -> DomainResult<()>;
+        async fn is_password_reused(&self, username: &str, password: &str) -> DomainResult<bool>;
+    }
+    #[async_trait]
     impl BackendHandler for TestTcpBackendHandler {
         async fn list_users(&self, filters: Option<RequestFilter>) -> DomainResult<Vec<User>>;
-        async fn list_groups(&self) -> DomainResult<Vec<Group>>;
+        async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> DomainResult<Vec<Group>>;
         async fn get_user_details(&self, user_id: &str) -> DomainResult<User>;
+        async fn get_user_avatar(&self, user_id: &str) -> DomainResult<Option<Vec<u8>>>;
         async fn get_group_details(&self, group_id: GroupId) -> DomainResult<GroupIdAndName>;
+        async fn get_parent_groups(&self, group_id: GroupId) -> DomainResult<HashSet<GroupIdAndName>>;
         async fn get_user_groups(&self, user: &str) -> DomainResult<HashSet<GroupIdAndName>>;
         async fn create_user(&self, request: CreateUserRequest) -> DomainResult<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> DomainResult<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> DomainResult<()>;
         async fn delete_user(&self, user_id: &str) -> DomainResult<()>;
+        async fn restore_user(&self, user_id: &str) -> DomainResult<()>;
+        async fn list_deleted_users(&self) -> DomainResult<Vec<DeletedUser>>;
+        async fn rename_user(&self, user_id: &str, new_user_id: &str) -> DomainResult<()>;
         async fn create_group(&self, group_name: &str) -> DomainResult<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> DomainResult<()>;
         async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> DomainResult<()>;
         async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> DomainResult<()>;
+        async fn add_group_to_group(
+            &self,
+            member_group_id: GroupId,
+            parent_group_id: GroupId,
+        ) -> DomainResult<()>;
+        async fn remove_group_from_group(
+            &self,
+            member_group_id: GroupId,
+            parent_group_id: GroupId,
+        ) -> DomainResult<()>;
+        async fn get_user_hosts(&self, user_id: &str) -> DomainResult<Vec<String>>;
+        async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> DomainResult<()>;
+        async fn get_group_metadata(&self, group_id: GroupId) -> DomainResult<GroupMetadata>;
+        async fn get_group_mail(&self, group_id: GroupId) -> DomainResult<GroupMail>;
+        async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> DomainResult<()>;
+        async fn get_user_attributes(
+            &self,
+            user_id: &str,
+        ) -> DomainResult<std::collections::HashMap<String, Vec<String>>>;
+        async fn set_user_attribute(
+            &self,
+            user_id: &str,
+            name: &str,
+            values: Vec<String>,
+        ) -> DomainResult<()>;
+        async fn lock_user(&self, user_id: &str, reason: &str) -> DomainResult<()>;
+        async fn unlock_user(&self, user_id: &str) -> DomainResult<()>;
+        async fn get_user_lock(&self, user_id: &str) -> DomainResult<Option<UserLock>>;
+        async fn list_password_changes(
+            &self,
+        ) -> DomainResult<std::collections::HashMap<String, chrono::DateTime<chrono::Utc>>>;
+        async fn record_auth_failure(&self, failure: AuthFailure) -> DomainResult<()>;
+        async fn list_auth_failures(
+            &self,
+            user_id: Option<&str>,
+            offset: usize,
+            limit: usize,
+        ) -> DomainResult<Vec<AuthFailure>>;
+        async fn record_audit_event(&self, event: AuditEvent) -> DomainResult<()>;
+        async fn list_audit_events(
+            &self,
+            offset: usize,
+            limit: usize,
+        ) -> DomainResult<Vec<AuditEvent>>;
     }
     #[async_trait]
     impl TcpBackendHandler for TestTcpBackendHandler {
         async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
         async fn create_refresh_token(&self, user: &str) -> DomainResult<(String, chrono::Duration)>;
         async fn check_token(&self, refresh_token_hash: u64, user: &str) -> DomainResult<bool>;
+        async fn register_jwt(
+            &self,
+            user: &str,
+            jwt_hash: u64,
+            expiry_date: chrono::DateTime<chrono::Utc>,
+        ) -> DomainResult<()>;
         async fn blacklist_jwts(&self, user: &str) -> DomainResult<HashSet<u64>>;
         async fn delete_refresh_token(&self, refresh_token_hash: u64) -> DomainResult<()>;
+        async fn delete_refresh_tokens(&self, user: &str) -> DomainResult<()>;
+        async fn list_sessions(&self, user: Option<&str>) -> DomainResult<Vec<Session>>;
+        async fn start_password_reset(&self, user: &str) -> DomainResult<Option<String>>;
+        async fn get_user_id_for_password_reset_token(&self, token: &str) -> DomainResult<String>;
+        async fn delete_password_reset_token(&self, token: &str) -> DomainResult<()>;
+        async fn create_api_token(
+            &self,
+            user: &str,
+            name: &str,
+            scope: ApiTokenScope,
+            token_hash: &str,
+        ) -> DomainResult<ApiToken>;
+        async fn get_api_token(&self, token_hash: &str) -> DomainResult<Option<ApiToken>>;
+        async fn list_api_tokens(&self, user: Option<&str>) -> DomainResult<Vec<ApiToken>>;
+        async fn delete_api_token(&self, token_id: i32) -> DomainResult<()>;
     }
 }
@@ -4,19 +4,37 @@ use crate::{
         handler::{BackendHandler, LoginHandler},
         opaque_handler::OpaqueHandler,
     },
-    infra::{auth_service, configuration::Configuration, tcp_backend_handler::*},
+    infra::{
+        auth_service,
+        bind_throttle::BindThrottle,
+        configuration::{
+            AvatarConfig, Configuration, OidcConfig, PasswordExpiryConfig, PasswordPolicyConfig,
+            SmtpConfig,
+        },
+        connectors::{DeliveryLog, EventBus},
+        health::Readiness,
+        jwt_keys::{self, JwtKeys},
+        metrics::Metrics,
+        oidc_provider::{self, OidcProvider},
+        sessions::JwtBlacklist,
+        socket_activation::InheritedListeners,
+        tcp_backend_handler::*,
+    },
 };
 use actix_files::{Files, NamedFile};
 use actix_http::HttpServiceBuilder;
 use actix_server::ServerBuilder;
 use actix_service::map_config;
-use actix_web::{dev::AppConfig, web, App, HttpRequest, HttpResponse};
+use actix_web::{
+    dev::{AppConfig, Service},
+    web, App, HttpRequest, HttpResponse,
+};
 use anyhow::{Context, Result};
-use hmac::{Hmac, NewMac};
-use sha2::Sha512;
-use std::collections::HashSet;
+use futures_util::FutureExt;
 use std::path::PathBuf;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tracing_actix_web::TracingLogger;
 
 async fn index(req: HttpRequest) -> actix_web::Result<NamedFile> {
     let mut path = PathBuf::new();
@@ -34,78 +52,243 @@ pub(crate) fn error_to_http_response(error: DomainError) -> HttpResponse {
         DomainError::DatabaseError(_)
         | DomainError::InternalError(_)
         | DomainError::UnknownCryptoError(_) => HttpResponse::InternalServerError(),
-        DomainError::Base64DecodeError(_) | DomainError::BinarySerializationError(_) => {
-            HttpResponse::BadRequest()
-        }
+        DomainError::Base64DecodeError(_)
+        | DomainError::BinarySerializationError(_)
+        | DomainError::InvalidInput(_)
+        | DomainError::InvalidFields(_) => HttpResponse::BadRequest(),
+        DomainError::TimeoutError(_) => HttpResponse::ServiceUnavailable(),
+        DomainError::PermissionDenied(_) => HttpResponse::Forbidden(),
+        DomainError::Conflict(_) => HttpResponse::Conflict(),
     }
     .body(error.to_string())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn http_config<Backend>(
     cfg: &mut web::ServiceConfig,
     backend_handler: Backend,
-    jwt_secret: String,
-    jwt_blacklist: HashSet<u64>,
+    jwt_keys: JwtKeys,
+    jwt_blacklist: JwtBlacklist,
+    delivery_log: DeliveryLog,
+    event_bus: EventBus,
+    bind_throttle: BindThrottle,
+    metrics: Metrics,
+    readiness: Readiness,
+    avatar_config: AvatarConfig,
+    password_expiry_config: PasswordExpiryConfig,
+    password_policy_config: PasswordPolicyConfig,
+    oidc_config: Option<OidcConfig>,
+    oidc_provider: Option<OidcProvider>,
+    smtp_config: Option<SmtpConfig>,
+    http_url: String,
+    ldap_base_dn: String,
 ) where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
 {
     cfg.app_data(web::Data::new(AppState::<Backend> {
         backend_handler,
-        jwt_key: Hmac::new_varkey(jwt_secret.as_bytes()).unwrap(),
-        jwt_blacklist: RwLock::new(jwt_blacklist),
-    }))
+        jwt_keys,
+        jwt_blacklist,
+        delivery_log,
+        event_bus,
+        bind_throttle,
+        metrics,
+        readiness,
+        avatar_config,
+        password_expiry_config,
+        password_policy_config,
+        oidc_config,
+        oidc_provider,
+        smtp_config,
+        http_url,
+        ldap_base_dn,
+    }));
+    configure_routes::<Backend>(cfg);
     // Serve index.html and main.js, and default to index.html.
-    .route(
+    cfg.route(
         "/{filename:(index\\.html|main\\.js|style\\.css)?}",
         web::get().to(index),
     )
-    .service(web::scope("/auth").configure(auth_service::configure_server::<Backend>))
-    // API endpoint.
-    .service(
-        web::scope("/api")
-            .wrap(auth_service::CookieToHeaderTranslatorFactory)
-            .configure(super::graphql::api::configure_endpoint::<Backend>),
-    )
     // Serve the /pkg path with the compiled WASM app.
     .service(Files::new("/pkg", "./app/pkg"))
     // Default to serve index.html for unknown routes, to support routing.
     .service(web::scope("/").route("/.*", web::get().to(index)));
 }
 
+/// The routes of the server, without the files of the web UI.
+pub(crate) fn configure_routes<Backend>(cfg: &mut web::ServiceConfig)
+where
+    Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
+{
+    #[cfg(feature = "metrics")]
+    cfg.route(
+        "/metrics",
+        web::get().to(super::metrics::get_metrics::<Backend>),
+    );
+    cfg.route("/health", web::get().to(super::health::get_health))
+        .route("/ready", web::get().to(super::health::get_ready::<Backend>))
+        .service(web::scope("/auth").configure(auth_service::configure_server::<Backend>))
+        .route(
+            "/.well-known/openid-configuration",
+            web::get().to(oidc_provider::get_discovery_document::<Backend>),
+        )
+        // The public keys of the JWTs, for the other services to check them.
+        .route(
+            "/.well-known/jwks.json",
+            web::get().to(jwt_keys::get_jwks::<Backend>),
+        )
+        .service(web::scope("/oidc").configure(oidc_provider::configure_server::<Backend>))
+        // Provisioning of the users and groups.
+        .service(web::scope("/scim/v2").configure(super::scim::configure_server::<Backend>))
+        // API endpoint.
+        .service(
+            web::scope("/api")
+                .wrap(auth_service::CookieToHeaderTranslatorFactory)
+                .configure(super::graphql::api::configure_endpoint::<Backend>)
+                .route(
+                    "/user/{user_id}/avatar",
+                    web::get().to(super::avatar::get_avatar::<Backend>),
+                )
+                .route(
+                    "/export.ldif",
+                    web::get().to(super::export::get_ldif_export::<Backend>),
+                )
+                .route(
+                    "/openapi.json",
+                    web::get().to(super::openapi::get_openapi_spec),
+                ),
+        );
+}
+
 pub(crate) struct AppState<Backend> {
     pub backend_handler: Backend,
-    pub jwt_key: Hmac<Sha512>,
-    pub jwt_blacklist: RwLock<HashSet<u64>>,
+    pub jwt_keys: JwtKeys,
+    pub jwt_blacklist: JwtBlacklist,
+    pub delivery_log: DeliveryLog,
+    pub event_bus: EventBus,
+    pub bind_throttle: BindThrottle,
+    pub metrics: Metrics,
+    pub readiness: Readiness,
+    pub avatar_config: AvatarConfig,
+    pub password_expiry_config: PasswordExpiryConfig,
+    pub password_policy_config: PasswordPolicyConfig,
+    pub oidc_config: Option<OidcConfig>,
+    pub oidc_provider: Option<OidcProvider>,
+    pub smtp_config: Option<SmtpConfig>,
+    pub http_url: String,
+    pub ldap_base_dn: String,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn build_tcp_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    delivery_log: DeliveryLog,
+    event_bus: EventBus,
+    bind_throttle: BindThrottle,
+    metrics: Metrics,
+    readiness: Readiness,
     server_builder: ServerBuilder,
+    listeners: &mut InheritedListeners,
 ) -> Result<ServerBuilder>
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
 {
-    let jwt_secret = config.jwt_secret.clone();
-    let jwt_blacklist = backend_handler.get_jwt_blacklist().await?;
-    server_builder
-        .bind("http", ("0.0.0.0", config.http_port), move || {
-            let backend_handler = backend_handler.clone();
-            let jwt_secret = jwt_secret.clone();
-            let jwt_blacklist = jwt_blacklist.clone();
-            HttpServiceBuilder::new()
-                .finish(map_config(
-                    App::new().configure(move |cfg| {
-                        http_config(cfg, backend_handler, jwt_secret, jwt_blacklist)
+    let jwt_keys = JwtKeys::from_config(config)?;
+    // Shared by the workers, for the logouts to apply to all of them.
+    let jwt_blacklist = Arc::new(RwLock::new(backend_handler.get_jwt_blacklist().await?));
+    let avatar_config = config.avatar.clone();
+    let password_expiry_config = config.password_expiry.clone();
+    let password_policy_config = config.password_policy.clone();
+    let oidc_config = config.oidc.clone();
+    let oidc_provider = config
+        .oidc_provider
+        .as_ref()
+        .map(|provider_config| OidcProvider::new(provider_config, &config.http_url))
+        .transpose()?;
+    let smtp_config = config.smtp.clone();
+    let http_url = config.http_url.clone();
+    let ldap_base_dn = config.ldap_base_dn.clone();
+    let factory = move || {
+        let backend_handler = backend_handler.clone();
+        let jwt_keys = jwt_keys.clone();
+        let jwt_blacklist = jwt_blacklist.clone();
+        let delivery_log = delivery_log.clone();
+        let event_bus = event_bus.clone();
+        let bind_throttle = bind_throttle.clone();
+        let metrics = metrics.clone();
+        let readiness = readiness.clone();
+        let avatar_config = avatar_config.clone();
+        let password_expiry_config = password_expiry_config.clone();
+        let password_policy_config = password_policy_config.clone();
+        let oidc_config = oidc_config.clone();
+        let oidc_provider = oidc_provider.clone();
+        let smtp_config = smtp_config.clone();
+        let http_url = http_url.clone();
+        let ldap_base_dn = ldap_base_dn.clone();
+        HttpServiceBuilder::new()
+            .finish(map_config(
+                App::new()
+                    .wrap_fn({
+                        let metrics = metrics.clone();
+                        move |request, service| {
+                            let metrics = metrics.clone();
+                            let method = request.method().to_string();
+                            let start = Instant::now();
+                            service.call(request).map(move |response| {
+                                // The pattern of the route rather than the path, e.g. for the avatars.
+                                let (route, status) = match &response {
+                                    Ok(response) => (
+                                        response.request().match_pattern(),
+                                        response.status().as_u16(),
+                                    ),
+                                    Err(e) => (None, e.as_response_error().status_code().as_u16()),
+                                };
+                                metrics.record_http_request(
+                                    route.as_deref().unwrap_or("unmatched"),
+                                    &method,
+                                    status,
+                                    start.elapsed(),
+                                );
+                                response
+                            })
+                        }
+                    })
+                    // Opens a span with a `request_id` for each request.
+                    .wrap(TracingLogger::default())
+                    .configure(move |cfg| {
+                        http_config(
+                            cfg,
+                            backend_handler,
+                            jwt_keys,
+                            jwt_blacklist,
+                            delivery_log,
+                            event_bus,
+                            bind_throttle,
+                            metrics,
+                            readiness,
+                            avatar_config,
+                            password_expiry_config,
+                            password_policy_config,
+                            oidc_config,
+                            oidc_provider,
+                            smtp_config,
+                            http_url,
+                            ldap_base_dn,
+                        )
                     }),
-                    |_| AppConfig::default(),
-                ))
-                .tcp()
-        })
-        .with_context(|| {
-            format!(
-                "While bringing up the TCP server with port {}",
-                config.http_port
-            )
-        })
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+    };
+    match listeners.take(config.http_port) {
+        Some(listener) => server_builder.listen("http", listener, factory),
+        None => server_builder.bind("http", ("0.0.0.0", config.http_port), factory),
+    }
+    .with_context(|| {
+        format!(
+            "While bringing up the TCP server with port {}",
+            config.http_port
+        )
+    })
 }
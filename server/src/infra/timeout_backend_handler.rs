@@ -0,0 +1,618 @@
+use crate::{
+    domain::{error::*, handler::*, opaque_handler::*},
+    infra::{
+        metrics::Metrics,
+        tcp_backend_handler::{ApiToken, ApiTokenScope, Session, TcpBackendHandler},
+    },
+};
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// A backend handler forwarding everything to `Backend`, but failing with
+/// [`DomainError::TimeoutError`] when an operation takes longer than the timeout, e.g. because the
+/// database is stuck, so that the clients get an error instead of hanging.
+///
+/// Being the layer next to the database, it also records the duration of the operations in the
+/// [`Metrics`].
+#[derive(Clone)]
+pub struct TimeoutBackendHandler<Backend> {
+    inner: Backend,
+    timeout: Duration,
+    metrics: Metrics,
+}
+
+impl<Backend> TimeoutBackendHandler<Backend> {
+    pub fn new(inner: Backend, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    async fn run<T>(&self, operation: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let start = Instant::now();
+        let result = tokio::time::timeout(self.timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(DomainError::TimeoutError(operation.to_string())));
+        self.metrics
+            .record_backend_operation(operation, result.is_ok(), start.elapsed());
+        result
+    }
+}
+
+#[async_trait]
+impl<Backend: LoginHandler + Sync> LoginHandler for TimeoutBackendHandler<Backend> {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        self.run("bind", self.inner.bind(request)).await
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for TimeoutBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<RequestFilter>) -> Result<Vec<User>> {
+        self.run("list_users", self.inner.list_users(filters)).await
+    }
+
+    /// The timeout applies to each user: a large result can take longer as a whole.
+    fn list_users_stream(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+    ) -> BoxStream<'_, Result<User>> {
+        let timeout = self.timeout;
+        let users = self.inner.list_users_stream(filters, order);
+        futures::stream::unfold(Some(users), move |state| async move {
+            let mut users = state?;
+            match tokio::time::timeout(timeout, users.next()).await {
+                Ok(Some(user)) => Some((user, Some(users))),
+                Ok(None) => None,
+                Err(_) => Some((
+                    Err(DomainError::TimeoutError("list_users".to_string())),
+                    None,
+                )),
+            }
+        })
+        .boxed()
+    }
+
+    async fn list_users_page(
+        &self,
+        filters: Option<RequestFilter>,
+        order: UserOrder,
+        direction: SortDirection,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<User>> {
+        self.run(
+            "list_users_page",
+            self.inner
+                .list_users_page(filters, order, direction, offset, limit),
+        )
+        .await
+    }
+
+    async fn count_users(&self, filters: Option<RequestFilter>) -> Result<usize> {
+        self.run("count_users", self.inner.count_users(filters))
+            .await
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.run("list_groups", self.inner.list_groups(filters))
+            .await
+    }
+
+    async fn list_groups_page(
+        &self,
+        filters: Option<GroupRequestFilter>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Group>> {
+        self.run(
+            "list_groups_page",
+            self.inner.list_groups_page(filters, offset, limit),
+        )
+        .await
+    }
+
+    async fn list_groups_with_member_count(&self) -> Result<Vec<GroupSummary>> {
+        self.run(
+            "list_groups_with_member_count",
+            self.inner.list_groups_with_member_count(),
+        )
+        .await
+    }
+
+    async fn get_user_details(&self, user_id: &str) -> Result<User> {
+        self.run("get_user_details", self.inner.get_user_details(user_id))
+            .await
+    }
+
+    async fn get_user_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        self.run("get_user_avatar", self.inner.get_user_avatar(user_id))
+            .await
+    }
+
+    async fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.run("get_user_by_email", self.inner.get_user_by_email(email))
+            .await
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.run("get_group_details", self.inner.get_group_details(group_id))
+            .await
+    }
+
+    async fn get_parent_groups(&self, group_id: GroupId) -> Result<HashSet<GroupIdAndName>> {
+        self.run("get_parent_groups", self.inner.get_parent_groups(group_id))
+            .await
+    }
+
+    async fn get_group_members(
+        &self,
+        group_id: GroupId,
+        offset: usize,
+        limit: usize,
+    ) -> Result<GroupMembersPage> {
+        self.run(
+            "get_group_members",
+            self.inner.get_group_members(group_id, offset, limit),
+        )
+        .await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.run("create_user", self.inner.create_user(request))
+            .await
+    }
+
+    async fn bulk_create_users(&self, requests: Vec<CreateUserRequest>) -> Result<Vec<Result<()>>> {
+        self.run("bulk_create_users", self.inner.bulk_create_users(requests))
+            .await
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        self.run("update_user", self.inner.update_user(request))
+            .await
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.run("update_group", self.inner.update_group(request))
+            .await
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.run("delete_user", self.inner.delete_user(user_id))
+            .await
+    }
+
+    async fn restore_user(&self, user_id: &str) -> Result<()> {
+        self.run("restore_user", self.inner.restore_user(user_id))
+            .await
+    }
+
+    async fn list_deleted_users(&self) -> Result<Vec<DeletedUser>> {
+        self.run("list_deleted_users", self.inner.list_deleted_users())
+            .await
+    }
+
+    async fn rename_user(&self, user_id: &str, new_user_id: &str) -> Result<()> {
+        self.run("rename_user", self.inner.rename_user(user_id, new_user_id))
+            .await
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.run("create_group", self.inner.create_group(group_name))
+            .await
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.run("delete_group", self.inner.delete_group(group_id))
+            .await
+    }
+
+    async fn add_user_to_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.run(
+            "add_user_to_group",
+            self.inner.add_user_to_group(user_id, group_id),
+        )
+        .await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &str, group_id: GroupId) -> Result<()> {
+        self.run(
+            "remove_user_from_group",
+            self.inner.remove_user_from_group(user_id, group_id),
+        )
+        .await
+    }
+
+    async fn add_group_to_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.run(
+            "add_group_to_group",
+            self.inner
+                .add_group_to_group(member_group_id, parent_group_id),
+        )
+        .await
+    }
+
+    async fn remove_group_from_group(
+        &self,
+        member_group_id: GroupId,
+        parent_group_id: GroupId,
+    ) -> Result<()> {
+        self.run(
+            "remove_group_from_group",
+            self.inner
+                .remove_group_from_group(member_group_id, parent_group_id),
+        )
+        .await
+    }
+
+    async fn add_users_to_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        self.run(
+            "add_users_to_group",
+            self.inner.add_users_to_group(group_id, user_ids),
+        )
+        .await
+    }
+
+    async fn remove_users_from_group(&self, group_id: GroupId, user_ids: &[String]) -> Result<()> {
+        self.run(
+            "remove_users_from_group",
+            self.inner.remove_users_from_group(group_id, user_ids),
+        )
+        .await
+    }
+
+    async fn set_group_members(
+        &self,
+        group_id: GroupId,
+        user_ids: &[String],
+    ) -> Result<MembershipChanges> {
+        self.run(
+            "set_group_members",
+            self.inner.set_group_members(group_id, user_ids),
+        )
+        .await
+    }
+
+    async fn get_user_groups(&self, user: &str) -> Result<HashSet<GroupIdAndName>> {
+        self.run("get_user_groups", self.inner.get_user_groups(user))
+            .await
+    }
+
+    async fn get_users_groups(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, HashSet<GroupIdAndName>>> {
+        self.run("get_users_groups", self.inner.get_users_groups(user_ids))
+            .await
+    }
+
+    async fn get_user_hosts(&self, user_id: &str) -> Result<Vec<String>> {
+        self.run("get_user_hosts", self.inner.get_user_hosts(user_id))
+            .await
+    }
+
+    async fn set_user_hosts(&self, user_id: &str, hosts: Vec<String>) -> Result<()> {
+        self.run("set_user_hosts", self.inner.set_user_hosts(user_id, hosts))
+            .await
+    }
+
+    async fn get_group_metadata(&self, group_id: GroupId) -> Result<GroupMetadata> {
+        self.run(
+            "get_group_metadata",
+            self.inner.get_group_metadata(group_id),
+        )
+        .await
+    }
+
+    async fn get_group_mail(&self, group_id: GroupId) -> Result<GroupMail> {
+        self.run("get_group_mail", self.inner.get_group_mail(group_id))
+            .await
+    }
+
+    async fn set_group_mail(&self, group_id: GroupId, mail: GroupMail) -> Result<()> {
+        self.run("set_group_mail", self.inner.set_group_mail(group_id, mail))
+            .await
+    }
+
+    async fn get_user_attributes(&self, user_id: &str) -> Result<HashMap<String, Vec<String>>> {
+        self.run(
+            "get_user_attributes",
+            self.inner.get_user_attributes(user_id),
+        )
+        .await
+    }
+
+    async fn set_user_attribute(
+        &self,
+        user_id: &str,
+        name: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        self.run(
+            "set_user_attribute",
+            self.inner.set_user_attribute(user_id, name, values),
+        )
+        .await
+    }
+
+    async fn lock_user(&self, user_id: &str, reason: &str) -> Result<()> {
+        self.run("lock_user", self.inner.lock_user(user_id, reason))
+            .await
+    }
+
+    async fn unlock_user(&self, user_id: &str) -> Result<()> {
+        self.run("unlock_user", self.inner.unlock_user(user_id))
+            .await
+    }
+
+    async fn get_user_lock(&self, user_id: &str) -> Result<Option<UserLock>> {
+        self.run("get_user_lock", self.inner.get_user_lock(user_id))
+            .await
+    }
+
+    async fn list_password_changes(
+        &self,
+    ) -> Result<HashMap<String, chrono::DateTime<chrono::Utc>>> {
+        self.run("list_password_changes", self.inner.list_password_changes())
+            .await
+    }
+
+    async fn get_password_change(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        self.run(
+            "get_password_change",
+            self.inner.get_password_change(user_id),
+        )
+        .await
+    }
+
+    async fn record_auth_failure(&self, failure: AuthFailure) -> Result<()> {
+        self.run(
+            "record_auth_failure",
+            self.inner.record_auth_failure(failure),
+        )
+        .await
+    }
+
+    async fn list_auth_failures(
+        &self,
+        user_id: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<AuthFailure>> {
+        self.run(
+            "list_auth_failures",
+            self.inner.list_auth_failures(user_id, offset, limit),
+        )
+        .await
+    }
+
+    async fn record_audit_event(&self, event: AuditEvent) -> Result<()> {
+        self.run("record_audit_event", self.inner.record_audit_event(event))
+            .await
+    }
+
+    async fn list_audit_events(&self, offset: usize, limit: usize) -> Result<Vec<AuditEvent>> {
+        self.run(
+            "list_audit_events",
+            self.inner.list_audit_events(offset, limit),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Backend: OpaqueHandler + Sync> OpaqueHandler for TimeoutBackendHandler<Backend> {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.run("login_start", self.inner.login_start(request))
+            .await
+    }
+
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<String> {
+        self.run("login_finish", self.inner.login_finish(request))
+            .await
+    }
+
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.run("registration_start", self.inner.registration_start(request))
+            .await
+    }
+
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<()> {
+        self.run(
+            "registration_finish",
+            self.inner.registration_finish(request),
+        )
+        .await
+    }
+
+    async fn is_password_reused(&self, username: &str, password: &str) -> Result<bool> {
+        self.run(
+            "is_password_reused",
+            self.inner.is_password_reused(username, password),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync> TcpBackendHandler for TimeoutBackendHandler<Backend> {
+    /// Only called at startup, where waiting is fine.
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+        self.inner.get_jwt_blacklist().await
+    }
+
+    async fn create_refresh_token(&self, user: &str) -> Result<(String, chrono::Duration)> {
+        self.run(
+            "create_refresh_token",
+            self.inner.create_refresh_token(user),
+        )
+        .await
+    }
+
+    async fn check_token(&self, refresh_token_hash: u64, user: &str) -> Result<bool> {
+        self.run(
+            "check_token",
+            self.inner.check_token(refresh_token_hash, user),
+        )
+        .await
+    }
+
+    async fn register_jwt(
+        &self,
+        user: &str,
+        jwt_hash: u64,
+        expiry_date: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.run(
+            "register_jwt",
+            self.inner.register_jwt(user, jwt_hash, expiry_date),
+        )
+        .await
+    }
+
+    async fn blacklist_jwts(&self, user: &str) -> Result<HashSet<u64>> {
+        self.run("blacklist_jwts", self.inner.blacklist_jwts(user))
+            .await
+    }
+
+    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
+        self.run(
+            "delete_refresh_token",
+            self.inner.delete_refresh_token(refresh_token_hash),
+        )
+        .await
+    }
+
+    async fn delete_refresh_tokens(&self, user: &str) -> Result<()> {
+        self.run(
+            "delete_refresh_tokens",
+            self.inner.delete_refresh_tokens(user),
+        )
+        .await
+    }
+
+    async fn list_sessions(&self, user: Option<&str>) -> Result<Vec<Session>> {
+        self.run("list_sessions", self.inner.list_sessions(user))
+            .await
+    }
+
+    async fn start_password_reset(&self, user: &str) -> Result<Option<String>> {
+        self.run(
+            "start_password_reset",
+            self.inner.start_password_reset(user),
+        )
+        .await
+    }
+
+    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<String> {
+        self.run(
+            "get_user_id_for_password_reset_token",
+            self.inner.get_user_id_for_password_reset_token(token),
+        )
+        .await
+    }
+
+    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
+        self.run(
+            "delete_password_reset_token",
+            self.inner.delete_password_reset_token(token),
+        )
+        .await
+    }
+
+    async fn create_api_token(
+        &self,
+        user: &str,
+        name: &str,
+        scope: ApiTokenScope,
+        token_hash: &str,
+    ) -> Result<ApiToken> {
+        self.run(
+            "create_api_token",
+            self.inner.create_api_token(user, name, scope, token_hash),
+        )
+        .await
+    }
+
+    async fn get_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        self.run("get_api_token", self.inner.get_api_token(token_hash))
+            .await
+    }
+
+    async fn list_api_tokens(&self, user: Option<&str>) -> Result<Vec<ApiToken>> {
+        self.run("list_api_tokens", self.inner.list_api_tokens(user))
+            .await
+    }
+
+    async fn delete_api_token(&self, token_id: i32) -> Result<()> {
+        self.run("delete_api_token", self.inner.delete_api_token(token_id))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::MockTestBackendHandler;
+
+    #[derive(Clone)]
+    struct SlowLoginHandler;
+
+    #[async_trait]
+    impl LoginHandler for SlowLoginHandler {
+        async fn bind(&self, _request: BindRequest) -> Result<()> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let handler = TimeoutBackendHandler::new(SlowLoginHandler, Duration::from_millis(10));
+        let result = handler
+            .bind(BindRequest {
+                name: "bob".to_string(),
+                password: "pass".to_string(),
+            })
+            .await;
+        assert!(matches!(result, Err(DomainError::TimeoutError(op)) if op == "bind"));
+    }
+
+    #[tokio::test]
+    async fn test_no_timeout() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let handler = TimeoutBackendHandler::new(mock, Duration::from_secs(10));
+        assert!(handler.list_users(None).await.unwrap().is_empty());
+    }
+}
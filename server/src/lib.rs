@@ -0,0 +1,47 @@
+//! lldap is a lightweight LDAP server for authentication, backed by a SQL database.
+//!
+//! This library exposes the building blocks of the server, so that other projects can embed the
+//! user store or build alternative frontends on top of it:
+//!   - [`domain`] contains the user/group model and the storage layer: the
+//!     [`domain::handler::BackendHandler`] and [`domain::handler::LoginHandler`] traits, and their
+//!     SQL implementation [`domain::sql_backend_handler::SqlBackendHandler`]. The OPAQUE password
+//!     protocol is handled through [`domain::opaque_handler::OpaqueHandler`].
+//!   - [`infra`] contains the servers built on top of the domain: the LDAP and HTTP (GraphQL and
+//!     authentication) endpoints, as well as the configuration.
+//!
+//! With the `test-utils` feature, `domain::test_backend_handler::TestBackendHandler` provides an
+//! in-memory implementation of the handler traits, to write tests without a database.
+//!
+//! A minimal embedding looks like:
+//!
+//! ```no_run
+//! use lldap::domain::{
+//!     handler::{BackendHandler, CreateUserRequest},
+//!     sql_backend_handler::SqlBackendHandler,
+//!     sql_tables::{init_table, PoolOptions},
+//! };
+//! use lldap::infra::configuration::ConfigurationBuilder;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = ConfigurationBuilder::default()
+//!     .database_url("sqlite://users.db?mode=rwc".to_string())
+//!     .build()?;
+//! let pool = PoolOptions::new().connect(&config.database_url).await?;
+//! init_table(&pool).await?;
+//! let handler = SqlBackendHandler::new(config, pool);
+//! handler
+//!     .create_user(CreateUserRequest {
+//!         user_id: "bob".to_string(),
+//!         email: "bob@example.com".to_string(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+#![allow(clippy::nonstandard_macro_braces)]
+
+pub mod domain;
+pub mod infra;
@@ -1,22 +1,36 @@
 #![forbid(unsafe_code)]
 #![allow(clippy::nonstandard_macro_braces)]
 
-use crate::{
+use actix::Actor;
+use anyhow::{anyhow, Context, Result};
+use futures_util::TryFutureExt;
+use lldap::{
     domain::{
+        self,
         handler::{BackendHandler, CreateUserRequest},
+        opaque_handler::OpaqueHandler,
         sql_backend_handler::SqlBackendHandler,
         sql_opaque_handler::register_password,
         sql_tables::PoolOptions,
     },
-    infra::{cli::*, configuration::Configuration, db_cleaner::Scheduler},
+    infra::{
+        self,
+        admin_cli::{self, Action, DatabaseDirectory, ServerDirectory},
+        audit_backend_handler::AuditBackendHandler,
+        bind_throttle::BindThrottle,
+        caching_backend_handler::CachingBackendHandler,
+        cli::*,
+        configuration::Configuration,
+        connectors::{ConnectorBackendHandler, DeliveryLog, EventBus},
+        db_cleaner::Scheduler,
+        health::Readiness,
+        metrics::Metrics,
+        password_expiry::ExpiryNotifier,
+        socket_activation::InheritedListeners,
+        timeout_backend_handler::TimeoutBackendHandler,
+    },
 };
-use actix::Actor;
-use anyhow::{anyhow, Context, Result};
-use futures_util::TryFutureExt;
-use log::*;
-
-mod domain;
-mod infra;
+use tracing::{debug, error, info, warn};
 
 async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration) -> Result<()> {
     assert!(
@@ -43,12 +57,37 @@ async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration)
         .context("Error adding admin user to group")
 }
 
+async fn bootstrap<Handler: BackendHandler + OpaqueHandler>(
+    handler: &Handler,
+    config: &Configuration,
+) -> Result<()> {
+    if config.bootstrap.users.is_empty() && config.bootstrap.groups.is_empty() {
+        if config.bootstrap.prune {
+            warn!("Nothing declared in the bootstrap section, ignoring `prune`");
+        }
+        return Ok(());
+    }
+    let report = infra::bootstrap::reconcile(handler, &config.bootstrap, &config.ldap_user_dn)
+        .await
+        .context("while reconciling the bootstrap users and groups")?;
+    if report.is_empty() {
+        info!("The users and groups match the bootstrap section");
+    } else {
+        info!("Reconciled the bootstrap section: {:?}", report);
+    }
+    Ok(())
+}
+
 async fn run_server(config: Configuration) -> Result<()> {
     let sql_pool = PoolOptions::new()
         .max_connections(5)
         .connect(&config.database_url)
         .await?;
     domain::sql_tables::init_table(&sql_pool).await?;
+    domain::sql_tables::set_unique_email_index(&sql_pool, config.unique_emails).await?;
+    domain::sql_tables::check_schema_version(&sql_pool)
+        .await
+        .context("while checking the database schema")?;
     let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
     if let Err(e) = backend_handler.get_user_details(&config.ldap_user_dn).await {
         warn!("Could not get admin user, trying to create it: {:#}", e);
@@ -56,42 +95,352 @@ async fn run_server(config: Configuration) -> Result<()> {
             .await
             .map_err(|e| anyhow!("Error setting up admin login/account: {:#}", e))?;
     }
+    let metrics = Metrics::new(sql_pool.clone())?;
+    let readiness = Readiness::new(
+        sql_pool.clone(),
+        std::time::Duration::from_secs(config.database_timeout_seconds),
+    );
+    let backend_handler = TimeoutBackendHandler::new(
+        backend_handler,
+        std::time::Duration::from_secs(config.database_timeout_seconds),
+    )
+    .with_metrics(metrics.clone());
+    let backend_handler =
+        CachingBackendHandler::new(backend_handler, &config.cache).with_metrics(metrics.clone());
+    let backend_handler = AuditBackendHandler::new(backend_handler);
+    let delivery_log = DeliveryLog::default();
+    let event_bus = EventBus::default();
+    let backend_handler = ConnectorBackendHandler::new(
+        backend_handler,
+        config.connectors.clone(),
+        delivery_log.clone(),
+        event_bus.clone(),
+    );
+    bootstrap(&backend_handler, &config).await?;
+    let bind_throttle = BindThrottle::new(config.bind_throttle.clone());
+    let bind_throttle = if config.bind_throttle.persist {
+        bind_throttle
+            .with_persistence(sql_pool.clone())
+            .await
+            .context("while loading the bind throttling")?
+    } else {
+        bind_throttle
+    };
+    let mut listeners = InheritedListeners::from_env()?;
+    let (shutdown_trigger, shutdown) = infra::shutdown::channel();
     let server_builder = infra::ldap_server::build_ldap_server(
         &config,
         backend_handler.clone(),
+        bind_throttle.clone(),
+        metrics.clone(),
+        readiness.clone(),
+        shutdown,
         actix_server::Server::build(),
+        &mut listeners,
     )?;
     infra::jwt_sql_tables::init_table(&sql_pool).await?;
-    let server_builder =
-        infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder).await?;
+    if config.password_expiry.max_age_days > 0 {
+        if let Some(smtp) = &config.smtp {
+            // Every day at 8:00.
+            ExpiryNotifier::new(
+                "0 0 8 * * * *",
+                backend_handler.clone(),
+                config.password_expiry.clone(),
+                smtp.clone(),
+            )
+            .start();
+        } else {
+            info!("No smtp configuration, the password expiry warnings are only in the web UI");
+        }
+    }
+    let server_builder = infra::tcp_server::build_tcp_server(
+        &config,
+        backend_handler,
+        delivery_log,
+        event_bus,
+        bind_throttle,
+        metrics,
+        readiness,
+        server_builder,
+        &mut listeners,
+    )
+    .await?;
+    listeners.warn_unused();
     // Run every hour.
-    let scheduler = Scheduler::new("0 0 * * * * *", sql_pool);
+    let scheduler = Scheduler::new(
+        "0 0 * * * * *",
+        sql_pool.clone(),
+        chrono::Duration::days(config.auth_failure_retention_days.into()),
+        chrono::Duration::days(config.audit_log_retention_days.into()),
+        chrono::Duration::days(config.deleted_user_retention_days.into()),
+    );
     scheduler.start();
-    server_builder.workers(1).run().await?;
+    let server = server_builder
+        .workers(config.worker_threads)
+        .shutdown_timeout(config.shutdown_grace_period_seconds)
+        // Handled below, to close the LDAP connections before waiting for them.
+        .disable_signals()
+        .run();
+    let stopped_server = server.clone();
+    let grace_period = config.shutdown_grace_period_seconds;
+    actix_rt::spawn(async move {
+        if let Err(e) = infra::shutdown::wait_for_signal().await {
+            error!("Could not listen to the shutdown signals: {}", e);
+            return;
+        }
+        info!(
+            "Shutting down, waiting up to {} seconds for the connections",
+            grace_period
+        );
+        shutdown_trigger.trigger();
+        stopped_server.stop(true).await;
+    });
+    server.await?;
+    sql_pool.close().await;
     Ok(())
 }
 
 fn run_server_command(opts: RunOpts) -> Result<()> {
     let config = infra::configuration::init(opts.clone())?;
-    infra::logging::init(config.clone())?;
 
-    info!("Starting LLDAP....");
+    actix::run(async move {
+        // In the runtime, which exports the traces.
+        infra::logging::init_with_traces(&config)?;
 
-    debug!("CLI: {:#?}", opts);
-    debug!("Configuration: {:#?}", config);
+        info!("Starting LLDAP....");
 
-    actix::run(
-        run_server(config).unwrap_or_else(|e| error!("Could not bring up the servers: {:?}", e)),
-    )?;
+        debug!("CLI: {:#?}", opts);
+        debug!("Configuration: {:#?}", config);
+
+        if let Err(e) = run_server(config).await {
+            error!("Could not bring up the servers: {:?}", e);
+        }
+        infra::logging::shutdown().await;
+        Ok::<_, anyhow::Error>(())
+    })??;
 
     info!("End.");
     Ok(())
 }
 
+/// Configuration for the commands other than `run`, that only need the database.
+fn init_command_config(config_file: &str, verbose: bool) -> Result<Configuration> {
+    let config = infra::configuration::init(RunOpts {
+        config_file: config_file.to_string(),
+        ldap_port: None,
+        ldaps_port: None,
+        bootstrap_file: None,
+        verbose,
+    })?;
+    infra::logging::init(config.clone())?;
+    Ok(config)
+}
+
+async fn open_backend_handler(config: Configuration) -> Result<SqlBackendHandler> {
+    let sql_pool = PoolOptions::new()
+        .max_connections(5)
+        .connect(&config.database_url)
+        .await?;
+    domain::sql_tables::init_table(&sql_pool).await?;
+    domain::sql_tables::set_unique_email_index(&sql_pool, config.unique_emails).await?;
+    Ok(SqlBackendHandler::new(config, sql_pool))
+}
+
+async fn migrate(config: Configuration, opts: MigrateOpts) -> Result<()> {
+    let backend_handler = open_backend_handler(config).await?;
+    let report = infra::ldap_migration::migrate(&backend_handler, &opts).await?;
+    if opts.dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+    print!("{}", report);
+    Ok(())
+}
+
+fn migrate_command(opts: MigrateOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(migrate(config, opts))?
+}
+
+async fn seed(config: Configuration, opts: SeedOpts) -> Result<()> {
+    let backend_handler = open_backend_handler(config).await?;
+    let report = infra::seed::seed(&backend_handler, &opts).await?;
+    print!("{}", report);
+    Ok(())
+}
+
+fn seed_command(opts: SeedOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(seed(config, opts))?
+}
+
+async fn import(config: Configuration, opts: ImportOpts) -> Result<()> {
+    let backend_handler = open_backend_handler(config).await?;
+    let report = infra::import::import(&backend_handler, &opts).await?;
+    if opts.dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+    print!("{}", report);
+    Ok(())
+}
+
+fn import_command(opts: ImportOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(import(config, opts))?
+}
+
+async fn export(config: Configuration, opts: ExportOpts) -> Result<()> {
+    let base_dn = opts
+        .base_dn
+        .clone()
+        .unwrap_or_else(|| config.ldap_base_dn.clone());
+    let backend_handler = open_backend_handler(config).await?;
+    let ldif = infra::export::export_ldif(&backend_handler, &base_dn).await?;
+    match opts.output_file {
+        None => print!("{}", ldif),
+        Some(path) => {
+            std::fs::write(&path, ldif).with_context(|| format!("unable to write in '{}'", path))?
+        }
+    }
+    Ok(())
+}
+
+fn export_command(opts: ExportOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(export(config, opts))?
+}
+
+async fn backup(config: Configuration, opts: BackupOpts) -> Result<()> {
+    let manifest =
+        infra::backup::backup(&config.database_url, std::path::Path::new(&opts.output)).await?;
+    println!(
+        "Backed up the database at schema version {} to {}",
+        manifest.schema_version, opts.output
+    );
+    Ok(())
+}
+
+fn backup_command(opts: BackupOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(backup(config, opts))?
+}
+
+async fn restore(config: Configuration, opts: RestoreOpts) -> Result<()> {
+    let manifest =
+        infra::backup::restore(&config.database_url, std::path::Path::new(&opts.input)).await?;
+    println!(
+        "Restored the backup of {} (LLDAP {}, schema version {})",
+        manifest.creation_date, manifest.lldap_version, manifest.schema_version
+    );
+    Ok(())
+}
+
+fn restore_command(opts: RestoreOpts) -> Result<()> {
+    let config = init_command_config(&opts.config_file, opts.verbose)?;
+    actix::run(restore(config, opts))?
+}
+
+async fn administer(
+    target: AdminTargetOpts,
+    config: Option<Configuration>,
+    action: Action,
+) -> Result<()> {
+    let output = match config {
+        None => {
+            let directory = ServerDirectory::new(
+                target.server_url.as_deref().unwrap_or_default(),
+                target.token.as_deref().unwrap_or_default(),
+            );
+            admin_cli::execute(&directory, action).await?
+        }
+        Some(config) => {
+            let backend_handler = open_backend_handler(config).await?;
+            admin_cli::execute(&DatabaseDirectory::new(&backend_handler), action).await?
+        }
+    };
+    print!("{}", output);
+    Ok(())
+}
+
+/// Runs the action on the server if given, otherwise on the database of the config.
+fn admin_command(target: AdminTargetOpts, action: Action) -> Result<()> {
+    let config = match target.server_url {
+        Some(_) => None,
+        None => Some(init_command_config(&target.config_file, target.verbose)?),
+    };
+    actix::run(administer(target, config, action))?
+}
+
+fn user_command(command: UserCommand) -> Result<()> {
+    match command {
+        UserCommand::Add(opts) => admin_command(
+            opts.target,
+            Action::AddUser {
+                request: CreateUserRequest {
+                    user_id: opts.user_id,
+                    email: opts.email,
+                    display_name: opts.display_name,
+                    first_name: opts.first_name,
+                    last_name: opts.last_name,
+                },
+                password: opts.password,
+            },
+        ),
+        UserCommand::List(target) => admin_command(target, Action::ListUsers),
+        UserCommand::Del(opts) => admin_command(opts.target, Action::DeleteUser(opts.user_id)),
+    }
+}
+
+fn group_command(command: GroupCommand) -> Result<()> {
+    match command {
+        GroupCommand::Add(opts) => admin_command(opts.target, Action::AddGroup(opts.name)),
+        GroupCommand::Member(GroupMemberCommand::Add(opts)) => admin_command(
+            opts.target,
+            Action::AddMember {
+                group: opts.group,
+                user_id: opts.user_id,
+            },
+        ),
+    }
+}
+
+fn password_command(command: PasswordCommand) -> Result<()> {
+    let PasswordCommand::Reset(opts) = command;
+    let password = match opts.password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            std::io::stdin()
+                .read_line(&mut line)
+                .context("while reading the password")?;
+            let password = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+            if password.is_empty() {
+                return Err(anyhow!("No password given on the standard input"));
+            }
+            password
+        }
+    };
+    admin_command(
+        opts.target,
+        Action::SetPassword {
+            user_id: opts.user_id,
+            password,
+        },
+    )
+}
+
 fn main() -> Result<()> {
     let cli_opts = infra::cli::init();
     match cli_opts.command {
         Command::ExportGraphQLSchema(opts) => infra::graphql::api::export_schema(opts),
         Command::Run(opts) => run_server_command(opts),
+        Command::Migrate(opts) => migrate_command(opts),
+        Command::Seed(opts) => seed_command(opts),
+        Command::Import(opts) => import_command(opts),
+        Command::Export(opts) => export_command(opts),
+        Command::Backup(opts) => backup_command(opts),
+        Command::Restore(opts) => restore_command(opts),
+        Command::User(command) => user_command(command),
+        Command::Group(command) => group_command(command),
+        Command::Password(command) => password_command(command),
     }
 }
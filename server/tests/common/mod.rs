@@ -0,0 +1,163 @@
+//! Harness to run the full lldap binary against a temporary database, for the end-to-end tests.
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub const BASE_DN: &str = "dc=example,dc=com";
+pub const ADMIN_DN: &str = "cn=admin,ou=people,dc=example,dc=com";
+pub const ADMIN_PASSWORD: &str = "admin_password";
+
+/// A running lldap server, killed when dropped.
+pub struct TestServer {
+    process: Child,
+    pub ldap_port: u16,
+    pub http_port: u16,
+    // Keep the directory alive as long as the server.
+    _data_dir: tempfile::TempDir,
+}
+
+fn get_free_port() -> u16 {
+    TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn wait_for_port(port: u16) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "Server didn't start listening on port {}",
+            port
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let data_dir = tempfile::tempdir().unwrap();
+        let ldap_port = get_free_port();
+        let http_port = get_free_port();
+        let process = Command::new(env!("CARGO_BIN_EXE_lldap"))
+            .arg("run")
+            .arg("--config-file")
+            .arg(data_dir.path().join("lldap_config.toml"))
+            .env("LLDAP_LDAP_PORT", ldap_port.to_string())
+            .env("LLDAP_HTTP_PORT", http_port.to_string())
+            .env("LLDAP_LDAP_BASE_DN", BASE_DN)
+            .env("LLDAP_LDAP_USER_DN", "admin")
+            .env("LLDAP_LDAP_USER_PASS", ADMIN_PASSWORD)
+            .env("LLDAP_JWT_SECRET", "integration_test_secret")
+            .env(
+                "LLDAP_DATABASE_URL",
+                format!(
+                    "sqlite://{}?mode=rwc",
+                    data_dir.path().join("users.db").display()
+                ),
+            )
+            .env(
+                "LLDAP_KEY_FILE",
+                data_dir.path().join("private_key").display().to_string(),
+            )
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Could not start the lldap binary");
+        wait_for_port(ldap_port);
+        wait_for_port(http_port);
+        Self {
+            process,
+            ldap_port,
+            http_port,
+            _data_dir: data_dir,
+        }
+    }
+
+    pub fn ldap_url(&self) -> String {
+        format!("ldap://127.0.0.1:{}", self.ldap_port)
+    }
+
+    pub fn admin_connection(&self) -> ldap3::LdapConn {
+        let mut ldap = ldap3::LdapConn::new(&self.ldap_url()).unwrap();
+        ldap.simple_bind(ADMIN_DN, ADMIN_PASSWORD)
+            .unwrap()
+            .success()
+            .unwrap();
+        ldap
+    }
+
+    /// Runs a GraphQL query as the admin, and returns the "data" part of the response.
+    pub fn graphql(&self, query: &str, variables: serde_json::Value) -> serde_json::Value {
+        let client = reqwest::blocking::Client::new();
+        let token = client
+            .post(format!("http://127.0.0.1:{}/auth", self.http_port))
+            .json(&serde_json::json!({"name": "admin", "password": ADMIN_PASSWORD}))
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .unwrap();
+        let response: serde_json::Value = client
+            .post(format!("http://127.0.0.1:{}/api/graphql", self.http_port))
+            .bearer_auth(token)
+            .json(&serde_json::json!({"query": query, "variables": variables}))
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .unwrap();
+        assert!(
+            response.get("errors").is_none(),
+            "GraphQL errors: {}",
+            response
+        );
+        response["data"].clone()
+    }
+
+    pub fn create_user(&self, user_id: &str, email: &str, password: &str) {
+        self.graphql(
+            r#"mutation CreateUser($user: CreateUserInput!) { createUser(user: $user) { id } }"#,
+            serde_json::json!({"user": {"id": user_id, "email": email, "displayName": user_id}}),
+        );
+        let user_dn = format!("cn={},ou=people,{}", user_id, BASE_DN);
+        let mut ldap = self.admin_connection();
+        ldap.extended(ldap3::exop::PasswordModify {
+            user_id: Some(user_dn.as_str()),
+            old_pass: None,
+            new_pass: Some(password),
+        })
+        .unwrap()
+        .success()
+        .unwrap();
+    }
+
+    pub fn create_group(&self, name: &str) -> i64 {
+        self.graphql(
+            r#"mutation CreateGroup($name: String!) { createGroup(name: $name) { id } }"#,
+            serde_json::json!({ "name": name }),
+        )["createGroup"]["id"]
+            .as_i64()
+            .unwrap()
+    }
+
+    pub fn add_user_to_group(&self, user_id: &str, group_id: i64) {
+        self.graphql(
+            r#"mutation AddUserToGroup($user: String!, $group: Int!) {
+                 addUserToGroup(userId: $user, groupId: $group) { ok }
+               }"#,
+            serde_json::json!({"user": user_id, "group": group_id}),
+        );
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,147 @@
+//! End-to-end tests: they run the actual server binary and talk to it with a real LDAP client.
+//!
+//! Run them with `cargo test --features integration-tests --test ldap_integration`.
+
+mod common;
+
+use common::{TestServer, ADMIN_DN, BASE_DN};
+use ldap3::{LdapConn, LdapError, Scope, SearchEntry};
+use std::collections::HashSet;
+
+fn search_users(ldap: &mut LdapConn, filter: &str) -> HashSet<String> {
+    let (entries, _) = ldap
+        .search(
+            &format!("ou=people,{}", BASE_DN),
+            Scope::Subtree,
+            filter,
+            vec!["uid"],
+        )
+        .unwrap()
+        .success()
+        .unwrap();
+    entries
+        .into_iter()
+        .map(|e| SearchEntry::construct(e).attrs["uid"][0].clone())
+        .collect()
+}
+
+fn set_of(values: &[&str]) -> HashSet<String> {
+    values.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_admin_bind() {
+    let server = TestServer::start();
+    let mut ldap = LdapConn::new(&server.ldap_url()).unwrap();
+    ldap.simple_bind(ADMIN_DN, common::ADMIN_PASSWORD)
+        .unwrap()
+        .success()
+        .unwrap();
+    let result = ldap.simple_bind(ADMIN_DN, "wrong_password").unwrap();
+    assert_eq!(
+        result.rc, 49,
+        "Expected invalidCredentials, got {:?}",
+        result
+    );
+}
+
+#[test]
+fn test_user_bind() {
+    let server = TestServer::start();
+    server.create_user("bob", "bob@example.com", "bob_password");
+    let mut ldap = LdapConn::new(&server.ldap_url()).unwrap();
+    ldap.simple_bind(&format!("cn=bob,ou=people,{}", BASE_DN), "bob_password")
+        .unwrap()
+        .success()
+        .unwrap();
+    // Regular users are not allowed to search.
+    match ldap
+        .search(BASE_DN, Scope::Subtree, "(objectClass=*)", vec!["uid"])
+        .unwrap()
+        .success()
+    {
+        Err(LdapError::LdapResult { result }) => assert_eq!(result.rc, 50),
+        other => panic!("Expected insufficientAccessRights, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_search_filters() {
+    let server = TestServer::start();
+    server.create_user("bob", "bob@example.com", "bob_password");
+    server.create_user("alice", "alice@example.com", "alice_password");
+    let group = server.create_group("family");
+    server.add_user_to_group("bob", group);
+
+    let mut ldap = server.admin_connection();
+    assert_eq!(
+        search_users(&mut ldap, "(objectClass=person)"),
+        set_of(&["admin", "alice", "bob"])
+    );
+    assert_eq!(search_users(&mut ldap, "(uid=bob)"), set_of(&["bob"]));
+    assert_eq!(
+        search_users(&mut ldap, "(|(uid=bob)(mail=alice@example.com))"),
+        set_of(&["alice", "bob"])
+    );
+    assert_eq!(
+        search_users(&mut ldap, "(&(objectClass=person)(!(uid=bob)))"),
+        set_of(&["admin", "alice"])
+    );
+    assert_eq!(
+        search_users(
+            &mut ldap,
+            &format!("(memberOf=cn=family,ou=groups,{})", BASE_DN)
+        ),
+        set_of(&["bob"])
+    );
+}
+
+#[test]
+fn test_search_groups() {
+    let server = TestServer::start();
+    server.create_user("bob", "bob@example.com", "bob_password");
+    let group = server.create_group("family");
+    server.add_user_to_group("bob", group);
+
+    let mut ldap = server.admin_connection();
+    let (entries, _) = ldap
+        .search(
+            &format!("ou=groups,{}", BASE_DN),
+            Scope::Subtree,
+            "(objectClass=groupOfUniqueNames)",
+            vec!["cn", "uniqueMember"],
+        )
+        .unwrap()
+        .success()
+        .unwrap();
+    let groups = entries
+        .into_iter()
+        .map(SearchEntry::construct)
+        .map(|e| (e.attrs["cn"][0].clone(), e.attrs["uniqueMember"].clone()))
+        .collect::<Vec<_>>();
+    assert!(groups.contains(&(
+        "family".to_string(),
+        vec![format!("cn=bob,ou=people,{}", BASE_DN)]
+    )));
+}
+
+#[test]
+fn test_root_dse() {
+    let server = TestServer::start();
+    let mut ldap = server.admin_connection();
+    let (entries, _) = ldap
+        .search(
+            "",
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["supportedLDAPVersion"],
+        )
+        .unwrap()
+        .success()
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        SearchEntry::construct(entries.into_iter().next().unwrap()).attrs["supportedLDAPVersion"],
+        vec!["3".to_string()]
+    );
+}